@@ -0,0 +1,126 @@
+//! Fan-out of one event to the live subscriptions of many connections
+//!
+//! Measures `SubscriptionRegistry::distribute_event` by number of connections,
+//! subscriptions per connection and share of connections the event matches.
+//!
+//! Run with: cargo bench --bench subscription_fanout
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use relay_builder::subscription_registry::ConnectionHandle;
+use relay_builder::{EventDistributor, SubscriptionRegistry};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use websocket_builder::MessageSender;
+
+type Receiver = flume::Receiver<(RelayMessage<'static>, usize)>;
+
+/// Registry with `connections` connections of `filters` subscriptions each,
+/// the first `match_ratio` of them having one subscription to `author`
+fn populate(
+    connections: usize,
+    filters: usize,
+    match_ratio: f64,
+    author: PublicKey,
+) -> (
+    Arc<SubscriptionRegistry>,
+    Vec<ConnectionHandle>,
+    Vec<Receiver>,
+) {
+    let registry = Arc::new(SubscriptionRegistry::new(None));
+    let matching = (connections as f64 * match_ratio).round() as usize;
+    let mut handles = Vec::with_capacity(connections);
+    let mut receivers = Vec::with_capacity(connections);
+
+    for index in 0..connections {
+        let connection_id = format!("bench-{index}");
+        let (tx, rx) = flume::unbounded();
+        handles.push(registry.register_connection(
+            connection_id.clone(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        ));
+        receivers.push(rx);
+
+        for filter in 0..filters {
+            let subscribed = if filter == 0 && index < matching {
+                author
+            } else {
+                Keys::generate().public_key()
+            };
+            registry
+                .add_subscription(
+                    &connection_id,
+                    SubscriptionId::new(format!("sub-{filter}")),
+                    vec![Filter::new().author(subscribed).kind(Kind::TextNote)],
+                )
+                .expect("connection is registered");
+        }
+    }
+    (registry, handles, receivers)
+}
+
+fn bench_distribute_event(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let keys = Keys::generate();
+    let event = Arc::new(
+        EventBuilder::text_note("Benchmark event")
+            .sign_with_keys(&keys)
+            .expect("Failed to create event"),
+    );
+
+    let mut group = c.benchmark_group("distribute_event");
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(10);
+
+    for connections in [100, 1_000, 10_000] {
+        for filters in [1, 10] {
+            for match_ratio in [0.0, 0.1, 1.0] {
+                let (registry, _handles, receivers) =
+                    populate(connections, filters, match_ratio, keys.public_key());
+
+                group.bench_with_input(
+                    BenchmarkId::new(
+                        format!(
+                            "{filters}_filters_{}pct_match",
+                            (match_ratio * 100.0) as u32
+                        ),
+                        connections,
+                    ),
+                    &connections,
+                    |b, _| {
+                        b.to_async(&rt).iter_custom(|iters| {
+                            let registry = Arc::clone(&registry);
+                            let event = Arc::clone(&event);
+                            let receivers = &receivers;
+                            async move {
+                                let mut elapsed = Duration::ZERO;
+                                for _ in 0..iters {
+                                    let started = Instant::now();
+                                    registry
+                                        .distribute_event(Arc::clone(&event), &Scope::Default)
+                                        .await;
+                                    elapsed += started.elapsed();
+
+                                    // Drain the frames outside of the measurement
+                                    for rx in receivers {
+                                        rx.drain().for_each(drop);
+                                    }
+                                }
+                                elapsed
+                            }
+                        });
+                    },
+                );
+            }
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_distribute_event);
+criterion_main!(benches);
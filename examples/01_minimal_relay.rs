@@ -10,7 +10,6 @@
 //! Run with: cargo run --example minimal_relay --features axum
 
 use anyhow::Result;
-use axum::{routing::get, Router};
 use nostr_sdk::prelude::*;
 use relay_builder::{RelayBuilder, RelayConfig, RelayInfo};
 use std::net::SocketAddr;
@@ -36,27 +35,24 @@ async fn main() -> Result<()> {
         software: "relay_builder".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         icon: None,
+        limitation: None,
+        payments_url: None,
+        fees: None,
     };
 
     // Build the relay - uses DefaultRelayProcessor which accepts all valid events
-    let root_handler = RelayBuilder::<()>::new(config.clone())
+    let service = RelayBuilder::<()>::new(config.clone())
         .with_relay_info(relay_info)
-        .build_axum()
+        .into_make_service()
         .await?;
 
-    // Create HTTP server
-    let app = Router::new().route("/", get(root_handler));
-
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
     println!("🚀 Minimal relay listening on: {addr}");
     println!("📡 WebSocket endpoint: ws://localhost:8080");
+    println!("🩺 Health check: http://localhost:8080/health");
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await?;
+    axum::serve(listener, service).await?;
 
     Ok(())
 }
@@ -92,6 +92,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         software: "https://github.com/verse-pbc/relay_builder".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         icon: None,
+        limitation: None,
+        payments_url: None,
+        fees: None,
     };
 
     // Build the relay handler using the new build_axum method
@@ -116,6 +116,9 @@ async fn main() -> Result<()> {
         software: "relay_builder".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         icon: None,
+        limitation: None,
+        payments_url: None,
+        fees: None,
     };
 
     // Build relay with rate limiting middleware
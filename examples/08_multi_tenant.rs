@@ -11,7 +11,7 @@ use axum::{routing::get, Router};
 use nostr_sdk::prelude::*;
 use relay_builder::{
     EventContext, EventProcessor, RelayBuilder, RelayConfig, RelayInfo, Result as RelayResult,
-    StoreCommand,
+    ScopeRelayInfo, StoreCommand,
 };
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -63,12 +63,25 @@ async fn main() -> Result<()> {
         software: "relay_builder".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         icon: None,
+        limitation: None,
+        payments_url: None,
+        fees: None,
     };
 
     // Build relay with multi-tenant support
     let handler = RelayBuilder::new(config)
         .with_event_processor(MultiTenantProcessor)
         .with_relay_info(relay_info)
+        // alice.relay.example.com presents its own NIP-11 identity
+        .with_scope_relay_info(
+            nostr_lmdb::Scope::named("alice")?,
+            ScopeRelayInfo {
+                name: Some("Alice's Community".to_string()),
+                description: Some("Alice's corner of the relay".to_string()),
+                contact: Some("alice@example.com".to_string()),
+                ..Default::default()
+            },
+        )
         .build_axum()
         .await?;
 
@@ -42,6 +42,7 @@ async fn main() -> Result<()> {
     config = config.with_websocket_config(WebSocketConfig {
         max_connections: Some(1000),
         max_connection_time: Some(3600), // 1 hour
+        max_message_size: Some(128 * 1024),
     });
 
     // Relay information
@@ -54,6 +55,9 @@ async fn main() -> Result<()> {
         software: "relay_builder".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         icon: None,
+        limitation: None,
+        payments_url: None,
+        fees: None,
     };
 
     // Production components
@@ -0,0 +1,13 @@
+//! Arbitrary frames through the client message converter
+//!
+//! Run with: cargo +nightly fuzz run client_message
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use relay_builder::message_converter::parse_client_message_lenient;
+
+fuzz_target!(|bytes: &[u8]| {
+    // Refused frames are fine, panics are not
+    let _ = parse_client_message_lenient(bytes);
+});
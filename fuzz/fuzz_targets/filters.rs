@@ -0,0 +1,30 @@
+//! Arbitrary JSON filters through REQ parsing and filter normalization
+//!
+//! Run with: cargo +nightly fuzz run filters
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nostr_sdk::prelude::*;
+use relay_builder::message_converter::parse_client_message_lenient;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Ok(filter) = std::str::from_utf8(bytes) else {
+        return;
+    };
+    let frame = format!(r#"["REQ","fuzz",{filter}]"#);
+
+    // A normalized filter must survive a second round trip unchanged
+    if let Ok(Some(ClientMessage::Req { filter, .. })) =
+        parse_client_message_lenient(frame.as_bytes())
+    {
+        let again = format!(r#"["REQ","fuzz",{}]"#, filter.as_json());
+        match parse_client_message_lenient(again.as_bytes()) {
+            Ok(Some(ClientMessage::Req { filter: again, .. })) => assert_eq!(filter, again),
+            other => panic!(
+                "normalized filter {} not accepted: {other:?}",
+                filter.as_json()
+            ),
+        }
+    }
+});
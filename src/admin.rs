@@ -0,0 +1,1024 @@
+//! Authenticated admin HTTP API
+//!
+//! [`AdminApi`] is an axum router exposing the relay's live connections and
+//! subscriptions, its moderation lists and report queue, scopes and tenants, slow queries, the REQ audit log, event provenance, background task health and
+//! traffic statistics, relay-wide or per scope for metering tenants, plus a retention trigger deleting old events. Register it with
+//! [`RelayBuilder::with_admin_api`](crate::RelayBuilder::with_admin_api), keep
+//! a clone and mount [`AdminApi::router`] wherever the host app wants it, e.g.
+//! under `/admin`. Every request must carry `Authorization: Bearer <token>`,
+//! or a [NIP-98](crate::http_auth) authorization signed by one of the admin
+//! pubkeys set with [`AdminApi::with_nip98_admins`]. A NIP-98 authorization
+//! is good for one request only, must cover the body of any request but `GET`
+//! and `HEAD`, and caps it at [`MAX_AUTHENTICATED_BODY`] bytes.
+//!
+//! | Method | Path | |
+//! |---|---|---|
+//! | GET | `/connections` | Connection statistics |
+//! | GET | `/connections/{id}/subscriptions` | Subscriptions of one connection |
+//! | GET | `/subscriptions` | All subscriptions |
+//! | GET | `/moderation` | Moderation lists |
+//! | POST, DELETE | `/moderation/{list}/{value}` | Add to or remove from `banned-pubkeys`, `shadow-banned-pubkeys`, `banned-events`, `allowed-pubkeys` or `blocked-words` |
+//! | GET | `/reports` | NIP-56 reports per target, most reported by trusted reporters first |
+//! | DELETE | `/reports/{pubkey,event}/{value}` | Dismiss the reports on a pubkey or event |
+//! | POST | `/retention` | Delete events older than `older_than_secs` |
+//! | POST | `/purge/{pubkey}` | Delete a pubkey's events, and with `include_tagged=true` those tagging it, in every scope and tombstone it |
+//! | GET | `/scopes` | Stored scopes |
+//! | DELETE | `/scopes/{name}` | Delete a scope's data |
+//! | POST | `/scopes/migrate` | Move live connections between scopes |
+//! | GET | `/tenants` | Provisioned tenants |
+//! | POST, DELETE | `/tenants/{name}` | Create or delete a tenant |
+//! | POST | `/tenants/{name}/disable`, `/tenants/{name}/enable` | Stop or resume serving a tenant |
+//! | GET | `/stats` | Relay-wide totals |
+//! | GET | `/stats/scopes`, `/stats/scopes/{name}` | Connections, event rate, stored events by kind and storage size per scope |
+//! | GET | `/slow-queries` | Recorded slow queries |
+//! | GET | `/audit/reqs` | Audit log entries, by `since`, `until`, `pubkey`, `connection`, `scope` and `limit` |
+//! | GET | `/events/{id}/provenance` | Where a stored event came from |
+//! | GET | `/tasks` | Health of supervised background tasks |
+
+use crate::audit_log::{AuditLog, AuditQuery, ReqAuditEntry};
+use crate::database::{RelayDatabase, ScopeStorageStats};
+use crate::http_auth::{HttpAuthError, ReplayCache};
+use crate::moderation::ModerationStore;
+use crate::provenance::{EventProvenance, ProvenanceStore};
+use crate::reports::{ReportEntry, ReportQueue, ReportTarget};
+use crate::slow_query_log::{SlowQuery, SlowQueryLog};
+use crate::subscription_registry::{
+    ConnectionStats, ScopeActivity, ScopeMigration, SubscriptionPriority, SubscriptionRegistry,
+};
+use crate::supervisor::{TaskHealth, TaskSupervisor};
+use crate::tenants::{Tenant, TenantStore};
+use crate::utils::Attachment;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+/// Largest body read to check the `payload` tag of a NIP-98 authorization
+pub const MAX_AUTHENTICATED_BODY: usize = 64 * 1024;
+
+/// Relay components the admin API operates on, attached when the relay is built
+pub(crate) struct AdminContext {
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+    pub(crate) database: Arc<RelayDatabase>,
+    pub(crate) moderation: Option<ModerationStore>,
+    pub(crate) reports: Option<ReportQueue>,
+    pub(crate) slow_query_log: Option<SlowQueryLog>,
+    pub(crate) audit_log: Option<AuditLog>,
+    pub(crate) provenance: Option<ProvenanceStore>,
+    pub(crate) tenants: Option<TenantStore>,
+    pub(crate) task_supervisor: TaskSupervisor,
+}
+
+/// Admin HTTP API of a relay
+#[derive(Clone)]
+pub struct AdminApi {
+    token: Arc<str>,
+    /// Public URL the router is mounted at, and the pubkeys allowed to sign NIP-98 requests
+    nip98: Option<(Arc<str>, Arc<HashSet<PublicKey>>)>,
+    /// NIP-98 authorizations already used
+    replays: ReplayCache,
+    context: Attachment<AdminContext>,
+}
+
+impl std::fmt::Debug for AdminApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminApi")
+            .field("attached", &self.context.is_attached())
+            .finish()
+    }
+}
+
+impl AdminApi {
+    /// Accept requests bearing `token`
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            token: Arc::from(token.into()),
+            nip98: None,
+            replays: ReplayCache::new(),
+            context: Attachment::default(),
+        }
+    }
+
+    /// Also accept NIP-98 authorizations signed by `admins`
+    ///
+    /// `base_url` is the public URL the router is mounted at, e.g.
+    /// `https://relay.example.com/admin`.
+    #[must_use]
+    pub fn with_nip98_admins(
+        mut self,
+        base_url: impl Into<String>,
+        admins: impl IntoIterator<Item = PublicKey>,
+    ) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        self.nip98 = Some((Arc::from(base_url), Arc::new(admins.into_iter().collect())));
+        self
+    }
+
+    /// Operate on the relay built with this API, answers 503 until then
+    pub(crate) fn attach(&self, context: AdminContext) {
+        self.context.attach(context, "Admin API");
+    }
+
+    /// The API's routes, guarded by the bearer token or NIP-98
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/connections", get(list_connections))
+            .route(
+                "/connections/{id}/subscriptions",
+                get(connection_subscriptions),
+            )
+            .route("/subscriptions", get(list_subscriptions))
+            .route("/moderation", get(moderation_lists))
+            .route(
+                "/moderation/{list}/{value}",
+                post(moderation_add).delete(moderation_remove),
+            )
+            .route("/reports", get(list_reports))
+            .route(
+                "/reports/{target}/{value}",
+                axum::routing::delete(dismiss_report),
+            )
+            .route("/retention", post(run_retention))
+            .route("/purge/{pubkey}", post(purge_pubkey))
+            .route("/scopes", get(list_scopes))
+            .route("/scopes/{name}", axum::routing::delete(delete_scope))
+            .route("/scopes/migrate", post(migrate_scope))
+            .route("/tenants", get(list_tenants))
+            .route("/tenants/{name}", post(create_tenant).delete(delete_tenant))
+            .route("/tenants/{name}/disable", post(disable_tenant))
+            .route("/tenants/{name}/enable", post(enable_tenant))
+            .route("/stats", get(stats))
+            .route("/stats/scopes", get(all_scope_stats))
+            .route("/stats/scopes/{name}", get(one_scope_stats))
+            .route("/slow-queries", get(slow_queries))
+            .route("/audit/reqs", get(audit_reqs))
+            .route("/events/{id}/provenance", get(event_provenance))
+            .route("/tasks", get(task_health))
+            .layer(middleware::from_fn_with_state(self.clone(), require_token))
+            .with_state(self.clone())
+    }
+
+    fn context(&self) -> Result<&AdminContext, AdminError> {
+        self.context.get().ok_or_else(|| {
+            AdminError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "relay is not running".to_string(),
+            )
+        })
+    }
+}
+
+/// Error answered as `{"error": "..."}`
+#[derive(Debug)]
+struct AdminError(StatusCode, String);
+
+impl AdminError {
+    fn bad_request(message: impl std::fmt::Display) -> Self {
+        Self(StatusCode::BAD_REQUEST, message.to_string())
+    }
+
+    fn not_found(message: impl std::fmt::Display) -> Self {
+        Self(StatusCode::NOT_FOUND, message.to_string())
+    }
+}
+
+impl From<crate::error::Error> for AdminError {
+    fn from(error: crate::error::Error) -> Self {
+        let status = if error.is_client_error() {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        Self(status, error.to_string())
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+async fn require_token(State(api): State<AdminApi>, request: Request, next: Next) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let bearer_authorized = authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), api.token.as_bytes()));
+    if bearer_authorized {
+        return next.run(request).await;
+    }
+
+    let Some((base_url, admins)) = &api.nip98 else {
+        return AdminError(StatusCode::UNAUTHORIZED, "invalid admin token".to_string())
+            .into_response();
+    };
+    // The body is only buffered for a signed authorization from an admin
+    // that covers it, and never past MAX_AUTHENTICATED_BODY
+    let event = match crate::http_auth::signed_event(authorization.as_deref()) {
+        Ok(event) => event,
+        Err(e) => return AdminError(StatusCode::UNAUTHORIZED, e.to_string()).into_response(),
+    };
+    if !admins.contains(&event.pubkey) {
+        return AdminError(StatusCode::FORBIDDEN, "not an admin".to_string()).into_response();
+    }
+    // Requests that may carry a body can't be replayed with another one
+    let has_payload = crate::http_auth::has_payload(&event);
+    if !has_payload && !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return AdminError(
+            StatusCode::UNAUTHORIZED,
+            HttpAuthError::PayloadMissing.to_string(),
+        )
+        .into_response();
+    }
+    let (request, body) = if has_payload {
+        let (parts, body) = request.into_parts();
+        let Ok(bytes) = axum::body::to_bytes(body, MAX_AUTHENTICATED_BODY).await else {
+            return AdminError(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("body must be at most {MAX_AUTHENTICATED_BODY} bytes"),
+            )
+            .into_response();
+        };
+        (
+            Request::from_parts(parts, axum::body::Body::from(bytes.clone())),
+            bytes,
+        )
+    } else {
+        (request, axum::body::Bytes::new())
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let verified = crate::http_auth::verify_request(
+        &event,
+        &format!("{base_url}{path}"),
+        request.method().as_str(),
+        &body,
+    )
+    .and_then(|()| api.replays.check(&event));
+    match verified {
+        Ok(()) => next.run(request).await,
+        Err(e) => AdminError(StatusCode::UNAUTHORIZED, e.to_string()).into_response(),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Name of a scope, `None` for the default scope
+fn scope_name(scope: &Scope) -> Option<String> {
+    match scope {
+        Scope::Named { name, .. } => Some(name.to_string()),
+        Scope::Default => None,
+    }
+}
+
+fn parse_scope(name: Option<&str>) -> Result<Scope, AdminError> {
+    match name {
+        None => Ok(Scope::Default),
+        Some(name) => Scope::named(name).map_err(AdminError::bad_request),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ConnectionView {
+    connection_id: String,
+    remote_address: Option<String>,
+    auth_pubkey: Option<String>,
+    scope: Option<String>,
+    priority: SubscriptionPriority,
+    connected_at: u64,
+    messages_in: u64,
+    bytes_in: u64,
+    messages_out: u64,
+    bytes_out: u64,
+    events_accepted: u64,
+    events_rejected: u64,
+    subscriptions_opened: u64,
+    active_subscriptions: usize,
+}
+
+impl From<ConnectionStats> for ConnectionView {
+    fn from(stats: ConnectionStats) -> Self {
+        Self {
+            connection_id: stats.connection_id,
+            remote_address: stats.remote_address,
+            auth_pubkey: stats.auth_pubkey.map(|pubkey| pubkey.to_hex()),
+            scope: scope_name(&stats.scope),
+            priority: stats.priority,
+            connected_at: stats.connected_at.as_u64(),
+            messages_in: stats.messages_in,
+            bytes_in: stats.bytes_in,
+            messages_out: stats.messages_out,
+            bytes_out: stats.bytes_out,
+            events_accepted: stats.events_accepted,
+            events_rejected: stats.events_rejected,
+            subscriptions_opened: stats.subscriptions_opened,
+            active_subscriptions: stats.active_subscriptions,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SubscriptionView {
+    connection_id: String,
+    subscription_id: SubscriptionId,
+    filters: Vec<Filter>,
+}
+
+async fn list_connections(
+    State(api): State<AdminApi>,
+) -> Result<Json<Vec<ConnectionView>>, AdminError> {
+    let registry = &api.context()?.registry;
+    Ok(Json(
+        registry
+            .connections_snapshot()
+            .into_iter()
+            .map(ConnectionView::from)
+            .collect(),
+    ))
+}
+
+async fn connection_subscriptions(
+    State(api): State<AdminApi>,
+    Path(connection_id): Path<String>,
+) -> Result<Json<Vec<SubscriptionView>>, AdminError> {
+    let subscriptions = api
+        .context()?
+        .registry
+        .subscriptions(&connection_id)
+        .ok_or_else(|| AdminError::not_found("unknown connection"))?;
+
+    Ok(Json(
+        subscriptions
+            .into_iter()
+            .map(|(subscription_id, filters)| SubscriptionView {
+                connection_id: connection_id.clone(),
+                subscription_id,
+                filters,
+            })
+            .collect(),
+    ))
+}
+
+async fn list_subscriptions(
+    State(api): State<AdminApi>,
+) -> Result<Json<Vec<SubscriptionView>>, AdminError> {
+    let registry = &api.context()?.registry;
+    let mut views = Vec::new();
+    for stats in registry.connections_snapshot() {
+        // The connection may have gone away since the snapshot
+        let Some(subscriptions) = registry.subscriptions(&stats.connection_id) else {
+            continue;
+        };
+        views.extend(subscriptions.into_iter().map(|(subscription_id, filters)| {
+            SubscriptionView {
+                connection_id: stats.connection_id.clone(),
+                subscription_id,
+                filters,
+            }
+        }));
+    }
+    Ok(Json(views))
+}
+
+fn moderation(api: &AdminApi) -> Result<&ModerationStore, AdminError> {
+    api.context()?
+        .moderation
+        .as_ref()
+        .ok_or_else(|| AdminError::not_found("moderation is not enabled"))
+}
+
+async fn moderation_lists(
+    State(api): State<AdminApi>,
+) -> Result<Json<crate::moderation::ModerationLists>, AdminError> {
+    Ok(Json(moderation(&api)?.lists()))
+}
+
+async fn update_moderation(
+    api: &AdminApi,
+    list: &str,
+    value: &str,
+    add: bool,
+) -> Result<StatusCode, AdminError> {
+    let moderation = moderation(api)?;
+    let parse_pubkey = || PublicKey::parse(value).map_err(AdminError::bad_request);
+
+    match (list, add) {
+        ("banned-pubkeys", true) => moderation.ban_pubkey(parse_pubkey()?).await?,
+        ("banned-pubkeys", false) => moderation.unban_pubkey(parse_pubkey()?).await?,
+        ("shadow-banned-pubkeys", true) => moderation.shadow_ban_pubkey(parse_pubkey()?).await?,
+        ("shadow-banned-pubkeys", false) => moderation.lift_shadow_ban(parse_pubkey()?).await?,
+        ("allowed-pubkeys", true) => moderation.allow_pubkey(parse_pubkey()?).await?,
+        ("allowed-pubkeys", false) => moderation.disallow_pubkey(parse_pubkey()?).await?,
+        ("banned-events", add) => {
+            let id = EventId::parse(value).map_err(AdminError::bad_request)?;
+            if add {
+                moderation.ban_event(id).await?
+            } else {
+                moderation.unban_event(id).await?
+            }
+        }
+        ("blocked-words", true) => moderation.block_word(value).await?,
+        ("blocked-words", false) => moderation.unblock_word(value).await?,
+        _ => return Err(AdminError::not_found(format!("unknown list '{list}'"))),
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn moderation_add(
+    State(api): State<AdminApi>,
+    Path((list, value)): Path<(String, String)>,
+) -> Result<StatusCode, AdminError> {
+    update_moderation(&api, &list, &value, true).await
+}
+
+async fn moderation_remove(
+    State(api): State<AdminApi>,
+    Path((list, value)): Path<(String, String)>,
+) -> Result<StatusCode, AdminError> {
+    update_moderation(&api, &list, &value, false).await
+}
+
+fn reports(api: &AdminApi) -> Result<&ReportQueue, AdminError> {
+    api.context()?
+        .reports
+        .as_ref()
+        .ok_or_else(|| AdminError::not_found("report queue is not enabled"))
+}
+
+#[derive(Debug, Serialize)]
+struct ReportView {
+    scope: Option<String>,
+    pubkey: Option<String>,
+    event: Option<String>,
+    reporters: Vec<String>,
+    trusted_reports: usize,
+    report_types: BTreeMap<String, usize>,
+    last_reported_at: u64,
+}
+
+impl From<ReportEntry> for ReportView {
+    fn from(entry: ReportEntry) -> Self {
+        let (pubkey, event) = match entry.target {
+            ReportTarget::Pubkey(pubkey) => (Some(pubkey), None),
+            ReportTarget::Event(id) => (entry.author, Some(id.to_hex())),
+        };
+        Self {
+            scope: scope_name(&entry.scope),
+            pubkey: pubkey.map(|pubkey| pubkey.to_hex()),
+            event,
+            reporters: entry
+                .reporters
+                .iter()
+                .map(|pubkey| pubkey.to_hex())
+                .collect(),
+            trusted_reports: entry.trusted_reports,
+            report_types: entry.report_types,
+            last_reported_at: entry.last_reported_at.as_u64(),
+        }
+    }
+}
+
+async fn list_reports(State(api): State<AdminApi>) -> Result<Json<Vec<ReportView>>, AdminError> {
+    Ok(Json(
+        reports(&api)?
+            .entries()
+            .into_iter()
+            .map(ReportView::from)
+            .collect(),
+    ))
+}
+
+async fn dismiss_report(
+    State(api): State<AdminApi>,
+    Path((target, value)): Path<(String, String)>,
+) -> Result<StatusCode, AdminError> {
+    let queue = reports(&api)?;
+    let target = match target.as_str() {
+        "pubkey" => {
+            ReportTarget::Pubkey(PublicKey::parse(&value).map_err(AdminError::bad_request)?)
+        }
+        "event" => ReportTarget::Event(EventId::parse(&value).map_err(AdminError::bad_request)?),
+        _ => {
+            return Err(AdminError::not_found(format!(
+                "unknown report target '{target}'"
+            )))
+        }
+    };
+    if !queue.dismiss(&target) {
+        return Err(AdminError::not_found("no reports on this target"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct RetentionRequest {
+    /// Scope to prune, the default scope when absent
+    scope: Option<String>,
+    /// Only prune these kinds, all kinds when empty
+    #[serde(default)]
+    kinds: Vec<u16>,
+    /// Delete events created more than this many seconds ago
+    older_than_secs: u64,
+}
+
+async fn run_retention(
+    State(api): State<AdminApi>,
+    Json(request): Json<RetentionRequest>,
+) -> Result<Json<serde_json::Value>, AdminError> {
+    let database = &api.context()?.database;
+    let scope = parse_scope(request.scope.as_deref())?;
+
+    let cutoff = Timestamp::now()
+        .as_u64()
+        .saturating_sub(request.older_than_secs);
+    let mut filter = Filter::new().until(Timestamp::from(cutoff));
+    if !request.kinds.is_empty() {
+        filter = filter.kinds(request.kinds.into_iter().map(Kind::from));
+    }
+
+    let deleted = database.count(vec![filter.clone()], &scope).await?;
+    database.delete(filter, &scope).await?;
+    Ok(Json(serde_json::json!({ "deleted": deleted })))
+}
+
+#[derive(Debug, Deserialize)]
+struct PurgeParams {
+    /// Also purge events tagging the pubkey
+    #[serde(default)]
+    include_tagged: bool,
+}
+
+async fn purge_pubkey(
+    State(api): State<AdminApi>,
+    Path(pubkey): Path<String>,
+    Query(params): Query<PurgeParams>,
+) -> Result<Json<serde_json::Value>, AdminError> {
+    let pubkey = PublicKey::parse(&pubkey).map_err(AdminError::bad_request)?;
+    let database = &api.context()?.database;
+
+    // Refuse re-ingestion before purging, so nothing slips in between
+    moderation(&api)?
+        .tombstone_pubkey(pubkey, params.include_tagged)
+        .await?;
+
+    let mut scopes = database.list_scopes().await?;
+    if !scopes.contains(&Scope::Default) {
+        scopes.push(Scope::Default);
+    }
+    let (mut authored, mut tagged) = (0, 0);
+    for scope in &scopes {
+        authored += database.purge_author(&pubkey, scope).await?;
+        if params.include_tagged {
+            tagged += database.purge_mentions(&pubkey, scope).await?;
+        }
+    }
+    tracing::info!(
+        "Purged {} authored and {} tagging events of {} from {} scope(s)",
+        authored,
+        tagged,
+        pubkey,
+        scopes.len()
+    );
+    Ok(Json(serde_json::json!({
+        "scopes": scopes.len(),
+        "authored": authored,
+        "tagged": tagged,
+    })))
+}
+
+async fn list_scopes(State(api): State<AdminApi>) -> Result<Json<Vec<String>>, AdminError> {
+    let scopes = api.context()?.database.list_scopes().await?;
+    Ok(Json(scopes.iter().filter_map(scope_name).collect()))
+}
+
+async fn delete_scope(
+    State(api): State<AdminApi>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let scope = parse_scope(Some(&name))?;
+    api.context()?.database.delete_scope(&scope).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct MigrateScopeRequest {
+    /// Scope to move connections from, the default scope when absent
+    from: Option<String>,
+    /// Scope to move connections to, the default scope when absent
+    to: Option<String>,
+    /// Disconnect the connections instead of migrating them
+    #[serde(default)]
+    disconnect: bool,
+}
+
+async fn migrate_scope(
+    State(api): State<AdminApi>,
+    Json(request): Json<MigrateScopeRequest>,
+) -> Result<Json<serde_json::Value>, AdminError> {
+    let from = parse_scope(request.from.as_deref())?;
+    let to = parse_scope(request.to.as_deref())?;
+    let migration = if request.disconnect {
+        ScopeMigration::Disconnect
+    } else {
+        ScopeMigration::Migrate
+    };
+
+    let affected = api.context()?.registry.migrate_scope(&from, &to, migration);
+    Ok(Json(serde_json::json!({ "affected": affected })))
+}
+
+fn tenants(api: &AdminApi) -> Result<&TenantStore, AdminError> {
+    api.context()?
+        .tenants
+        .as_ref()
+        .ok_or_else(|| AdminError::not_found("tenants are not enabled"))
+}
+
+async fn list_tenants(
+    State(api): State<AdminApi>,
+) -> Result<Json<BTreeMap<String, Tenant>>, AdminError> {
+    Ok(Json(tenants(&api)?.tenants()))
+}
+
+async fn create_tenant(
+    State(api): State<AdminApi>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    tenants(&api)?.create(&name).await?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn delete_tenant(
+    State(api): State<AdminApi>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, AdminError> {
+    let disconnected = tenants(&api)?.delete(&name).await?;
+    Ok(Json(serde_json::json!({ "disconnected": disconnected })))
+}
+
+async fn disable_tenant(
+    State(api): State<AdminApi>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, AdminError> {
+    let disconnected = tenants(&api)?.disable(&name).await?;
+    Ok(Json(serde_json::json!({ "disconnected": disconnected })))
+}
+
+async fn enable_tenant(
+    State(api): State<AdminApi>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    tenants(&api)?.enable(&name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RelayStats {
+    connections: usize,
+    active_subscriptions: usize,
+    messages_in: u64,
+    bytes_in: u64,
+    messages_out: u64,
+    bytes_out: u64,
+    events_accepted: u64,
+    events_rejected: u64,
+    slow_queries: usize,
+}
+
+async fn stats(State(api): State<AdminApi>) -> Result<Json<RelayStats>, AdminError> {
+    let context = api.context()?;
+    let mut stats = RelayStats {
+        slow_queries: context
+            .slow_query_log
+            .as_ref()
+            .map_or(0, |log| log.entries().len()),
+        ..Default::default()
+    };
+
+    for connection in context.registry.connections_snapshot() {
+        stats.connections += 1;
+        stats.active_subscriptions += connection.active_subscriptions;
+        stats.messages_in += connection.messages_in;
+        stats.bytes_in += connection.bytes_in;
+        stats.messages_out += connection.messages_out;
+        stats.bytes_out += connection.bytes_out;
+        stats.events_accepted += connection.events_accepted;
+        stats.events_rejected += connection.events_rejected;
+    }
+    Ok(Json(stats))
+}
+
+#[derive(Debug, Serialize)]
+struct ScopeStatsView {
+    scope: Option<String>,
+    #[serde(flatten)]
+    activity: ScopeActivity,
+    #[serde(flatten)]
+    storage: ScopeStorageStats,
+}
+
+async fn scope_stats_view(
+    context: &AdminContext,
+    scope: Scope,
+    activity: &mut HashMap<Scope, ScopeActivity>,
+) -> Result<ScopeStatsView, AdminError> {
+    Ok(ScopeStatsView {
+        storage: context.database.scope_stats(&scope).await?,
+        activity: activity.remove(&scope).unwrap_or_default(),
+        scope: scope_name(&scope),
+    })
+}
+
+async fn all_scope_stats(
+    State(api): State<AdminApi>,
+) -> Result<Json<Vec<ScopeStatsView>>, AdminError> {
+    let context = api.context()?;
+    let mut activity = context.registry.scope_activity();
+
+    let mut scopes = context.database.list_scopes().await?;
+    if !scopes.contains(&Scope::Default) {
+        scopes.insert(0, Scope::Default);
+    }
+    for scope in activity.keys() {
+        if !scopes.contains(scope) {
+            scopes.push(scope.clone());
+        }
+    }
+
+    let mut views = Vec::with_capacity(scopes.len());
+    for scope in scopes {
+        views.push(scope_stats_view(context, scope, &mut activity).await?);
+    }
+    Ok(Json(views))
+}
+
+async fn one_scope_stats(
+    State(api): State<AdminApi>,
+    Path(name): Path<String>,
+) -> Result<Json<ScopeStatsView>, AdminError> {
+    let context = api.context()?;
+    let scope = parse_scope(Some(&name))?;
+    let mut activity = context.registry.scope_activity();
+    Ok(Json(scope_stats_view(context, scope, &mut activity).await?))
+}
+
+#[derive(Debug, Serialize)]
+struct SlowQueryView {
+    connection_id: String,
+    subscription_id: SubscriptionId,
+    filters: Vec<Filter>,
+    scope: Option<String>,
+    duration_ms: u128,
+    pagination_attempts: usize,
+    events_scanned: usize,
+    events_sent: usize,
+    events_filtered: usize,
+    recorded_at: u64,
+}
+
+impl From<SlowQuery> for SlowQueryView {
+    fn from(query: SlowQuery) -> Self {
+        Self {
+            connection_id: query.connection_id,
+            subscription_id: query.subscription_id,
+            filters: query.filters,
+            scope: scope_name(&query.scope),
+            duration_ms: query.duration.as_millis(),
+            pagination_attempts: query.pagination_attempts,
+            events_scanned: query.events_scanned,
+            events_sent: query.events_sent,
+            events_filtered: query.events_filtered,
+            recorded_at: query.recorded_at.as_u64(),
+        }
+    }
+}
+
+async fn slow_queries(State(api): State<AdminApi>) -> Result<Json<Vec<SlowQueryView>>, AdminError> {
+    let log = api
+        .context()?
+        .slow_query_log
+        .as_ref()
+        .ok_or_else(|| AdminError::not_found("slow query log is not enabled"))?;
+    Ok(Json(
+        log.entries().into_iter().map(SlowQueryView::from).collect(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditParams {
+    since: Option<u64>,
+    until: Option<u64>,
+    pubkey: Option<String>,
+    connection: Option<String>,
+    scope: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn audit_reqs(
+    State(api): State<AdminApi>,
+    Query(params): Query<AuditParams>,
+) -> Result<Json<Vec<ReqAuditEntry>>, AdminError> {
+    let log = api
+        .context()?
+        .audit_log
+        .as_ref()
+        .ok_or_else(|| AdminError::not_found("audit log is not enabled"))?;
+    let auth_pubkey = params
+        .pubkey
+        .as_deref()
+        .map(PublicKey::parse)
+        .transpose()
+        .map_err(AdminError::bad_request)?;
+    let query = AuditQuery {
+        since: params.since.map(Timestamp::from),
+        until: params.until.map(Timestamp::from),
+        auth_pubkey,
+        connection_id: params.connection,
+        scope: params.scope,
+        limit: params.limit,
+    };
+    Ok(Json(log.query(&query).await?))
+}
+
+async fn event_provenance(
+    State(api): State<AdminApi>,
+    Path(id): Path<String>,
+) -> Result<Json<EventProvenance>, AdminError> {
+    let store = api
+        .context()?
+        .provenance
+        .as_ref()
+        .ok_or_else(|| AdminError::not_found("provenance is not recorded"))?;
+    let event_id = EventId::parse(&id).map_err(AdminError::bad_request)?;
+    store
+        .get(&event_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| AdminError::not_found("no provenance recorded for this event"))
+}
+
+async fn task_health(
+    State(api): State<AdminApi>,
+) -> Result<Json<BTreeMap<String, TaskHealth>>, AdminError> {
+    Ok(Json(api.context()?.task_supervisor.health()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+    use axum::body::Body;
+    use tower::ServiceExt;
+    use websocket_builder::MessageSender;
+
+    fn admin_request(path: &str, token: Option<&str>) -> Request {
+        let mut request = axum::http::Request::builder().uri(path);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requires_token_and_lists_connections() {
+        let (_tmp_dir, database, _keys) = setup_test_with_database().await;
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let api = AdminApi::new("secret");
+
+        // Not attached to a relay yet
+        let response = api
+            .router()
+            .oneshot(admin_request("/connections", Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        api.attach(AdminContext {
+            registry: registry.clone(),
+            database,
+            moderation: None,
+            reports: None,
+            slow_query_log: None,
+            audit_log: None,
+            provenance: None,
+            tenants: None,
+            task_supervisor: TaskSupervisor::new(),
+        });
+        let (tx, _rx) = flume::bounded(10);
+        let _handle = registry.register_connection(
+            "conn1".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+
+        for token in [None, Some("wrong")] {
+            let response = api
+                .router()
+                .oneshot(admin_request("/connections", token))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = api
+            .router()
+            .oneshot(admin_request("/connections", Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let connections: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(connections[0]["connection_id"], "conn1");
+
+        let response = api
+            .router()
+            .oneshot(admin_request("/moderation", Some("secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_nip98_admins() {
+        let admin = Keys::generate();
+        let api = AdminApi::new("secret")
+            .with_nip98_admins("https://relay.example.com/admin/", [admin.public_key()]);
+        let url = "https://relay.example.com/admin/slow-queries";
+
+        for (keys, status) in [
+            (&admin, StatusCode::SERVICE_UNAVAILABLE),
+            (&Keys::generate(), StatusCode::FORBIDDEN),
+        ] {
+            let authorization =
+                crate::http_auth::authorization_header(keys, url, "GET", None).unwrap();
+            let request = axum::http::Request::builder()
+                .uri("/slow-queries")
+                .header(header::AUTHORIZATION, authorization)
+                .body(Body::empty())
+                .unwrap();
+            let response = api.router().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status);
+        }
+
+        let body = vec![b' '; MAX_AUTHENTICATED_BODY + 1];
+        let url = "https://relay.example.com/admin/moderation/banned-pubkeys/x";
+        let authorization =
+            crate::http_auth::authorization_header(&admin, url, "POST", Some(&body)).unwrap();
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/moderation/banned-pubkeys/x")
+            .header(header::AUTHORIZATION, authorization)
+            .body(Body::from(body))
+            .unwrap();
+        let response = api.router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Bodies must be covered, and an authorization only serves once
+        let authorization =
+            crate::http_auth::authorization_header(&admin, url, "POST", None).unwrap();
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/moderation/banned-pubkeys/x")
+            .header(header::AUTHORIZATION, authorization)
+            .body(Body::empty())
+            .unwrap();
+        let response = api.router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let authorization = crate::http_auth::authorization_header(
+            &admin,
+            "https://relay.example.com/admin/connections",
+            "GET",
+            None,
+        )
+        .unwrap();
+        for status in [StatusCode::SERVICE_UNAVAILABLE, StatusCode::UNAUTHORIZED] {
+            let request = axum::http::Request::builder()
+                .uri("/connections")
+                .header(header::AUTHORIZATION, authorization.clone())
+                .body(Body::empty())
+                .unwrap();
+            let response = api.router().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
+}
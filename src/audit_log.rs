@@ -0,0 +1,389 @@
+//! Persistent audit log of REQs
+//!
+//! [`AuditLog`] records every REQ a client sends, with its connection,
+//! authenticated pubkey, subscription id, filters, scope and time, for abuse
+//! investigations and the compliance needs of hosted relays. Entries are
+//! appended as JSON lines to one file per UTC day in the log's directory,
+//! e.g. `reqs-2026-10-16.jsonl`. Files are never rewritten: whole days are
+//! deleted once older than the retention, 30 days by default.
+//!
+//! REQs don't wait on the disk: entries are queued and written by a
+//! background task the relay spawns, and are dropped with a warning when the
+//! queue is full. [`AuditLog::query`] reads entries back by time range,
+//! pubkey, connection or scope; the admin API serves it at `GET /audit/reqs`.
+
+use crate::error::{Error, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+/// Entries returned by a query without a limit
+pub const DEFAULT_AUDIT_QUERY_LIMIT: usize = 500;
+
+/// Entries waiting to be written before new ones are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// Prefix of the log's file names
+const FILE_PREFIX: &str = "reqs";
+
+/// Time between deletions of expired files
+pub(crate) const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// One REQ as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReqAuditEntry {
+    pub recorded_at: Timestamp,
+    pub connection_id: String,
+    /// Pubkey the client authenticated as with NIP-42, if any
+    pub auth_pubkey: Option<PublicKey>,
+    pub subscription_id: SubscriptionId,
+    /// Filters as the client sent them
+    pub filters: Vec<Filter>,
+    /// Name of the scope, `None` for the default scope
+    pub scope: Option<String>,
+}
+
+impl ReqAuditEntry {
+    /// Entry of a REQ received now
+    pub fn new(
+        connection_id: impl Into<String>,
+        auth_pubkey: Option<PublicKey>,
+        subscription_id: SubscriptionId,
+        filters: Vec<Filter>,
+        scope: &Scope,
+    ) -> Self {
+        Self {
+            recorded_at: Timestamp::now(),
+            connection_id: connection_id.into(),
+            auth_pubkey,
+            subscription_id,
+            filters,
+            scope: match scope {
+                Scope::Named { name, .. } => Some(name.to_string()),
+                Scope::Default => None,
+            },
+        }
+    }
+}
+
+/// Entries to read back from the audit log; every condition set must hold
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditQuery {
+    pub since: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+    pub auth_pubkey: Option<PublicKey>,
+    pub connection_id: Option<String>,
+    /// Name of the scope, `None` matches every scope
+    pub scope: Option<String>,
+    /// At most this many entries, [`DEFAULT_AUDIT_QUERY_LIMIT`] when absent
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &ReqAuditEntry) -> bool {
+        self.since.is_none_or(|since| entry.recorded_at >= since)
+            && self.until.is_none_or(|until| entry.recorded_at <= until)
+            && self
+                .auth_pubkey
+                .is_none_or(|pubkey| entry.auth_pubkey == Some(pubkey))
+            && self
+                .connection_id
+                .as_ref()
+                .is_none_or(|id| &entry.connection_id == id)
+            && self
+                .scope
+                .as_ref()
+                .is_none_or(|scope| entry.scope.as_ref() == Some(scope))
+    }
+}
+
+/// Append-only log of REQs in daily JSON lines files
+///
+/// Keep a clone to query the entries the relay records.
+#[derive(Clone)]
+pub struct AuditLog {
+    dir: Arc<PathBuf>,
+    retention: Duration,
+    queue: flume::Sender<ReqAuditEntry>,
+    receiver: flume::Receiver<ReqAuditEntry>,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("dir", &self.dir)
+            .field("retention", &self.retention)
+            .field("queued", &self.queue.len())
+            .finish()
+    }
+}
+
+impl AuditLog {
+    /// Log to files in `dir`, created when missing, kept for 30 days
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            Error::internal(format!("Failed to create audit log directory {dir:?}: {e}"))
+        })?;
+        let (queue, receiver) = flume::bounded(QUEUE_CAPACITY);
+        Ok(Self {
+            dir: Arc::new(dir),
+            retention: Duration::from_secs(30 * 24 * 3600),
+            queue,
+            receiver,
+        })
+    }
+
+    /// Delete the files of days older than `retention`
+    #[must_use]
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Queue `entry` for writing, dropping it when the queue is full
+    pub fn record(&self, entry: ReqAuditEntry) {
+        if self.queue.try_send(entry).is_err() {
+            warn!("Audit log queue is full, dropping a REQ entry");
+        }
+    }
+
+    /// Entries matching `query`, newest first
+    ///
+    /// Entries still queued are not returned yet.
+    pub async fn query(&self, query: &AuditQuery) -> Result<Vec<ReqAuditEntry>> {
+        let since = query.since.map(day_of);
+        let until = query.until.map(day_of);
+
+        let mut entries = Vec::new();
+        for (day, path) in daily_files(&self.dir, FILE_PREFIX).await? {
+            if since.is_some_and(|since| day < since) || until.is_some_and(|until| day > until) {
+                continue;
+            }
+            let contents = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| Error::internal(format!("Failed to read audit log {path:?}: {e}")))?;
+            entries.extend(
+                contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<ReqAuditEntry>(line).ok())
+                    .filter(|entry| query.matches(entry)),
+            );
+        }
+
+        entries.sort_by(|a, b| b.recorded_at.cmp(&a.recorded_at));
+        entries.truncate(query.limit.unwrap_or(DEFAULT_AUDIT_QUERY_LIMIT));
+        Ok(entries)
+    }
+
+    /// Delete the files of days older than the retention, returning how many
+    pub async fn prune(&self) -> Result<usize> {
+        prune_daily_files(&self.dir, FILE_PREFIX, self.retention).await
+    }
+
+    /// Write queued entries and prune expired files until `cancellation_token` fires
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+    ) {
+        let log = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let mut writer = DailyWriter::new(Arc::clone(&log.dir), FILE_PREFIX);
+            let mut prune_ticker = tokio::time::interval(PRUNE_INTERVAL);
+            prune_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = prune_ticker.tick() => {
+                        if let Err(e) = log.prune().await {
+                            warn!("Failed to prune audit log: {}", e);
+                        }
+                    }
+                    entry = log.receiver.recv_async() => {
+                        let Ok(entry) = entry else { break };
+                        // Write whatever queued up meanwhile before flushing
+                        let batch = std::iter::once(entry).chain(log.receiver.try_iter());
+                        if let Err(e) = writer.write(batch, |entry| entry.recorded_at).await {
+                            warn!("Failed to write audit log: {}", e);
+                        }
+                    }
+                }
+            }
+            if let Err(e) = writer
+                .write(log.receiver.try_iter(), |entry| entry.recorded_at)
+                .await
+            {
+                warn!("Failed to write audit log: {}", e);
+            }
+            debug!("Audit log writer stopped");
+        });
+    }
+}
+
+/// Files named `<prefix>-YYYY-MM-DD.jsonl` in `dir` with their day
+pub(crate) async fn daily_files(dir: &Path, prefix: &str) -> Result<Vec<(NaiveDate, PathBuf)>> {
+    let read_error = |e: std::io::Error| Error::internal(format!("Failed to list {dir:?}: {e}"));
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(read_error)?;
+    while let Some(entry) = entries.next_entry().await.map_err(read_error)? {
+        let path = entry.path();
+        if let Some(day) = parse_file_name(prefix, &path) {
+            files.push((day, path));
+        }
+    }
+    Ok(files)
+}
+
+/// Delete the daily files of `prefix` older than `retention`, returning how many
+pub(crate) async fn prune_daily_files(
+    dir: &Path,
+    prefix: &str,
+    retention: Duration,
+) -> Result<usize> {
+    let cutoff = day_of(Timestamp::now() - retention.as_secs());
+    let mut deleted = 0;
+    for (day, path) in daily_files(dir, prefix).await? {
+        if day < cutoff {
+            tokio::fs::remove_file(&path)
+                .await
+                .map_err(|e| Error::internal(format!("Failed to delete {path:?}: {e}")))?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Appends entries to the file of their day, switching files at midnight UTC
+pub(crate) struct DailyWriter {
+    dir: Arc<PathBuf>,
+    prefix: &'static str,
+    current: Option<(NaiveDate, tokio::fs::File)>,
+}
+
+impl DailyWriter {
+    pub(crate) fn new(dir: Arc<PathBuf>, prefix: &'static str) -> Self {
+        Self {
+            dir,
+            prefix,
+            current: None,
+        }
+    }
+
+    /// Append `entries` as JSON lines to the files of the days `recorded_at` gives
+    pub(crate) async fn write<T: Serialize>(
+        &mut self,
+        entries: impl Iterator<Item = T>,
+        recorded_at: impl Fn(&T) -> Timestamp,
+    ) -> std::io::Result<()> {
+        for entry in entries {
+            let day = day_of(recorded_at(&entry));
+            if self
+                .current
+                .as_ref()
+                .is_none_or(|(current_day, _)| *current_day != day)
+            {
+                if let Some((_, mut file)) = self.current.take() {
+                    file.flush().await?;
+                }
+                let file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(file_name(self.prefix, day)))
+                    .await?;
+                self.current = Some((day, file));
+            }
+            let (_, file) = self.current.as_mut().expect("file of the day is open");
+            let mut line = serde_json::to_vec(&entry).map_err(std::io::Error::other)?;
+            line.push(b'\n');
+            file.write_all(&line).await?;
+        }
+        if let Some((_, file)) = &mut self.current {
+            file.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// UTC day of `timestamp`
+fn day_of(timestamp: Timestamp) -> NaiveDate {
+    DateTime::<Utc>::from_timestamp(timestamp.as_u64() as i64, 0)
+        .unwrap_or_default()
+        .date_naive()
+}
+
+fn file_name(prefix: &str, day: NaiveDate) -> String {
+    format!("{prefix}-{}.jsonl", day.format("%Y-%m-%d"))
+}
+
+fn parse_file_name(prefix: &str, path: &Path) -> Option<NaiveDate> {
+    let name = path.file_name()?.to_str()?;
+    let day = name
+        .strip_prefix(prefix)?
+        .strip_prefix('-')?
+        .strip_suffix(".jsonl")?;
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_entries_are_written_queried_and_expire() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let log = AuditLog::new(tmp_dir.path()).unwrap();
+        let task_tracker = TaskTracker::new();
+        let cancellation_token = CancellationToken::new();
+        log.spawn(&task_tracker, Some(cancellation_token.clone()));
+
+        let alice = Keys::generate().public_key();
+        let tenant = Scope::named("tenant").unwrap();
+        log.record(ReqAuditEntry::new(
+            "conn-1",
+            Some(alice),
+            SubscriptionId::new("feed"),
+            vec![Filter::new().author(alice)],
+            &tenant,
+        ));
+        log.record(ReqAuditEntry::new(
+            "conn-2",
+            None,
+            SubscriptionId::new("all"),
+            vec![Filter::new()],
+            &Scope::Default,
+        ));
+        cancellation_token.cancel();
+        task_tracker.close();
+        task_tracker.wait().await;
+
+        let all = log.query(&AuditQuery::default()).await.unwrap();
+        assert_eq!(all.len(), 2);
+        let by_alice = log
+            .query(&AuditQuery {
+                auth_pubkey: Some(alice),
+                ..AuditQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_alice.len(), 1);
+        assert_eq!(by_alice[0].connection_id, "conn-1");
+        assert_eq!(by_alice[0].scope.as_deref(), Some("tenant"));
+
+        // Days past the retention are deleted whole
+        let old_day = day_of(Timestamp::now() - 40 * 24 * 3600);
+        std::fs::write(tmp_dir.path().join(file_name(FILE_PREFIX, old_day)), "").unwrap();
+        assert_eq!(log.prune().await.unwrap(), 1);
+        assert_eq!(log.query(&AuditQuery::default()).await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,243 @@
+//! Serialize-once support for event fan-out
+//!
+//! A popular event matching thousands of subscriptions used to be serialized
+//! once per subscription when the outgoing `RelayMessage::Event` was turned
+//! into a WebSocket frame. Distribution now serializes the event a single time
+//! into a [`SerializedEvent`] and remembers it in a small process-wide cache;
+//! [`NostrMessageConverter`](crate::message_converter::NostrMessageConverter)
+//! then only splices the subscription id into the pre-serialized JSON.
+//!
+//! `MessageSender` lives in `websocket_builder` and only carries typed
+//! messages, so the pre-serialized JSON travels through this cache rather than
+//! through the channel itself. [`MessageSenderExt::send_serialized_event`]
+//! wraps both steps for code that sends events outside of distribution.
+//!
+//! Frames are also kept as [`Bytes`] per subscription id, so the connections
+//! of clients using the same subscription id share one buffer when framed
+//! with [`NostrMessageConverter::outbound_to_bytes`](crate::message_converter::NostrMessageConverter::outbound_to_bytes).
+
+use bytes::Bytes;
+use lru::LruCache;
+use nostr_sdk::prelude::*;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use websocket_builder::MessageSender;
+
+/// Number of recently broadcast events whose JSON is kept for framing
+const SERIALIZED_EVENTS_CAPACITY: usize = 4096;
+
+/// Number of subscription ids whose frames are kept per event
+const FRAMES_PER_EVENT: usize = 16;
+
+/// Frames already built for an event, by subscription id
+type Frames = Arc<Mutex<Vec<(SubscriptionId, Bytes)>>>;
+
+static SERIALIZED_EVENTS: Lazy<RwLock<LruCache<EventId, (Arc<str>, Frames)>>> = Lazy::new(|| {
+    RwLock::new(LruCache::new(
+        NonZeroUsize::new(SERIALIZED_EVENTS_CAPACITY).expect("capacity is non-zero"),
+    ))
+});
+
+/// An event serialized to JSON once, shared by every subscription it is sent to
+#[derive(Debug, Clone)]
+pub struct SerializedEvent {
+    id: EventId,
+    json: Arc<str>,
+    frames: Frames,
+}
+
+impl SerializedEvent {
+    /// Serialize `event`, reusing the JSON of a recent broadcast of the same event
+    pub fn new(event: &Event) -> Self {
+        if let Some(serialized) = cached_event(&event.id) {
+            return serialized;
+        }
+
+        let serialized = Self {
+            id: event.id,
+            json: Arc::from(event.as_json()),
+            frames: Frames::default(),
+        };
+        serialized.cache();
+        serialized
+    }
+
+    fn cache(&self) {
+        SERIALIZED_EVENTS
+            .write()
+            .put(self.id, (Arc::clone(&self.json), Arc::clone(&self.frames)));
+    }
+
+    /// Id of the serialized event
+    pub fn id(&self) -> &EventId {
+        &self.id
+    }
+
+    /// The event's JSON
+    pub fn json(&self) -> &str {
+        &self.json
+    }
+
+    /// Full `["EVENT", <subscription_id>, <event>]` frame for one subscription
+    pub fn frame(&self, subscription_id: &SubscriptionId) -> String {
+        event_frame(subscription_id, &self.json)
+    }
+
+    /// Same frame as [`Self::frame`], shared with every connection framing it
+    /// for the same subscription id
+    pub fn frame_bytes(&self, subscription_id: &SubscriptionId) -> Bytes {
+        let mut frames = self.frames.lock();
+        if let Some((_, frame)) = frames.iter().find(|(id, _)| id == subscription_id) {
+            return frame.clone();
+        }
+
+        let frame = Bytes::from(self.frame(subscription_id));
+        if frames.len() < FRAMES_PER_EVENT {
+            frames.push((subscription_id.clone(), frame.clone()));
+        }
+        frame
+    }
+}
+
+/// A recently broadcast event, if still cached
+pub(crate) fn cached_event(id: &EventId) -> Option<SerializedEvent> {
+    SERIALIZED_EVENTS
+        .read()
+        .peek(id)
+        .map(|(json, frames)| SerializedEvent {
+            id: *id,
+            json: Arc::clone(json),
+            frames: Arc::clone(frames),
+        })
+}
+
+/// JSON of a recently broadcast event, if still cached
+pub(crate) fn cached_event_json(id: &EventId) -> Option<Arc<str>> {
+    SERIALIZED_EVENTS
+        .read()
+        .peek(id)
+        .map(|(json, _)| Arc::clone(json))
+}
+
+/// Build an EVENT frame from pre-serialized event JSON
+pub(crate) fn event_frame(subscription_id: &SubscriptionId, event_json: &str) -> String {
+    let subscription_id =
+        serde_json::to_string(subscription_id.as_str()).expect("serializing a string cannot fail");
+
+    let mut frame = String::with_capacity(12 + subscription_id.len() + event_json.len());
+    frame.push_str(r#"["EVENT","#);
+    frame.push_str(&subscription_id);
+    frame.push(',');
+    frame.push_str(event_json);
+    frame.push(']');
+    frame
+}
+
+/// Length of the frame `message` is sent as
+///
+/// Events are serialized through the cache, so the converter reuses the JSON.
+pub(crate) fn relay_message_len(message: &RelayMessage<'_>) -> usize {
+    match message {
+        // ["EVENT","<subscription_id>",<event>]
+        RelayMessage::Event {
+            subscription_id,
+            event,
+        } => 13 + subscription_id.as_str().len() + SerializedEvent::new(event).json().len(),
+        _ => message.as_json().len(),
+    }
+}
+
+/// Length of `message` as JSON
+///
+/// Events are serialized through the cache, so distributing them reuses the JSON.
+pub(crate) fn client_message_len(message: &ClientMessage<'_>) -> usize {
+    match message {
+        // ["EVENT",<event>]
+        ClientMessage::Event(event) => 10 + SerializedEvent::new(event).json().len(),
+        _ => message.as_json().len(),
+    }
+}
+
+/// Sending pre-serialized events through a [`MessageSender`]
+pub trait MessageSenderExt {
+    /// Send `event` to `subscription_id`, framed from its pre-serialized JSON
+    fn send_serialized_event(
+        &mut self,
+        subscription_id: SubscriptionId,
+        event: &Event,
+        serialized: &SerializedEvent,
+    ) -> anyhow::Result<()>;
+}
+
+impl MessageSenderExt for MessageSender<RelayMessage<'static>> {
+    fn send_serialized_event(
+        &mut self,
+        subscription_id: SubscriptionId,
+        event: &Event,
+        serialized: &SerializedEvent,
+    ) -> anyhow::Result<()> {
+        debug_assert_eq!(event.id, serialized.id);
+
+        // Keep the JSON cached for the converter, even if it was evicted meanwhile
+        if cached_event_json(&serialized.id).is_none() {
+            serialized.cache();
+        }
+
+        self.send(RelayMessage::event(subscription_id, event.clone()))
+            .map_err(|e| anyhow::anyhow!("Failed to send event: {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_matches_relay_message_json() {
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("with \"quotes\" and \\ escapes")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let subscription_id = SubscriptionId::new("sub \"1\"");
+
+        let serialized = SerializedEvent::new(&event);
+        let frame = serialized.frame(&subscription_id);
+        let expected = RelayMessage::event(subscription_id, event.clone()).as_json();
+
+        let frame: serde_json::Value = serde_json::from_str(&frame).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(&expected).unwrap();
+        assert_eq!(frame, expected);
+    }
+
+    #[test]
+    fn test_serialized_once_per_event() {
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("popular")
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        let first = SerializedEvent::new(&event);
+        let second = SerializedEvent::new(&event);
+        assert!(Arc::ptr_eq(&first.json, &second.json));
+        assert_eq!(cached_event_json(&event.id).as_deref(), Some(first.json()));
+    }
+
+    #[test]
+    fn test_frame_bytes_shared_per_subscription_id() {
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("shared")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let subscription_id = SubscriptionId::new("feed");
+
+        let first = SerializedEvent::new(&event).frame_bytes(&subscription_id);
+        let second = SerializedEvent::new(&event).frame_bytes(&subscription_id);
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        assert_eq!(first, SerializedEvent::new(&event).frame(&subscription_id));
+
+        let other = SerializedEvent::new(&event).frame_bytes(&SubscriptionId::new("other"));
+        assert_ne!(first.as_ptr(), other.as_ptr());
+    }
+}
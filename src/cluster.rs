@@ -0,0 +1,387 @@
+//! Sharing live events between relay nodes
+//!
+//! Several instances of a relay can serve the same clients behind a load
+//! balancer. Without clustering, a subscription on node A never sees an event
+//! published on node B. A [`Cluster`] gossips every event a node stores to the
+//! other nodes over a [`ClusterTransport`], and distributes the events it
+//! receives to its local subscriptions.
+//!
+//! Nodes either share a storage backend ([`ClusterStorage::Shared`]), in which
+//! case received events are only distributed, or keep their own storage
+//! ([`ClusterStorage::PerNode`]), in which case they are stored first. The
+//! transport is anything that can broadcast messages, e.g. NATS, Redis pub/sub
+//! or plain TCP; [`ClusterMessage::to_json`] and [`ClusterMessage::from_json`]
+//! give it a wire format. [`LocalTransport`] connects nodes within a process.
+
+use crate::database::RelayDatabase;
+use crate::error::{Error, Result};
+use crate::event_sink::EventSink;
+use crate::subscription_registry::{EventDistributor, SubscriptionRegistry};
+use async_trait::async_trait;
+use lru::LruCache;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// Default number of events waiting to be gossiped
+pub const DEFAULT_CLUSTER_QUEUE_SIZE: usize = 10_000;
+
+/// Number of recently received events that are not gossiped back
+const RECEIVED_CAPACITY: usize = 10_000;
+
+/// An event stored by one node, gossiped to the others
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMessage {
+    /// Node the event was stored on
+    pub node_id: String,
+    pub scope: Scope,
+    pub event: Event,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireMessage {
+    node_id: String,
+    /// Name of the scope, `None` for the default scope
+    scope: Option<String>,
+    event: Event,
+}
+
+impl ClusterMessage {
+    /// Encode the message as JSON
+    pub fn to_json(&self) -> String {
+        let wire = WireMessage {
+            node_id: self.node_id.clone(),
+            scope: match &self.scope {
+                Scope::Named { name, .. } => Some(name.to_string()),
+                Scope::Default => None,
+            },
+            event: self.event.clone(),
+        };
+        serde_json::to_string(&wire).expect("cluster messages always serialize")
+    }
+
+    /// Decode a message encoded with [`Self::to_json`]
+    pub fn from_json(json: &str) -> Result<Self> {
+        let wire: WireMessage = serde_json::from_str(json)
+            .map_err(|e| Error::invalid(format!("Invalid cluster message: {e}")))?;
+        let scope = match wire.scope {
+            Some(name) => Scope::named(&name)
+                .map_err(|e| Error::invalid(format!("Invalid cluster scope '{name}': {e}")))?,
+            None => Scope::Default,
+        };
+        Ok(Self {
+            node_id: wire.node_id,
+            scope,
+            event: wire.event,
+        })
+    }
+}
+
+/// Broadcasts messages between the nodes of a cluster
+#[async_trait]
+pub trait ClusterTransport: Send + Sync + std::fmt::Debug {
+    /// Send `message` to every node, this one included or not
+    async fn publish(&self, message: &ClusterMessage) -> Result<()>;
+
+    /// Start receiving the messages published by the nodes
+    async fn subscribe(&self) -> Result<flume::Receiver<ClusterMessage>>;
+}
+
+/// Transport between nodes running in the same process
+///
+/// Clones are connected to each other.
+#[derive(Debug, Clone, Default)]
+pub struct LocalTransport {
+    subscribers: Arc<Mutex<Vec<flume::Sender<ClusterMessage>>>>,
+}
+
+impl LocalTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ClusterTransport for LocalTransport {
+    async fn publish(&self, message: &ClusterMessage) -> Result<()> {
+        self.subscribers
+            .lock()
+            .retain(|subscriber| subscriber.send(message.clone()).is_ok());
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<flume::Receiver<ClusterMessage>> {
+        let (tx, rx) = flume::unbounded();
+        self.subscribers.lock().push(tx);
+        Ok(rx)
+    }
+}
+
+/// Where the nodes of a cluster store their events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClusterStorage {
+    /// All nodes use the same backend, received events are already stored
+    #[default]
+    Shared,
+    /// Every node has its own storage, received events are stored too
+    PerNode,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Counters of a cluster node since it was started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClusterStats {
+    /// Events gossiped to the other nodes
+    pub sent: u64,
+    /// Events received from the other nodes
+    pub received: u64,
+    /// Events not gossiped because the queue was full or the transport failed
+    pub dropped: u64,
+}
+
+/// This node's membership in a cluster
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    node_id: String,
+    transport: Arc<dyn ClusterTransport>,
+    storage: ClusterStorage,
+    outbound: flume::Sender<ClusterMessage>,
+    outbound_queue: flume::Receiver<ClusterMessage>,
+    /// Events received from other nodes, so they are not gossiped back
+    received: Arc<Mutex<LruCache<EventId, ()>>>,
+    counters: Arc<Counters>,
+}
+
+impl Cluster {
+    /// Join the cluster reachable over `transport` as `node_id`
+    ///
+    /// Node ids must be unique within the cluster.
+    pub fn new(node_id: impl Into<String>, transport: impl ClusterTransport + 'static) -> Self {
+        let (outbound, outbound_queue) = flume::bounded(DEFAULT_CLUSTER_QUEUE_SIZE);
+        Self {
+            node_id: node_id.into(),
+            transport: Arc::new(transport),
+            storage: ClusterStorage::default(),
+            outbound,
+            outbound_queue,
+            received: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(RECEIVED_CAPACITY).expect("capacity is non-zero"),
+            ))),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// How the nodes store their events, shared by default
+    #[must_use]
+    pub fn with_storage(mut self, storage: ClusterStorage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Id of this node
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Counters since the node was started
+    pub fn stats(&self) -> ClusterStats {
+        ClusterStats {
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            received: self.counters.received.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Start gossiping stored events and distributing received ones through `registry`
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+        database: Arc<RelayDatabase>,
+        registry: Arc<SubscriptionRegistry>,
+    ) {
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        let cluster = self.clone();
+        let token = cancellation_token.clone();
+        task_tracker.spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    _ = token.cancelled() => break,
+                    message = cluster.outbound_queue.recv_async() => match message {
+                        Ok(message) => message,
+                        Err(_) => break,
+                    },
+                };
+                match cluster.transport.publish(&message).await {
+                    Ok(()) => cluster.counters.sent.fetch_add(1, Ordering::Relaxed),
+                    Err(e) => {
+                        warn!("Failed to gossip event {}: {}", message.event.id, e);
+                        cluster.counters.dropped.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+            }
+        });
+
+        let cluster = self.clone();
+        task_tracker.spawn(async move {
+            let inbound = match cluster.transport.subscribe().await {
+                Ok(inbound) => inbound,
+                Err(e) => {
+                    warn!("Failed to join the cluster: {}", e);
+                    return;
+                }
+            };
+            info!("Node {} joined the cluster", cluster.node_id);
+
+            loop {
+                let message = tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    message = inbound.recv_async() => match message {
+                        Ok(message) => message,
+                        Err(_) => break,
+                    },
+                };
+                if message.node_id != cluster.node_id {
+                    cluster.receive(message, &database, &registry).await;
+                }
+            }
+            debug!("Node {} left the cluster", cluster.node_id);
+        });
+    }
+
+    /// Store `message`'s event if needed and distribute it to local subscriptions
+    async fn receive(
+        &self,
+        message: ClusterMessage,
+        database: &RelayDatabase,
+        registry: &SubscriptionRegistry,
+    ) {
+        let ClusterMessage { scope, event, .. } = message;
+
+        if self.storage == ClusterStorage::PerNode {
+            match database.has_event(&event.id, &scope).await {
+                Ok(false) => {}
+                Ok(true) => return,
+                Err(e) => {
+                    warn!("Failed to check for gossiped event {}: {}", event.id, e);
+                    return;
+                }
+            }
+            if let Err(e) = database.save_event(&event, &scope).await {
+                warn!("Failed to save gossiped event {}: {}", event.id, e);
+                return;
+            }
+        }
+
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        self.received.lock().put(event.id, ());
+        registry.distribute_event(Arc::new(event), &scope).await;
+    }
+}
+
+#[async_trait]
+impl EventSink for Cluster {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        // Events from other nodes are distributed here too, don't send them back
+        if self.received.lock().pop(&event.id).is_some() {
+            return;
+        }
+        let message = ClusterMessage {
+            node_id: self.node_id.clone(),
+            scope: scope.clone(),
+            event: Event::clone(&event),
+        };
+        if self.outbound.try_send(message).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+    use std::time::Duration;
+    use websocket_builder::MessageSender;
+
+    #[tokio::test]
+    async fn test_events_are_gossiped_to_other_nodes() {
+        let (_tmp_a, database_a, keys) = setup_test_with_database().await;
+        let (_tmp_b, database_b, _) = setup_test_with_database().await;
+        let transport = LocalTransport::new();
+        let task_tracker = TaskTracker::new();
+
+        let node_a = Cluster::new("a", transport.clone()).with_storage(ClusterStorage::PerNode);
+        let registry_a =
+            Arc::new(SubscriptionRegistry::new(None).with_sink(Arc::new(node_a.clone())));
+        node_a.spawn(&task_tracker, None, database_a, registry_a.clone());
+
+        let node_b = Cluster::new("b", transport).with_storage(ClusterStorage::PerNode);
+        let registry_b =
+            Arc::new(SubscriptionRegistry::new(None).with_sink(Arc::new(node_b.clone())));
+        node_b.spawn(&task_tracker, None, database_b.clone(), registry_b.clone());
+
+        let (tx, rx) = flume::bounded(10);
+        let _handle = registry_b.register_connection(
+            "conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+        registry_b
+            .add_subscription("conn", SubscriptionId::new("live"), vec![Filter::new()])
+            .unwrap();
+        // Let node b subscribe to the transport
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let event = EventBuilder::text_note("from a")
+            .sign_with_keys(&keys)
+            .unwrap();
+        registry_a
+            .distribute_event(Arc::new(event.clone()), &Scope::Default)
+            .await;
+
+        let (message, _) = tokio::time::timeout(Duration::from_secs(2), rx.recv_async())
+            .await
+            .unwrap()
+            .unwrap();
+        match message {
+            RelayMessage::Event {
+                event: received, ..
+            } => assert_eq!(received.id, event.id),
+            other => panic!("Expected Event message, got {other:?}"),
+        }
+        assert!(database_b
+            .has_event(&event.id, &Scope::Default)
+            .await
+            .unwrap());
+        assert_eq!(node_b.stats().received, 1);
+        // Node b does not gossip the event back
+        assert_eq!(node_b.stats().sent, 0);
+
+        let decoded = ClusterMessage::from_json(
+            &ClusterMessage {
+                node_id: "a".to_string(),
+                scope: Scope::named("tenant").unwrap(),
+                event,
+            }
+            .to_json(),
+        )
+        .unwrap();
+        assert_eq!(decoded.scope, Scope::named("tenant").unwrap());
+    }
+}
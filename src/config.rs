@@ -5,6 +5,8 @@ use crate::database::RelayDatabase;
 use crate::error::Error;
 use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
 /// Configuration for scope/subdomain handling
@@ -68,15 +70,212 @@ pub struct WebSocketConfig {
     pub max_connections: Option<usize>,
     /// Maximum connection time in seconds
     pub max_connection_time: Option<u64>,
+    /// Largest client message, in bytes, the relay attempts to parse
+    pub max_message_size: Option<usize>,
 }
 
-/// Database configuration - either a path or an existing database instance
+/// Structural limits on incoming events
+///
+/// Checked before the signature is verified, so oversized events are refused
+/// without spending CPU on them. All limits are unset by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventLimits {
+    /// Maximum size of the JSON-serialized event in bytes
+    pub max_event_size: Option<usize>,
+    /// Maximum number of tags
+    pub max_tags: Option<usize>,
+    /// Maximum length in bytes of any single tag value
+    pub max_tag_value_length: Option<usize>,
+    /// Maximum content length in bytes, for kinds without their own limit
+    pub max_content_length: Option<usize>,
+    /// Maximum content length in bytes per kind
+    pub kind_content_length: HashMap<Kind, usize>,
+    /// Ranges of accepted kinds, every kind is accepted when empty
+    pub allowed_kinds: Vec<RangeInclusive<u16>>,
+}
+
+impl EventLimits {
+    /// No limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with_max_event_size(mut self, bytes: usize) -> Self {
+        self.max_event_size = Some(bytes);
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_tags(mut self, tags: usize) -> Self {
+        self.max_tags = Some(tags);
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_tag_value_length(mut self, bytes: usize) -> Self {
+        self.max_tag_value_length = Some(bytes);
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_content_length(mut self, bytes: usize) -> Self {
+        self.max_content_length = Some(bytes);
+        self
+    }
+
+    /// Limit the content of `kind` to `bytes`, overriding `max_content_length`
+    #[must_use]
+    pub fn with_kind_content_length(mut self, kind: Kind, bytes: usize) -> Self {
+        self.kind_content_length.insert(kind, bytes);
+        self
+    }
+
+    /// Only accept kinds within `ranges`, e.g. `[0..=9999, 30000..=39999]`
+    #[must_use]
+    pub fn with_allowed_kinds<I>(mut self, ranges: I) -> Self
+    where
+        I: IntoIterator<Item = RangeInclusive<u16>>,
+    {
+        self.allowed_kinds.extend(ranges);
+        self
+    }
+
+    /// Whether events of `kind` are accepted
+    pub fn allows_kind(&self, kind: Kind) -> bool {
+        self.allowed_kinds.is_empty()
+            || self
+                .allowed_kinds
+                .iter()
+                .any(|range| range.contains(&kind.as_u16()))
+    }
+
+    /// Whether no limit is set
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Content length limit applying to `kind`
+    pub fn content_length_for(&self, kind: Kind) -> Option<usize> {
+        self.kind_content_length
+            .get(&kind)
+            .copied()
+            .or(self.max_content_length)
+    }
+
+    /// Check `event` against the limits, returning why it is refused
+    pub fn validate(&self, event: &Event) -> Result<(), String> {
+        if !self.allows_kind(event.kind) {
+            return Err(format!("kind {} is not accepted", event.kind));
+        }
+        if let Some(max) = self.content_length_for(event.kind) {
+            if event.content.len() > max {
+                return Err(format!("content exceeds {max} bytes"));
+            }
+        }
+        if let Some(max) = self.max_tags {
+            if event.tags.len() > max {
+                return Err(format!("more than {max} tags"));
+            }
+        }
+        if let Some(max) = self.max_tag_value_length {
+            if event
+                .tags
+                .iter()
+                .any(|tag| tag.as_slice().iter().any(|value| value.len() > max))
+            {
+                return Err(format!("tag value exceeds {max} bytes"));
+            }
+        }
+        // Serializing is the expensive check, run it last
+        if let Some(max) = self.max_event_size {
+            if event.as_json().len() > max {
+                return Err(format!("event exceeds {max} bytes"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Limits of one scope, replacing the relay-wide ones where set
+///
+/// Set with [`RelayConfig::with_scope_limits`]. They apply to the scope's
+/// connections and are advertised in the scope's NIP-11 document.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScopeLimits {
+    /// Maximum number of active subscriptions per connection
+    pub max_subscriptions: Option<usize>,
+    /// Maximum limit value allowed in subscription filters
+    pub max_limit: Option<usize>,
+    /// Structural limits checked before signature verification
+    pub event_limits: Option<EventLimits>,
+}
+
+impl ScopeLimits {
+    /// Relay-wide limits everywhere
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with_max_subscriptions(mut self, max_subscriptions: usize) -> Self {
+        self.max_subscriptions = Some(max_subscriptions);
+        self
+    }
+
+    #[must_use]
+    pub fn with_max_limit(mut self, max_limit: usize) -> Self {
+        self.max_limit = Some(max_limit);
+        self
+    }
+
+    #[must_use]
+    pub fn with_event_limits(mut self, event_limits: EventLimits) -> Self {
+        self.event_limits = Some(event_limits);
+        self
+    }
+}
+
+/// Database configuration - a path, a scope-sharded path, or an existing database instance
 #[derive(Debug, Clone)]
 pub enum DatabaseConfig {
     /// Create a new database at the specified path
     Path(String),
     /// Use an existing database instance
     Instance(Arc<RelayDatabase>),
+    /// Create a new database at the specified path, storing each named scope
+    /// in its own LMDB environment
+    ShardedPath {
+        /// Path of the database (the default scope lives here)
+        path: String,
+        /// Maximum number of scope environments kept open at once
+        max_open_shards: usize,
+    },
+}
+
+impl DatabaseConfig {
+    /// Create a scope-sharded database configuration
+    pub fn sharded(path: impl Into<String>, max_open_shards: usize) -> Self {
+        DatabaseConfig::ShardedPath {
+            path: path.into(),
+            max_open_shards,
+        }
+    }
+
+    /// Open the configured database
+    fn open(self) -> Result<Arc<RelayDatabase>, Error> {
+        match self {
+            DatabaseConfig::Path(path) => Ok(Arc::new(RelayDatabase::new(path)?)),
+            DatabaseConfig::Instance(db) => Ok(db),
+            DatabaseConfig::ShardedPath {
+                path,
+                max_open_shards,
+            } => Ok(Arc::new(RelayDatabase::with_scope_sharding(
+                path,
+                max_open_shards,
+            )?)),
+        }
+    }
 }
 
 impl From<String> for DatabaseConfig {
@@ -118,6 +317,34 @@ pub struct RelayConfig {
     pub max_subscriptions: usize,
     /// Maximum limit value allowed in subscription filters
     pub max_limit: usize,
+    /// Read replicas used to serve historical queries (REQ) instead of the primary database
+    pub read_replicas: Vec<DatabaseConfig>,
+    /// Number of connection shards events are distributed to in parallel
+    pub distribution_shards: usize,
+    /// Close subscriptions of connections without client activity for this many seconds
+    pub idle_timeout: Option<u64>,
+    /// What to do when a connection's outbound channel is full
+    pub slow_consumer_policy: crate::subscription_registry::SlowConsumerPolicy,
+    /// Subscriptions one event is delivered to before lower priorities are throttled
+    pub fanout_limit: Option<usize>,
+    /// Estimated bytes of subscription filters and queued events before REQs are refused
+    pub subscription_memory_limit: Option<usize>,
+    /// Flush behaviour of the per-connection replaceable events buffer
+    pub replaceable_buffer: crate::subscription_coordinator::ReplaceableBufferConfig,
+    /// Pagination of the stored events served for a REQ
+    pub pagination: crate::subscription_coordinator::PaginationConfig,
+    /// When clients get `OK` relative to storage and distribution of their events
+    pub ordering: crate::subscription_coordinator::OrderingMode,
+    /// Structural limits checked before signature verification
+    pub event_limits: EventLimits,
+    /// Limits replacing the relay-wide ones in some scopes
+    pub scope_limits: HashMap<Scope, ScopeLimits>,
+    /// Fraction of connections traced with per-connection spans, from 0.0 to 1.0
+    pub trace_sample_rate: f64,
+    /// Peers whose `Forwarded`/`X-Forwarded-*` headers are believed
+    pub proxy_headers: crate::proxy::ProxyHeaders,
+    /// What to do with client messages that cannot be parsed
+    pub parse_error_policy: crate::message_converter::ParseErrorPolicy,
 }
 
 impl RelayConfig {
@@ -137,6 +364,20 @@ impl RelayConfig {
             websocket_config: WebSocketConfig::default(),
             max_subscriptions: 50,
             max_limit: 5000,
+            read_replicas: Vec::new(),
+            distribution_shards: 1,
+            idle_timeout: None,
+            slow_consumer_policy: Default::default(),
+            fanout_limit: None,
+            subscription_memory_limit: None,
+            replaceable_buffer: Default::default(),
+            pagination: Default::default(),
+            ordering: Default::default(),
+            event_limits: EventLimits::default(),
+            scope_limits: HashMap::new(),
+            trace_sample_rate: 1.0,
+            proxy_headers: Default::default(),
+            parse_error_policy: Default::default(),
         }
     }
 
@@ -157,14 +398,7 @@ impl RelayConfig {
         _cancellation_token: Option<tokio_util::sync::CancellationToken>,
     ) -> Result<Arc<RelayDatabase>, Error> {
         match &self.database {
-            Some(DatabaseConfig::Path(path)) => {
-                let database = RelayDatabase::new(path)?;
-                Ok(Arc::new(database))
-            }
-            Some(DatabaseConfig::Instance(db)) => {
-                // Return the existing database instance
-                Ok(db.clone())
-            }
+            Some(database_config) => database_config.clone().open(),
             None => Err(Error::internal(
                 "Database configuration is required".to_string(),
             )),
@@ -179,16 +413,7 @@ impl RelayConfig {
         _task_tracker: Option<tokio_util::task::TaskTracker>,
         _cancellation_token: Option<tokio_util::sync::CancellationToken>,
     ) -> Result<Arc<RelayDatabase>, Error> {
-        match database_config {
-            DatabaseConfig::Path(path) => {
-                let database = RelayDatabase::new(&path)?;
-                Ok(Arc::new(database))
-            }
-            DatabaseConfig::Instance(db) => {
-                // Return the existing database instance
-                Ok(db)
-            }
-        }
+        database_config.open()
     }
 
     /// Set the scope configuration
@@ -248,6 +473,15 @@ impl RelayConfig {
         self
     }
 
+    /// Answer client messages larger than `bytes` with a NOTICE instead of parsing them
+    ///
+    /// Advertised as `max_message_length` in NIP-11. Oversized messages count
+    /// as strikes under [`ParseErrorPolicy::Notice`](crate::message_converter::ParseErrorPolicy::Notice).
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.websocket_config.max_message_size = Some(bytes);
+        self
+    }
+
     /// Set the maximum number of active subscriptions per connection
     pub fn with_max_subscriptions(mut self, max_subscriptions: usize) -> Self {
         self.max_subscriptions = max_subscriptions;
@@ -267,6 +501,168 @@ impl RelayConfig {
         self
     }
 
+    /// Add a read replica used to serve historical queries
+    ///
+    /// When one or more replicas are configured, REQ queries are spread across them
+    /// while writes keep going to the primary database.
+    pub fn with_read_replica<D: Into<DatabaseConfig>>(mut self, replica: D) -> Self {
+        self.read_replicas.push(replica.into());
+        self
+    }
+
+    /// Distribute events to connections from `shards` parallel workers
+    ///
+    /// Useful with many thousands of connections, where fan-out on a single
+    /// task becomes the bottleneck during event storms. Defaults to 1 (inline).
+    pub fn with_distribution_shards(mut self, shards: usize) -> Self {
+        self.distribution_shards = shards.max(1);
+        self
+    }
+
+    /// Drop connections that sent nothing for `seconds`
+    ///
+    /// Their subscriptions are closed and they stop receiving events. Dead
+    /// connections (full or closed channels) are reaped regardless of this setting.
+    pub fn with_idle_timeout(mut self, seconds: u64) -> Self {
+        self.idle_timeout = Some(seconds);
+        self
+    }
+
+    /// Choose how connections that don't keep up with distribution are treated
+    ///
+    /// Defaults to [`SlowConsumerPolicy::Disconnect`](crate::SlowConsumerPolicy::Disconnect).
+    pub fn with_slow_consumer_policy(
+        mut self,
+        policy: crate::subscription_registry::SlowConsumerPolicy,
+    ) -> Self {
+        self.slow_consumer_policy = policy;
+        self
+    }
+
+    /// Throttle lower priority subscriptions once an event matched `limit` of them
+    ///
+    /// Counted per distribution shard. Priorities are assigned with
+    /// [`RelayBuilder::with_subscription_priorities`](crate::RelayBuilder::with_subscription_priorities).
+    pub fn with_fanout_limit(mut self, limit: usize) -> Self {
+        self.fanout_limit = Some(limit);
+        self
+    }
+
+    /// Refuse REQs once subscriptions hold an estimated `bytes` of memory
+    ///
+    /// See [`SubscriptionRegistry::with_memory_limit`](crate::SubscriptionRegistry::with_memory_limit).
+    pub fn with_subscription_memory_limit(mut self, bytes: usize) -> Self {
+        self.subscription_memory_limit = Some(bytes);
+        self
+    }
+
+    /// Configure when relay-generated replaceable events are flushed to the database
+    pub fn with_replaceable_buffer(
+        mut self,
+        config: crate::subscription_coordinator::ReplaceableBufferConfig,
+    ) -> Self {
+        self.replaceable_buffer = config;
+        self
+    }
+
+    /// Configure how the stored events of a REQ are paginated
+    ///
+    /// Defaults to 50 windows per filter, each fetching the filter's limit.
+    pub fn with_pagination(
+        mut self,
+        config: crate::subscription_coordinator::PaginationConfig,
+    ) -> Self {
+        self.pagination = config;
+        self
+    }
+
+    /// Choose when clients get `OK` relative to storage and distribution of their events
+    ///
+    /// Defaults to [`OrderingMode::Relaxed`](crate::OrderingMode::Relaxed);
+    /// [`OrderingMode::ReadYourWrites`](crate::OrderingMode::ReadYourWrites)
+    /// stops serving historical queries from read replicas.
+    pub fn with_ordering(
+        mut self,
+        ordering: crate::subscription_coordinator::OrderingMode,
+    ) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// Refuse events breaking `limits` before verifying their signature
+    ///
+    /// The limits are also advertised in the NIP-11 `limitation` object.
+    pub fn with_event_limits(mut self, limits: EventLimits) -> Self {
+        self.event_limits = limits;
+        self
+    }
+
+    /// Apply `limits` instead of the relay-wide ones to `scope`
+    ///
+    /// The scope's NIP-11 document advertises them. They take precedence over
+    /// the limits of a [`RuntimeConfig`](crate::runtime_config::RuntimeConfig).
+    pub fn with_scope_limits(mut self, scope: Scope, limits: ScopeLimits) -> Self {
+        self.scope_limits.insert(scope, limits);
+        self
+    }
+
+    /// Maximum subscriptions per connection in `scope`, 0 when unlimited
+    pub fn max_subscriptions_for(&self, scope: &Scope) -> usize {
+        self.scope_limits
+            .get(scope)
+            .and_then(|limits| limits.max_subscriptions)
+            .unwrap_or(self.max_subscriptions)
+    }
+
+    /// Maximum filter limit in `scope`
+    pub fn max_limit_for(&self, scope: &Scope) -> usize {
+        self.scope_limits
+            .get(scope)
+            .and_then(|limits| limits.max_limit)
+            .unwrap_or(self.max_limit)
+    }
+
+    /// Event limits in `scope`
+    pub fn event_limits_for(&self, scope: &Scope) -> &EventLimits {
+        self.scope_limits
+            .get(scope)
+            .and_then(|limits| limits.event_limits.as_ref())
+            .unwrap_or(&self.event_limits)
+    }
+
+    /// Trace a random `sample_rate` fraction of connections
+    ///
+    /// Sampled connections get a `connection` span carrying the connection id,
+    /// subdomain and authenticated pubkey, parent of their REQ, save and
+    /// distribution spans. Defaults to 1.0, tracing every connection.
+    pub fn with_trace_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.trace_sample_rate = sample_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Choose which peers may set the client IP and host through proxy headers
+    ///
+    /// Defaults to [`ProxyHeaders::TrustAll`](crate::proxy::ProxyHeaders::TrustAll);
+    /// use `TrustFrom` with the proxy addresses when clients can also reach
+    /// the relay directly.
+    pub fn with_proxy_headers(mut self, proxy_headers: crate::proxy::ProxyHeaders) -> Self {
+        self.proxy_headers = proxy_headers;
+        self
+    }
+
+    /// Choose how client messages that cannot be parsed are handled
+    ///
+    /// Defaults to [`ParseErrorPolicy::Disconnect`](crate::message_converter::ParseErrorPolicy::Disconnect);
+    /// `Notice` answers them with a NOTICE and only drops the connection after
+    /// `max_strikes` of them.
+    pub fn with_parse_error_policy(
+        mut self,
+        policy: crate::message_converter::ParseErrorPolicy,
+    ) -> Self {
+        self.parse_error_policy = policy;
+        self
+    }
+
     /// Calculate the WebSocket channel size based on configuration
     /// This is used for per-connection MessageSender channels
     pub fn calculate_channel_size(&self) -> usize {
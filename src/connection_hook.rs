@@ -0,0 +1,30 @@
+//! Callbacks run when WebSocket connections open and close
+//!
+//! A [`ConnectionHook`] sees every WebSocket connection once it is registered
+//! with the relay and again when it goes away, with its address, scope, user
+//! agent and traffic counters as a [`ConnectionStats`]. Host applications use
+//! them for IP bans, session logs or presence without patching the registry.
+//! Hooks are registered with
+//! [`RelayBuilder::with_connection_hook`](crate::RelayBuilder::with_connection_hook).
+//!
+//! Hooks run synchronously, `on_disconnect` from the drop of the connection's
+//! handle or from the registry detaching the connection, e.g. when it is
+//! reaped, shed or drained, so they should hand slow work off to a task.
+
+use crate::subscription_registry::ConnectionStats;
+
+/// Callbacks around the lifetime of a WebSocket connection
+pub trait ConnectionHook: Send + Sync + std::fmt::Debug {
+    /// Called once `connection` is registered, before any of its messages
+    ///
+    /// Returning `Err(reason)` sends `reason` as a NOTICE and closes the connection.
+    fn on_connect(&self, connection: &ConnectionStats) -> Result<(), String> {
+        let _ = connection;
+        Ok(())
+    }
+
+    /// Called when `connection` leaves the registry, with its final counters
+    fn on_disconnect(&self, connection: &ConnectionStats) {
+        let _ = connection;
+    }
+}
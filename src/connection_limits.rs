@@ -0,0 +1,221 @@
+//! Caps on concurrent WebSocket connections
+//!
+//! [`ConnectionLimits`] is a [`ConnectionHook`] refusing connections beyond a
+//! number per client IP, or beyond a total for the whole relay, when they
+//! register. Refused clients get a `rate-limited:` NOTICE and their socket is
+//! closed; each refusal is reported through
+//! [`RelayMetricsHandler::record_connection_rejected`](crate::metrics::RelayMetricsHandler::record_connection_rejected).
+//!
+//! The client IP is the one of the connection's remote address, so it honours
+//! the relay's proxy header settings. Connections without a known address only
+//! count towards the total.
+
+use crate::connection_hook::ConnectionHook;
+use crate::subscription_registry::ConnectionStats;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::debug;
+
+/// NOTICE sent to connections refused for their IP
+pub const PER_IP_LIMIT_NOTICE: &str = "rate-limited: too many connections from your address";
+
+/// NOTICE sent to connections refused because the relay is full
+pub const TOTAL_LIMIT_NOTICE: &str = "rate-limited: relay is at capacity, try again later";
+
+/// Connections currently counted
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Maximum concurrent connections per client IP and in total
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimits {
+    max_per_ip: Option<usize>,
+    max_total: Option<usize>,
+    counts: Arc<Mutex<Counts>>,
+    /// IP of each counted connection, `None` when its address is unknown
+    counted: Arc<DashMap<String, Option<IpAddr>>>,
+}
+
+impl ConnectionLimits {
+    /// No limits until configured
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuse connections from an IP already holding `max_per_ip` of them
+    #[must_use]
+    pub fn with_max_per_ip(mut self, max_per_ip: usize) -> Self {
+        self.max_per_ip = Some(max_per_ip);
+        self
+    }
+
+    /// Refuse connections while `max_total` are open
+    #[must_use]
+    pub fn with_max_total(mut self, max_total: usize) -> Self {
+        self.max_total = Some(max_total);
+        self
+    }
+
+    /// Connections currently open from `ip`
+    pub fn connections_from(&self, ip: &IpAddr) -> usize {
+        self.counts.lock().per_ip.get(ip).copied().unwrap_or(0)
+    }
+
+    /// Connections currently open
+    pub fn total_connections(&self) -> usize {
+        self.counts.lock().total
+    }
+
+    fn reject(&self, reason: &'static str, notice: &str) -> Result<(), String> {
+        if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+            metrics.record_connection_rejected(reason);
+        }
+        Err(notice.to_string())
+    }
+}
+
+impl ConnectionHook for ConnectionLimits {
+    fn on_connect(&self, connection: &ConnectionStats) -> Result<(), String> {
+        let ip = connection
+            .remote_address
+            .as_deref()
+            .and_then(|address| address.parse::<SocketAddr>().ok())
+            .map(|address| address.ip());
+
+        let mut counts = self.counts.lock();
+        if self.max_total.is_some_and(|max| counts.total >= max) {
+            debug!(
+                "Refused connection {}: {} connections open",
+                connection.connection_id, counts.total
+            );
+            return self.reject("total", TOTAL_LIMIT_NOTICE);
+        }
+        if let Some(ip) = ip {
+            let from_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+            if self.max_per_ip.is_some_and(|max| from_ip >= max) {
+                debug!(
+                    "Refused connection {}: {} connections open from {}",
+                    connection.connection_id, from_ip, ip
+                );
+                return self.reject("per_ip", PER_IP_LIMIT_NOTICE);
+            }
+            *counts.per_ip.entry(ip).or_default() += 1;
+        }
+        counts.total += 1;
+        self.counted.insert(connection.connection_id.clone(), ip);
+        Ok(())
+    }
+
+    fn on_disconnect(&self, connection: &ConnectionStats) {
+        // Refused connections were never counted
+        let Some((_, ip)) = self.counted.remove(&connection.connection_id) else {
+            return;
+        };
+
+        let mut counts = self.counts.lock();
+        counts.total = counts.total.saturating_sub(1);
+        if let Some(ip) = ip {
+            if let Some(from_ip) = counts.per_ip.get_mut(&ip) {
+                *from_ip -= 1;
+                if *from_ip == 0 {
+                    counts.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscription_registry::SubscriptionRegistry;
+    use nostr_lmdb::Scope;
+    use nostr_sdk::prelude::*;
+    use websocket_builder::MessageSender;
+
+    #[tokio::test]
+    async fn test_per_ip_and_total_limits() {
+        let limits = ConnectionLimits::new().with_max_per_ip(2).with_max_total(3);
+        let registry =
+            SubscriptionRegistry::new(None).with_connection_hook(Arc::new(limits.clone()));
+        let (tx, _rx) = flume::bounded::<(RelayMessage<'static>, usize)>(10);
+
+        let connect = |id: &str, address: &str| {
+            let handle = registry.register_connection(
+                id.to_string(),
+                MessageSender::new(tx.clone(), 0),
+                None,
+                Arc::new(Scope::Default),
+            );
+            registry.set_remote_address(id, address.to_string());
+            (registry.announce_connection(id), handle)
+        };
+
+        let (first, _first) = connect("a1", "10.0.0.1:1000");
+        let (second, second_handle) = connect("a2", "10.0.0.1:1001");
+        let (third, _third) = connect("a3", "10.0.0.1:1002");
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(third, Err(PER_IP_LIMIT_NOTICE.to_string()));
+
+        let (other, _other) = connect("b1", "10.0.0.2:1000");
+        let (full, _full) = connect("c1", "10.0.0.3:1000");
+        assert!(other.is_ok());
+        assert_eq!(full, Err(TOTAL_LIMIT_NOTICE.to_string()));
+        assert_eq!(limits.total_connections(), 3);
+
+        // Closing a counted connection frees its slot
+        drop(second_handle);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(limits.connections_from(&ip), 1);
+        assert_eq!(limits.total_connections(), 2);
+        let (again, _again) = connect("a4", "10.0.0.1:1003");
+        assert!(again.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connections_removed_by_the_registry_free_their_slot() {
+        let limits = ConnectionLimits::new().with_max_per_ip(1);
+        let registry =
+            SubscriptionRegistry::new(None).with_connection_hook(Arc::new(limits.clone()));
+        let (tx, _rx) = flume::bounded::<(RelayMessage<'static>, usize)>(10);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let connect = |id: &str, address: &str| {
+            let handle = registry.register_connection(
+                id.to_string(),
+                MessageSender::new(tx.clone(), 0),
+                None,
+                Arc::new(Scope::Default),
+            );
+            registry.set_remote_address(id, address.to_string());
+            (registry.announce_connection(id), handle)
+        };
+
+        // Reaped while its socket task still holds the handle
+        let (idle, _idle) = connect("idle", "10.0.0.1:1000");
+        assert!(idle.is_ok());
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(
+            registry
+                .reap(Some(std::time::Duration::from_millis(10)))
+                .idle,
+            1
+        );
+        assert_eq!(limits.connections_from(&ip), 0);
+
+        // Shed under overload
+        let (anonymous, _anonymous) = connect("anonymous", "10.0.0.1:1001");
+        assert!(anonymous.is_ok());
+        assert_eq!(registry.shed_anonymous(1, "rate-limited: overloaded"), 1);
+        assert_eq!(limits.total_connections(), 0);
+
+        let (again, _again) = connect("again", "10.0.0.1:1002");
+        assert!(again.is_ok());
+    }
+}
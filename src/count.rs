@@ -0,0 +1,346 @@
+//! NIP-45 COUNT with an optional approximate mode
+//!
+//! COUNT requests are answered with the number of stored events matching the
+//! filter, counted by the database. Counting a whole kind or a prolific author
+//! on a large relay scans a lot of index entries, so a [`CountConfig`] with
+//! [`CountConfig::with_approximation_above`] also keeps HyperLogLog sketches of
+//! the event ids stored per kind and per author in each scope. A filter made
+//! only of kinds or only of authors is first estimated from the sketches; if
+//! the estimate reaches the threshold it is answered right away with
+//! `"approximate": true`, otherwise the database counts it exactly.
+//!
+//! The sketches are built from the stored events in the background when the
+//! relay starts, exact counts are used until then. They never forget an id, so
+//! deleted and replaced events keep counting towards estimates.
+//!
+//! COUNT goes through the same checks as REQ: filters are verified and
+//! augmented, and events are counted only when visible to the requester
+//! through moderation and
+//! [`EventProcessor::can_see_event`](crate::EventProcessor::can_see_event).
+//! That means reading the matching events one by one, so the database counts
+//! matches directly, and sketches are used, only when there is no moderation
+//! and the processor's
+//! [`can_hide_events`](crate::EventProcessor::can_hide_events) is `false`.
+
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::event_sink::EventSink;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Precision of the per-kind sketches, 4 KiB each for a ~1.6% standard error
+const KIND_PRECISION: u8 = 12;
+
+/// Precision of the per-author sketches, 256 bytes each for a ~6.5% standard error
+const AUTHOR_PRECISION: u8 = 8;
+
+/// Events read per query while building the sketches
+const LOAD_BATCH: usize = 10_000;
+
+/// HyperLogLog estimator of a number of distinct event ids
+#[derive(Debug, Clone)]
+struct HyperLogLog {
+    precision: u8,
+    registers: Box<[u8]>,
+}
+
+impl HyperLogLog {
+    fn new(precision: u8) -> Self {
+        Self {
+            precision,
+            registers: vec![0; 1 << precision].into_boxed_slice(),
+        }
+    }
+
+    /// Add an id; event ids are already SHA-256 hashes
+    fn insert(&mut self, id: &EventId) {
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&id.as_bytes()[..8]);
+        let hash = u64::from_be_bytes(prefix);
+
+        let index = (hash >> (64 - self.precision)) as usize;
+        // The guard bit bounds the rank when the remaining bits are all zero
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&register| 2f64.powi(-i32::from(register)))
+            .sum();
+        let raw = alpha * m * m / sum;
+
+        // Linear counting is more accurate for small cardinalities
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+/// Sketches of the events stored per kind and per author in each scope
+#[derive(Debug, Default)]
+struct CountSketches {
+    kinds: DashMap<(Scope, Kind), HyperLogLog>,
+    authors: DashMap<(Scope, PublicKey), HyperLogLog>,
+    /// Set once the stored events were added
+    ready: AtomicBool,
+}
+
+impl CountSketches {
+    fn record(&self, event: &Event, scope: &Scope) {
+        self.kinds
+            .entry((scope.clone(), event.kind))
+            .or_insert_with(|| HyperLogLog::new(KIND_PRECISION))
+            .insert(&event.id);
+        self.authors
+            .entry((scope.clone(), event.pubkey))
+            .or_insert_with(|| HyperLogLog::new(AUTHOR_PRECISION))
+            .insert(&event.id);
+    }
+
+    /// Estimated events matching `filter` in `scope`, if sketches can tell
+    ///
+    /// Only filters made of kinds alone or authors alone can be estimated:
+    /// each event has a single kind and author, so the matching sets are
+    /// disjoint and their estimates add up.
+    fn estimate(&self, filter: &Filter, scope: &Scope) -> Option<u64> {
+        if !self.ready.load(Ordering::Acquire)
+            || filter.ids.is_some()
+            || filter.search.is_some()
+            || filter.since.is_some()
+            || filter.until.is_some()
+            || !filter.generic_tags.is_empty()
+        {
+            return None;
+        }
+
+        let total: f64 = match (&filter.kinds, &filter.authors) {
+            (Some(kinds), None) if !kinds.is_empty() => kinds
+                .iter()
+                .map(|kind| {
+                    self.kinds
+                        .get(&(scope.clone(), *kind))
+                        .map_or(0.0, |sketch| sketch.estimate())
+                })
+                .sum(),
+            (None, Some(authors)) if !authors.is_empty() => authors
+                .iter()
+                .map(|author| {
+                    self.authors
+                        .get(&(scope.clone(), *author))
+                        .map_or(0.0, |sketch| sketch.estimate())
+                })
+                .sum(),
+            _ => return None,
+        };
+        Some(total.round() as u64)
+    }
+
+    /// Add the events stored in every scope of `database`
+    async fn load(&self, database: &RelayDatabase) -> Result<()> {
+        let mut scopes = database.list_scopes().await?;
+        if !scopes.contains(&Scope::Default) {
+            scopes.push(Scope::Default);
+        }
+        for scope in scopes {
+            let mut until: Option<Timestamp> = None;
+            loop {
+                let mut filter = Filter::new().limit(LOAD_BATCH);
+                if let Some(until) = until {
+                    filter = filter.until(until);
+                }
+                let events = database.query(vec![filter], &scope).await?;
+                let Some(oldest) = events.iter().map(|event| event.created_at).min() else {
+                    break;
+                };
+                for event in events.iter() {
+                    self.record(event, &scope);
+                }
+                if events.len() < LOAD_BATCH || oldest.as_u64() == 0 {
+                    break;
+                }
+                // Ids are only counted once, so the next page may overlap this one;
+                // it must start earlier when a whole page shares one timestamp
+                until = Some(if until == Some(oldest) {
+                    oldest - 1
+                } else {
+                    oldest
+                });
+            }
+        }
+        self.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// How COUNT requests are answered
+#[derive(Debug, Clone, Default)]
+pub struct CountConfig {
+    /// Estimates from this many events on are sent as approximate
+    approximate_above: Option<u64>,
+    sketches: Arc<CountSketches>,
+}
+
+impl CountConfig {
+    /// Exact counts from the database
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer filters of kinds or authors estimated at `threshold` events or
+    /// more from sketches, flagged as approximate
+    #[must_use]
+    pub fn with_approximation_above(mut self, threshold: u64) -> Self {
+        self.approximate_above = Some(threshold);
+        self
+    }
+
+    /// Whether sketches are kept
+    pub fn is_approximate(&self) -> bool {
+        self.approximate_above.is_some()
+    }
+
+    /// Build the sketches from the events stored in `database`
+    pub(crate) async fn load(&self, database: &RelayDatabase) -> Result<()> {
+        self.sketches.load(database).await
+    }
+
+    /// Events matching `filters` in `scope`, and whether the count is approximate
+    ///
+    /// With a `visible` function the matching events are read and only those
+    /// it accepts are counted, exactly.
+    pub(crate) async fn count(
+        &self,
+        database: &RelayDatabase,
+        filters: Vec<Filter>,
+        scope: &Scope,
+        visible: Option<&(dyn Fn(&Event) -> bool + Send + Sync)>,
+    ) -> Result<(usize, bool)> {
+        if visible.is_some() || filters.len() != 1 {
+            let events = database.query(filters, scope).await?;
+            let count = match visible {
+                Some(visible) => events.iter().filter(|event| visible(event)).count(),
+                None => events.len(),
+            };
+            return Ok((count, false));
+        }
+        if let Some(threshold) = self.approximate_above {
+            if let Some(estimate) = self.sketches.estimate(&filters[0], scope) {
+                if estimate >= threshold {
+                    return Ok((estimate as usize, true));
+                }
+            }
+        }
+        Ok((database.count(filters, scope).await?, false))
+    }
+}
+
+#[async_trait]
+impl EventSink for CountConfig {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        if self.is_approximate() {
+            self.sketches.record(&event, scope);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    #[test]
+    fn test_hyperloglog_estimate() {
+        let keys = Keys::generate();
+        let mut sketch = HyperLogLog::new(KIND_PRECISION);
+        for i in 0..20_000 {
+            let event = EventBuilder::text_note(i.to_string())
+                .sign_with_keys(&keys)
+                .unwrap();
+            sketch.insert(&event.id);
+            // Ids are counted once
+            sketch.insert(&event.id);
+        }
+        let error = (sketch.estimate() - 20_000.0).abs() / 20_000.0;
+        assert!(error < 0.05, "estimate off by {error}");
+    }
+
+    #[tokio::test]
+    async fn test_small_counts_stay_exact() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        for i in 0..30 {
+            let event = EventBuilder::text_note(i.to_string())
+                .sign_with_keys(&keys)
+                .unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        let config = CountConfig::new().with_approximation_above(20);
+        let notes = Filter::new().kind(Kind::TextNote);
+
+        // Exact until the sketches are built
+        assert_eq!(
+            config
+                .count(&database, vec![notes.clone()], &Scope::Default, None)
+                .await
+                .unwrap(),
+            (30, false)
+        );
+
+        config.load(&database).await.unwrap();
+        let (estimate, approximate) = config
+            .count(&database, vec![notes.clone()], &Scope::Default, None)
+            .await
+            .unwrap();
+        assert!(approximate);
+        assert!((28..=32).contains(&estimate));
+
+        // Filters the sketches can't answer, and small estimates, are counted exactly
+        let recent = notes.since(Timestamp::from(0));
+        assert_eq!(
+            config
+                .count(&database, vec![recent], &Scope::Default, None)
+                .await
+                .unwrap(),
+            (30, false)
+        );
+        let exact = CountConfig::new().with_approximation_above(1_000);
+        exact.load(&database).await.unwrap();
+        assert_eq!(
+            exact
+                .count(
+                    &database,
+                    vec![Filter::new().author(keys.public_key())],
+                    &Scope::Default,
+                    None
+                )
+                .await
+                .unwrap(),
+            (30, false)
+        );
+
+        // Visibility is applied to exact counts, never to estimates
+        let visible = |event: &Event| event.content != "0";
+        assert_eq!(
+            config
+                .count(&database, vec![notes], &Scope::Default, Some(&visible))
+                .await
+                .unwrap(),
+            (29, false)
+        );
+    }
+}
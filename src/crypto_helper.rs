@@ -1,9 +1,14 @@
 //! Cryptographic operations for events
 
 use crate::error::{Error, Result};
+use crate::signer::Signer;
 use crate::subscription_coordinator::StoreCommand;
+use nostr::nips::nip44::{self, v2::ConversationKey};
+use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
 use rayon::prelude::*;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::oneshot;
@@ -14,16 +19,26 @@ use tracing::{debug, error, info};
 pub struct CryptoHelper {
     /// Keys for signing events
     keys: Arc<Keys>,
+    /// Additional signing identities by key id, shared by all clones
+    keyring: Arc<RwLock<HashMap<String, Arc<Keys>>>>,
     /// Verification request sender
     verify_sender: flume::Sender<VerifyRequest>,
-    /// Signing request sender
-    sign_sender: flume::Sender<StoreCommand>,
+    /// Signing request sender, with the keys to sign with
+    sign_sender: flume::Sender<(StoreCommand, Arc<Keys>)>,
+    /// Signer replacing `keys` for the relay identity, with its request sender
+    external_signer: Option<(Arc<dyn Signer>, flume::Sender<StoreCommand>)>,
     /// Stats counter for verified events
     verified_count: Arc<AtomicUsize>,
     /// Stats counter for signed events
     signed_count: Arc<AtomicUsize>,
 }
 
+/// Largest number of events verified in one batch, bounding the latency of a batch
+const MAX_VERIFY_BATCH: usize = 1024;
+
+/// Largest number of events handed to an external signer at once
+const MAX_SIGN_BATCH: usize = 64;
+
 /// Request to verify an event
 struct VerifyRequest {
     event: Event,
@@ -44,7 +59,7 @@ impl CryptoHelper {
         let verified_count = Arc::new(AtomicUsize::new(0));
 
         // Create signing channel with reasonable capacity
-        let (sign_sender, sign_receiver) = flume::bounded::<StoreCommand>(10000);
+        let (sign_sender, sign_receiver) = flume::bounded::<(StoreCommand, Arc<Keys>)>(10000);
         let signed_count = Arc::new(AtomicUsize::new(0));
 
         // Spawn the verification processor
@@ -55,13 +70,14 @@ impl CryptoHelper {
 
         // Spawn the signing processor
         let signed_count_clone = Arc::clone(&signed_count);
-        let keys_clone = Arc::clone(&keys);
         std::thread::spawn(move || {
-            Self::run_sign_processor(sign_receiver, keys_clone, signed_count_clone);
+            Self::run_sign_processor(sign_receiver, signed_count_clone);
         });
 
         Self {
             keys,
+            keyring: Arc::new(RwLock::new(HashMap::new())),
+            external_signer: None,
             verify_sender,
             sign_sender,
             verified_count,
@@ -69,6 +85,120 @@ impl CryptoHelper {
         }
     }
 
+    /// Sign relay-generated events with `signer` instead of the configured keys
+    ///
+    /// Store commands are batched and handed to the signer from a Tokio task, so
+    /// this must be called within a Tokio runtime. The keyring is unaffected.
+    #[must_use]
+    pub fn with_signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        let (sender, receiver) = flume::bounded::<StoreCommand>(10000);
+        tokio::spawn(Self::run_external_sign_processor(
+            receiver,
+            Arc::clone(&signer),
+            Arc::clone(&self.signed_count),
+        ));
+        self.external_signer = Some((signer, sender));
+        self
+    }
+
+    /// Public key of the relay identity, the signer's when one is set
+    pub fn public_key(&self) -> PublicKey {
+        match &self.external_signer {
+            Some((signer, _)) => signer.public_key(),
+            None => self.keys.public_key(),
+        }
+    }
+
+    /// Hand batches of store commands to an external signer
+    async fn run_external_sign_processor(
+        receiver: flume::Receiver<StoreCommand>,
+        signer: Arc<dyn Signer>,
+        signed_count: Arc<AtomicUsize>,
+    ) {
+        info!("External signing processor started");
+
+        while let Ok(first_command) = receiver.recv_async().await {
+            let batch: Vec<StoreCommand> = std::iter::once(first_command)
+                .chain(receiver.try_iter().take(MAX_SIGN_BATCH - 1))
+                .collect();
+
+            let mut events = Vec::with_capacity(batch.len());
+            let mut destinations = Vec::with_capacity(batch.len());
+            for command in batch {
+                if let StoreCommand::SaveUnsignedEvent(event, scope, response_handler) = command {
+                    events.push(event);
+                    destinations.push((scope, response_handler));
+                } else {
+                    error!("Non-SaveUnsignedEvent command received in signing processor");
+                }
+            }
+
+            debug!(
+                "Sending batch of {} events to external signer",
+                events.len()
+            );
+            let results = signer.sign_events(events).await;
+            signed_count.fetch_add(results.len(), Ordering::Relaxed);
+
+            for (result, (scope, response_handler)) in results.into_iter().zip(destinations) {
+                if let Some(sender) = response_handler {
+                    let _ = sender.send(result.map(|signed_event| {
+                        Some(StoreCommand::SaveSignedEvent(
+                            Box::new(signed_event),
+                            scope,
+                            None,
+                        ))
+                    }));
+                }
+            }
+        }
+
+        info!("External signing processor stopped");
+    }
+
+    /// Verify a batch of events and answer each request
+    ///
+    /// The same event flooded by many clients is only verified once per batch.
+    /// Requests are grouped by id and signature, so ids are recomputed first:
+    /// a tampered copy reusing a genuine id and signature fails on its own
+    /// instead of sharing the genuine event's result. secp256k1 has no schnorr
+    /// batch verification, so unique events are verified in parallel instead.
+    fn verify_batch(pool: &rayon::ThreadPool, batch: Vec<VerifyRequest>) {
+        let mut unique: HashMap<(EventId, [u8; 64]), (Event, Vec<oneshot::Sender<Result<()>>>)> =
+            HashMap::with_capacity(batch.len());
+        for VerifyRequest { event, response } in batch {
+            if !event.verify_id() {
+                debug!("Event id verification failed for {}", event.id);
+                let _ = response.send(Err(Error::protocol(format!(
+                    "Invalid event signature: {}",
+                    crate::ingest::INVALID_ID_MESSAGE
+                ))));
+                continue;
+            }
+            unique
+                .entry((event.id, event.sig.serialize()))
+                .or_insert_with(|| (event, Vec::new()))
+                .1
+                .push(response);
+        }
+
+        // Process the batch in parallel using rayon
+        pool.install(|| {
+            unique.into_par_iter().for_each(|(_, (event, responses))| {
+                // Perform the actual verification
+                let result = event.verify().map_err(|e| {
+                    debug!("Event verification failed: {:?}", e);
+                    format!("Invalid event signature: {e}")
+                });
+
+                // Send the result back (ignore send errors if receiver dropped)
+                for response in responses {
+                    let _ = response.send(result.clone().map_err(Error::protocol));
+                }
+            });
+        });
+    }
+
     /// Run the verification processor that batches and parallelizes verification
     fn run_verify_processor(
         receiver: flume::Receiver<VerifyRequest>,
@@ -95,27 +225,12 @@ impl CryptoHelper {
 
             // Collect a batch using the eager consumption pattern
             let batch: Vec<VerifyRequest> = std::iter::once(first_request)
-                .chain(receiver.drain())
+                .chain(receiver.try_iter().take(MAX_VERIFY_BATCH - 1))
                 .collect();
 
             let batch_size = batch.len();
             debug!("Processing verification batch of {} events", batch_size);
-
-            // Process the batch in parallel using rayon
-            pool.install(|| {
-                batch.into_par_iter().for_each(|request| {
-                    let VerifyRequest { event, response } = request;
-
-                    // Perform the actual verification
-                    let result = event.verify().map_err(|e| {
-                        debug!("Event verification failed: {:?}", e);
-                        Error::protocol(format!("Invalid event signature: {e}"))
-                    });
-
-                    // Send the result back (ignore send errors if receiver dropped)
-                    let _ = response.send(result);
-                });
-            });
+            Self::verify_batch(&pool, batch);
 
             // Update stats
             verified_count.fetch_add(batch_size, Ordering::Relaxed);
@@ -169,8 +284,7 @@ impl CryptoHelper {
 
     /// Run the signing processor that batches and parallelizes signing
     fn run_sign_processor(
-        receiver: flume::Receiver<StoreCommand>,
-        keys: Arc<Keys>,
+        receiver: flume::Receiver<(StoreCommand, Arc<Keys>)>,
         signed_count: Arc<AtomicUsize>,
     ) {
         info!("Crypto signing processor started");
@@ -199,7 +313,7 @@ impl CryptoHelper {
             };
 
             // Collect a batch using the eager consumption pattern
-            let batch: Vec<StoreCommand> = std::iter::once(first_command)
+            let batch: Vec<(StoreCommand, Arc<Keys>)> = std::iter::once(first_command)
                 .chain(receiver.drain())
                 .collect();
 
@@ -208,7 +322,7 @@ impl CryptoHelper {
 
             // Process the batch in parallel using rayon
             pool.install(|| {
-                batch.into_par_iter().for_each(|command| {
+                batch.into_par_iter().for_each(|(command, keys)| {
                     if let StoreCommand::SaveUnsignedEvent(event, scope, response_handler) = command
                     {
                         // Sign the event using block_in_place to run async code
@@ -279,9 +393,62 @@ impl CryptoHelper {
         info!("Crypto signing processor stopped");
     }
 
-    /// Sign an unsigned event with the configured keys
+    /// Add a signing identity under `key_id`, replacing any previous one
+    ///
+    /// Key ids are free-form, e.g. a scope name for tenant-specific service
+    /// events or a purpose such as `"announcements"`.
+    pub fn add_key(&self, key_id: impl Into<String>, keys: Keys) {
+        self.keyring.write().insert(key_id.into(), Arc::new(keys));
+    }
+
+    /// Remove the signing identity `key_id`
+    pub fn remove_key(&self, key_id: &str) -> Option<Arc<Keys>> {
+        self.keyring.write().remove(key_id)
+    }
+
+    /// Public key of the signing identity `key_id`
+    ///
+    /// Events signed with [`Self::sign_event_as`] must be built for this public key.
+    pub fn public_key_of(&self, key_id: &str) -> Option<PublicKey> {
+        self.keyring
+            .read()
+            .get(key_id)
+            .map(|keys| keys.public_key())
+    }
+
+    /// Ids of the added signing identities
+    pub fn key_ids(&self) -> Vec<String> {
+        self.keyring.read().keys().cloned().collect()
+    }
+
+    fn keys_for(&self, key_id: &str) -> Result<Arc<Keys>> {
+        self.keyring
+            .read()
+            .get(key_id)
+            .cloned()
+            .ok_or_else(|| Error::internal(format!("Unknown signing key: {key_id}")))
+    }
+
+    /// Sign an unsigned event with the relay identity
     pub async fn sign_event(&self, event: UnsignedEvent) -> Result<Event> {
-        self.keys.sign_event(event).await.map_err(|e| {
+        match &self.external_signer {
+            Some((signer, _)) => signer
+                .sign_events(vec![event])
+                .await
+                .pop()
+                .unwrap_or_else(|| Err(Error::internal("Signer returned no event"))),
+            None => Self::sign_with(&self.keys, event).await,
+        }
+    }
+
+    /// Sign an unsigned event with the signing identity `key_id`
+    pub async fn sign_event_as(&self, key_id: &str, event: UnsignedEvent) -> Result<Event> {
+        let keys = self.keys_for(key_id)?;
+        Self::sign_with(&keys, event).await
+    }
+
+    async fn sign_with(keys: &Keys, event: UnsignedEvent) -> Result<Event> {
+        keys.sign_event(event).await.map_err(|e| {
             error!("Failed to sign event: {:?}", e);
             Error::internal(format!("Failed to sign event: {e}"))
         })
@@ -306,18 +473,70 @@ impl CryptoHelper {
             .map_err(|_| Error::internal("Verification processor dropped response"))?
     }
 
+    /// Queue an event for verification without waiting
+    ///
+    /// Returns immediately, also from synchronous code; the result arrives on the
+    /// returned receiver once the verification pool processed the event's batch.
+    /// Fails right away when the verification queue is full.
+    pub fn verify_event_deferred(&self, event: Event) -> oneshot::Receiver<Result<()>> {
+        let (tx, rx) = oneshot::channel();
+
+        if let Err(e) = self.verify_sender.try_send(VerifyRequest {
+            event,
+            response: tx,
+        }) {
+            let reason = match e {
+                flume::TrySendError::Full(_) => "Verification queue is full",
+                flume::TrySendError::Disconnected(_) => "Verification processor unavailable",
+            };
+            let _ = e.into_inner().response.send(Err(Error::internal(reason)));
+        }
+
+        rx
+    }
+
     /// Get the number of events verified
     pub fn verified_count(&self) -> usize {
         self.verified_count.load(Ordering::Relaxed)
     }
 
+    /// Events queued for verification
+    pub fn pending_verifications(&self) -> usize {
+        self.verify_sender.len()
+    }
+
+    /// Store commands queued for signing with the relay's keys
+    pub fn pending_signatures(&self) -> usize {
+        self.sign_sender.len()
+    }
+
     /// Sign a store command (converts SaveUnsignedEvent to SaveSignedEvent)
     pub async fn sign_store_command(&self, command: StoreCommand) -> Result<()> {
+        let Some((_, sender)) = &self.external_signer else {
+            return self.queue_signing(command, Arc::clone(&self.keys)).await;
+        };
+
+        match command {
+            StoreCommand::SaveUnsignedEvent(..) => sender
+                .send_async(command)
+                .await
+                .map_err(|_| Error::internal("Signing processor unavailable")),
+            _ => Err(Error::internal("Expected SaveUnsignedEvent command")),
+        }
+    }
+
+    /// Sign a store command with the signing identity `key_id`
+    pub async fn sign_store_command_as(&self, key_id: &str, command: StoreCommand) -> Result<()> {
+        let keys = self.keys_for(key_id)?;
+        self.queue_signing(command, keys).await
+    }
+
+    async fn queue_signing(&self, command: StoreCommand, keys: Arc<Keys>) -> Result<()> {
         match command {
             StoreCommand::SaveUnsignedEvent(..) => {
                 // Send to the signing processor for batched processing
                 self.sign_sender
-                    .send_async(command)
+                    .send_async((command, keys))
                     .await
                     .map_err(|_| Error::internal("Signing processor unavailable"))?;
                 Ok(())
@@ -329,6 +548,133 @@ impl CryptoHelper {
         }
     }
 
+    /// Secret key of the relay's local keys, which `operation` needs
+    ///
+    /// Fails when an external signer is set: the local keys are not the
+    /// relay's identity then.
+    fn local_secret_key(&self, operation: &str) -> Result<&SecretKey> {
+        if self.external_signer.is_some() {
+            return Err(Error::internal(format!(
+                "{operation} need the relay's local keys, not an external signer"
+            )));
+        }
+        Ok(self.keys.secret_key())
+    }
+
+    /// NIP-44 conversation key between the relay keys and `public_key`
+    ///
+    /// Worth caching when exchanging many messages with the same pubkey.
+    /// Fails when an external signer is set.
+    pub fn conversation_key(&self, public_key: &PublicKey) -> Result<ConversationKey> {
+        ConversationKey::derive(self.local_secret_key("Conversation keys")?, public_key)
+            .map_err(|e| Error::internal(format!("Failed to derive conversation key: {e}")))
+    }
+
+    /// NIP-44 encrypt `plaintext` from the relay keys to `public_key`
+    ///
+    /// Fails when an external signer is set.
+    pub fn nip44_encrypt(&self, public_key: &PublicKey, plaintext: &str) -> Result<String> {
+        nip44::encrypt(
+            self.local_secret_key("NIP-44 payloads")?,
+            public_key,
+            plaintext,
+            nip44::Version::V2,
+        )
+        .map_err(|e| Error::internal(format!("Failed to encrypt: {e}")))
+    }
+
+    /// NIP-44 decrypt a `payload` sent by `public_key` to the relay keys
+    ///
+    /// Fails when an external signer is set.
+    pub fn nip44_decrypt(&self, public_key: &PublicKey, payload: &str) -> Result<String> {
+        nip44::decrypt(
+            self.local_secret_key("NIP-44 payloads")?,
+            public_key,
+            payload,
+        )
+        .map_err(|e| Error::protocol(format!("Failed to decrypt: {e}")))
+    }
+
+    /// NIP-44 encrypt `plaintext` from the configured keys to themselves
+    ///
+    /// For records the relay keeps at rest; unlike [`Self::nip44_encrypt`]
+    /// this works with an external signer set.
+    pub(crate) fn encrypt_at_rest(&self, plaintext: &str) -> Result<String> {
+        nip44::encrypt(
+            self.keys.secret_key(),
+            &self.keys.public_key(),
+            plaintext,
+            nip44::Version::V2,
+        )
+        .map_err(|e| Error::internal(format!("Failed to encrypt: {e}")))
+    }
+
+    /// NIP-44 decrypt a `payload` of [`Self::encrypt_at_rest`]
+    pub(crate) fn decrypt_at_rest(&self, payload: &str) -> Result<String> {
+        nip44::decrypt(self.keys.secret_key(), &self.keys.public_key(), payload)
+            .map_err(|e| Error::protocol(format!("Failed to decrypt: {e}")))
+    }
+
+    /// Build a NIP-17 direct message from the relay to `recipient`
+    ///
+    /// The seal is signed through the batched signing path and the gift wrap
+    /// with a one-time key. The returned command stores the gift wrap in
+    /// `scope`, e.g. as part of an `EventProcessor`'s commands, which also
+    /// delivers it to the recipient's live subscriptions. Encryption needs the
+    /// relay's local keys, so this fails when an external signer is set.
+    pub async fn direct_message(
+        &self,
+        recipient: PublicKey,
+        message: impl Into<String>,
+        scope: Scope,
+    ) -> Result<StoreCommand> {
+        self.local_secret_key("Direct messages")?;
+        let sender = self.keys.public_key();
+
+        let mut rumor = EventBuilder::private_msg_rumor(recipient, message).build(sender);
+        rumor.ensure_id();
+        let seal = EventBuilder::new(
+            Kind::Seal,
+            self.nip44_encrypt(&recipient, &rumor.as_json())?,
+        )
+        .build(sender);
+
+        let (tx, rx) = oneshot::channel();
+        self.sign_store_command(StoreCommand::SaveUnsignedEvent(
+            seal,
+            scope.clone(),
+            Some(tx),
+        ))
+        .await?;
+        let seal = match rx.await {
+            Ok(Ok(Some(StoreCommand::SaveSignedEvent(seal, _, _)))) => seal,
+            Ok(Ok(_)) => return Err(Error::internal("Seal signed but not returned")),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(Error::internal("Signing processor dropped response")),
+        };
+
+        let wrapper = Keys::generate();
+        let gift_wrap = EventBuilder::new(
+            Kind::GiftWrap,
+            nip44::encrypt(
+                wrapper.secret_key(),
+                &recipient,
+                seal.as_json(),
+                nip44::Version::V2,
+            )
+            .map_err(|e| Error::internal(format!("Failed to encrypt: {e}")))?,
+        )
+        .tag(Tag::public_key(recipient))
+        .sign_with_keys(&wrapper)
+        .map_err(|e| Error::internal(format!("Failed to sign gift wrap: {e}")))?;
+
+        Ok(StoreCommand::SaveSignedEvent(
+            Box::new(gift_wrap),
+            scope,
+            None,
+        ))
+    }
+
     /// Get the number of events signed
     pub fn signed_count(&self) -> usize {
         self.signed_count.load(Ordering::Relaxed)
@@ -413,6 +759,184 @@ mod tests {
             .contains("Invalid event signature"));
     }
 
+    #[tokio::test]
+    async fn test_deferred_verification_of_duplicates() {
+        let keys = Arc::new(Keys::generate());
+        let helper = CryptoHelper::new(Arc::clone(&keys));
+
+        let event = EventBuilder::text_note("flooded")
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        // Identical events share one verification but each gets its answer
+        let receivers: Vec<_> = (0..5)
+            .map(|_| helper.verify_event_deferred(event.clone()))
+            .collect();
+        for rx in receivers {
+            assert!(rx.await.unwrap().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tampered_copy_in_the_same_batch_fails() {
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("flooded")
+            .sign_with_keys(&keys)
+            .unwrap();
+        // Same id and signature as the genuine event, queued right after it
+        let mut tampered = event.clone();
+        tampered.content = "tampered".to_string();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let (genuine_tx, genuine_rx) = oneshot::channel();
+        let (tampered_tx, tampered_rx) = oneshot::channel();
+        CryptoHelper::verify_batch(
+            &pool,
+            vec![
+                VerifyRequest {
+                    event,
+                    response: genuine_tx,
+                },
+                VerifyRequest {
+                    event: tampered,
+                    response: tampered_tx,
+                },
+            ],
+        );
+
+        assert!(genuine_rx.await.unwrap().is_ok());
+        assert!(tampered_rx.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sign_with_keyring() {
+        let keys = Arc::new(Keys::generate());
+        let helper = CryptoHelper::new(Arc::clone(&keys));
+        let tenant = Keys::generate();
+        helper.add_key("tenant", tenant.clone());
+        assert_eq!(helper.public_key_of("tenant"), Some(tenant.public_key()));
+
+        let unsigned = EventBuilder::text_note("from tenant").build(tenant.public_key());
+        let signed = helper
+            .sign_event_as("tenant", unsigned.clone())
+            .await
+            .unwrap();
+        assert_eq!(signed.pubkey, tenant.public_key());
+        assert!(signed.verify().is_ok());
+
+        // Store commands go through the batched signer with the chosen identity
+        let (tx, rx) = oneshot::channel();
+        helper
+            .sign_store_command_as(
+                "tenant",
+                StoreCommand::SaveUnsignedEvent(
+                    unsigned.clone(),
+                    nostr_lmdb::Scope::Default,
+                    Some(tx),
+                ),
+            )
+            .await
+            .unwrap();
+        match rx.await.unwrap().unwrap() {
+            Some(StoreCommand::SaveSignedEvent(event, _, _)) => {
+                assert_eq!(event.pubkey, tenant.public_key());
+                assert!(event.verify().is_ok());
+            }
+            other => panic!("Expected signed command, got {other:?}"),
+        }
+
+        assert!(helper.sign_event_as("missing", unsigned).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_external_signer_replaces_keys() {
+        let local_keys = Arc::new(Keys::generate());
+        let remote_keys = Keys::generate();
+        let helper = CryptoHelper::new(local_keys).with_signer(Arc::new(remote_keys.clone()));
+        assert_eq!(helper.public_key(), remote_keys.public_key());
+
+        let unsigned = EventBuilder::text_note("signed remotely").build(remote_keys.public_key());
+        let (tx, rx) = oneshot::channel();
+        helper
+            .sign_store_command(StoreCommand::SaveUnsignedEvent(
+                unsigned,
+                nostr_lmdb::Scope::Default,
+                Some(tx),
+            ))
+            .await
+            .unwrap();
+        match rx.await.unwrap().unwrap() {
+            Some(StoreCommand::SaveSignedEvent(event, _, _)) => {
+                assert_eq!(event.pubkey, remote_keys.public_key());
+                assert!(event.verify().is_ok());
+            }
+            other => panic!("Expected signed command, got {other:?}"),
+        }
+
+        // The local keys are not the relay's identity anymore
+        let recipient = Keys::generate().public_key();
+        assert!(helper.conversation_key(&recipient).is_err());
+        assert!(helper.nip44_encrypt(&recipient, "secret").is_err());
+        assert!(helper.nip44_decrypt(&recipient, "payload").is_err());
+        assert!(helper
+            .direct_message(recipient, "secret", Scope::Default)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_round_trip() {
+        let relay_keys = Arc::new(Keys::generate());
+        let helper = CryptoHelper::new(Arc::clone(&relay_keys));
+        let recipient = Keys::generate();
+
+        // Plain NIP-44 in both directions
+        let payload = helper
+            .nip44_encrypt(&recipient.public_key(), "receipt")
+            .unwrap();
+        let plaintext =
+            nip44::decrypt(recipient.secret_key(), &relay_keys.public_key(), &payload).unwrap();
+        assert_eq!(plaintext, "receipt");
+        assert_eq!(
+            helper
+                .nip44_decrypt(&recipient.public_key(), &payload)
+                .unwrap(),
+            "receipt"
+        );
+
+        let command = helper
+            .direct_message(recipient.public_key(), "you were muted", Scope::Default)
+            .await
+            .unwrap();
+        let StoreCommand::SaveSignedEvent(gift_wrap, _, _) = command else {
+            panic!("Expected a signed gift wrap");
+        };
+        assert_eq!(gift_wrap.kind, Kind::GiftWrap);
+        assert_ne!(gift_wrap.pubkey, relay_keys.public_key());
+
+        let seal = Event::from_json(
+            nip44::decrypt(
+                recipient.secret_key(),
+                &gift_wrap.pubkey,
+                &gift_wrap.content,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(seal.pubkey, relay_keys.public_key());
+        assert!(seal.verify().is_ok());
+
+        let rumor = UnsignedEvent::from_json(
+            nip44::decrypt(recipient.secret_key(), &seal.pubkey, &seal.content).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(rumor.kind, Kind::PrivateDirectMessage);
+        assert_eq!(rumor.content, "you were muted");
+    }
+
     #[tokio::test]
     async fn test_concurrent_operations() {
         let keys = Arc::new(Keys::generate());
@@ -1,25 +1,208 @@
 //! Database abstraction for Nostr relays
 
 use crate::error::Error;
+use crate::query_cache::{QueryCache, QueryCacheStats};
+use crate::receipts::{ReceiptIndex, ReceivedEvent};
 use nostr_database::nostr::{Event, Filter};
 use nostr_database::Events;
 use nostr_lmdb::{NostrLMDB, Scope};
 use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+/// Directory (inside the database path) holding one LMDB environment per named scope
+const SCOPE_SHARDS_DIR: &str = "scopes";
+
+/// Receipt index entries read at a time by [`RelayDatabase::query_received`]
+const RECEIPT_SCAN_BATCH: usize = 500;
+
+/// File present in the database directory while a relay session runs
+const SESSION_MARKER: &str = "relay.running";
+
+/// Ids looked up at a time by [`RelayDatabase::verify_and_repair`]
+const VERIFY_ID_BATCH: usize = 500;
+
+/// Data file of an LMDB environment
+const LMDB_DATA_FILE: &str = "data.mdb";
+
+/// Scope environments kept open at once by [`RelayDatabase::open_read_only`]
+const READ_ONLY_OPEN_SHARDS: usize = 16;
+
+/// How LMDB flushes commits to disk
+///
+/// nostr-lmdb reads the mode from the `NOSTR_LMDB_MODE` environment variable
+/// (`sync` when unset) and takes no flag for it, so the mode is set by the
+/// operator for the whole process. A database opened with another mode than
+/// the variable's fails to open instead of silently running with it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LmdbSyncMode {
+    /// Flush data and metadata on every commit: durable, slowest
+    #[default]
+    Full,
+    /// Skip the metadata flush: a crash may lose the last commit but never
+    /// corrupts the database
+    NoMetaSync,
+    /// Leave flushing to the OS: fastest, a crash of the machine (not just the
+    /// process) may lose recent commits or corrupt the database
+    NoSync,
+}
+
+impl LmdbSyncMode {
+    /// Value of `NOSTR_LMDB_MODE` selecting this mode
+    pub fn as_env_value(self) -> &'static str {
+        match self {
+            Self::Full => "sync",
+            Self::NoMetaSync => "nometasync",
+            Self::NoSync => "nosync",
+        }
+    }
+
+    /// Mode nostr-lmdb uses in this process
+    fn from_env() -> std::result::Result<Self, String> {
+        let Ok(value) = std::env::var("NOSTR_LMDB_MODE") else {
+            return Ok(Self::Full);
+        };
+        [Self::Full, Self::NoMetaSync, Self::NoSync]
+            .into_iter()
+            .find(|mode| value.eq_ignore_ascii_case(mode.as_env_value()))
+            .ok_or(value)
+    }
+}
+
+/// Tuning of the LMDB environments of a [`RelayDatabase`]
+///
+/// Scope shards are opened with the same options. The map size is address
+/// space reserved up front, not disk space: the data file only grows as events
+/// are stored, so it can be set well above the expected size. The map does
+/// not grow at runtime: nostr-lmdb doesn't expose `mdb_env_set_mapsize` and an
+/// environment can't be reopened while in use. Once the map is full, saves
+/// fail with an error saying so, [`RelayDatabase::map_usage`] warns ahead of
+/// it, and the database must be reopened with a larger map size; existing
+/// files are reused as is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LmdbOptions {
+    /// Maximum size of the data file, in bytes
+    pub map_size: usize,
+    /// Maximum number of concurrent read transactions, across processes
+    pub max_readers: u32,
+    /// Named databases to allow on top of the ones nostr-lmdb uses
+    pub max_dbs: u32,
+    /// How commits are flushed to disk
+    pub sync_mode: LmdbSyncMode,
+}
+
+impl Default for LmdbOptions {
+    /// 32 GiB map, 126 readers, no extra databases and full sync
+    fn default() -> Self {
+        Self {
+            map_size: 32 * 1024 * 1024 * 1024,
+            max_readers: 126,
+            max_dbs: 0,
+            sync_mode: LmdbSyncMode::Full,
+        }
+    }
+}
+
+impl LmdbOptions {
+    /// Let the data file grow up to `map_size` bytes
+    #[must_use]
+    pub fn with_map_size(mut self, map_size: usize) -> Self {
+        self.map_size = map_size;
+        self
+    }
+
+    /// Allow `max_readers` concurrent read transactions
+    #[must_use]
+    pub fn with_max_readers(mut self, max_readers: u32) -> Self {
+        self.max_readers = max_readers;
+        self
+    }
+
+    /// Allow `max_dbs` named databases besides nostr-lmdb's own
+    #[must_use]
+    pub fn with_max_dbs(mut self, max_dbs: u32) -> Self {
+        self.max_dbs = max_dbs;
+        self
+    }
+
+    /// Flush commits according to `sync_mode`
+    #[must_use]
+    pub fn with_sync_mode(mut self, sync_mode: LmdbSyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// Open the environment at `path` with these options
+    fn open(&self, path: &Path) -> std::result::Result<NostrLMDB, nostr_database::DatabaseError> {
+        NostrLMDB::builder(path)
+            .map_size(self.map_size)
+            .max_readers(self.max_readers)
+            .additional_dbs(self.max_dbs)
+            .build()
+    }
+}
+
+/// How much of the main environment's map is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MapUsage {
+    /// Size of the data file, in bytes
+    pub used_bytes: u64,
+    /// Configured map size, in bytes
+    pub map_size: usize,
+}
+
+impl MapUsage {
+    /// Used fraction of the map, between 0 and 1
+    pub fn ratio(&self) -> f64 {
+        if self.map_size == 0 {
+            return 1.0;
+        }
+        self.used_bytes as f64 / self.map_size as f64
+    }
+}
 
 /// A Nostr relay database that wraps NostrLMDB with async operations
 #[derive(Debug, Clone)]
 pub struct RelayDatabase {
     lmdb: Arc<NostrLMDB>,
+    /// Directory of the main environment
+    path: Arc<PathBuf>,
+    /// Options every environment is opened with
+    options: LmdbOptions,
+    /// Whether writes are refused, see [`RelayDatabase::open_read_only`]
+    read_only: bool,
+    /// Per-scope environments, when scope sharding is enabled
+    shards: Option<Arc<ScopeShards>>,
+    /// Optional cache of query results
+    query_cache: Option<Arc<QueryCache>>,
+    /// First receipt times of saved events, when enabled
+    receipts: Option<ReceiptIndex>,
 }
 
 impl RelayDatabase {
-    /// Create a new relay database
+    /// Create a new relay database with the default [`LmdbOptions`]
     ///
     /// # Arguments
     /// * `db_path_param` - Path where the database should be stored
     pub fn new(db_path_param: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+        Self::with_options(db_path_param, LmdbOptions::default())
+    }
+
+    /// Create a new relay database with tuned LMDB environments
+    ///
+    /// # Arguments
+    /// * `db_path_param` - Path where the database should be stored
+    /// * `options` - Map size, readers, databases and sync mode of the environments
+    pub fn with_options(
+        db_path_param: impl AsRef<std::path::Path>,
+        options: LmdbOptions,
+    ) -> Result<Self, Error> {
         let db_path = db_path_param.as_ref().to_path_buf();
 
         // Ensure database directory exists
@@ -40,34 +223,268 @@ impl RelayDatabase {
             })?;
         }
 
-        // Open LMDB database with configuration from environment
-        info!("Opening LMDB database with configuration from environment");
-        if let Ok(mode) = std::env::var("NOSTR_LMDB_MODE") {
-            info!("LMDB mode: {}", mode);
+        // nostr-lmdb reads its sync mode from the environment
+        match LmdbSyncMode::from_env() {
+            Ok(mode) if mode == options.sync_mode => {
+                info!("LMDB mode: {}", mode.as_env_value());
+            }
+            Ok(mode) => {
+                return Err(Error::database(format!(
+                    "LMDB sync mode {:?} requested but NOSTR_LMDB_MODE selects {:?}, \
+                     set NOSTR_LMDB_MODE={} before starting the process",
+                    options.sync_mode,
+                    mode,
+                    options.sync_mode.as_env_value()
+                )));
+            }
+            Err(value) => {
+                return Err(Error::database(format!(
+                    "Unknown NOSTR_LMDB_MODE '{value}', expected sync, nometasync or nosync"
+                )));
+            }
         }
-        let lmdb_instance = NostrLMDB::open(&db_path).map_err(|e| {
+        info!(
+            "Opening LMDB database with a {} byte map and {} readers",
+            options.map_size, options.max_readers
+        );
+        let lmdb_instance = options.open(&db_path).map_err(|e| {
             Error::database(format!(
                 "Failed to open NostrLMDB at path '{db_path:?}': {e}"
             ))
         })?;
         let lmdb = Arc::new(lmdb_instance);
 
-        Ok(Self { lmdb })
+        Ok(Self {
+            lmdb,
+            path: Arc::new(db_path),
+            options,
+            read_only: false,
+            shards: None,
+            query_cache: None,
+            receipts: None,
+        })
+    }
+
+    /// Create a new relay database that stores each named scope in its own LMDB environment
+    ///
+    /// The default scope lives in the environment at `db_path_param`, while every
+    /// named scope gets a directory under `<db_path>/scopes/`. Shard environments
+    /// are opened lazily and the least recently used ones are closed once more than
+    /// `max_open_shards` are open, so a deployment with many tenants is not bound by
+    /// a single environment's map size. A tenant can be removed by deleting its
+    /// directory (or by calling [`RelayDatabase::delete_scope`]).
+    ///
+    /// # Arguments
+    /// * `db_path_param` - Path where the database should be stored
+    /// * `max_open_shards` - Maximum number of scope environments kept open at once
+    pub fn with_scope_sharding(
+        db_path_param: impl AsRef<Path>,
+        max_open_shards: usize,
+    ) -> Result<Self, Error> {
+        Self::with_scope_sharding_options(db_path_param, max_open_shards, LmdbOptions::default())
+    }
+
+    /// Create a scope-sharded relay database whose environments, the default
+    /// one and every shard, are opened with `options`
+    ///
+    /// See [`RelayDatabase::with_scope_sharding`].
+    pub fn with_scope_sharding_options(
+        db_path_param: impl AsRef<Path>,
+        max_open_shards: usize,
+        options: LmdbOptions,
+    ) -> Result<Self, Error> {
+        let db_path = db_path_param.as_ref().to_path_buf();
+        let mut database = Self::with_options(&db_path, options)?;
+        database.shards = Some(Arc::new(ScopeShards::new(
+            db_path.join(SCOPE_SHARDS_DIR),
+            max_open_shards,
+            options,
+        )?));
+        Ok(database)
+    }
+
+    /// Open an existing database for queries and exports only
+    ///
+    /// Meant for tools running next to the relay, e.g. analytics or dump
+    /// scripts: LMDB lets any number of processes share an environment, and
+    /// readers never block the relay's writer nor see a partial write. Every
+    /// method that would write (saving, deleting, repairing, sessions) fails
+    /// instead, so the tool can't race the relay's writes. Scope shards are
+    /// used when the database has any; scopes without a shard on disk read as
+    /// empty rather than being created.
+    ///
+    /// Each open read transaction pins the pages it reads, so a long-running
+    /// export makes the relay's data file grow until it ends.
+    pub fn open_read_only(db_path_param: impl AsRef<Path>) -> Result<Self, Error> {
+        let db_path = db_path_param.as_ref().to_path_buf();
+        if !db_path.join(LMDB_DATA_FILE).exists() {
+            return Err(Error::database(format!(
+                "No LMDB database at '{db_path:?}' to open read-only"
+            )));
+        }
+
+        let options = LmdbOptions::default();
+        let lmdb = options.open(&db_path).map_err(|e| {
+            Error::database(format!(
+                "Failed to open NostrLMDB read-only at path '{db_path:?}': {e}"
+            ))
+        })?;
+        let shards_dir = db_path.join(SCOPE_SHARDS_DIR);
+        let shards = if shards_dir.is_dir() {
+            let mut shards = ScopeShards::new(shards_dir, READ_ONLY_OPEN_SHARDS, options)?;
+            shards.read_only = true;
+            Some(Arc::new(shards))
+        } else {
+            None
+        };
+
+        info!("Opened LMDB database at {:?} read-only", db_path);
+        Ok(Self {
+            lmdb: Arc::new(lmdb),
+            path: Arc::new(db_path),
+            options,
+            read_only: true,
+            shards,
+            query_cache: None,
+            receipts: None,
+        })
+    }
+
+    /// Whether the database was opened with [`RelayDatabase::open_read_only`]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Refuse `operation` on a read-only database
+    fn ensure_writable(&self, operation: &str) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::database(format!(
+                "Cannot {operation}: the database is opened read-only"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Options the environments were opened with
+    pub fn options(&self) -> &LmdbOptions {
+        &self.options
+    }
+
+    /// Size of the main environment's data file against its map size
+    ///
+    /// Alert on [`MapUsage::ratio`] to reopen with a larger map size before
+    /// saves start failing.
+    pub fn map_usage(&self) -> Result<MapUsage> {
+        let data_file = self.path.join(LMDB_DATA_FILE);
+        let metadata = std::fs::metadata(&data_file)
+            .map_err(|e| Error::database(format!("Failed to stat {data_file:?}: {e}")))?;
+        Ok(MapUsage {
+            used_bytes: metadata.len(),
+            map_size: self.options.map_size,
+        })
+    }
+
+    /// Cache up to `capacity` query results in front of [`RelayDatabase::query`]
+    ///
+    /// Cached results are dropped when an event whose kind and author match one of
+    /// their filters is saved, and all results of a scope are dropped on deletes.
+    #[must_use]
+    pub fn with_query_cache(mut self, capacity: std::num::NonZeroUsize) -> Self {
+        self.query_cache = Some(Arc::new(QueryCache::new(capacity)));
+        self
+    }
+
+    /// Record when each saved event was first received in `receipts`
+    ///
+    /// Enables [`RelayDatabase::query_received`].
+    #[must_use]
+    pub fn with_receipt_index(mut self, receipts: ReceiptIndex) -> Self {
+        self.receipts = Some(receipts);
+        self
+    }
+
+    /// Whether saved events are recorded in a receipt index
+    pub fn has_receipt_index(&self) -> bool {
+        self.receipts.is_some()
+    }
+
+    /// Hit/miss statistics of the query cache, if enabled
+    pub fn query_cache_stats(&self) -> Option<QueryCacheStats> {
+        self.query_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Whether named scopes are stored in separate environments
+    pub fn is_scope_sharded(&self) -> bool {
+        self.shards.is_some()
+    }
+
+    /// Resolve the environment and the scope within it that hold data for `scope`
+    ///
+    /// Without sharding every scope lives in the main environment. With sharding,
+    /// named scopes map to the default scope of their own environment. Only
+    /// writes create a missing shard, so any client naming a new scope can't
+    /// make the relay open environments; reads of such a scope get `None`.
+    async fn env_for(
+        &self,
+        scope: &Scope,
+        create: bool,
+    ) -> Result<Option<(Arc<NostrLMDB>, Scope)>, Error> {
+        match (&self.shards, scope) {
+            (Some(shards), Scope::Named { name, .. }) => Ok(shards
+                .get(name, create)
+                .await?
+                .map(|env| (env, Scope::Default))),
+            _ => Ok(Some((Arc::clone(&self.lmdb), scope.clone()))),
+        }
     }
 
     /// Save an event directly
     pub async fn save_event(&self, event: &Event, scope: &Scope) -> Result<()> {
-        let env = Arc::clone(&self.lmdb);
-        let scoped_view = env.scoped(scope).map_err(|e| {
+        self.ensure_writable("save events")?;
+        let (env, env_scope) = self
+            .env_for(scope, true)
+            .await?
+            .ok_or_else(|| Error::database(format!("No scope shard for {scope:?}")))?;
+        let scoped_view = env.scoped(&env_scope).map_err(|e| {
             error!("Error getting scoped view: {:?}", e);
             Error::database(format!("Failed to get scoped view: {e}"))
         })?;
 
         scoped_view.save_event(event).await.map_err(|e| {
+            if is_map_full(&e.to_string()) {
+                error!(
+                    "LMDB map of {} bytes is full, event {} not saved for scope {:?}",
+                    self.options.map_size, event.id, scope
+                );
+                return Box::new(Error::database(format!(
+                    "Database is full: the LMDB map size of {} bytes is used up, \
+                     reopen it with a larger LmdbOptions::map_size",
+                    self.options.map_size
+                ))) as Box<dyn std::error::Error>;
+            }
             error!("Error saving event for scope {:?}: {:?}", scope, e);
             Box::new(e) as Box<dyn std::error::Error>
         })?;
 
+        if let Some(cache) = &self.query_cache {
+            cache.invalidate_event(event, scope);
+        }
+
+        if let Some(receipts) = self.receipts.clone() {
+            let id = event.id;
+            let owned_scope = scope.clone();
+            let recorded = tokio::task::spawn_blocking(move || {
+                receipts.record(&id, &owned_scope, Timestamp::now())
+            })
+            .await
+            .map_err(|e| Error::database(format!("Failed to spawn blocking task: {e}")))
+            .and_then(|result| result);
+            // The event is stored either way, only mirrors may miss it
+            if let Err(e) = recorded {
+                warn!("Failed to record receipt of event {}: {}", event.id, e);
+            }
+        }
+
         debug!(
             "Event saved successfully: {} for scope: {:?}",
             event.as_json(),
@@ -78,8 +495,11 @@ impl RelayDatabase {
 
     /// Delete events matching a filter
     pub async fn delete(&self, filter: Filter, scope: &Scope) -> Result<()> {
-        let lmdb = Arc::clone(&self.lmdb);
-        let scoped_view = lmdb.scoped(scope).map_err(|e| {
+        self.ensure_writable("delete events")?;
+        let Some((lmdb, lmdb_scope)) = self.env_for(scope, false).await? else {
+            return Ok(());
+        };
+        let scoped_view = lmdb.scoped(&lmdb_scope).map_err(|e| {
             error!("Error getting scoped view: {:?}", e);
             Error::database(format!("Failed to get scoped view: {e}"))
         })?;
@@ -89,14 +509,77 @@ impl RelayDatabase {
             Box::new(e) as Box<dyn std::error::Error>
         })?;
 
+        if let Some(cache) = &self.query_cache {
+            cache.invalidate_scope(scope);
+        }
+
         debug!("Deleted events successfully for scope: {:?}", scope);
         Ok(())
     }
 
+    /// Delete every event `pubkey` authored in `scope`, returning how many
+    ///
+    /// Meant for legal deletion requests; to keep the events from coming back,
+    /// tombstone the pubkey with [`ModerationStore::tombstone_pubkey`](crate::ModerationStore::tombstone_pubkey).
+    pub async fn purge_author(&self, pubkey: &PublicKey, scope: &Scope) -> Result<usize, Error> {
+        self.purge(Filter::new().author(*pubkey), scope).await
+    }
+
+    /// Delete every event tagging `pubkey` in a `p` tag in `scope`, returning how many
+    pub async fn purge_mentions(&self, pubkey: &PublicKey, scope: &Scope) -> Result<usize, Error> {
+        self.purge(Filter::new().pubkey(*pubkey), scope).await
+    }
+
+    async fn purge(&self, filter: Filter, scope: &Scope) -> Result<usize, Error> {
+        let count = self.count(vec![filter.clone()], scope).await?;
+        self.delete(filter, scope).await?;
+        Ok(count)
+    }
+
     /// Query events from the database
     pub async fn query(&self, filters: Vec<Filter>, scope: &Scope) -> Result<Events, Error> {
-        let lmdb = Arc::clone(&self.lmdb);
-        let scoped_view = lmdb.scoped(scope).map_err(|e| {
+        let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() else {
+            return self.query_cached(filters, scope).await;
+        };
+
+        let start = std::time::Instant::now();
+        let filter_count = filters.len();
+        let result = self.query_cached(filters, scope).await;
+        metrics.record_query_duration(start.elapsed(), filter_count);
+        result
+    }
+
+    /// Query through the query cache, when enabled
+    async fn query_cached(&self, filters: Vec<Filter>, scope: &Scope) -> Result<Events, Error> {
+        let Some(cache) = &self.query_cache else {
+            return self.query_uncached(filters, scope).await;
+        };
+
+        if let Some(cached) = cache.get(&filters, scope) {
+            let mut events = Events::new(&Filter::new());
+            events.extend(cached.iter().cloned());
+            return Ok(events);
+        }
+
+        let generation = cache.generation(scope);
+        let events: Vec<Event> = self
+            .query_uncached(filters.clone(), scope)
+            .await?
+            .into_iter()
+            .collect();
+        cache.insert(filters, scope, Arc::new(events.clone()), generation);
+
+        let mut result = Events::new(&Filter::new());
+        result.extend(events);
+        Ok(result)
+    }
+
+    /// Query events from the database, bypassing the query cache
+    async fn query_uncached(&self, filters: Vec<Filter>, scope: &Scope) -> Result<Events, Error> {
+        let Some((lmdb, lmdb_scope)) = self.env_for(scope, false).await? else {
+            return Ok(Events::new(&Filter::new()));
+        };
+        let scoped_view = lmdb.scoped(&lmdb_scope).map_err(|e| {
             error!("Error getting scoped view: {:?}", e);
             Error::database(format!("Failed to get scoped view: {e}"))
         })?;
@@ -143,10 +626,90 @@ impl RelayDatabase {
         Ok(all_events)
     }
 
+    /// Events of `scope` matching `filter`, in the order the relay first received them
+    ///
+    /// Starts at the events received at `since` and returns at most `limit`,
+    /// each with its receipt time. To page, call again with the receipt time
+    /// of the last event and skip the ids already seen. The `since`, `until`
+    /// and `limit` of `filter` still apply to `created_at`. Requires
+    /// [`RelayDatabase::with_receipt_index`].
+    pub async fn query_received(
+        &self,
+        filter: Filter,
+        since: Timestamp,
+        limit: usize,
+        scope: &Scope,
+    ) -> Result<Vec<ReceivedEvent>, Error> {
+        let Some(receipts) = &self.receipts else {
+            return Err(Error::database("receipt index is not enabled"));
+        };
+
+        let mut received = Vec::new();
+        let mut after = None;
+        let mut from = since;
+        while received.len() < limit {
+            let receipts = receipts.clone();
+            let owned_scope = scope.clone();
+            let batch = tokio::task::spawn_blocking(move || {
+                receipts.received_since(&owned_scope, from, after.as_ref(), RECEIPT_SCAN_BATCH)
+            })
+            .await
+            .map_err(|e| Error::database(format!("Failed to spawn blocking task: {e}")))??;
+            let Some(&(last_at, last_id)) = batch.last() else {
+                break;
+            };
+            let exhausted = batch.len() < RECEIPT_SCAN_BATCH;
+            (from, after) = (last_at, Some(last_id));
+
+            // Deleted events keep their entries, they are skipped here
+            let ids = batch.iter().map(|(_, id)| *id);
+            let mut stored: HashMap<EventId, Event> = self
+                .query_uncached(vec![Filter::new().ids(ids)], scope)
+                .await?
+                .into_iter()
+                .map(|event| (event.id, event))
+                .collect();
+            let wanted = limit - received.len();
+            received.extend(
+                batch
+                    .into_iter()
+                    .filter_map(|(received_at, id)| {
+                        stored
+                            .remove(&id)
+                            .map(|event| ReceivedEvent { received_at, event })
+                    })
+                    .filter(|received| {
+                        filter.match_event(
+                            &received.event,
+                            nostr_sdk::filter::MatchEventOptions::default(),
+                        )
+                    })
+                    .take(wanted),
+            );
+            if exhausted {
+                break;
+            }
+        }
+        Ok(received)
+    }
+
+    /// Whether the event `id` is already stored in `scope`
+    ///
+    /// An id index lookup that bypasses the query cache, cheap enough to run
+    /// before verifying or saving an incoming event.
+    pub async fn has_event(&self, id: &EventId, scope: &Scope) -> Result<bool, Error> {
+        let events = self
+            .query_uncached(vec![Filter::new().id(*id).limit(1)], scope)
+            .await?;
+        Ok(!events.is_empty())
+    }
+
     /// Get count of events matching filters
     pub async fn count(&self, filters: Vec<Filter>, scope: &Scope) -> Result<usize, Error> {
-        let lmdb = Arc::clone(&self.lmdb);
-        let scoped_view = lmdb.scoped(scope).map_err(|e| {
+        let Some((lmdb, lmdb_scope)) = self.env_for(scope, false).await? else {
+            return Ok(0);
+        };
+        let scoped_view = lmdb.scoped(&lmdb_scope).map_err(|e| {
             error!("Error getting scoped view: {:?}", e);
             Error::database(format!("Failed to get scoped view: {e}"))
         })?;
@@ -182,14 +745,215 @@ impl RelayDatabase {
         Ok(total_count)
     }
 
+    /// Events stored in `scope` by kind, and their size
+    ///
+    /// Scans every event of the scope, so it is meant for periodic metering
+    /// rather than for every request.
+    pub async fn scope_stats(&self, scope: &Scope) -> Result<ScopeStorageStats, Error> {
+        let events = self.query_uncached(vec![Filter::new()], scope).await?;
+
+        let mut stats = ScopeStorageStats::default();
+        for event in events {
+            stats.events += 1;
+            *stats.events_by_kind.entry(event.kind.as_u16()).or_default() += 1;
+            stats.storage_bytes += event.as_json().len() as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Check every scope for index damage and repair what the API allows
+    ///
+    /// Each stored event must be found when looked up by id, author and
+    /// kind; events an index misses are saved again, which rewrites their
+    /// index rows. Replaceable and addressable events older than the latest
+    /// version of their address are deleted. Lookups failing on index rows
+    /// of missing events are only reported, the `nostr-lmdb-integrity` tool
+    /// removes those rows offline.
+    ///
+    /// Reads every event of a scope at once, so it is meant for startup and
+    /// maintenance rather than for a live relay under load.
+    pub async fn verify_and_repair(&self) -> Result<IntegrityReport, Error> {
+        self.ensure_writable("repair")?;
+        let mut scopes = self.list_scopes().await?;
+        if !scopes.contains(&Scope::Default) {
+            scopes.insert(0, Scope::Default);
+        }
+
+        let mut report = IntegrityReport::default();
+        for scope in &scopes {
+            self.verify_scope(scope, &mut report).await?;
+        }
+        report.scopes = scopes.len();
+
+        if report.is_clean() {
+            info!(
+                "Integrity check found no damage in {} events of {} scopes",
+                report.events, report.scopes
+            );
+        } else {
+            warn!(
+                "Integrity check of {} events: reindexed {}, deleted {} stale versions, {} corrupt lookups left",
+                report.events,
+                report.reindexed.len(),
+                report.stale_versions.len(),
+                report.corrupt_lookups
+            );
+        }
+        Ok(report)
+    }
+
+    async fn verify_scope(&self, scope: &Scope, report: &mut IntegrityReport) -> Result<(), Error> {
+        let Some(events) = self.lookup(Filter::new(), scope).await? else {
+            report.corrupt_lookups += 1;
+            return Ok(());
+        };
+        let events: HashMap<EventId, Event> =
+            events.into_iter().map(|event| (event.id, event)).collect();
+        report.events += events.len();
+
+        // The latest version of each address stays, older ones go
+        let mut latest: HashMap<(PublicKey, Kind, String), &Event> = HashMap::new();
+        let mut stale = Vec::new();
+        for event in events.values() {
+            if !event.kind.is_replaceable() && !event.kind.is_addressable() {
+                continue;
+            }
+            let identifier = if event.kind.is_addressable() {
+                event.tags.identifier().unwrap_or_default().to_string()
+            } else {
+                String::new()
+            };
+            match latest.entry((event.pubkey, event.kind, identifier)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(event);
+                }
+                Entry::Occupied(mut entry) => {
+                    let kept = *entry.get();
+                    let newer = (event.created_at, std::cmp::Reverse(event.id))
+                        > (kept.created_at, std::cmp::Reverse(kept.id));
+                    if newer {
+                        stale.push(kept.id);
+                        entry.insert(event);
+                    } else {
+                        stale.push(event.id);
+                    }
+                }
+            }
+        }
+
+        // Events each index should find, by lookup
+        let mut lookups: Vec<(Filter, HashSet<EventId>)> = Vec::new();
+        let ids: Vec<EventId> = events.keys().copied().collect();
+        for chunk in ids.chunks(VERIFY_ID_BATCH) {
+            lookups.push((
+                Filter::new().ids(chunk.iter().copied()),
+                chunk.iter().copied().collect(),
+            ));
+        }
+        let mut by_author: HashMap<PublicKey, HashSet<EventId>> = HashMap::new();
+        let mut by_kind: HashMap<Kind, HashSet<EventId>> = HashMap::new();
+        for event in events.values() {
+            by_author.entry(event.pubkey).or_default().insert(event.id);
+            by_kind.entry(event.kind).or_default().insert(event.id);
+        }
+        lookups.extend(
+            by_author
+                .into_iter()
+                .map(|(author, expected)| (Filter::new().author(author), expected)),
+        );
+        lookups.extend(
+            by_kind
+                .into_iter()
+                .map(|(kind, expected)| (Filter::new().kind(kind), expected)),
+        );
+
+        let mut unindexed = HashSet::new();
+        for (filter, expected) in lookups {
+            match self.lookup(filter, scope).await? {
+                Some(found) => {
+                    let found: HashSet<EventId> = found.into_iter().map(|event| event.id).collect();
+                    unindexed.extend(expected.difference(&found).copied());
+                }
+                None => report.corrupt_lookups += 1,
+            }
+        }
+
+        if !stale.is_empty() {
+            self.delete(Filter::new().ids(stale.iter().copied()), scope)
+                .await
+                .map_err(|e| Error::database(format!("Failed to delete stale versions: {e}")))?;
+        }
+        for id in unindexed {
+            if stale.contains(&id) {
+                continue;
+            }
+            let event = &events[&id];
+            self.delete(Filter::new().id(id), scope)
+                .await
+                .map_err(|e| Error::database(format!("Failed to reindex event {id}: {e}")))?;
+            self.save_event(event, scope)
+                .await
+                .map_err(|e| Error::database(format!("Failed to reindex event {id}: {e}")))?;
+            report.reindexed.push(id);
+        }
+        report.stale_versions.extend(stale);
+        Ok(())
+    }
+
+    /// Events matching `filter`, or `None` if an index row points to a missing event
+    async fn lookup(&self, filter: Filter, scope: &Scope) -> Result<Option<Events>, Error> {
+        let Some((lmdb, lmdb_scope)) = self.env_for(scope, false).await? else {
+            return Ok(Some(Events::new(&Filter::new())));
+        };
+        let scoped_view = lmdb.scoped(&lmdb_scope).map_err(|e| {
+            error!("Error getting scoped view: {:?}", e);
+            Error::database(format!("Failed to get scoped view: {e}"))
+        })?;
+
+        match scoped_view.query(filter).await {
+            Ok(events) => Ok(Some(events)),
+            Err(e) if e.to_string().contains("NotFound") || e.to_string().contains("Not found") => {
+                Ok(None)
+            }
+            Err(e) => Err(Error::database(format!("Database backend error: {e}"))),
+        }
+    }
+
+    /// Mark a relay session as running, returning whether the previous one
+    /// never ended, i.e. the relay stopped without [`RelayDatabase::end_session`]
+    pub fn begin_session(&self) -> Result<bool, Error> {
+        self.ensure_writable("begin a session")?;
+        let marker = self.path.join(SESSION_MARKER);
+        let unclean = marker.exists();
+        std::fs::write(&marker, Timestamp::now().to_string()).map_err(|e| {
+            Error::database(format!("Failed to write session marker {marker:?}: {e}"))
+        })?;
+        Ok(unclean)
+    }
+
+    /// Mark the relay session started with [`RelayDatabase::begin_session`] as cleanly ended
+    pub fn end_session(&self) -> Result<(), Error> {
+        self.ensure_writable("end a session")?;
+        let marker = self.path.join(SESSION_MARKER);
+        match std::fs::remove_file(&marker) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::database(format!(
+                "Failed to remove session marker {marker:?}: {e}"
+            ))),
+        }
+    }
+
     /// Get negentropy items (EventId, Timestamp) for efficient set reconciliation
     pub async fn negentropy_items(
         &self,
         filter: Filter,
         scope: &Scope,
     ) -> Result<Vec<(EventId, Timestamp)>, Error> {
-        let lmdb = Arc::clone(&self.lmdb);
-        let scoped_view = lmdb.scoped(scope).map_err(|e| {
+        let Some((lmdb, lmdb_scope)) = self.env_for(scope, false).await? else {
+            return Ok(Vec::new());
+        };
+        let scoped_view = lmdb.scoped(&lmdb_scope).map_err(|e| {
             error!("Error getting scoped view: {:?}", e);
             Error::database(format!("Failed to get scoped view: {e}"))
         })?;
@@ -226,13 +990,446 @@ impl RelayDatabase {
     pub async fn list_scopes(&self) -> Result<Vec<Scope>, Error> {
         let env = Arc::clone(&self.lmdb);
 
-        let scopes = tokio::task::spawn_blocking(move || env.list_scopes())
+        let mut scopes = tokio::task::spawn_blocking(move || env.list_scopes())
             .await
             .map_err(|e| Error::database(format!("Failed to spawn blocking task: {e}")))?
             .map_err(|e| Error::database(format!("Failed to list scopes: {e}")))?;
 
+        if let Some(shards) = &self.shards {
+            for name in shards.list()? {
+                let scope = Scope::named(&name)
+                    .map_err(|e| Error::database(format!("Invalid scope shard '{name}': {e}")))?;
+                if !scopes.contains(&scope) {
+                    scopes.push(scope);
+                }
+            }
+        }
+
         Ok(scopes)
     }
+
+    /// Delete all data stored for a scope
+    ///
+    /// With scope sharding enabled this closes the scope's environment and removes
+    /// its directory. Otherwise all events in the scope are deleted.
+    pub async fn delete_scope(&self, scope: &Scope) -> Result<(), Error> {
+        self.ensure_writable("delete scopes")?;
+        if let Some(receipts) = self.receipts.clone() {
+            let owned_scope = scope.clone();
+            tokio::task::spawn_blocking(move || receipts.remove_scope(&owned_scope))
+                .await
+                .map_err(|e| Error::database(format!("Failed to spawn blocking task: {e}")))??;
+        }
+        match (&self.shards, scope) {
+            (Some(shards), Scope::Named { name, .. }) => {
+                let shards = Arc::clone(shards);
+                let name = name.to_string();
+                tokio::task::spawn_blocking(move || shards.remove(&name))
+                    .await
+                    .map_err(|e| {
+                        Error::database(format!("Failed to spawn blocking task: {e}"))
+                    })??;
+                if let Some(cache) = &self.query_cache {
+                    cache.invalidate_scope(scope);
+                }
+                Ok(())
+            }
+            _ => self.delete(Filter::new(), scope).await,
+        }
+    }
+
+    /// Apply `writes` to `scope` in order, all of them or none
+    ///
+    /// When a write fails, the events saved so far are deleted again and the
+    /// events they replaced or the deletes removed are restored. The undo is
+    /// compensating rather than a single LMDB transaction: a crash in the
+    /// middle, or a concurrent writer to the same scope, can still observe a
+    /// partial batch. Deletion requests (kind 5) are refused, as the events
+    /// they remove can't be saved again.
+    pub async fn apply_batch(&self, writes: &[BatchWrite], scope: &Scope) -> Result<(), Error> {
+        self.ensure_writable("apply batches")?;
+        if writes.iter().any(
+            |write| matches!(write, BatchWrite::Save(event) if event.kind == Kind::EventDeletion),
+        ) {
+            return Err(Error::invalid("deletion requests can't be part of a batch"));
+        }
+
+        let mut saved = Vec::new();
+        let mut removed = Vec::new();
+        for write in writes {
+            if let Err(e) = self
+                .apply_write(write, scope, &mut saved, &mut removed)
+                .await
+            {
+                warn!(
+                    "Batch write failed for scope {:?}, undoing {} saved events: {}",
+                    scope,
+                    saved.len(),
+                    e
+                );
+                self.undo_batch(saved, removed, scope).await;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply one write of a batch, recording what it saved and what it removed
+    async fn apply_write(
+        &self,
+        write: &BatchWrite,
+        scope: &Scope,
+        saved: &mut Vec<EventId>,
+        removed: &mut Vec<Event>,
+    ) -> Result<(), Error> {
+        match write {
+            BatchWrite::Save(event) => {
+                if self.has_event(&event.id, scope).await? {
+                    return Ok(());
+                }
+                if event.kind.is_replaceable() || event.kind.is_addressable() {
+                    let mut filter = Filter::new().author(event.pubkey).kind(event.kind);
+                    if let Some(identifier) = event.tags.identifier() {
+                        filter = filter.identifier(identifier);
+                    }
+                    removed.extend(self.query_uncached(vec![filter], scope).await?);
+                }
+                self.save_event(event, scope)
+                    .await
+                    .map_err(|e| Error::database(format!("Failed to save event: {e}")))?;
+                saved.push(event.id);
+            }
+            BatchWrite::Delete(filter) => {
+                removed.extend(self.query_uncached(vec![filter.clone()], scope).await?);
+                self.delete(filter.clone(), scope)
+                    .await
+                    .map_err(|e| Error::database(format!("Failed to delete events: {e}")))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the events a failed batch saved and restore the ones it removed
+    async fn undo_batch(&self, saved: Vec<EventId>, mut removed: Vec<Event>, scope: &Scope) {
+        // Events the batch saved and then deleted itself stay gone
+        removed.retain(|event| !saved.contains(&event.id));
+        if !saved.is_empty() {
+            if let Err(e) = self.delete(Filter::new().ids(saved), scope).await {
+                error!("Failed to undo batch saves for scope {:?}: {}", scope, e);
+            }
+        }
+        for event in removed {
+            if let Err(e) = self.save_event(&event, scope).await {
+                error!(
+                    "Failed to restore event {} for scope {:?}: {}",
+                    event.id, scope, e
+                );
+            }
+        }
+    }
+}
+
+/// One write of [`RelayDatabase::apply_batch`]
+#[derive(Debug, Clone)]
+pub enum BatchWrite {
+    /// Save a signed event
+    Save(Event),
+    /// Delete the events matching the filter
+    Delete(Filter),
+}
+
+/// Stored events of one scope, see [`RelayDatabase::scope_stats`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ScopeStorageStats {
+    pub events: usize,
+    pub events_by_kind: BTreeMap<u16, usize>,
+    /// Size of the events as JSON, excluding indexes
+    pub storage_bytes: u64,
+}
+
+/// Damage found and repaired by [`RelayDatabase::verify_and_repair`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IntegrityReport {
+    pub scopes: usize,
+    pub events: usize,
+    /// Events an index missed, saved again to rewrite their index rows
+    pub reindexed: Vec<EventId>,
+    /// Older versions of replaceable and addressable events, deleted
+    pub stale_versions: Vec<EventId>,
+    /// Lookups failing on index rows of missing events, left for the
+    /// `nostr-lmdb-integrity` tool
+    pub corrupt_lookups: usize,
+}
+
+impl IntegrityReport {
+    /// Whether nothing was found to repair or report
+    pub fn is_clean(&self) -> bool {
+        self.reindexed.is_empty() && self.stale_versions.is_empty() && self.corrupt_lookups == 0
+    }
+}
+
+/// Lazily opened per-scope LMDB environments with least-recently-used closing
+#[derive(Debug)]
+struct ScopeShards {
+    root: PathBuf,
+    max_open: usize,
+    options: LmdbOptions,
+    /// Whether missing shards are refused rather than created
+    read_only: bool,
+    open: Mutex<OpenShards>,
+    /// Held while opening or removing a shard, so an environment is never
+    /// opened twice nor removed while it opens
+    opening: Mutex<()>,
+}
+
+/// Open shard environments and their usage order (front is least recently used)
+#[derive(Debug, Default)]
+struct OpenShards {
+    envs: HashMap<String, Arc<NostrLMDB>>,
+    lru: VecDeque<String>,
+}
+
+impl ScopeShards {
+    fn new(root: PathBuf, max_open: usize, options: LmdbOptions) -> Result<Self, Error> {
+        std::fs::create_dir_all(&root).map_err(|e| {
+            Error::database(format!(
+                "Failed to create scope shards directory '{root:?}': {e}"
+            ))
+        })?;
+
+        Ok(Self {
+            root,
+            max_open: max_open.max(1),
+            options,
+            read_only: false,
+            open: Mutex::new(OpenShards::default()),
+            opening: Mutex::new(()),
+        })
+    }
+
+    /// Get the environment for a scope, opening it if needed
+    ///
+    /// A shard missing on disk is only created when `create` is set, `None`
+    /// otherwise. Opening runs on the blocking pool.
+    async fn get(
+        self: &Arc<Self>,
+        name: &str,
+        create: bool,
+    ) -> Result<Option<Arc<NostrLMDB>>, Error> {
+        if let Some(env) = self.open_env(name) {
+            return Ok(Some(env));
+        }
+        let shards = Arc::clone(self);
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || shards.open_blocking(&name, create))
+            .await
+            .map_err(|e| Error::database(format!("Failed to spawn blocking task: {e}")))?
+    }
+
+    /// The environment of a scope if it is open
+    fn open_env(&self, name: &str) -> Option<Arc<NostrLMDB>> {
+        let mut open = self.open.lock();
+        let env = open.envs.get(name).cloned()?;
+        open.touch(name);
+        Some(env)
+    }
+
+    fn open_blocking(&self, name: &str, create: bool) -> Result<Option<Arc<NostrLMDB>>, Error> {
+        let _opening = self.opening.lock();
+        // Another task may have opened it in the meantime
+        if let Some(env) = self.open_env(name) {
+            return Ok(Some(env));
+        }
+
+        let path = self.root.join(shard_dir_name(name));
+        if !path.join(LMDB_DATA_FILE).exists() {
+            if self.read_only || !create {
+                return Ok(None);
+            }
+            std::fs::create_dir_all(&path).map_err(|e| {
+                Error::database(format!("Failed to create scope shard '{path:?}': {e}"))
+            })?;
+        }
+
+        debug!("Opening LMDB shard for scope '{}' at {:?}", name, path);
+        let env =
+            Arc::new(self.options.open(&path).map_err(|e| {
+                Error::database(format!("Failed to open scope shard '{path:?}': {e}"))
+            })?);
+
+        let mut open = self.open.lock();
+        open.envs.insert(name.to_string(), Arc::clone(&env));
+        open.lru.push_back(name.to_string());
+        open.evict(self.max_open);
+
+        Ok(Some(env))
+    }
+
+    /// Names of all scopes that have a shard on disk
+    fn list(&self) -> Result<Vec<String>, Error> {
+        let entries = std::fs::read_dir(&self.root).map_err(|e| {
+            Error::database(format!(
+                "Failed to read scope shards directory '{:?}': {e}",
+                self.root
+            ))
+        })?;
+
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().and_then(scope_name_from_dir))
+            .collect())
+    }
+
+    /// Close a scope's environment and remove its directory
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        let _opening = self.opening.lock();
+        {
+            let mut open = self.open.lock();
+            if let Some(env) = open.envs.remove(name) {
+                if Arc::strong_count(&env) > 1 {
+                    // Put it back, we can't delete files under an environment in use
+                    open.envs.insert(name.to_string(), env);
+                    return Err(Error::database(format!(
+                        "Scope shard '{name}' is in use and cannot be removed"
+                    )));
+                }
+            }
+            open.lru.retain(|n| n != name);
+        }
+
+        let path = self.root.join(shard_dir_name(name));
+        if path.exists() {
+            std::fs::remove_dir_all(&path).map_err(|e| {
+                Error::database(format!("Failed to remove scope shard '{path:?}': {e}"))
+            })?;
+        }
+
+        info!("Removed scope shard '{}'", name);
+        Ok(())
+    }
+}
+
+impl OpenShards {
+    fn touch(&mut self, name: &str) {
+        if let Some(pos) = self.lru.iter().position(|n| n == name) {
+            if let Some(name) = self.lru.remove(pos) {
+                self.lru.push_back(name);
+            }
+        }
+    }
+
+    /// Close least recently used environments until at most `max_open` remain.
+    /// Environments still referenced by in-flight operations are skipped.
+    fn evict(&mut self, max_open: usize) {
+        let mut skipped = 0;
+        while self.envs.len() > max_open && skipped < self.lru.len() {
+            let Some(name) = self.lru.pop_front() else {
+                break;
+            };
+
+            let in_use = self
+                .envs
+                .get(&name)
+                .map(|env| Arc::strong_count(env) > 1)
+                .unwrap_or(false);
+
+            if in_use {
+                self.lru.push_back(name);
+                skipped += 1;
+                continue;
+            }
+
+            debug!("Closing LMDB shard for scope '{}'", name);
+            self.envs.remove(&name);
+        }
+
+        if self.envs.len() > max_open {
+            warn!(
+                "{} scope shards open (limit {}), all busy",
+                self.envs.len(),
+                max_open
+            );
+        }
+    }
+}
+
+/// Prefix for shard directories whose scope name is hex encoded
+const HEX_SHARD_PREFIX: &str = "x-";
+
+/// Directory name for a scope shard
+///
+/// Names made of ASCII alphanumerics, `-` and `_` are used verbatim so operators
+/// can find a tenant's directory; anything else is hex encoded.
+fn shard_dir_name(name: &str) -> String {
+    let is_plain = !name.is_empty()
+        && !name.starts_with(HEX_SHARD_PREFIX)
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if is_plain {
+        name.to_string()
+    } else {
+        format!("{HEX_SHARD_PREFIX}{}", hex::encode(name))
+    }
+}
+
+/// Whether an LMDB error message reports a full map (`MDB_MAP_FULL`)
+fn is_map_full(message: &str) -> bool {
+    message.contains("MDB_MAP_FULL") || message.contains("mapsize limit reached")
+}
+
+/// Scope name for a shard directory (inverse of [`shard_dir_name`])
+fn scope_name_from_dir(dir: &str) -> Option<String> {
+    match dir.strip_prefix(HEX_SHARD_PREFIX) {
+        Some(encoded) => hex::decode(encoded)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok()),
+        None => Some(dir.to_string()),
+    }
+}
+
+/// A pool of read-only database handles used to serve historical queries
+///
+/// Queries are spread across the replicas in round-robin order so heavy REQ
+/// traffic doesn't contend with the primary database used for writes.
+#[derive(Debug, Clone)]
+pub struct ReadReplicas {
+    replicas: Arc<Vec<Arc<RelayDatabase>>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl ReadReplicas {
+    /// Create a new replica pool
+    ///
+    /// Returns `None` if no replicas are given.
+    pub fn new(replicas: Vec<Arc<RelayDatabase>>) -> Option<Self> {
+        if replicas.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            replicas: Arc::new(replicas),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Pick the next replica to serve a query
+    pub fn pick(&self) -> &Arc<RelayDatabase> {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+        &self.replicas[idx]
+    }
+
+    /// Number of replicas in the pool
+    pub fn len(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Whether the pool has no replicas (never true for a constructed pool)
+    pub fn is_empty(&self) -> bool {
+        self.replicas.is_empty()
+    }
 }
 
 #[cfg(test)]
@@ -386,4 +1583,268 @@ mod tests {
         assert_eq!(count_b, 3);
         assert_eq!(count_default, 3);
     }
+
+    #[tokio::test]
+    async fn test_read_replicas_round_robin() {
+        let tmp_dir = TempDir::new().unwrap();
+        let replica_a = Arc::new(RelayDatabase::new(tmp_dir.path().join("replica_a.db")).unwrap());
+        let replica_b = Arc::new(RelayDatabase::new(tmp_dir.path().join("replica_b.db")).unwrap());
+
+        assert!(ReadReplicas::new(vec![]).is_none());
+
+        let replicas = ReadReplicas::new(vec![replica_a.clone(), replica_b.clone()]).unwrap();
+        assert_eq!(replicas.len(), 2);
+        assert!(Arc::ptr_eq(replicas.pick(), &replica_a));
+        assert!(Arc::ptr_eq(replicas.pick(), &replica_b));
+        assert!(Arc::ptr_eq(replicas.pick(), &replica_a));
+    }
+
+    #[tokio::test]
+    async fn test_scope_sharding_isolates_environments() {
+        let tmp_dir = TempDir::new().unwrap();
+        let db_path = tmp_dir.path().join("test_sharded.db");
+
+        let database = RelayDatabase::with_scope_sharding(&db_path, 1).unwrap();
+        assert!(database.is_scope_sharded());
+
+        let scope_a = Scope::named("tenant_a").unwrap();
+        let scope_b = Scope::named("tenant-b").unwrap();
+
+        for (i, scope) in [&scope_a, &scope_b, &Scope::Default].iter().enumerate() {
+            let event = generate_test_event(i).await;
+            database.save_event(&event, scope).await.unwrap();
+        }
+
+        // Each named scope has its own directory
+        assert!(db_path.join(SCOPE_SHARDS_DIR).join("tenant_a").is_dir());
+        assert!(db_path.join(SCOPE_SHARDS_DIR).join("tenant-b").is_dir());
+
+        // Reopening an evicted shard still sees its data
+        for scope in [&scope_a, &scope_b, &Scope::Default] {
+            let count = database.count(vec![Filter::new()], scope).await.unwrap();
+            assert_eq!(count, 1);
+        }
+
+        let scopes = database.list_scopes().await.unwrap();
+        assert!(scopes.contains(&scope_a));
+        assert!(scopes.contains(&scope_b));
+
+        // Deleting a tenant removes its directory
+        database.delete_scope(&scope_a).await.unwrap();
+        assert!(!db_path.join(SCOPE_SHARDS_DIR).join("tenant_a").exists());
+        let count = database.count(vec![Filter::new()], &scope_a).await.unwrap();
+        assert_eq!(count, 0);
+
+        // Reads of an unknown scope don't create a shard
+        let unknown = Scope::named("unknown").unwrap();
+        assert!(database
+            .query(vec![Filter::new()], &unknown)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            database.count(vec![Filter::new()], &unknown).await.unwrap(),
+            0
+        );
+        assert!(database
+            .negentropy_items(Filter::new(), &unknown)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(!db_path.join(SCOPE_SHARDS_DIR).join("unknown").exists());
+        assert!(!db_path.join(SCOPE_SHARDS_DIR).join("tenant_a").exists());
+    }
+
+    #[test]
+    fn test_shard_dir_name_roundtrip() {
+        for name in ["tenant", "a.b", "x-tricky", "../escape", "ünï"] {
+            let dir = shard_dir_name(name);
+            assert!(!dir.contains('/') && !dir.contains('.'));
+            assert_eq!(scope_name_from_dir(&dir).as_deref(), Some(name));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_cache_invalidated_on_write() {
+        let tmp_dir = TempDir::new().unwrap();
+        let database = RelayDatabase::new(tmp_dir.path().join("test_cache.db"))
+            .unwrap()
+            .with_query_cache(std::num::NonZeroUsize::new(16).unwrap());
+
+        let keys = Keys::generate();
+        let filters = vec![Filter::new().author(keys.public_key())];
+
+        let first = EventBuilder::text_note("first")
+            .sign_with_keys(&keys)
+            .unwrap();
+        database.save_event(&first, &Scope::Default).await.unwrap();
+
+        assert_eq!(
+            database
+                .query(filters.clone(), &Scope::Default)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            database
+                .query(filters.clone(), &Scope::Default)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let stats = database.query_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        // A new event by the same author must not be hidden by the cache
+        let second = EventBuilder::text_note("second")
+            .sign_with_keys(&keys)
+            .unwrap();
+        database.save_event(&second, &Scope::Default).await.unwrap();
+        assert_eq!(
+            database
+                .query(filters, &Scope::Default)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scope_stats() {
+        let tmp_dir = TempDir::new().unwrap();
+        let database = RelayDatabase::new(tmp_dir.path().join("test_stats.db")).unwrap();
+        let alice = Scope::named("alice").unwrap();
+
+        let note = generate_test_event(0).await;
+        let metadata = EventBuilder::metadata(&Metadata::new().name("alice"))
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        database.save_event(&note, &alice).await.unwrap();
+        database.save_event(&metadata, &alice).await.unwrap();
+        database
+            .save_event(&generate_test_event(1).await, &Scope::Default)
+            .await
+            .unwrap();
+
+        let stats = database.scope_stats(&alice).await.unwrap();
+        assert_eq!(stats.events, 2);
+        assert_eq!(stats.events_by_kind, BTreeMap::from([(0, 1), (1, 1)]));
+        assert_eq!(
+            stats.storage_bytes,
+            (note.as_json().len() + metadata.as_json().len()) as u64
+        );
+        assert_eq!(
+            database
+                .scope_stats(&Scope::named("bob").unwrap())
+                .await
+                .unwrap(),
+            ScopeStorageStats::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_clean_database_and_sessions() {
+        let tmp_dir = TempDir::new().unwrap();
+        let database = RelayDatabase::new(tmp_dir.path().join("test_verify.db")).unwrap();
+        let alice = Scope::named("alice").unwrap();
+        for index in 0..3 {
+            database
+                .save_event(&generate_test_event(index).await, &alice)
+                .await
+                .unwrap();
+        }
+        let metadata = EventBuilder::metadata(&Metadata::new().name("bob"))
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        database
+            .save_event(&metadata, &Scope::Default)
+            .await
+            .unwrap();
+
+        let report = database.verify_and_repair().await.unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.events, 4);
+        assert_eq!(report.scopes, 2);
+
+        // A session left running is reported by the next one
+        assert!(!database.begin_session().unwrap());
+        assert!(database.begin_session().unwrap());
+        database.end_session().unwrap();
+        assert!(!database.begin_session().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tuned_environments_and_map_usage() {
+        let tmp_dir = TempDir::new().unwrap();
+        let options = LmdbOptions::default()
+            .with_map_size(64 * 1024 * 1024)
+            .with_max_readers(16);
+        let database =
+            RelayDatabase::with_scope_sharding_options(tmp_dir.path().join("db"), 2, options)
+                .unwrap();
+        assert_eq!(database.options(), &options);
+
+        let tenant = Scope::named("tenant").unwrap();
+        database
+            .save_event(&generate_test_event(0).await, &tenant)
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .query(vec![Filter::new()], &tenant)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let usage = database.map_usage().unwrap();
+        assert_eq!(usage.map_size, 64 * 1024 * 1024);
+        assert!(usage.used_bytes > 0 && usage.ratio() <= 1.0);
+
+        assert!(is_map_full("mdb_put failed: MDB_MAP_FULL"));
+        assert!(!is_map_full("MDB_NOTFOUND"));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_database_refuses_writes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let db_path = tmp_dir.path().join("db");
+        assert!(RelayDatabase::open_read_only(&db_path).is_err());
+
+        let event = generate_test_event(0).await;
+        {
+            let database = RelayDatabase::new(&db_path).unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+        // Let the writer's environment close before opening it again in this process
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let database = RelayDatabase::open_read_only(&db_path).unwrap();
+        assert!(database.is_read_only());
+        assert!(database
+            .has_event(&event.id, &Scope::Default)
+            .await
+            .unwrap());
+        assert!(database
+            .save_event(&generate_test_event(1).await, &Scope::Default)
+            .await
+            .is_err());
+        assert!(database
+            .delete(Filter::new(), &Scope::Default)
+            .await
+            .is_err());
+        assert!(database.begin_session().is_err());
+        assert_eq!(
+            database
+                .count(vec![Filter::new()], &Scope::Default)
+                .await
+                .unwrap(),
+            1
+        );
+    }
 }
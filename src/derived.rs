@@ -0,0 +1,296 @@
+//! Derived state kept up to date from stored events
+//!
+//! Policies like a web of trust, mute lists or group rosters read state that
+//! is derived from a few kinds of events: contact lists, mute lists, member
+//! lists. Querying the raw events on every check is slow, so a
+//! [`DerivedIndex`] maintains the derived state instead, e.g. a follow graph
+//! table, and [`DerivedIndexes`] keeps it consistent with the database:
+//!
+//! - when the relay starts, each index is reset and replayed from the stored
+//!   events of its kinds, oldest first, scope by scope
+//! - afterwards every event of its kinds the relay stores is applied, in the
+//!   order events were stored, one at a time per relay
+//!
+//! Events stored while the replay runs are queued and applied after it, so
+//! they may be applied twice: [`DerivedIndex::apply`] must be idempotent.
+//! [`DerivedIndexes::is_ready`] tells when the replay is over. Register the
+//! indexes with
+//! [`RelayBuilder::with_derived_indexes`](crate::RelayBuilder::with_derived_indexes).
+
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::event_sink::EventSink;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// Stored events waiting to be applied before saving waits for room
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// State derived from the stored events of some kinds
+#[async_trait]
+pub trait DerivedIndex: Send + Sync + std::fmt::Debug {
+    /// Kinds whose events the state is derived from
+    fn kinds(&self) -> Vec<Kind>;
+
+    /// Drop the state derived for `scope`, before it is replayed
+    async fn reset(&self, scope: &Scope) -> Result<()>;
+
+    /// Update the state with `event`, stored in `scope`
+    ///
+    /// Called with the events of [`Self::kinds`] in the order they were
+    /// stored, possibly more than once for the same event.
+    async fn apply(&self, event: &Event, scope: &Scope) -> Result<()>;
+}
+
+#[derive(Debug, Clone)]
+struct Registered {
+    name: String,
+    kinds: HashSet<Kind>,
+    index: Arc<dyn DerivedIndex>,
+}
+
+/// Derived indexes fed by the relay's write path
+#[derive(Clone)]
+pub struct DerivedIndexes {
+    indexes: Arc<Vec<Registered>>,
+    queue: flume::Sender<(Arc<Event>, Scope)>,
+    receiver: flume::Receiver<(Arc<Event>, Scope)>,
+    ready: Arc<AtomicBool>,
+}
+
+impl std::fmt::Debug for DerivedIndexes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DerivedIndexes")
+            .field(
+                "indexes",
+                &self
+                    .indexes
+                    .iter()
+                    .map(|registered| &registered.name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("queued", &self.queue.len())
+            .field("ready", &self.is_ready())
+            .finish()
+    }
+}
+
+impl Default for DerivedIndexes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DerivedIndexes {
+    /// No index yet
+    pub fn new() -> Self {
+        let (queue, receiver) = flume::bounded(QUEUE_CAPACITY);
+        Self {
+            indexes: Arc::new(Vec::new()),
+            queue,
+            receiver,
+            ready: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Maintain `index`, named `name` in logs
+    #[must_use]
+    pub fn with_index(
+        mut self,
+        name: impl Into<String>,
+        index: impl DerivedIndex + 'static,
+    ) -> Self {
+        Arc::make_mut(&mut self.indexes).push(Registered {
+            name: name.into(),
+            kinds: index.kinds().into_iter().collect(),
+            index: Arc::new(index),
+        });
+        self
+    }
+
+    /// Whether the startup replay is over and stored events are applied as they come
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Reset every index and replay the stored events of its kinds, returning how many
+    ///
+    /// Each index reads all the events of its kinds in a scope at once.
+    pub async fn replay(&self, database: &RelayDatabase) -> Result<usize> {
+        let mut scopes = database.list_scopes().await?;
+        if !scopes.contains(&Scope::Default) {
+            scopes.insert(0, Scope::Default);
+        }
+
+        let mut replayed = 0;
+        for scope in &scopes {
+            for registered in self.indexes.iter() {
+                registered.index.reset(scope).await?;
+                let filter = Filter::new().kinds(registered.kinds.iter().copied());
+                let mut events: Vec<Event> = database
+                    .query(vec![filter], scope)
+                    .await?
+                    .into_iter()
+                    .collect();
+                events.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+                for event in &events {
+                    apply(registered, event, scope).await;
+                }
+                replayed += events.len();
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Replay the indexes, then apply stored events until `cancellation_token` fires
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+        database: Arc<RelayDatabase>,
+    ) {
+        let indexes = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            match indexes.replay(&database).await {
+                Ok(replayed) => info!("Replayed {} events into derived indexes", replayed),
+                Err(e) => warn!("Failed to replay derived indexes: {}", e),
+            }
+            indexes.ready.store(true, Ordering::Release);
+
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    stored = indexes.receiver.recv_async() => {
+                        let Ok((event, scope)) = stored else { break };
+                        for registered in indexes.indexes.iter() {
+                            if registered.kinds.contains(&event.kind) {
+                                apply(registered, &event, &scope).await;
+                            }
+                        }
+                    }
+                }
+            }
+            debug!("Derived indexes stopped");
+        });
+    }
+}
+
+async fn apply(registered: &Registered, event: &Event, scope: &Scope) {
+    if let Err(e) = registered.index.apply(event, scope).await {
+        warn!(
+            "Derived index '{}' failed on event {}: {}",
+            registered.name, event.id, e
+        );
+    }
+}
+
+#[async_trait]
+impl EventSink for DerivedIndexes {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        if self
+            .indexes
+            .iter()
+            .any(|registered| registered.kinds.contains(&event.kind))
+        {
+            // Waits for room rather than letting the state drift
+            let _ = self.queue.send_async((event, scope.clone())).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    /// Follows per pubkey, from the latest contact list
+    #[derive(Debug, Default)]
+    struct FollowCounts {
+        lists: Mutex<HashMap<PublicKey, (Timestamp, usize)>>,
+    }
+
+    #[async_trait]
+    impl DerivedIndex for Arc<FollowCounts> {
+        fn kinds(&self) -> Vec<Kind> {
+            vec![Kind::ContactList]
+        }
+
+        async fn reset(&self, _: &Scope) -> Result<()> {
+            self.lists.lock().clear();
+            Ok(())
+        }
+
+        async fn apply(&self, event: &Event, _: &Scope) -> Result<()> {
+            let mut lists = self.lists.lock();
+            let follows = event.tags.public_keys().count();
+            let latest = lists
+                .entry(event.pubkey)
+                .or_insert((event.created_at, follows));
+            if event.created_at >= latest.0 {
+                *latest = (event.created_at, follows);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_then_live_updates() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let contact_list = |follows: usize, created_at: u64| {
+            EventBuilder::new(Kind::ContactList, "")
+                .tags((0..follows).map(|_| Tag::public_key(Keys::generate().public_key())))
+                .custom_created_at(Timestamp::from(created_at))
+                .sign_with_keys(&keys)
+                .unwrap()
+        };
+        database
+            .save_event(&contact_list(2, 1000), &Scope::Default)
+            .await
+            .unwrap();
+
+        let counts = Arc::new(FollowCounts::default());
+        let indexes = DerivedIndexes::new().with_index("follows", counts.clone());
+        let task_tracker = TaskTracker::new();
+        let cancellation_token = CancellationToken::new();
+        indexes.spawn(&task_tracker, Some(cancellation_token.clone()), database);
+
+        while !indexes.is_ready() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(counts.lists.lock()[&keys.public_key()].1, 2);
+
+        // Stored events of other kinds are not queued
+        let note = EventBuilder::text_note("hi").sign_with_keys(&keys).unwrap();
+        indexes
+            .on_event_stored(Arc::new(note), &Scope::Default)
+            .await;
+        indexes
+            .on_event_stored(Arc::new(contact_list(5, 2000)), &Scope::Default)
+            .await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while counts.lists.lock()[&keys.public_key()].1 != 5 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(indexes.receiver.is_empty());
+
+        cancellation_token.cancel();
+        task_tracker.close();
+        task_tracker.wait().await;
+    }
+}
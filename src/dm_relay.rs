@@ -0,0 +1,180 @@
+//! NIP-17 DM relay profile
+//!
+//! A DM relay is what users list in their kind 10050 event: an inbox that
+//! only stores NIP-59 gift wraps (kind 1059) and DM relay lists, and only hands
+//! a gift wrap to the recipient it is addressed to. [`DmRelayProcessor`]
+//! enforces those rules; [`RelayBuilder::with_dm_relay_mode`] installs it
+//! together with NIP-42 authentication, the accepted kinds and the NIP-11
+//! advertisement.
+//!
+//! [`RelayBuilder::with_dm_relay_mode`]: crate::RelayBuilder::with_dm_relay_mode
+
+use crate::error::{Error, Result};
+use crate::event_processor::{EventContext, EventProcessor};
+use crate::subscription_coordinator::StoreCommand;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+
+/// Kind of NIP-59 gift wraps
+pub const GIFT_WRAP_KIND: u16 = 1059;
+
+/// Kind of NIP-17 DM relay lists
+pub const DM_RELAY_LIST_KIND: u16 = 10050;
+
+/// NIPs a DM relay supports on top of NIP-01
+pub const DM_RELAY_NIPS: [u16; 3] = [17, 42, 59];
+
+/// Event processor of a NIP-17 DM relay
+///
+/// - Reading and writing require NIP-42 authentication.
+/// - Only gift wraps and DM relay lists are accepted, and a DM relay list only
+///   from its author.
+/// - A gift wrap is only delivered to the pubkeys of its `p` tags.
+#[derive(Debug, Clone)]
+pub struct DmRelayProcessor<T = ()> {
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T> Default for DmRelayProcessor<T> {
+    fn default() -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T> EventProcessor<T> for DmRelayProcessor<T>
+where
+    T: Send + Sync + std::fmt::Debug + 'static,
+{
+    fn can_see_event(
+        &self,
+        event: &Event,
+        _custom_state: Arc<parking_lot::RwLock<T>>,
+        context: EventContext<'_>,
+    ) -> Result<bool> {
+        let Some(authed_pubkey) = context.authed_pubkey else {
+            return Ok(false);
+        };
+        if event.kind.as_u16() != GIFT_WRAP_KIND {
+            return Ok(true);
+        }
+        Ok(event
+            .tags
+            .public_keys()
+            .any(|recipient| recipient == authed_pubkey))
+    }
+
+    fn verify_filters(
+        &self,
+        _filters: &[Filter],
+        _custom_state: Arc<parking_lot::RwLock<T>>,
+        context: EventContext<'_>,
+    ) -> Result<()> {
+        if context.authed_pubkey.is_none() {
+            return Err(Error::auth_required(
+                "DM relays only serve authenticated users",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn handle_event(
+        &self,
+        event: Event,
+        _custom_state: Arc<parking_lot::RwLock<T>>,
+        context: EventContext<'_>,
+    ) -> Result<Vec<StoreCommand>> {
+        let Some(authed_pubkey) = context.authed_pubkey else {
+            return Err(Error::auth_required(
+                "DM relays only accept authenticated users",
+            ));
+        };
+        match event.kind.as_u16() {
+            GIFT_WRAP_KIND => {}
+            DM_RELAY_LIST_KIND if event.pubkey == *authed_pubkey => {}
+            DM_RELAY_LIST_KIND => {
+                return Err(Error::restricted(
+                    "DM relay lists can only be published by their author",
+                ))
+            }
+            _ => {
+                return Err(Error::restricted(
+                    "only gift wraps and DM relay lists are accepted",
+                ))
+            }
+        }
+        Ok(vec![(event, context.subdomain.clone()).into()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_lmdb::Scope;
+
+    #[tokio::test]
+    async fn test_gift_wraps_reach_only_their_recipient() {
+        let processor = DmRelayProcessor::<()>::default();
+        let state = Arc::new(parking_lot::RwLock::new(()));
+        let relay_pubkey = Keys::generate().public_key();
+        let recipient = Keys::generate().public_key();
+        let stranger = Keys::generate().public_key();
+        let context = |authed_pubkey| EventContext {
+            authed_pubkey,
+            subdomain: &Scope::Default,
+            relay_pubkey: &relay_pubkey,
+        };
+
+        let gift_wrap = EventBuilder::new(Kind::from(GIFT_WRAP_KIND), "sealed")
+            .tag(Tag::public_key(recipient))
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+
+        assert!(processor
+            .can_see_event(&gift_wrap, state.clone(), context(Some(&recipient)))
+            .unwrap());
+        for authed_pubkey in [Some(&stranger), None] {
+            assert!(!processor
+                .can_see_event(&gift_wrap, state.clone(), context(authed_pubkey))
+                .unwrap());
+        }
+        assert!(processor
+            .verify_filters(&[Filter::new()], state.clone(), context(None))
+            .is_err());
+
+        // Anyone authenticated may send a gift wrap, nothing else but their relay list
+        assert!(processor
+            .handle_event(gift_wrap.clone(), state.clone(), context(Some(&stranger)))
+            .await
+            .is_ok());
+        assert!(processor
+            .handle_event(gift_wrap, state.clone(), context(None))
+            .await
+            .is_err());
+
+        let author = Keys::generate();
+        let note = EventBuilder::text_note("hi")
+            .sign_with_keys(&author)
+            .unwrap();
+        let relay_list = EventBuilder::new(Kind::from(DM_RELAY_LIST_KIND), "")
+            .tag(Tag::parse(["relay", "wss://inbox.example.com"]).unwrap())
+            .sign_with_keys(&author)
+            .unwrap();
+        let author_pubkey = author.public_key();
+        assert!(processor
+            .handle_event(note, state.clone(), context(Some(&author_pubkey)))
+            .await
+            .is_err());
+        assert!(processor
+            .handle_event(relay_list.clone(), state.clone(), context(Some(&stranger)))
+            .await
+            .is_err());
+        assert!(processor
+            .handle_event(relay_list, state, context(Some(&author_pubkey)))
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,169 @@
+//! Connection draining for rolling deploys
+//!
+//! [`ConnectionDrain::drain`] takes a relay out of rotation without cutting
+//! its clients off: from then on new WebSocket connections get a
+//! `restricted:` NOTICE, naming the node to reconnect to when one is given,
+//! and are closed, and the readiness probe fails so load balancers stop
+//! routing to the relay. Connections already open keep their subscriptions
+//! until they leave or the deadline passes; the remaining ones are then
+//! detached with the same NOTICE and the relay's cancellation token is
+//! cancelled, which shuts it down like any other stop.
+//!
+//! Register the drain with
+//! [`RelayBuilder::with_connection_drain`](crate::RelayBuilder::with_connection_drain),
+//! keep a clone and call [`ConnectionDrain::drain`] from the deploy hook,
+//! e.g. on `SIGTERM`.
+
+use crate::connection_hook::ConnectionHook;
+use crate::error::{Error, Result};
+use crate::subscription_registry::{ConnectionStats, SubscriptionRegistry};
+use crate::utils::Attachment;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Start of the NOTICE sent to connections refused or detached while draining
+pub const DRAINING_NOTICE_PREFIX: &str = "restricted: relay is draining";
+
+/// How often draining checks whether connections are left
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Relay components draining works with, attached when the relay is built
+pub(crate) struct DrainContext {
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
+}
+
+/// Drains the relay's connections before it stops
+#[derive(Clone, Default)]
+pub struct ConnectionDrain {
+    /// NOTICE of refused connections, set once draining started
+    notice: Arc<RwLock<Option<String>>>,
+    context: Attachment<DrainContext>,
+}
+
+impl std::fmt::Debug for ConnectionDrain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionDrain")
+            .field("draining", &self.is_draining())
+            .field("attached", &self.context.is_attached())
+            .finish()
+    }
+}
+
+impl ConnectionDrain {
+    /// Not draining until [`Self::drain`] is called
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drain the relay built with this drain
+    pub(crate) fn attach(&self, context: DrainContext) {
+        self.context.attach(context, "Connection drain");
+    }
+
+    /// Whether new connections are refused
+    pub fn is_draining(&self) -> bool {
+        self.notice.read().is_some()
+    }
+
+    /// Refuse new connections, wait for open ones to leave until `deadline`,
+    /// then detach the rest and shut the relay down
+    ///
+    /// `redirect`, e.g. the URL of another node, is added to the NOTICE.
+    /// Returns the number of connections detached at the deadline.
+    pub async fn drain(&self, deadline: Duration, redirect: Option<&str>) -> Result<usize> {
+        let context = self
+            .context
+            .get()
+            .ok_or_else(|| Error::internal("Connection drain is not attached to a relay"))?;
+        let notice = match redirect {
+            Some(redirect) => format!("{DRAINING_NOTICE_PREFIX}, reconnect to {redirect}"),
+            None => format!("{DRAINING_NOTICE_PREFIX}, reconnect to another node"),
+        };
+        *self.notice.write() = Some(notice.clone());
+        info!(
+            "Draining {} connections within {:?}",
+            context.registry.connection_count(),
+            deadline
+        );
+
+        let deadline = Instant::now() + deadline;
+        while context.registry.connection_count() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL.min(deadline - Instant::now())).await;
+        }
+
+        let detached = context.registry.disconnect_all(&notice);
+        info!("Drained, detached {} connections at the deadline", detached);
+        if let Some(cancellation_token) = &context.cancellation_token {
+            cancellation_token.cancel();
+        }
+        Ok(detached)
+    }
+}
+
+impl ConnectionHook for ConnectionDrain {
+    fn on_connect(&self, _: &ConnectionStats) -> std::result::Result<(), String> {
+        match self.notice.read().as_ref() {
+            Some(notice) => {
+                if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+                    metrics.record_connection_rejected("draining");
+                }
+                Err(notice.clone())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use websocket_builder::MessageSender;
+
+    #[tokio::test]
+    async fn test_drain_refuses_new_connections_then_shuts_down() {
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+        let drain = ConnectionDrain::new();
+        drain.attach(DrainContext {
+            registry: registry.clone(),
+            cancellation_token: Some(cancellation_token.clone()),
+        });
+
+        let (tx, rx) = flume::bounded(10);
+        let _handle = registry.register_connection(
+            "conn1".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(nostr_lmdb::Scope::Default),
+        );
+        let stats = registry.connection_stats("conn1").unwrap();
+        assert_eq!(drain.on_connect(&stats), Ok(()));
+
+        let draining = tokio::spawn({
+            let drain = drain.clone();
+            async move {
+                drain
+                    .drain(Duration::from_millis(300), Some("wss://b.example.com"))
+                    .await
+            }
+        });
+        while !drain.is_draining() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            drain.on_connect(&stats),
+            Err("restricted: relay is draining, reconnect to wss://b.example.com".to_string())
+        );
+
+        // The open connection stays until the deadline, then is detached
+        assert_eq!(draining.await.unwrap().unwrap(), 1);
+        assert_eq!(registry.connection_count(), 0);
+        assert!(cancellation_token.is_cancelled());
+        assert!(!rx.is_empty());
+    }
+}
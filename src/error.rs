@@ -1,9 +1,29 @@
 //! Error types for the relay builder framework
+//!
+//! Errors are either caused by the client (a bad filter, an oversized
+//! message, missing authentication, ...) or by the relay itself (a database
+//! failure, a closed channel, ...). [`Error::to_relay_message`] answers the
+//! client with the matching `OK`, `CLOSED` or `NOTICE`. Client errors carry
+//! their message to the client, while internal errors are only described as
+//! [`INTERNAL_ERROR_MESSAGE`] so database and system details never leak; the
+//! [`Display`](std::fmt::Display) implementation keeps the details for logs.
 
+use nostr_sdk::{EventId, RelayMessage, SubscriptionId};
 use snafu::{Backtrace, Snafu};
 
+/// Reason sent to clients for internal errors
+pub const INTERNAL_ERROR_MESSAGE: &str = "error: internal error";
+
+/// The client message an error answers
+#[derive(Debug, Clone)]
+pub enum ClientMessageId {
+    Event(EventId),
+    Subscription(String),
+}
+
 #[derive(Debug, Snafu)]
 pub enum Error {
+    // Internal errors
     #[snafu(display("Internal error: {message}"))]
     Internal {
         message: String,
@@ -22,6 +42,28 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
+    /// A channel to a connection or a background task was closed
+    #[snafu(display("Channel closed: {message}"))]
+    ChannelClosed {
+        message: String,
+        backtrace: Backtrace,
+    },
+
+    // Client errors
+    /// A malformed request, e.g. a bad filter or an undecodable message
+    #[snafu(display("Invalid request: {message}"))]
+    Invalid {
+        message: String,
+        backtrace: Backtrace,
+    },
+
+    /// A request over one of the relay's size limits
+    #[snafu(display("Too large: {message}"))]
+    TooLarge {
+        message: String,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Protocol error: {message}"))]
     Protocol {
         message: String,
@@ -40,6 +82,12 @@ pub enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Rate limited: {message}"))]
+    RateLimited {
+        message: String,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Notice: {message}"))]
     Notice {
         message: String,
@@ -78,6 +126,30 @@ impl Error {
         }
     }
 
+    /// Create a channel closed error
+    pub fn channel_closed(message: impl Into<String>) -> Self {
+        Self::ChannelClosed {
+            message: message.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// Create an invalid request error
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::Invalid {
+            message: message.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
+    /// Create a too large error
+    pub fn too_large(message: impl Into<String>) -> Self {
+        Self::TooLarge {
+            message: message.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
     /// Create a protocol error
     pub fn protocol(message: impl Into<String>) -> Self {
         Self::Protocol {
@@ -102,6 +174,14 @@ impl Error {
         }
     }
 
+    /// Create a rate limited error
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self::RateLimited {
+            message: message.into(),
+            backtrace: Backtrace::capture(),
+        }
+    }
+
     /// Create a notice error
     pub fn notice(message: impl Into<String>) -> Self {
         Self::Notice {
@@ -130,6 +210,69 @@ impl Error {
             backtrace: Backtrace::capture(),
         }
     }
+
+    /// Whether the client caused the error, as opposed to a failure of the relay
+    pub fn is_client_error(&self) -> bool {
+        !matches!(
+            self,
+            Self::Internal { .. }
+                | Self::WebSocket { .. }
+                | Self::Database { .. }
+                | Self::ChannelClosed { .. }
+        )
+    }
+
+    /// The reason to send to the client, with its NIP-01 prefix
+    ///
+    /// Internal errors are all reported as [`INTERNAL_ERROR_MESSAGE`].
+    pub fn client_message(&self) -> String {
+        match self {
+            Self::Internal { .. }
+            | Self::WebSocket { .. }
+            | Self::Database { .. }
+            | Self::ChannelClosed { .. } => INTERNAL_ERROR_MESSAGE.to_string(),
+            Self::Invalid { message, .. }
+            | Self::TooLarge { message, .. }
+            | Self::Protocol { message, .. } => format!("invalid: {message}"),
+            Self::AuthRequired { message, .. } => format!("auth-required: {message}"),
+            Self::Restricted { message, .. } => format!("restricted: {message}"),
+            Self::RateLimited { message, .. } => format!("rate-limited: {message}"),
+            Self::Notice { message, .. } => message.clone(),
+            Self::EventError { message, .. } | Self::SubscriptionError { message, .. } => {
+                format!("error: {message}")
+            }
+        }
+    }
+
+    /// The message answering the client message `id` that failed with this error
+    ///
+    /// Notices are sent as `NOTICE`. Other errors are sent as `OK` for events
+    /// and as `CLOSED` for subscriptions, unless the error names its own event
+    /// or subscription.
+    pub fn to_relay_message(&self, id: &ClientMessageId) -> RelayMessage<'static> {
+        match (self, id) {
+            (Self::Notice { message, .. }, _) => RelayMessage::notice(message.clone()),
+            (Self::EventError { event_id, .. }, _) => {
+                RelayMessage::ok(*event_id, false, self.client_message())
+            }
+            (
+                Self::SubscriptionError {
+                    subscription_id, ..
+                },
+                _,
+            ) => RelayMessage::closed(
+                SubscriptionId::new(subscription_id.clone()),
+                self.client_message(),
+            ),
+            (_, ClientMessageId::Event(event_id)) => {
+                RelayMessage::ok(*event_id, false, self.client_message())
+            }
+            (_, ClientMessageId::Subscription(subscription_id)) => RelayMessage::closed(
+                SubscriptionId::new(subscription_id.clone()),
+                self.client_message(),
+            ),
+        }
+    }
 }
 
 // Conversion to anyhow is done by anyhow's blanket implementation
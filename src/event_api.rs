@@ -0,0 +1,1176 @@
+//! HTTP endpoints for publishing and querying events
+//!
+//! [`EventApi`] is an axum router for server-side publishers, serverless
+//! functions and curl-based consumers that don't want to speak WebSockets.
+//! Register it with
+//! [`RelayBuilder::with_event_api`](crate::RelayBuilder::with_event_api), keep
+//! a clone and mount [`EventApi::router`] in the host app.
+//!
+//! | Method | Path | |
+//! |---|---|---|
+//! | POST | `/event` | Publish the signed event in the body |
+//! | GET | `/req?filters=<json>` | Stored events matching the NIP-01 filters |
+//! | POST | `/req` | Stored events matching the NIP-01 filters in the body |
+//! | GET | `/subscribe?filters=<json>` | Server-Sent Events stream of live events matching the filters |
+//!
+//! Requests are authenticated with [NIP-98](crate::http_auth): the `u` tag of
+//! the authorization event is the requested URL below the public URL the API
+//! is mounted at. The signer is the authenticated pubkey policies and
+//! visibility checks see. Publishing requires it, querying does not.
+//!
+//! Published events take the same path as events published over WebSockets:
+//! the relay's rate limits, the event processor's `handle_event` as seen from
+//! a fresh connection authenticated as the signer, then the ingest pipeline,
+//! storage and distribution, the kind routes and post-save hooks, in the
+//! relay's ordering mode. Rate limits are keyed by the client address, taken
+//! from proxy headers like for WebSockets, and by the signer; the connection
+//! quotas apply to each request. The answer mirrors the NIP-01 `OK` message
+//! they would get: `{"event_id": ..., "accepted": ..., "message": ...}`.
+//!
+//! Queries take a JSON array of filters, or a single filter, and answer a JSON
+//! array of events, newest first. They are served like the stored events of a
+//! REQ from a fresh connection: the scope is resolved from the `Host` header,
+//! the rate limits, overload controller, filter validation and the event
+//! processor's `verify_filters` may refuse them, the query augmenter rewrites
+//! them, limits are capped at the relay's `max_limit` and the relay paginates
+//! past the events the event processor hides from the requester.
+//!
+//! Subscriptions take the same checks as queries, then register a virtual
+//! connection with the [`SubscriptionRegistry`], so live events reach them like
+//! they reach WebSocket subscriptions, through the same visibility checks. The
+//! connection hooks see that connection like a WebSocket one, so
+//! [`ConnectionLimits`](crate::ConnectionLimits) count SSE streams against the
+//! client address. Each event is sent as an SSE `event` message whose data is
+//! the event JSON; the virtual connection goes away with the HTTP connection.
+
+use crate::crypto_helper::CryptoHelper;
+use crate::database::{ReadReplicas, RelayDatabase};
+use crate::error::Error;
+use crate::event_processor::{EventContext, EventProcessor};
+use crate::filter_validation::FilterValidation;
+use crate::ingest::{IngestOutcome, IngestPipeline};
+use crate::kind_router::KindRouter;
+use crate::latency::{EventTimeline, LatencyBudget};
+use crate::overload::OverloadController;
+use crate::post_save::PostSaveHooks;
+use crate::provenance::{EventProvenance, IngestPath, ProvenanceStore};
+use crate::proxy::ProxyHeaders;
+use crate::query_augmenter::QueryAugmenter;
+use crate::rate_limit::{RateLimitedAction, RateLimiter};
+use crate::scope_resolver::ScopeResolver;
+use crate::subscription_coordinator::{
+    OrderingMode, PaginationConfig, StoreCommand, SubscriptionCoordinator,
+};
+use crate::subscription_registry::{SubscriptionRegistry, VisibilityFn};
+use crate::utils::Attachment;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{header, Extensions, HeaderMap, Method, StatusCode, Uri};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use futures_util::Stream;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+use websocket_builder::MessageSender;
+
+/// Live events buffered for a slow SSE client
+const SSE_CHANNEL_SIZE: usize = 1000;
+
+/// Subscription id of the virtual connection behind an SSE stream
+const SSE_SUBSCRIPTION_ID: &str = "sse";
+
+/// Subscription id of the virtual connection serving a query
+const HTTP_SUBSCRIPTION_ID: &str = "http";
+
+/// The relay's event processor as seen from a fresh connection
+#[async_trait]
+pub(crate) trait HttpProcessor: Send + Sync {
+    /// Commands storing `event` published by `auth_pubkey`, see
+    /// [`EventProcessor::handle_event`]
+    async fn handle_event(
+        &self,
+        event: Event,
+        scope: &Scope,
+        auth_pubkey: &PublicKey,
+    ) -> Result<Vec<StoreCommand>, Error>;
+
+    /// Refuse `filters` sent by `auth_pubkey`, see
+    /// [`EventProcessor::verify_filters`]
+    fn verify_filters(
+        &self,
+        filters: &[Filter],
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Result<(), Error>;
+}
+
+/// [`HttpProcessor`] running an [`EventProcessor`] with a default custom state
+pub(crate) struct FreshConnection<T> {
+    processor: Arc<dyn EventProcessor<T>>,
+    relay_pubkey: PublicKey,
+}
+
+impl<T> FreshConnection<T> {
+    pub(crate) fn new(processor: Arc<dyn EventProcessor<T>>, relay_pubkey: PublicKey) -> Self {
+        Self {
+            processor,
+            relay_pubkey,
+        }
+    }
+}
+
+#[async_trait]
+impl<T> HttpProcessor for FreshConnection<T>
+where
+    T: Default + Send + Sync + 'static,
+{
+    async fn handle_event(
+        &self,
+        event: Event,
+        scope: &Scope,
+        auth_pubkey: &PublicKey,
+    ) -> Result<Vec<StoreCommand>, Error> {
+        let context = EventContext {
+            authed_pubkey: Some(auth_pubkey),
+            subdomain: scope,
+            relay_pubkey: &self.relay_pubkey,
+        };
+        self.processor
+            .handle_event(
+                event,
+                Arc::new(parking_lot::RwLock::new(T::default())),
+                context,
+            )
+            .await
+    }
+
+    fn verify_filters(
+        &self,
+        filters: &[Filter],
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Result<(), Error> {
+        let context = EventContext {
+            authed_pubkey: auth_pubkey,
+            subdomain: scope,
+            relay_pubkey: &self.relay_pubkey,
+        };
+        self.processor.verify_filters(
+            filters,
+            Arc::new(parking_lot::RwLock::new(T::default())),
+            context,
+        )
+    }
+}
+
+/// Relay components the event API works with, attached when the relay is built
+pub(crate) struct EventApiContext {
+    pub(crate) processor: Arc<dyn HttpProcessor>,
+    pub(crate) rate_limiters: Vec<RateLimiter>,
+    pub(crate) proxy_headers: ProxyHeaders,
+    pub(crate) filter_validation: Option<FilterValidation>,
+    pub(crate) query_augmenter: Option<Arc<dyn QueryAugmenter>>,
+    pub(crate) overload: Option<OverloadController>,
+    pub(crate) pagination: PaginationConfig,
+    pub(crate) read_replicas: Option<ReadReplicas>,
+    pub(crate) pipeline: IngestPipeline,
+    pub(crate) database: Arc<RelayDatabase>,
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+    pub(crate) crypto_helper: CryptoHelper,
+    pub(crate) ordering: OrderingMode,
+    pub(crate) post_save_hooks: Option<PostSaveHooks>,
+    pub(crate) kind_router: Option<KindRouter>,
+    pub(crate) latency_budget: Option<LatencyBudget>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
+    pub(crate) scope_resolver: Arc<dyn ScopeResolver>,
+    pub(crate) visibility: VisibilityFn,
+    pub(crate) max_limit: usize,
+    pub(crate) provenance: Option<ProvenanceStore>,
+}
+
+/// HTTP API publishing events into and querying events from a relay
+#[derive(Clone)]
+pub struct EventApi {
+    base_url: Arc<str>,
+    scope: Option<Scope>,
+    context: Attachment<EventApiContext>,
+}
+
+impl std::fmt::Debug for EventApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventApi")
+            .field("base_url", &self.base_url)
+            .field("scope", &self.scope)
+            .field("attached", &self.context.is_attached())
+            .finish()
+    }
+}
+
+/// JSON answer to a published event
+#[derive(Debug, Serialize)]
+struct OkView {
+    event_id: String,
+    accepted: bool,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReqParams {
+    filters: String,
+}
+
+/// Error answered as `{"error": "..."}`
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(message: impl std::fmt::Display) -> Self {
+        Self(StatusCode::BAD_REQUEST, message.to_string())
+    }
+
+    fn unauthorized(message: impl std::fmt::Display) -> Self {
+        Self(StatusCode::UNAUTHORIZED, message.to_string())
+    }
+
+    /// A connection refused by a connection hook for `reason`
+    fn refused(reason: String) -> Self {
+        let status = if reason.starts_with("rate-limited:") {
+            StatusCode::TOO_MANY_REQUESTS
+        } else if reason.starts_with("restricted:") {
+            StatusCode::FORBIDDEN
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        Self(status, reason)
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        let status = match &e {
+            Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::AuthRequired { .. } => StatusCode::UNAUTHORIZED,
+            Error::Restricted { .. } => StatusCode::FORBIDDEN,
+            _ if e.is_client_error() => StatusCode::BAD_REQUEST,
+            _ => {
+                error!("Event API request failed: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        Self(status, e.client_message())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl EventApi {
+    /// Serve the API mounted at `base_url`
+    ///
+    /// `base_url` is the public URL the router is mounted at, e.g.
+    /// `https://relay.example.com/api`; publishers put it followed by the
+    /// route in the `u` tag of their NIP-98 events.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: Arc::from(base_url.into().trim_end_matches('/')),
+            scope: None,
+            context: Attachment::default(),
+        }
+    }
+
+    /// Serve `scope` whatever the `Host` header of requests
+    #[must_use]
+    pub fn with_scope(mut self, scope: Scope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Work with the relay built with this API, answers 503 until then
+    pub(crate) fn attach(&self, context: EventApiContext) {
+        self.context.attach(context, "Event API");
+    }
+
+    /// The API's routes
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/event", post(publish_event))
+            .route("/req", get(query_events).post(query_events_json))
+            .route("/subscribe", get(subscribe))
+            .with_state(self.clone())
+    }
+
+    fn context(&self) -> Result<&EventApiContext, ApiError> {
+        self.context.get().ok_or_else(|| {
+            ApiError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "relay is not running".to_string(),
+            )
+        })
+    }
+
+    /// Scope a request with `headers` is served from
+    fn scope(&self, context: &EventApiContext, headers: &HeaderMap) -> Result<Scope, ApiError> {
+        if let Some(scope) = &self.scope {
+            return Ok(scope.clone());
+        }
+        let host = headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok());
+        context
+            .scope_resolver
+            .resolve(host)
+            .ok_or_else(|| ApiError(StatusCode::FORBIDDEN, "unknown tenant".to_string()))
+    }
+
+    /// Address of the client behind a request, when the peer address is known
+    fn client_address(
+        &self,
+        context: &EventApiContext,
+        headers: &HeaderMap,
+        extensions: &Extensions,
+    ) -> Option<SocketAddr> {
+        let ConnectInfo(peer) = extensions.get::<ConnectInfo<SocketAddr>>()?;
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let ip =
+            context
+                .proxy_headers
+                .client_ip(*peer, header("forwarded"), header("x-forwarded-for"));
+        Some(SocketAddr::new(ip, peer.port()))
+    }
+
+    /// Rate limiter connection id of a request, `<ip>:<port>` like WebSocket
+    /// connections when the peer address is known
+    fn client_id(
+        &self,
+        context: &EventApiContext,
+        headers: &HeaderMap,
+        extensions: &Extensions,
+    ) -> String {
+        self.client_address(context, headers, extensions)
+            .map_or_else(
+                || format!("http-{}", uuid::Uuid::new_v4()),
+                |address| address.to_string(),
+            )
+    }
+
+    /// Take the relay's rate limit tokens for one `action` request
+    fn check_rate_limits(
+        &self,
+        context: &EventApiContext,
+        scope: &Scope,
+        action: RateLimitedAction,
+        client_id: &str,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Result<(), ApiError> {
+        let result = context.rate_limiters.iter().try_for_each(|limiter| {
+            limiter.check(scope, RateLimitedAction::Message, client_id, auth_pubkey)?;
+            limiter.check(scope, action, client_id, auth_pubkey)
+        });
+        // Every request is a connection of its own
+        for limiter in &context.rate_limiters {
+            limiter.forget_connection(client_id);
+        }
+        result.map_err(|e| ApiError(StatusCode::TOO_MANY_REQUESTS, e.client_message()))
+    }
+
+    /// Pubkey of the NIP-98 event in `headers`, if it authorizes this request
+    fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        method: &Method,
+        uri: &Uri,
+        body: &[u8],
+    ) -> Result<PublicKey, ApiError> {
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        crate::http_auth::verify_header(
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok()),
+            &format!("{}{}", self.base_url, path),
+            method.as_str(),
+            body,
+        )
+        .map_err(ApiError::unauthorized)
+    }
+
+    /// Pubkey of the NIP-98 event in `headers` when there is one
+    fn authenticate_optional(
+        &self,
+        headers: &HeaderMap,
+        method: &Method,
+        uri: &Uri,
+        body: &[u8],
+    ) -> Result<Option<PublicKey>, ApiError> {
+        if headers.contains_key(header::AUTHORIZATION) {
+            self.authenticate(headers, method, uri, body).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Ingest `event` like an event published over a WebSocket
+    ///
+    /// The commands the event processor answers go through a coordinator of
+    /// their own virtual connection, so they get the pipeline, post-save
+    /// hooks, kind routes and ordering of WebSocket events, and the `OK` they
+    /// would be answered with.
+    async fn ingest(
+        &self,
+        context: &EventApiContext,
+        event: Event,
+        scope: &Scope,
+        auth_pubkey: &PublicKey,
+    ) -> OkView {
+        let event_id = event.id;
+        let refused = |message: String| OkView {
+            event_id: event_id.to_hex(),
+            accepted: false,
+            message,
+        };
+        let mut timeline = context
+            .latency_budget
+            .as_ref()
+            .map(|_| EventTimeline::start());
+        let mut commands = match context
+            .processor
+            .handle_event(event, scope, auth_pubkey)
+            .await
+        {
+            Ok(commands) => commands,
+            Err(e) => return refused(e.client_message()),
+        };
+        let Some(idx) = commands
+            .iter()
+            .position(|command| matches!(command, StoreCommand::SaveSignedEvent(..)))
+        else {
+            return refused("blocked: the event was not accepted".to_string());
+        };
+        let mut command = commands.swap_remove(idx);
+        let (tx, rx) = flume::bounded(1);
+        let cancellation_token = context
+            .cancellation_token
+            .as_ref()
+            .map_or_else(CancellationToken::new, CancellationToken::child_token);
+        let coordinator = SubscriptionCoordinator::builder(
+            context.database.clone(),
+            context.crypto_helper.clone(),
+            context.registry.clone(),
+            format!("http-{}", uuid::Uuid::new_v4()),
+            MessageSender::new(tx.clone(), 0),
+        )
+        .with_auth_pubkey(Some(*auth_pubkey))
+        .with_scope(Arc::new(scope.clone()))
+        .with_cancellation_token(cancellation_token.clone())
+        .with_ingest_path(IngestPath::Http)
+        .with_ingest_pipeline(Some(context.pipeline.clone()))
+        .with_ordering(context.ordering)
+        .with_post_save_hooks(context.post_save_hooks.clone())
+        .with_kind_router(context.kind_router.clone())
+        .build();
+
+        if let Some(timeline) = timeline.as_mut() {
+            timeline.mark_policy_complete();
+        }
+        if let Err(e) = command.set_message_sender(MessageSender::new(tx, 0)) {
+            return refused(e.client_message());
+        }
+        let outcome = coordinator.ingest(command, timeline.as_mut()).await;
+
+        if let Ok(IngestOutcome::Stored) = outcome {
+            debug!("Stored event {} published over HTTP", event_id);
+            // Derived events are only saved with the event itself
+            for command in commands {
+                if let Err(e) = coordinator.ingest(command, None).await {
+                    error!("Failed to store a command derived over HTTP: {}", e);
+                }
+            }
+            if let Some(provenance) = &context.provenance {
+                let mut record = EventProvenance::new(event_id, IngestPath::Http);
+                record.auth_pubkey = Some(*auth_pubkey);
+                provenance.record(record);
+            }
+            if let (Some(budget), Some(timeline)) = (&context.latency_budget, &timeline) {
+                budget.record(timeline);
+            }
+        }
+        // Flushes the replaceable events the post-save hooks buffered
+        cancellation_token.cancel();
+
+        // The coordinator answers every outcome with an `OK`
+        match rx.try_recv() {
+            Ok((
+                RelayMessage::Ok {
+                    status, message, ..
+                },
+                _,
+            )) => OkView {
+                event_id: event_id.to_hex(),
+                accepted: status,
+                message: message.into_owned(),
+            },
+            _ => refused(crate::error::INTERNAL_ERROR_MESSAGE.to_string()),
+        }
+    }
+
+    /// `filters` to serve to `auth_pubkey`, after the checks of a REQ
+    ///
+    /// The overload controller, filter validation and the event processor's
+    /// `verify_filters` may refuse them, then the query augmenter rewrites
+    /// them.
+    async fn admit_filters(
+        &self,
+        context: &EventApiContext,
+        filters: Vec<Filter>,
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Result<Vec<Filter>, ApiError> {
+        if let Some(overload) = &context.overload {
+            overload.check_req()?;
+        }
+        let filters = match &context.filter_validation {
+            Some(validation) => validation.validate(filters)?,
+            None => filters,
+        };
+        context
+            .processor
+            .verify_filters(&filters, scope, auth_pubkey)?;
+
+        // Filters were verified as sent, the augmented ones are what gets served
+        Ok(match &context.query_augmenter {
+            Some(augmenter) => augmenter.augment(filters, scope, auth_pubkey).await,
+            None => filters,
+        })
+    }
+
+    /// Stored events of `scope` matching `filters` that `auth_pubkey` may see
+    ///
+    /// Served like the historical part of a REQ, by a coordinator of its own
+    /// virtual connection: the query planner and paginator keep querying past
+    /// the events hidden from `auth_pubkey` until the limits are filled.
+    async fn query(
+        &self,
+        context: &EventApiContext,
+        filters: Vec<Filter>,
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Result<Vec<Event>, ApiError> {
+        let filters = self
+            .admit_filters(context, filters, scope, auth_pubkey)
+            .await?;
+
+        // Under elevated load historical queries take turns
+        let _permit = match &context.overload {
+            Some(overload) => overload.historical_permit().await,
+            None => None,
+        };
+
+        let (tx, rx) = flume::unbounded();
+        let cancellation_token = context
+            .cancellation_token
+            .as_ref()
+            .map_or_else(CancellationToken::new, CancellationToken::child_token);
+        let coordinator = SubscriptionCoordinator::builder(
+            context.database.clone(),
+            context.crypto_helper.clone(),
+            context.registry.clone(),
+            format!("http-{}", uuid::Uuid::new_v4()),
+            MessageSender::new(tx, 0),
+        )
+        .with_auth_pubkey(auth_pubkey.copied())
+        .with_scope(Arc::new(scope.clone()))
+        .with_cancellation_token(cancellation_token.clone())
+        .with_max_limit(context.max_limit)
+        .with_pagination(context.pagination)
+        .with_ordering(context.ordering)
+        .with_read_replicas(context.read_replicas.clone())
+        .build();
+
+        let visibility = context.visibility.clone();
+        let result = coordinator
+            .handle_stored_req(
+                SubscriptionId::new(HTTP_SUBSCRIPTION_ID),
+                &filters,
+                auth_pubkey.copied(),
+                scope,
+                move |event: &Event, scope: &Scope, auth_pubkey: Option<&PublicKey>| {
+                    visibility(event, scope, auth_pubkey)
+                },
+            )
+            .await;
+        cancellation_token.cancel();
+        result?;
+
+        let mut events: Vec<Event> = rx
+            .drain()
+            .filter_map(|(message, _)| match message {
+                RelayMessage::Event { event, .. } => Some(event.into_owned()),
+                _ => None,
+            })
+            .collect();
+        // Each planned query is sent newest first
+        events.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        Ok(events)
+    }
+}
+
+/// Register a virtual connection subscribed to `filters` and stream its events
+///
+/// The connection hooks see it like a WebSocket connection from
+/// `remote_address`, so the connection limits count it and may refuse it.
+fn subscribe_stream(
+    context: &EventApiContext,
+    filters: Vec<Filter>,
+    scope: Scope,
+    auth_pubkey: Option<PublicKey>,
+    remote_address: Option<SocketAddr>,
+) -> Result<impl Stream<Item = Result<SseEvent, Infallible>>, ApiError> {
+    let (tx, rx) = flume::bounded(SSE_CHANNEL_SIZE);
+    let connection_id = format!("sse-{}", uuid::Uuid::new_v4());
+    let handle = context.registry.register_connection(
+        connection_id.clone(),
+        MessageSender::new(tx, 0),
+        auth_pubkey,
+        Arc::new(scope),
+    );
+    if let Some(remote_address) = remote_address {
+        context
+            .registry
+            .set_remote_address(&connection_id, remote_address.to_string());
+    }
+    context
+        .registry
+        .announce_connection(&connection_id)
+        .map_err(ApiError::refused)?;
+    context
+        .registry
+        .set_visibility(&connection_id, Some(context.visibility.clone()));
+    context.registry.add_subscription(
+        &connection_id,
+        SubscriptionId::new(SSE_SUBSCRIPTION_ID),
+        filters,
+    )?;
+
+    // The stream owns the handle, dropping it unregisters the connection
+    Ok(futures_util::stream::unfold(
+        (rx, handle),
+        |(rx, handle)| async move {
+            loop {
+                let (message, _) = rx.recv_async().await.ok()?;
+                if let RelayMessage::Event { event, .. } = message {
+                    let sse = SseEvent::default().event("event").data(event.as_json());
+                    return Some((Ok(sse), (rx, handle)));
+                }
+            }
+        },
+    ))
+}
+
+/// A JSON array of filters, or a single filter
+fn parse_filters(json: &str) -> Result<Vec<Filter>, ApiError> {
+    serde_json::from_str::<Vec<Filter>>(json)
+        .or_else(|_| serde_json::from_str::<Filter>(json).map(|filter| vec![filter]))
+        .map_err(|e| ApiError::bad_request(format!("invalid filters: {e}")))
+}
+
+async fn publish_event(
+    State(api): State<EventApi>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    uri: Uri,
+    body: Bytes,
+) -> Result<Json<OkView>, ApiError> {
+    let context = api.context()?;
+    let auth_pubkey = api.authenticate(&headers, &Method::POST, &uri, &body)?;
+    let scope = api.scope(context, &headers)?;
+    let client_id = api.client_id(context, &headers, &extensions);
+    api.check_rate_limits(
+        context,
+        &scope,
+        RateLimitedAction::Event,
+        &client_id,
+        Some(&auth_pubkey),
+    )?;
+    let event = Event::from_json(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid event: {e}")))?;
+
+    Ok(Json(api.ingest(context, event, &scope, &auth_pubkey).await))
+}
+
+async fn query_events(
+    State(api): State<EventApi>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    uri: Uri,
+    Query(params): Query<ReqParams>,
+) -> Result<Json<Vec<Event>>, ApiError> {
+    let context = api.context()?;
+    let auth_pubkey = api.authenticate_optional(&headers, &Method::GET, &uri, &[])?;
+    let scope = api.scope(context, &headers)?;
+    let client_id = api.client_id(context, &headers, &extensions);
+    api.check_rate_limits(
+        context,
+        &scope,
+        RateLimitedAction::Req,
+        &client_id,
+        auth_pubkey.as_ref(),
+    )?;
+    let filters = parse_filters(&params.filters)?;
+
+    let events = api
+        .query(context, filters, &scope, auth_pubkey.as_ref())
+        .await?;
+    Ok(Json(events))
+}
+
+async fn query_events_json(
+    State(api): State<EventApi>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    uri: Uri,
+    body: Bytes,
+) -> Result<Json<Vec<Event>>, ApiError> {
+    let context = api.context()?;
+    let auth_pubkey = api.authenticate_optional(&headers, &Method::POST, &uri, &body)?;
+    let scope = api.scope(context, &headers)?;
+    let client_id = api.client_id(context, &headers, &extensions);
+    api.check_rate_limits(
+        context,
+        &scope,
+        RateLimitedAction::Req,
+        &client_id,
+        auth_pubkey.as_ref(),
+    )?;
+    let filters = parse_filters(&String::from_utf8_lossy(&body))?;
+
+    let events = api
+        .query(context, filters, &scope, auth_pubkey.as_ref())
+        .await?;
+    Ok(Json(events))
+}
+
+async fn subscribe(
+    State(api): State<EventApi>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    uri: Uri,
+    Query(params): Query<ReqParams>,
+) -> Result<Sse<impl Stream<Item = Result<SseEvent, Infallible>>>, ApiError> {
+    let context = api.context()?;
+    let auth_pubkey = api.authenticate_optional(&headers, &Method::GET, &uri, &[])?;
+    let scope = api.scope(context, &headers)?;
+    let client_id = api.client_id(context, &headers, &extensions);
+    api.check_rate_limits(
+        context,
+        &scope,
+        RateLimitedAction::Req,
+        &client_id,
+        auth_pubkey.as_ref(),
+    )?;
+    let filters = parse_filters(&params.filters)?;
+    let filters = api
+        .admit_filters(context, filters, &scope, auth_pubkey.as_ref())
+        .await?;
+
+    let remote_address = api.client_address(context, &headers, &extensions);
+    let stream = subscribe_stream(context, filters, scope, auth_pubkey, remote_address)?;
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScopeConfig;
+    use crate::subscription_coordinator::DUPLICATE_EVENT_MESSAGE;
+    use crate::subscription_registry::EventDistributor;
+    use crate::test_utils::setup_test_with_database;
+    use axum::body::Body;
+    use base64::Engine;
+    use futures_util::StreamExt;
+    use tower::ServiceExt;
+
+    const BASE_URL: &str = "https://relay.example.com/api";
+
+    fn request(
+        method: &str,
+        path: &str,
+        auth: Option<&Event>,
+        body: &str,
+    ) -> axum::http::Request<Body> {
+        let mut request = axum::http::Request::builder().method(method).uri(path);
+        if let Some(auth) = auth {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(auth.as_json());
+            request = request.header(header::AUTHORIZATION, format!("Nostr {encoded}"));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    fn auth_event(keys: &Keys, url: &str, method: &str) -> Event {
+        EventBuilder::new(Kind::HttpAuth, "")
+            .tags([
+                Tag::parse(["u", url]).unwrap(),
+                Tag::parse(["method", method]).unwrap(),
+            ])
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    async fn send(
+        api: &EventApi,
+        request: axum::http::Request<Body>,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = api.router().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn context(database: Arc<RelayDatabase>) -> EventApiContext {
+        EventApiContext {
+            processor: Arc::new(FreshConnection::new(
+                Arc::new(crate::event_processor::DefaultRelayProcessor::<()>::default()),
+                Keys::generate().public_key(),
+            )),
+            rate_limiters: Vec::new(),
+            proxy_headers: ProxyHeaders::default(),
+            filter_validation: None,
+            query_augmenter: None,
+            overload: None,
+            pagination: PaginationConfig::default(),
+            read_replicas: None,
+            pipeline: IngestPipeline::new(),
+            database,
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+            crypto_helper: CryptoHelper::new(Arc::new(Keys::generate())),
+            ordering: OrderingMode::default(),
+            post_save_hooks: None,
+            kind_router: None,
+            latency_budget: None,
+            cancellation_token: None,
+            scope_resolver: Arc::new(ScopeConfig::Disabled),
+            // Only the author sees their notes containing "secret"
+            visibility: Arc::new(|event, _, auth_pubkey| {
+                !event.content.contains("secret") || auth_pubkey == Some(&event.pubkey)
+            }),
+            max_limit: 10,
+            provenance: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_requires_nip98_and_runs_pipeline() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let api = EventApi::new(BASE_URL);
+        let event = EventBuilder::text_note("over http")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let body = event.as_json();
+        let url = format!("{BASE_URL}/event");
+        let auth = auth_event(&keys, &url, "POST");
+
+        let (status, _) = send(&api, request("POST", "/event", Some(&auth), &body)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        api.attach(context(database.clone()));
+
+        for auth in [
+            None,
+            Some(auth_event(&keys, "https://other.example.com/event", "POST")),
+            Some(auth_event(&keys, &url, "GET")),
+        ] {
+            let (status, _) = send(&api, request("POST", "/event", auth.as_ref(), &body)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let (status, ok) = send(&api, request("POST", "/event", Some(&auth), &body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ok["event_id"], event.id.to_hex());
+        assert_eq!(ok["accepted"], true);
+        let stored = database
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+
+        let (_, ok) = send(&api, request("POST", "/event", Some(&auth), &body)).await;
+        assert_eq!(ok["accepted"], true);
+        assert_eq!(ok["message"], DUPLICATE_EVENT_MESSAGE);
+
+        let mut forged = event.clone();
+        forged.content = "tampered".to_string();
+        let (_, ok) = send(
+            &api,
+            request("POST", "/event", Some(&auth), &forged.as_json()),
+        )
+        .await;
+        assert_eq!(ok["accepted"], false);
+        assert_eq!(
+            ok["message"],
+            format!("invalid: {}", crate::ingest::INVALID_ID_MESSAGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_runs_the_event_processor_and_rate_limits() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let api = EventApi::new(BASE_URL);
+        api.attach(EventApiContext {
+            processor: Arc::new(FreshConnection::new(
+                Arc::new(crate::dm_relay::DmRelayProcessor::<()>::default()),
+                Keys::generate().public_key(),
+            )),
+            rate_limiters: vec![RateLimiter::new(
+                crate::rate_limit::RateLimitConfig::new().limit(
+                    crate::rate_limit::RateLimitKey::Pubkey,
+                    RateLimitedAction::Event,
+                    crate::rate_limit::Quota::per_minute(2),
+                ),
+            )],
+            ..context(database.clone())
+        });
+        let auth = auth_event(&keys, &format!("{BASE_URL}/event"), "POST");
+
+        // The DM relay only takes gift wraps and DM relay lists
+        let note = EventBuilder::text_note("not a DM")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let (status, ok) = send(
+            &api,
+            request("POST", "/event", Some(&auth), &note.as_json()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ok["accepted"], false);
+        assert_eq!(
+            ok["message"],
+            "restricted: only gift wraps and DM relay lists are accepted"
+        );
+        assert!(database
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let list = EventBuilder::new(Kind::from(crate::dm_relay::DM_RELAY_LIST_KIND), "")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let (_, ok) = send(
+            &api,
+            request("POST", "/event", Some(&auth), &list.as_json()),
+        )
+        .await;
+        assert_eq!(ok["accepted"], true);
+
+        // Both requests took a token of the signer
+        let (status, _) = send(
+            &api,
+            request("POST", "/event", Some(&auth), &list.as_json()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_published_events_reach_kind_routes() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (routed_tx, routed_rx) = flume::unbounded();
+        let api = EventApi::new(BASE_URL);
+        api.attach(EventApiContext {
+            kind_router: Some(KindRouter::new().route(
+                "notes",
+                1..=1,
+                1,
+                move |event: Arc<Event>, _: Scope| {
+                    let routed_tx = routed_tx.clone();
+                    async move {
+                        let _ = routed_tx.send(event.id);
+                        Ok::<(), crate::error::Error>(())
+                    }
+                },
+            )),
+            ..context(database)
+        });
+
+        let event = EventBuilder::text_note("routed")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let auth = auth_event(&keys, &format!("{BASE_URL}/event"), "POST");
+        let (_, ok) = send(
+            &api,
+            request("POST", "/event", Some(&auth), &event.as_json()),
+        )
+        .await;
+        assert_eq!(ok["accepted"], true);
+
+        let routed =
+            tokio::time::timeout(std::time::Duration::from_secs(2), routed_rx.recv_async())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(routed, event.id);
+    }
+
+    #[tokio::test]
+    async fn test_query_applies_visibility() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let api = EventApi::new(BASE_URL);
+        api.attach(context(database.clone()));
+
+        for content in ["public", "secret"] {
+            let event = EventBuilder::text_note(content)
+                .sign_with_keys(&keys)
+                .unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        let filters = r#"[{"kinds":[1]}]"#;
+        let (status, events) = send(&api, request("POST", "/req", None, filters)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(events.as_array().unwrap().len(), 1);
+        assert_eq!(events[0]["content"], "public");
+
+        let path = "/req?filters=%7B%22kinds%22%3A%5B1%5D%7D";
+        let auth = auth_event(&keys, &format!("{BASE_URL}{path}"), "GET");
+        let (status, events) = send(&api, request("GET", path, Some(&auth), "")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(events.as_array().unwrap().len(), 2);
+
+        let (status, _) = send(&api, request("POST", "/req", None, "not json")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_query_pages_past_hidden_events_and_checks_filters() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let api = EventApi::new(BASE_URL);
+        api.attach(EventApiContext {
+            filter_validation: Some(FilterValidation::default()),
+            ..context(database.clone())
+        });
+
+        // The newest events are hidden from anonymous requesters
+        let now = Timestamp::now().as_u64();
+        for (i, content) in ["old public", "public", "secret", "secret", "secret"]
+            .into_iter()
+            .enumerate()
+        {
+            let event = EventBuilder::text_note(content)
+                .custom_created_at(Timestamp::from(now - 10 + i as u64))
+                .sign_with_keys(&keys)
+                .unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        let (status, events) = send(
+            &api,
+            request("POST", "/req", None, r#"{"kinds":[1],"limit":2}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let contents: Vec<_> = events
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["content"].as_str().unwrap())
+            .collect();
+        assert_eq!(contents, ["public", "old public"]);
+
+        let (status, error) = send(
+            &api,
+            request("POST", "/req", None, r#"{"since":20,"until":10}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"], "invalid: filter 0: since is after until");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_streams_live_events() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let api = EventApi::new(BASE_URL);
+        let context = context(database);
+        let registry = context.registry.clone();
+        api.attach(context);
+
+        let path = "/subscribe?filters=%7B%22kinds%22%3A%5B1%5D%7D";
+        let response = api
+            .router()
+            .oneshot(request("GET", path, None, ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(registry.connections_snapshot().len(), 1);
+
+        for content in ["secret", "live"] {
+            let event = EventBuilder::text_note(content)
+                .sign_with_keys(&keys)
+                .unwrap();
+            registry
+                .distribute_event(Arc::new(event), &Scope::Default)
+                .await;
+        }
+
+        let mut body = response.into_body().into_data_stream();
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(2), body.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(chunk.starts_with("event: event\n"));
+        assert!(chunk.contains(r#""content":"live""#));
+
+        drop(body);
+        assert_eq!(registry.connections_snapshot().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_checks_filters_and_connection_limits() {
+        let (_tmp_dir, database, _keys) = setup_test_with_database().await;
+        let api = EventApi::new(BASE_URL);
+        let limits = crate::ConnectionLimits::new().with_max_total(1);
+        api.attach(EventApiContext {
+            filter_validation: Some(FilterValidation::default()),
+            registry: Arc::new(
+                SubscriptionRegistry::new(None).with_connection_hook(Arc::new(limits.clone())),
+            ),
+            ..context(database)
+        });
+
+        // {"since":20,"until":10}
+        let path = "/subscribe?filters=%7B%22since%22%3A20%2C%22until%22%3A10%7D";
+        let (status, error) = send(&api, request("GET", path, None, "")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"], "invalid: filter 0: since is after until");
+        assert_eq!(limits.total_connections(), 0);
+
+        let path = "/subscribe?filters=%7B%22kinds%22%3A%5B1%5D%7D";
+        let first = api
+            .router()
+            .oneshot(request("GET", path, None, ""))
+            .await
+            .unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(limits.total_connections(), 1);
+
+        let (status, error) = send(&api, request("GET", path, None, "")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error["error"], crate::connection_limits::TOTAL_LIMIT_NOTICE);
+
+        drop(first);
+        assert_eq!(limits.total_connections(), 0);
+    }
+}
@@ -0,0 +1,246 @@
+//! Acceptance policies evaluated before an event is persisted
+//!
+//! An [`EventPolicyChain`] runs its [`EventPolicy`]s in order for every signed
+//! event the coordinator is about to save. The first rejection wins and is sent
+//! to the client as `OK false` with a machine-readable prefix, so common rules
+//! (allowed kinds, content size, timestamp bounds) no longer need to be coded
+//! into each `EventProcessor`.
+
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Outcome of an [`EventPolicy`] check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// Let the event through to the next policy
+    Accept,
+    /// Refuse the event; the reason is sent to the client in the OK message
+    Reject(ClosedReason),
+}
+
+/// A rule deciding whether an event may be stored
+#[async_trait]
+pub trait EventPolicy: Send + Sync + std::fmt::Debug {
+    /// Check `event` about to be saved to `scope` by a client authenticated as `auth_pubkey`
+    async fn check(
+        &self,
+        event: &Event,
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> PolicyDecision;
+}
+
+/// Ordered list of policies, evaluated until one rejects
+#[derive(Debug, Clone, Default)]
+pub struct EventPolicyChain {
+    policies: Arc<Vec<Arc<dyn EventPolicy>>>,
+}
+
+impl EventPolicyChain {
+    /// An empty chain that accepts everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a policy, evaluated after the ones already in the chain
+    #[must_use]
+    pub fn with_policy(mut self, policy: impl EventPolicy + 'static) -> Self {
+        Arc::make_mut(&mut self.policies).push(Arc::new(policy));
+        self
+    }
+
+    /// Append a shared policy
+    #[must_use]
+    pub fn with_arc_policy(mut self, policy: Arc<dyn EventPolicy>) -> Self {
+        Arc::make_mut(&mut self.policies).push(policy);
+        self
+    }
+
+    /// Append every policy of `other`, keeping their order
+    #[must_use]
+    pub fn with_arc_policies(mut self, other: &EventPolicyChain) -> Self {
+        Arc::make_mut(&mut self.policies).extend(other.policies.iter().cloned());
+        self
+    }
+
+    /// Whether the chain has no policy
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Run the policies in order, returning the first rejection
+    pub async fn check(
+        &self,
+        event: &Event,
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> PolicyDecision {
+        for policy in self.policies.iter() {
+            if let reject @ PolicyDecision::Reject(_) =
+                policy.check(event, scope, auth_pubkey).await
+            {
+                return reject;
+            }
+        }
+        PolicyDecision::Accept
+    }
+}
+
+/// Only accept the listed kinds
+#[derive(Debug, Clone)]
+pub struct KindAllowlist {
+    kinds: HashSet<Kind>,
+}
+
+impl KindAllowlist {
+    /// Accept only events of `kinds`
+    pub fn new(kinds: impl IntoIterator<Item = Kind>) -> Self {
+        Self {
+            kinds: kinds.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventPolicy for KindAllowlist {
+    async fn check(&self, event: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        if self.kinds.contains(&event.kind) {
+            PolicyDecision::Accept
+        } else {
+            PolicyDecision::Reject(ClosedReason::Blocked(format!(
+                "kind {} is not accepted",
+                event.kind
+            )))
+        }
+    }
+}
+
+/// Reject events whose content is longer than a number of bytes
+#[derive(Debug, Clone, Copy)]
+pub struct MaxContentLength(pub usize);
+
+#[async_trait]
+impl EventPolicy for MaxContentLength {
+    async fn check(&self, event: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        if event.content.len() <= self.0 {
+            PolicyDecision::Accept
+        } else {
+            PolicyDecision::Reject(ClosedReason::Invalid(format!(
+                "content exceeds {} bytes",
+                self.0
+            )))
+        }
+    }
+}
+
+/// Reject events whose `created_at` is too far from the relay's clock
+#[derive(Debug, Clone, Copy)]
+pub struct CreatedAtBounds {
+    /// How far in the past `created_at` may be, unbounded when `None`
+    pub max_past: Option<Duration>,
+    /// How far in the future `created_at` may be
+    pub max_future: Duration,
+}
+
+impl Default for CreatedAtBounds {
+    fn default() -> Self {
+        Self {
+            max_past: None,
+            max_future: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+#[async_trait]
+impl EventPolicy for CreatedAtBounds {
+    async fn check(&self, event: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        let now = Timestamp::now().as_u64();
+        let created_at = event.created_at.as_u64();
+
+        if created_at > now.saturating_add(self.max_future.as_secs()) {
+            return PolicyDecision::Reject(ClosedReason::Invalid(
+                "created_at is too far in the future".to_string(),
+            ));
+        }
+        if let Some(max_past) = self.max_past {
+            if created_at < now.saturating_sub(max_past.as_secs()) {
+                return PolicyDecision::Reject(ClosedReason::Invalid(
+                    "created_at is too far in the past".to_string(),
+                ));
+            }
+        }
+
+        PolicyDecision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(content: &str, created_at: Timestamp) -> Event {
+        EventBuilder::text_note(content)
+            .custom_created_at(created_at)
+            .sign_with_keys(&Keys::generate())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_first_rejection_wins() {
+        let chain = EventPolicyChain::new()
+            .with_policy(KindAllowlist::new([Kind::TextNote]))
+            .with_policy(MaxContentLength(5))
+            .with_policy(CreatedAtBounds::default());
+
+        let accepted = note("hi", Timestamp::now());
+        assert_eq!(
+            chain.check(&accepted, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+
+        let too_long = note("way too long", Timestamp::now());
+        match chain.check(&too_long, &Scope::Default, None).await {
+            PolicyDecision::Reject(reason) => assert_eq!(reason.prefix(), "invalid"),
+            other => panic!("Expected rejection, got {other:?}"),
+        }
+
+        let reaction = EventBuilder::new(Kind::Reaction, "+")
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        match chain.check(&reaction, &Scope::Default, None).await {
+            PolicyDecision::Reject(reason) => assert_eq!(reason.prefix(), "blocked"),
+            other => panic!("Expected rejection, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_created_at_bounds() {
+        let bounds = CreatedAtBounds {
+            max_past: Some(Duration::from_secs(3600)),
+            max_future: Duration::from_secs(60),
+        };
+        let now = Timestamp::now().as_u64();
+
+        let future = note("later", Timestamp::from(now + 600));
+        let past = note("earlier", Timestamp::from(now - 7200));
+        let recent = note("now", Timestamp::from(now - 60));
+
+        assert!(matches!(
+            bounds.check(&future, &Scope::Default, None).await,
+            PolicyDecision::Reject(_)
+        ));
+        assert!(matches!(
+            bounds.check(&past, &Scope::Default, None).await,
+            PolicyDecision::Reject(_)
+        ));
+        assert_eq!(
+            bounds.check(&recent, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+    }
+}
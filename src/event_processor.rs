@@ -100,8 +100,10 @@ where
 {
     /// Check if an event should be visible to this connection.
     ///
-    /// This method is called in hot loops during subscription processing,
-    /// so it must be synchronous for maximum performance with zero allocations.
+    /// This method is called in hot loops during subscription processing, for
+    /// stored events and for live events distributed to the connection's
+    /// subscriptions, so it must be synchronous for maximum performance with
+    /// zero allocations.
     /// The Arc<RwLock<T>> allows implementors to choose whether they need read
     /// or write access to the state.
     ///
@@ -125,6 +127,15 @@ where
         Ok(true)
     }
 
+    /// Whether [`can_see_event`](Self::can_see_event) may hide events.
+    ///
+    /// COUNT requests are counted through `can_see_event`, event by event,
+    /// unless this returns `false`; only then are matches counted by the
+    /// database directly and approximate counts allowed.
+    fn can_hide_events(&self) -> bool {
+        true
+    }
+
     /// Verify if filters are allowed for this connection.
     ///
     /// This method validates subscription filters before processing.
@@ -192,5 +203,7 @@ impl<T> EventProcessor<T> for DefaultRelayProcessor<T>
 where
     T: Send + Sync + std::fmt::Debug + 'static,
 {
-    // Uses all default implementations from the trait
+    fn can_hide_events(&self) -> bool {
+        false
+    }
 }
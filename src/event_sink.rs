@@ -0,0 +1,125 @@
+//! Hooks run for every stored event
+//!
+//! An [`EventSink`] is handed every event once it is stored, right after it
+//! was distributed to local subscriptions, so host applications can index
+//! events in a search engine, push them to a queue or run business logic.
+//! Sinks are registered with
+//! [`RelayBuilder::with_event_sink`](crate::RelayBuilder::with_event_sink).
+//! Upstream relays, webhooks and cluster gossip are sinks too.
+//!
+//! Sinks are awaited while the event is distributed, so they should hand the
+//! event off rather than process it inline: [`ChannelSink`] queues events for
+//! a consumer task, [`CallbackSink`] runs a quick synchronous callback.
+
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Receives every event stored by the relay
+#[async_trait]
+pub trait EventSink: Send + Sync + std::fmt::Debug {
+    /// Called once `event` is stored in `scope` and distributed
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope);
+}
+
+/// Sink queueing stored events on a bounded channel
+///
+/// Events arriving while the channel is full are dropped and counted.
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    sender: flume::Sender<(Arc<Event>, Scope)>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ChannelSink {
+    /// A sink and the receiving end of its channel, holding up to `capacity` events
+    pub fn new(capacity: usize) -> (Self, flume::Receiver<(Arc<Event>, Scope)>) {
+        let (sender, receiver) = flume::bounded(capacity.max(1));
+        let sink = Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (sink, receiver)
+    }
+
+    /// Events dropped because the channel was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl EventSink for ChannelSink {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        if self.sender.try_send((event, scope.clone())).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sink running a synchronous callback for each stored event
+#[derive(Clone)]
+pub struct CallbackSink {
+    callback: Arc<dyn Fn(&Event, &Scope) + Send + Sync>,
+}
+
+impl std::fmt::Debug for CallbackSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CallbackSink").finish_non_exhaustive()
+    }
+}
+
+impl CallbackSink {
+    pub fn new(callback: impl Fn(&Event, &Scope) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Arc::new(callback),
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for CallbackSink {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        (self.callback)(&event, scope);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscription_registry::{EventDistributor, SubscriptionRegistry};
+
+    #[tokio::test]
+    async fn test_sinks_receive_distributed_events() {
+        let (channel, rx) = ChannelSink::new(1);
+        let calls = Arc::new(AtomicU64::new(0));
+        let counted = Arc::clone(&calls);
+        let callback = CallbackSink::new(move |_, _| {
+            counted.fetch_add(1, Ordering::Relaxed);
+        });
+        let registry = SubscriptionRegistry::new(None)
+            .with_sink(Arc::new(channel.clone()))
+            .with_sink(Arc::new(callback));
+
+        let keys = Keys::generate();
+        let first = EventBuilder::text_note("one")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let second = EventBuilder::text_note("two")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let scope = Scope::named("tenant").unwrap();
+        registry
+            .distribute_event(Arc::new(first.clone()), &scope)
+            .await;
+        registry.distribute_event(Arc::new(second), &scope).await;
+
+        let (event, received_scope) = rx.try_recv().unwrap();
+        assert_eq!(event.id, first.id);
+        assert_eq!(received_scope, scope);
+        assert_eq!(channel.dropped(), 1);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
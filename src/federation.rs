@@ -0,0 +1,326 @@
+//! Pulling events from remote relays
+//!
+//! A [`Puller`] subscribes to remote relays and ingests the events they send
+//! into a chosen scope, so a relay built with this crate can mirror or
+//! aggregate others. Received events go through the same stages as events
+//! published by clients, from validation and policies to signature
+//! verification and dedup, before they are stored and distributed to local
+//! subscriptions.
+//!
+//! Each source is rate limited on its own, and the relay an event was first
+//! pulled from is remembered for recent events, see [`Puller::source_of`].
+
+use crate::database::RelayDatabase;
+use crate::ingest::{Admission, ConnectionMetadata, IngestPipeline, IngestStage};
+use crate::provenance::{EventProvenance, IngestPath, ProvenanceStore};
+use crate::rate_limit::{Quota, RateLimitConfig, RateLimitKey, RateLimitedAction, RateLimiter};
+use crate::subscription_registry::{EventDistributor, SubscriptionRegistry};
+use lru::LruCache;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// Number of recently pulled events whose source is remembered
+const PROVENANCE_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Default)]
+struct Counters {
+    stored: AtomicU64,
+    duplicates: AtomicU64,
+    rejected: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+/// Counters of a puller since it was started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PullerStats {
+    /// Events stored and distributed
+    pub stored: u64,
+    /// Events that were already stored
+    pub duplicates: u64,
+    /// Events refused by the ingest pipeline
+    pub rejected: u64,
+    /// Events dropped because their source exceeded its rate limit
+    pub rate_limited: u64,
+}
+
+/// Everything a running puller ingests through
+#[derive(Debug)]
+struct Sink {
+    pipeline: IngestPipeline,
+    database: Arc<RelayDatabase>,
+    registry: Arc<SubscriptionRegistry>,
+    provenance: Option<ProvenanceStore>,
+}
+
+/// Subscribes to remote relays and ingests what they send
+#[derive(Debug, Clone)]
+pub struct Puller {
+    sources: Vec<(RelayUrl, Filter)>,
+    scope: Scope,
+    auth_keys: Option<Keys>,
+    rate_limiter: Option<RateLimiter>,
+    counters: Arc<Counters>,
+    provenance: Arc<Mutex<LruCache<EventId, RelayUrl>>>,
+}
+
+impl Default for Puller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Puller {
+    /// A puller without sources, ingesting into the default scope
+    pub fn new() -> Self {
+        Self {
+            sources: Vec::new(),
+            scope: Scope::Default,
+            auth_keys: None,
+            rate_limiter: None,
+            counters: Arc::new(Counters::default()),
+            provenance: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(PROVENANCE_CAPACITY).expect("capacity is non-zero"),
+            ))),
+        }
+    }
+
+    /// Subscribe to `url` with `filter`
+    #[must_use]
+    pub fn with_source(mut self, url: RelayUrl, filter: Filter) -> Self {
+        self.sources.push((url, filter));
+        self
+    }
+
+    /// Ingest into `scope` instead of the default scope
+    #[must_use]
+    pub fn with_scope(mut self, scope: Scope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Answer the sources' NIP-42 challenges with `keys`
+    #[must_use]
+    pub fn with_auth_keys(mut self, keys: Keys) -> Self {
+        self.auth_keys = Some(keys);
+        self
+    }
+
+    /// Ingest at most `quota` events from each source, dropping the excess
+    #[must_use]
+    pub fn with_rate_limit(mut self, quota: Quota) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(RateLimitConfig::new().limit(
+            RateLimitKey::Connection,
+            RateLimitedAction::Event,
+            quota,
+        )));
+        self
+    }
+
+    /// Counters since the puller was started
+    pub fn stats(&self) -> PullerStats {
+        PullerStats {
+            stored: self.counters.stored.load(Ordering::Relaxed),
+            duplicates: self.counters.duplicates.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            rate_limited: self.counters.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Relay a recently stored event was pulled from
+    pub fn source_of(&self, event_id: &EventId) -> Option<RelayUrl> {
+        self.provenance.lock().get(event_id).cloned()
+    }
+
+    /// Subscribe to the sources and ingest through `pipeline` into `database`
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+        pipeline: IngestPipeline,
+        database: Arc<RelayDatabase>,
+        registry: Arc<SubscriptionRegistry>,
+        provenance: Option<ProvenanceStore>,
+    ) {
+        let puller = self.clone();
+        let sink = Sink {
+            pipeline,
+            database,
+            registry,
+            provenance,
+        };
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let client = match &puller.auth_keys {
+                Some(keys) => Client::builder().signer(keys.clone()).build(),
+                None => Client::default(),
+            };
+            for (url, _) in &puller.sources {
+                if let Err(e) = client.add_relay(url.clone()).await {
+                    warn!("Failed to add federation source {}: {}", url, e);
+                }
+            }
+            client.connect().await;
+
+            let mut notifications = client.notifications();
+            for (url, filter) in &puller.sources {
+                if let Err(e) = client
+                    .subscribe_to([url.clone()], filter.clone(), None)
+                    .await
+                {
+                    warn!("Failed to subscribe to federation source {}: {}", url, e);
+                }
+            }
+            info!(
+                "Pulling events from {} relays into {:?}",
+                puller.sources.len(),
+                puller.scope
+            );
+
+            loop {
+                let notification = tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    notification = notifications.recv() => notification,
+                };
+                match notification {
+                    Ok(RelayPoolNotification::Event {
+                        relay_url, event, ..
+                    }) => puller.ingest(&sink, *event, relay_url).await,
+                    Ok(RelayPoolNotification::Shutdown) => break,
+                    Ok(_) => {}
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Federation puller lagged, skipped {} events", skipped);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+
+            client.disconnect().await;
+            debug!("Federation puller stopped");
+        });
+    }
+
+    /// Run `event` pulled from `source` through the pipeline, then store and distribute it
+    async fn ingest(&self, sink: &Sink, event: Event, source: RelayUrl) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let key = source.to_string();
+            if rate_limiter
+                .check(&self.scope, RateLimitedAction::Event, &key, None)
+                .is_err()
+            {
+                self.counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+
+        match sink
+            .pipeline
+            .admit(
+                &event,
+                &self.scope,
+                &ConnectionMetadata::new(IngestPath::Federation),
+                &sink.database,
+            )
+            .await
+        {
+            Admission::Accept => {}
+            Admission::Duplicate => {
+                self.counters.duplicates.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Admission::ShadowAccept => {
+                debug!("Dropped event {} from {} as spam", event.id, source);
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Admission::Reject(reason) => {
+                debug!("Refused event {} from {}: {}", event.id, source, reason);
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+
+        let persist_started = std::time::Instant::now();
+        let saved = sink.database.save_event(&event, &self.scope).await;
+        crate::ingest::record_stage(IngestStage::Persist, persist_started);
+        if let Err(e) = saved {
+            warn!("Failed to save event {} from {}: {}", event.id, source, e);
+            return;
+        }
+
+        if let Some(provenance) = &sink.provenance {
+            let mut record = EventProvenance::new(event.id, IngestPath::Federation);
+            record.source_relay = Some(source.to_string());
+            provenance.record(record);
+        }
+        self.provenance.lock().put(event.id, source);
+        self.counters.stored.fetch_add(1, Ordering::Relaxed);
+
+        let distribute_started = std::time::Instant::now();
+        sink.registry
+            .distribute_event(Arc::new(event), &self.scope)
+            .await;
+        crate::ingest::record_stage(IngestStage::Distribute, distribute_started);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    #[tokio::test]
+    async fn test_pulled_events_are_ingested_once() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let sink = Sink {
+            pipeline: IngestPipeline::new(),
+            database: database.clone(),
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+            provenance: None,
+        };
+        let source = RelayUrl::parse("wss://source.example.com").unwrap();
+        let scope = Scope::named("mirror").unwrap();
+        let puller = Puller::new()
+            .with_source(source.clone(), Filter::new())
+            .with_scope(scope.clone())
+            .with_rate_limit(Quota::per_minute(2));
+
+        let event = EventBuilder::text_note("pulled")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let mut forged = EventBuilder::text_note("forged")
+            .sign_with_keys(&keys)
+            .unwrap();
+        forged.content = "tampered".to_string();
+
+        puller.ingest(&sink, event.clone(), source.clone()).await;
+        puller.ingest(&sink, event.clone(), source.clone()).await;
+        // Over the rate limit
+        puller.ingest(&sink, forged.clone(), source.clone()).await;
+
+        assert_eq!(
+            puller.stats(),
+            PullerStats {
+                stored: 1,
+                duplicates: 1,
+                rate_limited: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(puller.source_of(&event.id), Some(source.clone()));
+        let stored = database.query(vec![Filter::new()], &scope).await.unwrap();
+        assert_eq!(stored.len(), 1);
+
+        // Forged events are refused by the pipeline
+        let unlimited = Puller::new().with_scope(scope.clone());
+        unlimited.ingest(&sink, forged, source).await;
+        assert_eq!(unlimited.stats().rejected, 1);
+    }
+}
@@ -0,0 +1,217 @@
+//! Validation and normalization of REQ filters
+//!
+//! [`FilterValidation`] runs on the filters of every REQ before they are
+//! handed to the [`EventProcessor`](crate::EventProcessor), so the historical
+//! query and the subscription registered for live events both get the same,
+//! normalized filters. Degenerate filters close the subscription with an
+//! `invalid:` reason naming the filter and the problem:
+//!
+//! - filters without any condition, when [`FilterValidation::deny_empty_filters`] is set
+//! - `since` after `until`, which can't match anything
+//! - too many values in `ids`, `authors`, `kinds` or one tag
+//! - `ids` combined with `authors` whose product exceeds the allowed pairs,
+//!   as the database looks up every pair
+//!
+//! Accepted filters are normalized: empty sets, which nostr filters treat as
+//! wildcards, are dropped, repeated filters are sent once and limits are
+//! clamped. Values within a field are sets and so never repeat.
+
+use crate::error::{Error, Result};
+use nostr_sdk::prelude::*;
+
+/// Checks and normalization applied to REQ filters
+#[derive(Debug, Clone)]
+pub struct FilterValidation {
+    allow_empty: bool,
+    max_values: usize,
+    max_id_author_pairs: usize,
+    max_limit: Option<usize>,
+}
+
+impl Default for FilterValidation {
+    fn default() -> Self {
+        Self {
+            allow_empty: true,
+            max_values: 1000,
+            max_id_author_pairs: 10_000,
+            max_limit: None,
+        }
+    }
+}
+
+impl FilterValidation {
+    /// Allow 1000 values per field and 10 000 id and author pairs
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuse filters without ids, authors, kinds, tags, search or time range
+    #[must_use]
+    pub fn deny_empty_filters(mut self) -> Self {
+        self.allow_empty = false;
+        self
+    }
+
+    /// Refuse filters with more than `max_values` ids, authors, kinds or values of one tag
+    #[must_use]
+    pub fn with_max_values(mut self, max_values: usize) -> Self {
+        self.max_values = max_values;
+        self
+    }
+
+    /// Refuse filters whose ids times authors exceed `max_pairs`
+    #[must_use]
+    pub fn with_max_id_author_pairs(mut self, max_pairs: usize) -> Self {
+        self.max_id_author_pairs = max_pairs;
+        self
+    }
+
+    /// Lower filter limits above `max_limit` to it
+    #[must_use]
+    pub fn with_max_limit(mut self, max_limit: usize) -> Self {
+        self.max_limit = Some(max_limit);
+        self
+    }
+
+    /// Check `filters` and return their normalized form
+    pub fn validate(&self, filters: Vec<Filter>) -> Result<Vec<Filter>> {
+        let mut normalized: Vec<Filter> = Vec::with_capacity(filters.len());
+        for (index, filter) in filters.into_iter().enumerate() {
+            let filter = self
+                .validate_filter(filter)
+                .map_err(|reason| Error::invalid(format!("filter {index}: {reason}")))?;
+            if !normalized.contains(&filter) {
+                normalized.push(filter);
+            }
+        }
+        Ok(normalized)
+    }
+
+    fn validate_filter(&self, mut filter: Filter) -> std::result::Result<Filter, String> {
+        if filter.ids.as_ref().is_some_and(|ids| ids.is_empty()) {
+            filter.ids = None;
+        }
+        if filter
+            .authors
+            .as_ref()
+            .is_some_and(|authors| authors.is_empty())
+        {
+            filter.authors = None;
+        }
+        if filter.kinds.as_ref().is_some_and(|kinds| kinds.is_empty()) {
+            filter.kinds = None;
+        }
+        filter.generic_tags.retain(|_, values| !values.is_empty());
+
+        if let (Some(since), Some(until)) = (filter.since, filter.until) {
+            if since > until {
+                return Err("since is after until".to_string());
+            }
+        }
+
+        let ids = filter.ids.as_ref().map_or(0, |ids| ids.len());
+        let authors = filter.authors.as_ref().map_or(0, |authors| authors.len());
+        let kinds = filter.kinds.as_ref().map_or(0, |kinds| kinds.len());
+        for (field, values) in [("ids", ids), ("authors", authors), ("kinds", kinds)] {
+            if values > self.max_values {
+                return Err(format!(
+                    "too many {field}: {values}, at most {} allowed",
+                    self.max_values
+                ));
+            }
+        }
+        for (tag, values) in &filter.generic_tags {
+            if values.len() > self.max_values {
+                return Err(format!(
+                    "too many #{tag} values: {}, at most {} allowed",
+                    values.len(),
+                    self.max_values
+                ));
+            }
+        }
+        if ids > 0 && authors > 0 && ids.saturating_mul(authors) > self.max_id_author_pairs {
+            return Err(format!(
+                "{ids} ids by {authors} authors exceed {} combinations",
+                self.max_id_author_pairs
+            ));
+        }
+
+        if !self.allow_empty
+            && ids == 0
+            && authors == 0
+            && kinds == 0
+            && filter.generic_tags.is_empty()
+            && filter.search.is_none()
+            && filter.since.is_none()
+            && filter.until.is_none()
+        {
+            return Err("filter must have at least one condition".to_string());
+        }
+
+        if let Some(max_limit) = self.max_limit {
+            filter.limit = Some(filter.limit.map_or(max_limit, |limit| limit.min(max_limit)));
+        }
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_degenerate_filters_are_refused() {
+        let validation = FilterValidation::new()
+            .deny_empty_filters()
+            .with_max_values(3)
+            .with_max_id_author_pairs(4);
+        let reason =
+            |filters: Vec<Filter>| validation.validate(filters).unwrap_err().client_message();
+
+        assert_eq!(
+            reason(vec![
+                Filter::new().kind(Kind::TextNote),
+                Filter::new().limit(10)
+            ]),
+            "invalid: filter 1: filter must have at least one condition"
+        );
+        assert_eq!(
+            reason(vec![Filter::new()
+                .since(Timestamp::from(20))
+                .until(Timestamp::from(10))]),
+            "invalid: filter 0: since is after until"
+        );
+        assert_eq!(
+            reason(vec![Filter::new().kinds((0..4u16).map(Kind::from))]),
+            "invalid: filter 0: too many kinds: 4, at most 3 allowed"
+        );
+
+        let ids = (0..3).map(|i| EventId::from_byte_array([i; 32]));
+        let authors = (0..2).map(|_| Keys::generate().public_key());
+        assert_eq!(
+            reason(vec![Filter::new().ids(ids).authors(authors)]),
+            "invalid: filter 0: 3 ids by 2 authors exceed 4 combinations"
+        );
+    }
+
+    #[test]
+    fn test_filters_are_normalized() {
+        let validation = FilterValidation::new().with_max_limit(100);
+        let notes = Filter::new().kind(Kind::TextNote).limit(500);
+        let filters = validation
+            .validate(vec![
+                notes.clone(),
+                notes,
+                Filter::new().authors(Vec::<PublicKey>::new()).limit(5),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            filters,
+            vec![
+                Filter::new().kind(Kind::TextNote).limit(100),
+                Filter::new().limit(5),
+            ]
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Publishing stored events to Kafka or NATS
+//!
+//! A [`FirehoseSink`] is an [`EventSink`] publishing every stored event to a
+//! Kafka topic (`kafka` feature) or a NATS subject (`nats` feature), for
+//! analytics pipelines downstream of the relay. Each message is a JSON object
+//! `{"scope": <name or null>, "event": {...}}`; Kafka messages are keyed by
+//! the scope name, so the events of a tenant stay in order.
+//!
+//! Events are queued and published in batches of up to `batch_size`, or
+//! whatever arrived within `batch_interval`. Published and failed deliveries
+//! are reported through [`RelayMetricsHandler::record_sink_delivery`]; events
+//! arriving while the queue is full are dropped and counted.
+//!
+//! [`RelayMetricsHandler::record_sink_delivery`]: crate::metrics::RelayMetricsHandler::record_sink_delivery
+
+use crate::error::{Error, Result};
+use crate::event_sink::EventSink;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+/// Default maximum number of events per batch
+pub const DEFAULT_FIREHOSE_BATCH_SIZE: usize = 500;
+
+/// Default time events wait for a batch to fill up
+pub const DEFAULT_FIREHOSE_BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default number of events waiting to be published
+pub const DEFAULT_FIREHOSE_QUEUE_SIZE: usize = 50_000;
+
+/// An encoded event ready to be published
+#[derive(Debug, Clone)]
+struct Message {
+    /// Name of the scope, `None` for the default scope
+    scope: Option<String>,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn new(event: &Event, scope: &Scope) -> Self {
+        let scope = match scope {
+            Scope::Named { name, .. } => Some(name.to_string()),
+            Scope::Default => None,
+        };
+        let payload = serde_json::json!({ "scope": scope, "event": event })
+            .to_string()
+            .into_bytes();
+        Self { scope, payload }
+    }
+}
+
+/// Where a firehose publishes
+#[async_trait]
+trait Backend: Send + Sync + std::fmt::Debug {
+    /// Label of the backend in metrics
+    fn name(&self) -> &'static str;
+
+    /// Publish `batch`, returning how many messages failed
+    async fn publish(&self, batch: &[Message]) -> usize;
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    published: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Counters of a firehose since it was started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FirehoseStats {
+    /// Events the broker acknowledged
+    pub published: u64,
+    /// Events the broker did not acknowledge
+    pub failed: u64,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// Sink publishing stored events to a message broker
+#[derive(Debug, Clone)]
+pub struct FirehoseSink {
+    backend: Arc<dyn Backend>,
+    batch_size: usize,
+    batch_interval: Duration,
+    queue_size: usize,
+    /// Queue of the publishing task, set when the firehose is started
+    queue: Arc<OnceCell<flume::Sender<Message>>>,
+    counters: Arc<Counters>,
+}
+
+impl FirehoseSink {
+    fn new(backend: impl Backend + 'static) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            batch_size: DEFAULT_FIREHOSE_BATCH_SIZE,
+            batch_interval: DEFAULT_FIREHOSE_BATCH_INTERVAL,
+            queue_size: DEFAULT_FIREHOSE_QUEUE_SIZE,
+            queue: Arc::new(OnceCell::new()),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Publish to `topic` on the Kafka cluster reachable at `brokers`
+    #[cfg(feature = "kafka")]
+    pub fn kafka(brokers: &str, topic: impl Into<String>) -> Result<Self> {
+        Ok(Self::new(kafka::KafkaBackend::new(brokers, topic.into())?))
+    }
+
+    /// Publish to `subject` on the NATS server at `url`
+    #[cfg(feature = "nats")]
+    pub async fn nats(url: &str, subject: impl Into<String>) -> Result<Self> {
+        Ok(Self::new(
+            nats::NatsBackend::connect(url, subject.into()).await?,
+        ))
+    }
+
+    /// Publish up to `batch_size` events at once, waiting at most `batch_interval` for more
+    #[must_use]
+    pub fn with_batching(mut self, batch_size: usize, batch_interval: Duration) -> Self {
+        self.batch_size = batch_size.max(1);
+        self.batch_interval = batch_interval;
+        self
+    }
+
+    /// Keep at most `queue_size` events waiting to be published
+    #[must_use]
+    pub fn with_queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size.max(1);
+        self
+    }
+
+    /// Counters since the firehose was started
+    pub fn stats(&self) -> FirehoseStats {
+        FirehoseStats {
+            published: self.counters.published.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Start publishing queued events
+    ///
+    /// Events are only queued once the firehose is started. Starting it again
+    /// does nothing.
+    pub fn spawn(&self, task_tracker: &TaskTracker, cancellation_token: Option<CancellationToken>) {
+        let (tx, rx) = flume::bounded(self.queue_size);
+        if self.queue.set(tx).is_err() {
+            warn!("Firehose {} is already running", self.backend.name());
+            return;
+        }
+
+        let firehose = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            loop {
+                let first = tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    message = rx.recv_async() => match message {
+                        Ok(message) => message,
+                        Err(_) => break,
+                    },
+                };
+                let mut batch = vec![first];
+                let deadline = tokio::time::sleep(firehose.batch_interval);
+                tokio::pin!(deadline);
+                while batch.len() < firehose.batch_size {
+                    tokio::select! {
+                        _ = &mut deadline => break,
+                        message = rx.recv_async() => match message {
+                            Ok(message) => batch.push(message),
+                            Err(_) => break,
+                        },
+                    }
+                }
+
+                firehose.publish(&batch).await;
+            }
+
+            debug!("Firehose {} stopped", firehose.backend.name());
+        });
+    }
+
+    async fn publish(&self, batch: &[Message]) {
+        let failed = self.backend.publish(batch).await.min(batch.len());
+        let published = batch.len() - failed;
+
+        self.counters
+            .published
+            .fetch_add(published as u64, Ordering::Relaxed);
+        self.counters
+            .failed
+            .fetch_add(failed as u64, Ordering::Relaxed);
+        if failed > 0 {
+            warn!(
+                "Firehose {} failed to publish {} of {} events",
+                self.backend.name(),
+                failed,
+                batch.len()
+            );
+        }
+        if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+            metrics.record_sink_delivery(self.backend.name(), published, failed);
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for FirehoseSink {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        let Some(queue) = self.queue.get() else {
+            return;
+        };
+        if queue.try_send(Message::new(&event, scope)).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use super::*;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+
+    pub(super) struct KafkaBackend {
+        producer: FutureProducer,
+        topic: String,
+    }
+
+    impl std::fmt::Debug for KafkaBackend {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("KafkaBackend")
+                .field("topic", &self.topic)
+                .finish()
+        }
+    }
+
+    impl KafkaBackend {
+        pub(super) fn new(brokers: &str, topic: String) -> Result<Self> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("message.timeout.ms", "10000")
+                .create()
+                .map_err(|e| Error::internal(format!("Failed to create Kafka producer: {e}")))?;
+            Ok(Self { producer, topic })
+        }
+    }
+
+    #[async_trait]
+    impl Backend for KafkaBackend {
+        fn name(&self) -> &'static str {
+            "kafka"
+        }
+
+        async fn publish(&self, batch: &[Message]) -> usize {
+            let deliveries = batch.iter().map(|message| {
+                let mut record = FutureRecord::to(&self.topic).payload(&message.payload);
+                if let Some(scope) = &message.scope {
+                    record = record.key(scope.as_str());
+                }
+                self.producer.send(record, Duration::from_secs(0))
+            });
+
+            futures_util::future::join_all(deliveries)
+                .await
+                .into_iter()
+                .filter(|delivery| delivery.is_err())
+                .count()
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use super::*;
+
+    #[derive(Debug)]
+    pub(super) struct NatsBackend {
+        client: async_nats::Client,
+        subject: String,
+    }
+
+    impl NatsBackend {
+        pub(super) async fn connect(url: &str, subject: String) -> Result<Self> {
+            let client = async_nats::connect(url)
+                .await
+                .map_err(|e| Error::internal(format!("Failed to connect to NATS: {e}")))?;
+            Ok(Self { client, subject })
+        }
+    }
+
+    #[async_trait]
+    impl Backend for NatsBackend {
+        fn name(&self) -> &'static str {
+            "nats"
+        }
+
+        async fn publish(&self, batch: &[Message]) -> usize {
+            let mut failed = 0;
+            for message in batch {
+                if let Err(e) = self
+                    .client
+                    .publish(self.subject.clone(), message.payload.clone().into())
+                    .await
+                {
+                    debug!("Failed to publish to NATS: {}", e);
+                    failed += 1;
+                }
+            }
+            // Published messages are only buffered until flushed
+            if let Err(e) = self.client.flush().await {
+                debug!("Failed to flush NATS messages: {}", e);
+                return batch.len();
+            }
+            failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    #[derive(Debug, Default)]
+    struct Recording {
+        published: Mutex<Vec<Message>>,
+    }
+
+    #[async_trait]
+    impl Backend for Arc<Recording> {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        async fn publish(&self, batch: &[Message]) -> usize {
+            self.published.lock().extend_from_slice(batch);
+            // Refuse the events of named scopes
+            batch
+                .iter()
+                .filter(|message| message.scope.is_some())
+                .count()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_firehose_batches_and_counts() {
+        let recording = Arc::new(Recording::default());
+        let firehose =
+            FirehoseSink::new(Arc::clone(&recording)).with_batching(10, Duration::from_millis(10));
+        let task_tracker = TaskTracker::new();
+        firehose.spawn(&task_tracker, None);
+
+        let keys = Keys::generate();
+        let event = Arc::new(EventBuilder::text_note("hi").sign_with_keys(&keys).unwrap());
+        firehose
+            .on_event_stored(event.clone(), &Scope::Default)
+            .await;
+        firehose
+            .on_event_stored(event.clone(), &Scope::named("tenant").unwrap())
+            .await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(
+            firehose.stats(),
+            FirehoseStats {
+                published: 1,
+                failed: 1,
+                dropped: 0,
+            }
+        );
+        let published = recording.published.lock();
+        let message: serde_json::Value = serde_json::from_slice(&published[1].payload).unwrap();
+        assert_eq!(message["scope"], "tenant");
+        assert_eq!(message["event"]["id"], event.id.to_hex());
+    }
+}
@@ -3,13 +3,15 @@
 //! This module provides a global context for metrics handlers that need to be
 //! accessed from different parts of the relay system.
 
-use crate::metrics::SubscriptionMetricsHandler;
+use crate::metrics::{RelayMetricsHandler, SubscriptionMetricsHandler};
 use once_cell::sync::OnceCell;
 use std::sync::Arc;
 
 static SUBSCRIPTION_METRICS_HANDLER: OnceCell<Arc<dyn SubscriptionMetricsHandler>> =
     OnceCell::new();
 
+static RELAY_METRICS_HANDLER: OnceCell<Arc<dyn RelayMetricsHandler>> = OnceCell::new();
+
 /// Set the global subscription metrics handler
 pub fn set_subscription_metrics_handler(handler: Arc<dyn SubscriptionMetricsHandler>) {
     SUBSCRIPTION_METRICS_HANDLER
@@ -21,3 +23,15 @@ pub fn set_subscription_metrics_handler(handler: Arc<dyn SubscriptionMetricsHand
 pub fn get_subscription_metrics_handler() -> Option<Arc<dyn SubscriptionMetricsHandler>> {
     SUBSCRIPTION_METRICS_HANDLER.get().cloned()
 }
+
+/// Set the global relay metrics handler
+pub fn set_relay_metrics_handler(handler: Arc<dyn RelayMetricsHandler>) {
+    RELAY_METRICS_HANDLER
+        .set(handler)
+        .unwrap_or_else(|_| panic!("Relay metrics handler already set"));
+}
+
+/// Get the global relay metrics handler
+pub fn get_relay_metrics_handler() -> Option<&'static Arc<dyn RelayMetricsHandler>> {
+    RELAY_METRICS_HANDLER.get()
+}
@@ -3,13 +3,19 @@
 //! This module provides pre-built handlers that can be used with various web frameworks.
 //! Currently supports Axum, with other frameworks planned.
 
+use crate::payments::RelayFees;
+use crate::proxy::ProxyHeaders;
+use crate::scope_resolver::ScopeResolver;
 use crate::NostrConnectionState;
 use axum::{
     extract::ConnectInfo,
     http::HeaderMap,
     response::{IntoResponse, Json},
 };
+use nostr_lmdb::Scope;
 use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -19,6 +25,11 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 use websocket_builder::{UnifiedWebSocketExt, WebSocketUpgrade};
 
+/// Value of header `name`, if it is valid ASCII
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
 /// Helper struct for automatic connection counting
 struct ConnectionCounter {
     counter: Option<Arc<AtomicUsize>>,
@@ -43,28 +54,6 @@ impl Drop for ConnectionCounter {
     }
 }
 
-/// Extract the real client IP from headers or socket address
-fn get_real_ip(headers: &HeaderMap, socket_addr: SocketAddr) -> String {
-    // Try to get the real client IP from X-Forwarded-For header
-    let ip = if let Some(forwarded_for) = headers.get("x-forwarded-for") {
-        if let Ok(forwarded_str) = forwarded_for.to_str() {
-            // Get the first IP in the list (original client IP)
-            if let Some(real_ip) = forwarded_str.split(',').next() {
-                real_ip.trim().to_string()
-            } else {
-                socket_addr.ip().to_string()
-            }
-        } else {
-            socket_addr.ip().to_string()
-        }
-    } else {
-        socket_addr.ip().to_string()
-    };
-
-    // Always append the port from the socket address to ensure uniqueness
-    format!("{}:{}", ip, socket_addr.port())
-}
-
 /// A complete Nostr relay service with WebSocket handling and HTTP endpoints
 pub struct RelayService<T = ()>
 where
@@ -78,8 +67,14 @@ where
     cancellation_token: CancellationToken,
     /// Optional connection counter for metrics
     connection_counter: Option<Arc<AtomicUsize>>,
-    /// Subdomain configuration
-    pub(crate) scope_config: crate::config::ScopeConfig,
+    /// Maps the requested host onto the connection's scope
+    scope_resolver: Arc<dyn ScopeResolver>,
+    /// Per-scope overrides of the NIP-11 document
+    scope_relay_info: HashMap<Scope, ScopeRelayInfo>,
+    /// Peers whose proxy headers are believed
+    proxy_headers: ProxyHeaders,
+    /// WebSocket subprotocols of the codecs accepted besides JSON
+    subprotocols: Vec<&'static str>,
 }
 
 /// NIP-11 Relay Information Document
@@ -94,6 +89,77 @@ pub struct RelayInfo {
     pub version: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limitation: Option<RelayLimitation>,
+    /// Where clients can pay for a paid relay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payments_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees: Option<RelayFees>,
+}
+
+/// NIP-11 `limitation` object
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RelayLimitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_required: Option<bool>,
+    /// Writes are limited to some kinds or authors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restricted_writes: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_event_tags: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_content_length: Option<usize>,
+}
+
+/// Per-scope overrides of the relay's NIP-11 document
+///
+/// Fields left as `None` fall back to the relay-wide [`RelayInfo`], so a tenant
+/// only needs to set what makes its identity different.
+#[derive(Debug, Clone, Default)]
+pub struct ScopeRelayInfo {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub icon: Option<String>,
+    /// Admin contact for this scope
+    pub contact: Option<String>,
+    /// Admin pubkey for this scope
+    pub pubkey: Option<String>,
+    pub limitation: Option<RelayLimitation>,
+}
+
+impl ScopeRelayInfo {
+    /// Overlay these overrides on the relay-wide document
+    pub fn apply(&self, base: &RelayInfo) -> RelayInfo {
+        let mut info = base.clone();
+        if let Some(name) = &self.name {
+            info.name = name.clone();
+        }
+        if let Some(description) = &self.description {
+            info.description = description.clone();
+        }
+        if let Some(contact) = &self.contact {
+            info.contact = contact.clone();
+        }
+        if let Some(pubkey) = &self.pubkey {
+            info.pubkey = pubkey.clone();
+        }
+        if self.icon.is_some() {
+            info.icon = self.icon.clone();
+        }
+        if self.limitation.is_some() {
+            info.limitation = self.limitation.clone();
+        }
+        info
+    }
 }
 
 /// Generate default HTML page for relay info
@@ -281,10 +347,82 @@ where
             relay_info,
             cancellation_token: cancellation_token.unwrap_or_default(),
             connection_counter,
-            scope_config,
+            scope_resolver: Arc::new(scope_config),
+            scope_relay_info: HashMap::new(),
+            proxy_headers: ProxyHeaders::default(),
+            subprotocols: Vec::new(),
         }
     }
 
+    /// Resolve connection scopes with `scope_resolver` instead of the scope config
+    #[must_use]
+    pub fn with_scope_resolver(mut self, scope_resolver: Arc<dyn ScopeResolver>) -> Self {
+        self.scope_resolver = scope_resolver;
+        self
+    }
+
+    /// Read the client IP and host from proxy headers of the peers `proxy_headers` trusts
+    #[must_use]
+    pub fn with_proxy_headers(mut self, proxy_headers: ProxyHeaders) -> Self {
+        self.proxy_headers = proxy_headers;
+        self
+    }
+
+    /// Agree to the first of `subprotocols` a connecting client offers
+    #[must_use]
+    pub fn with_subprotocols(mut self, subprotocols: Vec<&'static str>) -> Self {
+        self.subprotocols = subprotocols;
+        self
+    }
+
+    /// Subprotocol to answer a client offering those in `headers`
+    fn negotiate_subprotocol(&self, headers: &HeaderMap) -> Option<&'static str> {
+        let offered = headers
+            .get_all(axum::http::header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>();
+        self.subprotocols
+            .iter()
+            .copied()
+            .find(|subprotocol| offered.contains(subprotocol))
+    }
+
+    /// Client address as `ip:port`, the port being the socket peer's
+    ///
+    /// The port keeps connections from the same client apart, and the IP is
+    /// what rate limiting and logs see.
+    pub fn client_address(&self, headers: &HeaderMap, addr: SocketAddr) -> String {
+        let ip = self.proxy_headers.client_ip(
+            addr,
+            header_str(headers, "forwarded"),
+            header_str(headers, "x-forwarded-for"),
+        );
+        SocketAddr::new(ip, addr.port()).to_string()
+    }
+
+    /// Host the client connected to, used to resolve its scope
+    pub fn request_host(&self, headers: &HeaderMap, addr: SocketAddr) -> Option<String> {
+        self.proxy_headers.host(
+            addr,
+            header_str(headers, "forwarded"),
+            header_str(headers, "x-forwarded-host"),
+            header_str(headers, "host"),
+        )
+    }
+
+    /// Serve a different NIP-11 document for some scopes
+    #[must_use]
+    pub fn with_scope_relay_info(
+        mut self,
+        scope_relay_info: HashMap<Scope, ScopeRelayInfo>,
+    ) -> Self {
+        self.scope_relay_info = scope_relay_info;
+        self
+    }
+
     /// Check if request wants NIP-11 JSON based on Accept header
     pub fn wants_nostr_json(accept_header: &str) -> bool {
         accept_header == "application/nostr+json"
@@ -295,6 +433,19 @@ where
         &self.relay_info
     }
 
+    /// Get the relay information for the scope a `Host` header resolves to
+    pub fn relay_info_for_host(&self, host: Option<&str>) -> Cow<'_, RelayInfo> {
+        if self.scope_relay_info.is_empty() {
+            return Cow::Borrowed(&self.relay_info);
+        }
+
+        let scope = self.scope_resolver.resolve(host).unwrap_or(Scope::Default);
+        match self.scope_relay_info.get(&scope) {
+            Some(overrides) => Cow::Owned(overrides.apply(&self.relay_info)),
+            None => Cow::Borrowed(&self.relay_info),
+        }
+    }
+
     /// Get the cancellation token
     pub fn cancellation_token(&self) -> &CancellationToken {
         &self.cancellation_token
@@ -314,28 +465,24 @@ where
         addr: SocketAddr,
         headers: &HeaderMap,
     ) -> axum::response::Response {
-        let real_ip = get_real_ip(headers, addr);
-        let host = headers
-            .get("host")
-            .and_then(|h| h.to_str().ok())
-            .map(String::from);
-
-        // Extract subdomain for logging
-        let subdomain = host.as_ref().and_then(|h| match &self.scope_config {
-            crate::config::ScopeConfig::Subdomain { base_domain_parts } => {
-                crate::subdomain::extract_subdomain(h, *base_domain_parts)
-            }
-            _ => None,
-        });
-
-        let display_info = if let Some(sub) = &subdomain {
-            if !sub.is_empty() {
-                format!(" @{sub}")
-            } else {
-                String::new()
-            }
-        } else {
-            String::new()
+        let real_ip = self.client_address(headers, addr);
+        let host = self.request_host(headers, addr);
+
+        let Some(scope) = self.scope_resolver.resolve(host.as_deref()) else {
+            info!(
+                "Refused WebSocket connection from {} to unknown tenant {:?}",
+                real_ip, host
+            );
+            return axum::http::StatusCode::FORBIDDEN.into_response();
+        };
+        let subdomain = match &scope {
+            Scope::Named { name, .. } => Some(name.to_string()),
+            Scope::Default => None,
+        };
+
+        let display_info = match &subdomain {
+            Some(sub) => format!(" @{sub}"),
+            None => String::new(),
         };
 
         // Log the upgrade request with real IP and subdomain
@@ -345,7 +492,8 @@ where
         );
 
         let ws_handler = self.ws_handler.clone();
-        let cancellation_token = self.cancellation_token.clone();
+        // Cancelled on shutdown, or by the registry to close this connection
+        let connection_token = self.cancellation_token.child_token();
         let connection_counter = self.connection_counter.clone();
 
         // Create isolated span for this connection
@@ -361,27 +509,29 @@ where
 
         info!("New WebSocket connection from {}", real_ip);
 
-        // Create state with subdomain information
+        // Create state with the resolved scope
         let mut state = NostrConnectionState::<T>::default();
+        state.remote_address = Some(real_ip.clone());
+        state.user_agent = headers
+            .get(axum::http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        state.subdomain = Arc::new(scope);
+        state.connection_token = connection_token.clone();
 
-        // Set subdomain based on host header and scope config
-        if let Some(host_str) = &host {
-            if let crate::config::ScopeConfig::Subdomain { base_domain_parts } = &self.scope_config
-            {
-                if let Some(subdomain_name) =
-                    crate::subdomain::extract_subdomain(host_str, *base_domain_parts)
-                {
-                    if let Ok(scope) = nostr_lmdb::Scope::named(&subdomain_name) {
-                        state.subdomain = Arc::new(scope);
-                    }
-                }
-            }
-        }
+        let subprotocol = self.negotiate_subprotocol(headers);
 
         // Use the unified API for WebSocket handling with pre-configured state
-        ws_handler
-            .handle_upgrade(ws, real_ip, cancellation_token, state)
-            .await
+        let mut response = ws_handler
+            .handle_upgrade(ws, real_ip, connection_token, state)
+            .await;
+        if let Some(subprotocol) = subprotocol {
+            response.headers_mut().insert(
+                axum::http::header::SEC_WEBSOCKET_PROTOCOL,
+                axum::http::HeaderValue::from_static(subprotocol),
+            );
+        }
+        response
     }
 
     /// Creates an Axum-compatible WebSocket-only handler function
@@ -430,7 +580,9 @@ where
                 if let Some(accept) = headers.get(axum::http::header::ACCEPT) {
                     if let Ok(value) = accept.to_str() {
                         if Self::wants_nostr_json(value) {
-                            return Json(&handlers.relay_info).into_response();
+                            let host = handlers.request_host(&headers, addr);
+                            return Json(handlers.relay_info_for_host(host.as_deref()).as_ref())
+                                .into_response();
                         }
                     }
                 }
@@ -440,4 +592,37 @@ where
             })
         }
     }
+
+    /// Creates an Axum-compatible health check handler
+    ///
+    /// Answers `200` with the number of open connections while the relay runs
+    /// and `503` once its cancellation token was cancelled, so load balancers
+    /// stop routing to a relay that is shutting down.
+    pub fn axum_health_handler(
+        self: Arc<Self>,
+    ) -> impl Fn() -> Pin<Box<dyn Future<Output = axum::response::Response> + Send>>
+           + Clone
+           + Send
+           + Sync
+           + 'static {
+        move || {
+            let handlers = self.clone();
+
+            Box::pin(async move {
+                let (status, body) = if handlers.cancellation_token.is_cancelled() {
+                    (axum::http::StatusCode::SERVICE_UNAVAILABLE, "shutting_down")
+                } else {
+                    (axum::http::StatusCode::OK, "ok")
+                };
+                (
+                    status,
+                    Json(serde_json::json!({
+                        "status": body,
+                        "connections": handlers.connection_count(),
+                    })),
+                )
+                    .into_response()
+            })
+        }
+    }
 }
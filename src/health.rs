@@ -0,0 +1,432 @@
+//! Liveness and readiness probes
+//!
+//! [`HealthChecks`] answers the two questions orchestrators ask a relay:
+//!
+//! - liveness, `GET /healthz`: is the process still working, i.e. its runtime
+//!   schedules a task in time; failing it should restart the relay
+//! - readiness, `GET /readyz`: should it get traffic, i.e. liveness holds, no
+//!   supervised background task is stuck restarting, the crypto helper
+//!   verifies a signature in time, the relay is not shutting down nor
+//!   draining, the database was not opened read-only, answers a lookup in time
+//!   and its LMDB map is not nearly full, and no queue (signature
+//!   verification, signing, deliveries to slow connections, or one registered
+//!   with [`HealthChecks::with_queue`]) is over its limit
+//!
+//! Both answer `200` when every check passes and `503` otherwise, with a
+//! [`HealthReport`] as JSON naming each check. The relay's
+//! [`into_axum_router`](crate::RelayBuilder::into_axum_router) serves them;
+//! otherwise pass a clone to
+//! [`RelayBuilder::with_health_checks`](crate::RelayBuilder::with_health_checks)
+//! and mount [`HealthChecks::router`].
+
+use crate::crypto_helper::CryptoHelper;
+use crate::database::RelayDatabase;
+use crate::drain::ConnectionDrain;
+use crate::subscription_registry::SubscriptionRegistry;
+use crate::supervisor::TaskSupervisor;
+use crate::utils::Attachment;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Outcome of one check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckResult {
+    pub ok: bool,
+    /// What was measured, or why the check failed
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn pass(detail: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            detail: detail.into(),
+        }
+    }
+
+    fn fail(detail: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Outcome of a probe: passes when every check does
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+impl HealthReport {
+    fn new(checks: BTreeMap<String, CheckResult>) -> Self {
+        Self {
+            ok: checks.values().all(|check| check.ok),
+            checks,
+        }
+    }
+}
+
+/// Depth of an application queue and its limit
+#[derive(Clone)]
+struct QueueLimit {
+    name: String,
+    depth: Arc<dyn Fn() -> usize + Send + Sync>,
+    limit: usize,
+}
+
+/// Relay components the probes check, attached when the relay is built
+pub(crate) struct HealthContext {
+    pub(crate) database: Arc<RelayDatabase>,
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+    pub(crate) crypto_helper: CryptoHelper,
+    pub(crate) task_supervisor: TaskSupervisor,
+    pub(crate) cancellation_token: Option<CancellationToken>,
+    pub(crate) connection_drain: Option<ConnectionDrain>,
+}
+
+/// Liveness and readiness probes of a relay
+#[derive(Clone)]
+pub struct HealthChecks {
+    timeout: Duration,
+    max_map_usage: f64,
+    crypto_queue_limit: usize,
+    delivery_queue_limit: usize,
+    queues: Vec<QueueLimit>,
+    /// Signed event the crypto helper verifies
+    probe: Arc<Event>,
+    context: Attachment<HealthContext>,
+}
+
+impl std::fmt::Debug for HealthChecks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HealthChecks")
+            .field("timeout", &self.timeout)
+            .field("max_map_usage", &self.max_map_usage)
+            .field(
+                "queues",
+                &self
+                    .queues
+                    .iter()
+                    .map(|queue| &queue.name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("attached", &self.context.is_attached())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for HealthChecks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HealthChecks {
+    /// 2 second timeouts, a map at most 95% full, 8000 events queued for
+    /// verification or signing and 100000 for slow connections
+    pub fn new() -> Self {
+        let probe = EventBuilder::text_note("health probe")
+            .sign_with_keys(&Keys::generate())
+            .expect("signing with generated keys succeeds");
+        Self {
+            timeout: Duration::from_secs(2),
+            max_map_usage: 0.95,
+            crypto_queue_limit: 8_000,
+            delivery_queue_limit: 100_000,
+            queues: Vec::new(),
+            probe: Arc::new(probe),
+            context: Attachment::default(),
+        }
+    }
+
+    /// Fail checks whose database lookup or signature verification takes longer than `timeout`
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Not ready once the data file uses more than `ratio` of the LMDB map
+    #[must_use]
+    pub fn with_max_map_usage(mut self, ratio: f64) -> Self {
+        self.max_map_usage = ratio;
+        self
+    }
+
+    /// Not ready with more than `limit` events queued for verification or signing
+    #[must_use]
+    pub fn with_crypto_queue_limit(mut self, limit: usize) -> Self {
+        self.crypto_queue_limit = limit;
+        self
+    }
+
+    /// Not ready with more than `limit` events queued for slow connections
+    #[must_use]
+    pub fn with_delivery_queue_limit(mut self, limit: usize) -> Self {
+        self.delivery_queue_limit = limit;
+        self
+    }
+
+    /// Not ready while the depth `depth` reports of a queue of the application exceeds `limit`
+    #[must_use]
+    pub fn with_queue(
+        mut self,
+        name: impl Into<String>,
+        depth: impl Fn() -> usize + Send + Sync + 'static,
+        limit: usize,
+    ) -> Self {
+        self.queues.push(QueueLimit {
+            name: name.into(),
+            depth: Arc::new(depth),
+            limit,
+        });
+        self
+    }
+
+    /// Check the relay built with these probes, which fail until then
+    pub(crate) fn attach(&self, context: HealthContext) {
+        self.context.attach(context, "Health checks");
+    }
+
+    /// Whether the process works: its runtime still schedules tasks
+    ///
+    /// Only checks the process, so a relay still being built, which can take a
+    /// while with [startup repair](crate::RelayBuilder::with_startup_repair),
+    /// or struggling with a dependency isn't restarted for it.
+    pub async fn liveness(&self) -> HealthReport {
+        HealthReport::new(BTreeMap::from([(
+            "runtime".to_string(),
+            self.check_runtime().await,
+        )]))
+    }
+
+    /// Whether the relay should get traffic: liveness, background tasks, the
+    /// crypto helper, shutdown, database and queues
+    pub async fn readiness(&self) -> HealthReport {
+        let Some(context) = self.context.get() else {
+            return starting(CheckResult::fail("relay is starting"));
+        };
+        let mut checks = self.liveness().await.checks;
+        checks.insert("tasks".to_string(), self.check_tasks(context));
+        checks.insert("crypto".to_string(), self.check_crypto(context).await);
+        let shutting_down = context
+            .cancellation_token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled);
+        let draining = context
+            .connection_drain
+            .as_ref()
+            .is_some_and(ConnectionDrain::is_draining);
+        checks.insert(
+            "shutdown".to_string(),
+            if shutting_down {
+                CheckResult::fail("shutting down")
+            } else if draining {
+                CheckResult::fail("draining connections")
+            } else {
+                CheckResult::pass("running")
+            },
+        );
+        checks.insert("database".to_string(), self.check_database(context).await);
+        checks.insert("queues".to_string(), self.check_queues(context));
+        HealthReport::new(checks)
+    }
+
+    async fn check_runtime(&self) -> CheckResult {
+        let started = Instant::now();
+        match tokio::time::timeout(self.timeout, tokio::spawn(async {})).await {
+            Ok(Ok(())) => CheckResult::pass(format!(
+                "scheduled a task in {} ms",
+                started.elapsed().as_millis()
+            )),
+            Ok(Err(e)) => CheckResult::fail(format!("task failed: {e}")),
+            Err(_) => CheckResult::fail(format!("no task scheduled within {:?}", self.timeout)),
+        }
+    }
+
+    fn check_tasks(&self, context: &HealthContext) -> CheckResult {
+        let restarting: Vec<String> = context
+            .task_supervisor
+            .health()
+            .into_iter()
+            .filter(|(_, health)| health.restarting > 0)
+            .map(|(name, _)| name)
+            .collect();
+        if restarting.is_empty() {
+            CheckResult::pass("no task restarting")
+        } else {
+            CheckResult::fail(format!("restarting: {}", restarting.join(", ")))
+        }
+    }
+
+    async fn check_crypto(&self, context: &HealthContext) -> CheckResult {
+        let started = Instant::now();
+        let verified = tokio::time::timeout(
+            self.timeout,
+            context.crypto_helper.verify_event((*self.probe).clone()),
+        )
+        .await;
+        match verified {
+            Ok(Ok(())) => {
+                CheckResult::pass(format!("verified in {} ms", started.elapsed().as_millis()))
+            }
+            Ok(Err(e)) => CheckResult::fail(format!("verification failed: {e}")),
+            Err(_) => CheckResult::fail(format!("no verification within {:?}", self.timeout)),
+        }
+    }
+
+    async fn check_database(&self, context: &HealthContext) -> CheckResult {
+        let database = &context.database;
+        if database.is_read_only() {
+            return CheckResult::fail("opened read-only");
+        }
+        let usage = match database.map_usage() {
+            Ok(usage) => usage,
+            Err(e) => return CheckResult::fail(e.to_string()),
+        };
+        if usage.ratio() > self.max_map_usage {
+            return CheckResult::fail(format!("map {:.0}% full", usage.ratio() * 100.0));
+        }
+
+        let started = Instant::now();
+        let lookup = tokio::time::timeout(
+            self.timeout,
+            database.has_event(&self.probe.id, &Scope::Default),
+        )
+        .await;
+        match lookup {
+            Ok(Ok(_)) => CheckResult::pass(format!(
+                "lookup in {} ms, map {:.0}% full",
+                started.elapsed().as_millis(),
+                usage.ratio() * 100.0
+            )),
+            Ok(Err(e)) => CheckResult::fail(format!("lookup failed: {e}")),
+            Err(_) => CheckResult::fail(format!("no lookup within {:?}", self.timeout)),
+        }
+    }
+
+    fn check_queues(&self, context: &HealthContext) -> CheckResult {
+        let depths = [
+            (
+                "verification",
+                context.crypto_helper.pending_verifications(),
+                self.crypto_queue_limit,
+            ),
+            (
+                "signing",
+                context.crypto_helper.pending_signatures(),
+                self.crypto_queue_limit,
+            ),
+            (
+                "deliveries",
+                context.registry.queued_events(),
+                self.delivery_queue_limit,
+            ),
+        ]
+        .into_iter()
+        .chain(
+            self.queues
+                .iter()
+                .map(|queue| (queue.name.as_str(), (queue.depth)(), queue.limit)),
+        );
+
+        let mut over = Vec::new();
+        let mut total = 0;
+        for (name, depth, limit) in depths {
+            total += depth;
+            if depth > limit {
+                over.push(format!("{name} {depth}/{limit}"));
+            }
+        }
+        if over.is_empty() {
+            CheckResult::pass(format!("{total} queued"))
+        } else {
+            CheckResult::fail(format!("over limit: {}", over.join(", ")))
+        }
+    }
+
+    /// `GET /healthz` and `GET /readyz`
+    #[cfg(feature = "axum")]
+    pub fn router(&self) -> axum::Router {
+        use axum::extract::State;
+        use axum::routing::get;
+
+        async fn healthz(State(checks): State<HealthChecks>) -> axum::response::Response {
+            respond(checks.liveness().await)
+        }
+
+        async fn readyz(State(checks): State<HealthChecks>) -> axum::response::Response {
+            respond(checks.readiness().await)
+        }
+
+        axum::Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .with_state(self.clone())
+    }
+}
+
+fn starting(check: CheckResult) -> HealthReport {
+    HealthReport::new(BTreeMap::from([("relay".to_string(), check)]))
+}
+
+#[cfg(feature = "axum")]
+fn respond(report: HealthReport) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let status = if report.ok {
+        axum::http::StatusCode::OK
+    } else {
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, axum::Json(report)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    #[tokio::test]
+    async fn test_probes_of_a_running_then_stopping_relay() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let checks = HealthChecks::new().with_queue("backlog", || 3, 2);
+        assert!(checks.liveness().await.ok);
+        assert!(!checks.readiness().await.ok);
+
+        let cancellation_token = CancellationToken::new();
+        checks.attach(HealthContext {
+            database,
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+            crypto_helper: CryptoHelper::new(Arc::new(keys)),
+            task_supervisor: TaskSupervisor::new(),
+            cancellation_token: Some(cancellation_token.clone()),
+            connection_drain: None,
+        });
+
+        // Liveness only checks the process
+        let liveness = checks.liveness().await;
+        assert!(liveness.ok, "{liveness:?}");
+        assert_eq!(liveness.checks.keys().collect::<Vec<_>>(), vec!["runtime"]);
+
+        // The application queue is over its limit
+        let readiness = checks.readiness().await;
+        assert!(!readiness.ok);
+        assert!(readiness.checks["tasks"].ok);
+        assert!(readiness.checks["crypto"].ok);
+        assert!(readiness.checks["database"].ok);
+        assert_eq!(readiness.checks["queues"].detail, "over limit: backlog 3/2");
+
+        cancellation_token.cancel();
+        let readiness = checks.readiness().await;
+        assert!(!readiness.checks["shutdown"].ok);
+        assert!(checks.liveness().await.ok);
+    }
+}
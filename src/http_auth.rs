@@ -0,0 +1,293 @@
+//! NIP-98 HTTP authentication
+//!
+//! HTTP endpoints authenticate callers with a kind 27235 event, base64
+//! encoded in an `Authorization: Nostr <event>` header. The event is valid
+//! when it is properly signed, was created less than [`HTTP_AUTH_WINDOW`] from
+//! now, its `u` tag is the absolute requested URL, its `method` tag the
+//! request method and, when it has a `payload` tag, that tag is the hex
+//! SHA-256 of the request body. [`verify_header`] checks all of it and
+//! returns the signer.
+//!
+//! Endpoints that must read the body to check the `payload` tag first call
+//! [`signed_event`], which checks the kind, signature and age, so that only a
+//! properly signed authorization makes them buffer anything.
+//!
+//! Endpoints whose requests change state should also require the `payload`
+//! tag on requests with a body and refuse authorizations seen before with a
+//! [`ReplayCache`], so a captured header can't be sent again with another
+//! body while it is still fresh.
+
+use crate::error::{Error, Result};
+use base64::Engine;
+use nostr::hashes::{sha256, Hash};
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Scheme of NIP-98 `Authorization` headers
+pub const HTTP_AUTH_SCHEME: &str = "Nostr";
+
+/// How far the `created_at` of an authorization event may be from now
+pub const HTTP_AUTH_WINDOW: Duration = Duration::from_secs(60);
+
+/// Why an authorization was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpAuthError {
+    /// No `Authorization: Nostr ...` header
+    Missing,
+    /// The header is not a base64 encoded event
+    Malformed,
+    /// The event is not of kind 27235
+    WrongKind,
+    /// The event id or signature is invalid
+    InvalidSignature,
+    /// The event was created outside the accepted window
+    Expired,
+    /// The `u` tag is not the requested URL
+    UrlMismatch,
+    /// The `method` tag is not the request method
+    MethodMismatch,
+    /// The `payload` tag is not the hash of the body
+    PayloadMismatch,
+    /// The request has a body but the event no `payload` tag
+    PayloadMissing,
+    /// The event already authorized a request
+    Replayed,
+}
+
+impl std::fmt::Display for HttpAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            Self::Missing => "missing NIP-98 authorization",
+            Self::Malformed => "authorization is not a base64 encoded event",
+            Self::WrongKind => "authorization event must be of kind 27235",
+            Self::InvalidSignature => "authorization event signature is invalid",
+            Self::Expired => "authorization event is expired",
+            Self::UrlMismatch => "authorization event is for another URL",
+            Self::MethodMismatch => "authorization event is for another method",
+            Self::PayloadMismatch => "authorization payload does not match the body",
+            Self::PayloadMissing => "authorization must include the payload hash of the body",
+            Self::Replayed => "authorization event was already used",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for HttpAuthError {}
+
+/// Signer of the authorization event in `header`, a full `Authorization` header value
+pub fn verify_header(
+    header: Option<&str>,
+    url: &str,
+    method: &str,
+    body: &[u8],
+) -> std::result::Result<PublicKey, HttpAuthError> {
+    let event = decode_header(header)?;
+    verify_event(&event, url, method, body)?;
+    Ok(event.pubkey)
+}
+
+/// Authorization event in `header`, once its kind, signature and age are checked
+///
+/// The request itself still has to be checked with [`verify_request`].
+pub fn signed_event(header: Option<&str>) -> std::result::Result<Event, HttpAuthError> {
+    let event = decode_header(header)?;
+    verify_signed(&event)?;
+    Ok(event)
+}
+
+/// Check that `event` authorizes a `method` request to `url` carrying `body`
+pub fn verify_event(
+    event: &Event,
+    url: &str,
+    method: &str,
+    body: &[u8],
+) -> std::result::Result<(), HttpAuthError> {
+    verify_signed(event)?;
+    verify_request(event, url, method, body)
+}
+
+fn decode_header(header: Option<&str>) -> std::result::Result<Event, HttpAuthError> {
+    let encoded = header
+        .and_then(|value| value.strip_prefix(HTTP_AUTH_SCHEME))
+        .and_then(|value| value.strip_prefix(' '))
+        .ok_or(HttpAuthError::Missing)?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|_| HttpAuthError::Malformed)?;
+    Event::from_json(decoded).map_err(|_| HttpAuthError::Malformed)
+}
+
+fn verify_signed(event: &Event) -> std::result::Result<(), HttpAuthError> {
+    if event.kind != Kind::HttpAuth {
+        return Err(HttpAuthError::WrongKind);
+    }
+    if event.verify().is_err() {
+        return Err(HttpAuthError::InvalidSignature);
+    }
+    let now = Timestamp::now().as_u64();
+    if event.created_at.as_u64().abs_diff(now) > HTTP_AUTH_WINDOW.as_secs() {
+        return Err(HttpAuthError::Expired);
+    }
+    Ok(())
+}
+
+/// Check the `u`, `method` and `payload` tags of an already [`signed_event`]
+pub fn verify_request(
+    event: &Event,
+    url: &str,
+    method: &str,
+    body: &[u8],
+) -> std::result::Result<(), HttpAuthError> {
+    if tag_value(event, "u") != Some(url) {
+        return Err(HttpAuthError::UrlMismatch);
+    }
+    if !tag_value(event, "method").is_some_and(|m| m.eq_ignore_ascii_case(method)) {
+        return Err(HttpAuthError::MethodMismatch);
+    }
+    if let Some(payload) = tag_value(event, "payload") {
+        if !payload.eq_ignore_ascii_case(&sha256::Hash::hash(body).to_string()) {
+            return Err(HttpAuthError::PayloadMismatch);
+        }
+    }
+    Ok(())
+}
+
+/// Whether the authorization `event` has a `payload` tag, so the body must be
+/// read to verify it
+pub fn has_payload(event: &Event) -> bool {
+    tag_value(event, "payload").is_some()
+}
+
+/// Second value of the first `name` tag of `event`
+fn tag_value<'a>(event: &'a Event, name: &str) -> Option<&'a str> {
+    event.tags.iter().find_map(|tag| match tag.as_slice() {
+        [tag_name, value, ..] if tag_name == name => Some(value.as_str()),
+        _ => None,
+    })
+}
+
+/// Ids of the authorization events already used, while they are fresh
+///
+/// An event outside [`HTTP_AUTH_WINDOW`] is refused anyway, so ids are kept
+/// until their event expires. Clones share the cache.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayCache {
+    seen: Arc<Mutex<HashMap<EventId, Timestamp>>>,
+}
+
+impl ReplayCache {
+    /// An empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the use of `event`, refusing it if it was used before
+    pub fn check(&self, event: &Event) -> std::result::Result<(), HttpAuthError> {
+        let now = Timestamp::now();
+        let expires_at = Timestamp::from(event.created_at.as_u64() + HTTP_AUTH_WINDOW.as_secs());
+        let mut seen = self.seen.lock();
+        seen.retain(|_, expires_at| *expires_at >= now);
+        if seen.insert(event.id, expires_at).is_some() {
+            return Err(HttpAuthError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+/// `Authorization` header value signing a `method` request to `url` with `keys`
+///
+/// The body hash is included when `body` is given.
+pub fn authorization_header(
+    keys: &Keys,
+    url: &str,
+    method: &str,
+    body: Option<&[u8]>,
+) -> Result<String> {
+    let mut tags = vec![
+        Tag::custom(TagKind::custom("u"), [url]),
+        Tag::custom(TagKind::custom("method"), [method.to_uppercase()]),
+    ];
+    if let Some(body) = body {
+        tags.push(Tag::custom(
+            TagKind::custom("payload"),
+            [sha256::Hash::hash(body).to_string()],
+        ));
+    }
+    let event = EventBuilder::new(Kind::HttpAuth, "")
+        .tags(tags)
+        .sign_with_keys(keys)
+        .map_err(|e| Error::internal(format!("Failed to sign authorization event: {e}")))?;
+    Ok(format!(
+        "{HTTP_AUTH_SCHEME} {}",
+        base64::engine::general_purpose::STANDARD.encode(event.as_json())
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://relay.example.com/api/event";
+
+    #[test]
+    fn test_verify_header() {
+        let keys = Keys::generate();
+        let header = authorization_header(&keys, URL, "post", Some(b"{}")).unwrap();
+
+        assert_eq!(
+            verify_header(Some(&header), URL, "POST", b"{}"),
+            Ok(keys.public_key())
+        );
+        let event = signed_event(Some(&header)).unwrap();
+        assert!(has_payload(&event));
+        assert_eq!(verify_request(&event, URL, "POST", b"{}"), Ok(()));
+        assert_eq!(
+            verify_header(Some(&header), URL, "POST", b"[]"),
+            Err(HttpAuthError::PayloadMismatch)
+        );
+        assert_eq!(
+            verify_header(Some(&header), URL, "GET", b"{}"),
+            Err(HttpAuthError::MethodMismatch)
+        );
+        assert_eq!(
+            verify_header(Some(&header), "https://other.example.com", "POST", b"{}"),
+            Err(HttpAuthError::UrlMismatch)
+        );
+        assert_eq!(
+            verify_header(None, URL, "POST", b"{}"),
+            Err(HttpAuthError::Missing)
+        );
+        assert_eq!(
+            verify_header(Some("Nostr not-base64!"), URL, "POST", b"{}"),
+            Err(HttpAuthError::Malformed)
+        );
+
+        let stale = EventBuilder::new(Kind::HttpAuth, "")
+            .tags([
+                Tag::custom(TagKind::custom("u"), [URL]),
+                Tag::custom(TagKind::custom("method"), ["GET"]),
+            ])
+            .custom_created_at(Timestamp::now() - HTTP_AUTH_WINDOW.as_secs() * 2)
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(
+            verify_event(&stale, URL, "GET", b""),
+            Err(HttpAuthError::Expired)
+        );
+        let stale_header = format!(
+            "{HTTP_AUTH_SCHEME} {}",
+            base64::engine::general_purpose::STANDARD.encode(stale.as_json())
+        );
+        assert_eq!(
+            signed_event(Some(&stale_header)),
+            Err(HttpAuthError::Expired)
+        );
+
+        let replays = ReplayCache::new();
+        assert_eq!(replays.check(&event), Ok(()));
+        assert_eq!(replays.check(&event), Err(HttpAuthError::Replayed));
+    }
+}
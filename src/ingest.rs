@@ -0,0 +1,475 @@
+//! Ordered stages of the event write path
+//!
+//! Every signed event a connection saves goes through the same stages, in
+//! this order:
+//!
+//! 1. **validate**: accepted kinds and structural [`EventLimits`] (size, tags,
+//!    content)
+//! 2. **policy**: the [`EventPolicyChain`] (tenants, moderation, allowlists,
+//!    web of trust, payments, ...)
+//! 3. **verify**: the id is recomputed, then the signature is checked by the
+//!    [`CryptoHelper`]
+//! 4. **dedup**: events already stored in the scope are acknowledged with
+//!    `OK true duplicate:` and go no further
+//! 5. **spam**: the [`SpamFilter`] scores the event with the author's recent
+//!    history and the [`ConnectionMetadata`] of its sender
+//! 6. **persist**: the database write
+//! 7. **distribute**: fan-out to matching subscriptions
+//!
+//! Parsing happens before, when the message converter turns a frame into an
+//! EVENT. Cheap checks come first, so an event a policy refuses never costs a
+//! signature verification. An [`IngestPipeline`] holds the configuration of the
+//! stages up to spam, and the [`SubscriptionCoordinator`] runs it before
+//! persisting and distributing. Custom stages are [`EventPolicy`]s inserted
+//! before a built-in stage with [`IngestPipeline::with_stage_before`]. The time
+//! spent in each stage, custom stages before it included, is reported through
+//! [`RelayMetricsHandler::record_ingest_stage`].
+//!
+//! Refused events are answered with `OK false` and a NIP-01 prefixed reason
+//! naming the check that failed, e.g. `invalid: event id does not match its
+//! content`.
+//!
+//! [`SubscriptionCoordinator`]: crate::subscription_coordinator::SubscriptionCoordinator
+//! [`RelayMetricsHandler::record_ingest_stage`]: crate::metrics::RelayMetricsHandler::record_ingest_stage
+
+use crate::config::EventLimits;
+use crate::crypto_helper::CryptoHelper;
+use crate::database::RelayDatabase;
+use crate::event_policy::{EventPolicy, EventPolicyChain, PolicyDecision};
+use crate::provenance::IngestPath;
+use crate::spam::SpamFilter;
+use crate::subscription_coordinator::ClosedReason;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::error;
+
+/// Reason given for events whose id is not the hash of their content
+pub const INVALID_ID_MESSAGE: &str = "event id does not match its content";
+
+/// Reason given for events whose signature does not match their id and pubkey
+pub const INVALID_SIGNATURE_MESSAGE: &str = "event signature verification failed";
+
+/// A built-in stage of the write path, in pipeline order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IngestStage {
+    Validate,
+    Policy,
+    Verify,
+    Dedup,
+    Spam,
+    Persist,
+    Distribute,
+}
+
+impl IngestStage {
+    /// All stages, in pipeline order
+    pub const ALL: [IngestStage; 7] = [
+        IngestStage::Validate,
+        IngestStage::Policy,
+        IngestStage::Verify,
+        IngestStage::Dedup,
+        IngestStage::Spam,
+        IngestStage::Persist,
+        IngestStage::Distribute,
+    ];
+
+    /// Stable name for use as a metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IngestStage::Validate => "validate",
+            IngestStage::Policy => "policy",
+            IngestStage::Verify => "verify",
+            IngestStage::Dedup => "dedup",
+            IngestStage::Spam => "spam",
+            IngestStage::Persist => "persist",
+            IngestStage::Distribute => "distribute",
+        }
+    }
+}
+
+/// Verdict of the stages before persist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// Persist and distribute the event
+    Accept,
+    /// The event is already stored, acknowledge it without saving it again
+    Duplicate,
+    /// Acknowledge the event as accepted without storing or distributing it
+    ShadowAccept,
+    /// Refuse the event; the reason is sent to the client in the OK message
+    Reject(ClosedReason),
+}
+
+/// What happened to an event that went through the pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IngestOutcome {
+    /// The event was stored and distributed
+    Stored,
+    /// The event was already stored and was not distributed again
+    Duplicate,
+    /// The event was acknowledged but dropped as spam
+    ShadowAccepted,
+}
+
+/// Where an event being admitted comes from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionMetadata {
+    pub path: IngestPath,
+    /// Connection that sent the event, for WebSocket clients
+    pub connection_id: Option<String>,
+    /// Pubkey the sender authenticated as, with NIP-42 or NIP-98
+    pub auth_pubkey: Option<PublicKey>,
+    /// Address the client connected from, if known
+    pub remote_address: Option<String>,
+}
+
+impl ConnectionMetadata {
+    /// Event received by `path` from an unknown sender
+    pub fn new(path: IngestPath) -> Self {
+        Self {
+            path,
+            ..Self::default()
+        }
+    }
+
+    /// Sender authenticated as `auth_pubkey`
+    #[must_use]
+    pub fn with_auth_pubkey(mut self, auth_pubkey: Option<PublicKey>) -> Self {
+        self.auth_pubkey = auth_pubkey;
+        self
+    }
+}
+
+/// Configuration of the stages an event passes before it is persisted
+///
+/// Every stage is a no-op until configured, except the id check and dedup.
+#[derive(Debug, Clone, Default)]
+pub struct IngestPipeline {
+    limits: EventLimits,
+    /// Limits replacing `limits` in some scopes
+    scope_limits: Arc<HashMap<Scope, EventLimits>>,
+    policies: EventPolicyChain,
+    verifier: Option<CryptoHelper>,
+    spam: Option<SpamFilter>,
+    /// Custom stages with the built-in stage they run before, in insertion order
+    custom: Arc<Vec<(IngestStage, Arc<dyn EventPolicy>)>>,
+}
+
+impl IngestPipeline {
+    /// A pipeline that only deduplicates
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuse events breaking `limits` in the validate stage
+    #[must_use]
+    pub fn with_limits(mut self, limits: EventLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Refuse events of `scope` breaking `limits` instead of the relay-wide ones
+    #[must_use]
+    pub fn with_scope_limits(mut self, scope: Scope, limits: EventLimits) -> Self {
+        Arc::make_mut(&mut self.scope_limits).insert(scope, limits);
+        self
+    }
+
+    /// Run `policies` in the policy stage
+    #[must_use]
+    pub fn with_policies(mut self, policies: EventPolicyChain) -> Self {
+        self.policies = policies;
+        self
+    }
+
+    /// Verify signatures with `crypto_helper` in the verify stage
+    #[must_use]
+    pub fn with_verification(mut self, crypto_helper: CryptoHelper) -> Self {
+        self.verifier = Some(crypto_helper);
+        self
+    }
+
+    /// Score events with `spam` in the spam stage
+    #[must_use]
+    pub fn with_spam_filter(mut self, spam: SpamFilter) -> Self {
+        self.spam = Some(spam);
+        self
+    }
+
+    /// Run `stage` right before the built-in `before` stage
+    ///
+    /// Custom stages inserted before the same built-in stage run in insertion
+    /// order. An event can't be refused once stored, so stages inserted before
+    /// [`IngestStage::Distribute`] run before [`IngestStage::Persist`] too.
+    #[must_use]
+    pub fn with_stage_before(self, before: IngestStage, stage: impl EventPolicy + 'static) -> Self {
+        self.with_arc_stage_before(before, Arc::new(stage))
+    }
+
+    /// Run a shared `stage` right before the built-in `before` stage
+    #[must_use]
+    pub fn with_arc_stage_before(
+        mut self,
+        before: IngestStage,
+        stage: Arc<dyn EventPolicy>,
+    ) -> Self {
+        let before = match before {
+            IngestStage::Distribute => IngestStage::Persist,
+            before => before,
+        };
+        Arc::make_mut(&mut self.custom).push((before, stage));
+        self
+    }
+
+    /// Run the stages up to spam for `event` from `source` about to be saved to `scope`
+    pub async fn admit(
+        &self,
+        event: &Event,
+        scope: &Scope,
+        source: &ConnectionMetadata,
+        database: &RelayDatabase,
+    ) -> Admission {
+        let auth_pubkey = source.auth_pubkey.as_ref();
+        for stage in [
+            IngestStage::Validate,
+            IngestStage::Policy,
+            IngestStage::Verify,
+            IngestStage::Dedup,
+            IngestStage::Spam,
+        ] {
+            let started = Instant::now();
+            let admission = match self.run_custom(stage, event, scope, auth_pubkey).await {
+                Admission::Accept => {
+                    self.run_builtin(stage, event, scope, source, database)
+                        .await
+                }
+                refused => refused,
+            };
+            record_stage(stage, started);
+            if admission != Admission::Accept {
+                return admission;
+            }
+        }
+
+        // Custom stages placed before persist are the last chance to refuse
+        self.run_custom(IngestStage::Persist, event, scope, auth_pubkey)
+            .await
+    }
+
+    /// Check `event` against the accepted kinds and the limits of `scope`
+    pub fn validate(&self, event: &Event, scope: &Scope) -> Result<(), ClosedReason> {
+        self.scope_limits
+            .get(scope)
+            .unwrap_or(&self.limits)
+            .validate(event)
+            .map_err(ClosedReason::Invalid)
+    }
+
+    /// Recompute the id of `event`, then verify its signature
+    pub async fn verify(&self, event: &Event) -> Result<(), ClosedReason> {
+        if !event.verify_id() {
+            return Err(ClosedReason::Invalid(INVALID_ID_MESSAGE.to_string()));
+        }
+        let Some(verifier) = &self.verifier else {
+            return Ok(());
+        };
+        match verifier.verify_event(event.clone()).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_client_error() => {
+                Err(ClosedReason::Invalid(INVALID_SIGNATURE_MESSAGE.to_string()))
+            }
+            Err(e) => {
+                error!("Could not verify event {}: {}", event.id, e);
+                Err(ClosedReason::Error(
+                    "could not verify the event".to_string(),
+                ))
+            }
+        }
+    }
+
+    async fn run_builtin(
+        &self,
+        stage: IngestStage,
+        event: &Event,
+        scope: &Scope,
+        source: &ConnectionMetadata,
+        database: &RelayDatabase,
+    ) -> Admission {
+        let auth_pubkey = source.auth_pubkey.as_ref();
+        match stage {
+            IngestStage::Validate => match self.validate(event, scope) {
+                Ok(()) => Admission::Accept,
+                Err(reason) => Admission::Reject(reason),
+            },
+            IngestStage::Policy => match self.policies.check(event, scope, auth_pubkey).await {
+                PolicyDecision::Accept => Admission::Accept,
+                PolicyDecision::Reject(reason) => Admission::Reject(reason),
+            },
+            IngestStage::Verify => match self.verify(event).await {
+                Ok(()) => Admission::Accept,
+                Err(reason) => Admission::Reject(reason),
+            },
+            IngestStage::Dedup => match database.has_event(&event.id, scope).await {
+                Ok(true) => Admission::Duplicate,
+                Ok(false) => Admission::Accept,
+                Err(e) => Admission::Reject(ClosedReason::Error(format!(
+                    "could not check for duplicates: {e}"
+                ))),
+            },
+            IngestStage::Spam => match &self.spam {
+                Some(spam) => spam.admit(event, scope, source).await,
+                None => Admission::Accept,
+            },
+            IngestStage::Persist | IngestStage::Distribute => Admission::Accept,
+        }
+    }
+
+    async fn run_custom(
+        &self,
+        before: IngestStage,
+        event: &Event,
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Admission {
+        for (stage, policy) in self.custom.iter() {
+            if *stage != before {
+                continue;
+            }
+            if let PolicyDecision::Reject(reason) = policy.check(event, scope, auth_pubkey).await {
+                return Admission::Reject(reason);
+            }
+        }
+        Admission::Accept
+    }
+}
+
+/// Report the time spent in `stage` since `started`
+pub(crate) fn record_stage(stage: IngestStage, started: Instant) {
+    if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+        metrics.record_ingest_stage(stage.as_str(), started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_policy::MaxContentLength;
+    use crate::test_utils::setup_test_with_database;
+
+    #[derive(Debug)]
+    struct RejectAll;
+
+    #[async_trait::async_trait]
+    impl EventPolicy for RejectAll {
+        async fn check(&self, _: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+            PolicyDecision::Reject(ClosedReason::Blocked("custom stage".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stages_run_in_order() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let pipeline = IngestPipeline::new()
+            .with_limits(EventLimits::new().with_max_tags(1))
+            .with_policies(EventPolicyChain::new().with_policy(MaxContentLength(10)))
+            .with_verification(CryptoHelper::new(Arc::new(Keys::generate())));
+
+        let event = EventBuilder::text_note("hello")
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(
+            pipeline
+                .admit(
+                    &event,
+                    &Scope::Default,
+                    &ConnectionMetadata::default(),
+                    &database
+                )
+                .await,
+            Admission::Accept
+        );
+        database.save_event(&event, &Scope::Default).await.unwrap();
+        assert_eq!(
+            pipeline
+                .admit(
+                    &event,
+                    &Scope::Default,
+                    &ConnectionMetadata::default(),
+                    &database
+                )
+                .await,
+            Admission::Duplicate
+        );
+
+        // Validation runs before the policies
+        let tagged = EventBuilder::text_note("far too long for the policy")
+            .tags([Tag::hashtag("a"), Tag::hashtag("b")])
+            .sign_with_keys(&keys)
+            .unwrap();
+        match pipeline
+            .admit(
+                &tagged,
+                &Scope::Default,
+                &ConnectionMetadata::default(),
+                &database,
+            )
+            .await
+        {
+            Admission::Reject(ClosedReason::Invalid(reason)) => {
+                assert!(reason.contains("tags"))
+            }
+            other => panic!("Expected invalid, got {other:?}"),
+        }
+
+        // A forged event is refused by verification, not deduplicated
+        let mut forged = event.clone();
+        forged.content = "tampered".to_string();
+        assert_eq!(
+            pipeline
+                .admit(
+                    &forged,
+                    &Scope::Default,
+                    &ConnectionMetadata::default(),
+                    &database
+                )
+                .await,
+            Admission::Reject(ClosedReason::Invalid(INVALID_ID_MESSAGE.to_string()))
+        );
+        let mut resigned = event.clone();
+        resigned.sig = EventBuilder::text_note("other")
+            .sign_with_keys(&keys)
+            .unwrap()
+            .sig;
+        assert_eq!(
+            pipeline.verify(&resigned).await,
+            Err(ClosedReason::Invalid(INVALID_SIGNATURE_MESSAGE.to_string()))
+        );
+
+        let tenant = Scope::named("tenant").unwrap();
+        let kinds = IngestPipeline::new()
+            .with_limits(EventLimits::new().with_allowed_kinds([0..=0]))
+            .with_scope_limits(tenant.clone(), EventLimits::new());
+        assert_eq!(
+            kinds.validate(&event, &Scope::Default),
+            Err(ClosedReason::Invalid("kind 1 is not accepted".to_string()))
+        );
+        assert_eq!(kinds.validate(&event, &tenant), Ok(()));
+
+        // Custom stages run before the built-in stage they were placed before
+        let pipeline = pipeline.with_stage_before(IngestStage::Dedup, RejectAll);
+        assert_eq!(
+            pipeline
+                .admit(
+                    &event,
+                    &Scope::Default,
+                    &ConnectionMetadata::default(),
+                    &database
+                )
+                .await,
+            Admission::Reject(ClosedReason::Blocked("custom stage".to_string()))
+        );
+    }
+}
@@ -0,0 +1,470 @@
+//! Routing of accepted events to host handlers by event kind
+//!
+//! Relays often need to do extra work once an event has been stored: zap
+//! receipts (kind 9735) feed a payment ledger, long-form articles (kind 30023)
+//! go to a search indexer, and so on. [`KindRouter`] maps kind ranges to
+//! [`KindHandler`]s that run in the background after the event was saved, so a
+//! slow or failing handler never delays the OK response or affects other routes.
+//!
+//! Handlers that must decide whether an event is stored, e.g. refusing a
+//! reaction to an unknown event, are registered with
+//! [`KindRouter::route_inline`] instead. They run inside the ingest pipeline,
+//! right before persist, with the database and scope in a [`KindContext`].
+
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::subscription_coordinator::ClosedReason;
+use crate::utils::Attachment;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+/// Handler invoked for every accepted event whose kind falls in its route
+#[async_trait]
+pub trait KindHandler: Send + Sync {
+    /// Process an event that has been saved to `scope`
+    async fn handle(&self, event: Arc<Event>, scope: Scope) -> Result<()>;
+}
+
+#[async_trait]
+impl<F, Fut> KindHandler for F
+where
+    F: Fn(Arc<Event>, Scope) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    async fn handle(&self, event: Arc<Event>, scope: Scope) -> Result<()> {
+        self(event, scope).await
+    }
+}
+
+/// What an inline handler sees of the relay while an event is ingested
+#[derive(Clone)]
+pub struct KindContext {
+    /// Scope the event is about to be saved to
+    pub scope: Scope,
+    /// Pubkey the submitting client authenticated as, if any
+    pub auth_pubkey: Option<PublicKey>,
+    /// The relay's database
+    pub database: Arc<RelayDatabase>,
+}
+
+/// Handler deciding on events of its kinds before they are saved
+#[async_trait]
+pub trait InlineKindHandler: Send + Sync {
+    /// Accept `event` or refuse it with the reason sent in the OK message
+    async fn handle(&self, event: Arc<Event>, context: KindContext) -> PolicyDecision;
+}
+
+#[async_trait]
+impl<F, Fut> InlineKindHandler for F
+where
+    F: Fn(Arc<Event>, KindContext) -> Fut + Send + Sync,
+    Fut: Future<Output = PolicyDecision> + Send,
+{
+    async fn handle(&self, event: Arc<Event>, context: KindContext) -> PolicyDecision {
+        self(event, context).await
+    }
+}
+
+#[derive(Clone)]
+struct Route {
+    name: String,
+    kinds: RangeInclusive<u16>,
+    handler: Arc<dyn KindHandler>,
+    /// Limits how many invocations of this handler run at once
+    permits: Arc<Semaphore>,
+}
+
+#[derive(Clone)]
+struct InlineRoute {
+    name: String,
+    kinds: RangeInclusive<u16>,
+    handler: Arc<dyn InlineKindHandler>,
+}
+
+/// Table of kind ranges to post-acceptance and inline handlers
+///
+/// Every background route has its own concurrency limit. When a route is saturated, new
+/// invocations wait for a permit in the background instead of blocking the
+/// connection that submitted the event. Handler errors and panics are logged
+/// and stay contained to the invocation that raised them.
+///
+/// # Example
+/// ```rust,no_run
+/// use async_trait::async_trait;
+/// use nostr_lmdb::Scope;
+/// use nostr_sdk::prelude::*;
+/// use relay_builder::{KindHandler, KindRouter};
+/// use std::sync::Arc;
+///
+/// struct ZapLedger;
+///
+/// #[async_trait]
+/// impl KindHandler for ZapLedger {
+///     async fn handle(&self, event: Arc<Event>, _scope: Scope) -> relay_builder::Result<()> {
+///         println!("zap receipt {}", event.id);
+///         Ok(())
+///     }
+/// }
+///
+/// let router = KindRouter::new().route("zap-ledger", 9735..=9735, 4, ZapLedger);
+/// ```
+#[derive(Clone, Default)]
+pub struct KindRouter {
+    routes: Arc<Vec<Route>>,
+    inline_routes: Arc<Vec<InlineRoute>>,
+    task_tracker: TaskTracker,
+    /// Set when the router is attached to a relay
+    database: Attachment<Arc<RelayDatabase>>,
+}
+
+impl std::fmt::Debug for KindRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KindRouter")
+            .field(
+                "routes",
+                &self
+                    .routes
+                    .iter()
+                    .map(|route| (&route.name, &route.kinds))
+                    .collect::<Vec<_>>(),
+            )
+            .field(
+                "inline_routes",
+                &self
+                    .inline_routes
+                    .iter()
+                    .map(|route| (&route.name, &route.kinds))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl KindRouter {
+    /// Create an empty routing table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Route events with a kind in `kinds` to `handler`
+    ///
+    /// At most `max_concurrency` invocations of the handler run at the same time
+    /// (a value of 0 is treated as 1). An event matching several routes is
+    /// delivered to each of them.
+    #[must_use]
+    pub fn route<H>(
+        mut self,
+        name: impl Into<String>,
+        kinds: RangeInclusive<u16>,
+        max_concurrency: usize,
+        handler: H,
+    ) -> Self
+    where
+        H: KindHandler + 'static,
+    {
+        let route = Route {
+            name: name.into(),
+            kinds,
+            handler: Arc::new(handler),
+            permits: Arc::new(Semaphore::new(max_concurrency.max(1))),
+        };
+        Arc::make_mut(&mut self.routes).push(route);
+        self
+    }
+
+    /// Run `handler` on events with a kind in `kinds` before they are saved
+    ///
+    /// Inline routes run in the ingest pipeline right before persist, after the
+    /// policies, signature verification and deduplication, in the order they
+    /// were added. The first route refusing an event stops it; the client gets
+    /// the reason as `OK false`. Unlike [`Self::route`], a slow handler delays
+    /// the OK response of the events it handles.
+    #[must_use]
+    pub fn route_inline<H>(
+        mut self,
+        name: impl Into<String>,
+        kinds: RangeInclusive<u16>,
+        handler: H,
+    ) -> Self
+    where
+        H: InlineKindHandler + 'static,
+    {
+        let route = InlineRoute {
+            name: name.into(),
+            kinds,
+            handler: Arc::new(handler),
+        };
+        Arc::make_mut(&mut self.inline_routes).push(route);
+        self
+    }
+
+    /// Track handler tasks with the given tracker for graceful shutdown
+    #[must_use]
+    pub fn with_task_tracker(mut self, task_tracker: TaskTracker) -> Self {
+        self.task_tracker = task_tracker;
+        self
+    }
+
+    /// Hand inline handlers the database of the relay built with this router
+    pub(crate) fn attach(&self, database: Arc<RelayDatabase>) {
+        self.database.attach(database, "Kind router");
+    }
+
+    /// Whether any inline route was added
+    pub(crate) fn has_inline_routes(&self) -> bool {
+        !self.inline_routes.is_empty()
+    }
+
+    /// Whether any background route accepts events of `kind`
+    pub fn handles(&self, kind: Kind) -> bool {
+        let kind = kind.as_u16();
+        self.routes.iter().any(|route| route.kinds.contains(&kind))
+    }
+
+    /// Whether the routing table has no background routes
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Hand an accepted event to every matching route
+    ///
+    /// Returns immediately; handlers run on background tasks.
+    pub fn dispatch(&self, event: Arc<Event>, scope: &Scope) {
+        let kind = event.kind.as_u16();
+
+        for route in self
+            .routes
+            .iter()
+            .filter(|route| route.kinds.contains(&kind))
+        {
+            let name = route.name.clone();
+            let handler = Arc::clone(&route.handler);
+            let permits = Arc::clone(&route.permits);
+            let event = Arc::clone(&event);
+            let scope = scope.clone();
+
+            self.task_tracker.spawn(async move {
+                let Ok(_permit) = permits.acquire_owned().await else {
+                    return;
+                };
+
+                let event_id = event.id;
+                // Run the handler on its own task so a panic only takes down this invocation
+                match tokio::spawn(async move { handler.handle(event, scope).await }).await {
+                    Ok(Ok(())) => debug!("Kind route '{}' handled event {}", name, event_id),
+                    Ok(Err(e)) => {
+                        warn!("Kind route '{}' failed for event {}: {}", name, event_id, e)
+                    }
+                    Err(e) => warn!(
+                        "Kind route '{}' panicked on event {}: {}",
+                        name, event_id, e
+                    ),
+                }
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl EventPolicy for KindRouter {
+    async fn check(
+        &self,
+        event: &Event,
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> PolicyDecision {
+        let kind = event.kind.as_u16();
+        let mut routes = self
+            .inline_routes
+            .iter()
+            .filter(|route| route.kinds.contains(&kind))
+            .peekable();
+        if routes.peek().is_none() {
+            return PolicyDecision::Accept;
+        }
+
+        let Some(database) = self.database.get() else {
+            return PolicyDecision::Reject(ClosedReason::Error(
+                "kind handlers are not ready".to_string(),
+            ));
+        };
+        let event = Arc::new(event.clone());
+        let context = KindContext {
+            scope: scope.clone(),
+            auth_pubkey: auth_pubkey.copied(),
+            database: Arc::clone(database),
+        };
+
+        for route in routes {
+            let decision = route
+                .handler
+                .handle(Arc::clone(&event), context.clone())
+                .await;
+            if let PolicyDecision::Reject(reason) = decision {
+                debug!(
+                    "Kind route '{}' refused event {}: {:?}",
+                    route.name, event.id, reason
+                );
+                return PolicyDecision::Reject(reason);
+            }
+        }
+        PolicyDecision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::test_utils::setup_test_with_database;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn event_of_kind(kind: u16) -> Arc<Event> {
+        let keys = Keys::generate();
+        Arc::new(
+            EventBuilder::new(Kind::from(kind), "")
+                .sign_with_keys(&keys)
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_by_kind_range() {
+        let zaps = Arc::new(AtomicUsize::new(0));
+        let articles = Arc::new(AtomicUsize::new(0));
+
+        let router = {
+            let zaps = Arc::clone(&zaps);
+            let articles = Arc::clone(&articles);
+            KindRouter::new()
+                .route("zaps", 9735..=9735, 1, move |_: Arc<Event>, _: Scope| {
+                    let zaps = Arc::clone(&zaps);
+                    async move {
+                        zaps.fetch_add(1, Ordering::SeqCst);
+                        Ok::<(), Error>(())
+                    }
+                })
+                .route(
+                    "articles",
+                    30000..=39999,
+                    1,
+                    move |_: Arc<Event>, _: Scope| {
+                        let articles = Arc::clone(&articles);
+                        async move {
+                            articles.fetch_add(1, Ordering::SeqCst);
+                            Ok::<(), Error>(())
+                        }
+                    },
+                )
+        };
+
+        assert!(router.handles(Kind::ZapReceipt));
+        assert!(!router.handles(Kind::TextNote));
+
+        for kind in [9735, 30023, 1, 30023] {
+            router.dispatch(event_of_kind(kind), &Scope::Default);
+        }
+
+        router.task_tracker.close();
+        router.task_tracker.wait().await;
+
+        assert_eq!(zaps.load(Ordering::SeqCst), 1);
+        assert_eq!(articles.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failing_route_is_isolated() {
+        let delivered = Arc::new(AtomicUsize::new(0));
+
+        let router = {
+            let delivered = Arc::clone(&delivered);
+            KindRouter::new()
+                .route("broken", 1..=1, 1, |_: Arc<Event>, _: Scope| async move {
+                    Err::<(), Error>(Error::internal("indexer unavailable"))
+                })
+                .route(
+                    "panicking",
+                    1..=1,
+                    1,
+                    |event: Arc<Event>, _: Scope| async move {
+                        assert!(!event.content.is_empty(), "handler bug");
+                        Ok::<(), Error>(())
+                    },
+                )
+                .route("healthy", 1..=1, 1, move |_: Arc<Event>, _: Scope| {
+                    let delivered = Arc::clone(&delivered);
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        delivered.fetch_add(1, Ordering::SeqCst);
+                        Ok::<(), Error>(())
+                    }
+                })
+        };
+
+        router.dispatch(event_of_kind(1), &Scope::Default);
+        router.dispatch(event_of_kind(1), &Scope::Default);
+
+        router.task_tracker.close();
+        router.task_tracker.wait().await;
+
+        assert_eq!(delivered.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_inline_route_uses_database() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let router = KindRouter::new().route_inline(
+            "reactions",
+            7..=7,
+            |event: Arc<Event>, context: KindContext| async move {
+                for target in event.tags.event_ids() {
+                    if !matches!(
+                        context.database.has_event(target, &context.scope).await,
+                        Ok(true)
+                    ) {
+                        return PolicyDecision::Reject(ClosedReason::Invalid(
+                            "unknown event".to_string(),
+                        ));
+                    }
+                }
+                PolicyDecision::Accept
+            },
+        );
+        router.attach(Arc::clone(&database));
+        assert!(router.has_inline_routes());
+        assert!(router.is_empty());
+
+        let note = EventBuilder::text_note("hello")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let reaction = EventBuilder::new(Kind::Reaction, "+")
+            .tag(Tag::event(note.id))
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        // Other kinds are not routed
+        assert_eq!(
+            router.check(&note, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+        assert!(matches!(
+            router.check(&reaction, &Scope::Default, None).await,
+            PolicyDecision::Reject(ClosedReason::Invalid(_))
+        ));
+
+        database.save_event(&note, &Scope::Default).await.unwrap();
+        assert_eq!(
+            router.check(&reaction, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+    }
+}
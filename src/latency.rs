@@ -0,0 +1,304 @@
+//! End-to-end latency budget for inbound events
+//!
+//! Every EVENT is timestamped as it moves through the relay:
+//!
+//! - **parsed**: the message left the converter and entered the middleware chain
+//! - **policy complete**: auth and the `EventProcessor` accepted it
+//! - **persisted**: the [ingest pipeline](crate::ingest) admitted it and the
+//!   database write finished
+//! - **distributed**: the event was fanned out to matching subscriptions
+//!
+//! The differences between consecutive timestamps are recorded per [`EventStage`],
+//! so a slowdown can be attributed to the middlewares, ingest and storage, or
+//! fan-out. [`crate::ingest`] breaks the persist segment down further.
+
+use crate::middlewares::MetricsHandler;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Default number of samples kept per stage for percentile calculation
+pub const DEFAULT_LATENCY_WINDOW: usize = 1024;
+
+/// A segment of an event's path through the relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventStage {
+    /// Parsed → policy complete (middlewares, auth, EventProcessor)
+    Policy,
+    /// Policy complete → persisted (ingest pipeline and database write)
+    Persist,
+    /// Persisted → distributed (fan-out to subscriptions)
+    Distribute,
+    /// Parsed → distributed
+    Total,
+}
+
+impl EventStage {
+    /// All stages, in pipeline order
+    pub const ALL: [EventStage; 4] = [
+        EventStage::Policy,
+        EventStage::Persist,
+        EventStage::Distribute,
+        EventStage::Total,
+    ];
+
+    /// Stable name for use as a metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventStage::Policy => "policy",
+            EventStage::Persist => "persist",
+            EventStage::Distribute => "distribute",
+            EventStage::Total => "total",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            EventStage::Policy => 0,
+            EventStage::Persist => 1,
+            EventStage::Distribute => 2,
+            EventStage::Total => 3,
+        }
+    }
+}
+
+/// Timestamps of a single event's stages
+#[derive(Debug, Clone, Copy)]
+pub struct EventTimeline {
+    parsed: Instant,
+    policy_complete: Option<Instant>,
+    persisted: Option<Instant>,
+    distributed: Option<Instant>,
+}
+
+impl EventTimeline {
+    /// Start a timeline for an event that was just parsed
+    pub fn start() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    /// Start a timeline for an event parsed at `parsed`
+    pub fn starting_at(parsed: Instant) -> Self {
+        Self {
+            parsed,
+            policy_complete: None,
+            persisted: None,
+            distributed: None,
+        }
+    }
+
+    /// The event passed verification and the event processor
+    pub fn mark_policy_complete(&mut self) {
+        self.policy_complete = Some(Instant::now());
+    }
+
+    /// The event was written to the database
+    pub fn mark_persisted(&mut self) {
+        self.persisted = Some(Instant::now());
+    }
+
+    /// The event was handed to all matching subscriptions
+    pub fn mark_distributed(&mut self) {
+        self.distributed = Some(Instant::now());
+    }
+
+    /// Duration of every stage whose start and end were both recorded
+    pub fn stage_latencies(&self) -> Vec<(EventStage, Duration)> {
+        let span = |from: Option<Instant>, to: Option<Instant>| match (from, to) {
+            (Some(from), Some(to)) => Some(to.saturating_duration_since(from)),
+            _ => None,
+        };
+
+        [
+            (
+                EventStage::Policy,
+                span(Some(self.parsed), self.policy_complete),
+            ),
+            (
+                EventStage::Persist,
+                span(self.policy_complete, self.persisted),
+            ),
+            (
+                EventStage::Distribute,
+                span(self.persisted, self.distributed),
+            ),
+            (EventStage::Total, span(Some(self.parsed), self.distributed)),
+        ]
+        .into_iter()
+        .filter_map(|(stage, duration)| duration.map(|d| (stage, d)))
+        .collect()
+    }
+}
+
+/// p50/p95/p99 of a stage over the recent sample window, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StagePercentiles {
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    /// Number of samples the percentiles were computed from
+    pub samples: usize,
+}
+
+/// Collector of per-stage event latencies
+///
+/// Keep a clone around to read [`LatencyBudget::percentiles`] while the relay
+/// records into another.
+/// Samples are also forwarded to [`MetricsHandler::record_stage_latency`] when a
+/// metrics handler is attached.
+#[derive(Clone)]
+pub struct LatencyBudget {
+    windows: Arc<[Mutex<VecDeque<f64>>; 4]>,
+    window_size: usize,
+    metrics_handler: Option<Arc<dyn MetricsHandler>>,
+}
+
+impl std::fmt::Debug for LatencyBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LatencyBudget")
+            .field("window_size", &self.window_size)
+            .field("metrics_handler", &self.metrics_handler.is_some())
+            .finish()
+    }
+}
+
+impl Default for LatencyBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_LATENCY_WINDOW)
+    }
+}
+
+impl LatencyBudget {
+    /// Create a budget that keeps the last `window_size` samples per stage
+    pub fn new(window_size: usize) -> Self {
+        let window_size = window_size.max(1);
+        Self {
+            windows: Arc::new(std::array::from_fn(|_| {
+                Mutex::new(VecDeque::with_capacity(window_size))
+            })),
+            window_size,
+            metrics_handler: None,
+        }
+    }
+
+    /// Forward every recorded sample to a metrics handler
+    #[must_use]
+    pub fn with_metrics_handler(mut self, handler: Arc<dyn MetricsHandler>) -> Self {
+        self.metrics_handler = Some(handler);
+        self
+    }
+
+    /// Record the stages of a finished event
+    pub fn record(&self, timeline: &EventTimeline) {
+        for (stage, duration) in timeline.stage_latencies() {
+            let latency_ms = duration.as_secs_f64() * 1000.0;
+
+            {
+                let mut window = self.windows[stage.index()].lock();
+                if window.len() == self.window_size {
+                    window.pop_front();
+                }
+                window.push_back(latency_ms);
+            }
+
+            if let Some(handler) = &self.metrics_handler {
+                handler.record_stage_latency(stage, latency_ms);
+            }
+        }
+    }
+
+    /// Percentiles of a stage over the sample window, `None` without samples
+    pub fn percentiles(&self, stage: EventStage) -> Option<StagePercentiles> {
+        let mut samples: Vec<f64> = self.windows[stage.index()].lock().iter().copied().collect();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(f64::total_cmp);
+
+        // Nearest-rank percentile
+        let rank = |p: f64| {
+            let index = ((p / 100.0) * samples.len() as f64).ceil() as usize;
+            samples[index.clamp(1, samples.len()) - 1]
+        };
+
+        Some(StagePercentiles {
+            p50_ms: rank(50.0),
+            p95_ms: rank(95.0),
+            p99_ms: rank(99.0),
+            samples: samples.len(),
+        })
+    }
+
+    /// Percentiles of every stage that has samples
+    pub fn snapshot(&self) -> Vec<(EventStage, StagePercentiles)> {
+        EventStage::ALL
+            .into_iter()
+            .filter_map(|stage| self.percentiles(stage).map(|p| (stage, p)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeline(policy_ms: u64, persist_ms: u64, distribute_ms: u64) -> EventTimeline {
+        let parsed = Instant::now();
+        let policy_complete = parsed + Duration::from_millis(policy_ms);
+        let persisted = policy_complete + Duration::from_millis(persist_ms);
+        EventTimeline {
+            parsed,
+            policy_complete: Some(policy_complete),
+            persisted: Some(persisted),
+            distributed: Some(persisted + Duration::from_millis(distribute_ms)),
+        }
+    }
+
+    #[test]
+    fn test_stage_percentiles() {
+        let budget = LatencyBudget::new(100);
+        for i in 1..=100 {
+            budget.record(&timeline(i, 2, 1));
+        }
+
+        let policy = budget.percentiles(EventStage::Policy).unwrap();
+        assert_eq!(policy.samples, 100);
+        assert!((policy.p50_ms - 50.0).abs() < 0.5);
+        assert!((policy.p95_ms - 95.0).abs() < 0.5);
+        assert!((policy.p99_ms - 99.0).abs() < 0.5);
+
+        let persist = budget.percentiles(EventStage::Persist).unwrap();
+        assert!((persist.p99_ms - 2.0).abs() < 0.5);
+
+        let total = budget.percentiles(EventStage::Total).unwrap();
+        assert!((total.p50_ms - 53.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_window_keeps_recent_samples() {
+        let budget = LatencyBudget::new(10);
+        for _ in 0..10 {
+            budget.record(&timeline(500, 0, 0));
+        }
+        for _ in 0..10 {
+            budget.record(&timeline(1, 0, 0));
+        }
+
+        let policy = budget.percentiles(EventStage::Policy).unwrap();
+        assert_eq!(policy.samples, 10);
+        assert!(policy.p99_ms < 2.0);
+    }
+
+    #[test]
+    fn test_incomplete_timeline_records_only_finished_stages() {
+        let budget = LatencyBudget::default();
+        let mut timeline = EventTimeline::start();
+        timeline.mark_policy_complete();
+        budget.record(&timeline);
+
+        assert!(budget.percentiles(EventStage::Policy).is_some());
+        assert!(budget.percentiles(EventStage::Persist).is_none());
+        assert!(budget.percentiles(EventStage::Total).is_none());
+    }
+}
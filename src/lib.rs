@@ -7,48 +7,185 @@
 //! - WebSocket connection management
 //! - Database abstraction
 
+#[cfg(feature = "axum")]
+pub mod admin;
+pub mod audit_log;
+pub mod broadcast;
+pub mod cluster;
 pub mod config;
+pub mod connection_hook;
+pub mod connection_limits;
+pub mod count;
 pub mod crypto_helper;
 pub mod database;
+pub mod derived;
+pub mod dm_relay;
+pub mod drain;
 pub mod error;
+#[cfg(feature = "axum")]
+pub mod event_api;
+pub mod event_policy;
 pub mod event_processor;
+pub mod event_sink;
+pub mod federation;
+pub mod filter_validation;
+#[cfg(any(feature = "kafka", feature = "nats"))]
+pub mod firehose;
 pub mod global_metrics;
 #[cfg(feature = "axum")]
 pub mod handlers;
+pub mod health;
+pub mod http_auth;
+pub mod ingest;
+pub mod kind_router;
+pub mod latency;
+#[cfg(feature = "media")]
+pub mod media;
+pub mod memory;
 pub mod message_converter;
 pub mod metrics;
 pub mod middlewares;
+pub mod moderation;
+pub mod nip66;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod overload;
+pub mod pagination;
+pub mod payments;
+pub mod post_save;
+pub mod provenance;
+pub mod proxy;
+pub mod query_augmenter;
+pub mod query_cache;
+pub mod query_planner;
+pub mod rate_limit;
+pub mod receipts;
 pub mod relay_builder;
 pub mod relay_middleware;
+pub mod reports;
+pub mod resume;
+pub mod runtime_config;
+pub mod scheduler;
+pub mod scope_resolver;
+pub mod signer;
+#[cfg(feature = "testing")]
+pub mod simulation;
+pub mod slow_query_log;
+pub mod spam;
 pub mod state;
+pub mod status;
 pub mod subdomain;
 pub mod subscription_coordinator;
 pub mod subscription_registry;
+pub mod supervisor;
+pub mod tenants;
 #[cfg(test)]
 pub mod test_utils;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tombstones;
+pub mod upstream;
 pub mod utils;
+pub mod vanish;
+pub mod web_of_trust;
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
 
-pub use config::{RelayConfig, ScopeConfig, WebSocketConfig};
+#[cfg(feature = "axum")]
+pub use admin::AdminApi;
+pub use audit_log::{AuditLog, AuditQuery, ReqAuditEntry};
+pub use broadcast::{MessageSenderExt, SerializedEvent};
+pub use cluster::{
+    Cluster, ClusterMessage, ClusterStats, ClusterStorage, ClusterTransport, LocalTransport,
+};
+pub use config::{EventLimits, RelayConfig, ScopeConfig, ScopeLimits, WebSocketConfig};
+pub use connection_hook::ConnectionHook;
+pub use connection_limits::ConnectionLimits;
+pub use count::CountConfig;
 pub use crypto_helper::CryptoHelper;
-pub use database::RelayDatabase;
-pub use error::{Error, Result};
+pub use database::{
+    BatchWrite, IntegrityReport, LmdbOptions, LmdbSyncMode, MapUsage, ReadReplicas, RelayDatabase,
+    ScopeStorageStats,
+};
+pub use derived::{DerivedIndex, DerivedIndexes};
+pub use dm_relay::DmRelayProcessor;
+pub use drain::{ConnectionDrain, DRAINING_NOTICE_PREFIX};
+pub use error::{Error, Result, INTERNAL_ERROR_MESSAGE};
+#[cfg(feature = "axum")]
+pub use event_api::EventApi;
+pub use event_policy::{EventPolicy, EventPolicyChain, PolicyDecision};
 pub use event_processor::{DefaultRelayProcessor, EventContext, EventProcessor};
+pub use event_sink::{CallbackSink, ChannelSink, EventSink};
+pub use federation::{Puller, PullerStats};
+pub use filter_validation::FilterValidation;
+#[cfg(any(feature = "kafka", feature = "nats"))]
+pub use firehose::{FirehoseSink, FirehoseStats};
+pub use handlers::{RelayInfo, RelayLimitation, RelayService, ScopeRelayInfo};
 #[cfg(feature = "axum")]
-pub use handlers::{RelayInfo, RelayService};
+pub use health::{CheckResult, HealthChecks, HealthReport};
+pub use http_auth::HttpAuthError;
+pub use ingest::{Admission, ConnectionMetadata, IngestOutcome, IngestPipeline, IngestStage};
+pub use kind_router::{InlineKindHandler, KindContext, KindHandler, KindRouter};
+pub use latency::{EventStage, LatencyBudget};
+#[cfg(feature = "s3")]
+pub use media::S3BlobStore;
+#[cfg(feature = "media")]
+pub use media::{BlobDescriptor, BlobStore, DiskBlobStore, MediaServer};
+pub use memory::MemoryUsage;
 
-pub use message_converter::NostrMessageConverter;
+#[cfg(feature = "cbor")]
+pub use message_converter::CborCodec;
+pub use message_converter::{JsonCodec, MessageCodec, NostrMessageConverter, ParseErrorPolicy};
+pub use moderation::{ModerationLists, ModerationStore};
+pub use nip66::Nip66;
+#[cfg(feature = "otel")]
+pub use otel::{OtelConfig, OtelExporter, OtelMetrics};
+pub use overload::{LoadLevel, OverloadController};
+pub use pagination::Paginator;
+pub use payments::{Membership, MembershipStore, PaymentPolicy, RelayFee, RelayFees};
+pub use post_save::{PostSaveHook, PostSaveHooks};
+pub use provenance::{EventProvenance, IngestPath, ProvenanceStore};
+pub use proxy::ProxyHeaders;
+pub use query_augmenter::QueryAugmenter;
+pub use query_planner::{plan_queries, QueryPlan};
+pub use rate_limit::{Quota, RateLimitConfig, RateLimitKey, RateLimitedAction, RateLimiter};
+pub use receipts::{ReceiptIndex, ReceivedEvent};
 #[cfg(feature = "axum")]
 pub use relay_builder::HtmlOption;
 pub use relay_builder::{DefaultRelayWebSocketHandler, RelayBuilder, RelayWebSocketHandler};
 pub use relay_middleware::RelayMiddleware;
+pub use reports::{ReportEntry, ReportQueue, ReportTarget};
+pub use resume::ResumeCursors;
+pub use runtime_config::{RateLimitRule, ReloadableConfig, RuntimeConfig};
+pub use scheduler::{ScheduledEvent, Scheduler};
+pub use scope_resolver::{ScopeResolver, SubdomainResolver};
+pub use signer::{ExternalSigner, Signer};
+pub use slow_query_log::{SlowQuery, SlowQueryLog};
+pub use spam::{AuthorHistory, HeuristicScorer, SpamFilter, SpamScorer, SpamVerdict};
 pub use state::{DefaultNostrConnectionState, NostrConnectionState};
-pub use subscription_coordinator::{StoreCommand, SubscriptionCoordinator};
-pub use subscription_registry::{EventDistributor, SubscriptionRegistry};
+pub use status::{RelayStatus, StatusFormat, StatusPublisher};
+pub use subscription_coordinator::{
+    ClosedReason, OkReason, OrderingMode, PaginationConfig, ReplaceableBufferConfig, StoreCommand,
+    SubscriptionCoordinator, SubscriptionCoordinatorBuilder, WindowStrategy,
+};
+pub use subscription_registry::{
+    ConnectionStats, EventDistributor, PriorityFn, ReapStats, ScopeActivity, ScopeMigration,
+    ShadowBanFn, SlowConsumerPolicy, SubscriptionPriority, SubscriptionRegistry, VisibilityFn,
+};
+pub use supervisor::{TaskHealth, TaskSupervisor};
+pub use tenants::{Tenant, TenantResolver, TenantStatus, TenantStore};
+pub use tombstones::TombstoneStore;
+pub use upstream::{Upstream, UpstreamStats};
+pub use vanish::VanishRequests;
+pub use web_of_trust::WebOfTrust;
+#[cfg(feature = "webhooks")]
+pub use webhooks::{Webhook, WebhookStats};
 
 // Re-export commonly used middlewares
 pub use middlewares::{
-    AuthConfig, ClientMessageId, ErrorHandlingMiddleware, EventVerifierMiddleware,
-    LoggerMiddleware, Nip40ExpirationMiddleware, Nip42Middleware, Nip70Middleware,
+    AuthConfig, ClientMessageHook, ClientMessageId, ErrorHandlingMiddleware,
+    EventVerifierMiddleware, HookAction, HookContext, HookMiddleware, LoggerMiddleware,
+    Nip40ExpirationMiddleware, Nip42Middleware, Nip70Middleware, RateLimitMiddleware,
 };
 
 // Re-export websocket_builder types to avoid version conflicts
@@ -0,0 +1,718 @@
+//! Blossom media hosting next to the relay
+//!
+//! [`MediaServer`] is an axum router storing blobs addressed by their SHA-256
+//! as described by Blossom (BUD-01 and BUD-02), so a relay can host the media
+//! its events refer to in the same binary. Register it with
+//! [`RelayBuilder::with_media_server`](crate::RelayBuilder::with_media_server),
+//! keep a clone and mount [`MediaServer::router`] at the root of a host.
+//!
+//! | Method | Path | |
+//! |---|---|---|
+//! | GET, HEAD | `/<sha256>[.ext]` | The blob, or its headers |
+//! | PUT | `/upload` | Store the body, answers its blob descriptor |
+//! | DELETE | `/<sha256>` | Delete a blob, only its uploader may |
+//!
+//! Uploads and deletions carry a kind 24242 authorization event in an
+//! `Authorization: Nostr <base64 event>` header, with a `t` tag naming the
+//! action, an `x` tag with the blob hash and an `expiration` tag in the
+//! future. The authorization event goes through the relay's event policies,
+//! so moderation bans, allowlists, payments and the web of trust decide who
+//! may upload like they decide who may publish.
+//!
+//! Blobs are kept in a [`BlobStore`]: [`DiskBlobStore`] on the local disk, or
+//! `S3BlobStore` in an S3 bucket with the `s3` feature. Once stored, a NIP-94
+//! file metadata event signed by the relay and tagging the uploader is
+//! published, unless disabled with [`MediaServer::with_metadata_events`].
+
+use crate::crypto_helper::CryptoHelper;
+use crate::database::RelayDatabase;
+use crate::error::{Error, Result};
+use crate::event_policy::{EventPolicyChain, PolicyDecision};
+use crate::subscription_registry::{EventDistributor, SubscriptionRegistry};
+use crate::utils::Attachment;
+use async_trait::async_trait;
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{get, put};
+use axum::Router;
+use base64::Engine;
+use nostr::hashes::{sha256, Hash};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, error};
+
+/// Kind of Blossom authorization events
+pub const BLOSSOM_AUTH_KIND: Kind = Kind::Custom(24242);
+
+/// Default largest blob accepted, in bytes
+pub const DEFAULT_MAX_BLOB_SIZE: usize = 100 * 1024 * 1024;
+
+/// What the server knows about a stored blob, answered to uploads (BUD-02)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobDescriptor {
+    /// Where the blob is served
+    pub url: String,
+    /// Hex SHA-256 of the blob
+    pub sha256: String,
+    /// Size in bytes
+    pub size: u64,
+    /// MIME type given by the uploader
+    #[serde(rename = "type")]
+    pub mime_type: String,
+    /// Upload time, in seconds since the epoch
+    pub uploaded: u64,
+    /// Pubkey of the uploader
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+}
+
+/// Where blobs and their descriptors are kept
+#[async_trait]
+pub trait BlobStore: Send + Sync + std::fmt::Debug {
+    /// Store `data` and its `descriptor` under `descriptor.sha256`
+    async fn put(&self, descriptor: &BlobDescriptor, data: Bytes) -> Result<()>;
+
+    /// The blob stored under `sha256` with its descriptor
+    async fn get(&self, sha256: &str) -> Result<Option<(BlobDescriptor, Bytes)>>;
+
+    /// The descriptor of the blob stored under `sha256`
+    async fn descriptor(&self, sha256: &str) -> Result<Option<BlobDescriptor>>;
+
+    /// Delete the blob stored under `sha256`, returning whether it existed
+    async fn delete(&self, sha256: &str) -> Result<bool>;
+}
+
+/// Blobs stored as files in a directory, each next to a `.json` descriptor
+#[derive(Debug, Clone)]
+pub struct DiskBlobStore {
+    root: PathBuf,
+}
+
+impl DiskBlobStore {
+    /// Store blobs in `root`, created when missing
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root).map_err(|e| {
+            Error::internal(format!("Failed to create media directory {root:?}: {e}"))
+        })?;
+        Ok(Self { root })
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.root.join(sha256)
+    }
+
+    fn descriptor_path(&self, sha256: &str) -> PathBuf {
+        self.root.join(format!("{sha256}.json"))
+    }
+}
+
+#[async_trait]
+impl BlobStore for DiskBlobStore {
+    async fn put(&self, descriptor: &BlobDescriptor, data: Bytes) -> Result<()> {
+        let descriptor_json = serde_json::to_vec(descriptor)
+            .map_err(|e| Error::internal(format!("Failed to encode blob descriptor: {e}")))?;
+        tokio::fs::write(self.blob_path(&descriptor.sha256), data)
+            .await
+            .map_err(|e| Error::internal(format!("Failed to write blob: {e}")))?;
+        tokio::fs::write(self.descriptor_path(&descriptor.sha256), descriptor_json)
+            .await
+            .map_err(|e| Error::internal(format!("Failed to write blob descriptor: {e}")))
+    }
+
+    async fn get(&self, sha256: &str) -> Result<Option<(BlobDescriptor, Bytes)>> {
+        let Some(descriptor) = self.descriptor(sha256).await? else {
+            return Ok(None);
+        };
+        match tokio::fs::read(self.blob_path(sha256)).await {
+            Ok(data) => Ok(Some((descriptor, Bytes::from(data)))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::internal(format!("Failed to read blob: {e}"))),
+        }
+    }
+
+    async fn descriptor(&self, sha256: &str) -> Result<Option<BlobDescriptor>> {
+        match tokio::fs::read(self.descriptor_path(sha256)).await {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| Error::internal(format!("Corrupt blob descriptor: {e}"))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::internal(format!(
+                "Failed to read blob descriptor: {e}"
+            ))),
+        }
+    }
+
+    async fn delete(&self, sha256: &str) -> Result<bool> {
+        let existed = tokio::fs::remove_file(self.descriptor_path(sha256))
+            .await
+            .is_ok();
+        let _ = tokio::fs::remove_file(self.blob_path(sha256)).await;
+        Ok(existed)
+    }
+}
+
+/// Blobs stored as objects of an S3 bucket, each next to a `.json` descriptor
+#[cfg(feature = "s3")]
+#[derive(Debug, Clone)]
+pub struct S3BlobStore {
+    store: Arc<object_store::aws::AmazonS3>,
+}
+
+#[cfg(feature = "s3")]
+impl S3BlobStore {
+    /// Store blobs in `bucket`, with credentials and region from the `AWS_*` environment
+    pub fn from_env(bucket: &str) -> Result<Self> {
+        let store = object_store::aws::AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()
+            .map_err(|e| Error::internal(format!("Failed to configure S3: {e}")))?;
+        Ok(Self {
+            store: Arc::new(store),
+        })
+    }
+
+    async fn read(&self, key: &str) -> Result<Option<Bytes>> {
+        use object_store::ObjectStore;
+
+        match self.store.get(&object_store::path::Path::from(key)).await {
+            Ok(object) => object
+                .bytes()
+                .await
+                .map(Some)
+                .map_err(|e| Error::internal(format!("Failed to read {key} from S3: {e}"))),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(Error::internal(format!(
+                "Failed to read {key} from S3: {e}"
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, descriptor: &BlobDescriptor, data: Bytes) -> Result<()> {
+        use object_store::ObjectStore;
+
+        let descriptor_json = serde_json::to_vec(descriptor)
+            .map_err(|e| Error::internal(format!("Failed to encode blob descriptor: {e}")))?;
+        self.store
+            .put(
+                &object_store::path::Path::from(descriptor.sha256.as_str()),
+                data.into(),
+            )
+            .await
+            .map_err(|e| Error::internal(format!("Failed to write blob to S3: {e}")))?;
+        self.store
+            .put(
+                &object_store::path::Path::from(format!("{}.json", descriptor.sha256)),
+                descriptor_json.into(),
+            )
+            .await
+            .map_err(|e| Error::internal(format!("Failed to write blob descriptor to S3: {e}")))?;
+        Ok(())
+    }
+
+    async fn get(&self, sha256: &str) -> Result<Option<(BlobDescriptor, Bytes)>> {
+        let Some(descriptor) = self.descriptor(sha256).await? else {
+            return Ok(None);
+        };
+        Ok(self.read(sha256).await?.map(|data| (descriptor, data)))
+    }
+
+    async fn descriptor(&self, sha256: &str) -> Result<Option<BlobDescriptor>> {
+        match self.read(&format!("{sha256}.json")).await? {
+            Some(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| Error::internal(format!("Corrupt blob descriptor: {e}"))),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, sha256: &str) -> Result<bool> {
+        use object_store::ObjectStore;
+
+        let existed = self.descriptor(sha256).await?.is_some();
+        for key in [sha256.to_string(), format!("{sha256}.json")] {
+            self.store
+                .delete(&object_store::path::Path::from(key))
+                .await
+                .map_err(|e| Error::internal(format!("Failed to delete blob from S3: {e}")))?;
+        }
+        Ok(existed)
+    }
+}
+
+/// Relay components the media server works with, attached when the relay is built
+pub(crate) struct MediaContext {
+    pub(crate) policies: EventPolicyChain,
+    pub(crate) crypto_helper: CryptoHelper,
+    pub(crate) database: Arc<RelayDatabase>,
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+}
+
+/// Blossom media server of a relay
+#[derive(Clone)]
+pub struct MediaServer {
+    base_url: Arc<str>,
+    store: Arc<dyn BlobStore>,
+    max_blob_size: usize,
+    metadata_events: bool,
+    context: Attachment<MediaContext>,
+}
+
+impl std::fmt::Debug for MediaServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MediaServer")
+            .field("base_url", &self.base_url)
+            .field("store", &self.store)
+            .field("max_blob_size", &self.max_blob_size)
+            .field("attached", &self.context.is_attached())
+            .finish()
+    }
+}
+
+/// Error answered as `{"error": "..."}` with an `X-Reason` header (BUD-01)
+#[derive(Debug)]
+struct MediaError(StatusCode, String);
+
+impl MediaError {
+    fn internal(error: Error) -> Self {
+        error!("Media server error: {}", error);
+        Self(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal error".to_string(),
+        )
+    }
+}
+
+impl IntoResponse for MediaError {
+    fn into_response(self) -> Response {
+        let mut response = (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response();
+        if let Ok(reason) = header::HeaderValue::from_str(&self.1) {
+            response.headers_mut().insert("X-Reason", reason);
+        }
+        response
+    }
+}
+
+impl MediaServer {
+    /// Serve blobs kept in `store` at `base_url`, e.g. `https://cdn.example.com`
+    pub fn new(base_url: impl Into<String>, store: impl BlobStore + 'static) -> Self {
+        Self {
+            base_url: Arc::from(base_url.into().trim_end_matches('/')),
+            store: Arc::new(store),
+            max_blob_size: DEFAULT_MAX_BLOB_SIZE,
+            metadata_events: true,
+            context: Attachment::default(),
+        }
+    }
+
+    /// Refuse blobs larger than `max_blob_size` bytes
+    #[must_use]
+    pub fn with_max_blob_size(mut self, max_blob_size: usize) -> Self {
+        self.max_blob_size = max_blob_size;
+        self
+    }
+
+    /// Whether to publish a NIP-94 file metadata event for each upload, on by default
+    #[must_use]
+    pub fn with_metadata_events(mut self, metadata_events: bool) -> Self {
+        self.metadata_events = metadata_events;
+        self
+    }
+
+    /// Work with the relay built with this server, uploads answer 503 until then
+    pub(crate) fn attach(&self, context: MediaContext) {
+        self.context.attach(context, "Media server");
+    }
+
+    /// The server's routes
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/upload", put(upload))
+            .route("/{blob}", get(get_blob).head(head_blob).delete(delete_blob))
+            .layer(DefaultBodyLimit::max(self.max_blob_size))
+            .with_state(self.clone())
+    }
+
+    fn context(&self) -> Result<&MediaContext, MediaError> {
+        self.context.get().ok_or_else(|| {
+            MediaError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "relay is not running".to_string(),
+            )
+        })
+    }
+
+    /// Pubkey of the Blossom authorization in `headers` for `action` on `sha256`
+    ///
+    /// The authorization event must also pass the relay's event policies.
+    async fn authorize(
+        &self,
+        context: &MediaContext,
+        headers: &HeaderMap,
+        action: &str,
+        sha256: &str,
+    ) -> Result<PublicKey, MediaError> {
+        let auth = parse_authorization(headers)
+            .and_then(|auth| verify_authorization(&auth, action, sha256).map(|()| auth))
+            .map_err(|reason| MediaError(StatusCode::UNAUTHORIZED, reason.to_string()))?;
+
+        match context
+            .policies
+            .check(&auth, &Scope::Default, Some(&auth.pubkey))
+            .await
+        {
+            PolicyDecision::Accept => Ok(auth.pubkey),
+            PolicyDecision::Reject(reason) => {
+                Err(MediaError(StatusCode::FORBIDDEN, reason.to_string()))
+            }
+        }
+    }
+
+    /// Publish the NIP-94 file metadata event of `descriptor`
+    async fn publish_metadata(
+        &self,
+        context: &MediaContext,
+        descriptor: &BlobDescriptor,
+        uploader: PublicKey,
+    ) {
+        let mut tags = vec![
+            Tag::custom(TagKind::custom("url"), [descriptor.url.clone()]),
+            Tag::custom(TagKind::custom("m"), [descriptor.mime_type.clone()]),
+            Tag::custom(TagKind::custom("x"), [descriptor.sha256.clone()]),
+            Tag::custom(TagKind::custom("size"), [descriptor.size.to_string()]),
+        ];
+        tags.push(Tag::public_key(uploader));
+        let unsigned = EventBuilder::new(Kind::FileMetadata, "")
+            .tags(tags)
+            .build(context.crypto_helper.public_key());
+
+        let event = match context.crypto_helper.sign_event(unsigned).await {
+            Ok(event) => event,
+            Err(e) => {
+                error!("Failed to sign file metadata event: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = context.database.save_event(&event, &Scope::Default).await {
+            error!("Failed to save file metadata event {}: {}", event.id, e);
+            return;
+        }
+        context
+            .registry
+            .distribute_event(Arc::new(event), &Scope::Default)
+            .await;
+    }
+}
+
+/// The authorization event in `headers`
+fn parse_authorization(headers: &HeaderMap) -> Result<Event, &'static str> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Nostr "))
+        .ok_or("missing authorization")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .map_err(|_| "authorization is not base64")?;
+    Event::from_json(decoded).map_err(|_| "authorization is not an event")
+}
+
+/// Check that `auth` allows `action` on the blob `sha256` (BUD-01)
+fn verify_authorization(auth: &Event, action: &str, sha256: &str) -> Result<(), &'static str> {
+    if auth.kind != BLOSSOM_AUTH_KIND {
+        return Err("authorization event must be of kind 24242");
+    }
+    if auth.verify().is_err() {
+        return Err("authorization event signature is invalid");
+    }
+    let now = Timestamp::now();
+    if auth.created_at > now {
+        return Err("authorization event is from the future");
+    }
+    let expiration = tag_values(auth, "expiration")
+        .next()
+        .and_then(|value| value.parse::<u64>().ok());
+    if !expiration.is_some_and(|expiration| expiration > now.as_u64()) {
+        return Err("authorization event is expired");
+    }
+    if !tag_values(auth, "t").any(|value| value == action) {
+        return Err("authorization event is for another action");
+    }
+    if !tag_values(auth, "x").any(|value| value.eq_ignore_ascii_case(sha256)) {
+        return Err("authorization event is for another blob");
+    }
+    Ok(())
+}
+
+/// Second values of the `name` tags of `event`
+fn tag_values<'a>(event: &'a Event, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    event
+        .tags
+        .iter()
+        .filter_map(move |tag| match tag.as_slice() {
+            [tag_name, value, ..] if tag_name == name => Some(value.as_str()),
+            _ => None,
+        })
+}
+
+/// Hash of the blob a `/<sha256>[.ext]` path refers to
+fn blob_hash(path: &str) -> Result<String, MediaError> {
+    let hash = path.split_once('.').map_or(path, |(hash, _)| hash);
+    if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Ok(hash.to_ascii_lowercase())
+    } else {
+        Err(MediaError(StatusCode::NOT_FOUND, "not a blob".to_string()))
+    }
+}
+
+async fn upload(
+    State(server): State<MediaServer>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<BlobDescriptor>, MediaError> {
+    let context = server.context()?;
+    let sha256 = sha256::Hash::hash(&body).to_string();
+    let uploader = server
+        .authorize(context, &headers, "upload", &sha256)
+        .await?;
+
+    if let Some(descriptor) = server
+        .store
+        .descriptor(&sha256)
+        .await
+        .map_err(MediaError::internal)?
+    {
+        return Ok(Json(descriptor));
+    }
+
+    let descriptor = BlobDescriptor {
+        url: format!("{}/{}", server.base_url, sha256),
+        sha256,
+        size: body.len() as u64,
+        mime_type: headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string(),
+        uploaded: Timestamp::now().as_u64(),
+        uploader: Some(uploader.to_hex()),
+    };
+    server
+        .store
+        .put(&descriptor, body)
+        .await
+        .map_err(MediaError::internal)?;
+    debug!("Stored blob {} from {}", descriptor.sha256, uploader);
+
+    if server.metadata_events {
+        server
+            .publish_metadata(context, &descriptor, uploader)
+            .await;
+    }
+    Ok(Json(descriptor))
+}
+
+async fn get_blob(
+    State(server): State<MediaServer>,
+    Path(blob): Path<String>,
+) -> Result<Response, MediaError> {
+    let sha256 = blob_hash(&blob)?;
+    let (descriptor, data) = server
+        .store
+        .get(&sha256)
+        .await
+        .map_err(MediaError::internal)?
+        .ok_or_else(|| MediaError(StatusCode::NOT_FOUND, "blob not found".to_string()))?;
+
+    Ok((
+        [(header::CONTENT_TYPE, descriptor.mime_type)],
+        Body::from(data),
+    )
+        .into_response())
+}
+
+async fn head_blob(
+    State(server): State<MediaServer>,
+    Path(blob): Path<String>,
+) -> Result<Response, MediaError> {
+    let sha256 = blob_hash(&blob)?;
+    let descriptor = server
+        .store
+        .descriptor(&sha256)
+        .await
+        .map_err(MediaError::internal)?
+        .ok_or_else(|| MediaError(StatusCode::NOT_FOUND, "blob not found".to_string()))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, descriptor.mime_type),
+            (header::CONTENT_LENGTH, descriptor.size.to_string()),
+        ],
+        Body::empty(),
+    )
+        .into_response())
+}
+
+async fn delete_blob(
+    State(server): State<MediaServer>,
+    Path(blob): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, MediaError> {
+    let context = server.context()?;
+    let sha256 = blob_hash(&blob)?;
+    let pubkey = server
+        .authorize(context, &headers, "delete", &sha256)
+        .await?;
+
+    let descriptor = server
+        .store
+        .descriptor(&sha256)
+        .await
+        .map_err(MediaError::internal)?
+        .ok_or_else(|| MediaError(StatusCode::NOT_FOUND, "blob not found".to_string()))?;
+    if descriptor.uploader.as_deref() != Some(pubkey.to_hex().as_str()) {
+        return Err(MediaError(
+            StatusCode::FORBIDDEN,
+            "only the uploader may delete a blob".to_string(),
+        ));
+    }
+
+    server
+        .store
+        .delete(&sha256)
+        .await
+        .map_err(MediaError::internal)?;
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+    use tower::ServiceExt;
+
+    fn authorization(keys: &Keys, action: &str, sha256: &str) -> String {
+        let expiration = Timestamp::now().as_u64() + 60;
+        let auth = EventBuilder::new(BLOSSOM_AUTH_KIND, "")
+            .tags([
+                Tag::custom(TagKind::custom("t"), [action]),
+                Tag::custom(TagKind::custom("x"), [sha256]),
+                Tag::custom(TagKind::custom("expiration"), [expiration.to_string()]),
+            ])
+            .sign_with_keys(keys)
+            .unwrap();
+        format!(
+            "Nostr {}",
+            base64::engine::general_purpose::STANDARD.encode(auth.as_json())
+        )
+    }
+
+    #[tokio::test]
+    async fn test_upload_get_and_delete() {
+        let (tmp_dir, database, keys) = setup_test_with_database().await;
+        let store = DiskBlobStore::new(tmp_dir.path().join("media")).unwrap();
+        let server = MediaServer::new("https://cdn.example.com", store);
+        server.attach(MediaContext {
+            policies: EventPolicyChain::new(),
+            crypto_helper: CryptoHelper::new(Arc::new(Keys::generate())),
+            database: database.clone(),
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+        });
+
+        let data = b"hello blossom".to_vec();
+        let sha256 = sha256::Hash::hash(&data).to_string();
+        let upload = |auth: Option<String>| {
+            let mut request = axum::http::Request::builder()
+                .method("PUT")
+                .uri("/upload")
+                .header(header::CONTENT_TYPE, "text/plain");
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            request.body(Body::from(data.clone())).unwrap()
+        };
+
+        let response = server.router().oneshot(upload(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let wrong_blob = authorization(&keys, "upload", &"0".repeat(64));
+        let response = server
+            .router()
+            .oneshot(upload(Some(wrong_blob)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = server
+            .router()
+            .oneshot(upload(Some(authorization(&keys, "upload", &sha256))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let descriptor: BlobDescriptor = serde_json::from_slice(&body).unwrap();
+        assert_eq!(descriptor.url, format!("https://cdn.example.com/{sha256}"));
+        assert_eq!(descriptor.size, data.len() as u64);
+
+        let response = server
+            .router()
+            .oneshot(
+                axum::http::Request::builder()
+                    .uri(format!("/{sha256}.txt"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body.as_ref(), data.as_slice());
+
+        // The file metadata event is published
+        let metadata = database
+            .query(
+                vec![Filter::new().kind(Kind::FileMetadata)],
+                &Scope::Default,
+            )
+            .await
+            .unwrap();
+        assert_eq!(metadata.len(), 1);
+
+        // Only the uploader may delete the blob
+        for (keys, status) in [
+            (Keys::generate(), StatusCode::FORBIDDEN),
+            (keys, StatusCode::OK),
+        ] {
+            let response = server
+                .router()
+                .oneshot(
+                    axum::http::Request::builder()
+                        .method("DELETE")
+                        .uri(format!("/{sha256}"))
+                        .header(
+                            header::AUTHORIZATION,
+                            authorization(&keys, "delete", &sha256),
+                        )
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+        assert!(server.store.descriptor(&sha256).await.unwrap().is_none());
+    }
+}
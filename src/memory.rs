@@ -0,0 +1,162 @@
+//! Approximate memory accounting of subscription state
+//!
+//! The [`SubscriptionRegistry`](crate::SubscriptionRegistry) keeps the filters
+//! of every open subscription and, under
+//! [`SlowConsumerPolicy::DropOldest`](crate::SlowConsumerPolicy::DropOldest),
+//! the events queued for slow connections. A single REQ with thousands of ids
+//! or authors holds far more memory than a typical one, so the registry
+//! estimates the size of both and refuses subscriptions that would take the
+//! total over its limit, see
+//! [`SubscriptionRegistry::with_memory_limit`](crate::SubscriptionRegistry::with_memory_limit).
+//!
+//! Sizes are estimates of the heap and inline size of the values, not
+//! allocator measurements; they are meant to compare against a limit, not to
+//! match the process RSS.
+
+use crate::error::{Error, Result};
+use nostr_sdk::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bookkeeping of each entry of a set or map, on top of the value itself
+const ENTRY_OVERHEAD: usize = 16;
+
+/// Approximate bytes held by `filter`
+pub fn filter_size(filter: &Filter) -> usize {
+    let set = |len: usize, item: usize| len * (item + ENTRY_OVERHEAD);
+
+    let mut size = std::mem::size_of::<Filter>();
+    size += filter.ids.as_ref().map_or(0, |ids| set(ids.len(), 32));
+    size += filter
+        .authors
+        .as_ref()
+        .map_or(0, |authors| set(authors.len(), 32));
+    size += filter.kinds.as_ref().map_or(0, |kinds| set(kinds.len(), 2));
+    size += filter.search.as_ref().map_or(0, String::len);
+    for values in filter.generic_tags.values() {
+        size += ENTRY_OVERHEAD;
+        size += values
+            .iter()
+            .map(|value| value.len() + std::mem::size_of::<String>() + ENTRY_OVERHEAD)
+            .sum::<usize>();
+    }
+    size
+}
+
+/// Approximate bytes held by the filters of one subscription
+pub fn filters_size(filters: &[Filter]) -> usize {
+    filters.iter().map(filter_size).sum()
+}
+
+/// Approximate bytes held by `event`
+pub fn event_size(event: &Event) -> usize {
+    let tags: usize = event
+        .tags
+        .iter()
+        .map(|tag| {
+            tag.as_slice()
+                .iter()
+                .map(|value| value.len() + std::mem::size_of::<String>())
+                .sum::<usize>()
+        })
+        .sum();
+    std::mem::size_of::<Event>() + event.content.len() + tags
+}
+
+/// Estimated memory of the subscription state of a registry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes held by the filters of open subscriptions
+    pub filters: usize,
+    /// Bytes held by events queued for slow connections
+    pub pending_events: usize,
+    /// Limit of the total, if any
+    pub limit: Option<usize>,
+}
+
+impl MemoryUsage {
+    /// Bytes held by filters and queued events
+    pub fn total(&self) -> usize {
+        self.filters + self.pending_events
+    }
+}
+
+/// Running totals shared by a registry and its connections
+#[derive(Debug, Default)]
+pub(crate) struct MemoryAccount {
+    filters: AtomicUsize,
+    pending_events: AtomicUsize,
+    limit: Option<usize>,
+}
+
+impl MemoryAccount {
+    pub(crate) fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            filters: self.filters.load(Ordering::Relaxed),
+            pending_events: self.pending_events.load(Ordering::Relaxed),
+            limit: self.limit,
+        }
+    }
+
+    /// Refuse `bytes` more of filters if they would exceed the limit
+    pub(crate) fn check(&self, bytes: usize) -> Result<()> {
+        match self.limit {
+            Some(limit) if self.usage().total() + bytes > limit => Err(Error::rate_limited(
+                "relay subscription memory is full, try again later",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn add_filters(&self, bytes: usize) {
+        self.filters.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove_filters(&self, bytes: usize) {
+        self.filters.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_pending(&self, bytes: usize) {
+        self.pending_events.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove_pending(&self, bytes: usize) {
+        self.pending_events.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_size_grows_with_ids() {
+        let keys = Keys::generate();
+        let ids: Vec<EventId> = (0..1000)
+            .map(|index| {
+                EventBuilder::text_note(index.to_string())
+                    .sign_with_keys(&keys)
+                    .unwrap()
+                    .id
+            })
+            .collect();
+
+        let small = filter_size(&Filter::new().kind(Kind::TextNote));
+        let large = filter_size(&Filter::new().ids(ids));
+        assert!(large >= small + 1000 * 32);
+
+        let account = MemoryAccount::new(Some(large));
+        account.add_filters(small);
+        assert!(account.check(small).is_ok());
+        assert!(account.check(large).is_err());
+        account.remove_filters(small);
+        assert!(account.check(large).is_ok());
+        assert_eq!(account.usage().total(), 0);
+    }
+}
@@ -1,12 +1,225 @@
 //! Message conversion utilities
+//!
+//! Messages are JSON by default. A binary [`MessageCodec`] such as
+//! [`CborCodec`] (`cbor` feature) can be added with
+//! [`RelayBuilder::with_codec`](crate::RelayBuilder::with_codec) for closed
+//! ecosystems controlling both ends: clients ask for it by offering its
+//! [`MessageCodec::subprotocol`] in `Sec-WebSocket-Protocol`, and frames
+//! starting with a CBOR array header are decoded with it. JSON frames are
+//! still accepted on every connection.
+//!
+//! Relay messages are still written as JSON text frames, the only frames
+//! `websocket_builder` converters produce; [`MessageCodec::encode`] is there
+//! for transports writing binary frames.
 
 use anyhow::Result;
+use bytes::Bytes;
 use nostr_sdk::prelude::*;
+use std::sync::Arc;
 use websocket_builder::MessageConverter;
 
+/// Encoding of Nostr messages on the wire
+pub trait MessageCodec: Send + Sync + std::fmt::Debug {
+    /// WebSocket subprotocol clients offer to use this codec
+    fn subprotocol(&self) -> &'static str;
+
+    /// Decode a client message
+    fn decode(&self, bytes: &[u8]) -> Result<ClientMessage<'static>>;
+
+    /// Encode a relay message
+    fn encode(&self, message: &RelayMessage<'_>) -> Result<Vec<u8>>;
+}
+
+/// The NIP-01 JSON encoding
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl MessageCodec for JsonCodec {
+    fn subprotocol(&self) -> &'static str {
+        "nostr.json"
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ClientMessage<'static>> {
+        ClientMessage::from_json(bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to parse client message: {}", e))
+    }
+
+    fn encode(&self, message: &RelayMessage<'_>) -> Result<Vec<u8>> {
+        Ok(message.as_json().into_bytes())
+    }
+}
+
+/// The NIP-01 message arrays encoded as CBOR instead of JSON
+///
+/// Events keep their JSON field names and hex strings, so ids and signatures
+/// are checked exactly as for JSON messages.
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl MessageCodec for CborCodec {
+    fn subprotocol(&self) -> &'static str {
+        "nostr.cbor"
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ClientMessage<'static>> {
+        let value: serde_json::Value = ciborium::from_reader(bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to parse CBOR client message: {}", e))?;
+        ClientMessage::from_json(value.to_string())
+            .map_err(|e| anyhow::anyhow!("Failed to parse client message: {}", e))
+    }
+
+    fn encode(&self, message: &RelayMessage<'_>) -> Result<Vec<u8>> {
+        let value: serde_json::Value = serde_json::from_str(&message.as_json())?;
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to encode CBOR relay message: {}", e))?;
+        Ok(bytes)
+    }
+}
+
+/// What the relay does with client messages it cannot parse
+///
+/// Set with [`RelayConfig::with_parse_error_policy`](crate::config::RelayConfig::with_parse_error_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseErrorPolicy {
+    /// Fail the message, which drops the connection
+    #[default]
+    Disconnect,
+    /// Answer each malformed message with a NOTICE describing the error, and
+    /// drop the connection on the `max_strikes`-th one
+    Notice { max_strikes: u32 },
+}
+
+/// Subscription id prefix of the placeholder standing for an unparsable message
+const PARSE_ERROR_PREFIX: &str = "\0parse-error:";
+
+/// Placeholder handed to the middleware chain for a message failing with `error`
+///
+/// The converter has no access to the connection, so the error travels as a
+/// CLOSE of a subscription id no client uses, answered by `ParseErrorMiddleware`.
+fn parse_error_placeholder(error: impl std::fmt::Display) -> ClientMessage<'static> {
+    ClientMessage::close(SubscriptionId::new(format!("{PARSE_ERROR_PREFIX}{error}")))
+}
+
+/// Parse error carried by a placeholder from [`NostrMessageConverter`]
+pub(crate) fn parse_error<'m>(message: &'m ClientMessage<'_>) -> Option<&'m str> {
+    match message {
+        ClientMessage::Close(subscription_id) => {
+            subscription_id.as_str().strip_prefix(PARSE_ERROR_PREFIX)
+        }
+        _ => None,
+    }
+}
+
+/// Subscription id prefix of the placeholder standing for an approximate count
+const APPROXIMATE_COUNT_PREFIX: &str = "\0approximate:";
+
+/// COUNT answer flagged as approximate
+///
+/// `RelayMessage::Count` has no `approximate` field, so the flag travels in the
+/// subscription id and the converter writes it out.
+pub(crate) fn approximate_count(
+    subscription_id: &SubscriptionId,
+    count: usize,
+) -> RelayMessage<'static> {
+    RelayMessage::count(
+        SubscriptionId::new(format!("{APPROXIMATE_COUNT_PREFIX}{subscription_id}")),
+        count,
+    )
+}
+
+/// NIP-45 frame of a count built by [`approximate_count`]
+fn approximate_count_json(message: &RelayMessage<'_>) -> Option<String> {
+    let RelayMessage::Count {
+        subscription_id,
+        count,
+    } = message
+    else {
+        return None;
+    };
+    let subscription_id = subscription_id
+        .as_str()
+        .strip_prefix(APPROXIMATE_COUNT_PREFIX)?;
+    Some(
+        serde_json::json!(["COUNT", subscription_id, {"count": count, "approximate": true}])
+            .to_string(),
+    )
+}
+
+/// Whether `bytes` starts with a CBOR array header, which no JSON text does
+fn is_cbor_array(bytes: &[u8]) -> bool {
+    bytes.first().is_some_and(|byte| byte >> 5 == 4)
+}
+
 /// Message converter for Nostr protocol messages
-#[derive(Clone, Debug)]
-pub struct NostrMessageConverter;
+#[derive(Clone, Debug, Default)]
+pub struct NostrMessageConverter {
+    /// Codec of binary frames, `None` to only accept JSON
+    binary_codec: Option<Arc<dyn MessageCodec>>,
+    parse_error_policy: ParseErrorPolicy,
+    /// Largest message parsed, in bytes
+    max_message_size: Option<usize>,
+}
+
+impl NostrMessageConverter {
+    /// Also accept messages encoded with `codec`
+    #[must_use]
+    pub fn with_codec(mut self, codec: Arc<dyn MessageCodec>) -> Self {
+        self.binary_codec = Some(codec);
+        self
+    }
+
+    /// Handle unparsable messages according to `policy`
+    #[must_use]
+    pub fn with_parse_error_policy(mut self, policy: ParseErrorPolicy) -> Self {
+        self.parse_error_policy = policy;
+        self
+    }
+
+    /// Answer messages larger than `bytes` with a NOTICE without parsing them
+    #[must_use]
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = Some(bytes);
+        self
+    }
+
+    /// Error for an unparsable message, or its placeholder when answered with a NOTICE
+    fn parse_failure<'a>(&self, error: anyhow::Error) -> Result<Option<ClientMessage<'a>>> {
+        match self.parse_error_policy {
+            ParseErrorPolicy::Disconnect => Err(error),
+            ParseErrorPolicy::Notice { .. } => Ok(Some(parse_error_placeholder(error))),
+        }
+    }
+
+    /// Codec accepted besides JSON
+    pub fn codec(&self) -> Option<&Arc<dyn MessageCodec>> {
+        self.binary_codec.as_ref()
+    }
+
+    /// JSON frame of `message` for transports writing [`Bytes`]
+    ///
+    /// Unlike `outbound_to_string`, which allocates a frame per connection,
+    /// broadcast events reuse the frame built for the first connection with
+    /// the same subscription id.
+    pub fn outbound_to_bytes(&self, message: RelayMessage<'_>) -> Result<Bytes> {
+        if let RelayMessage::Event {
+            subscription_id,
+            event,
+        } = &message
+        {
+            if let Some(serialized) = crate::broadcast::cached_event(&event.id) {
+                return Ok(serialized.frame_bytes(subscription_id));
+            }
+        }
+        if let Some(json) = approximate_count_json(&message) {
+            return Ok(Bytes::from(json));
+        }
+
+        Ok(Bytes::from(message.as_json()))
+    }
+}
 
 impl<'a> MessageConverter<ClientMessage<'a>, RelayMessage<'a>> for NostrMessageConverter {
     fn inbound_from_bytes(&self, bytes: &[u8]) -> Result<Option<ClientMessage<'a>>> {
@@ -14,6 +227,30 @@ impl<'a> MessageConverter<ClientMessage<'a>, RelayMessage<'a>> for NostrMessageC
             return Ok(None);
         }
 
+        // Oversized messages are answered whatever the parse error policy
+        if let Some(max) = self.max_message_size.filter(|max| bytes.len() > *max) {
+            tracing::debug!("Refused client message of {} bytes", bytes.len());
+            return Ok(Some(parse_error_placeholder(format!(
+                "message of {} bytes exceeds the {} bytes limit",
+                bytes.len(),
+                max
+            ))));
+        }
+
+        if let Some(codec) = self.binary_codec.as_ref().filter(|_| is_cbor_array(bytes)) {
+            return match codec.decode(bytes) {
+                Ok(message) => Ok(Some(message)),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to decode {} client message: {}",
+                        codec.subprotocol(),
+                        e
+                    );
+                    self.parse_failure(e)
+                }
+            };
+        }
+
         match ClientMessage::from_json(bytes) {
             Ok(sdk_msg) => Ok(Some(sdk_msg)),
             Err(e) => {
@@ -21,21 +258,106 @@ impl<'a> MessageConverter<ClientMessage<'a>, RelayMessage<'a>> for NostrMessageC
                     Ok(s) => s,
                     Err(e) => {
                         tracing::warn!("Invalid UTF-8 in client message: {}", e);
-                        return Ok(None);
+                        return match self.parse_error_policy {
+                            ParseErrorPolicy::Disconnect => Ok(None),
+                            ParseErrorPolicy::Notice { .. } => {
+                                Ok(Some(parse_error_placeholder("message is not UTF-8")))
+                            }
+                        };
                     }
                 };
 
                 tracing::warn!("Failed to parse client message: {}, error: {}", message, e);
-                Err(anyhow::anyhow!("Failed to parse client message: {}", e))
+                self.parse_failure(anyhow::anyhow!("Failed to parse client message: {}", e))
             }
         }
     }
 
     fn outbound_to_string(&self, message: RelayMessage<'a>) -> Result<String> {
+        // Events being broadcast were serialized once during distribution
+        if let RelayMessage::Event {
+            subscription_id,
+            event,
+        } = &message
+        {
+            if let Some(json) = crate::broadcast::cached_event_json(&event.id) {
+                return Ok(crate::broadcast::event_frame(subscription_id, &json));
+            }
+        }
+        if let Some(json) = approximate_count_json(&message) {
+            return Ok(json);
+        }
+
         Ok(message.as_json())
     }
 }
 
+/// Parse `bytes` as a connection would, then normalize the message's filters
+///
+/// Entry point of the fuzz targets in `fuzz/`: every input must come back as
+/// a message, `Ok(None)` for frames that are ignored, or the reason it is
+/// refused, and never panic. Unparsable frames go through the NOTICE
+/// [`ParseErrorPolicy`], CBOR frames are decoded when the `cbor` feature is
+/// enabled, and the filters of REQ and COUNT messages are checked with the
+/// default [`FilterValidation`](crate::FilterValidation).
+#[doc(hidden)]
+pub fn parse_client_message_lenient(
+    bytes: &[u8],
+) -> std::result::Result<Option<ClientMessage<'static>>, String> {
+    let converter =
+        NostrMessageConverter::default().with_parse_error_policy(ParseErrorPolicy::Notice {
+            max_strikes: u32::MAX,
+        });
+    #[cfg(feature = "cbor")]
+    let converter = converter.with_codec(Arc::new(CborCodec));
+
+    let parsed: Option<ClientMessage<'static>> = converter
+        .inbound_from_bytes(bytes)
+        .map_err(|e| e.to_string())?;
+    let Some(message) = parsed else {
+        return Ok(None);
+    };
+    if let Some(error) = parse_error(&message) {
+        return Err(error.to_string());
+    }
+
+    let validation = crate::filter_validation::FilterValidation::new();
+    let normalize =
+        |filters: Vec<Filter>| validation.validate(filters).map_err(|e| e.client_message());
+    let normalize_one = |filter: std::borrow::Cow<'static, Filter>| {
+        normalize(vec![filter.into_owned()])?
+            .pop()
+            .map(std::borrow::Cow::Owned)
+            .ok_or_else(|| "filter validation dropped the filter".to_string())
+    };
+
+    let message = match message {
+        ClientMessage::Req {
+            subscription_id,
+            filter,
+        } => ClientMessage::Req {
+            subscription_id,
+            filter: normalize_one(filter)?,
+        },
+        ClientMessage::ReqMultiFilter {
+            subscription_id,
+            filters,
+        } => ClientMessage::ReqMultiFilter {
+            subscription_id,
+            filters: normalize(filters)?,
+        },
+        ClientMessage::Count {
+            subscription_id,
+            filter,
+        } => ClientMessage::Count {
+            subscription_id,
+            filter: normalize_one(filter)?,
+        },
+        message => message,
+    };
+    Ok(Some(message))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -43,7 +365,7 @@ mod tests {
 
     #[test]
     fn test_inbound_from_bytes_valid_messages() {
-        let converter = NostrMessageConverter;
+        let converter = NostrMessageConverter::default();
 
         // Test EVENT message
         let keys = Keys::generate();
@@ -89,7 +411,7 @@ mod tests {
 
     #[test]
     fn test_inbound_from_bytes_empty_message() {
-        let converter = NostrMessageConverter;
+        let converter = NostrMessageConverter::default();
 
         // Test empty bytes
         let result = converter.inbound_from_bytes(&[]).unwrap();
@@ -106,7 +428,7 @@ mod tests {
 
     #[test]
     fn test_inbound_from_bytes_invalid_json() {
-        let converter = NostrMessageConverter;
+        let converter = NostrMessageConverter::default();
 
         // Test invalid JSON
         let result = converter.inbound_from_bytes(b"not json");
@@ -132,7 +454,7 @@ mod tests {
 
     #[test]
     fn test_auth_message() {
-        let converter = NostrMessageConverter;
+        let converter = NostrMessageConverter::default();
 
         // Test AUTH message
         let keys = Keys::generate();
@@ -153,7 +475,7 @@ mod tests {
 
     #[test]
     fn test_outbound_to_string() {
-        let converter = NostrMessageConverter;
+        let converter = NostrMessageConverter::default();
 
         // Test with NOTICE message
         let notice = RelayMessage::notice("Test notice");
@@ -184,4 +506,147 @@ mod tests {
         assert!(result.contains("true"));
         assert!(result.contains("saved"));
     }
+
+    #[test]
+    fn test_parse_error_notice_policy() {
+        let converter = NostrMessageConverter::default()
+            .with_parse_error_policy(ParseErrorPolicy::Notice { max_strikes: 3 });
+
+        let message = converter.inbound_from_bytes(b"not json").unwrap().unwrap();
+        assert!(parse_error(&message)
+            .unwrap()
+            .contains("Failed to parse client message"));
+        let message = converter
+            .inbound_from_bytes(&[0xFF, 0xFE])
+            .unwrap()
+            .unwrap();
+        assert_eq!(parse_error(&message), Some("message is not UTF-8"));
+
+        let close = converter
+            .inbound_from_bytes(br#"["CLOSE", "sub1"]"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(parse_error(&close), None);
+    }
+
+    #[test]
+    fn test_oversized_message_is_not_parsed() {
+        let converter = NostrMessageConverter::default().with_max_message_size(16);
+
+        let message = converter
+            .inbound_from_bytes(br#"["REQ", "sub1", {"kinds": [1]}]"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            parse_error(&message),
+            Some("message of 31 bytes exceeds the 16 bytes limit")
+        );
+
+        let close = converter.inbound_from_bytes(br#"["CLOSE","s"]"#).unwrap();
+        assert!(matches!(close, Some(ClientMessage::Close(_))));
+    }
+
+    #[test]
+    fn test_outbound_to_bytes() {
+        let converter = NostrMessageConverter::default();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("Hello")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let subscription_id = SubscriptionId::new("feed");
+
+        // Not broadcast yet, framed from scratch
+        let message = RelayMessage::event(subscription_id.clone(), event.clone());
+        let expected = converter.outbound_to_string(message.clone()).unwrap();
+        assert_eq!(converter.outbound_to_bytes(message).unwrap(), expected);
+
+        // Broadcast events share their frame
+        crate::broadcast::SerializedEvent::new(&event);
+        let first = converter
+            .outbound_to_bytes(RelayMessage::event(subscription_id.clone(), event.clone()))
+            .unwrap();
+        let second = converter
+            .outbound_to_bytes(RelayMessage::event(subscription_id, event))
+            .unwrap();
+        assert_eq!(first.as_ptr(), second.as_ptr());
+
+        let eose = RelayMessage::eose(SubscriptionId::new("sub1"));
+        assert_eq!(
+            converter.outbound_to_bytes(eose.clone()).unwrap(),
+            eose.as_json()
+        );
+    }
+
+    #[test]
+    fn test_approximate_count() {
+        let converter = NostrMessageConverter::default();
+        let subscription_id = SubscriptionId::new("count1");
+
+        let json = converter
+            .outbound_to_string(approximate_count(&subscription_id, 42))
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!(["COUNT", "count1", {"count": 42, "approximate": true}])
+        );
+
+        let exact = converter
+            .outbound_to_string(RelayMessage::count(subscription_id, 42))
+            .unwrap();
+        assert!(!exact.contains("approximate"));
+    }
+
+    #[test]
+    fn test_lenient_parsing_never_fails_the_frame() {
+        for bytes in [
+            &b"["[..],
+            b"[\"REQ\"]",
+            b"[\"EVENT\", {\"id\": 1}]",
+            b"\xff\xfe",
+            b"null",
+        ] {
+            assert!(parse_client_message_lenient(bytes).is_err());
+        }
+        assert_eq!(parse_client_message_lenient(b""), Ok(None));
+
+        let refused = parse_client_message_lenient(br#"["REQ", "s", {"since": 20, "until": 10}]"#);
+        assert_eq!(
+            refused,
+            Err("invalid: filter 0: since is after until".to_string())
+        );
+
+        match parse_client_message_lenient(br#"["REQ", "s", {"authors": [], "kinds": [1]}]"#) {
+            Ok(Some(ClientMessage::Req { filter, .. })) => {
+                assert_eq!(filter.as_ref(), &Filter::new().kind(Kind::TextNote))
+            }
+            other => panic!("Expected REQ message, got {other:?}"),
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_messages() {
+        let converter = NostrMessageConverter::default().with_codec(Arc::new(CborCodec));
+
+        let req = serde_json::json!(["REQ", "sub1", {"kinds": [1]}]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&req, &mut bytes).unwrap();
+        match converter.inbound_from_bytes(&bytes).unwrap() {
+            Some(ClientMessage::Req {
+                subscription_id, ..
+            }) => assert_eq!(subscription_id.as_str(), "sub1"),
+            _ => panic!("Expected REQ message"),
+        }
+
+        // JSON is still accepted
+        let result = converter
+            .inbound_from_bytes(br#"["CLOSE", "sub1"]"#)
+            .unwrap();
+        assert!(matches!(result, Some(ClientMessage::Close(_))));
+
+        let notice = CborCodec.encode(&RelayMessage::notice("hi")).unwrap();
+        let value: serde_json::Value = ciborium::from_reader(notice.as_slice()).unwrap();
+        assert_eq!(value, serde_json::json!(["NOTICE", "hi"]));
+    }
 }
@@ -3,6 +3,24 @@
 //! This module provides trait interfaces that allow the relay to report metrics
 //! without depending on a specific metrics implementation.
 
+use crate::memory::MemoryUsage;
+use std::time::Duration;
+
+/// Measurements of serving the stored events of one REQ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReqStats {
+    /// Time from the first database query to EOSE
+    pub time_to_eose: Duration,
+    /// Events sent before EOSE
+    pub events_sent: usize,
+    /// Pagination windows queried, summed over the filters
+    pub windows_scanned: usize,
+    /// Events returned by the database
+    pub events_scanned: usize,
+    /// Scanned events hidden from the client by the visibility filter
+    pub events_filtered: usize,
+}
+
 /// Trait for handling subscription metrics
 pub trait SubscriptionMetricsHandler: Send + Sync + std::fmt::Debug {
     /// Called when a subscription is added
@@ -10,6 +28,13 @@ pub trait SubscriptionMetricsHandler: Send + Sync + std::fmt::Debug {
 
     /// Called when subscriptions are removed
     fn decrement_active_subscriptions(&self, count: usize);
+
+    /// Called when the reaper removed dead or idle connections
+    fn record_reaped_connections(&self, _dead: usize, _idle: usize) {}
+
+    /// Called with the estimated subscription memory when a subscription is
+    /// added and after each reap
+    fn record_subscription_memory(&self, _usage: &MemoryUsage) {}
 }
 
 /// Trait for handling event processing metrics
@@ -18,6 +43,52 @@ pub trait EventProcessingMetricsHandler: Send + Sync + std::fmt::Debug {
     fn increment_inbound_events_processed(&self);
 }
 
+/// Trait for metrics of the ingest, query and distribution paths
+///
+/// Every method has a no-op default, implement the ones you export.
+pub trait RelayMetricsHandler: Send + Sync + std::fmt::Debug {
+    /// Called when a client event was saved
+    fn record_event_saved(&self, _kind: u16) {}
+
+    /// Called when a client event was not saved; `reason` is the machine-readable
+    /// prefix sent to the client, e.g. `duplicate`, `blocked` or `error`
+    fn record_event_rejected(&self, _kind: u16, _reason: &str) {}
+
+    /// Called after a database query with the number of filters it ran
+    fn record_query_duration(&self, _duration: Duration, _filters: usize) {}
+
+    /// Called once a REQ filter was served with the number of pages it took
+    fn record_pagination_attempts(&self, _attempts: usize) {}
+
+    /// Called once the stored events of a REQ were sent, after EOSE
+    fn record_req(&self, _stats: &ReqStats) {}
+
+    /// Called after an event was distributed with the number of matched subscriptions
+    fn record_distribution_matches(&self, _matches: usize) {}
+
+    /// Called when an event could not be handed to a connection during distribution
+    fn increment_send_failures(&self) {}
+
+    /// Called when the replaceable events buffer flushed this many entries
+    fn record_buffer_flush(&self, _entries: usize) {}
+
+    /// Called after a client event went through an ingest `stage`, e.g.
+    /// `validate`, `verify` or `persist`, see [`crate::ingest::IngestStage`]
+    fn record_ingest_stage(&self, _stage: &str, _duration: Duration) {}
+
+    /// Called after a built-in sink, e.g. `kafka` or `nats`, published a batch
+    /// of stored events
+    fn record_sink_delivery(&self, _sink: &str, _delivered: usize, _failed: usize) {}
+
+    /// Called when a connection was refused by [`crate::connection_limits::ConnectionLimits`];
+    /// `limit` is `per_ip` or `total`
+    fn record_connection_rejected(&self, _limit: &str) {}
+
+    /// Called when a task run by the [`crate::supervisor::TaskSupervisor`]
+    /// panicked, before it is restarted
+    fn record_task_panic(&self, _task: &str) {}
+}
+
 /// A no-op implementation for when metrics are not needed
 #[derive(Debug, Clone, Default)]
 pub struct NoOpMetricsHandler;
@@ -30,3 +101,5 @@ impl SubscriptionMetricsHandler for NoOpMetricsHandler {
 impl EventProcessingMetricsHandler for NoOpMetricsHandler {
     fn increment_inbound_events_processed(&self) {}
 }
+
+impl RelayMetricsHandler for NoOpMetricsHandler {}
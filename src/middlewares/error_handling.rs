@@ -1,21 +1,14 @@
 //! Error handling middleware
 
+pub use crate::error::ClientMessageId;
 use crate::error::Error;
 use crate::state::NostrConnectionState;
 use anyhow::Result;
 use async_trait::async_trait;
 use nostr_sdk::prelude::*;
-use std::borrow::Cow;
 use tracing::error;
 use websocket_builder::{InboundContext, Middleware, OutboundContext, SendMessage};
 
-/// Message ID for error handling
-#[derive(Debug, Clone)]
-pub enum ClientMessageId {
-    Event(EventId),
-    Subscription(String),
-}
-
 /// Middleware for handling errors in the message processing chain
 #[derive(Debug)]
 pub struct ErrorHandlingMiddleware<T = ()> {
@@ -57,6 +50,9 @@ impl<T: Clone + Send + Sync + std::fmt::Debug + 'static> Middleware for ErrorHan
             Some(ClientMessage::Close(subscription_id)) => {
                 ClientMessageId::Subscription(subscription_id.to_string())
             }
+            Some(ClientMessage::Count {
+                subscription_id, ..
+            }) => ClientMessageId::Subscription(subscription_id.to_string()),
             Some(ClientMessage::Auth(auth)) => ClientMessageId::Event(auth.id),
             Some(ClientMessage::NegOpen {
                 subscription_id, ..
@@ -97,6 +93,9 @@ impl<T: Clone + Send + Sync + std::fmt::Debug + 'static> Middleware for ErrorHan
 }
 
 /// Handle inbound errors by sending appropriate relay messages
+///
+/// Internal errors are logged in full; the client only learns that the
+/// request failed.
 async fn handle_inbound_error<T: Clone + Send + Sync + std::fmt::Debug + 'static>(
     error: &Error,
     ctx: &mut InboundContext<
@@ -106,75 +105,21 @@ async fn handle_inbound_error<T: Clone + Send + Sync + std::fmt::Debug + 'static
     >,
     client_message_id: ClientMessageId,
 ) -> Result<(), anyhow::Error> {
-    use Error::*;
-
-    let message = match error {
-        Notice { message, .. } => RelayMessage::notice(message.clone()),
-        EventError {
-            message, event_id, ..
-        } => RelayMessage::Ok {
-            event_id: *event_id,
-            status: false,
-            message: format!("error: {message}").into(),
-        },
-        SubscriptionError {
-            message,
-            subscription_id,
-            ..
-        } => RelayMessage::Closed {
-            subscription_id: Cow::Owned(SubscriptionId::new(subscription_id)),
-            message: format!("error: {message}").into(),
-        },
-        AuthRequired { message, .. } => {
-            // For auth errors, use the auth-required prefix as per NIP-42
-            match client_message_id {
-                ClientMessageId::Event(event_id) => RelayMessage::Ok {
-                    event_id,
-                    status: false,
-                    message: format!("auth-required: {message}").into(),
-                },
-                ClientMessageId::Subscription(subscription_id) => RelayMessage::Closed {
-                    subscription_id: Cow::Owned(SubscriptionId::new(subscription_id)),
-                    message: format!("auth-required: {message}").into(),
-                },
-            }
-        }
-        Restricted { message, .. } => {
-            // For restricted access errors, use the restricted prefix as per NIP-42
-            match client_message_id {
-                ClientMessageId::Event(event_id) => RelayMessage::Ok {
-                    event_id,
-                    status: false,
-                    message: format!("restricted: {message}").into(),
-                },
-                ClientMessageId::Subscription(subscription_id) => RelayMessage::Closed {
-                    subscription_id: Cow::Owned(SubscriptionId::new(subscription_id)),
-                    message: format!("restricted: {message}").into(),
-                },
-            }
-        }
-        _ => {
-            // For other error types, use generic error prefix
-            match client_message_id {
-                ClientMessageId::Event(event_id) => RelayMessage::Ok {
-                    event_id,
-                    status: false,
-                    message: format!("error: {error}").into(),
-                },
-                ClientMessageId::Subscription(subscription_id) => RelayMessage::Closed {
-                    subscription_id: Cow::Owned(SubscriptionId::new(subscription_id)),
-                    message: format!("error: {error}").into(),
-                },
-            }
-        }
-    };
+    if !error.is_client_error() {
+        error!(
+            "Internal error answering {:?}: {}",
+            client_message_id, error
+        );
+    }
 
-    ctx.send_message(message)
+    ctx.send_message(error.to_relay_message(&client_message_id))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::INTERNAL_ERROR_MESSAGE;
+    use std::borrow::Cow;
 
     #[test]
     fn test_client_message_id_event() {
@@ -345,4 +290,39 @@ mod tests {
         assert!(error_msg.starts_with("error: "));
         assert!(error_msg.contains("Database connection failed"));
     }
+
+    #[test]
+    fn test_errors_to_relay_messages() {
+        let event_id = EventId::all_zeros();
+        let event = ClientMessageId::Event(event_id);
+        let subscription = ClientMessageId::Subscription("sub1".to_string());
+
+        // Internal details never reach the client
+        let database_error = Error::database("mdb_put failed: MDB_MAP_FULL");
+        assert!(!database_error.is_client_error());
+        assert_eq!(
+            database_error.to_relay_message(&event),
+            RelayMessage::ok(event_id, false, INTERNAL_ERROR_MESSAGE)
+        );
+        assert_eq!(
+            Error::channel_closed("EOSE").to_relay_message(&subscription),
+            RelayMessage::closed(SubscriptionId::new("sub1"), INTERNAL_ERROR_MESSAGE)
+        );
+
+        // Client errors carry their reason
+        let invalid = Error::invalid("bad filter");
+        assert!(invalid.is_client_error());
+        assert_eq!(
+            invalid.to_relay_message(&subscription),
+            RelayMessage::closed(SubscriptionId::new("sub1"), "invalid: bad filter")
+        );
+        assert_eq!(
+            Error::rate_limited("slow down").to_relay_message(&event),
+            RelayMessage::ok(event_id, false, "rate-limited: slow down")
+        );
+        assert_eq!(
+            Error::notice("hello").to_relay_message(&event),
+            RelayMessage::notice("hello")
+        );
+    }
 }
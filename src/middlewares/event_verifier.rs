@@ -1,27 +1,52 @@
 //! Event verification middleware
 
+use crate::config::EventLimits;
 use crate::crypto_helper::CryptoHelper;
+use crate::database::RelayDatabase;
+use crate::ingest::IngestPipeline;
 use crate::state::NostrConnectionState;
+use crate::subscription_coordinator::{OkReason, DUPLICATE_EVENT_MESSAGE};
 use anyhow::Result;
 use async_trait::async_trait;
 use nostr_sdk::prelude::*;
 use std::borrow::Cow;
+use std::sync::Arc;
 use websocket_builder::{InboundContext, Middleware, OutboundContext, SendMessage};
 
 /// Middleware that verifies event signatures and basic validity
+///
+/// Runs the validate and verify checks of the [`IngestPipeline`], so refused
+/// events get the same `OK false` reasons as in the coordinator.
 #[derive(Clone, Debug)]
 pub struct EventVerifierMiddleware<T = ()> {
-    crypto_helper: CryptoHelper,
+    pipeline: IngestPipeline,
+    database: Option<Arc<RelayDatabase>>,
     _phantom: std::marker::PhantomData<T>,
 }
 
 impl<T> EventVerifierMiddleware<T> {
     pub fn new(crypto_helper: CryptoHelper) -> Self {
         Self {
-            crypto_helper,
+            pipeline: IngestPipeline::new().with_verification(crypto_helper),
+            database: None,
             _phantom: std::marker::PhantomData,
         }
     }
+
+    /// Refuse events breaking `limits` before verifying their signature
+    #[must_use]
+    pub fn with_limits(mut self, limits: EventLimits) -> Self {
+        self.pipeline = self.pipeline.with_limits(limits);
+        self
+    }
+
+    /// Answer events already stored in `database` with `OK true duplicate:`
+    /// instead of verifying them again
+    #[must_use]
+    pub fn with_duplicate_check(mut self, database: Arc<RelayDatabase>) -> Self {
+        self.database = Some(database);
+        self
+    }
 }
 
 #[async_trait]
@@ -36,20 +61,27 @@ impl<T: Clone + Send + Sync + std::fmt::Debug + 'static> Middleware for EventVer
     ) -> Result<(), anyhow::Error> {
         if let Some(ClientMessage::Event(event_cow)) = &ctx.message {
             let event_id = event_cow.id;
-            let event_to_verify: Event = event_cow.as_ref().clone();
-
-            // Verify the event asynchronously
-            let verification_failed = match self.crypto_helper.verify_event(event_to_verify).await {
-                Ok(()) => false,
-                Err(_) => true,
-            };
-
-            if verification_failed {
-                ctx.send_message(RelayMessage::ok(
-                    event_id,
-                    false,
-                    Cow::Borrowed("invalid: event signature verification failed"),
-                ))?;
+
+            // Cheap structural checks first, so oversized events never reach the verifier
+            let scope = Arc::clone(&ctx.state.read().subdomain);
+            if let Err(reason) = self.pipeline.validate(event_cow, &scope) {
+                ctx.send_message(OkReason::from(reason).to_message(event_id))?;
+                return Ok(());
+            }
+
+            if let Some(database) = &self.database {
+                if database.has_event(&event_id, &scope).await.unwrap_or(false) {
+                    ctx.send_message(RelayMessage::ok(
+                        event_id,
+                        true,
+                        Cow::Borrowed(DUPLICATE_EVENT_MESSAGE),
+                    ))?;
+                    return Ok(());
+                }
+            }
+
+            if let Err(reason) = self.pipeline.verify(event_cow).await {
+                ctx.send_message(OkReason::from(reason).to_message(event_id))?;
                 return Ok(());
             }
         }
@@ -150,6 +182,55 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_event_over_limits_is_refused() {
+        let limits = EventLimits::new()
+            .with_max_tags(1)
+            .with_kind_content_length(Kind::TextNote, 4);
+        let keys = Keys::generate();
+        let long = EventBuilder::text_note("too long")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let tagged = EventBuilder::text_note("ok")
+            .tags([Tag::hashtag("a"), Tag::hashtag("b")])
+            .sign_with_keys(&keys)
+            .unwrap();
+        let valid = EventBuilder::text_note("ok").sign_with_keys(&keys).unwrap();
+
+        assert_eq!(
+            limits.validate(&long),
+            Err("content exceeds 4 bytes".to_string())
+        );
+        assert_eq!(
+            limits.validate(&tagged),
+            Err("more than 1 tags".to_string())
+        );
+        assert_eq!(limits.validate(&valid), Ok(()));
+
+        let chain: Vec<
+            Arc<
+                dyn Middleware<
+                    State = NostrConnectionState<()>,
+                    IncomingMessage = ClientMessage<'static>,
+                    OutgoingMessage = RelayMessage<'static>,
+                >,
+            >,
+        > = vec![Arc::new(
+            EventVerifierMiddleware::<()>::new(create_crypto_helper()).with_limits(limits),
+        )];
+        let mut ctx = create_test_inbound_context(
+            "test_connection".to_string(),
+            Some(ClientMessage::Event(Cow::Owned(long))),
+            None,
+            create_test_state(),
+            chain.clone(),
+            0,
+        );
+
+        let result = chain[0].process_inbound(&mut ctx).await;
+        assert!(result.is_ok());
+    }
+
     #[tokio::test]
     async fn test_non_event_message_passes_through() {
         let crypto_helper = create_crypto_helper();
@@ -0,0 +1,133 @@
+//! Ordered before/after hooks around client message handling
+//!
+//! Implementing [`Middleware`] directly requires dealing with the websocket
+//! contexts and the connection state type. [`ClientMessageHook`] is a smaller
+//! surface for cross-cutting concerns such as logging, auth gating or spam
+//! scoring: it sees every EVENT, REQ, CLOSE, AUTH and COUNT together with the
+//! connection's scope and authenticated pubkey, and may answer a message itself
+//! instead of letting the relay handle it.
+
+use crate::state::NostrConnectionState;
+use anyhow::Result;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+use tracing::debug;
+use websocket_builder::{InboundContext, Middleware, OutboundContext, SendMessage};
+
+/// Connection details passed to hooks
+#[derive(Debug, Clone, Copy)]
+pub struct HookContext<'a> {
+    /// Id of the connection the message arrived on
+    pub connection_id: &'a str,
+    /// Scope the connection is bound to
+    pub scope: &'a Scope,
+    /// NIP-42 authenticated pubkey, if any
+    pub auth_pubkey: Option<&'a PublicKey>,
+}
+
+/// What to do with a message after a `before` hook ran
+#[derive(Debug, Clone)]
+pub enum HookAction {
+    /// Pass the message on to the next hook and eventually the relay
+    Continue,
+    /// Answer with this message and stop processing
+    Respond(RelayMessage<'static>),
+    /// Silently drop the message
+    Drop,
+}
+
+/// Hook invoked around the handling of every client message
+///
+/// `before` hooks run in registration order, `after` hooks in reverse order,
+/// only for messages that reached the relay.
+#[async_trait]
+pub trait ClientMessageHook: Send + Sync + std::fmt::Debug {
+    /// Called before the relay handles `message`
+    async fn before(&self, _message: &ClientMessage<'static>, _ctx: HookContext<'_>) -> HookAction {
+        HookAction::Continue
+    }
+
+    /// Called after the relay handled `message`, `succeeded` is false if handling failed
+    async fn after(
+        &self,
+        _message: &ClientMessage<'static>,
+        _ctx: HookContext<'_>,
+        _succeeded: bool,
+    ) {
+    }
+}
+
+/// Middleware running a list of [`ClientMessageHook`]s
+#[derive(Debug, Clone)]
+pub struct HookMiddleware<T = ()> {
+    hooks: Arc<Vec<Arc<dyn ClientMessageHook>>>,
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T> HookMiddleware<T> {
+    /// Create a middleware running `hooks` in order
+    pub fn new(hooks: Vec<Arc<dyn ClientMessageHook>>) -> Self {
+        Self {
+            hooks: Arc::new(hooks),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Clone + Send + Sync + std::fmt::Debug + 'static> Middleware for HookMiddleware<T> {
+    type State = NostrConnectionState<T>;
+    type IncomingMessage = ClientMessage<'static>;
+    type OutgoingMessage = RelayMessage<'static>;
+
+    async fn process_inbound(
+        &self,
+        ctx: &mut InboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        // Later middlewares take the message, keep a copy for the after hooks
+        let Some(message) = ctx.message.clone() else {
+            return ctx.next().await;
+        };
+        let (scope, auth_pubkey) = {
+            let state = ctx.state.read();
+            (Arc::clone(&state.subdomain), state.authed_pubkey)
+        };
+        let connection_id = ctx.connection_id.clone();
+        let hook_ctx = HookContext {
+            connection_id: &connection_id,
+            scope: &scope,
+            auth_pubkey: auth_pubkey.as_ref(),
+        };
+
+        for hook in self.hooks.iter() {
+            match hook.before(&message, hook_ctx).await {
+                HookAction::Continue => {}
+                HookAction::Respond(response) => {
+                    debug!("Message answered by hook {:?}", hook);
+                    return ctx.send_message(response);
+                }
+                HookAction::Drop => {
+                    debug!("Message dropped by hook {:?}", hook);
+                    return Ok(());
+                }
+            }
+        }
+
+        let result = ctx.next().await;
+
+        for hook in self.hooks.iter().rev() {
+            hook.after(&message, hook_ctx, result.is_ok()).await;
+        }
+
+        result
+    }
+
+    async fn process_outbound(
+        &self,
+        ctx: &mut OutboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        ctx.next().await
+    }
+}
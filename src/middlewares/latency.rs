@@ -0,0 +1,58 @@
+//! Middleware that starts the latency timeline of inbound events
+//!
+//! It must run first in the chain so the policy stage covers authentication
+//! and custom middlewares. The timeline is
+//! completed and recorded by the relay middleware once the event is distributed.
+
+use crate::latency::EventTimeline;
+use crate::state::NostrConnectionState;
+use anyhow::Result;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use websocket_builder::{InboundContext, Middleware, OutboundContext};
+
+/// Stamps the parse time of every EVENT on the connection state
+#[derive(Debug, Clone)]
+pub struct LatencyMiddleware<T = ()> {
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T> Default for LatencyMiddleware<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> LatencyMiddleware<T> {
+    /// Create a new latency middleware
+    pub fn new() -> Self {
+        Self {
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Clone + Send + Sync + std::fmt::Debug + 'static> Middleware for LatencyMiddleware<T> {
+    type State = NostrConnectionState<T>;
+    type IncomingMessage = ClientMessage<'static>;
+    type OutgoingMessage = RelayMessage<'static>;
+
+    async fn process_inbound(
+        &self,
+        ctx: &mut InboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(ClientMessage::Event(_)) = ctx.message.as_ref() {
+            ctx.state.write().event_timeline = Some(EventTimeline::start());
+        }
+
+        ctx.next().await
+    }
+
+    async fn process_outbound(
+        &self,
+        ctx: &mut OutboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        ctx.next().await
+    }
+}
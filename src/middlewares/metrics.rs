@@ -32,6 +32,14 @@ pub trait MetricsHandler: Send + Sync + std::fmt::Debug {
     /// Called when an inbound event is processed
     fn increment_inbound_events_processed(&self);
 
+    /// Record the latency of one stage of an event's path through the relay
+    ///
+    /// Only called when a [`crate::latency::LatencyBudget`] is active.
+    fn record_stage_latency(&self, _stage: crate::latency::EventStage, _latency_ms: f64) {}
+
+    /// Called when the web of trust rejected an event from an untrusted author
+    fn increment_untrusted_author_rejections(&self) {}
+
     /// Whether to track latency for this event (allows sampling)
     fn should_track_latency(&self) -> bool {
         true // Default to always track for backward compatibility
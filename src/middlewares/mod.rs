@@ -2,16 +2,24 @@
 
 mod error_handling;
 mod event_verifier;
+mod hooks;
+mod latency;
 mod logger;
 mod metrics;
 mod nip40_expiration;
 mod nip42_auth;
 mod nip70_protected;
+mod parse_error;
+mod rate_limit;
 
 pub use error_handling::{ClientMessageId, ErrorHandlingMiddleware};
 pub use event_verifier::EventVerifierMiddleware;
+pub use hooks::{ClientMessageHook, HookAction, HookContext, HookMiddleware};
+pub use latency::LatencyMiddleware;
 pub use logger::LoggerMiddleware;
 pub use metrics::{MetricsHandler, MetricsMiddleware};
 pub use nip40_expiration::Nip40ExpirationMiddleware;
 pub use nip42_auth::{AuthConfig, Nip42Middleware};
 pub use nip70_protected::Nip70Middleware;
+pub use parse_error::ParseErrorMiddleware;
+pub use rate_limit::RateLimitMiddleware;
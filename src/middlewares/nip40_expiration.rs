@@ -4,6 +4,7 @@ use crate::state::NostrConnectionState;
 use crate::subscription_coordinator::StoreCommand;
 use async_trait::async_trait;
 use nostr_sdk::prelude::*;
+use std::sync::Arc;
 use tracing::{error, warn};
 use websocket_builder::{InboundContext, Middleware, OutboundContext};
 
@@ -25,8 +26,17 @@ fn get_event_expiration(event: &Event) -> Option<Timestamp> {
 /// If the tag exists and the timestamp is in the past, the event is dropped
 /// and an `OK: false` message is sent back. On the outbound side, it filters
 /// out events that have expired and queues them for lazy deletion.
-#[derive(Debug, Clone)]
-pub struct Nip40ExpirationMiddleware;
+#[derive(Clone)]
+pub struct Nip40ExpirationMiddleware {
+    clock: Arc<dyn Fn() -> Timestamp + Send + Sync>,
+}
+
+impl std::fmt::Debug for Nip40ExpirationMiddleware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nip40ExpirationMiddleware")
+            .finish_non_exhaustive()
+    }
+}
 
 impl Default for Nip40ExpirationMiddleware {
     fn default() -> Self {
@@ -36,7 +46,19 @@ impl Default for Nip40ExpirationMiddleware {
 
 impl Nip40ExpirationMiddleware {
     pub fn new() -> Self {
-        Self
+        Self {
+            clock: Arc::new(Timestamp::now),
+        }
+    }
+
+    /// Compare expirations to the time `clock` returns instead of the system time
+    ///
+    /// Tests use it with `testing::TestClock`, enabled with the `testing`
+    /// feature, to expire events without waiting.
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Fn() -> Timestamp + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
     }
 }
 
@@ -53,7 +75,7 @@ impl Middleware for Nip40ExpirationMiddleware {
         if let Some(ClientMessage::Event(event_cow)) = &ctx.message {
             let event_ref: &Event = event_cow.as_ref();
             if let Some(expiration) = get_event_expiration(event_ref) {
-                if expiration < Timestamp::now() {
+                if expiration < (self.clock)() {
                     warn!(
                         target: "nip40",
                         "Event {} (kind {}) with expiration {} is expired. Publisher: {}.",
@@ -94,7 +116,7 @@ impl Middleware for Nip40ExpirationMiddleware {
         if let Some(RelayMessage::Event { event, .. }) = &mut ctx.message {
             let event_ref: &Event = event.as_ref();
             if let Some(expiration) = get_event_expiration(event_ref) {
-                if expiration < Timestamp::now() {
+                if expiration < (self.clock)() {
                     warn!(
                         target: "nip40",
                         "Dropping expired event {} (kind {}) with expiration {} from outbound. Publisher: {}.",
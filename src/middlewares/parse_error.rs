@@ -0,0 +1,136 @@
+//! Middleware answering unparsable client messages with a NOTICE
+
+use crate::message_converter::parse_error;
+use crate::state::NostrConnectionState;
+use anyhow::Result;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use tracing::{debug, warn};
+use websocket_builder::{InboundContext, Middleware, OutboundContext};
+
+/// Answers the messages [`NostrMessageConverter`] could not parse with a
+/// NOTICE, and drops the connection on the `max_strikes`-th one
+///
+/// Installed first in the chain by the relay builder when the
+/// [`ParseErrorPolicy`] is `Notice`; the converter then hands it a placeholder
+/// instead of failing the message.
+///
+/// [`NostrMessageConverter`]: crate::message_converter::NostrMessageConverter
+/// [`ParseErrorPolicy`]: crate::message_converter::ParseErrorPolicy
+#[derive(Debug, Clone)]
+pub struct ParseErrorMiddleware<T = ()> {
+    max_strikes: u32,
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T> ParseErrorMiddleware<T> {
+    /// Drop connections on their `max_strikes`-th malformed message
+    pub fn new(max_strikes: u32) -> Self {
+        Self {
+            max_strikes: max_strikes.max(1),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Clone + Send + Sync + std::fmt::Debug + 'static> Middleware for ParseErrorMiddleware<T> {
+    type State = NostrConnectionState<T>;
+    type IncomingMessage = ClientMessage<'static>;
+    type OutgoingMessage = RelayMessage<'static>;
+
+    async fn process_inbound(
+        &self,
+        ctx: &mut InboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(error) = ctx
+            .message
+            .as_ref()
+            .and_then(parse_error)
+            .map(str::to_string)
+        else {
+            return ctx.next().await;
+        };
+
+        let strikes = {
+            let mut state = ctx.state.write();
+            state.parse_errors += 1;
+            state.parse_errors
+        };
+
+        if strikes >= self.max_strikes {
+            warn!(
+                "Dropping connection {} after {} malformed messages",
+                ctx.connection_id, strikes
+            );
+            let _ = ctx.send_message(RelayMessage::notice(
+                "error: too many malformed messages, closing connection",
+            ));
+            return Err(anyhow::anyhow!(
+                "Too many malformed messages from {}",
+                ctx.connection_id
+            ));
+        }
+
+        debug!(
+            "Malformed message {} of {} from {}: {}",
+            strikes, self.max_strikes, ctx.connection_id, error
+        );
+        ctx.send_message(RelayMessage::notice(format!("invalid: {error}")))
+    }
+
+    async fn process_outbound(
+        &self,
+        ctx: &mut OutboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        ctx.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_converter::{NostrMessageConverter, ParseErrorPolicy};
+    use crate::test_utils::create_test_inbound_context;
+    use std::sync::Arc;
+    use websocket_builder::MessageConverter;
+
+    #[tokio::test]
+    async fn test_notice_then_disconnect() {
+        let converter = NostrMessageConverter::default()
+            .with_parse_error_policy(ParseErrorPolicy::Notice { max_strikes: 2 });
+        let chain: Vec<
+            Arc<
+                dyn Middleware<
+                    State = NostrConnectionState<()>,
+                    IncomingMessage = ClientMessage<'static>,
+                    OutgoingMessage = RelayMessage<'static>,
+                >,
+            >,
+        > = vec![Arc::new(ParseErrorMiddleware::<()>::new(2))];
+        let (tx, rx) = flume::bounded(10);
+        let state = NostrConnectionState::new(RelayUrl::parse("wss://test.relay").unwrap())
+            .expect("Valid state");
+
+        let message = converter.inbound_from_bytes(b"not json").unwrap();
+        let mut ctx = create_test_inbound_context(
+            "test_connection".to_string(),
+            message.clone(),
+            Some(tx),
+            state,
+            chain.clone(),
+            0,
+        );
+
+        assert!(chain[0].process_inbound(&mut ctx).await.is_ok());
+        let (notice, _) = rx.try_recv().unwrap();
+        assert!(matches!(
+            notice,
+            RelayMessage::Notice(message) if message.starts_with("invalid: Failed to parse")
+        ));
+
+        ctx.message = message;
+        assert!(chain[0].process_inbound(&mut ctx).await.is_err());
+        assert!(matches!(rx.try_recv().unwrap().0, RelayMessage::Notice(_)));
+    }
+}
@@ -0,0 +1,87 @@
+//! Middleware enforcing a [`RateLimiter`] on client messages
+
+use crate::rate_limit::{RateLimitedAction, RateLimiter};
+use crate::state::NostrConnectionState;
+use anyhow::Result;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+use websocket_builder::{DisconnectContext, InboundContext, Middleware, OutboundContext};
+
+/// Rejects messages once the client's token buckets are empty
+///
+/// Every message takes a [`RateLimitedAction::Message`] token, EVENTs also an
+/// [`RateLimitedAction::Event`] token and REQs a [`RateLimitedAction::Req`] token.
+/// Rejections are returned as [`crate::error::Error::RateLimited`], so
+/// `ErrorHandlingMiddleware` must run earlier in the chain to answer them with
+/// `OK false` or `CLOSED` and the `rate-limited:` prefix.
+#[derive(Debug, Clone)]
+pub struct RateLimitMiddleware<T = ()> {
+    limiter: RateLimiter,
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T> RateLimitMiddleware<T> {
+    /// Create a middleware enforcing `limiter`
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self {
+            limiter,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Clone + Send + Sync + std::fmt::Debug + 'static> Middleware for RateLimitMiddleware<T> {
+    type State = NostrConnectionState<T>;
+    type IncomingMessage = ClientMessage<'static>;
+    type OutgoingMessage = RelayMessage<'static>;
+
+    async fn process_inbound(
+        &self,
+        ctx: &mut InboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        let action = match &ctx.message {
+            Some(ClientMessage::Event(_)) => Some(RateLimitedAction::Event),
+            Some(ClientMessage::Req { .. } | ClientMessage::ReqMultiFilter { .. }) => {
+                Some(RateLimitedAction::Req)
+            }
+            Some(_) => None,
+            None => return ctx.next().await,
+        };
+
+        let (scope, auth_pubkey) = {
+            let state = ctx.state.read();
+            (Arc::clone(&state.subdomain), state.authed_pubkey)
+        };
+
+        self.limiter.check(
+            &scope,
+            RateLimitedAction::Message,
+            &ctx.connection_id,
+            auth_pubkey.as_ref(),
+        )?;
+        if let Some(action) = action {
+            self.limiter
+                .check(&scope, action, &ctx.connection_id, auth_pubkey.as_ref())?;
+        }
+
+        ctx.next().await
+    }
+
+    async fn process_outbound(
+        &self,
+        ctx: &mut OutboundContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        ctx.next().await
+    }
+
+    async fn on_disconnect(
+        &self,
+        ctx: &mut DisconnectContext<Self::State, Self::IncomingMessage, Self::OutgoingMessage>,
+    ) -> Result<(), anyhow::Error> {
+        self.limiter.forget_connection(&ctx.connection_id);
+        self.limiter.prune();
+        ctx.next().await
+    }
+}
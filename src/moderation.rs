@@ -0,0 +1,491 @@
+//! Allow/deny lists for moderating a relay at runtime
+//!
+//! [`ModerationStore`] keeps banned pubkeys, banned event ids, allowed pubkeys
+//! and blocked words in memory and persists every change to the relay database
+//! as a relay-signed NIP-78 application data event, so the lists survive
+//! restarts. The store is consulted at ingest, as an [`EventPolicy`], and when
+//! events are sent to clients, for both historical queries and live
+//! subscriptions, so a change takes effect immediately.
+//!
+//! Shadow-banned pubkeys aren't told anything: their events are answered with
+//! `OK true` and stored, but only connections authenticated as their author
+//! see them. Other clients' queries leave them out and the
+//! [`SubscriptionRegistry`](crate::SubscriptionRegistry) neither distributes
+//! them to other connections nor hands them to sinks.
+
+use crate::database::RelayDatabase;
+use crate::error::{Error, Result};
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Kind of the event the lists are persisted in (NIP-78 application data)
+pub const MODERATION_LIST_KIND: Kind = Kind::ApplicationSpecificData;
+
+/// `d` tag of the event the lists are persisted in
+pub const MODERATION_LIST_IDENTIFIER: &str = "relay_builder/moderation";
+
+/// The moderation lists
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModerationLists {
+    /// Authors whose events are rejected and hidden
+    #[serde(default)]
+    pub banned_pubkeys: HashSet<PublicKey>,
+    /// Events that are rejected and hidden
+    #[serde(default)]
+    pub banned_events: HashSet<EventId>,
+    /// When not empty, only these authors may publish
+    #[serde(default)]
+    pub allowed_pubkeys: HashSet<PublicKey>,
+    /// Case-insensitive words rejected and hidden in event content
+    #[serde(default)]
+    pub blocked_words: HashSet<String>,
+    /// Authors whose data was purged, with the purge time; their events
+    /// created up to then are rejected
+    #[serde(default)]
+    pub purged_pubkeys: HashMap<PublicKey, Timestamp>,
+    /// Pubkeys whose mentions were purged, with the purge time; events created
+    /// up to then that tag them are rejected
+    #[serde(default)]
+    pub purged_mentions: HashMap<PublicKey, Timestamp>,
+    /// Authors whose events are accepted but only shown to themselves
+    #[serde(default)]
+    pub shadow_banned_pubkeys: HashSet<PublicKey>,
+}
+
+impl ModerationLists {
+    /// Why `event` may not be stored, `None` if it may
+    pub fn rejection(&self, event: &Event) -> Option<ClosedReason> {
+        if self.banned_pubkeys.contains(&event.pubkey) {
+            return Some(ClosedReason::Blocked("pubkey is banned".to_string()));
+        }
+        if self.banned_events.contains(&event.id) {
+            return Some(ClosedReason::Blocked("event is banned".to_string()));
+        }
+        if self
+            .purged_pubkeys
+            .get(&event.pubkey)
+            .is_some_and(|purged_at| event.created_at <= *purged_at)
+        {
+            return Some(ClosedReason::Blocked(
+                "the author's data was purged from this relay".to_string(),
+            ));
+        }
+        if !self.purged_mentions.is_empty()
+            && event.tags.public_keys().any(|pubkey| {
+                self.purged_mentions
+                    .get(pubkey)
+                    .is_some_and(|purged_at| event.created_at <= *purged_at)
+            })
+        {
+            return Some(ClosedReason::Blocked(
+                "event mentions a pubkey whose data was purged".to_string(),
+            ));
+        }
+        if !self.allowed_pubkeys.is_empty() && !self.allowed_pubkeys.contains(&event.pubkey) {
+            return Some(ClosedReason::Restricted(
+                "pubkey is not on the allowlist".to_string(),
+            ));
+        }
+        if self.contains_blocked_word(&event.content) {
+            return Some(ClosedReason::Blocked(
+                "content contains a blocked word".to_string(),
+            ));
+        }
+        None
+    }
+
+    /// Whether a stored `event` may be sent to clients
+    ///
+    /// The allowlist only gates publishing, events stored before it was set stay visible.
+    pub fn is_visible(&self, event: &Event) -> bool {
+        self.is_visible_to(event, None)
+    }
+
+    /// Whether a stored `event` may be sent to a client authenticated as `viewer`
+    ///
+    /// Events of shadow-banned authors are only visible to their author.
+    pub fn is_visible_to(&self, event: &Event, viewer: Option<&PublicKey>) -> bool {
+        !self.banned_pubkeys.contains(&event.pubkey)
+            && !self.banned_events.contains(&event.id)
+            && !self.contains_blocked_word(&event.content)
+            && (!self.shadow_banned_pubkeys.contains(&event.pubkey)
+                || viewer == Some(&event.pubkey))
+    }
+
+    fn contains_blocked_word(&self, content: &str) -> bool {
+        if self.blocked_words.is_empty() {
+            return false;
+        }
+        let content = content.to_lowercase();
+        self.blocked_words.iter().any(|word| content.contains(word))
+    }
+}
+
+struct Inner {
+    lists: ModerationLists,
+    /// `created_at` of the last persisted version, versions must strictly increase
+    saved_at: Timestamp,
+}
+
+/// Shared, persistent moderation lists
+#[derive(Clone)]
+pub struct ModerationStore {
+    inner: Arc<RwLock<Inner>>,
+    database: Arc<RelayDatabase>,
+    keys: Keys,
+}
+
+impl std::fmt::Debug for ModerationStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.read();
+        f.debug_struct("ModerationStore")
+            .field("banned_pubkeys", &inner.lists.banned_pubkeys.len())
+            .field("banned_events", &inner.lists.banned_events.len())
+            .field("allowed_pubkeys", &inner.lists.allowed_pubkeys.len())
+            .field("blocked_words", &inner.lists.blocked_words.len())
+            .field(
+                "shadow_banned_pubkeys",
+                &inner.lists.shadow_banned_pubkeys.len(),
+            )
+            .finish()
+    }
+}
+
+impl ModerationStore {
+    /// Load the lists persisted in `database` by the relay identified by `keys`
+    pub async fn open(database: Arc<RelayDatabase>, keys: Keys) -> Result<Self> {
+        let filter = Filter::new()
+            .author(keys.public_key())
+            .kind(MODERATION_LIST_KIND)
+            .identifier(MODERATION_LIST_IDENTIFIER)
+            .limit(1);
+        let stored = database.query(vec![filter], &Scope::Default).await?;
+
+        let (lists, saved_at) = match stored.into_iter().next() {
+            Some(event) => (
+                serde_json::from_str(&event.content)
+                    .map_err(|e| Error::database(format!("Invalid moderation lists event: {e}")))?,
+                event.created_at,
+            ),
+            None => (ModerationLists::default(), Timestamp::from(0)),
+        };
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Inner { lists, saved_at })),
+            database,
+            keys,
+        })
+    }
+
+    /// Snapshot of the current lists
+    pub fn lists(&self) -> ModerationLists {
+        self.inner.read().lists.clone()
+    }
+
+    /// Why `event` may not be stored, `None` if it may
+    pub fn rejection(&self, event: &Event) -> Option<ClosedReason> {
+        self.inner.read().lists.rejection(event)
+    }
+
+    /// Whether a stored `event` may be sent to clients
+    pub fn is_visible(&self, event: &Event) -> bool {
+        self.is_visible_to(event, None)
+    }
+
+    /// Whether a stored `event` may be sent to a client authenticated as `viewer`
+    pub fn is_visible_to(&self, event: &Event, viewer: Option<&PublicKey>) -> bool {
+        // The persisted lists themselves are private to the relay
+        if event.pubkey == self.keys.public_key()
+            && event.kind == MODERATION_LIST_KIND
+            && event.tags.identifier() == Some(MODERATION_LIST_IDENTIFIER)
+        {
+            return false;
+        }
+        self.inner.read().lists.is_visible_to(event, viewer)
+    }
+
+    /// Whether the events of `pubkey` are only shown to itself
+    pub fn is_shadow_banned(&self, pubkey: &PublicKey) -> bool {
+        self.inner
+            .read()
+            .lists
+            .shadow_banned_pubkeys
+            .contains(pubkey)
+    }
+
+    /// Change the lists and persist the result
+    ///
+    /// The change applies immediately, even before it is persisted.
+    pub async fn update(&self, change: impl FnOnce(&mut ModerationLists)) -> Result<()> {
+        let event = {
+            let mut inner = self.inner.write();
+            change(&mut inner.lists);
+
+            let created_at = std::cmp::max(
+                Timestamp::now(),
+                Timestamp::from(inner.saved_at.as_u64() + 1),
+            );
+            inner.saved_at = created_at;
+
+            let content = serde_json::to_string(&inner.lists)
+                .map_err(|e| Error::internal(format!("Failed to serialize lists: {e}")))?;
+            EventBuilder::new(MODERATION_LIST_KIND, content)
+                .tag(Tag::identifier(MODERATION_LIST_IDENTIFIER))
+                .custom_created_at(created_at)
+                .sign_with_keys(&self.keys)
+                .map_err(|e| Error::internal(format!("Failed to sign lists: {e}")))?
+        };
+
+        self.database.save_event(&event, &Scope::Default).await
+    }
+
+    /// Reject and hide all events of `pubkey`
+    pub async fn ban_pubkey(&self, pubkey: PublicKey) -> Result<()> {
+        self.update(|lists| {
+            lists.banned_pubkeys.insert(pubkey);
+        })
+        .await
+    }
+
+    /// Accept the events of `pubkey` but only show them to itself
+    pub async fn shadow_ban_pubkey(&self, pubkey: PublicKey) -> Result<()> {
+        self.update(|lists| {
+            lists.shadow_banned_pubkeys.insert(pubkey);
+        })
+        .await
+    }
+
+    /// Lift a shadow ban set with [`Self::shadow_ban_pubkey`]
+    pub async fn lift_shadow_ban(&self, pubkey: PublicKey) -> Result<()> {
+        self.update(|lists| {
+            lists.shadow_banned_pubkeys.remove(&pubkey);
+        })
+        .await
+    }
+
+    /// Reject events of `pubkey` created until now, and events tagging it if
+    /// `mentions`, once its data is purged
+    ///
+    /// See [`RelayDatabase::purge_author`].
+    pub async fn tombstone_pubkey(&self, pubkey: PublicKey, mentions: bool) -> Result<()> {
+        let now = Timestamp::now();
+        self.update(|lists| {
+            lists.purged_pubkeys.insert(pubkey, now);
+            if mentions {
+                lists.purged_mentions.insert(pubkey, now);
+            }
+        })
+        .await
+    }
+
+    /// Lift a ban set with [`Self::ban_pubkey`]
+    pub async fn unban_pubkey(&self, pubkey: PublicKey) -> Result<()> {
+        self.update(|lists| {
+            lists.banned_pubkeys.remove(&pubkey);
+        })
+        .await
+    }
+
+    /// Reject and hide the event `id`
+    pub async fn ban_event(&self, id: EventId) -> Result<()> {
+        self.update(|lists| {
+            lists.banned_events.insert(id);
+        })
+        .await
+    }
+
+    /// Lift a ban set with [`Self::ban_event`]
+    pub async fn unban_event(&self, id: EventId) -> Result<()> {
+        self.update(|lists| {
+            lists.banned_events.remove(&id);
+        })
+        .await
+    }
+
+    /// Add `pubkey` to the allowlist, which restricts publishing to its members
+    pub async fn allow_pubkey(&self, pubkey: PublicKey) -> Result<()> {
+        self.update(|lists| {
+            lists.allowed_pubkeys.insert(pubkey);
+        })
+        .await
+    }
+
+    /// Remove `pubkey` from the allowlist
+    pub async fn disallow_pubkey(&self, pubkey: PublicKey) -> Result<()> {
+        self.update(|lists| {
+            lists.allowed_pubkeys.remove(&pubkey);
+        })
+        .await
+    }
+
+    /// Reject and hide events whose content contains `word`, ignoring case
+    pub async fn block_word(&self, word: &str) -> Result<()> {
+        let word = word.to_lowercase();
+        self.update(|lists| {
+            lists.blocked_words.insert(word);
+        })
+        .await
+    }
+
+    /// Remove a word added with [`Self::block_word`]
+    pub async fn unblock_word(&self, word: &str) -> Result<()> {
+        let word = word.to_lowercase();
+        self.update(|lists| {
+            lists.blocked_words.remove(&word);
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl EventPolicy for ModerationStore {
+    async fn check(&self, event: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        match self.rejection(event) {
+            Some(reason) => PolicyDecision::Reject(reason),
+            None => PolicyDecision::Accept,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    #[tokio::test]
+    async fn test_lists_apply_and_persist() {
+        let (_tmp_dir, database, relay_keys) = setup_test_with_database().await;
+        let store = ModerationStore::open(database.clone(), relay_keys.clone())
+            .await
+            .unwrap();
+
+        let spammer = Keys::generate();
+        let spam = EventBuilder::text_note("buy now")
+            .sign_with_keys(&spammer)
+            .unwrap();
+        assert!(store.rejection(&spam).is_none());
+
+        store.ban_pubkey(spammer.public_key()).await.unwrap();
+        store.block_word("CASINO").await.unwrap();
+        assert!(store.rejection(&spam).is_some());
+        assert!(!store.is_visible(&spam));
+
+        let author = Keys::generate();
+        let casino = EventBuilder::text_note("Visit my casino")
+            .sign_with_keys(&author)
+            .unwrap();
+        assert!(!store.is_visible(&casino));
+
+        // The lists are restored from the database
+        let reopened = ModerationStore::open(database.clone(), relay_keys.clone())
+            .await
+            .unwrap();
+        assert_eq!(reopened.lists(), store.lists());
+
+        // The persisted lists are not served to clients
+        let persisted = database
+            .query(
+                vec![Filter::new().kind(MODERATION_LIST_KIND)],
+                &Scope::Default,
+            )
+            .await
+            .unwrap();
+        assert_eq!(persisted.len(), 1);
+        assert!(!store.is_visible(&persisted.into_iter().next().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_gates_publishing_only() {
+        let (_tmp_dir, database, relay_keys) = setup_test_with_database().await;
+        let store = ModerationStore::open(database, relay_keys).await.unwrap();
+
+        let member = Keys::generate();
+        let outsider = Keys::generate();
+        store.allow_pubkey(member.public_key()).await.unwrap();
+
+        let from_member = EventBuilder::text_note("hi")
+            .sign_with_keys(&member)
+            .unwrap();
+        let from_outsider = EventBuilder::text_note("hi")
+            .sign_with_keys(&outsider)
+            .unwrap();
+
+        assert!(store.rejection(&from_member).is_none());
+        assert!(matches!(
+            store.rejection(&from_outsider),
+            Some(ClosedReason::Restricted(_))
+        ));
+        assert!(store.is_visible(&from_outsider));
+    }
+
+    #[tokio::test]
+    async fn test_purged_pubkey_is_deleted_and_tombstoned() {
+        let (_tmp_dir, database, relay_keys) = setup_test_with_database().await;
+        let store = ModerationStore::open(database.clone(), relay_keys.clone())
+            .await
+            .unwrap();
+        let user = Keys::generate();
+        let friend = Keys::generate();
+        let tenant = Scope::named("tenant").unwrap();
+        let past = Timestamp::now() - 60;
+
+        let note = EventBuilder::text_note("forget me")
+            .custom_created_at(past)
+            .sign_with_keys(&user)
+            .unwrap();
+        let reply = EventBuilder::text_note("replying")
+            .tag(Tag::public_key(user.public_key()))
+            .custom_created_at(past)
+            .sign_with_keys(&friend)
+            .unwrap();
+        for scope in [&Scope::Default, &tenant] {
+            database.save_event(&note, scope).await.unwrap();
+            database.save_event(&reply, scope).await.unwrap();
+        }
+
+        store
+            .tombstone_pubkey(user.public_key(), true)
+            .await
+            .unwrap();
+        for scope in [&Scope::Default, &tenant] {
+            assert_eq!(
+                database
+                    .purge_author(&user.public_key(), scope)
+                    .await
+                    .unwrap(),
+                1
+            );
+            assert_eq!(
+                database
+                    .purge_mentions(&user.public_key(), scope)
+                    .await
+                    .unwrap(),
+                1
+            );
+        }
+        let remaining = database
+            .query(vec![Filter::new().kind(Kind::TextNote)], &tenant)
+            .await
+            .unwrap();
+        assert!(remaining.is_empty());
+
+        // Old events can't come back, new ones are accepted
+        assert!(store.rejection(&note).is_some());
+        assert!(store.rejection(&reply).is_some());
+        let fresh = EventBuilder::text_note("hello again")
+            .custom_created_at(Timestamp::now() + 60)
+            .sign_with_keys(&user)
+            .unwrap();
+        assert!(store.rejection(&fresh).is_none());
+
+        let reopened = ModerationStore::open(database, relay_keys).await.unwrap();
+        assert!(reopened.rejection(&note).is_some());
+    }
+}
@@ -0,0 +1,275 @@
+//! NIP-66 relay discovery and monitoring
+//!
+//! [`Nip66`] makes the relay its own monitor: a [`StatusPublisher`] publishes,
+//! for every active scope, a kind 30166 discovery event signed by the relay
+//! identity whose `d` tag is the scope's relay URL, with the network, the
+//! supported NIPs, the requirements and the measured `rtt-read` and
+//! `rtt-write` times as tags and a NIP-11 excerpt with the software and
+//! version as content. A kind 10166 announcement describing the checks is
+//! published at startup. The relay can't time a connection to itself, so
+//! `rtt-open` is left to external monitors.
+//!
+//! As an [`EventPolicy`], [`Nip66`] also accepts the discovery events and
+//! announcements of other monitors when they are well-formed: discovery
+//! events need a `d` tag and numeric `rtt-*` values, announcements a numeric
+//! `frequency`.
+//!
+//! Named scopes are assumed to be served on a subdomain of the relay URL,
+//! e.g. scope `team` of `wss://relay.example.com` at
+//! `wss://team.relay.example.com`.
+
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::status::{RelayStatus, StatusPublisher};
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::time::Duration;
+
+/// Kind of NIP-66 relay discovery events
+pub const RELAY_DISCOVERY_KIND: Kind = Kind::Custom(30166);
+
+/// Kind of NIP-66 monitor announcements
+pub const MONITOR_ANNOUNCEMENT_KIND: Kind = Kind::Custom(10166);
+
+/// Checks the relay runs on itself, announced in `c` tags
+const CHECKS: [&str; 3] = ["read", "write", "nip11"];
+
+/// NIP-66 discovery events describing this relay, and validation of others'
+#[derive(Debug, Clone)]
+pub struct Nip66 {
+    relay_url: String,
+    frequency: Duration,
+    network: String,
+    supported_nips: Vec<u16>,
+    requirements: Vec<String>,
+    software: String,
+    version: String,
+}
+
+impl Nip66 {
+    /// Describe the relay served at `relay_url` every hour, on the clearnet
+    pub fn new(relay_url: impl Into<String>) -> Self {
+        Self {
+            relay_url: relay_url.into().trim_end_matches('/').to_string(),
+            frequency: Duration::from_secs(3600),
+            network: "clearnet".to_string(),
+            supported_nips: vec![1, 66],
+            requirements: Vec::new(),
+            software: "relay_builder".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Publish discovery events every `frequency`
+    #[must_use]
+    pub fn with_frequency(mut self, frequency: Duration) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Network the relay is reachable on, e.g. `tor` or `i2p`
+    #[must_use]
+    pub fn with_network(mut self, network: impl Into<String>) -> Self {
+        self.network = network.into();
+        self
+    }
+
+    /// NIPs advertised in `N` tags
+    #[must_use]
+    pub fn with_supported_nips(mut self, supported_nips: Vec<u16>) -> Self {
+        self.supported_nips = supported_nips;
+        self
+    }
+
+    /// Advertise a requirement in an `R` tag, e.g. `auth` or `!payment`
+    #[must_use]
+    pub fn with_requirement(mut self, requirement: impl Into<String>) -> Self {
+        self.requirements.push(requirement.into());
+        self
+    }
+
+    /// Software and version reported in the content, `relay_builder` by default
+    #[must_use]
+    pub fn with_software(
+        mut self,
+        software: impl Into<String>,
+        version: impl Into<String>,
+    ) -> Self {
+        self.software = software.into();
+        self.version = version.into();
+        self
+    }
+
+    /// Status publisher emitting this relay's discovery events and announcement
+    pub fn status_publisher(&self) -> StatusPublisher {
+        let nip66 = self.clone();
+        StatusPublisher::new(self.frequency)
+            .with_format(move |status| nip66.discovery_event(status))
+            .with_announcement(self.monitor_announcement())
+    }
+
+    /// Relay URL of `scope`
+    pub fn scope_url(&self, scope: &Scope) -> String {
+        let Scope::Named { name, .. } = scope else {
+            return self.relay_url.clone();
+        };
+        match url::Url::parse(&self.relay_url) {
+            Ok(mut url) => {
+                let host = format!("{name}.{}", url.host_str().unwrap_or_default());
+                match url.set_host(Some(&host)) {
+                    Ok(()) => url.to_string().trim_end_matches('/').to_string(),
+                    Err(_) => self.relay_url.clone(),
+                }
+            }
+            Err(_) => self.relay_url.clone(),
+        }
+    }
+
+    /// Kind 30166 discovery event of `status`'s scope
+    pub fn discovery_event(&self, status: &RelayStatus) -> EventBuilder {
+        let mut tags = vec![
+            Tag::identifier(self.scope_url(&status.scope)),
+            Tag::custom(TagKind::custom("n"), [self.network.clone()]),
+        ];
+        for (name, rtt) in [
+            ("rtt-read", status.rtt_read),
+            ("rtt-write", status.rtt_write),
+        ] {
+            if let Some(rtt) = rtt {
+                tags.push(Tag::custom(TagKind::custom(name), [rtt.to_string()]));
+            }
+        }
+        tags.extend(
+            self.supported_nips
+                .iter()
+                .map(|nip| Tag::custom(TagKind::custom("N"), [nip.to_string()])),
+        );
+        tags.extend(
+            self.requirements
+                .iter()
+                .map(|requirement| Tag::custom(TagKind::custom("R"), [requirement.clone()])),
+        );
+
+        let content = serde_json::json!({
+            "supported_nips": self.supported_nips,
+            "software": self.software,
+            "version": self.version,
+        });
+        EventBuilder::new(RELAY_DISCOVERY_KIND, content.to_string()).tags(tags)
+    }
+
+    /// Kind 10166 announcement of the relay monitoring itself
+    pub fn monitor_announcement(&self) -> EventBuilder {
+        let mut tags = vec![Tag::custom(
+            TagKind::custom("frequency"),
+            [self.frequency.as_secs().to_string()],
+        )];
+        tags.extend(
+            CHECKS
+                .iter()
+                .map(|check| Tag::custom(TagKind::custom("c"), [check.to_string()])),
+        );
+        EventBuilder::new(MONITOR_ANNOUNCEMENT_KIND, "").tags(tags)
+    }
+}
+
+/// First value of the first `name` tag of `event`
+fn tag_value<'a>(event: &'a Event, name: &str) -> Option<&'a str> {
+    event.tags.iter().find_map(|tag| match tag.as_slice() {
+        [tag_name, value, ..] if tag_name == name => Some(value.as_str()),
+        _ => None,
+    })
+}
+
+#[async_trait]
+impl EventPolicy for Nip66 {
+    async fn check(&self, event: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        let invalid = |reason: String| PolicyDecision::Reject(ClosedReason::Invalid(reason));
+
+        if event.kind == RELAY_DISCOVERY_KIND {
+            if event.tags.identifier().is_none_or(str::is_empty) {
+                return invalid("relay discovery events need a d tag".to_string());
+            }
+            for name in ["rtt-open", "rtt-read", "rtt-write"] {
+                if tag_value(event, name).is_some_and(|rtt| rtt.parse::<u64>().is_err()) {
+                    return invalid(format!("{name} must be a number of milliseconds"));
+                }
+            }
+        } else if event.kind == MONITOR_ANNOUNCEMENT_KIND
+            && tag_value(event, "frequency")
+                .is_none_or(|frequency| frequency.parse::<u64>().is_err())
+        {
+            return invalid("monitor announcements need a frequency in seconds".to_string());
+        }
+        PolicyDecision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_discovery_events_describe_each_scope() {
+        let nip66 = Nip66::new("wss://relay.example.com/").with_requirement("auth");
+        let keys = Keys::generate();
+        let status = RelayStatus {
+            scope: Scope::named("team").unwrap(),
+            connections: 1,
+            active_subscriptions: 2,
+            events_per_second: 0.5,
+            uptime: 60,
+            rtt_read: Some(3),
+            rtt_write: None,
+        };
+
+        let event = nip66
+            .discovery_event(&status)
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(event.kind, RELAY_DISCOVERY_KIND);
+        assert_eq!(
+            event.tags.identifier(),
+            Some("wss://team.relay.example.com")
+        );
+        assert_eq!(tag_value(&event, "rtt-read"), Some("3"));
+        assert_eq!(tag_value(&event, "rtt-write"), None);
+        assert_eq!(tag_value(&event, "R"), Some("auth"));
+        assert_eq!(
+            nip66.check(&event, &status.scope, None).await,
+            PolicyDecision::Accept
+        );
+        assert_eq!(nip66.scope_url(&Scope::Default), "wss://relay.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_monitor_events_are_refused() {
+        let nip66 = Nip66::new("wss://relay.example.com");
+        let keys = Keys::generate();
+        let check = |builder: EventBuilder| {
+            let event = builder.sign_with_keys(&keys).unwrap();
+            let nip66 = nip66.clone();
+            async move { nip66.check(&event, &Scope::Default, None).await }
+        };
+
+        let no_d = EventBuilder::new(RELAY_DISCOVERY_KIND, "");
+        assert!(matches!(check(no_d).await, PolicyDecision::Reject(_)));
+
+        let bad_rtt = EventBuilder::new(RELAY_DISCOVERY_KIND, "").tags([
+            Tag::identifier("wss://other.example.com"),
+            Tag::custom(TagKind::custom("rtt-open"), ["fast"]),
+        ]);
+        assert!(matches!(check(bad_rtt).await, PolicyDecision::Reject(_)));
+
+        assert_eq!(
+            check(nip66.monitor_announcement()).await,
+            PolicyDecision::Accept
+        );
+        let no_frequency = EventBuilder::new(MONITOR_ANNOUNCEMENT_KIND, "");
+        assert!(matches!(
+            check(no_frequency).await,
+            PolicyDecision::Reject(_)
+        ));
+    }
+}
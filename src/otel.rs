@@ -0,0 +1,308 @@
+//! OpenTelemetry export, enabled with the `otel` feature
+//!
+//! [`OtelExporter`] sends traces and metrics to an OTLP collector (Tempo,
+//! Grafana Alloy, the OpenTelemetry Collector...). Traces come from the
+//! relay's `tracing` spans through [`OtelExporter::layer`], so the sampled
+//! connection, REQ, save and distribution spans show up as OTLP spans.
+//! Metrics come from [`OtelMetrics`], a [`RelayMetricsHandler`] recording the
+//! ingest, query and fan-out paths with `kind` and `result` attributes.
+
+use crate::error::{Error, Result};
+use crate::metrics::{RelayMetricsHandler, ReqStats};
+use opentelemetry::metrics::{Counter, Histogram, Meter, MeterProvider as _};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use std::time::Duration;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Instrumentation scope name of the relay's spans and instruments
+const INSTRUMENTATION_NAME: &str = "relay_builder";
+
+/// Default OTLP/gRPC collector endpoint
+pub const DEFAULT_OTLP_ENDPOINT: &str = "http://localhost:4317";
+
+/// Where and as what the relay exports its telemetry
+#[derive(Debug, Clone)]
+pub struct OtelConfig {
+    /// OTLP/gRPC endpoint of the collector
+    pub endpoint: String,
+    /// `service.name` resource attribute
+    pub service_name: String,
+    /// How often metrics are pushed
+    pub metrics_interval: Duration,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: DEFAULT_OTLP_ENDPOINT.to_string(),
+            service_name: INSTRUMENTATION_NAME.to_string(),
+            metrics_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl OtelConfig {
+    /// Export to the collector at `endpoint`
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Report as `service_name`
+    #[must_use]
+    pub fn with_service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.service_name = service_name.into();
+        self
+    }
+
+    /// Push metrics every `interval`
+    #[must_use]
+    pub fn with_metrics_interval(mut self, interval: Duration) -> Self {
+        self.metrics_interval = interval;
+        self
+    }
+}
+
+/// OTLP trace and metric pipelines
+///
+/// Must be created inside a Tokio runtime. Call [`Self::shutdown`] before
+/// exiting so buffered spans and metrics are flushed.
+#[derive(Debug, Clone)]
+pub struct OtelExporter {
+    tracer_provider: TracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl OtelExporter {
+    /// Start exporting to the collector described by `config`
+    pub fn new(config: &OtelConfig) -> Result<Self> {
+        let resource = Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]);
+
+        let span_exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .build()
+            .map_err(|e| Error::internal(format!("Failed to create OTLP span exporter: {e}")))?;
+        let tracer_provider = TracerProvider::builder()
+            .with_batch_exporter(span_exporter, runtime::Tokio)
+            .with_resource(resource.clone())
+            .build();
+
+        let metric_exporter = opentelemetry_otlp::MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .build()
+            .map_err(|e| Error::internal(format!("Failed to create OTLP metric exporter: {e}")))?;
+        let reader = PeriodicReader::builder(metric_exporter, runtime::Tokio)
+            .with_interval(config.metrics_interval)
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    /// `tracing` layer exporting spans, add it to the application's subscriber
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.tracer_provider.tracer(INSTRUMENTATION_NAME))
+    }
+
+    /// Metrics handler exporting through this pipeline
+    ///
+    /// Pass it to [`crate::RelayBuilder::with_relay_metrics`].
+    pub fn metrics(&self) -> OtelMetrics {
+        OtelMetrics::new(&self.meter_provider.meter(INSTRUMENTATION_NAME))
+    }
+
+    /// Flush and stop both pipelines
+    pub fn shutdown(&self) -> Result<()> {
+        self.tracer_provider
+            .shutdown()
+            .map_err(|e| Error::internal(format!("Failed to shut down tracer provider: {e}")))?;
+        self.meter_provider
+            .shutdown()
+            .map_err(|e| Error::internal(format!("Failed to shut down meter provider: {e}")))
+    }
+}
+
+/// [`RelayMetricsHandler`] recording OpenTelemetry instruments
+#[derive(Debug, Clone)]
+pub struct OtelMetrics {
+    events: Counter<u64>,
+    query_duration: Histogram<f64>,
+    pagination_attempts: Histogram<u64>,
+    distribution_matches: Histogram<u64>,
+    send_failures: Counter<u64>,
+    buffer_flush: Histogram<u64>,
+    ingest_stage_duration: Histogram<f64>,
+    sink_events: Counter<u64>,
+    connections_rejected: Counter<u64>,
+    req_time_to_eose: Histogram<f64>,
+    req_events_sent: Histogram<u64>,
+    req_windows: Histogram<u64>,
+    req_events_filtered: Histogram<u64>,
+}
+
+impl OtelMetrics {
+    /// Create the relay's instruments on `meter`
+    pub fn new(meter: &Meter) -> Self {
+        Self {
+            events: meter
+                .u64_counter("relay.events")
+                .with_description("Client events by kind and result")
+                .build(),
+            query_duration: meter
+                .f64_histogram("relay.query.duration")
+                .with_unit("s")
+                .with_description("Duration of database queries")
+                .build(),
+            pagination_attempts: meter
+                .u64_histogram("relay.query.pagination_attempts")
+                .with_description("Pages needed to serve a REQ filter")
+                .build(),
+            distribution_matches: meter
+                .u64_histogram("relay.distribution.matches")
+                .with_description("Subscriptions matched by a distributed event")
+                .build(),
+            send_failures: meter
+                .u64_counter("relay.distribution.send_failures")
+                .with_description("Events that could not be handed to a connection")
+                .build(),
+            buffer_flush: meter
+                .u64_histogram("relay.replaceable_buffer.flush")
+                .with_description("Entries flushed by the replaceable events buffer")
+                .build(),
+            ingest_stage_duration: meter
+                .f64_histogram("relay.ingest.stage.duration")
+                .with_unit("s")
+                .with_description("Time client events spent in each ingest stage")
+                .build(),
+            sink_events: meter
+                .u64_counter("relay.sink.events")
+                .with_description("Stored events published by sinks, by sink and result")
+                .build(),
+            connections_rejected: meter
+                .u64_counter("relay.connections.rejected")
+                .with_description("Connections refused by connection limits, by limit")
+                .build(),
+            req_time_to_eose: meter
+                .f64_histogram("relay.req.time_to_eose")
+                .with_unit("s")
+                .with_description("Time from the first query of a REQ to its EOSE")
+                .build(),
+            req_events_sent: meter
+                .u64_histogram("relay.req.events_sent")
+                .with_description("Stored events sent for a REQ before EOSE")
+                .build(),
+            req_windows: meter
+                .u64_histogram("relay.req.windows")
+                .with_description("Pagination windows queried to serve a REQ")
+                .build(),
+            req_events_filtered: meter
+                .u64_histogram("relay.req.events_filtered")
+                .with_description("Scanned events of a REQ hidden by the visibility filter")
+                .build(),
+        }
+    }
+}
+
+impl RelayMetricsHandler for OtelMetrics {
+    fn record_event_saved(&self, kind: u16) {
+        self.events.add(
+            1,
+            &[
+                KeyValue::new("kind", i64::from(kind)),
+                KeyValue::new("result", "saved"),
+            ],
+        );
+    }
+
+    fn record_event_rejected(&self, kind: u16, reason: &str) {
+        self.events.add(
+            1,
+            &[
+                KeyValue::new("kind", i64::from(kind)),
+                KeyValue::new("result", reason.to_string()),
+            ],
+        );
+    }
+
+    fn record_query_duration(&self, duration: Duration, filters: usize) {
+        self.query_duration.record(
+            duration.as_secs_f64(),
+            &[KeyValue::new("filters", filters as i64)],
+        );
+    }
+
+    fn record_pagination_attempts(&self, attempts: usize) {
+        self.pagination_attempts.record(attempts as u64, &[]);
+    }
+
+    fn record_req(&self, stats: &ReqStats) {
+        self.req_time_to_eose
+            .record(stats.time_to_eose.as_secs_f64(), &[]);
+        self.req_events_sent.record(stats.events_sent as u64, &[]);
+        self.req_windows.record(stats.windows_scanned as u64, &[]);
+        self.req_events_filtered
+            .record(stats.events_filtered as u64, &[]);
+    }
+
+    fn record_distribution_matches(&self, matches: usize) {
+        self.distribution_matches.record(matches as u64, &[]);
+    }
+
+    fn increment_send_failures(&self) {
+        self.send_failures.add(1, &[]);
+    }
+
+    fn record_buffer_flush(&self, entries: usize) {
+        self.buffer_flush.record(entries as u64, &[]);
+    }
+
+    fn record_ingest_stage(&self, stage: &str, duration: Duration) {
+        self.ingest_stage_duration.record(
+            duration.as_secs_f64(),
+            &[KeyValue::new("stage", stage.to_string())],
+        );
+    }
+
+    fn record_sink_delivery(&self, sink: &str, delivered: usize, failed: usize) {
+        for (result, count) in [("delivered", delivered), ("failed", failed)] {
+            if count > 0 {
+                self.sink_events.add(
+                    count as u64,
+                    &[
+                        KeyValue::new("sink", sink.to_string()),
+                        KeyValue::new("result", result),
+                    ],
+                );
+            }
+        }
+    }
+
+    fn record_connection_rejected(&self, limit: &str) {
+        self.connections_rejected
+            .add(1, &[KeyValue::new("limit", limit.to_string())]);
+    }
+}
@@ -0,0 +1,358 @@
+//! Load shedding when the relay falls behind
+//!
+//! An [`OverloadController`] samples the relay's pressure signals every
+//! interval: the p95 total event latency of the [`LatencyBudget`], the events
+//! queued for slow connections in the [`SubscriptionRegistry`] and any queue
+//! depth registered with [`OverloadController::with_queue`]. Each signal is
+//! compared to its limit and the worst ratio sets the [`LoadLevel`]:
+//!
+//! - below 1, [`LoadLevel::Normal`]: nothing is shed
+//! - from 1, [`LoadLevel::Elevated`]: historical queries of REQs wait for one
+//!   of a few permits, so stored event scans yield to live traffic
+//! - from 2, [`LoadLevel::Overloaded`]: new REQs are refused with
+//!   `CLOSED: rate-limited:` and, when enabled, anonymous connections are
+//!   shed, least recently active first: they get a NOTICE and are closed,
+//!   freeing their buffers and tasks
+//!
+//! Memory and latency then stop growing with the offered load instead of
+//! growing without bound. Subscriptions already open keep receiving live
+//! events at every level.
+
+use crate::error::{Error, Result};
+use crate::latency::{EventStage, LatencyBudget};
+use crate::subscription_registry::SubscriptionRegistry;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// NOTICE and CLOSED reason of connections shed under overload
+pub const OVERLOAD_NOTICE: &str = "rate-limited: relay overloaded, connection shed";
+
+/// Pressure of the relay, from the worst of its signals
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoadLevel {
+    /// Every signal is within its limit
+    #[default]
+    Normal,
+    /// A signal is over its limit: historical queries are throttled
+    Elevated,
+    /// A signal is over twice its limit: new REQs are refused
+    Overloaded,
+}
+
+impl LoadLevel {
+    /// Level of the worst signal-to-limit ratio
+    fn from_pressure(pressure: f64) -> Self {
+        if pressure >= 2.0 {
+            Self::Overloaded
+        } else if pressure >= 1.0 {
+            Self::Elevated
+        } else {
+            Self::Normal
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Normal,
+            1 => Self::Elevated,
+            _ => Self::Overloaded,
+        }
+    }
+}
+
+/// Depth of a queue the controller watches
+pub type QueueProbe = Arc<dyn Fn() -> usize + Send + Sync>;
+
+/// Queue depth and the depth considered full
+#[derive(Clone)]
+struct QueueLimit {
+    name: String,
+    depth: QueueProbe,
+    limit: usize,
+}
+
+/// Relay components the controller samples, available once the relay is built
+pub(crate) struct OverloadContext {
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+    pub(crate) latency_budget: Option<LatencyBudget>,
+}
+
+/// Sheds load when event latency or queue depths exceed their limits
+///
+/// Clones share the current level, so the relay checks REQs against the
+/// level the background sampler sets.
+#[derive(Clone)]
+pub struct OverloadController {
+    interval: Duration,
+    latency_limit: Option<Duration>,
+    delivery_queue_limit: Option<usize>,
+    queues: Vec<QueueLimit>,
+    shed_anonymous: Option<usize>,
+    level: Arc<AtomicU8>,
+    historical_permits: Arc<Semaphore>,
+}
+
+impl std::fmt::Debug for OverloadController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OverloadController")
+            .field("interval", &self.interval)
+            .field("latency_limit", &self.latency_limit)
+            .field("delivery_queue_limit", &self.delivery_queue_limit)
+            .field(
+                "queues",
+                &self
+                    .queues
+                    .iter()
+                    .map(|queue| &queue.name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("level", &self.level())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for OverloadController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OverloadController {
+    /// Controller sampling every second, with no limits until some are set
+    ///
+    /// Under elevated load 4 historical queries run at a time.
+    pub fn new() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            latency_limit: None,
+            delivery_queue_limit: None,
+            queues: Vec::new(),
+            shed_anonymous: None,
+            level: Arc::new(AtomicU8::new(LoadLevel::Normal as u8)),
+            historical_permits: Arc::new(Semaphore::new(4)),
+        }
+    }
+
+    /// Sample the signals every `interval`
+    #[must_use]
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Limit the p95 latency from receiving an event to distributing it
+    ///
+    /// The relay keeps a latency budget when this is set, see
+    /// [`crate::RelayBuilder::with_latency_budget`].
+    #[must_use]
+    pub fn with_latency_limit(mut self, limit: Duration) -> Self {
+        self.latency_limit = Some(limit);
+        self
+    }
+
+    /// Limit the events queued for slow connections across the relay
+    ///
+    /// Events are only queued under [`crate::SlowConsumerPolicy::DropOldest`].
+    #[must_use]
+    pub fn with_delivery_queue_limit(mut self, limit: usize) -> Self {
+        self.delivery_queue_limit = Some(limit);
+        self
+    }
+
+    /// Limit the depth `depth` reports of a queue of the application
+    #[must_use]
+    pub fn with_queue(
+        mut self,
+        name: impl Into<String>,
+        depth: impl Fn() -> usize + Send + Sync + 'static,
+        limit: usize,
+    ) -> Self {
+        self.queues.push(QueueLimit {
+            name: name.into(),
+            depth: Arc::new(depth),
+            limit,
+        });
+        self
+    }
+
+    /// Run `permits` historical queries at a time under elevated load
+    #[must_use]
+    pub fn with_historical_concurrency(mut self, permits: usize) -> Self {
+        self.historical_permits = Arc::new(Semaphore::new(permits.max(1)));
+        self
+    }
+
+    /// Shed up to `per_interval` anonymous connections each interval while overloaded
+    ///
+    /// Connections that authenticated with NIP-42 are kept.
+    #[must_use]
+    pub fn with_anonymous_shedding(mut self, per_interval: usize) -> Self {
+        self.shed_anonymous = Some(per_interval);
+        self
+    }
+
+    /// Current load level
+    pub fn level(&self) -> LoadLevel {
+        LoadLevel::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    /// Refuse a new REQ while overloaded
+    pub fn check_req(&self) -> Result<()> {
+        if self.level() == LoadLevel::Overloaded {
+            return Err(Error::rate_limited("relay overloaded, try again later"));
+        }
+        Ok(())
+    }
+
+    /// Wait for a historical query permit under elevated load
+    ///
+    /// Returns `None`, without waiting, at the normal level.
+    pub async fn historical_permit(&self) -> Option<OwnedSemaphorePermit> {
+        if self.level() == LoadLevel::Normal {
+            return None;
+        }
+        self.historical_permits.clone().acquire_owned().await.ok()
+    }
+
+    /// Sample the signals and update the level, returning it
+    pub(crate) fn evaluate(&self, context: &OverloadContext) -> LoadLevel {
+        let mut pressure: f64 = 0.0;
+
+        if let (Some(limit), Some(budget)) = (self.latency_limit, &context.latency_budget) {
+            if let Some(total) = budget.percentiles(EventStage::Total) {
+                let limit_ms = limit.as_secs_f64() * 1000.0;
+                pressure = pressure.max(total.p95_ms / limit_ms.max(f64::EPSILON));
+            }
+        }
+        if let Some(limit) = self.delivery_queue_limit {
+            pressure = pressure.max(context.registry.queued_events() as f64 / limit.max(1) as f64);
+        }
+        for queue in &self.queues {
+            pressure = pressure.max((queue.depth)() as f64 / queue.limit.max(1) as f64);
+        }
+
+        let level = LoadLevel::from_pressure(pressure);
+        let previous = LoadLevel::from_u8(self.level.swap(level as u8, Ordering::Relaxed));
+        if level > previous {
+            warn!(
+                "Load level raised from {:?} to {:?} (pressure {:.2})",
+                previous, level, pressure
+            );
+        } else if level < previous {
+            info!("Load level lowered from {:?} to {:?}", previous, level);
+        }
+        level
+    }
+
+    /// Sample every interval and shed load until `cancellation_token` fires
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+        context: OverloadContext,
+    ) {
+        let controller = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let mut ticker = tokio::time::interval(controller.interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        let level = controller.evaluate(&context);
+                        if let (LoadLevel::Overloaded, Some(max)) = (level, controller.shed_anonymous) {
+                            context.registry.shed_anonymous(max, OVERLOAD_NOTICE);
+                        }
+                    }
+                }
+            }
+            debug!("Overload controller stopped");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_lmdb::Scope;
+    use nostr_sdk::prelude::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn test_levels_follow_queue_pressure() {
+        let depth = Arc::new(AtomicUsize::new(0));
+        let probe = depth.clone();
+        let controller = OverloadController::new()
+            .with_queue("ingest", move || probe.load(Ordering::Relaxed), 100)
+            .with_anonymous_shedding(10);
+        let context = OverloadContext {
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+            latency_budget: None,
+        };
+
+        assert_eq!(controller.evaluate(&context), LoadLevel::Normal);
+        assert!(controller.check_req().is_ok());
+        assert!(controller.historical_permit().await.is_none());
+
+        depth.store(150, Ordering::Relaxed);
+        assert_eq!(controller.evaluate(&context), LoadLevel::Elevated);
+        assert!(controller.check_req().is_ok());
+        assert!(controller.historical_permit().await.is_some());
+
+        depth.store(250, Ordering::Relaxed);
+        assert_eq!(controller.evaluate(&context), LoadLevel::Overloaded);
+        let error = controller.check_req().unwrap_err();
+        assert!(error.client_message().starts_with("rate-limited:"));
+
+        depth.store(10, Ordering::Relaxed);
+        assert_eq!(controller.clone().evaluate(&context), LoadLevel::Normal);
+        assert_eq!(controller.level(), LoadLevel::Normal);
+    }
+
+    #[tokio::test]
+    async fn test_only_anonymous_connections_are_shed() {
+        let registry = SubscriptionRegistry::new(None);
+        let scope = Arc::new(Scope::Default);
+        let (anonymous_tx, anonymous_rx) = flume::bounded(10);
+        let _anonymous = registry.register_connection(
+            "anonymous".to_string(),
+            websocket_builder::MessageSender::new(anonymous_tx, 0),
+            None,
+            scope.clone(),
+        );
+        let anonymous_token = CancellationToken::new();
+        registry.set_connection_token("anonymous", anonymous_token.clone());
+        let (authed_tx, authed_rx) = flume::bounded(10);
+        let _authed = registry.register_connection(
+            "authed".to_string(),
+            websocket_builder::MessageSender::new(authed_tx, 0),
+            Some(Keys::generate().public_key()),
+            scope,
+        );
+        let authed_token = CancellationToken::new();
+        registry.set_connection_token("authed", authed_token.clone());
+        registry
+            .add_subscription("anonymous", SubscriptionId::new("sub"), vec![Filter::new()])
+            .unwrap();
+
+        assert_eq!(registry.shed_anonymous(10, OVERLOAD_NOTICE), 1);
+
+        let messages: Vec<_> = anonymous_rx
+            .try_iter()
+            .map(|(message, _)| message)
+            .collect();
+        assert!(matches!(messages[0], RelayMessage::Closed { .. }));
+        assert!(matches!(messages[1], RelayMessage::Notice(_)));
+        assert!(authed_rx.is_empty());
+        assert!(anonymous_token.is_cancelled());
+        assert!(!authed_token.is_cancelled());
+        assert_eq!(registry.shed_anonymous(10, OVERLOAD_NOTICE), 0);
+    }
+}
@@ -0,0 +1,373 @@
+//! Windowed pagination of stored events
+//!
+//! A [`Paginator`] walks the events matching one filter from newest to
+//! oldest, one database window at a time, until the filter's limit is
+//! reached, the database runs out of events or the
+//! [`PaginationConfig::max_attempts`] windows were queried. It serves the
+//! stored events of REQs and suits anything else reading more events than a
+//! single query should return, such as exports.
+//!
+//! A full window may have left out events sharing its oldest timestamp, so
+//! before paging past that timestamp its events are fetched with boundary
+//! queries of [`BOUNDARY_WINDOWS`] more windows each. The database returns
+//! the events of one timestamp in id order, so each boundary query pages
+//! further through them until one comes back short. The first boundary query
+//! of a window doesn't count as a window, the following ones do. Events
+//! already seen are skipped, so events sharing a timestamp across a window
+//! boundary are neither repeated nor lost.
+
+use crate::database::RelayDatabase;
+use crate::error::{Error, Result};
+use crate::subscription_coordinator::PaginationConfig;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::collections::HashSet;
+
+/// Window size of filters without a limit
+pub const DEFAULT_WINDOW: usize = 500;
+
+/// Windows of events sharing a timestamp each boundary query adds
+pub const BOUNDARY_WINDOWS: usize = 2;
+
+/// Successive windows of the events matching one filter
+#[derive(Debug, Clone)]
+pub struct Paginator {
+    filter: Filter,
+    config: PaginationConfig,
+    /// Events to return, the filter's limit
+    limit: usize,
+    window: usize,
+    until: Option<Timestamp>,
+    /// Timestamp whose events are fetched before the next window
+    boundary: Option<Timestamp>,
+    /// Limit of the next boundary query
+    boundary_limit: usize,
+    /// Boundary queries of the current boundary so far
+    boundary_queries: usize,
+    seen: HashSet<EventId>,
+    returned: usize,
+    attempts: usize,
+    scanned: usize,
+    exhausted: bool,
+}
+
+impl Paginator {
+    /// Paginate the events matching `filter`, up to its limit
+    pub fn new(filter: Filter, config: PaginationConfig) -> Self {
+        let limit = filter.limit.unwrap_or(usize::MAX);
+        let window = filter.limit.unwrap_or(DEFAULT_WINDOW).max(1);
+        Self {
+            until: filter.until,
+            filter,
+            config,
+            limit,
+            window,
+            boundary: None,
+            boundary_limit: 0,
+            boundary_queries: 0,
+            seen: HashSet::new(),
+            returned: 0,
+            attempts: 0,
+            scanned: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Filter of the next window or boundary query, `None` once pagination is over
+    pub fn next_filter(&self) -> Option<Filter> {
+        if self.is_done() {
+            return None;
+        }
+        let mut filter = self.filter.clone();
+        match self.boundary {
+            Some(boundary) => {
+                filter.since = Some(boundary);
+                filter.until = Some(boundary);
+                filter.limit = Some(self.boundary_limit);
+            }
+            None => {
+                filter.until = self.until;
+                filter.limit = Some(self.window);
+            }
+        }
+        Some(filter)
+    }
+
+    /// Take in the events the database returned for [`Self::next_filter`]
+    ///
+    /// Returns the events not seen before for which `keep` returns `true`,
+    /// newest first, without exceeding the limit. Events `keep` refuses don't
+    /// count towards the limit.
+    pub fn page(
+        &mut self,
+        events: impl IntoIterator<Item = Event>,
+        keep: impl FnMut(&Event) -> bool,
+    ) -> Vec<Event> {
+        if let Some(boundary) = self.boundary {
+            let (page, fetched, _) = self.take_fresh(events, keep);
+            self.boundary_queries += 1;
+            // A full boundary query may have left out events of the timestamp
+            if fetched >= self.boundary_limit {
+                self.attempts += 1;
+                self.boundary_limit = self.boundary_limit.saturating_add(self.boundary_step());
+                return page;
+            }
+            self.boundary = None;
+            match boundary.as_u64().checked_sub(1) {
+                Some(until) => {
+                    self.until = Some(Timestamp::from(until));
+                    self.window = self.config.next_window(self.window);
+                }
+                None => self.exhausted = true,
+            }
+            return page;
+        }
+
+        self.attempts += 1;
+        let (page, fetched, oldest) = self.take_fresh(events, keep);
+        self.scanned += fetched;
+
+        // A window with fewer events than asked for holds the last ones
+        match oldest {
+            Some(oldest) if fetched >= self.window => {
+                self.boundary = Some(oldest);
+                self.boundary_limit = self.window.saturating_add(self.boundary_step());
+                self.boundary_queries = 0;
+            }
+            _ => self.exhausted = true,
+        }
+        page
+    }
+
+    /// Events of the boundary timestamp each boundary query adds
+    fn boundary_step(&self) -> usize {
+        self.window.saturating_mul(BOUNDARY_WINDOWS)
+    }
+
+    /// Events of `events` not seen before that `keep` accepts, within the
+    /// limit, with the number of events and the oldest timestamp of `events`
+    fn take_fresh(
+        &mut self,
+        events: impl IntoIterator<Item = Event>,
+        mut keep: impl FnMut(&Event) -> bool,
+    ) -> (Vec<Event>, usize, Option<Timestamp>) {
+        let mut fetched = 0;
+        let mut oldest: Option<Timestamp> = None;
+        let mut fresh = Vec::new();
+        for event in events {
+            fetched += 1;
+            oldest = Some(oldest.map_or(event.created_at, |oldest| oldest.min(event.created_at)));
+            if self.seen.insert(event.id) {
+                fresh.push(event);
+            }
+        }
+        fresh.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+
+        let mut page = Vec::new();
+        for event in fresh {
+            if self.returned >= self.limit {
+                break;
+            }
+            if keep(&event) {
+                self.returned += 1;
+                page.push(event);
+            }
+        }
+        (page, fetched, oldest)
+    }
+
+    /// Query the next window in `database` and take in its events, see [`Self::page`]
+    ///
+    /// Returns `None` once pagination is over.
+    pub async fn next_page(
+        &mut self,
+        database: &RelayDatabase,
+        scope: &Scope,
+        keep: impl FnMut(&Event) -> bool,
+    ) -> Result<Option<Vec<Event>>> {
+        let Some(filter) = self.next_filter() else {
+            return Ok(None);
+        };
+        let events = database
+            .query(vec![filter], scope)
+            .await
+            .map_err(|e| Error::database(format!("Failed to fetch events: {e:?}")))?;
+        Ok(Some(self.page(events, keep)))
+    }
+
+    /// Whether no more windows will be queried
+    ///
+    /// The first boundary query of the last window allowed is still made.
+    pub fn is_done(&self) -> bool {
+        self.exhausted
+            || self.returned >= self.limit
+            || (self.attempts >= self.config.max_attempts
+                && (self.boundary.is_none() || self.boundary_queries > 0))
+    }
+
+    /// Whether pagination stopped at the attempt limit before finding every event
+    pub fn ran_out_of_attempts(&self) -> bool {
+        !self.exhausted && self.returned < self.limit && self.attempts >= self.config.max_attempts
+    }
+
+    /// Events returned so far
+    pub fn returned(&self) -> usize {
+        self.returned
+    }
+
+    /// Windows queried so far
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+
+    /// Events the windows returned so far, boundary queries excluded
+    pub fn scanned(&self) -> usize {
+        self.scanned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscription_coordinator::WindowStrategy;
+    use proptest::prelude::*;
+
+    /// Up to `limit` events of `events` matching `filter`, newest first, as the database returns them
+    fn query(events: &[Event], filter: &Filter) -> Vec<Event> {
+        let mut matching: Vec<Event> = events
+            .iter()
+            .filter(|event| {
+                filter.match_event(event, nostr_sdk::filter::MatchEventOptions::default())
+            })
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+        matching.truncate(filter.limit.unwrap_or(usize::MAX));
+        matching
+    }
+
+    fn events(timestamps: &[u64]) -> Vec<Event> {
+        let keys = Keys::generate();
+        timestamps
+            .iter()
+            .enumerate()
+            .map(|(index, created_at)| {
+                EventBuilder::text_note(index.to_string())
+                    .custom_created_at(Timestamp::from(*created_at))
+                    .sign_with_keys(&keys)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Events the paginator returns for `filter` over `events`, keeping those `keep` accepts
+    fn paginate(
+        events: &[Event],
+        filter: Filter,
+        config: PaginationConfig,
+        keep: impl Fn(&Event) -> bool,
+    ) -> (Vec<Event>, Paginator) {
+        let mut paginator = Paginator::new(filter, config);
+        let mut returned = Vec::new();
+        while let Some(filter) = paginator.next_filter() {
+            returned.extend(paginator.page(query(events, &filter), &keep));
+        }
+        (returned, paginator)
+    }
+
+    #[test]
+    fn test_ties_across_window_boundaries_are_not_lost() {
+        // The first window of 3 holds two of the three events at 5, and the
+        // hidden newest event leaves room for the third
+        let events = events(&[10, 5, 5, 5, 1]);
+        let (returned, paginator) = paginate(
+            &events,
+            Filter::new().limit(3),
+            PaginationConfig::default(),
+            |event| event.created_at != Timestamp::from(10),
+        );
+
+        let ids: HashSet<_> = returned.iter().map(|event| event.id).collect();
+        assert_eq!(ids.len(), 3);
+        assert!(returned
+            .iter()
+            .all(|event| event.created_at == Timestamp::from(5)));
+        assert_eq!(paginator.attempts(), 1);
+        assert!(!paginator.ran_out_of_attempts());
+    }
+
+    #[test]
+    fn test_boundary_queries_are_bounded() {
+        // Twelve events share the oldest timestamp of the first window of 2
+        let mut timestamps = vec![5; 12];
+        timestamps.push(1);
+        let events = events(&timestamps);
+        let mut paginator = Paginator::new(Filter::new().limit(2), PaginationConfig::default());
+        let mut limits = Vec::new();
+        let mut returned = Vec::new();
+        while let Some(filter) = paginator.next_filter() {
+            limits.push(filter.limit.unwrap());
+            returned.extend(paginator.page(query(&events, &filter), |event| {
+                event.created_at != Timestamp::from(5)
+            }));
+        }
+
+        // Boundary queries page through the timestamp 4 events at a time
+        assert_eq!(limits, [2, 6, 10, 14, 2]);
+        assert_eq!(returned.len(), 1);
+        assert_eq!(returned[0].created_at, Timestamp::from(1));
+        assert_eq!(paginator.attempts(), 4);
+        assert!(!paginator.ran_out_of_attempts());
+    }
+
+    #[test]
+    fn test_boundary_queries_count_as_attempts() {
+        let events = events(&[5; 12]);
+        let (returned, paginator) = paginate(
+            &events,
+            Filter::new().limit(2),
+            PaginationConfig::default().with_max_attempts(2),
+            |_| false,
+        );
+        assert!(returned.is_empty());
+        assert_eq!(paginator.attempts(), 2);
+        assert!(paginator.ran_out_of_attempts());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_pages_have_no_duplicates_or_omissions(
+            timestamps in proptest::collection::vec(0u64..20, 0..60),
+            limit in 1usize..30,
+            exponential in any::<bool>(),
+            hidden in 0u64..4,
+        ) {
+            let events = events(&timestamps);
+            let config = if exponential {
+                PaginationConfig::default()
+                    .with_strategy(WindowStrategy::Exponential { factor: 2, max_window: 16 })
+            } else {
+                PaginationConfig::default().with_max_attempts(1000)
+            };
+            // Hide the events whose timestamp is a multiple of `hidden`, if any
+            let visible = |event: &Event| hidden == 0 || event.created_at.as_u64() % hidden != 0;
+            let (returned, paginator) = paginate(&events, Filter::new().limit(limit), config, visible);
+
+            let ids: HashSet<_> = returned.iter().map(|event| event.id).collect();
+            prop_assert_eq!(ids.len(), returned.len());
+            prop_assert!(returned.iter().all(visible));
+            prop_assert!(returned.windows(2).all(|pair| pair[0].created_at >= pair[1].created_at));
+
+            if !paginator.ran_out_of_attempts() {
+                let mut expected: Vec<_> = events.iter().filter(|event| visible(event)).collect();
+                expected.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+                expected.truncate(limit);
+                prop_assert_eq!(returned.len(), expected.len());
+                // Only events tied with the oldest expected one may differ
+                if let Some(oldest) = expected.last().map(|event| event.created_at) {
+                    prop_assert!(returned.iter().all(|event| event.created_at >= oldest));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Paid relay support
+//!
+//! A [`MembershipStore`] records which pubkeys paid and until when. It does not
+//! take payments itself: operators wire their LN invoices, Cashu tokens or fiat
+//! checkout to [`MembershipStore::grant`] once a payment settles. The
+//! [`PaymentPolicy`] is an [`EventPolicy`] rejecting writes from non-members
+//! with the NIP-01 `payment-required:` prefix, and carries the payments URL and
+//! fees advertised in the NIP-11 document.
+
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A paid membership
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Membership {
+    /// When the first payment was recorded
+    pub since: Timestamp,
+    /// When the membership ends, `None` for a lifetime membership
+    pub expires_at: Option<Timestamp>,
+}
+
+impl Membership {
+    /// Whether the membership is valid at `now`
+    pub fn is_active_at(&self, now: Timestamp) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// Shared memberships keyed by pubkey
+#[derive(Debug, Clone, Default)]
+pub struct MembershipStore {
+    members: Arc<DashMap<PublicKey, Membership>>,
+}
+
+impl MembershipStore {
+    /// An empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a payment for `pubkey`, valid for `duration` or forever when `None`
+    ///
+    /// Renewing an active membership extends it from its current expiry, so
+    /// paying early does not lose time.
+    pub fn grant(&self, pubkey: PublicKey, duration: Option<Duration>) -> Membership {
+        let now = Timestamp::now();
+        let mut entry = self.members.entry(pubkey).or_insert(Membership {
+            since: now,
+            expires_at: Some(now),
+        });
+
+        if !entry.is_active_at(now) {
+            *entry = Membership {
+                since: now,
+                expires_at: Some(now),
+            };
+        }
+        entry.expires_at = match (entry.expires_at, duration) {
+            (Some(expires_at), Some(duration)) => Some(expires_at + duration),
+            _ => None,
+        };
+        *entry
+    }
+
+    /// End the membership of `pubkey`, e.g. after a refund
+    pub fn revoke(&self, pubkey: &PublicKey) -> Option<Membership> {
+        self.members
+            .remove(pubkey)
+            .map(|(_, membership)| membership)
+    }
+
+    /// The membership of `pubkey`, expired or not
+    pub fn membership(&self, pubkey: &PublicKey) -> Option<Membership> {
+        self.members.get(pubkey).map(|membership| *membership)
+    }
+
+    /// Whether `pubkey` has an active membership
+    pub fn is_member(&self, pubkey: &PublicKey) -> bool {
+        let now = Timestamp::now();
+        self.members
+            .get(pubkey)
+            .is_some_and(|membership| membership.is_active_at(now))
+    }
+
+    /// Drop expired memberships, returning how many were removed
+    pub fn prune_expired(&self) -> usize {
+        let now = Timestamp::now();
+        let before = self.members.len();
+        self.members
+            .retain(|_, membership| membership.is_active_at(now));
+        before - self.members.len()
+    }
+
+    /// Number of recorded memberships, expired ones included
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Whether no membership is recorded
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+/// NIP-11 fee entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RelayFee {
+    /// Price in `unit`
+    pub amount: u64,
+    /// Currency unit, e.g. `msats`
+    pub unit: String,
+    /// Seconds the payment is valid for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+    /// Kinds the fee applies to, for publication fees
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<u16>>,
+}
+
+impl RelayFee {
+    /// A one-off fee of `amount` millisatoshis
+    pub fn msats(amount: u64) -> Self {
+        Self {
+            amount,
+            unit: "msats".to_string(),
+            period: None,
+            kinds: None,
+        }
+    }
+
+    /// Make the fee recurring every `period`
+    #[must_use]
+    pub fn with_period(mut self, period: Duration) -> Self {
+        self.period = Some(period.as_secs());
+        self
+    }
+}
+
+/// NIP-11 `fees` object
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RelayFees {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub admission: Vec<RelayFee>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subscription: Vec<RelayFee>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub publication: Vec<RelayFee>,
+}
+
+impl RelayFees {
+    /// Whether no fee is listed
+    pub fn is_empty(&self) -> bool {
+        self.admission.is_empty() && self.subscription.is_empty() && self.publication.is_empty()
+    }
+}
+
+/// Admission policy accepting events only from paying members
+#[derive(Debug, Clone)]
+pub struct PaymentPolicy {
+    memberships: MembershipStore,
+    payments_url: Option<String>,
+    fees: RelayFees,
+    free_kinds: HashSet<Kind>,
+}
+
+impl PaymentPolicy {
+    /// Require an active membership in `memberships` to publish
+    pub fn new(memberships: MembershipStore) -> Self {
+        Self {
+            memberships,
+            payments_url: None,
+            fees: RelayFees::default(),
+            free_kinds: HashSet::new(),
+        }
+    }
+
+    /// Where clients can pay, included in rejections and the NIP-11 document
+    #[must_use]
+    pub fn with_payments_url(mut self, url: impl Into<String>) -> Self {
+        self.payments_url = Some(url.into());
+        self
+    }
+
+    /// Fees advertised in the NIP-11 document
+    #[must_use]
+    pub fn with_fees(mut self, fees: RelayFees) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    /// Accept these kinds from anyone, e.g. zap requests or DMs to members
+    #[must_use]
+    pub fn with_free_kinds(mut self, kinds: impl IntoIterator<Item = Kind>) -> Self {
+        self.free_kinds.extend(kinds);
+        self
+    }
+
+    /// The memberships this policy checks
+    pub fn memberships(&self) -> &MembershipStore {
+        &self.memberships
+    }
+
+    /// The payments URL, if set
+    pub fn payments_url(&self) -> Option<&str> {
+        self.payments_url.as_deref()
+    }
+
+    /// The advertised fees
+    pub fn fees(&self) -> &RelayFees {
+        &self.fees
+    }
+}
+
+#[async_trait]
+impl EventPolicy for PaymentPolicy {
+    async fn check(
+        &self,
+        event: &Event,
+        _: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> PolicyDecision {
+        if self.free_kinds.contains(&event.kind)
+            || self.memberships.is_member(&event.pubkey)
+            || auth_pubkey.is_some_and(|pubkey| self.memberships.is_member(pubkey))
+        {
+            return PolicyDecision::Accept;
+        }
+
+        let message = match &self.payments_url {
+            Some(url) => format!("no active membership, pay at {url}"),
+            None => "no active membership".to_string(),
+        };
+        PolicyDecision::Reject(ClosedReason::PaymentRequired(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grant_extends_active_membership() {
+        let store = MembershipStore::new();
+        let pubkey = Keys::generate().public_key();
+        assert!(!store.is_member(&pubkey));
+
+        let first = store.grant(pubkey, Some(Duration::from_secs(3600)));
+        let renewed = store.grant(pubkey, Some(Duration::from_secs(3600)));
+        assert_eq!(renewed.since, first.since);
+        assert_eq!(
+            renewed.expires_at.unwrap(),
+            first.expires_at.unwrap() + Duration::from_secs(3600)
+        );
+        assert!(store.is_member(&pubkey));
+
+        // Lifetime memberships never expire
+        assert_eq!(store.grant(pubkey, None).expires_at, None);
+
+        store.revoke(&pubkey);
+        assert!(!store.is_member(&pubkey));
+    }
+
+    #[test]
+    fn test_expired_memberships_are_inactive() {
+        let store = MembershipStore::new();
+        let pubkey = Keys::generate().public_key();
+
+        store.grant(pubkey, Some(Duration::ZERO));
+        assert!(!store.is_member(&pubkey));
+        assert!(store.membership(&pubkey).is_some());
+        assert_eq!(store.prune_expired(), 1);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_policy_requires_payment() {
+        let store = MembershipStore::new();
+        let policy = PaymentPolicy::new(store.clone())
+            .with_payments_url("https://relay.example/pay")
+            .with_free_kinds([Kind::ZapRequest]);
+
+        let member = Keys::generate();
+        let stranger = Keys::generate();
+        store.grant(member.public_key(), None);
+
+        let paid = EventBuilder::text_note("hi")
+            .sign_with_keys(&member)
+            .unwrap();
+        assert_eq!(
+            policy.check(&paid, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+
+        let unpaid = EventBuilder::text_note("hi")
+            .sign_with_keys(&stranger)
+            .unwrap();
+        match policy.check(&unpaid, &Scope::Default, None).await {
+            PolicyDecision::Reject(reason) => {
+                assert_eq!(reason.prefix(), "payment-required");
+                assert!(reason.message().contains("https://relay.example/pay"));
+            }
+            other => panic!("Expected rejection, got {other:?}"),
+        }
+
+        // A member may publish events signed by others once authenticated
+        assert_eq!(
+            policy
+                .check(&unpaid, &Scope::Default, Some(&member.public_key()))
+                .await,
+            PolicyDecision::Accept
+        );
+
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "")
+            .sign_with_keys(&stranger)
+            .unwrap();
+        assert_eq!(
+            policy.check(&zap_request, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+    }
+}
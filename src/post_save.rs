@@ -0,0 +1,80 @@
+//! Follow-up commands chained after an event is saved
+//!
+//! A [`PostSaveHook`] sees every event the coordinator stored and distributed
+//! and may answer with more [`StoreCommand`]s, e.g. a NIP-29 join request
+//! producing the updated member list signed by the relay. The follow-ups are
+//! processed like any other command, and the events they store run through
+//! the hooks again.
+//!
+//! Chains are cut after [`PostSaveHooks::with_max_depth`] generations, so two
+//! hooks answering each other can't loop forever; replayed events stop a
+//! chain earlier, as duplicates aren't saved again.
+
+use crate::subscription_coordinator::StoreCommand;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+
+/// Produces commands to run after an event is stored
+#[async_trait]
+pub trait PostSaveHook: Send + Sync + std::fmt::Debug {
+    /// Commands to process now that `event` is stored in `scope`
+    async fn after_save(&self, event: &Event, scope: &Scope) -> Vec<StoreCommand>;
+}
+
+/// Ordered list of post-save hooks with the chain depth limit
+#[derive(Debug, Clone)]
+pub struct PostSaveHooks {
+    hooks: Arc<Vec<Arc<dyn PostSaveHook>>>,
+    max_depth: usize,
+}
+
+impl Default for PostSaveHooks {
+    fn default() -> Self {
+        Self {
+            hooks: Arc::new(Vec::new()),
+            max_depth: 4,
+        }
+    }
+}
+
+impl PostSaveHooks {
+    /// No hooks, chains up to 4 generations deep
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a hook, run after the ones already registered
+    #[must_use]
+    pub fn with_hook(mut self, hook: impl PostSaveHook + 'static) -> Self {
+        Arc::make_mut(&mut self.hooks).push(Arc::new(hook));
+        self
+    }
+
+    /// Drop follow-ups of events that are already `max_depth` follow-ups deep
+    #[must_use]
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Whether no hook is registered
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Generations of follow-ups processed before a chain is cut
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Commands of every hook for `event`, in hook order
+    pub async fn after_save(&self, event: &Event, scope: &Scope) -> Vec<StoreCommand> {
+        let mut commands = Vec::new();
+        for hook in self.hooks.iter() {
+            commands.extend(hook.after_save(event, scope).await);
+        }
+        commands
+    }
+}
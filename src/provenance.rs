@@ -0,0 +1,293 @@
+//! Provenance of stored events
+//!
+//! [`ProvenanceStore`] keeps, next to the events database, where each event
+//! the relay stored came from: when it was received, the [`IngestPath`] it
+//! came in by, the connection and NIP-42 pubkey that sent it and a salted
+//! hash of the sender's IP address. Operators look records up with
+//! [`ProvenanceStore::get`], or through the admin API at
+//! `GET /events/{id}/provenance`, to tell who sent an event and when without
+//! grepping logs.
+//!
+//! Records are appended as JSON lines to one file per UTC day in the store's
+//! directory, e.g. `provenance-2026-10-16.jsonl`, by a background task the
+//! relay spawns, and whole days are deleted once older than the retention,
+//! 30 days by default. The most recent records are also kept in memory, so
+//! lookups of fresh events don't read the files.
+//!
+//! IP addresses themselves are never written. Hashes tell whether two events
+//! came from the same address, and [`ProvenanceStore::hash_ip`] checks a
+//! suspected one; the salt is generated once and kept in the directory.
+
+use crate::audit_log::{daily_files, prune_daily_files, DailyWriter, PRUNE_INTERVAL};
+use crate::error::{Error, Result};
+use lru::LruCache;
+use nostr::hashes::{sha256, Hash};
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+/// Prefix of the store's file names
+const FILE_PREFIX: &str = "provenance";
+
+/// File holding the salt of IP hashes
+const SALT_FILE: &str = "ip-salt";
+
+/// Records waiting to be written before new ones are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// Records of the latest stored events kept in memory
+const RECENT_CAPACITY: usize = 10_000;
+
+/// How an event reached the relay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestPath {
+    /// EVENT message of a WebSocket client
+    #[default]
+    WebSocket,
+    /// `POST /event` of the [`EventApi`](crate::EventApi)
+    Http,
+    /// Pulled from a remote relay by a [`Puller`](crate::Puller)
+    Federation,
+}
+
+/// Where a stored event came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventProvenance {
+    pub event_id: EventId,
+    pub received_at: Timestamp,
+    pub path: IngestPath,
+    /// Connection that sent the event, for WebSocket clients
+    pub connection_id: Option<String>,
+    /// Pubkey the sender authenticated as, with NIP-42 or NIP-98
+    pub auth_pubkey: Option<PublicKey>,
+    /// Salted hash of the sender's IP address, see [`ProvenanceStore::hash_ip`]
+    pub ip_hash: Option<String>,
+    /// Relay the event was pulled from, for federation
+    pub source_relay: Option<String>,
+}
+
+impl EventProvenance {
+    /// Provenance of `event_id` received now by `path`, without sender details
+    pub fn new(event_id: EventId, path: IngestPath) -> Self {
+        Self {
+            event_id,
+            received_at: Timestamp::now(),
+            path,
+            connection_id: None,
+            auth_pubkey: None,
+            ip_hash: None,
+            source_relay: None,
+        }
+    }
+}
+
+/// Sidecar store of the provenance of stored events
+///
+/// Keep a clone to look up the records the relay writes.
+#[derive(Clone)]
+pub struct ProvenanceStore {
+    dir: Arc<PathBuf>,
+    salt: Arc<[u8]>,
+    retention: Duration,
+    recent: Arc<Mutex<LruCache<EventId, EventProvenance>>>,
+    queue: flume::Sender<EventProvenance>,
+    receiver: flume::Receiver<EventProvenance>,
+}
+
+impl std::fmt::Debug for ProvenanceStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProvenanceStore")
+            .field("dir", &self.dir)
+            .field("retention", &self.retention)
+            .field("queued", &self.queue.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProvenanceStore {
+    /// Store records in `dir`, created when missing, kept for 30 days
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            Error::internal(format!(
+                "Failed to create provenance directory {dir:?}: {e}"
+            ))
+        })?;
+
+        let salt_path = dir.join(SALT_FILE);
+        let salt = match std::fs::read(&salt_path) {
+            Ok(salt) if !salt.is_empty() => salt,
+            _ => {
+                let salt = rand::random::<[u8; 32]>().to_vec();
+                std::fs::write(&salt_path, &salt).map_err(|e| {
+                    Error::internal(format!("Failed to write IP salt {salt_path:?}: {e}"))
+                })?;
+                salt
+            }
+        };
+
+        let (queue, receiver) = flume::bounded(QUEUE_CAPACITY);
+        Ok(Self {
+            dir: Arc::new(dir),
+            salt: Arc::from(salt),
+            retention: Duration::from_secs(30 * 24 * 3600),
+            recent: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(RECENT_CAPACITY).expect("capacity is non-zero"),
+            ))),
+            queue,
+            receiver,
+        })
+    }
+
+    /// Delete the records of days older than `retention`
+    #[must_use]
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Salted hash of `address`, an IP address with or without a port
+    pub fn hash_ip(&self, address: &str) -> String {
+        let ip = address
+            .parse::<SocketAddr>()
+            .map(|address| address.ip().to_string())
+            .unwrap_or_else(|_| address.to_string());
+        let mut input = self.salt.to_vec();
+        input.extend_from_slice(ip.as_bytes());
+        sha256::Hash::hash(&input).to_string()
+    }
+
+    /// Queue `provenance` for writing, dropping it when the queue is full
+    pub fn record(&self, provenance: EventProvenance) {
+        self.recent
+            .lock()
+            .put(provenance.event_id, provenance.clone());
+        if self.queue.try_send(provenance).is_err() {
+            warn!("Provenance queue is full, dropping a record");
+        }
+    }
+
+    /// Where `event_id` came from, the latest record if it was stored again
+    pub async fn get(&self, event_id: &EventId) -> Result<Option<EventProvenance>> {
+        if let Some(provenance) = self.recent.lock().get(event_id) {
+            return Ok(Some(provenance.clone()));
+        }
+
+        let id = event_id.to_hex();
+        let mut files = daily_files(&self.dir, FILE_PREFIX).await?;
+        files.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, path) in files {
+            let contents = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| Error::internal(format!("Failed to read {path:?}: {e}")))?;
+            let found = contents
+                .lines()
+                .rev()
+                .filter(|line| line.contains(&id))
+                .filter_map(|line| serde_json::from_str::<EventProvenance>(line).ok())
+                .find(|provenance| provenance.event_id == *event_id);
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Delete the files of days older than the retention, returning how many
+    pub async fn prune(&self) -> Result<usize> {
+        prune_daily_files(&self.dir, FILE_PREFIX, self.retention).await
+    }
+
+    /// Write queued records and prune expired files until `cancellation_token` fires
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+    ) {
+        let store = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let mut writer = DailyWriter::new(Arc::clone(&store.dir), FILE_PREFIX);
+            let mut prune_ticker = tokio::time::interval(PRUNE_INTERVAL);
+            prune_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = prune_ticker.tick() => {
+                        if let Err(e) = store.prune().await {
+                            warn!("Failed to prune provenance records: {}", e);
+                        }
+                    }
+                    provenance = store.receiver.recv_async() => {
+                        let Ok(provenance) = provenance else { break };
+                        let batch = std::iter::once(provenance).chain(store.receiver.try_iter());
+                        if let Err(e) = writer.write(batch, |record| record.received_at).await {
+                            warn!("Failed to write provenance records: {}", e);
+                        }
+                    }
+                }
+            }
+            if let Err(e) = writer
+                .write(store.receiver.try_iter(), |record| record.received_at)
+                .await
+            {
+                warn!("Failed to write provenance records: {}", e);
+            }
+            debug!("Provenance writer stopped");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_records_survive_a_restart() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let store = ProvenanceStore::new(tmp_dir.path()).unwrap();
+        let task_tracker = TaskTracker::new();
+        let cancellation_token = CancellationToken::new();
+        store.spawn(&task_tracker, Some(cancellation_token.clone()));
+
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("spam")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let mut provenance = EventProvenance::new(event.id, IngestPath::WebSocket);
+        provenance.connection_id = Some("conn-1".to_string());
+        provenance.auth_pubkey = Some(keys.public_key());
+        provenance.ip_hash = Some(store.hash_ip("203.0.113.7:52100"));
+        store.record(provenance.clone());
+        assert_eq!(
+            store.get(&event.id).await.unwrap(),
+            Some(provenance.clone())
+        );
+
+        cancellation_token.cancel();
+        task_tracker.close();
+        task_tracker.wait().await;
+
+        // A new store reads the files with the same salt
+        let reopened = ProvenanceStore::new(tmp_dir.path()).unwrap();
+        assert_eq!(reopened.get(&event.id).await.unwrap(), Some(provenance));
+        assert_eq!(
+            reopened.hash_ip("203.0.113.7"),
+            store.hash_ip("203.0.113.7:1")
+        );
+        let other = EventBuilder::text_note("ham")
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert_eq!(reopened.get(&other.id).await.unwrap(), None);
+    }
+}
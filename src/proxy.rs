@@ -0,0 +1,198 @@
+//! Client address and host behind a reverse proxy
+//!
+//! When the relay runs behind nginx, Caddy or a load balancer, the socket peer
+//! is the proxy. The client IP then comes from the RFC 7239 `Forwarded` header
+//! or `X-Forwarded-For`, and the host the client asked for (which decides the
+//! subdomain scope) from `Forwarded: host=` or `X-Forwarded-Host`. Headers are
+//! only believed from peers allowed by [`ProxyHeaders`], since any client can
+//! send them.
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Which peers may set proxy headers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ProxyHeaders {
+    /// Ignore proxy headers, use the socket peer and `Host`
+    Ignore,
+    /// Believe proxy headers from any peer; only safe when clients cannot reach
+    /// the relay without going through the proxy
+    #[default]
+    TrustAll,
+    /// Believe proxy headers only from these proxy addresses
+    TrustFrom(Vec<IpAddr>),
+}
+
+impl ProxyHeaders {
+    /// Whether headers sent by `peer` are believed
+    pub fn trusts(&self, peer: IpAddr) -> bool {
+        match self {
+            Self::Ignore => false,
+            Self::TrustAll => true,
+            Self::TrustFrom(proxies) => proxies.contains(&peer),
+        }
+    }
+
+    /// IP of the client behind `peer`
+    ///
+    /// With [`Self::TrustFrom`] the forwarding chain is walked from the
+    /// closest hop and the first address that is not a trusted proxy wins, so
+    /// clients can't spoof their address by prepending entries.
+    pub fn client_ip(
+        &self,
+        peer: SocketAddr,
+        forwarded: Option<&str>,
+        x_forwarded_for: Option<&str>,
+    ) -> IpAddr {
+        if !self.trusts(peer.ip()) {
+            return peer.ip();
+        }
+
+        let chain: Vec<IpAddr> = match forwarded {
+            Some(forwarded) => forwarded_values(forwarded, "for")
+                .filter_map(|value| parse_node(&value))
+                .collect(),
+            None => x_forwarded_for
+                .into_iter()
+                .flat_map(|value| value.split(','))
+                .filter_map(parse_node)
+                .collect(),
+        };
+
+        let client = match self {
+            Self::TrustFrom(proxies) => chain
+                .iter()
+                .rev()
+                .find(|ip| !proxies.contains(*ip))
+                .or(chain.first()),
+            _ => chain.first(),
+        };
+        client.copied().unwrap_or(peer.ip())
+    }
+
+    /// Host the client connected to
+    ///
+    /// Taken from `Forwarded: host=` or `X-Forwarded-Host` when `peer` is
+    /// trusted, from `Host` otherwise.
+    pub fn host(
+        &self,
+        peer: SocketAddr,
+        forwarded: Option<&str>,
+        x_forwarded_host: Option<&str>,
+        host: Option<&str>,
+    ) -> Option<String> {
+        if self.trusts(peer.ip()) {
+            let forwarded_host = forwarded
+                .and_then(|forwarded| forwarded_values(forwarded, "host").next())
+                .or_else(|| {
+                    x_forwarded_host
+                        .and_then(|value| value.split(',').next())
+                        .map(|value| value.trim().to_string())
+                })
+                .filter(|value| !value.is_empty());
+            if forwarded_host.is_some() {
+                return forwarded_host;
+            }
+        }
+        host.map(str::to_string)
+    }
+}
+
+/// Values of `parameter` in a `Forwarded` header, one per hop, unquoted
+fn forwarded_values<'a>(
+    forwarded: &'a str,
+    parameter: &'a str,
+) -> impl Iterator<Item = String> + 'a {
+    forwarded
+        .split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(move |pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case(parameter)
+                .then(|| value.trim().trim_matches('"').to_string())
+        })
+}
+
+/// Parse an address from `Forwarded: for=` or `X-Forwarded-For`
+///
+/// Accepts `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` and `[2001:db8::1]:80`.
+/// Obfuscated identifiers such as `unknown` or `_hidden` yield `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|rest| rest.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_ip_from_headers() {
+        let proxy: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let trust_all = ProxyHeaders::TrustAll;
+        assert_eq!(
+            trust_all.client_ip(proxy, None, Some("203.0.113.7, 10.0.0.1")),
+            client
+        );
+        assert_eq!(
+            trust_all.client_ip(
+                proxy,
+                Some(r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.1"#),
+                Some("198.51.100.1")
+            ),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(trust_all.client_ip(proxy, None, None), proxy.ip());
+
+        // Spoofed leading entries are skipped when the proxies are known
+        let trust_from = ProxyHeaders::TrustFrom(vec![proxy.ip()]);
+        assert_eq!(
+            trust_from.client_ip(proxy, None, Some("1.1.1.1, 203.0.113.7")),
+            client
+        );
+        let direct: SocketAddr = "198.51.100.9:6000".parse().unwrap();
+        assert_eq!(
+            trust_from.client_ip(direct, None, Some("1.1.1.1")),
+            direct.ip()
+        );
+
+        assert_eq!(
+            ProxyHeaders::Ignore.client_ip(proxy, None, Some("203.0.113.7")),
+            proxy.ip()
+        );
+    }
+
+    #[test]
+    fn test_host_from_headers() {
+        let proxy: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let trust_all = ProxyHeaders::TrustAll;
+
+        assert_eq!(
+            trust_all.host(
+                proxy,
+                Some("for=203.0.113.7;host=alice.example.com"),
+                Some("bob.example.com"),
+                Some("internal:8080")
+            ),
+            Some("alice.example.com".to_string())
+        );
+        assert_eq!(
+            trust_all.host(proxy, None, Some("bob.example.com"), Some("internal:8080")),
+            Some("bob.example.com".to_string())
+        );
+        assert_eq!(
+            ProxyHeaders::Ignore.host(proxy, None, Some("bob.example.com"), Some("internal:8080")),
+            Some("internal:8080".to_string())
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Rewriting REQ filters before they reach the database
+//!
+//! The visibility check of an [`EventProcessor`] runs on every event a query
+//! returns, so a relay hiding most of its events (e.g. private NIP-29 groups)
+//! scans and discards thousands of rows per REQ. A [`QueryAugmenter`] narrows
+//! the filters up front instead, for example by adding an `#h` tag listing the
+//! groups the authenticated pubkey belongs to, so the database indexes do the
+//! work. The augmented filters serve both the stored events and the live
+//! subscription; `can_see_event` still runs on every event, so an augmenter
+//! only has to narrow, never to be exact.
+//!
+//! [`EventProcessor`]: crate::event_processor::EventProcessor
+
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+
+/// Rewrites the filters of a REQ before they are queried
+#[async_trait]
+pub trait QueryAugmenter: Send + Sync + std::fmt::Debug {
+    /// Filters to serve instead of `filters`, sent by a client of `scope`
+    /// authenticated as `auth_pubkey`
+    ///
+    /// Returning no filter serves nothing: the client gets EOSE right away and
+    /// no live events.
+    async fn augment(
+        &self,
+        filters: Vec<Filter>,
+        scope: &Scope,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Vec<Filter>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+    use std::collections::HashMap;
+
+    /// Restricts filters to the groups a pubkey is a member of
+    #[derive(Debug)]
+    struct GroupMembership(HashMap<PublicKey, Vec<String>>);
+
+    #[async_trait]
+    impl QueryAugmenter for GroupMembership {
+        async fn augment(
+            &self,
+            filters: Vec<Filter>,
+            _scope: &Scope,
+            auth_pubkey: Option<&PublicKey>,
+        ) -> Vec<Filter> {
+            let Some(groups) = auth_pubkey.and_then(|pubkey| self.0.get(pubkey)) else {
+                return Vec::new();
+            };
+            filters
+                .into_iter()
+                .map(|filter| {
+                    filter.custom_tags(SingleLetterTag::lowercase(Alphabet::H), groups.clone())
+                })
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_augmented_filters_are_narrowed_by_the_index() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        for group in ["public", "members", "secret"] {
+            let event = EventBuilder::new(Kind::from(9), group)
+                .tag(Tag::custom(TagKind::from("h"), [group]))
+                .sign_with_keys(&keys)
+                .unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        let member = Keys::generate().public_key();
+        let augmenter = GroupMembership(HashMap::from([(
+            member,
+            vec!["public".to_string(), "members".to_string()],
+        )]));
+
+        let filters = augmenter
+            .augment(
+                vec![Filter::new().kind(Kind::from(9))],
+                &Scope::Default,
+                Some(&member),
+            )
+            .await;
+        let mut contents: Vec<String> = database
+            .query(filters, &Scope::Default)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.content)
+            .collect();
+        contents.sort();
+        assert_eq!(contents, vec!["members", "public"]);
+
+        // Anonymous clients are served nothing
+        assert!(augmenter
+            .augment(vec![Filter::new()], &Scope::Default, None)
+            .await
+            .is_empty());
+    }
+}
@@ -0,0 +1,311 @@
+//! LRU cache for database query results
+//!
+//! Popular REQs (e.g. a global feed requested by thousands of clients) end up
+//! running the exact same filters over and over. This cache sits in front of
+//! [`RelayDatabase::query`](crate::database::RelayDatabase::query), keyed by the
+//! hash of the filters and the scope, and drops entries whenever a write could
+//! change their result.
+//!
+//! A query missing the cache runs against the database before its result is
+//! stored, so a write landing in between would leave a stale entry behind.
+//! Every invalidation bumps the scope's generation, and results are only
+//! stored if the generation read before querying is still current.
+
+use lru::LruCache;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::trace;
+
+/// Cache key: hash of the filters plus the scope they ran against
+type CacheKey = (u64, Scope);
+
+/// A cached query result
+struct CacheEntry {
+    filters: Vec<Filter>,
+    events: Arc<Vec<Event>>,
+}
+
+/// Hit/miss counters for a [`QueryCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryCacheStats {
+    /// Queries answered from the cache
+    pub hits: u64,
+    /// Queries that went to the database
+    pub misses: u64,
+    /// Entries currently cached
+    pub entries: usize,
+}
+
+/// LRU cache of query results keyed by (filter hash, scope)
+pub struct QueryCache {
+    entries: Mutex<LruCache<CacheKey, CacheEntry>>,
+    /// Invalidations per scope so far, only changed with `entries` locked
+    generations: Mutex<HashMap<Scope, u64>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl std::fmt::Debug for QueryCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryCache")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl QueryCache {
+    /// Create a cache holding at most `capacity` query results
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            generations: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up the cached result of `filters` in `scope`
+    pub fn get(&self, filters: &[Filter], scope: &Scope) -> Option<Arc<Vec<Event>>> {
+        let key = (hash_filters(filters), scope.clone());
+        let mut entries = self.entries.lock();
+
+        match entries.get(&key) {
+            // Guard against hash collisions
+            Some(entry) if entry.filters == filters => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(Arc::clone(&entry.events))
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Current generation of `scope`, to read before querying the database
+    pub fn generation(&self, scope: &Scope) -> u64 {
+        *self.generations.lock().entry(scope.clone()).or_insert(0)
+    }
+
+    /// Store the result of `filters` in `scope`, queried at `generation`
+    ///
+    /// The result is dropped if `scope` was invalidated since, as it may miss
+    /// the writes that invalidated it.
+    pub fn insert(
+        &self,
+        filters: Vec<Filter>,
+        scope: &Scope,
+        events: Arc<Vec<Event>>,
+        generation: u64,
+    ) {
+        let key = (hash_filters(&filters), scope.clone());
+        let mut entries = self.entries.lock();
+        if self.generations.lock().get(scope).copied().unwrap_or(0) != generation {
+            trace!("Not caching a query result that raced with a write");
+            return;
+        }
+        entries.put(key, CacheEntry { filters, events });
+    }
+
+    /// Drop cached results that saving `event` could change
+    ///
+    /// Deletion requests and requests to vanish remove events of any kind and
+    /// author, so they drop every result of the scope. Otherwise an entry is
+    /// dropped when any of its filters accepts the event's kind and author;
+    /// the version a replaceable or addressable event replaces shares both, so
+    /// results holding it are dropped too.
+    pub fn invalidate_event(&self, event: &Event, scope: &Scope) {
+        if event.kind == Kind::EventDeletion
+            || event.kind.as_u16() == crate::vanish::VANISH_REQUEST_KIND
+        {
+            self.invalidate_scope(scope);
+            return;
+        }
+        self.invalidate_where(scope, |entry| {
+            entry
+                .filters
+                .iter()
+                .any(|filter| filter_may_match(filter, event.kind, &event.pubkey))
+        });
+    }
+
+    /// Drop every cached result for `scope`
+    pub fn invalidate_scope(&self, scope: &Scope) {
+        self.invalidate_where(scope, |_| true);
+    }
+
+    /// Remove all cached results
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock();
+        for generation in self.generations.lock().values_mut() {
+            *generation += 1;
+        }
+        entries.clear();
+    }
+
+    /// Current hit/miss counters
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().len(),
+        }
+    }
+
+    fn invalidate_where(&self, scope: &Scope, predicate: impl Fn(&CacheEntry) -> bool) {
+        let mut entries = self.entries.lock();
+        *self.generations.lock().entry(scope.clone()).or_insert(0) += 1;
+        let stale: Vec<CacheKey> = entries
+            .iter()
+            .filter(|((_, key_scope), entry)| key_scope == scope && predicate(entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &stale {
+            entries.pop(key);
+        }
+
+        if !stale.is_empty() {
+            trace!("Invalidated {} cached query results", stale.len());
+        }
+    }
+}
+
+/// Whether an event of `kind` by `author` could show up in the filter's results
+fn filter_may_match(filter: &Filter, kind: Kind, author: &PublicKey) -> bool {
+    let kind_matches = filter
+        .kinds
+        .as_ref()
+        .is_none_or(|kinds| kinds.contains(&kind));
+    let author_matches = filter
+        .authors
+        .as_ref()
+        .is_none_or(|authors| authors.contains(author));
+
+    kind_matches && author_matches
+}
+
+/// Stable hash of a list of filters
+fn hash_filters(filters: &[Filter]) -> u64 {
+    let mut hasher = twox_hash::XxHash64::with_seed(0);
+    for filter in filters {
+        filter.as_json().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_note(keys: &Keys, content: &str) -> Event {
+        EventBuilder::text_note(content)
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    fn cache() -> QueryCache {
+        QueryCache::new(NonZeroUsize::new(8).unwrap())
+    }
+
+    #[test]
+    fn test_hit_and_miss() {
+        let cache = cache();
+        let keys = Keys::generate();
+        let filters = vec![Filter::new().kind(Kind::TextNote)];
+
+        assert!(cache.get(&filters, &Scope::Default).is_none());
+
+        let events = Arc::new(vec![text_note(&keys, "cached")]);
+        let generation = cache.generation(&Scope::Default);
+        cache.insert(filters.clone(), &Scope::Default, events, generation);
+
+        assert_eq!(cache.get(&filters, &Scope::Default).unwrap().len(), 1);
+
+        // Same filters in another scope are a different entry
+        let other_scope = Scope::named("other").unwrap();
+        assert!(cache.get(&filters, &other_scope).is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn test_invalidation_by_kind_and_author() {
+        let cache = cache();
+        let author = Keys::generate();
+        let other = Keys::generate();
+
+        let by_author = vec![Filter::new().author(author.public_key())];
+        let metadata_only = vec![Filter::new().kind(Kind::Metadata)];
+        for filters in [&by_author, &metadata_only] {
+            let generation = cache.generation(&Scope::Default);
+            cache.insert(
+                filters.clone(),
+                &Scope::Default,
+                Arc::new(Vec::new()),
+                generation,
+            );
+        }
+
+        // A note from someone else matches neither entry
+        cache.invalidate_event(&text_note(&other, "unrelated"), &Scope::Default);
+        assert_eq!(cache.stats().entries, 2);
+
+        // A note from the author invalidates only the author's feed
+        cache.invalidate_event(&text_note(&author, "new post"), &Scope::Default);
+        assert!(cache.get(&by_author, &Scope::Default).is_none());
+        assert!(cache.get(&metadata_only, &Scope::Default).is_some());
+
+        // A deletion request can remove events of any kind and author
+        let generation = cache.generation(&Scope::Default);
+        cache.insert(
+            by_author.clone(),
+            &Scope::Default,
+            Arc::new(Vec::new()),
+            generation,
+        );
+        let deletion = EventBuilder::new(Kind::EventDeletion, "")
+            .sign_with_keys(&other)
+            .unwrap();
+        cache.invalidate_event(&deletion, &Scope::Default);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_result_racing_a_write_is_not_cached() {
+        let cache = cache();
+        let keys = Keys::generate();
+        let filters = vec![Filter::new().author(keys.public_key())];
+        let other_scope = Scope::named("other").unwrap();
+
+        // The query started before the write and may miss it
+        let generation = cache.generation(&Scope::Default);
+        let other_generation = cache.generation(&other_scope);
+        cache.invalidate_event(&text_note(&keys, "new post"), &Scope::Default);
+        cache.insert(
+            filters.clone(),
+            &Scope::Default,
+            Arc::new(Vec::new()),
+            generation,
+        );
+        assert!(cache.get(&filters, &Scope::Default).is_none());
+
+        // Writes to other scopes don't matter
+        cache.insert(
+            filters.clone(),
+            &other_scope,
+            Arc::new(Vec::new()),
+            other_generation,
+        );
+        assert!(cache.get(&filters, &other_scope).is_some());
+    }
+}
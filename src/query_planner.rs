@@ -0,0 +1,259 @@
+//! Merging the filters of a REQ into fewer database queries
+//!
+//! Clients often send several filters that only differ in one set, e.g. one
+//! filter per kind for the same authors, or one per author for the same
+//! kinds. Querying them one by one scans the same index ranges again for
+//! each. [`plan_queries`] merges filters that are equal but for the ids,
+//! authors, kinds or values of a single tag into one [`QueryPlan`] querying
+//! the union, which matches exactly the events of its parts.
+//!
+//! While the merged query pages from newest to oldest, [`QueryPlan::take`]
+//! counts each event against the limit of every part it matches, and events
+//! whose parts are all full are left out. The merged query shares one
+//! paginator, with the summed limit and the same window budget, so a busy part
+//! can use up the windows before a sparse one is filled, e.g. the profile of
+//! an author next to their latest notes. When the paginator gives up, the
+//! parts still short of their limit are queried again on their own with
+//! [`QueryPlan::unfilled`], so the results stay those of the separate filters.
+
+use nostr_sdk::prelude::*;
+
+/// Set constraint of a filter that merging widens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    Ids,
+    Authors,
+    Kinds,
+    Tag(SingleLetterTag),
+}
+
+impl Dimension {
+    /// Dimensions `filter` constrains
+    fn of(filter: &Filter) -> impl Iterator<Item = Self> + '_ {
+        [
+            filter.ids.as_ref().map(|_| Self::Ids),
+            filter.authors.as_ref().map(|_| Self::Authors),
+            filter.kinds.as_ref().map(|_| Self::Kinds),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(filter.generic_tags.keys().map(|tag| Self::Tag(*tag)))
+    }
+
+    fn is_set(self, filter: &Filter) -> bool {
+        match self {
+            Self::Ids => filter.ids.is_some(),
+            Self::Authors => filter.authors.is_some(),
+            Self::Kinds => filter.kinds.is_some(),
+            Self::Tag(tag) => filter.generic_tags.contains_key(&tag),
+        }
+    }
+
+    /// `filter` without its limit nor this constraint
+    fn without(self, filter: &Filter) -> Filter {
+        let mut filter = filter.clone();
+        filter.limit = None;
+        match self {
+            Self::Ids => filter.ids = None,
+            Self::Authors => filter.authors = None,
+            Self::Kinds => filter.kinds = None,
+            Self::Tag(tag) => {
+                filter.generic_tags.remove(&tag);
+            }
+        }
+        filter
+    }
+
+    /// Add the values `other` allows in this dimension to `filter`
+    fn widen(self, filter: &mut Filter, other: &Filter) {
+        match self {
+            Self::Ids => filter
+                .ids
+                .get_or_insert_with(Default::default)
+                .extend(other.ids.iter().flatten().copied()),
+            Self::Authors => filter
+                .authors
+                .get_or_insert_with(Default::default)
+                .extend(other.authors.iter().flatten().copied()),
+            Self::Kinds => filter
+                .kinds
+                .get_or_insert_with(Default::default)
+                .extend(other.kinds.iter().flatten().copied()),
+            Self::Tag(tag) => filter
+                .generic_tags
+                .entry(tag)
+                .or_default()
+                .extend(other.generic_tags.get(&tag).into_iter().flatten().cloned()),
+        }
+    }
+}
+
+/// One database query serving one or more filters of a REQ
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlan {
+    /// Filter to query, matching the events of every part
+    pub filter: Filter,
+    /// Filters of the REQ the query serves
+    pub parts: Vec<Filter>,
+}
+
+impl QueryPlan {
+    fn new(filter: Filter) -> Self {
+        Self {
+            filter: filter.clone(),
+            parts: vec![filter],
+        }
+    }
+
+    /// Merge `filter` into the plan if the union is exactly the events of both
+    fn try_merge(&mut self, filter: &Filter) -> bool {
+        let mut unlimited = self.filter.clone();
+        unlimited.limit = None;
+        let mut other = filter.clone();
+        other.limit = None;
+
+        if unlimited != other {
+            let Some(dimension) = Dimension::of(&self.filter).find(|dimension| {
+                dimension.is_set(filter)
+                    && dimension.without(&self.filter) == dimension.without(filter)
+            }) else {
+                return false;
+            };
+            dimension.widen(&mut self.filter, filter);
+        }
+
+        self.filter.limit = match (self.filter.limit, filter.limit) {
+            (Some(limit), Some(other)) => Some(limit.saturating_add(other)),
+            _ => None,
+        };
+        self.parts.push(filter.clone());
+        true
+    }
+
+    /// Events each part still takes, its limit to start with
+    pub fn quotas(&self) -> Vec<usize> {
+        self.parts
+            .iter()
+            .map(|part| part.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// Count `event` against the quota of every part it matches, returning
+    /// whether any of them still took it
+    pub fn take(&self, quotas: &mut [usize], event: &Event) -> bool {
+        if let [quota] = quotas {
+            // A single part is the query itself
+            if *quota == 0 {
+                return false;
+            }
+            *quota -= 1;
+            return true;
+        }
+
+        let mut taken = false;
+        for (part, quota) in self.parts.iter().zip(quotas.iter_mut()) {
+            if *quota > 0
+                && part.match_event(event, nostr_sdk::filter::MatchEventOptions::default())
+            {
+                *quota -= 1;
+                taken = true;
+            }
+        }
+        taken
+    }
+
+    /// Plans querying, one by one, the limited parts still missing events
+    ///
+    /// Their limits are what each part still takes; events already served
+    /// must be skipped by the caller.
+    pub fn unfilled(&self, quotas: &[usize]) -> Vec<QueryPlan> {
+        if self.parts.len() < 2 {
+            return Vec::new();
+        }
+        self.parts
+            .iter()
+            .zip(quotas)
+            .filter(|(part, quota)| part.limit.is_some() && **quota > 0)
+            .map(|(part, quota)| QueryPlan::new(part.clone().limit(*quota)))
+            .collect()
+    }
+
+    /// Whether every part got all the events it takes
+    pub fn is_filled(quotas: &[usize]) -> bool {
+        quotas.iter().all(|quota| *quota == 0)
+    }
+}
+
+/// Group `filters` into queries, merging filters that differ in one set only
+///
+/// Plans keep the order of the first filter they serve.
+pub fn plan_queries(filters: &[Filter]) -> Vec<QueryPlan> {
+    let mut plans: Vec<QueryPlan> = Vec::new();
+    for filter in filters {
+        if !plans.iter_mut().any(|plan| plan.try_merge(filter)) {
+            plans.push(QueryPlan::new(filter.clone()));
+        }
+    }
+    plans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_differing_in_one_set_are_merged() {
+        let alice = Keys::generate();
+        let bob = Keys::generate().public_key();
+        let filters = vec![
+            Filter::new()
+                .author(alice.public_key())
+                .kind(Kind::TextNote)
+                .limit(2),
+            Filter::new()
+                .author(alice.public_key())
+                .kind(Kind::Reaction)
+                .limit(2),
+            // Differs in authors from the merged filter
+            Filter::new()
+                .author(bob)
+                .kinds([Kind::TextNote, Kind::Reaction])
+                .limit(2),
+            // Differs in both authors and kinds, merging would widen it
+            Filter::new().author(bob).kind(Kind::Metadata).limit(2),
+        ];
+
+        let plans = plan_queries(&filters);
+        assert_eq!(plans.len(), 2);
+        assert_eq!(
+            plans[0].filter,
+            Filter::new()
+                .authors([alice.public_key(), bob])
+                .kinds([Kind::TextNote, Kind::Reaction])
+                .limit(6)
+        );
+        assert_eq!(plans[0].parts, filters[..3]);
+        assert_eq!(plans[1].parts, filters[3..]);
+
+        // Each part keeps its own limit
+        let plan = &plans[0];
+        let mut quotas = plan.quotas();
+        let note = |content: &str| {
+            EventBuilder::text_note(content)
+                .sign_with_keys(&alice)
+                .unwrap()
+        };
+        assert!(plan.take(&mut quotas, &note("one")));
+        assert!(plan.take(&mut quotas, &note("two")));
+        assert!(!plan.take(&mut quotas, &note("three")));
+        assert_eq!(quotas, vec![0, 2, 2]);
+        assert!(!QueryPlan::is_filled(&quotas));
+        assert_eq!(
+            plan.unfilled(&quotas),
+            vec![
+                QueryPlan::new(filters[1].clone()),
+                QueryPlan::new(filters[2].clone())
+            ]
+        );
+    }
+}
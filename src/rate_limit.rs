@@ -0,0 +1,432 @@
+//! Token-bucket rate limiting for client messages
+//!
+//! A [`RateLimiter`] holds one token bucket per (scope, key, action), where the
+//! key is the connection, the client IP or the authenticated pubkey. Quotas are
+//! set per [`RateLimitKey`] and [`RateLimitedAction`] in a [`RateLimitConfig`],
+//! relay-wide or per scope. The limiter is enforced by
+//! [`RateLimitMiddleware`](crate::middlewares::RateLimitMiddleware), which turns a
+//! rejection into an `OK false` / `CLOSED` with the NIP-01 `rate-limited:` prefix.
+
+use crate::error::{Error, Result};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A sustained rate with a burst allowance
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Tokens refilled per second
+    pub per_second: f64,
+    /// Bucket size, the number of requests allowed back to back
+    pub burst: u32,
+}
+
+impl Quota {
+    /// `count` requests per second, with a burst of `count`
+    pub fn per_second(count: u32) -> Self {
+        Self {
+            per_second: f64::from(count),
+            burst: count.max(1),
+        }
+    }
+
+    /// `count` requests per minute, with a burst of `count`
+    pub fn per_minute(count: u32) -> Self {
+        Self {
+            per_second: f64::from(count) / 60.0,
+            burst: count.max(1),
+        }
+    }
+
+    /// Allow `burst` requests back to back
+    #[must_use]
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+}
+
+/// What a bucket is keyed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// One bucket per WebSocket connection
+    Connection,
+    /// One bucket per client IP, shared by all its connections
+    Ip,
+    /// One bucket per NIP-42 authenticated pubkey; unauthenticated clients are not limited by it
+    Pubkey,
+}
+
+impl RateLimitKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitKey::Connection => "connection",
+            RateLimitKey::Ip => "ip",
+            RateLimitKey::Pubkey => "pubkey",
+        }
+    }
+}
+
+/// What consumes tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitedAction {
+    /// Publishing an EVENT
+    Event,
+    /// Opening a subscription with REQ
+    Req,
+    /// Any client message, EVENT and REQ included
+    Message,
+}
+
+impl RateLimitedAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitedAction::Event => "events",
+            RateLimitedAction::Req => "subscriptions",
+            RateLimitedAction::Message => "messages",
+        }
+    }
+}
+
+/// Quotas per key and action
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    quotas: HashMap<(RateLimitKey, RateLimitedAction), Quota>,
+}
+
+impl RateLimitConfig {
+    /// A configuration without any limit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit `action` per `key` to `quota`
+    #[must_use]
+    pub fn limit(mut self, key: RateLimitKey, action: RateLimitedAction, quota: Quota) -> Self {
+        self.quotas.insert((key, action), quota);
+        self
+    }
+
+    /// Quota of `action` per `key`, if limited
+    pub fn quota(&self, key: RateLimitKey, action: RateLimitedAction) -> Option<Quota> {
+        self.quotas.get(&(key, action)).copied()
+    }
+
+    /// Whether no limit is configured
+    pub fn is_empty(&self) -> bool {
+        self.quotas.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Connection(String),
+    Ip(String),
+    Pubkey(PublicKey),
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(quota: Quota, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(quota.burst),
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, quota: Quota, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * quota.per_second).min(f64::from(quota.burst));
+        self.last_refill = now;
+    }
+
+    fn try_take(&mut self, quota: Quota, now: Instant) -> bool {
+        self.refill(quota, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until the next token is available
+    fn retry_after(&self, quota: Quota) -> Duration {
+        if quota.per_second <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_secs_f64(((1.0 - self.tokens) / quota.per_second).max(0.0))
+    }
+}
+
+/// Client IP of a connection id, which has the form `<ip>:<port>`
+fn ip_of(connection_id: &str) -> &str {
+    connection_id
+        .rsplit_once(':')
+        .map_or(connection_id, |(ip, _)| ip)
+}
+
+/// Shared token-bucket limiter
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    default: Arc<ArcSwap<RateLimitConfig>>,
+    scopes: Arc<HashMap<Scope, Arc<RateLimitConfig>>>,
+    buckets: Arc<DashMap<(Scope, BucketKey, RateLimitedAction), TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter applying `config` to every scope
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            default: Arc::new(ArcSwap::from_pointee(config)),
+            scopes: Arc::new(HashMap::new()),
+            buckets: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Use `config` instead of the relay-wide configuration for `scope`
+    #[must_use]
+    pub fn with_scope(mut self, scope: Scope, config: RateLimitConfig) -> Self {
+        Arc::make_mut(&mut self.scopes).insert(scope, Arc::new(config));
+        self
+    }
+
+    /// Replace the relay-wide configuration, shared by all clones
+    ///
+    /// Existing buckets keep their tokens and refill at the new rate.
+    pub fn set_config(&self, config: RateLimitConfig) {
+        self.default.store(Arc::new(config));
+    }
+
+    fn config_for(&self, scope: &Scope) -> Arc<RateLimitConfig> {
+        match self.scopes.get(scope) {
+            Some(config) => Arc::clone(config),
+            None => self.default.load_full(),
+        }
+    }
+
+    /// Take a token for `action` from every bucket that applies to the client
+    ///
+    /// Returns [`Error::RateLimited`] naming the first exhausted quota.
+    pub fn check(
+        &self,
+        scope: &Scope,
+        action: RateLimitedAction,
+        connection_id: &str,
+        auth_pubkey: Option<&PublicKey>,
+    ) -> Result<()> {
+        let config = self.config_for(scope);
+        let now = Instant::now();
+
+        for key in [
+            RateLimitKey::Connection,
+            RateLimitKey::Ip,
+            RateLimitKey::Pubkey,
+        ] {
+            let Some(quota) = config.quota(key, action) else {
+                continue;
+            };
+            let bucket_key = match key {
+                RateLimitKey::Connection => BucketKey::Connection(connection_id.to_string()),
+                RateLimitKey::Ip => BucketKey::Ip(ip_of(connection_id).to_string()),
+                RateLimitKey::Pubkey => match auth_pubkey {
+                    Some(pubkey) => BucketKey::Pubkey(*pubkey),
+                    None => continue,
+                },
+            };
+
+            let mut bucket = self
+                .buckets
+                .entry((scope.clone(), bucket_key, action))
+                .or_insert_with(|| TokenBucket::full(quota, now));
+            if !bucket.try_take(quota, now) {
+                return Err(Error::rate_limited(format!(
+                    "too many {} per {}, retry in {}ms",
+                    action.as_str(),
+                    key.as_str(),
+                    bucket.retry_after(quota).as_millis()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop the buckets of a closed connection
+    pub fn forget_connection(&self, connection_id: &str) {
+        self.buckets.retain(
+            |(_, key, _), _| !matches!(key, BucketKey::Connection(id) if id == connection_id),
+        );
+    }
+
+    /// Drop buckets that refilled completely, they are equivalent to fresh ones
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|(scope, key, action), bucket| {
+            let key = match key {
+                BucketKey::Connection(_) => RateLimitKey::Connection,
+                BucketKey::Ip(_) => RateLimitKey::Ip,
+                BucketKey::Pubkey(_) => RateLimitKey::Pubkey,
+            };
+            let Some(quota) = self.config_for(scope).quota(key, *action) else {
+                return false;
+            };
+            bucket.refill(quota, now);
+            bucket.tokens < f64::from(quota.burst)
+        });
+    }
+
+    /// Number of live buckets
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_rejection() {
+        let limiter = RateLimiter::new(RateLimitConfig::new().limit(
+            RateLimitKey::Connection,
+            RateLimitedAction::Event,
+            Quota::per_minute(60).with_burst(3),
+        ));
+
+        for _ in 0..3 {
+            limiter
+                .check(
+                    &Scope::Default,
+                    RateLimitedAction::Event,
+                    "1.2.3.4:1000",
+                    None,
+                )
+                .unwrap();
+        }
+        let err = limiter
+            .check(
+                &Scope::Default,
+                RateLimitedAction::Event,
+                "1.2.3.4:1000",
+                None,
+            )
+            .unwrap_err();
+        assert!(matches!(err, Error::RateLimited { .. }));
+
+        // Other connections and actions have their own buckets
+        limiter
+            .check(
+                &Scope::Default,
+                RateLimitedAction::Event,
+                "1.2.3.4:1001",
+                None,
+            )
+            .unwrap();
+        limiter
+            .check(
+                &Scope::Default,
+                RateLimitedAction::Req,
+                "1.2.3.4:1000",
+                None,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ip_and_pubkey_buckets_are_shared() {
+        let limiter = RateLimiter::new(
+            RateLimitConfig::new()
+                .limit(
+                    RateLimitKey::Ip,
+                    RateLimitedAction::Req,
+                    Quota::per_minute(1),
+                )
+                .limit(
+                    RateLimitKey::Pubkey,
+                    RateLimitedAction::Event,
+                    Quota::per_minute(1),
+                ),
+        );
+        let pubkey = Keys::generate().public_key();
+
+        limiter
+            .check(&Scope::Default, RateLimitedAction::Req, "10.0.0.1:1", None)
+            .unwrap();
+        assert!(limiter
+            .check(&Scope::Default, RateLimitedAction::Req, "10.0.0.1:2", None)
+            .is_err());
+
+        limiter
+            .check(
+                &Scope::Default,
+                RateLimitedAction::Event,
+                "10.0.0.2:1",
+                Some(&pubkey),
+            )
+            .unwrap();
+        assert!(limiter
+            .check(
+                &Scope::Default,
+                RateLimitedAction::Event,
+                "10.0.0.3:1",
+                Some(&pubkey)
+            )
+            .is_err());
+        // Unauthenticated clients are not subject to pubkey quotas
+        limiter
+            .check(
+                &Scope::Default,
+                RateLimitedAction::Event,
+                "10.0.0.3:1",
+                None,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_scope_override_and_prune() {
+        let tenant = Scope::named("tenant").unwrap();
+        let limiter = RateLimiter::new(RateLimitConfig::new().limit(
+            RateLimitKey::Connection,
+            RateLimitedAction::Message,
+            Quota::per_minute(1),
+        ))
+        .with_scope(tenant.clone(), RateLimitConfig::new());
+
+        for _ in 0..5 {
+            limiter
+                .check(&tenant, RateLimitedAction::Message, "1.1.1.1:1", None)
+                .unwrap();
+        }
+        limiter
+            .check(
+                &Scope::Default,
+                RateLimitedAction::Message,
+                "1.1.1.1:1",
+                None,
+            )
+            .unwrap();
+        assert_eq!(limiter.bucket_count(), 1);
+
+        // The drained bucket is kept, until its connection goes away
+        limiter.prune();
+        assert_eq!(limiter.bucket_count(), 1);
+        limiter.forget_connection("1.1.1.1:1");
+        assert_eq!(limiter.bucket_count(), 0);
+    }
+}
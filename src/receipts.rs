@@ -0,0 +1,271 @@
+//! Index of the time the relay first received each event
+//!
+//! `created_at` is chosen by the client, so an indexer or mirror catching up
+//! with `since` misses events that were published late or backdated. A
+//! [`ReceiptIndex`] records, per scope, when each event was first stored, and
+//! [`RelayDatabase::query_received`](crate::RelayDatabase::query_received)
+//! pages through stored events in that order instead. Saving an event again,
+//! e.g. one pulled back from another relay, keeps its first receipt time.
+//!
+//! The index is an LMDB environment of its own, enabled with
+//! [`RelayDatabase::with_receipt_index`](crate::RelayDatabase::with_receipt_index).
+//! Entries of deleted events are skipped by queries and only removed with
+//! their scope.
+
+use crate::error::{Error, Result};
+use heed::byteorder::BigEndian;
+use heed::types::{Bytes, Unit, U64};
+use heed::{Database, Env, EnvOpenOptions};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use std::ops::Bound;
+use std::path::Path;
+
+/// Map size of the index environment
+const MAP_SIZE: usize = 4 * 1024 * 1024 * 1024;
+
+/// First receipt time by scope and event id
+type ReceiptsById = Database<Bytes, U64<BigEndian>>;
+
+/// Scope, receipt time and event id, in receipt order
+type ReceiptsByTime = Database<Bytes, Unit>;
+
+/// A stored event with the time the relay first received it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedEvent {
+    pub received_at: Timestamp,
+    pub event: Event,
+}
+
+/// Persistent index of the first receipt time of stored events
+#[derive(Clone)]
+pub struct ReceiptIndex {
+    env: Env,
+    by_id: ReceiptsById,
+    by_time: ReceiptsByTime,
+}
+
+impl std::fmt::Debug for ReceiptIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReceiptIndex")
+            .field("path", &self.env.path())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReceiptIndex {
+    /// Open the index in `dir`, created when missing
+    ///
+    /// `dir` must not be the directory of the events database, and the index
+    /// must be opened once per process.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir).map_err(|e| {
+            Error::database(format!(
+                "Failed to create receipt index directory {dir:?}: {e}"
+            ))
+        })?;
+
+        // SAFETY: the environment is only opened once, see above
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(MAP_SIZE)
+                .max_dbs(2)
+                .open(dir)
+        }
+        .map_err(lmdb_error)?;
+        let mut wtxn = env.write_txn().map_err(lmdb_error)?;
+        let by_id = env
+            .create_database(&mut wtxn, Some("by_id"))
+            .map_err(lmdb_error)?;
+        let by_time = env
+            .create_database(&mut wtxn, Some("by_time"))
+            .map_err(lmdb_error)?;
+        wtxn.commit().map_err(lmdb_error)?;
+
+        Ok(Self {
+            env,
+            by_id,
+            by_time,
+        })
+    }
+
+    /// Record `id` as received in `scope` at `received_at`, unless it was before
+    ///
+    /// Returns the first receipt time.
+    pub fn record(&self, id: &EventId, scope: &Scope, received_at: Timestamp) -> Result<Timestamp> {
+        let prefix = scope_prefix(scope);
+        let id_key = [prefix.as_slice(), id.as_bytes()].concat();
+
+        let mut wtxn = self.env.write_txn().map_err(lmdb_error)?;
+        if let Some(first) = self.by_id.get(&wtxn, &id_key).map_err(lmdb_error)? {
+            return Ok(Timestamp::from(first));
+        }
+        self.by_id
+            .put(&mut wtxn, &id_key, &received_at.as_u64())
+            .map_err(lmdb_error)?;
+        self.by_time
+            .put(&mut wtxn, &time_key(&prefix, received_at, Some(id)), &())
+            .map_err(lmdb_error)?;
+        wtxn.commit().map_err(lmdb_error)?;
+        Ok(received_at)
+    }
+
+    /// When `id` was first received in `scope`
+    pub fn received_at(&self, id: &EventId, scope: &Scope) -> Result<Option<Timestamp>> {
+        let id_key = [scope_prefix(scope).as_slice(), id.as_bytes()].concat();
+        let rtxn = self.env.read_txn().map_err(lmdb_error)?;
+        let first = self.by_id.get(&rtxn, &id_key).map_err(lmdb_error)?;
+        Ok(first.map(Timestamp::from))
+    }
+
+    /// Ids received in `scope` from `since` on, oldest first, at most `limit`
+    ///
+    /// With `after`, the scan resumes past that id, received at `since`.
+    pub fn received_since(
+        &self,
+        scope: &Scope,
+        since: Timestamp,
+        after: Option<&EventId>,
+        limit: usize,
+    ) -> Result<Vec<(Timestamp, EventId)>> {
+        let prefix = scope_prefix(scope);
+        let start = time_key(&prefix, since, after);
+        let end = scope_end(&prefix);
+        let range = (
+            match after {
+                Some(_) => Bound::Excluded(start.as_slice()),
+                None => Bound::Included(start.as_slice()),
+            },
+            Bound::Excluded(end.as_slice()),
+        );
+
+        let rtxn = self.env.read_txn().map_err(lmdb_error)?;
+        let mut received = Vec::new();
+        for entry in self
+            .by_time
+            .range(&rtxn, &range)
+            .map_err(lmdb_error)?
+            .take(limit)
+        {
+            let (key, ()) = entry.map_err(lmdb_error)?;
+            let (time, id) = key[prefix.len()..].split_at(8);
+            let time = u64::from_be_bytes(time.try_into().expect("keys hold 8 time bytes"));
+            let id = EventId::from_slice(id)
+                .map_err(|e| Error::database(format!("Invalid id in receipt index: {e}")))?;
+            received.push((Timestamp::from(time), id));
+        }
+        Ok(received)
+    }
+
+    /// Remove every entry of `scope`, returning how many events it had
+    pub fn remove_scope(&self, scope: &Scope) -> Result<usize> {
+        let prefix = scope_prefix(scope);
+        let end = scope_end(&prefix);
+        let range = (
+            Bound::Included(prefix.as_slice()),
+            Bound::Excluded(end.as_slice()),
+        );
+
+        let mut wtxn = self.env.write_txn().map_err(lmdb_error)?;
+        let removed = self
+            .by_id
+            .delete_range(&mut wtxn, &range)
+            .map_err(lmdb_error)?;
+        self.by_time
+            .delete_range(&mut wtxn, &range)
+            .map_err(lmdb_error)?;
+        wtxn.commit().map_err(lmdb_error)?;
+        Ok(removed)
+    }
+}
+
+fn lmdb_error(e: heed::Error) -> Error {
+    Error::database(format!("Receipt index error: {e}"))
+}
+
+/// Key prefix of `scope`: its name and a NUL, which scope names never contain
+fn scope_prefix(scope: &Scope) -> Vec<u8> {
+    let mut prefix = match scope {
+        Scope::Named { name, .. } => name.as_bytes().to_vec(),
+        Scope::Default => Vec::new(),
+    };
+    prefix.push(0);
+    prefix
+}
+
+/// First key past every key of the scope with `prefix`
+fn scope_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    *end.last_mut().expect("prefixes end with NUL") = 1;
+    end
+}
+
+fn time_key(prefix: &[u8], received_at: Timestamp, id: Option<&EventId>) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(&received_at.as_u64().to_be_bytes());
+    if let Some(id) = id {
+        key.extend_from_slice(id.as_bytes());
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_receipt_is_kept_and_scanned_in_order() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let index = ReceiptIndex::open(tmp_dir.path()).unwrap();
+        let keys = Keys::generate();
+        let tenant = Scope::named("tenant").unwrap();
+
+        // Backdated events still sort by when they arrived
+        let ids: Vec<EventId> = (0..3)
+            .map(|index| {
+                EventBuilder::text_note(index.to_string())
+                    .custom_created_at(Timestamp::from(1000 - index))
+                    .sign_with_keys(&keys)
+                    .unwrap()
+                    .id
+            })
+            .collect();
+        for (offset, id) in ids.iter().enumerate() {
+            index
+                .record(id, &Scope::Default, Timestamp::from(5000 + offset as u64))
+                .unwrap();
+        }
+        index
+            .record(&ids[0], &tenant, Timestamp::from(7000))
+            .unwrap();
+
+        // Saving again keeps the first receipt
+        assert_eq!(
+            index
+                .record(&ids[0], &Scope::Default, Timestamp::from(9000))
+                .unwrap(),
+            Timestamp::from(5000)
+        );
+
+        let all = index
+            .received_since(&Scope::Default, Timestamp::from(0), None, 10)
+            .unwrap();
+        assert_eq!(
+            all.iter().map(|(_, id)| *id).collect::<Vec<_>>(),
+            ids.clone()
+        );
+        let (resume_at, resume_id) = all[0];
+        let rest = index
+            .received_since(&Scope::Default, resume_at, Some(&resume_id), 10)
+            .unwrap();
+        assert_eq!(rest, all[1..]);
+
+        assert_eq!(index.remove_scope(&tenant).unwrap(), 1);
+        assert_eq!(index.received_at(&ids[0], &tenant).unwrap(), None);
+        assert_eq!(
+            index.received_at(&ids[0], &Scope::Default).unwrap(),
+            Some(Timestamp::from(5000))
+        );
+    }
+}
@@ -1,14 +1,54 @@
 //! RelayBuilder for constructing Nostr relays with custom state
 
+use crate::audit_log::AuditLog;
+use crate::cluster::Cluster;
 use crate::config::{DatabaseConfig, RelayConfig};
+use crate::connection_hook::ConnectionHook;
+use crate::connection_limits::ConnectionLimits;
+use crate::count::CountConfig;
 use crate::crypto_helper::CryptoHelper;
+use crate::database::ReadReplicas;
+use crate::derived::DerivedIndexes;
+use crate::dm_relay::{DmRelayProcessor, DM_RELAY_LIST_KIND, DM_RELAY_NIPS, GIFT_WRAP_KIND};
+use crate::drain::ConnectionDrain;
 use crate::error::Error;
+use crate::event_policy::{EventPolicy, EventPolicyChain};
 use crate::event_processor::{DefaultRelayProcessor, EventContext, EventProcessor};
-use crate::message_converter::NostrMessageConverter;
-use crate::metrics::SubscriptionMetricsHandler;
-use crate::middlewares::MetricsHandler;
+use crate::event_sink::EventSink;
+use crate::federation::Puller;
+use crate::filter_validation::FilterValidation;
+use crate::health::HealthChecks;
+use crate::ingest::{IngestPipeline, IngestStage};
+use crate::kind_router::KindRouter;
+use crate::latency::LatencyBudget;
+use crate::message_converter::{MessageCodec, NostrMessageConverter};
+use crate::metrics::{RelayMetricsHandler, SubscriptionMetricsHandler};
+use crate::middlewares::{ClientMessageHook, MetricsHandler};
+use crate::moderation::ModerationStore;
+use crate::nip66::Nip66;
+use crate::overload::OverloadController;
+use crate::payments::PaymentPolicy;
+use crate::post_save::PostSaveHooks;
+use crate::provenance::ProvenanceStore;
+use crate::query_augmenter::QueryAugmenter;
+use crate::rate_limit::RateLimiter;
 use crate::relay_middleware::RelayMiddleware;
+use crate::reports::ReportQueue;
+use crate::resume::ResumeCursors;
+use crate::runtime_config::ReloadableConfig;
+use crate::scheduler::Scheduler;
+use crate::signer::Signer;
+use crate::slow_query_log::SlowQueryLog;
+use crate::spam::{SpamFilter, SpamScorer};
 use crate::state::NostrConnectionState;
+use crate::status::StatusPublisher;
+use crate::subscription_registry::{PriorityFn, SubscriptionPriority};
+use crate::supervisor::TaskSupervisor;
+use crate::tenants::TenantStore;
+use crate::tombstones::TombstoneStore;
+use crate::upstream::Upstream;
+use crate::vanish::{VanishRequests, VANISH_REQUEST_KIND};
+use crate::web_of_trust::WebOfTrust;
 use async_trait::async_trait;
 use nostr_sdk::prelude::*;
 use std::marker::PhantomData;
@@ -56,7 +96,9 @@ impl std::fmt::Debug for HtmlOption {
 /// By default, the builder automatically adds these middlewares:
 /// - `LoggerMiddleware` - Request/response logging
 /// - `ErrorHandlingMiddleware` - Global error handling
-/// - `EventVerifierMiddleware` - Cryptographic signature verification
+///
+/// Event limits, signature verification and deduplication are stages of the
+/// [`IngestPipeline`] run when an event is saved, see [`crate::ingest`].
 ///
 /// Additional middlewares are added when configured:
 /// - `MetricsMiddleware` - When `with_metrics()` is called
@@ -120,6 +162,8 @@ pub struct RelayBuilder<T = ()> {
     metrics_handler: Option<Arc<dyn MetricsHandler>>,
     /// Optional subscription metrics handler
     subscription_metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>,
+    /// Optional ingest, query and distribution metrics handler
+    relay_metrics_handler: Option<Arc<dyn RelayMetricsHandler>>,
     /// HTML rendering option for browser requests
     #[cfg(feature = "axum")]
     html_option: HtmlOption,
@@ -127,11 +171,116 @@ pub struct RelayBuilder<T = ()> {
     task_tracker: Option<TaskTracker>,
     /// Bare mode - skip all default middlewares
     bare_mode: bool,
+    /// NIP-17 DM relay profile
+    dm_relay: bool,
     /// Event processor - defaults to DefaultRelayProcessor
     event_processor: Arc<dyn EventProcessor<T>>,
+    /// Post-acceptance handlers by event kind
+    kind_router: Option<KindRouter>,
+    /// Optional per-stage event latency collector
+    latency_budget: Option<LatencyBudget>,
+    /// Optional token-bucket limits on client messages
+    rate_limiter: Option<RateLimiter>,
+    /// Policies every signed event must pass before it is saved
+    event_policies: EventPolicyChain,
+    /// Custom ingest stages with the built-in stage they run before
+    ingest_stages: Vec<(IngestStage, Arc<dyn EventPolicy>)>,
+    /// Scorer of the spam ingest stage
+    spam_scorer: Option<Arc<dyn SpamScorer>>,
+    /// Hooks around client message handling, in registration order
+    message_hooks: Vec<Arc<dyn ClientMessageHook>>,
+    /// Binary codec accepted besides JSON
+    codec: Option<Arc<dyn MessageCodec>>,
+    /// Optional allow/deny lists
+    moderation: Option<ModerationStore>,
+    /// Optional NIP-56 report queue
+    report_queue: Option<ReportQueue>,
+    /// NIP-62 request to vanish handling
+    vanish_requests: Option<VanishRequests>,
+    /// Index of deleted events refused at ingest
+    tombstones: Option<TombstoneStore>,
+    /// NIP-45 COUNT support
+    count: Option<CountConfig>,
+    /// Assigns connections their subscription priority
+    subscription_priorities: Option<PriorityFn>,
+    /// Checks and normalization of REQ and COUNT filters
+    filter_validation: Option<FilterValidation>,
+    /// Hooks chaining follow-up commands after stored events
+    post_save_hooks: Option<PostSaveHooks>,
+    /// Rewrites REQ filters before they are queried
+    query_augmenter: Option<Arc<dyn QueryAugmenter>>,
+    /// Resume cursors handed to reconnecting clients
+    resume_cursors: Option<ResumeCursors>,
+    /// Paid admission, advertised in NIP-11
+    payments: Option<PaymentPolicy>,
+    /// Follow-graph admission and its refresh interval
+    web_of_trust: Option<(WebOfTrust, std::time::Duration)>,
+    /// Hooks run for every stored event
+    event_sinks: Vec<Arc<dyn EventSink>>,
+    /// Callbacks run when connections open and close
+    connection_hooks: Vec<Arc<dyn ConnectionHook>>,
+    /// Caps on concurrent connections, checked before the connection hooks
+    connection_limits: Option<ConnectionLimits>,
+    /// Refuses new connections and shuts the relay down once drained
+    connection_drain: Option<ConnectionDrain>,
+    /// Relays stored events are mirrored to
+    upstreams: Vec<Upstream>,
+    /// Remote relays events are pulled from
+    pullers: Vec<Puller>,
+    /// Nodes live events are shared with
+    cluster: Option<Cluster>,
+    /// Relay-authored events published at a later time
+    scheduler: Option<Scheduler>,
+    /// Periodic relay-signed status events
+    status_publisher: Option<StatusPublisher>,
+    /// Load shedding under overload
+    overload: Option<OverloadController>,
+    /// NIP-66 self-monitoring, advertised in NIP-11
+    nip66: bool,
+    /// HTTP endpoints notified of stored events
+    #[cfg(feature = "webhooks")]
+    webhooks: Vec<crate::webhooks::Webhook>,
+    /// Kafka or NATS topics stored events are published to
+    #[cfg(any(feature = "kafka", feature = "nats"))]
+    firehoses: Vec<crate::firehose::FirehoseSink>,
+    /// Signer replacing the configured keys for relay-generated events
+    signer: Option<Arc<dyn Signer>>,
+    /// Log of REQs whose historical query was slow
+    slow_query_log: Option<SlowQueryLog>,
+    /// Persistent log of every REQ
+    audit_log: Option<AuditLog>,
+    /// Sidecar store of where stored events came from
+    provenance: Option<ProvenanceStore>,
+    /// State derived from stored events of some kinds
+    derived_indexes: Option<DerivedIndexes>,
+    /// Verify and repair the database when the last session didn't end cleanly
+    startup_repair: bool,
+    /// Restarts the relay's background tasks when they panic
+    task_supervisor: TaskSupervisor,
+    /// Liveness and readiness probes of the built relay
+    health_checks: HealthChecks,
+    /// Limits, rate limits and allowlists that can be reloaded at runtime
+    runtime_config: Option<ReloadableConfig>,
+    /// Provisioned tenants, the only named scopes served
+    tenants: Option<TenantStore>,
+    /// Admin HTTP API attached to the built relay
+    #[cfg(feature = "axum")]
+    admin_api: Option<crate::admin::AdminApi>,
+    /// HTTP endpoint publishing events into the built relay
+    #[cfg(feature = "axum")]
+    event_api: Option<crate::event_api::EventApi>,
+    /// Blossom media server attached to the built relay
+    #[cfg(feature = "media")]
+    media_server: Option<crate::media::MediaServer>,
     /// Relay information for NIP-11
     #[cfg(feature = "axum")]
     relay_info: Option<crate::handlers::RelayInfo>,
+    /// Per-scope NIP-11 overrides
+    #[cfg(feature = "axum")]
+    scope_relay_info: std::collections::HashMap<nostr_lmdb::Scope, crate::handlers::ScopeRelayInfo>,
+    /// Resolver replacing the scope config for incoming connections
+    #[cfg(feature = "axum")]
+    scope_resolver: Option<Arc<dyn crate::scope_resolver::ScopeResolver>>,
     _phantom: PhantomData<T>,
 }
 
@@ -148,13 +297,70 @@ where
             connection_counter: None,
             metrics_handler: None,
             subscription_metrics_handler: None,
+            relay_metrics_handler: None,
             #[cfg(feature = "axum")]
             html_option: HtmlOption::Default,
             task_tracker: None,
             bare_mode: false,
+            dm_relay: false,
             event_processor: Arc::new(DefaultRelayProcessor::default()),
+            kind_router: None,
+            latency_budget: None,
+            rate_limiter: None,
+            event_policies: EventPolicyChain::new(),
+            ingest_stages: Vec::new(),
+            spam_scorer: None,
+            message_hooks: Vec::new(),
+            codec: None,
+            moderation: None,
+            report_queue: None,
+            vanish_requests: None,
+            tombstones: None,
+            count: None,
+            subscription_priorities: None,
+            filter_validation: None,
+            post_save_hooks: None,
+            query_augmenter: None,
+            resume_cursors: None,
+            payments: None,
+            web_of_trust: None,
+            event_sinks: Vec::new(),
+            connection_hooks: Vec::new(),
+            connection_limits: None,
+            connection_drain: None,
+            upstreams: Vec::new(),
+            pullers: Vec::new(),
+            cluster: None,
+            scheduler: None,
+            status_publisher: None,
+            overload: None,
+            nip66: false,
+            #[cfg(feature = "webhooks")]
+            webhooks: Vec::new(),
+            #[cfg(any(feature = "kafka", feature = "nats"))]
+            firehoses: Vec::new(),
+            signer: None,
+            slow_query_log: None,
+            audit_log: None,
+            provenance: None,
+            derived_indexes: None,
+            startup_repair: false,
+            task_supervisor: TaskSupervisor::new(),
+            health_checks: HealthChecks::new(),
+            runtime_config: None,
+            tenants: None,
+            #[cfg(feature = "axum")]
+            admin_api: None,
+            #[cfg(feature = "axum")]
+            event_api: None,
+            #[cfg(feature = "media")]
+            media_server: None,
             #[cfg(feature = "axum")]
             relay_info: None,
+            #[cfg(feature = "axum")]
+            scope_relay_info: std::collections::HashMap::new(),
+            #[cfg(feature = "axum")]
+            scope_resolver: None,
             _phantom: PhantomData,
         }
     }
@@ -193,6 +399,19 @@ where
         self
     }
 
+    /// Set a handler for ingest, query and distribution metrics
+    ///
+    /// Like the subscription metrics handler it is installed globally, so it can
+    /// only be set once per process.
+    #[must_use]
+    pub fn with_relay_metrics<M>(mut self, handler: M) -> Self
+    where
+        M: RelayMetricsHandler + 'static,
+    {
+        self.relay_metrics_handler = Some(Arc::new(handler));
+        self
+    }
+
     /// Set a shared TaskTracker for all background tasks
     #[must_use]
     pub fn with_task_tracker(mut self, tracker: TaskTracker) -> Self {
@@ -214,6 +433,28 @@ where
         self
     }
 
+    /// Run as a NIP-17 DM relay
+    ///
+    /// Enables NIP-42 authentication, only accepts gift wraps (kind 1059) and
+    /// DM relay lists (kind 10050), installs a [`DmRelayProcessor`] delivering
+    /// gift wraps to their recipients only, and advertises NIPs 17, 42 and 59
+    /// with `auth_required` and `restricted_writes` in the NIP-11 document. See
+    /// [`crate::dm_relay`].
+    ///
+    /// Replaces the event processor, call `with_event_processor()` afterwards to
+    /// wrap or customize it.
+    #[must_use]
+    pub fn with_dm_relay_mode(mut self) -> Self {
+        self.dm_relay = true;
+        self.config.enable_auth = true;
+        self.config.event_limits.allowed_kinds = vec![
+            GIFT_WRAP_KIND..=GIFT_WRAP_KIND,
+            DM_RELAY_LIST_KIND..=DM_RELAY_LIST_KIND,
+        ];
+        self.event_processor = Arc::new(DmRelayProcessor::default());
+        self
+    }
+
     /// Set a custom event processor for handling relay business logic
     ///
     /// If not set, uses DefaultRelayProcessor which accepts all valid events.
@@ -228,6 +469,519 @@ where
         self
     }
 
+    /// Run host handlers for accepted events by kind
+    ///
+    /// Background handlers run after an event has been saved, inline ones in the
+    /// ingest pipeline right before persist, see [`KindRouter`].
+    #[must_use]
+    pub fn with_kind_router(mut self, kind_router: KindRouter) -> Self {
+        self.kind_router = Some(kind_router);
+        self
+    }
+
+    /// Record parse → policy → persist → distribute latencies of every event
+    ///
+    /// Keep a clone of `budget` to read p50/p95/p99 per [`crate::latency::EventStage`].
+    /// Samples are also forwarded to the metrics handler set with `with_metrics()`.
+    /// When a metrics handler is set, stage latencies are recorded even without
+    /// calling this method.
+    #[must_use]
+    pub fn with_latency_budget(mut self, budget: LatencyBudget) -> Self {
+        self.latency_budget = Some(budget);
+        self
+    }
+
+    /// Limit EVENTs, REQs and overall messages per connection, IP or pubkey
+    ///
+    /// Rejected messages are answered with `rate-limited:` OK/CLOSED responses,
+    /// see [`crate::middlewares::RateLimitMiddleware`].
+    #[must_use]
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Add a policy every signed event must pass before it is saved
+    ///
+    /// Policies run in the order they were added; the first rejection is sent to
+    /// the client as `OK false`. See [`crate::event_policy`] for built-in policies.
+    #[must_use]
+    pub fn with_event_policy(mut self, policy: impl EventPolicy + 'static) -> Self {
+        self.event_policies = self.event_policies.with_policy(policy);
+        self
+    }
+
+    /// Run `stage` right before the built-in ingest stage `before`
+    ///
+    /// Use this instead of [`Self::with_event_policy`] when a check must run at
+    /// a specific point of the write path, e.g. before signature verification or
+    /// after deduplication. See [`crate::ingest`] for the stages.
+    #[must_use]
+    pub fn with_ingest_stage(
+        mut self,
+        before: IngestStage,
+        stage: impl EventPolicy + 'static,
+    ) -> Self {
+        self.ingest_stages.push((before, Arc::new(stage)));
+        self
+    }
+
+    /// Score new events with `scorer` in the spam ingest stage
+    ///
+    /// Tagged events are queued in the report queue, if any. See
+    /// [`crate::spam`] and [`HeuristicScorer`](crate::spam::HeuristicScorer)
+    /// for the built-in scorer.
+    #[must_use]
+    pub fn with_spam_scorer(mut self, scorer: impl SpamScorer + 'static) -> Self {
+        self.spam_scorer = Some(Arc::new(scorer));
+        self
+    }
+
+    /// Moderate the relay with persistent allow/deny lists
+    ///
+    /// Events are checked against the lists at ingest, ahead of policies added
+    /// with `with_event_policy()`, and banned events are hidden from queries and
+    /// live subscriptions. Keep a clone of `moderation` to change the lists at runtime.
+    #[must_use]
+    pub fn with_moderation(mut self, moderation: ModerationStore) -> Self {
+        self.moderation = Some(moderation);
+        self
+    }
+
+    /// Queue NIP-56 reports (kind 1984) for moderators
+    ///
+    /// Stored reports are loaded when the relay is built and accepted ones are
+    /// recorded once saved. The queue is served by the admin API; see
+    /// [`crate::reports`] for hiding targets automatically.
+    #[must_use]
+    pub fn with_report_queue(mut self, report_queue: ReportQueue) -> Self {
+        self.report_queue = Some(report_queue);
+        self
+    }
+
+    /// Honour NIP-62 requests to vanish addressed to this relay
+    ///
+    /// The author's events are purged before the request is acknowledged, and
+    /// older events of theirs are refused afterwards. NIP-62 is advertised in
+    /// the NIP-11 document. See [`crate::vanish`].
+    #[must_use]
+    pub fn with_vanish_requests(mut self) -> Self {
+        self.vanish_requests = Some(VanishRequests::new(&self.config.relay_url));
+        self
+    }
+
+    /// Refuse events deleted with NIP-09 deletion requests when they are sent again
+    ///
+    /// Stored deletion requests are indexed when the relay is built and new ones
+    /// before they are acknowledged. See [`crate::tombstones`].
+    #[must_use]
+    pub fn with_tombstones(mut self, tombstones: TombstoneStore) -> Self {
+        self.tombstones = Some(tombstones);
+        self
+    }
+
+    /// Answer NIP-45 COUNT requests as configured by `count`
+    ///
+    /// With [`CountConfig::with_approximation_above`] the sketches are built in
+    /// the background once the relay is built. See [`crate::count`].
+    #[must_use]
+    pub fn with_count(mut self, count: CountConfig) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// Rank connections with `priority_fn` for fan-out overload
+    ///
+    /// Lower priorities are throttled first once an event matches more
+    /// subscriptions than [`RelayConfig::with_fanout_limit`] allows, and low
+    /// priority connections are disconnected as soon as they fall behind.
+    #[must_use]
+    pub fn with_subscription_priorities(
+        mut self,
+        priority_fn: impl Fn(Option<&PublicKey>, &nostr_lmdb::Scope) -> SubscriptionPriority
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.subscription_priorities = Some(Arc::new(priority_fn));
+        self
+    }
+
+    /// Refuse degenerate REQ and COUNT filters and normalize the others
+    ///
+    /// See [`crate::filter_validation`].
+    #[must_use]
+    pub fn with_filter_validation(mut self, filter_validation: FilterValidation) -> Self {
+        self.filter_validation = Some(filter_validation);
+        self
+    }
+
+    /// Process the commands `post_save_hooks` return after each stored event
+    ///
+    /// See [`crate::post_save`].
+    #[must_use]
+    pub fn with_post_save_hooks(mut self, post_save_hooks: PostSaveHooks) -> Self {
+        self.post_save_hooks = Some(post_save_hooks);
+        self
+    }
+
+    /// Rewrite REQ filters with `query_augmenter` before they are queried
+    ///
+    /// Lets the database indexes enforce visibility, e.g. by restricting a
+    /// REQ to the groups of the authenticated pubkey, instead of discarding
+    /// most rows in `EventProcessor::can_see_event`, which still runs.
+    #[must_use]
+    pub fn with_query_augmenter(mut self, query_augmenter: impl QueryAugmenter + 'static) -> Self {
+        self.query_augmenter = Some(Arc::new(query_augmenter));
+        self
+    }
+
+    /// Let authenticated clients resume their subscriptions after a reconnect
+    ///
+    /// Each EOSE is followed by a NOTICE carrying a resume token; see the
+    /// [`resume`](crate::resume) module for the protocol.
+    #[must_use]
+    pub fn with_resume_cursors(mut self, resume_cursors: ResumeCursors) -> Self {
+        self.resume_cursors = Some(resume_cursors);
+        self
+    }
+
+    /// Only accept events from paying members
+    ///
+    /// The policy runs after the ones added with `with_event_policy()` so free
+    /// rejections come first. Its payments URL and fees are advertised in the
+    /// NIP-11 document unless the relay info already sets them.
+    #[must_use]
+    pub fn with_payments(mut self, payments: PaymentPolicy) -> Self {
+        self.payments = Some(payments);
+        self
+    }
+
+    /// Only accept events from authors within the web of trust
+    ///
+    /// The follow graph is rebuilt when the relay starts and then every
+    /// `refresh_interval`; rejections are reported to the metrics handler.
+    #[must_use]
+    pub fn with_web_of_trust(
+        mut self,
+        web_of_trust: WebOfTrust,
+        refresh_interval: std::time::Duration,
+    ) -> Self {
+        self.web_of_trust = Some((web_of_trust, refresh_interval));
+        self
+    }
+
+    /// Hand every stored event to `sink`, e.g. to index it or push it to a queue
+    ///
+    /// Sinks run in registration order, after the event was distributed to
+    /// local subscriptions.
+    #[must_use]
+    pub fn with_event_sink(mut self, sink: impl EventSink + 'static) -> Self {
+        self.event_sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Run `hook` when WebSocket connections open and close
+    ///
+    /// Hooks run in registration order; the first refusing a connection
+    /// closes it. See [`ConnectionHook`].
+    #[must_use]
+    pub fn with_connection_hook(mut self, hook: impl ConnectionHook + 'static) -> Self {
+        self.connection_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Refuse connections beyond `limits` per client IP or in total
+    ///
+    /// Checked when a connection registers, before the connection hooks;
+    /// refused clients get a `rate-limited:` NOTICE and are disconnected.
+    #[must_use]
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = Some(limits);
+        self
+    }
+
+    /// Drain connections with `connection_drain` before rolling deploys
+    ///
+    /// Keep a clone and call [`ConnectionDrain::drain`] to refuse new
+    /// connections, fail the readiness probe and, once the open connections
+    /// left or the deadline passed, cancel the token set with
+    /// [`Self::with_cancellation_token`].
+    #[must_use]
+    pub fn with_connection_drain(mut self, connection_drain: ConnectionDrain) -> Self {
+        self.connection_drain = Some(connection_drain);
+        self
+    }
+
+    /// Mirror stored events to `upstream`
+    ///
+    /// The upstream is connected when the relay starts. Keep a clone of
+    /// `upstream` to read its stats.
+    #[must_use]
+    pub fn with_upstream(mut self, upstream: Upstream) -> Self {
+        self.upstreams.push(upstream);
+        self
+    }
+
+    /// Ingest events pulled from remote relays by `puller`
+    ///
+    /// Pulled events go through the same ingest pipeline and event policies
+    /// as client events. Keep a clone of `puller` to read its stats.
+    #[must_use]
+    pub fn with_puller(mut self, puller: Puller) -> Self {
+        self.pullers.push(puller);
+        self
+    }
+
+    /// Share stored events with the other nodes of `cluster`
+    ///
+    /// Live subscriptions on this node then see events published on any
+    /// node. Keep a clone of `cluster` to read its stats.
+    #[must_use]
+    pub fn with_cluster(mut self, cluster: Cluster) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Publish the events scheduled on `scheduler` when they are due
+    ///
+    /// Keep a clone of `scheduler` to schedule events once the relay is
+    /// built. See [`crate::scheduler`].
+    #[must_use]
+    pub fn with_scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Publish relay-signed status events with `status_publisher`
+    ///
+    /// See [`crate::status`].
+    #[must_use]
+    pub fn with_status_publisher(mut self, status_publisher: StatusPublisher) -> Self {
+        self.status_publisher = Some(status_publisher);
+        self
+    }
+
+    /// Shed load with `overload` when event latency or queues exceed its limits
+    ///
+    /// Keep a clone of `overload` to read the current level. See [`crate::overload`].
+    #[must_use]
+    pub fn with_overload_controller(mut self, overload: OverloadController) -> Self {
+        self.overload = Some(overload);
+        self
+    }
+
+    /// Publish NIP-66 discovery events for this relay and check those of monitors
+    ///
+    /// Replaces the status publisher, if any. See [`crate::nip66`].
+    #[must_use]
+    pub fn with_nip66(mut self, nip66: Nip66) -> Self {
+        self.status_publisher = Some(nip66.status_publisher());
+        self.event_policies = self.event_policies.with_policy(nip66);
+        self.nip66 = true;
+        self
+    }
+
+    /// POST stored events matching `webhook`'s filter to its endpoint
+    ///
+    /// Keep a clone of `webhook` to read its stats.
+    #[cfg(feature = "webhooks")]
+    #[must_use]
+    pub fn with_webhook(mut self, webhook: crate::webhooks::Webhook) -> Self {
+        self.webhooks.push(webhook);
+        self
+    }
+
+    /// Publish stored events to the Kafka topic or NATS subject of `firehose`
+    ///
+    /// Keep a clone of `firehose` to read its stats.
+    #[cfg(any(feature = "kafka", feature = "nats"))]
+    #[must_use]
+    pub fn with_firehose(mut self, firehose: crate::firehose::FirehoseSink) -> Self {
+        self.firehoses.push(firehose);
+        self
+    }
+
+    /// Sign relay-generated events with `signer`, e.g. a NIP-46 bunker or an HSM
+    ///
+    /// The signer's public key becomes the relay identity passed to event
+    /// processors; the configured keys are no longer used for signing.
+    #[must_use]
+    pub fn with_signer(mut self, signer: impl Signer + 'static) -> Self {
+        self.signer = Some(Arc::new(signer));
+        self
+    }
+
+    /// Log REQs whose historical query takes longer than the log's threshold
+    ///
+    /// Keep a clone of `slow_query_log` to read the recorded queries back.
+    #[must_use]
+    pub fn with_slow_query_log(mut self, slow_query_log: SlowQueryLog) -> Self {
+        self.slow_query_log = Some(slow_query_log);
+        self
+    }
+
+    /// Record every REQ in `audit_log`
+    ///
+    /// Keep a clone of `audit_log` to query the entries, or read them through
+    /// the admin API. See [`crate::audit_log`].
+    #[must_use]
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Record where each stored event came from in `provenance`
+    ///
+    /// Events published over WebSockets, the event API and pullers are
+    /// recorded. Keep a clone of `provenance` to look records up, or read
+    /// them through the admin API. See [`crate::provenance`].
+    #[must_use]
+    pub fn with_provenance(mut self, provenance: ProvenanceStore) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// Keep `derived_indexes` up to date with the events the relay stores
+    ///
+    /// The indexes are replayed from the database when the relay starts.
+    /// Keep a clone to check when they are ready. See [`crate::derived`].
+    #[must_use]
+    pub fn with_derived_indexes(mut self, derived_indexes: DerivedIndexes) -> Self {
+        self.derived_indexes = Some(derived_indexes);
+        self
+    }
+
+    /// Run [`RelayDatabase::verify_and_repair`](crate::RelayDatabase::verify_and_repair)
+    /// before serving when the previous relay session didn't end cleanly
+    ///
+    /// A session ends cleanly when the cancellation token set with
+    /// `with_cancellation_token()` is cancelled, so without one every start
+    /// after the first is checked.
+    #[must_use]
+    pub fn with_startup_repair(mut self) -> Self {
+        self.startup_repair = true;
+        self
+    }
+
+    /// Supervise the relay's background tasks with `task_supervisor`
+    ///
+    /// The relay always restarts its connection reaper and the replaceable
+    /// events buffers of connections when they panic; keep a clone of
+    /// `task_supervisor` to read their [health](TaskSupervisor::health).
+    #[must_use]
+    pub fn with_task_supervisor(mut self, task_supervisor: TaskSupervisor) -> Self {
+        self.task_supervisor = task_supervisor;
+        self
+    }
+
+    /// Probe the built relay with `health_checks`
+    ///
+    /// [`Self::into_axum_router`] serves `/healthz` and `/readyz`; otherwise
+    /// keep a clone and mount [`HealthChecks::router`] in the host app.
+    #[must_use]
+    pub fn with_health_checks(mut self, health_checks: HealthChecks) -> Self {
+        self.health_checks = health_checks;
+        self
+    }
+
+    /// Read limits, rate limits and allowlists from `runtime_config`
+    ///
+    /// Keep a clone and call [`ReloadableConfig::reload`] or
+    /// [`ReloadableConfig::watch_file`] to change them without dropping
+    /// connections. `max_limit` and `max_subscriptions` from the relay config
+    /// are ignored; the rate limits apply on top of `with_rate_limiter()`.
+    #[must_use]
+    pub fn with_runtime_config(mut self, runtime_config: ReloadableConfig) -> Self {
+        self.runtime_config = Some(runtime_config);
+        self
+    }
+
+    /// Only serve the tenants provisioned in `tenants`
+    ///
+    /// Keep a clone to create, disable and delete tenants at runtime.
+    /// Connections to unknown or disabled tenants are refused and their
+    /// events rejected; the default scope is always served.
+    #[must_use]
+    pub fn with_tenants(mut self, tenants: TenantStore) -> Self {
+        self.tenants = Some(tenants);
+        self
+    }
+
+    /// Resolve the scope of incoming connections with `scope_resolver`
+    ///
+    /// Replaces the scope config of [`RelayConfig`] for WebSocket upgrades and
+    /// per-scope NIP-11 documents. Connections the resolver refuses are
+    /// answered with `403 Forbidden`. See
+    /// [`SubdomainResolver`](crate::scope_resolver::SubdomainResolver).
+    #[cfg(feature = "axum")]
+    #[must_use]
+    pub fn with_scope_resolver(
+        mut self,
+        scope_resolver: impl crate::scope_resolver::ScopeResolver + 'static,
+    ) -> Self {
+        self.scope_resolver = Some(Arc::new(scope_resolver));
+        self
+    }
+
+    /// Serve `admin_api` for the built relay
+    ///
+    /// Keep a clone and mount [`AdminApi::router`](crate::admin::AdminApi::router)
+    /// in the host app; it answers 503 until the relay is built.
+    #[cfg(feature = "axum")]
+    #[must_use]
+    pub fn with_admin_api(mut self, admin_api: crate::admin::AdminApi) -> Self {
+        self.admin_api = Some(admin_api);
+        self
+    }
+
+    /// Publish and query events over HTTP with `event_api`
+    ///
+    /// Keep a clone and mount [`EventApi::router`](crate::event_api::EventApi::router)
+    /// in the host app; it answers 503 until the relay is built. Events go
+    /// through the same ingest pipeline and event policies as WebSocket ones,
+    /// and queries through the same scope resolution and visibility checks.
+    #[cfg(feature = "axum")]
+    #[must_use]
+    pub fn with_event_api(mut self, event_api: crate::event_api::EventApi) -> Self {
+        self.event_api = Some(event_api);
+        self
+    }
+
+    /// Host media with `media_server`
+    ///
+    /// Keep a clone and mount [`MediaServer::router`](crate::media::MediaServer::router)
+    /// in the host app; uploads answer 503 until the relay is built. Upload
+    /// authorizations go through the relay's event policies, and file metadata
+    /// events are signed with the relay keys.
+    #[cfg(feature = "media")]
+    #[must_use]
+    pub fn with_media_server(mut self, media_server: crate::media::MediaServer) -> Self {
+        self.media_server = Some(media_server);
+        self
+    }
+
+    /// Add a hook around the handling of every client message
+    ///
+    /// `before` hooks run in the order they were added, after signature
+    /// verification and authentication, and may answer or drop a message.
+    /// See [`ClientMessageHook`].
+    #[must_use]
+    pub fn with_message_hook(mut self, hook: impl ClientMessageHook + 'static) -> Self {
+        self.message_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Accept messages encoded with `codec` besides JSON
+    ///
+    /// Clients select it by offering [`MessageCodec::subprotocol`] when
+    /// connecting. Meant for closed ecosystems where both ends are controlled.
+    #[must_use]
+    pub fn with_codec(mut self, codec: impl MessageCodec + 'static) -> Self {
+        self.codec = Some(Arc::new(codec));
+        self
+    }
+
     /// Set relay information for NIP-11 responses
     #[cfg(feature = "axum")]
     #[must_use]
@@ -236,6 +990,21 @@ where
         self
     }
 
+    /// Serve a distinct NIP-11 document for a scope
+    ///
+    /// When a request's `Host` header resolves to `scope` (see [`crate::config::ScopeConfig`]),
+    /// the overrides are applied on top of the relay-wide info from `with_relay_info()`.
+    #[cfg(feature = "axum")]
+    #[must_use]
+    pub fn with_scope_relay_info(
+        mut self,
+        scope: nostr_lmdb::Scope,
+        info: crate::handlers::ScopeRelayInfo,
+    ) -> Self {
+        self.scope_relay_info.insert(scope, info);
+        self
+    }
+
     /// Transform the builder to use a different state type
     pub fn with_custom_state<U>(self) -> RelayBuilder<U>
     where
@@ -248,13 +1017,75 @@ where
             connection_counter: self.connection_counter,
             metrics_handler: None,
             subscription_metrics_handler: None,
+            relay_metrics_handler: None,
             #[cfg(feature = "axum")]
             html_option: self.html_option,
             task_tracker: self.task_tracker,
             bare_mode: self.bare_mode,
-            event_processor: Arc::new(DefaultRelayProcessor::default()), // Reset to default processor
+            dm_relay: self.dm_relay,
+            // Reset to the default processor of the profile
+            event_processor: if self.dm_relay {
+                Arc::new(DmRelayProcessor::default())
+            } else {
+                Arc::new(DefaultRelayProcessor::default())
+            },
+            kind_router: self.kind_router,
+            latency_budget: self.latency_budget,
+            rate_limiter: self.rate_limiter,
+            event_policies: self.event_policies,
+            ingest_stages: self.ingest_stages,
+            spam_scorer: self.spam_scorer,
+            message_hooks: self.message_hooks,
+            codec: self.codec,
+            moderation: self.moderation,
+            report_queue: self.report_queue,
+            vanish_requests: self.vanish_requests,
+            tombstones: self.tombstones,
+            count: self.count,
+            subscription_priorities: self.subscription_priorities,
+            filter_validation: self.filter_validation,
+            post_save_hooks: self.post_save_hooks,
+            query_augmenter: self.query_augmenter,
+            resume_cursors: self.resume_cursors,
+            payments: self.payments,
+            web_of_trust: self.web_of_trust,
+            event_sinks: self.event_sinks,
+            connection_hooks: self.connection_hooks,
+            connection_limits: self.connection_limits,
+            connection_drain: self.connection_drain,
+            upstreams: self.upstreams,
+            pullers: self.pullers,
+            cluster: self.cluster,
+            scheduler: self.scheduler,
+            status_publisher: self.status_publisher,
+            overload: self.overload,
+            nip66: self.nip66,
+            #[cfg(feature = "webhooks")]
+            webhooks: self.webhooks,
+            #[cfg(any(feature = "kafka", feature = "nats"))]
+            firehoses: self.firehoses,
+            signer: self.signer,
+            slow_query_log: self.slow_query_log,
+            audit_log: self.audit_log,
+            provenance: self.provenance,
+            derived_indexes: self.derived_indexes,
+            startup_repair: self.startup_repair,
+            task_supervisor: self.task_supervisor,
+            health_checks: self.health_checks,
+            runtime_config: self.runtime_config,
+            tenants: self.tenants,
+            #[cfg(feature = "axum")]
+            admin_api: self.admin_api,
+            #[cfg(feature = "axum")]
+            event_api: self.event_api,
+            #[cfg(feature = "media")]
+            media_server: self.media_server,
             #[cfg(feature = "axum")]
             relay_info: self.relay_info,
+            #[cfg(feature = "axum")]
+            scope_relay_info: self.scope_relay_info,
+            #[cfg(feature = "axum")]
+            scope_resolver: self.scope_resolver,
             _phantom: PhantomData,
         }
     }
@@ -348,44 +1179,152 @@ where
     where
         T: Default,
     {
-        let relay_info = self.relay_info.clone();
+        let has_relay_info = self.relay_info.is_some();
         let service = self.build_relay_service_internal().await?;
 
-        Ok(
-            move |ws: Option<websocket_builder::WebSocketUpgrade>,
-                  connect_info: axum::extract::ConnectInfo<std::net::SocketAddr>,
-                  headers: axum::http::HeaderMap| {
-                let service = service.clone();
-                let relay_info = relay_info.clone();
-
-                Box::pin(async move {
-                    // Delegate to service's axum handler
-                    if ws.is_some()
-                        || headers
-                            .get("accept")
-                            .and_then(|h| h.to_str().ok())
-                            .map(|s| s == "application/nostr+json")
-                            .unwrap_or(false)
-                    {
-                        service.axum_root_handler()(ws, connect_info, headers).await
-                    } else if relay_info.is_some() {
-                        // Serve default relay info HTML
-                        use axum::response::{Html, IntoResponse};
-                        Html(crate::handlers::default_relay_html(
-                            relay_info.as_ref().unwrap(),
-                        ))
-                        .into_response()
-                    } else {
-                        // No relay info, return 404
-                        use axum::response::IntoResponse;
-                        axum::http::StatusCode::NOT_FOUND.into_response()
-                    }
-                })
-                    as std::pin::Pin<
-                        Box<dyn std::future::Future<Output = axum::response::Response> + Send>,
-                    >
-            },
-        )
+        Ok(Self::root_handler(service, has_relay_info))
+    }
+
+    /// Build an Axum router serving the relay
+    ///
+    /// `/` answers WebSocket upgrades, NIP-11 requests and, if `with_relay_info()`
+    /// was called, the relay's HTML page. `/health` reports whether the relay
+    /// is running, see [`crate::handlers::RelayService::axum_health_handler`],
+    /// and `/healthz` and `/readyz` are its liveness and readiness probes, see
+    /// [`HealthChecks`].
+    /// Merge or nest it into the host app's router, then serve it with
+    /// `into_make_service_with_connect_info::<SocketAddr>()`.
+    #[cfg(feature = "axum")]
+    pub async fn into_axum_router(self) -> Result<axum::Router, Error>
+    where
+        T: Default,
+    {
+        let has_relay_info = self.relay_info.is_some();
+        let health_checks = self.health_checks.clone();
+        let service = self.build_relay_service_internal().await?;
+
+        Ok(axum::Router::new()
+            .route(
+                "/",
+                axum::routing::get(Self::root_handler(service.clone(), has_relay_info)),
+            )
+            .route("/health", axum::routing::get(service.axum_health_handler()))
+            .merge(health_checks.router()))
+    }
+
+    /// Build a service that can be passed to `axum::serve` directly
+    ///
+    /// # Example
+    /// ```ignore
+    /// let service = RelayBuilder::<()>::new(config).into_make_service().await?;
+    /// let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+    /// axum::serve(listener, service).await?;
+    /// ```
+    #[cfg(feature = "axum")]
+    pub async fn into_make_service(
+        self,
+    ) -> Result<
+        axum::extract::connect_info::IntoMakeServiceWithConnectInfo<
+            axum::Router,
+            std::net::SocketAddr,
+        >,
+        Error,
+    >
+    where
+        T: Default,
+    {
+        Ok(self
+            .into_axum_router()
+            .await?
+            .into_make_service_with_connect_info::<std::net::SocketAddr>())
+    }
+
+    /// Serve the relay over TLS on `addr`, enabled with the `tls` feature
+    ///
+    /// The certificate chain and private key are read from PEM files. Serving
+    /// stops gracefully, giving connections 10 seconds to close, when the
+    /// cancellation token set with `with_cancellation_token()` is cancelled.
+    #[cfg(feature = "tls")]
+    pub async fn serve_tls(
+        self,
+        addr: std::net::SocketAddr,
+        cert_path: impl AsRef<std::path::Path>,
+        key_path: impl AsRef<std::path::Path>,
+    ) -> Result<(), Error>
+    where
+        T: Default,
+    {
+        let tls_config = axum_server::tls_rustls::RustlsConfig::from_pem_file(cert_path, key_path)
+            .await
+            .map_err(|e| Error::internal(format!("Failed to load TLS certificate: {e}")))?;
+
+        let handle = axum_server::Handle::new();
+        if let Some(cancellation_token) = self.cancellation_token.clone() {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                cancellation_token.cancelled().await;
+                handle.graceful_shutdown(Some(Duration::from_secs(10)));
+            });
+        }
+
+        let service = self.into_make_service().await?;
+        axum_server::bind_rustls(addr, tls_config)
+            .handle(handle)
+            .serve(service)
+            .await
+            .map_err(|e| Error::internal(format!("TLS server failed: {e}")))
+    }
+
+    /// Root handler serving WebSocket upgrades, NIP-11 and the HTML page
+    #[cfg(feature = "axum")]
+    fn root_handler(
+        service: Arc<crate::handlers::RelayService<T>>,
+        has_relay_info: bool,
+    ) -> impl Fn(
+        Option<websocket_builder::WebSocketUpgrade>,
+        axum::extract::ConnectInfo<std::net::SocketAddr>,
+        axum::http::HeaderMap,
+    ) -> std::pin::Pin<
+        Box<dyn std::future::Future<Output = axum::response::Response> + Send>,
+    > + Clone
+           + Send
+           + 'static
+    where
+        T: Default,
+    {
+        move |ws: Option<websocket_builder::WebSocketUpgrade>,
+              connect_info: axum::extract::ConnectInfo<std::net::SocketAddr>,
+              headers: axum::http::HeaderMap| {
+            let service = service.clone();
+
+            Box::pin(async move {
+                // Delegate to service's axum handler
+                if ws.is_some()
+                    || headers
+                        .get("accept")
+                        .and_then(|h| h.to_str().ok())
+                        .map(|s| s == "application/nostr+json")
+                        .unwrap_or(false)
+                {
+                    service.axum_root_handler()(ws, connect_info, headers).await
+                } else if has_relay_info {
+                    // Serve default relay info HTML for the requested scope
+                    use axum::response::{Html, IntoResponse};
+                    let host = service.request_host(&headers, connect_info.0);
+                    Html(crate::handlers::default_relay_html(
+                        &service.relay_info_for_host(host.as_deref()),
+                    ))
+                    .into_response()
+                } else {
+                    // No relay info, return 404
+                    use axum::response::IntoResponse;
+                    axum::http::StatusCode::NOT_FOUND.into_response()
+                }
+            })
+                as std::pin::Pin<
+                    Box<dyn std::future::Future<Output = axum::response::Response> + Send>,
+                >
+        }
     }
 
     /// Build a relay service with full control over individual components
@@ -418,7 +1357,7 @@ where
     /// Internal method to build relay service
     #[cfg(feature = "axum")]
     async fn build_relay_service_internal(
-        self,
+        mut self,
     ) -> Result<Arc<crate::handlers::RelayService<T>>, Error>
     where
         T: Default,
@@ -426,28 +1365,149 @@ where
         let cancellation_token = self.cancellation_token.clone();
         let connection_counter = self.connection_counter.clone();
         let scope_config = self.config.scope_config.clone();
-        let relay_info = self
-            .relay_info
-            .clone()
-            .unwrap_or_else(|| crate::handlers::RelayInfo {
-                name: "Nostr Relay".to_string(),
-                description: "A Nostr relay".to_string(),
-                pubkey: self.config.keys.public_key().to_string(),
-                contact: "".to_string(),
-                supported_nips: vec![1],
-                software: "relay_builder".to_string(),
-                version: env!("CARGO_PKG_VERSION").to_string(),
-                icon: None,
-            });
+        let proxy_headers = self.config.proxy_headers.clone();
+        let subprotocols = self.codec.iter().map(|codec| codec.subprotocol()).collect();
+        let scope_resolver = self.connection_scope_resolver();
+        let mut scope_relay_info = std::mem::take(&mut self.scope_relay_info);
+        let mut relay_info =
+            self.relay_info
+                .clone()
+                .unwrap_or_else(|| crate::handlers::RelayInfo {
+                    name: "Nostr Relay".to_string(),
+                    description: "A Nostr relay".to_string(),
+                    pubkey: self.config.keys.public_key().to_string(),
+                    contact: "".to_string(),
+                    supported_nips: vec![1],
+                    software: "relay_builder".to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    icon: None,
+                    limitation: None,
+                    payments_url: None,
+                    fees: None,
+                });
+
+        // Advertise the configured limits unless the caller provided their own
+        if relay_info.limitation.is_none() {
+            relay_info.limitation = Some(self.configured_limitation(None));
+        }
+
+        if self.count.is_some() && !relay_info.supported_nips.contains(&45) {
+            relay_info.supported_nips.push(45);
+            relay_info.supported_nips.sort_unstable();
+        }
+
+        if self.nip66 && !relay_info.supported_nips.contains(&66) {
+            relay_info.supported_nips.push(66);
+            relay_info.supported_nips.sort_unstable();
+        }
+
+        if self.vanish_requests.is_some() && !relay_info.supported_nips.contains(&62) {
+            relay_info.supported_nips.push(62);
+            relay_info.supported_nips.sort_unstable();
+        }
+
+        if self.dm_relay {
+            for nip in DM_RELAY_NIPS {
+                if !relay_info.supported_nips.contains(&nip) {
+                    relay_info.supported_nips.push(nip);
+                }
+            }
+            relay_info.supported_nips.sort_unstable();
+            if let Some(limitation) = relay_info.limitation.as_mut() {
+                limitation.auth_required = Some(true);
+                limitation.restricted_writes = Some(true);
+            }
+        }
+
+        if let Some(payments) = &self.payments {
+            if let Some(limitation) = relay_info.limitation.as_mut() {
+                limitation.payment_required = Some(true);
+            }
+            if relay_info.payments_url.is_none() {
+                relay_info.payments_url = payments.payments_url().map(str::to_string);
+            }
+            if relay_info.fees.is_none() && !payments.fees().is_empty() {
+                relay_info.fees = Some(payments.fees().clone());
+            }
+        }
+
+        // Scopes with their own limits advertise them, with the relay-wide flags
+        for scope in self.config.scope_limits.keys() {
+            let info = scope_relay_info.entry(scope.clone()).or_default();
+            if info.limitation.is_none() {
+                let relay_wide = relay_info.limitation.clone().unwrap_or_default();
+                info.limitation = Some(crate::handlers::RelayLimitation {
+                    auth_required: relay_wide.auth_required,
+                    payment_required: relay_wide.payment_required,
+                    restricted_writes: relay_wide.restricted_writes,
+                    ..self.configured_limitation(Some(scope))
+                });
+            }
+        }
 
         let handler = self.build_internal().await?;
-        Ok(Arc::new(crate::handlers::RelayService::new(
+        let service = crate::handlers::RelayService::new(
             handler,
             relay_info,
             cancellation_token,
             connection_counter,
             scope_config,
-        )))
+        )
+        .with_scope_relay_info(scope_relay_info)
+        .with_proxy_headers(proxy_headers)
+        .with_subprotocols(subprotocols)
+        .with_scope_resolver(scope_resolver);
+        Ok(Arc::new(service))
+    }
+
+    /// NIP-11 limits set by the relay config, for `scope` or relay-wide
+    #[cfg(feature = "axum")]
+    fn configured_limitation(
+        &self,
+        scope: Option<&nostr_lmdb::Scope>,
+    ) -> crate::handlers::RelayLimitation {
+        let (max_subscriptions, max_limit, event_limits) = match scope {
+            Some(scope) => (
+                self.config.max_subscriptions_for(scope),
+                self.config.max_limit_for(scope),
+                self.config.event_limits_for(scope),
+            ),
+            None => (
+                self.config.max_subscriptions,
+                self.config.max_limit,
+                &self.config.event_limits,
+            ),
+        };
+        crate::handlers::RelayLimitation {
+            max_subscriptions: Some(max_subscriptions),
+            max_limit: Some(max_limit),
+            auth_required: None,
+            payment_required: None,
+            restricted_writes: None,
+            max_message_length: self
+                .config
+                .websocket_config
+                .max_message_size
+                .or(event_limits.max_event_size),
+            max_event_tags: event_limits.max_tags,
+            max_content_length: event_limits.max_content_length,
+        }
+    }
+
+    /// Resolver of the scope of incoming connections and HTTP requests
+    ///
+    /// Tenants, when provisioned, restrict the configured resolver or the
+    /// scope config.
+    #[cfg(feature = "axum")]
+    fn connection_scope_resolver(&self) -> Arc<dyn crate::scope_resolver::ScopeResolver> {
+        let resolver = self
+            .scope_resolver
+            .clone()
+            .unwrap_or_else(|| Arc::new(self.config.scope_config.clone()));
+        match &self.tenants {
+            Some(tenants) => Arc::new(tenants.resolver(resolver)),
+            None => resolver,
+        }
     }
 
     // ===== Internal Methods =====
@@ -463,6 +1523,9 @@ where
         if let Some(handler) = self.subscription_metrics_handler.clone() {
             crate::global_metrics::set_subscription_metrics_handler(handler);
         }
+        if let Some(handler) = self.relay_metrics_handler.clone() {
+            crate::global_metrics::set_relay_metrics_handler(handler);
+        }
 
         let task_tracker = self.task_tracker.take().unwrap_or_default();
 
@@ -472,10 +1535,73 @@ where
         let _scope_config = self.config.scope_config.clone();
 
         // Create subscription registry
-        let subscription_registry =
-            Arc::new(crate::subscription_registry::SubscriptionRegistry::new(
-                self.subscription_metrics_handler.clone(),
-            ));
+        let mut subscription_registry = crate::subscription_registry::SubscriptionRegistry::new(
+            self.subscription_metrics_handler.clone(),
+        )
+        .with_supervised_distribution_shards(self.config.distribution_shards, &self.task_supervisor)
+        .with_slow_consumer_policy(self.config.slow_consumer_policy);
+        if let Some(priority_fn) = self.subscription_priorities.clone() {
+            subscription_registry = subscription_registry.with_priorities(priority_fn);
+        }
+        if let Some(limit) = self.config.fanout_limit {
+            subscription_registry = subscription_registry.with_fanout_limit(limit);
+        }
+        if let Some(limit) = self.config.subscription_memory_limit {
+            subscription_registry = subscription_registry.with_memory_limit(limit);
+        }
+        if let Some(moderation) = self.moderation.clone() {
+            subscription_registry = subscription_registry
+                .with_shadow_bans(Arc::new(move |pubkey| moderation.is_shadow_banned(pubkey)));
+        }
+        if let Some(derived_indexes) = self.derived_indexes.clone() {
+            subscription_registry = subscription_registry.with_sink(Arc::new(derived_indexes));
+        }
+        for sink in std::mem::take(&mut self.event_sinks) {
+            subscription_registry = subscription_registry.with_sink(sink);
+        }
+        if let Some(limits) = self.connection_limits.clone() {
+            subscription_registry = subscription_registry.with_connection_hook(Arc::new(limits));
+        }
+        if let Some(drain) = self.connection_drain.clone() {
+            subscription_registry = subscription_registry.with_connection_hook(Arc::new(drain));
+        }
+        for hook in std::mem::take(&mut self.connection_hooks) {
+            subscription_registry = subscription_registry.with_connection_hook(hook);
+        }
+        for upstream in std::mem::take(&mut self.upstreams) {
+            upstream.spawn(&task_tracker, self.cancellation_token.clone());
+            subscription_registry = subscription_registry.with_sink(Arc::new(upstream));
+        }
+        if let Some(cluster) = &self.cluster {
+            subscription_registry = subscription_registry.with_sink(Arc::new(cluster.clone()));
+        }
+        if let Some(count) = self.count.as_ref().filter(|count| count.is_approximate()) {
+            subscription_registry = subscription_registry.with_sink(Arc::new(count.clone()));
+        }
+        #[cfg(feature = "webhooks")]
+        for webhook in std::mem::take(&mut self.webhooks) {
+            webhook.spawn(&task_tracker, self.cancellation_token.clone());
+            subscription_registry = subscription_registry.with_sink(Arc::new(webhook));
+        }
+        #[cfg(any(feature = "kafka", feature = "nats"))]
+        for firehose in std::mem::take(&mut self.firehoses) {
+            firehose.spawn(&task_tracker, self.cancellation_token.clone());
+            subscription_registry = subscription_registry.with_sink(Arc::new(firehose));
+        }
+        let subscription_registry = Arc::new(subscription_registry);
+        if let Some(drain) = &self.connection_drain {
+            drain.attach(crate::drain::DrainContext {
+                registry: subscription_registry.clone(),
+                cancellation_token: self.cancellation_token.clone(),
+            });
+        }
+        subscription_registry.supervise_reaper(
+            &self.task_supervisor,
+            &task_tracker,
+            crate::subscription_registry::DEFAULT_REAPER_INTERVAL,
+            self.config.idle_timeout.map(std::time::Duration::from_secs),
+            self.cancellation_token.clone(),
+        );
 
         // Only create crypto worker if we're creating a new database
         let database = self.config.database.take();
@@ -486,7 +1612,9 @@ where
                 let crypto_helper = CryptoHelper::new(keys);
                 (db, crypto_helper)
             }
-            Some(database_config @ DatabaseConfig::Path(_)) => {
+            Some(
+                database_config @ (DatabaseConfig::Path(_) | DatabaseConfig::ShardedPath { .. }),
+            ) => {
                 // Create new database with keys
                 let keys = Arc::new(self.config.keys.clone());
                 let crypto_helper = CryptoHelper::new(keys);
@@ -505,6 +1633,40 @@ where
                 ));
             }
         };
+        let crypto_helper = match self.signer.clone() {
+            Some(signer) => crypto_helper.with_signer(signer),
+            None => crypto_helper,
+        };
+
+        if self.startup_repair {
+            if database.begin_session()? {
+                warn!("Previous relay session didn't end cleanly, verifying the database");
+                database.verify_and_repair().await?;
+            }
+            let database = database.clone();
+            let cancellation_token = self.cancellation_token.clone().unwrap_or_default();
+            task_tracker.spawn(async move {
+                cancellation_token.cancelled().await;
+                if let Err(e) = database.end_session() {
+                    warn!("Failed to end the database session: {}", e);
+                }
+            });
+        }
+
+        // Open read replicas used for historical queries
+        let read_replicas = std::mem::take(&mut self.config.read_replicas)
+            .into_iter()
+            .map(|replica_config| {
+                RelayConfig::create_database_from_config(
+                    replica_config,
+                    &self.config.websocket_config,
+                    self.config.max_subscriptions,
+                    Some(task_tracker.clone()),
+                    self.cancellation_token.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let read_replicas = ReadReplicas::new(read_replicas);
 
         let custom_middlewares = std::mem::take(&mut self.middlewares);
 
@@ -525,6 +1687,10 @@ where
                 self.0.can_see_event(event, custom_state, context)
             }
 
+            fn can_hide_events(&self) -> bool {
+                self.0.can_hide_events()
+            }
+
             fn verify_filters(
                 &self,
                 filters: &[Filter],
@@ -556,26 +1722,347 @@ where
             None
         };
 
+        let latency_budget = match (self.latency_budget.take(), self.metrics_handler.clone()) {
+            (Some(budget), Some(handler)) => Some(budget.with_metrics_handler(handler)),
+            (Some(budget), None) => Some(budget),
+            (None, Some(handler)) => Some(LatencyBudget::default().with_metrics_handler(handler)),
+            // The overload controller watches event latency even without metrics
+            (None, None) => self.overload.as_ref().map(|_| LatencyBudget::default()),
+        };
+
+        // Tenants, moderation and allowlists first, then custom policies, web of trust and payment
+        let mut event_policies = EventPolicyChain::new();
+        if let Some(tenants) = &self.tenants {
+            tenants.attach(subscription_registry.clone());
+            event_policies = event_policies.with_policy(tenants.clone());
+        }
+        if let Some(moderation) = &self.moderation {
+            event_policies = event_policies.with_policy(moderation.clone());
+        }
+        if let Some(vanish_requests) = &self.vanish_requests {
+            vanish_requests.load(&database).await?;
+            event_policies = event_policies.with_policy(vanish_requests.clone());
+        }
+        if let Some(tombstones) = &self.tombstones {
+            tombstones.load(&database).await?;
+            event_policies = event_policies.with_policy(tombstones.clone());
+        }
+        if let Some(runtime_config) = &self.runtime_config {
+            event_policies = event_policies.with_policy(runtime_config.clone());
+        }
+        event_policies = event_policies.with_arc_policies(&self.event_policies);
+        if let Some((web_of_trust, refresh_interval)) = self.web_of_trust.take() {
+            let web_of_trust = match self.metrics_handler.clone() {
+                Some(handler) => web_of_trust.with_metrics_handler(handler),
+                None => web_of_trust,
+            };
+            web_of_trust.spawn_refresh(
+                &task_tracker,
+                refresh_interval,
+                self.cancellation_token.clone(),
+            );
+            event_policies = event_policies.with_policy(web_of_trust);
+        }
+        if let Some(payments) = &self.payments {
+            event_policies = event_policies.with_policy(payments.clone());
+        }
+
+        // Structural limits and signature verification are skipped in bare mode
+        let mut ingest_pipeline = IngestPipeline::new();
+        if !self.bare_mode {
+            ingest_pipeline = ingest_pipeline
+                .with_limits(self.config.event_limits.clone())
+                .with_verification(crypto_helper.clone());
+            for (scope, limits) in &self.config.scope_limits {
+                if let Some(event_limits) = &limits.event_limits {
+                    ingest_pipeline =
+                        ingest_pipeline.with_scope_limits(scope.clone(), event_limits.clone());
+                }
+            }
+        }
+        for (before, stage) in std::mem::take(&mut self.ingest_stages) {
+            ingest_pipeline = ingest_pipeline.with_arc_stage_before(before, stage);
+        }
+        if let Some(scorer) = &self.spam_scorer {
+            ingest_pipeline = ingest_pipeline.with_spam_filter(
+                SpamFilter::new(scorer.clone()).with_report_queue(self.report_queue.clone()),
+            );
+        }
+        if let Some(report_queue) = &self.report_queue {
+            report_queue.load(&database).await?;
+            let kind_router = self.kind_router.take().unwrap_or_default();
+            self.kind_router =
+                Some(kind_router.route("nip56-reports", 1984..=1984, 1, report_queue.clone()));
+        }
+        if let Some(vanish_requests) = &self.vanish_requests {
+            let kind_router = self.kind_router.take().unwrap_or_default();
+            self.kind_router = Some(kind_router.route_inline(
+                "nip62-vanish",
+                VANISH_REQUEST_KIND..=VANISH_REQUEST_KIND,
+                vanish_requests.clone(),
+            ));
+        }
+        if let Some(tombstones) = &self.tombstones {
+            let kind_router = self.kind_router.take().unwrap_or_default();
+            self.kind_router =
+                Some(kind_router.route_inline("nip09-tombstones", 5..=5, tombstones.clone()));
+        }
+        if let Some(kind_router) = self
+            .kind_router
+            .as_ref()
+            .filter(|router| router.has_inline_routes())
+        {
+            kind_router.attach(database.clone());
+            ingest_pipeline =
+                ingest_pipeline.with_stage_before(IngestStage::Persist, kind_router.clone());
+        }
+
+        if let Some(count) = self.count.as_ref().filter(|count| count.is_approximate()) {
+            let count = count.clone();
+            let database = database.clone();
+            task_tracker.spawn(async move {
+                if let Err(e) = count.load(&database).await {
+                    warn!("Could not build COUNT sketches: {}", e);
+                }
+            });
+        }
+
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.spawn(
+                &task_tracker,
+                self.cancellation_token.clone(),
+                crate::scheduler::SchedulerContext {
+                    database: database.clone(),
+                    registry: subscription_registry.clone(),
+                    crypto_helper: crypto_helper.clone(),
+                },
+            );
+        }
+        if let Some(status_publisher) = &self.status_publisher {
+            status_publisher.spawn(
+                &task_tracker,
+                self.cancellation_token.clone(),
+                crate::status::StatusContext {
+                    database: database.clone(),
+                    registry: subscription_registry.clone(),
+                    crypto_helper: crypto_helper.clone(),
+                },
+            );
+        }
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.spawn(&task_tracker, self.cancellation_token.clone());
+        }
+        if let Some(provenance) = &self.provenance {
+            provenance.spawn(&task_tracker, self.cancellation_token.clone());
+        }
+        if let Some(derived_indexes) = &self.derived_indexes {
+            derived_indexes.spawn(
+                &task_tracker,
+                self.cancellation_token.clone(),
+                database.clone(),
+            );
+        }
+        if let Some(overload) = &self.overload {
+            overload.spawn(
+                &task_tracker,
+                self.cancellation_token.clone(),
+                crate::overload::OverloadContext {
+                    registry: subscription_registry.clone(),
+                    latency_budget: latency_budget.clone(),
+                },
+            );
+        }
+        if let Some(cluster) = self.cluster.take() {
+            cluster.spawn(
+                &task_tracker,
+                self.cancellation_token.clone(),
+                database.clone(),
+                subscription_registry.clone(),
+            );
+        }
+        for puller in std::mem::take(&mut self.pullers) {
+            puller.spawn(
+                &task_tracker,
+                self.cancellation_token.clone(),
+                ingest_pipeline
+                    .clone()
+                    .with_policies(event_policies.clone()),
+                database.clone(),
+                subscription_registry.clone(),
+                self.provenance.clone(),
+            );
+        }
+
+        self.health_checks.attach(crate::health::HealthContext {
+            database: database.clone(),
+            registry: subscription_registry.clone(),
+            crypto_helper: crypto_helper.clone(),
+            task_supervisor: self.task_supervisor.clone(),
+            cancellation_token: self.cancellation_token.clone(),
+            connection_drain: self.connection_drain.clone(),
+        });
+
+        #[cfg(feature = "axum")]
+        if let Some(admin_api) = &self.admin_api {
+            admin_api.attach(crate::admin::AdminContext {
+                registry: subscription_registry.clone(),
+                database: database.clone(),
+                moderation: self.moderation.clone(),
+                reports: self.report_queue.clone(),
+                slow_query_log: self.slow_query_log.clone(),
+                audit_log: self.audit_log.clone(),
+                provenance: self.provenance.clone(),
+                tenants: self.tenants.clone(),
+                task_supervisor: self.task_supervisor.clone(),
+            });
+        }
+        #[cfg(feature = "axum")]
+        if let Some(event_api) = &self.event_api {
+            // Stored events are hidden as from a fresh connection of the requester
+            let processor = self.event_processor.clone();
+            let relay_pubkey = crypto_helper.public_key();
+            let moderation = self.moderation.clone();
+            let visibility: crate::subscription_registry::VisibilityFn =
+                Arc::new(move |event, scope, auth_pubkey| {
+                    if moderation
+                        .as_ref()
+                        .is_some_and(|moderation| !moderation.is_visible_to(event, auth_pubkey))
+                    {
+                        return false;
+                    }
+                    let context = EventContext {
+                        authed_pubkey: auth_pubkey,
+                        subdomain: scope,
+                        relay_pubkey: &relay_pubkey,
+                    };
+                    processor
+                        .can_see_event(
+                            event,
+                            Arc::new(parking_lot::RwLock::new(T::default())),
+                            context,
+                        )
+                        .unwrap_or(false)
+                });
+            event_api.attach(crate::event_api::EventApiContext {
+                processor: Arc::new(crate::event_api::FreshConnection::new(
+                    self.event_processor.clone(),
+                    relay_pubkey,
+                )),
+                rate_limiters: self
+                    .rate_limiter
+                    .iter()
+                    .cloned()
+                    .chain(
+                        self.runtime_config
+                            .as_ref()
+                            .map(|runtime_config| runtime_config.rate_limiter()),
+                    )
+                    .collect(),
+                proxy_headers: self.config.proxy_headers.clone(),
+                filter_validation: self.filter_validation.clone(),
+                query_augmenter: self.query_augmenter.clone(),
+                overload: self.overload.clone(),
+                pagination: self.config.pagination,
+                read_replicas: read_replicas.clone(),
+                pipeline: ingest_pipeline
+                    .clone()
+                    .with_policies(event_policies.clone()),
+                database: database.clone(),
+                registry: subscription_registry.clone(),
+                crypto_helper: crypto_helper.clone(),
+                ordering: self.config.ordering,
+                post_save_hooks: self.post_save_hooks.clone(),
+                kind_router: self
+                    .kind_router
+                    .clone()
+                    .map(|router| router.with_task_tracker(task_tracker.clone())),
+                latency_budget: latency_budget.clone(),
+                cancellation_token: self.cancellation_token.clone(),
+                scope_resolver: self.connection_scope_resolver(),
+                visibility,
+                max_limit: self.config.max_limit,
+                provenance: self.provenance.clone(),
+            });
+        }
+        #[cfg(feature = "media")]
+        if let Some(media_server) = &self.media_server {
+            media_server.attach(crate::media::MediaContext {
+                policies: event_policies.clone(),
+                crypto_helper: crypto_helper.clone(),
+                database: database.clone(),
+                registry: subscription_registry.clone(),
+            });
+        }
+
         let relay_middleware = RelayMiddleware::new(
             DynProcessor(self.event_processor.clone()),
-            self.config.keys.public_key(),
-            database,
+            crypto_helper.public_key(),
+            database.clone(),
             subscription_registry.clone(),
             self.config.max_limit,
             RelayUrl::parse(&relay_url).expect("Valid relay URL"),
             crypto_helper.clone(),
             max_subscriptions,
-        );
+        )
+        .with_read_replicas(read_replicas)
+        .with_kind_router(
+            self.kind_router
+                .take()
+                .map(|router| router.with_task_tracker(task_tracker.clone())),
+        )
+        .with_latency_budget(latency_budget.clone())
+        .with_replaceable_buffer_config(self.config.replaceable_buffer)
+        .with_pagination_config(self.config.pagination)
+        .with_ordering(self.config.ordering)
+        .with_trace_sample_rate(self.config.trace_sample_rate)
+        .with_ingest_pipeline(Some(ingest_pipeline))
+        .with_event_policies(Some(event_policies))
+        .with_moderation(self.moderation.clone())
+        .with_query_augmenter(self.query_augmenter.clone())
+        .with_resume_cursors(self.resume_cursors.clone())
+        .with_slow_query_log(self.slow_query_log.clone())
+        .with_audit_log(self.audit_log.clone())
+        .with_provenance(self.provenance.clone())
+        .with_runtime_config(self.runtime_config.clone())
+        .with_count(self.count.clone())
+        .with_filter_validation(self.filter_validation.clone())
+        .with_post_save_hooks(self.post_save_hooks.clone())
+        .with_overload(self.overload.clone())
+        .with_supervisor(Some(self.task_supervisor.clone()))
+        .with_scope_limits(self.config.scope_limits.clone());
 
         let mut builder = WebSocketBuilder::<
             NostrConnectionState<T>,
             ClientMessage<'static>,
             RelayMessage<'static>,
             NostrMessageConverter,
-        >::new(NostrMessageConverter);
+        >::new({
+            let mut converter = NostrMessageConverter::default()
+                .with_parse_error_policy(self.config.parse_error_policy);
+            if let Some(max_message_size) = websocket_config.max_message_size {
+                converter = converter.with_max_message_size(max_message_size);
+            }
+            match self.codec.clone() {
+                Some(codec) => converter.with_codec(codec),
+                None => converter,
+            }
+        });
 
         builder = builder.with_channel_size(per_connection_channel_size);
 
+        // Answer unparsable and oversized messages before anything else sees their placeholder
+        let max_strikes = match self.config.parse_error_policy {
+            crate::message_converter::ParseErrorPolicy::Notice { max_strikes } => Some(max_strikes),
+            // Parse errors still fail the message, oversized ones only get a NOTICE
+            crate::message_converter::ParseErrorPolicy::Disconnect => {
+                websocket_config.max_message_size.map(|_| u32::MAX)
+            }
+        };
+        if let Some(max_strikes) = max_strikes {
+            builder =
+                builder.with_middleware(crate::middlewares::ParseErrorMiddleware::new(max_strikes));
+        }
+
         // Apply other websocket configurations if supported
         if let Some(max_connections) = websocket_config.max_connections {
             builder = builder.with_max_connections(max_connections);
@@ -584,6 +2071,11 @@ where
             builder = builder.with_max_connection_time(Duration::from_secs(max_time));
         }
 
+        // Stamp inbound events first so the latency budget covers the whole chain
+        if latency_budget.is_some() {
+            builder = builder.with_middleware(crate::middlewares::LatencyMiddleware::new());
+        }
+
         // Add standard middlewares unless in bare mode
         if !self.bare_mode {
             builder = builder.with_middleware(crate::middlewares::LoggerMiddleware::new());
@@ -595,6 +2087,17 @@ where
             );
         }
 
+        // Reject over-quota messages before any expensive validation
+        if let Some(rate_limiter) = self.rate_limiter.take() {
+            builder =
+                builder.with_middleware(crate::middlewares::RateLimitMiddleware::new(rate_limiter));
+        }
+        if let Some(runtime_config) = &self.runtime_config {
+            builder = builder.with_middleware(crate::middlewares::RateLimitMiddleware::new(
+                runtime_config.rate_limiter(),
+            ));
+        }
+
         // Add metrics middleware if handler is provided
         if let Some(metrics_handler) = self.metrics_handler.clone() {
             builder = builder.with_arc_middleware(Arc::new(
@@ -619,11 +2122,10 @@ where
                 builder.with_middleware(crate::middlewares::Nip42Middleware::new(auth_config));
         }
 
-        // Add event verification middleware unless in bare mode
-        if !self.bare_mode {
-            builder = builder.with_middleware(crate::middlewares::EventVerifierMiddleware::new(
-                crypto_helper.clone(),
-            ));
+        // Add message hooks
+        if !self.message_hooks.is_empty() {
+            let hooks = std::mem::take(&mut self.message_hooks);
+            builder = builder.with_middleware(crate::middlewares::HookMiddleware::new(hooks));
         }
 
         // Add custom middlewares
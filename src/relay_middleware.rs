@@ -4,17 +4,39 @@
 //! delegating business logic to EventProcessor implementations. The implementation
 //! is optimized for zero-allocation in hot paths like subscription processing.
 
-use crate::database::RelayDatabase;
+use crate::audit_log::{AuditLog, ReqAuditEntry};
+use crate::config::ScopeLimits;
+use crate::count::CountConfig;
+use crate::database::{ReadReplicas, RelayDatabase};
 use crate::error::Error;
+use crate::event_policy::EventPolicyChain;
 use crate::event_processor::{EventContext, EventProcessor};
+use crate::filter_validation::FilterValidation;
+use crate::ingest::{IngestOutcome, IngestPipeline};
+use crate::kind_router::KindRouter;
+use crate::latency::LatencyBudget;
+use crate::message_converter::approximate_count;
+use crate::moderation::ModerationStore;
+use crate::overload::OverloadController;
+use crate::post_save::PostSaveHooks;
+use crate::provenance::{EventProvenance, IngestPath, ProvenanceStore};
+use crate::query_augmenter::QueryAugmenter;
+use crate::resume::ResumeCursors;
+use crate::runtime_config::ReloadableConfig;
+use crate::slow_query_log::SlowQueryLog;
 use crate::state::NostrConnectionState;
-use crate::subscription_coordinator::StoreCommand;
+use crate::subscription_coordinator::{
+    OrderingMode, PaginationConfig, ReplaceableBufferConfig, StoreCommand, SubscriptionCoordinator,
+};
 use crate::subscription_registry::SubscriptionRegistry;
+use crate::supervisor::TaskSupervisor;
 use async_trait::async_trait;
 use negentropy::{Id, Negentropy, NegentropyStorageVector};
+use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tracing::{debug, error};
+use tracing::{debug, error, info};
 use websocket_builder::{InboundContext, Middleware, OutboundContext};
 
 /// Relay middleware that processes messages with zero-allocation performance.
@@ -43,6 +65,28 @@ where
     relay_url: RelayUrl,
     crypto_helper: crate::crypto_helper::CryptoHelper,
     max_subscriptions: Option<usize>,
+    read_replicas: Option<ReadReplicas>,
+    kind_router: Option<KindRouter>,
+    latency_budget: Option<LatencyBudget>,
+    replaceable_buffer: ReplaceableBufferConfig,
+    pagination: PaginationConfig,
+    ordering: OrderingMode,
+    trace_sample_rate: f64,
+    ingest_pipeline: Option<IngestPipeline>,
+    event_policies: Option<EventPolicyChain>,
+    moderation: Option<ModerationStore>,
+    query_augmenter: Option<Arc<dyn QueryAugmenter>>,
+    resume_cursors: Option<ResumeCursors>,
+    slow_query_log: Option<SlowQueryLog>,
+    audit_log: Option<AuditLog>,
+    provenance: Option<ProvenanceStore>,
+    runtime_config: Option<ReloadableConfig>,
+    count: Option<CountConfig>,
+    filter_validation: Option<FilterValidation>,
+    post_save_hooks: Option<PostSaveHooks>,
+    overload: Option<OverloadController>,
+    supervisor: Option<TaskSupervisor>,
+    scope_limits: Arc<HashMap<Scope, ScopeLimits>>,
     _phantom: std::marker::PhantomData<T>,
 }
 
@@ -82,19 +126,276 @@ where
             relay_url,
             crypto_helper,
             max_subscriptions,
+            read_replicas: None,
+            kind_router: None,
+            latency_budget: None,
+            replaceable_buffer: ReplaceableBufferConfig::default(),
+            pagination: PaginationConfig::default(),
+            ordering: OrderingMode::default(),
+            trace_sample_rate: 1.0,
+            ingest_pipeline: None,
+            event_policies: None,
+            moderation: None,
+            query_augmenter: None,
+            resume_cursors: None,
+            slow_query_log: None,
+            audit_log: None,
+            provenance: None,
+            runtime_config: None,
+            count: None,
+            filter_validation: None,
+            post_save_hooks: None,
+            overload: None,
+            supervisor: None,
+            scope_limits: Arc::new(HashMap::new()),
             _phantom: std::marker::PhantomData,
         }
     }
 
+    /// Serve historical queries from read replicas instead of the primary database
+    #[must_use]
+    pub fn with_read_replicas(mut self, read_replicas: Option<ReadReplicas>) -> Self {
+        self.read_replicas = read_replicas;
+        self
+    }
+
+    /// Hand accepted events to host handlers by kind
+    #[must_use]
+    pub fn with_kind_router(mut self, kind_router: Option<KindRouter>) -> Self {
+        self.kind_router = kind_router.filter(|router| !router.is_empty());
+        self
+    }
+
+    /// Record per-stage latencies of accepted events
+    ///
+    /// Requires [`crate::middlewares::LatencyMiddleware`] at the start of the chain.
+    #[must_use]
+    pub fn with_latency_budget(mut self, latency_budget: Option<LatencyBudget>) -> Self {
+        self.latency_budget = latency_budget;
+        self
+    }
+
+    /// Configure when buffered replaceable events of a connection are flushed
+    #[must_use]
+    pub fn with_replaceable_buffer_config(mut self, config: ReplaceableBufferConfig) -> Self {
+        self.replaceable_buffer = config;
+        self
+    }
+
+    /// Configure how the stored events of a REQ are paginated
+    #[must_use]
+    pub fn with_pagination_config(mut self, config: PaginationConfig) -> Self {
+        self.pagination = config;
+        self
+    }
+
+    /// Order `OK` responses, storage and distribution according to `ordering`
+    #[must_use]
+    pub fn with_ordering(mut self, ordering: OrderingMode) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// Trace this fraction of connections with per-connection spans
+    #[must_use]
+    pub fn with_trace_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.trace_sample_rate = sample_rate;
+        self
+    }
+
+    /// Run every signed event through `ingest_pipeline` before it is saved
+    #[must_use]
+    pub fn with_ingest_pipeline(mut self, ingest_pipeline: Option<IngestPipeline>) -> Self {
+        self.ingest_pipeline = ingest_pipeline;
+        self
+    }
+
+    /// Check every signed event against `event_policies` before it is saved
+    #[must_use]
+    pub fn with_event_policies(mut self, event_policies: Option<EventPolicyChain>) -> Self {
+        self.event_policies = event_policies;
+        self
+    }
+
+    /// Hide events banned by the moderation lists from queries and live subscriptions
+    ///
+    /// Ingest is checked by registering the store as an event policy.
+    #[must_use]
+    pub fn with_moderation(mut self, moderation: Option<ModerationStore>) -> Self {
+        self.moderation = moderation;
+        self
+    }
+
+    /// Rewrite REQ filters with `query_augmenter` before they are queried
+    #[must_use]
+    pub fn with_query_augmenter(
+        mut self,
+        query_augmenter: Option<Arc<dyn QueryAugmenter>>,
+    ) -> Self {
+        self.query_augmenter = query_augmenter;
+        self
+    }
+
+    /// Hand authenticated clients resume tokens from `resume_cursors`
+    #[must_use]
+    pub fn with_resume_cursors(mut self, resume_cursors: Option<ResumeCursors>) -> Self {
+        self.resume_cursors = resume_cursors;
+        self
+    }
+
+    /// Record REQs whose historical query was slow in `slow_query_log`
+    #[must_use]
+    pub fn with_slow_query_log(mut self, slow_query_log: Option<SlowQueryLog>) -> Self {
+        self.slow_query_log = slow_query_log;
+        self
+    }
+
+    /// Record every REQ in `audit_log`
+    #[must_use]
+    pub fn with_audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Record where each event clients publish came from in `provenance`
+    #[must_use]
+    pub fn with_provenance(mut self, provenance: Option<ProvenanceStore>) -> Self {
+        self.provenance = provenance;
+        self
+    }
+
+    /// Take `max_limit` and `max_subscriptions` from `runtime_config`, re-read on every message
+    #[must_use]
+    pub fn with_runtime_config(mut self, runtime_config: Option<ReloadableConfig>) -> Self {
+        self.runtime_config = runtime_config;
+        self
+    }
+
+    /// Answer NIP-45 COUNT requests as configured by `count`
+    #[must_use]
+    pub fn with_count(mut self, count: Option<CountConfig>) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Check and normalize REQ and COUNT filters with `filter_validation`
+    #[must_use]
+    pub fn with_filter_validation(mut self, filter_validation: Option<FilterValidation>) -> Self {
+        self.filter_validation = filter_validation;
+        self
+    }
+
+    /// Process the follow-up commands `post_save_hooks` return for stored events
+    #[must_use]
+    pub fn with_post_save_hooks(mut self, post_save_hooks: Option<PostSaveHooks>) -> Self {
+        self.post_save_hooks = post_save_hooks;
+        self
+    }
+
+    /// Refuse REQs and throttle historical queries as `overload` decides
+    #[must_use]
+    pub fn with_overload(mut self, overload: Option<OverloadController>) -> Self {
+        self.overload = overload;
+        self
+    }
+
+    /// Restart the per-connection tasks with `supervisor` when they panic
+    #[must_use]
+    pub fn with_supervisor(mut self, supervisor: Option<TaskSupervisor>) -> Self {
+        self.supervisor = supervisor;
+        self
+    }
+
+    /// Apply `scope_limits` instead of the relay-wide limits in their scopes
+    #[must_use]
+    pub fn with_scope_limits(mut self, scope_limits: HashMap<Scope, ScopeLimits>) -> Self {
+        self.scope_limits = Arc::new(scope_limits);
+        self
+    }
+
+    /// Maximum subscriptions per connection in `scope`
+    fn max_subscriptions_in(&self, scope: &Scope) -> Option<usize> {
+        match self
+            .scope_limits
+            .get(scope)
+            .and_then(|limits| limits.max_subscriptions)
+        {
+            Some(max_subscriptions) => Some(max_subscriptions).filter(|max| *max > 0),
+            None => match &self.runtime_config {
+                Some(runtime_config) => runtime_config.load().subscription_limit(),
+                None => self.max_subscriptions,
+            },
+        }
+    }
+
     /// Get a reference to the event processor
     pub fn processor(&self) -> &Arc<P> {
         &self.processor
     }
 
+    /// Record a REQ in the audit log, if any, before it is checked
+    fn audit_req(
+        &self,
+        connection_id: &str,
+        state: &parking_lot::RwLock<NostrConnectionState<T>>,
+        subscription_id: &SubscriptionId,
+        filters: &[Filter],
+    ) {
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+        let connection_state = state.read();
+        audit_log.record(ReqAuditEntry::new(
+            connection_id,
+            connection_state.authed_pubkey,
+            subscription_id.clone(),
+            filters.to_vec(),
+            &connection_state.subdomain,
+        ));
+    }
+
+    /// Record the provenance of `event_id`, stored from an EVENT of `connection_id`
+    fn record_provenance(
+        &self,
+        event_id: EventId,
+        connection_id: &str,
+        state: &parking_lot::RwLock<NostrConnectionState<T>>,
+    ) {
+        let Some(provenance) = &self.provenance else {
+            return;
+        };
+        let connection_state = state.read();
+        let mut record = EventProvenance::new(event_id, IngestPath::WebSocket);
+        record.connection_id = Some(connection_id.to_string());
+        record.auth_pubkey = connection_state.authed_pubkey;
+        record.ip_hash = connection_state
+            .remote_address
+            .as_deref()
+            .map(|address| provenance.hash_ip(address));
+        provenance.record(record);
+    }
+
+    /// Pick up scope changes made through [`SubscriptionRegistry::migrate_scope`]
+    fn sync_migrated_scope(&self, state: &parking_lot::RwLock<NostrConnectionState<T>>) {
+        let migrated_scope = {
+            let connection_state = state.read();
+            connection_state
+                .subscription_coordinator()
+                .and_then(|coordinator| coordinator.current_scope())
+                .filter(|scope| *scope != connection_state.subdomain)
+        };
+
+        if let Some(scope) = migrated_scope {
+            debug!("Connection scope migrated to {:?}", scope);
+            state.write().subdomain = scope;
+        }
+    }
+
     /// Handle EVENT messages with optimized performance
     async fn handle_event(
         &self,
         event: Event,
+        connection_id: &str,
         state: Arc<parking_lot::RwLock<NostrConnectionState<T>>>,
         message_sender: Option<websocket_builder::MessageSender<RelayMessage<'static>>>,
     ) -> Result<(), Error> {
@@ -106,6 +407,10 @@ where
                 Arc::clone(&connection_state.subdomain), // Clone the Arc pointer
             )
         };
+        let mut timeline = match self.latency_budget {
+            Some(_) => state.write().event_timeline.take(),
+            None => None,
+        };
 
         // Create custom state wrapper
         let custom_state_wrapper = Arc::new(parking_lot::RwLock::new({
@@ -119,10 +424,23 @@ where
             relay_pubkey: &self.relay_pubkey,
         };
 
-        let mut commands = self
+        let mut commands = match self
             .processor
             .handle_event(event, custom_state_wrapper, context)
-            .await?;
+            .await
+        {
+            Ok(commands) => commands,
+            Err(e) => {
+                if let Some(coordinator) = state.read().subscription_coordinator() {
+                    coordinator.record_event_result(false);
+                }
+                return Err(e);
+            }
+        };
+
+        if let Some(timeline) = timeline.as_mut() {
+            timeline.mark_policy_complete();
+        }
 
         let subscription_coordinator = {
             let state_guard = state.read();
@@ -155,24 +473,161 @@ where
         if let Some(idx) = event_command_idx {
             let mut event_command = commands.swap_remove(idx);
             event_command.set_message_sender(message_sender.unwrap())?;
-            subscription_coordinator
-                .save_and_broadcast(event_command)
-                .await
-                .map_err(|e| Error::database(e.to_string()))?;
+            let event_id = match &event_command {
+                StoreCommand::SaveSignedEvent(event, _, _) => Some(event.id),
+                _ => None,
+            };
+            match self.ingest(event_command, timeline.as_mut()).await {
+                Ok(IngestOutcome::Stored) => {
+                    if let Some(event_id) = event_id {
+                        self.record_provenance(event_id, connection_id, &state);
+                    }
+                }
+                // Derived events were already saved when the event was first stored
+                Ok(IngestOutcome::Duplicate) => return Ok(()),
+                // Derived events of spam are dropped with it
+                Ok(IngestOutcome::ShadowAccepted) => return Ok(()),
+                Err(e) => {
+                    // The coordinator already answered with OK false, derived events are dropped
+                    debug!("Event not saved: {}", e);
+                    return Ok(());
+                }
+            }
+
+            if let (Some(budget), Some(timeline)) = (&self.latency_budget, &timeline) {
+                budget.record(timeline);
+            }
         }
 
         // Process all remaining commands without message_sender
         for command in commands {
-            subscription_coordinator
-                .save_and_broadcast(command)
-                .await
-                .map_err(|e| Error::database(e.to_string()))?;
+            subscription_coordinator.ingest(command, None).await?;
         }
 
         // Database layer will send OK after persistence
         Ok(())
     }
 
+    async fn handle_count(
+        &self,
+        state: Arc<parking_lot::RwLock<NostrConnectionState<T>>>,
+        subscription_id: SubscriptionId,
+        filter: Filter,
+        sender: Option<websocket_builder::MessageSender<RelayMessage<'static>>>,
+    ) -> Result<(), Error> {
+        let Some(count) = &self.count else {
+            return Err(Error::notice("Message type not supported: COUNT"));
+        };
+        if let Some(overload) = &self.overload {
+            overload.check_req()?;
+        }
+        let filter = match &self.filter_validation {
+            Some(validation) => validation
+                .validate(vec![filter])?
+                .pop()
+                .ok_or_else(|| Error::internal("Filter validation dropped the filter"))?,
+            None => filter,
+        };
+
+        let (subdomain, authed_pubkey, custom_state) = {
+            let connection_state = state.read();
+            let context = EventContext {
+                authed_pubkey: connection_state.authed_pubkey.as_ref(),
+                subdomain: &connection_state.subdomain,
+                relay_pubkey: &self.relay_pubkey,
+            };
+            let custom_state_wrapper = Arc::new(parking_lot::RwLock::new(
+                connection_state.custom_state.clone(),
+            ));
+            self.processor.verify_filters(
+                std::slice::from_ref(&filter),
+                custom_state_wrapper,
+                context,
+            )?;
+            (
+                Arc::clone(&connection_state.subdomain),
+                connection_state.authed_pubkey,
+                connection_state.custom_state.clone(),
+            )
+        };
+
+        // Counted like a REQ would serve them
+        let filters = match &self.query_augmenter {
+            Some(augmenter) => {
+                augmenter
+                    .augment(vec![filter], &subdomain, authed_pubkey.as_ref())
+                    .await
+            }
+            None => vec![filter],
+        };
+        let visible = (self.moderation.is_some() || self.processor.can_hide_events()).then(|| {
+            let visibility = self.visibility_fn(custom_state);
+            let scope = nostr_lmdb::Scope::clone(&subdomain);
+            move |event: &Event| visibility(event, &scope, authed_pubkey.as_ref())
+        });
+        let _permit = match &self.overload {
+            Some(overload) => overload.historical_permit().await,
+            None => None,
+        };
+
+        let (events, approximate) = count
+            .count(
+                &self.database,
+                filters,
+                &subdomain,
+                visible
+                    .as_ref()
+                    .map(|visible| visible as &(dyn Fn(&Event) -> bool + Send + Sync)),
+            )
+            .await?;
+        if let Some(mut sender) = sender {
+            let response_message = if approximate {
+                approximate_count(&subscription_id, events)
+            } else {
+                RelayMessage::count(subscription_id, events)
+            };
+            sender.send(response_message).map_err(|e| {
+                Error::channel_closed(format!("Failed to send count response: {e}"))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Moderation and `can_see_event` checks for a connection with `custom_state`
+    fn visibility_fn(
+        &self,
+        custom_state: T,
+    ) -> impl Fn(&Event, &nostr_lmdb::Scope, Option<&PublicKey>) -> bool + Send + Sync + Clone + 'static
+    {
+        let processor = Arc::clone(&self.processor);
+        let relay_pubkey = self.relay_pubkey;
+        let moderation = self.moderation.clone();
+
+        // No async needed
+        move |event: &Event, scope: &nostr_lmdb::Scope, auth_pk: Option<&PublicKey>| -> bool {
+            if moderation
+                .as_ref()
+                .is_some_and(|moderation| !moderation.is_visible_to(event, auth_pk))
+            {
+                return false;
+            }
+
+            // Create context on stack - zero heap allocations
+            let context = EventContext {
+                authed_pubkey: auth_pk,
+                subdomain: scope,
+                relay_pubkey: &relay_pubkey,
+            };
+
+            // Create custom state wrapper for each call
+            let custom_state_wrapper = Arc::new(parking_lot::RwLock::new(custom_state.clone()));
+
+            processor
+                .can_see_event(event, custom_state_wrapper, context)
+                .unwrap_or(false)
+        }
+    }
+
     /// Handle subscription with optimized event filtering
     async fn handle_subscription(
         &self,
@@ -180,8 +635,19 @@ where
         subscription_id: String,
         filters: Vec<Filter>,
     ) -> Result<(), Error> {
+        if let Some(overload) = &self.overload {
+            overload.check_req()?;
+        }
+
         let subscription_id_obj = SubscriptionId::new(subscription_id.clone());
 
+        // Both the historical query and the live subscription use the normalized filters
+        let filters = match &self.filter_validation {
+            Some(validation) => validation.validate(filters)?,
+            None => filters,
+        };
+        self.registry.check_memory(&filters)?;
+
         // First check subscription limit and verify filters with write lock
         {
             let mut connection_state = state.write();
@@ -216,27 +682,7 @@ where
             (subdomain, authed_pubkey, custom_state)
         };
 
-        // Clone for the filter function
-        let processor = Arc::clone(&self.processor);
-        let relay_pubkey = self.relay_pubkey;
-
-        // Create filter function with cloned state - no async needed
-        let filter_fn =
-            move |event: &Event, scope: &nostr_lmdb::Scope, auth_pk: Option<&PublicKey>| -> bool {
-                // Create context on stack - zero heap allocations
-                let context = EventContext {
-                    authed_pubkey: auth_pk,
-                    subdomain: scope,
-                    relay_pubkey: &relay_pubkey,
-                };
-
-                // Create custom state wrapper for each call
-                let custom_state_wrapper = Arc::new(parking_lot::RwLock::new(custom_state.clone()));
-
-                processor
-                    .can_see_event(event, custom_state_wrapper, context)
-                    .unwrap_or(false)
-            };
+        let filter_fn = self.visibility_fn(custom_state);
 
         // Get subscription coordinator and process
         let subscription_coordinator = {
@@ -247,6 +693,22 @@ where
                 .clone()
         };
 
+        // Filters were verified as sent, the augmented ones are what gets served
+        let filters = match &self.query_augmenter {
+            Some(augmenter) => {
+                augmenter
+                    .augment(filters, &subdomain, authed_pubkey.as_ref())
+                    .await
+            }
+            None => filters,
+        };
+
+        // Under elevated load historical queries take turns
+        let _permit = match &self.overload {
+            Some(overload) => overload.historical_permit().await,
+            None => None,
+        };
+
         subscription_coordinator
             .handle_req(
                 SubscriptionId::new(subscription_id),
@@ -316,10 +778,10 @@ where
 
         // Perform initial reconciliation
         let hex_bytes = hex::decode(&initial_message)
-            .map_err(|e| Error::internal(format!("Failed to decode initial message: {e}")))?;
+            .map_err(|e| Error::invalid(format!("Failed to decode initial message: {e}")))?;
         let response_bytes = negentropy
             .reconcile(&hex_bytes)
-            .map_err(|e| Error::internal(format!("Failed to reconcile negentropy: {e}")))?;
+            .map_err(|e| Error::invalid(format!("Failed to reconcile negentropy: {e}")))?;
 
         // Send response
         if let Some(mut sender) = sender {
@@ -329,9 +791,9 @@ where
                 )),
                 message: std::borrow::Cow::Owned(hex::encode(response_bytes)),
             };
-            sender
-                .send(response_message)
-                .map_err(|e| Error::internal(format!("Failed to send negentropy response: {e}")))?;
+            sender.send(response_message).map_err(|e| {
+                Error::channel_closed(format!("Failed to send negentropy response: {e}"))
+            })?;
         }
 
         // Store negentropy instance in connection state
@@ -363,9 +825,9 @@ where
                 Some(negentropy) => {
                     // Decode incoming message and reconcile
                     let hex_bytes = hex::decode(&message)
-                        .map_err(|e| Error::internal(format!("Failed to decode message: {e}")))?;
+                        .map_err(|e| Error::invalid(format!("Failed to decode message: {e}")))?;
                     negentropy.reconcile(&hex_bytes).map_err(|e| {
-                        Error::internal(format!("Failed to reconcile negentropy: {e}"))
+                        Error::invalid(format!("Failed to reconcile negentropy: {e}"))
                     })?
                 }
                 None => {
@@ -382,9 +844,9 @@ where
                 subscription_id: std::borrow::Cow::Owned(subscription_id_obj),
                 message: std::borrow::Cow::Owned(hex::encode(response_bytes)),
             };
-            sender
-                .send(response_message)
-                .map_err(|e| Error::internal(format!("Failed to send negentropy response: {e}")))?;
+            sender.send(response_message).map_err(|e| {
+                Error::channel_closed(format!("Failed to send negentropy response: {e}"))
+            })?;
         }
 
         Ok(())
@@ -435,7 +897,7 @@ where
             let mut state = ctx.state.write();
             state.relay_url = self.relay_url.clone();
             state.registry = Some(self.registry.clone());
-            state.max_subscriptions = self.max_subscriptions;
+            state.max_subscriptions = self.max_subscriptions_in(&state.subdomain);
             // TODO: subdomain resolution needs to be handled differently without factory pattern
         }
 
@@ -443,18 +905,54 @@ where
         if let Some(ref sender) = ctx.sender {
             {
                 let mut state = ctx.state.write();
+                let scope_max_limit = self
+                    .scope_limits
+                    .get(state.subdomain.as_ref())
+                    .and_then(|limits| limits.max_limit);
+                let coordinator = SubscriptionCoordinator::builder(
+                    self.database.clone(),
+                    self.crypto_helper.clone(),
+                    self.registry.clone(),
+                    ctx.connection_id.clone(),
+                    sender.clone(),
+                )
+                .with_max_limit(scope_max_limit.unwrap_or(self.max_limit))
+                .with_replaceable_buffer(self.replaceable_buffer)
+                .with_pagination(self.pagination)
+                .with_ordering(self.ordering)
+                .with_post_save_hooks(self.post_save_hooks.clone())
+                .with_kind_router(self.kind_router.clone())
+                .with_resume_cursors(self.resume_cursors.clone())
+                .with_read_replicas(self.read_replicas.clone())
+                .with_ingest_pipeline(self.ingest_pipeline.clone())
+                .with_event_policies(self.event_policies.clone())
+                .with_trace_sample_rate(self.trace_sample_rate)
+                .with_slow_query_log(self.slow_query_log.clone())
+                .with_runtime_config(
+                    self.runtime_config
+                        .clone()
+                        .filter(|_| scope_max_limit.is_none()),
+                )
+                .with_supervisor(self.supervisor.clone());
                 state
-                    .setup_connection(
-                        self.database.clone(),
-                        self.registry.clone(),
-                        ctx.connection_id.clone(),
-                        sender.clone(),
-                        self.crypto_helper.clone(),
-                        Some(self.max_limit),
-                    )
+                    .setup_connection(coordinator)
                     .map_err(|e| anyhow::anyhow!("Failed to setup connection: {}", e))?;
             }
             debug!("RelayMiddleware: Connection setup complete");
+
+            let user_agent = ctx.state.read().user_agent.clone();
+            if let Some(user_agent) = user_agent {
+                self.registry.set_user_agent(&ctx.connection_id, user_agent);
+            }
+            if let Err(reason) = self.registry.announce_connection(&ctx.connection_id) {
+                info!("Connection {} refused: {}", ctx.connection_id, reason);
+                let _ = sender.clone().send(RelayMessage::notice(reason.clone()));
+                return Err(anyhow::anyhow!(
+                    "Connection {} refused: {}",
+                    ctx.connection_id,
+                    reason
+                ));
+            }
         } else {
             error!("RelayMiddleware: No message sender available for connection setup");
         }
@@ -470,12 +968,26 @@ where
             return ctx.next().await;
         };
 
+        self.registry.record_inbound(
+            &ctx.connection_id,
+            crate::broadcast::client_message_len(&message),
+        );
+        if let Some(coordinator) = ctx.state.read().subscription_coordinator() {
+            coordinator.touch();
+        }
+        self.sync_migrated_scope(&ctx.state);
+        if self.runtime_config.is_some() || !self.scope_limits.is_empty() {
+            let mut state = ctx.state.write();
+            state.max_subscriptions = self.max_subscriptions_in(&state.subdomain);
+        }
+
         match message {
             ClientMessage::Event(boxed_event) => {
                 // Handle EVENT message
                 match self
                     .handle_event(
                         boxed_event.into_owned(),
+                        &ctx.connection_id,
                         ctx.state.clone(),
                         ctx.sender.clone(),
                     )
@@ -495,13 +1007,11 @@ where
                 subscription_id,
                 filter,
             } => {
+                let filters = vec![filter.into_owned()];
+                self.audit_req(&ctx.connection_id, &ctx.state, &subscription_id, &filters);
                 // Use generic subscription handling directly
                 match self
-                    .handle_subscription(
-                        ctx.state.clone(),
-                        subscription_id.to_string(),
-                        vec![filter.into_owned()],
-                    )
+                    .handle_subscription(ctx.state.clone(), subscription_id.to_string(), filters)
                     .await
                 {
                     Ok(()) => {}
@@ -518,6 +1028,7 @@ where
                 subscription_id,
                 filters,
             } => {
+                self.audit_req(&ctx.connection_id, &ctx.state, &subscription_id, &filters);
                 // Use generic subscription handling directly
                 match self
                     .handle_subscription(ctx.state.clone(), subscription_id.to_string(), filters)
@@ -576,7 +1087,7 @@ where
                             subscription_id: std::borrow::Cow::Owned(SubscriptionId::new(
                                 subscription_id.to_string(),
                             )),
-                            message: std::borrow::Cow::Owned(format!("Error: {e}")),
+                            message: std::borrow::Cow::Owned(e.client_message()),
                         };
                         if let Some(mut sender) = ctx.sender.clone() {
                             let _ = sender.send(error_message);
@@ -608,7 +1119,7 @@ where
                             subscription_id: std::borrow::Cow::Owned(SubscriptionId::new(
                                 subscription_id.to_string(),
                             )),
-                            message: std::borrow::Cow::Owned(format!("Error: {e}")),
+                            message: std::borrow::Cow::Owned(e.client_message()),
                         };
                         if let Some(mut sender) = ctx.sender.clone() {
                             let _ = sender.send(error_message);
@@ -633,6 +1144,25 @@ where
                 ctx.next().await
             }
 
+            ClientMessage::Count {
+                subscription_id,
+                filter,
+            } => {
+                if let Err(e) = self
+                    .handle_count(
+                        ctx.state.clone(),
+                        subscription_id.into_owned(),
+                        filter.into_owned(),
+                        ctx.sender.clone(),
+                    )
+                    .await
+                {
+                    debug!("Count error: {}", e);
+                    return Err(e.into());
+                }
+                ctx.next().await
+            }
+
             // All other messages are not handled by default
             _ => match &message {
                 ClientMessage::Auth(_) => {
@@ -643,13 +1173,7 @@ where
                     ctx.next().await
                 }
                 _ => {
-                    let msg = format!(
-                        "Message type not supported: {}",
-                        match &message {
-                            ClientMessage::Count { .. } => "COUNT",
-                            _ => "UNKNOWN",
-                        }
-                    );
+                    let msg = "Message type not supported: UNKNOWN";
                     debug!("{msg}");
                     Err(Error::notice(msg).into())
                 }
@@ -667,6 +1191,15 @@ where
 
         // For broadcast events, check visibility before sending
         if let RelayMessage::Event { event, .. } = &message {
+            let authed_pubkey = ctx.state.read().authed_pubkey;
+            if self
+                .moderation
+                .as_ref()
+                .is_some_and(|moderation| !moderation.is_visible_to(event, authed_pubkey.as_ref()))
+            {
+                return ctx.next().await; // Filter out
+            }
+
             let should_filter = {
                 let state = ctx.state.read();
                 let subdomain = Arc::clone(&state.subdomain);
@@ -691,6 +1224,11 @@ where
             }
         }
 
+        self.registry.record_outbound(
+            &ctx.connection_id,
+            1,
+            crate::broadcast::relay_message_len(&message),
+        );
         ctx.message = Some(message);
         ctx.next().await
     }
@@ -0,0 +1,379 @@
+//! NIP-56 report ingestion and moderation queue
+//!
+//! [`ReportQueue`] aggregates the kind 1984 reports a relay accepts per scope
+//! and reported pubkey or event, counting each reporter once. Register it with
+//! [`RelayBuilder::with_report_queue`](crate::RelayBuilder::with_report_queue):
+//! reports already stored are loaded when the relay is built, new ones are
+//! recorded once saved, and moderators read and dismiss the queue through the
+//! [admin API](crate::admin).
+//!
+//! With [`ReportQueue::with_auto_hide`], a target reported by enough trusted
+//! reporters is banned in the [`ModerationStore`], which hides it from queries
+//! and subscriptions. The ban happens once, when the threshold is reached, so
+//! a moderator lifting it is not overruled by the reports already counted.
+
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::kind_router::KindHandler;
+use crate::moderation::ModerationStore;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// What a report is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReportTarget {
+    /// A user, reported with a `p` tag only
+    Pubkey(PublicKey),
+    /// An event, reported with an `e` tag
+    Event(EventId),
+}
+
+/// Reports aggregated for one target in one scope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportEntry {
+    /// Scope the reports were saved to
+    pub scope: Scope,
+    /// The reported pubkey or event
+    pub target: ReportTarget,
+    /// Author of the reported event, from the report's `p` tag
+    pub author: Option<PublicKey>,
+    /// Distinct pubkeys that reported the target
+    pub reporters: HashSet<PublicKey>,
+    /// How many of the reporters are trusted
+    pub trusted_reports: usize,
+    /// Reporters per NIP-56 report type (`spam`, `illegal`, ...)
+    pub report_types: BTreeMap<String, usize>,
+    /// `created_at` of the latest report
+    pub last_reported_at: Timestamp,
+}
+
+/// Queue of reported pubkeys and events awaiting moderation
+#[derive(Clone, Default)]
+pub struct ReportQueue {
+    entries: Arc<RwLock<HashMap<(Scope, ReportTarget), ReportEntry>>>,
+    trusted_reporters: Arc<HashSet<PublicKey>>,
+    /// Trusted reports after which a target is banned, and where
+    auto_hide: Option<(usize, ModerationStore)>,
+}
+
+impl std::fmt::Debug for ReportQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReportQueue")
+            .field("entries", &self.entries.read().len())
+            .field("trusted_reporters", &self.trusted_reporters.len())
+            .field(
+                "auto_hide_threshold",
+                &self.auto_hide.as_ref().map(|(threshold, _)| threshold),
+            )
+            .finish()
+    }
+}
+
+impl ReportQueue {
+    /// An empty queue without trusted reporters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count reports from `reporters` as trusted
+    #[must_use]
+    pub fn with_trusted_reporters(
+        mut self,
+        reporters: impl IntoIterator<Item = PublicKey>,
+    ) -> Self {
+        self.trusted_reporters = Arc::new(reporters.into_iter().collect());
+        self
+    }
+
+    /// Ban targets in `moderation` once `threshold` trusted reporters reported them
+    ///
+    /// Only trusted reporters count, so nothing is banned automatically until
+    /// some are set with [`Self::with_trusted_reporters`].
+    #[must_use]
+    pub fn with_auto_hide(mut self, threshold: usize, moderation: ModerationStore) -> Self {
+        self.auto_hide = Some((threshold.max(1), moderation));
+        self
+    }
+
+    /// Record the reports stored in every scope of `database`
+    pub async fn load(&self, database: &RelayDatabase) -> Result<()> {
+        let mut scopes = database.list_scopes().await?;
+        if !scopes.contains(&Scope::Default) {
+            scopes.push(Scope::Default);
+        }
+        for scope in scopes {
+            let reports = database
+                .query(vec![Filter::new().kind(Kind::Reporting)], &scope)
+                .await?;
+            for report in reports.iter() {
+                self.record(report, &scope);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a report saved to `scope` to the queue
+    ///
+    /// Returns the targets whose trusted reports reached the auto-hide
+    /// threshold with this report. Events that are not reports are ignored.
+    pub fn record(&self, report: &Event, scope: &Scope) -> Vec<ReportTarget> {
+        if report.kind != Kind::Reporting {
+            return Vec::new();
+        }
+        let trusted = self.trusted_reporters.contains(&report.pubkey);
+        let threshold = self.auto_hide.as_ref().map(|(threshold, _)| *threshold);
+
+        let mut reached = Vec::new();
+        let mut entries = self.entries.write();
+        for (target, author, report_type) in report_targets(report) {
+            let entry = entries
+                .entry((scope.clone(), target))
+                .or_insert_with(|| ReportEntry {
+                    scope: scope.clone(),
+                    target,
+                    author,
+                    reporters: HashSet::new(),
+                    trusted_reports: 0,
+                    report_types: BTreeMap::new(),
+                    last_reported_at: report.created_at,
+                });
+            entry.last_reported_at = entry.last_reported_at.max(report.created_at);
+            if !entry.reporters.insert(report.pubkey) {
+                continue;
+            }
+            if let Some(report_type) = report_type {
+                *entry.report_types.entry(report_type).or_default() += 1;
+            }
+            if trusted {
+                entry.trusted_reports += 1;
+                if threshold == Some(entry.trusted_reports) {
+                    reached.push(target);
+                }
+            }
+        }
+        reached
+    }
+
+    /// Queue `event` saved to `scope` on behalf of the relay, e.g. by a spam scorer
+    ///
+    /// `label` is counted as a report type; no reporter is added, so flags
+    /// never trigger the auto-hide.
+    pub fn flag(&self, event: &Event, scope: &Scope, label: &str) {
+        let target = ReportTarget::Event(event.id);
+        let mut entries = self.entries.write();
+        let entry = entries
+            .entry((scope.clone(), target))
+            .or_insert_with(|| ReportEntry {
+                scope: scope.clone(),
+                target,
+                author: Some(event.pubkey),
+                reporters: HashSet::new(),
+                trusted_reports: 0,
+                report_types: BTreeMap::new(),
+                last_reported_at: Timestamp::now(),
+            });
+        entry.last_reported_at = entry.last_reported_at.max(Timestamp::now());
+        *entry.report_types.entry(label.to_string()).or_default() += 1;
+    }
+
+    /// The queue, most reported by trusted reporters first
+    pub fn entries(&self) -> Vec<ReportEntry> {
+        let mut entries: Vec<_> = self.entries.read().values().cloned().collect();
+        entries.sort_by(|a, b| {
+            (b.trusted_reports, b.reporters.len(), b.last_reported_at).cmp(&(
+                a.trusted_reports,
+                a.reporters.len(),
+                a.last_reported_at,
+            ))
+        });
+        entries
+    }
+
+    /// Reports on `target` in `scope`
+    pub fn entry(&self, scope: &Scope, target: &ReportTarget) -> Option<ReportEntry> {
+        self.entries.read().get(&(scope.clone(), *target)).cloned()
+    }
+
+    /// Remove `target` from the queue in every scope, returns whether it was queued
+    ///
+    /// Reports received afterwards queue it again.
+    pub fn dismiss(&self, target: &ReportTarget) -> bool {
+        let mut entries = self.entries.write();
+        let queued = entries.len();
+        entries.retain(|(_, queued_target), _| queued_target != target);
+        entries.len() != queued
+    }
+
+    async fn hide(&self, target: ReportTarget) {
+        let Some((threshold, moderation)) = &self.auto_hide else {
+            return;
+        };
+        let banned = match target {
+            ReportTarget::Pubkey(pubkey) => moderation.ban_pubkey(pubkey).await,
+            ReportTarget::Event(id) => moderation.ban_event(id).await,
+        };
+        match banned {
+            Ok(()) => info!("Hid {:?} after {} trusted reports", target, threshold),
+            Err(e) => warn!("Could not hide reported {:?}: {}", target, e),
+        }
+    }
+}
+
+#[async_trait]
+impl KindHandler for ReportQueue {
+    async fn handle(&self, event: Arc<Event>, scope: Scope) -> Result<()> {
+        for target in self.record(&event, &scope) {
+            self.hide(target).await;
+        }
+        Ok(())
+    }
+}
+
+/// Targets of a NIP-56 report, with the reported author and the report type
+///
+/// A report on events names each of them in an `e` tag; otherwise it is about
+/// the pubkey of its `p` tag. The report type is the third value of the tag.
+fn report_targets(report: &Event) -> Vec<(ReportTarget, Option<PublicKey>, Option<String>)> {
+    let mut author = None;
+    let mut author_type = None;
+    let mut events = Vec::new();
+
+    for tag in report.tags.iter() {
+        match tag.as_slice() {
+            [name, value, rest @ ..] if name == "p" && author.is_none() => {
+                if let Ok(pubkey) = PublicKey::from_hex(value) {
+                    author = Some(pubkey);
+                    author_type = rest.first().cloned();
+                }
+            }
+            [name, value, rest @ ..] if name == "e" => {
+                if let Ok(id) = EventId::from_hex(value) {
+                    events.push((id, rest.first().cloned()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if events.is_empty() {
+        return author
+            .map(|pubkey| (ReportTarget::Pubkey(pubkey), None, author_type))
+            .into_iter()
+            .collect();
+    }
+    events
+        .into_iter()
+        .map(|(id, report_type)| (ReportTarget::Event(id), author, report_type))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    fn report(reporter: &Keys, tags: Vec<Tag>) -> Event {
+        EventBuilder::new(Kind::Reporting, "")
+            .tags(tags)
+            .sign_with_keys(reporter)
+            .unwrap()
+    }
+
+    fn tag(values: &[&str]) -> Tag {
+        Tag::parse(values.iter().copied()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_aggregates_reports_and_hides_after_trusted_reports() {
+        let (_tmp_dir, database, relay_keys) = setup_test_with_database().await;
+        let moderation = ModerationStore::open(database.clone(), relay_keys)
+            .await
+            .unwrap();
+        let trusted = [Keys::generate(), Keys::generate()];
+        let queue = ReportQueue::new()
+            .with_trusted_reporters(trusted.iter().map(|keys| keys.public_key()))
+            .with_auto_hide(2, moderation.clone());
+
+        let spammer = Keys::generate().public_key().to_hex();
+        let spam = EventBuilder::text_note("buy now")
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        let spam_id = spam.id.to_hex();
+        let on_event = |reporter: &Keys| {
+            report(
+                reporter,
+                vec![
+                    tag(&["e", &spam_id, "spam"]),
+                    tag(&["p", &spam.pubkey.to_hex()]),
+                ],
+            )
+        };
+
+        // A pubkey report, then the same untrusted reporter twice on an event
+        let anyone = Keys::generate();
+        queue
+            .handle(
+                Arc::new(report(
+                    &anyone,
+                    vec![tag(&["p", &spammer, "impersonation"])],
+                )),
+                Scope::Default,
+            )
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            queue
+                .handle(Arc::new(on_event(&anyone)), Scope::Default)
+                .await
+                .unwrap();
+        }
+        queue
+            .handle(Arc::new(on_event(&trusted[0])), Scope::Default)
+            .await
+            .unwrap();
+
+        let target = ReportTarget::Event(spam.id);
+        let entry = queue.entry(&Scope::Default, &target).unwrap();
+        assert_eq!(entry.author, Some(spam.pubkey));
+        assert_eq!(entry.reporters.len(), 2);
+        assert_eq!(entry.trusted_reports, 1);
+        assert_eq!(entry.report_types.get("spam"), Some(&2));
+        assert_eq!(queue.entries().len(), 2);
+        assert_eq!(queue.entries()[0].target, target);
+        assert!(moderation.is_visible(&spam));
+
+        // The second trusted reporter reaches the threshold
+        queue
+            .handle(Arc::new(on_event(&trusted[1])), Scope::Default)
+            .await
+            .unwrap();
+        assert!(!moderation.is_visible(&spam));
+
+        assert!(queue.dismiss(&target));
+        assert!(queue.entry(&Scope::Default, &target).is_none());
+        assert!(!queue.dismiss(&target));
+    }
+
+    #[tokio::test]
+    async fn test_load_stored_reports() {
+        let (_tmp_dir, database, _keys) = setup_test_with_database().await;
+        let reported = Keys::generate().public_key();
+        let stored = report(
+            &Keys::generate(),
+            vec![tag(&["p", &reported.to_hex(), "spam"])],
+        );
+        database.save_event(&stored, &Scope::Default).await.unwrap();
+
+        let queue = ReportQueue::new();
+        queue.load(&database).await.unwrap();
+        let entry = queue
+            .entry(&Scope::Default, &ReportTarget::Pubkey(reported))
+            .unwrap();
+        assert_eq!(entry.reporters, HashSet::from([stored.pubkey]));
+    }
+}
@@ -0,0 +1,156 @@
+//! Resuming subscriptions after a reconnect
+//!
+//! A client that reconnects usually sends its REQs again and is served every
+//! stored event a second time. With [`ResumeCursors`] enabled, a NIP-42
+//! authenticated client receives a resume token right after the EOSE of each
+//! REQ, in a NOTICE `resume: <subscription id> <token>`. The cursor behind
+//! the token remembers until when the client was served: the EOSE at first,
+//! then the CLOSE or the disconnect.
+//!
+//! After reconnecting and authenticating as the same pubkey, the client sends
+//! the REQ again with [`RESUME_TOKEN_SEPARATOR`] and the token appended to its
+//! subscription id, e.g. `feed~resume:5f0c...`, and only what it missed is
+//! served: with a [receipt index](crate::RelayDatabase::with_receipt_index),
+//! the events the relay received since the cursor, whatever their
+//! `created_at`. Without one the cursor narrows `since`, so events created
+//! before it but received after, e.g. backfilled ones or those of clients with
+//! a skewed clock, are lost. Cursors are kept per pubkey, expire after a short
+//! time and can be redeemed once; the resumed REQ gets a new token. Unknown
+//! or expired tokens are ignored and the REQ is served in full.
+
+use dashmap::DashMap;
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Prefix of the NOTICE carrying a resume token
+pub const RESUME_NOTICE_PREFIX: &str = "resume:";
+
+/// Separates the subscription id from the resume token in a resumed REQ
+pub const RESUME_TOKEN_SEPARATOR: &str = "~resume:";
+
+/// Default time a cursor can be redeemed after it was last advanced
+pub const DEFAULT_RESUME_TTL: Duration = Duration::from_secs(600);
+
+/// Cursors kept per pubkey, the oldest is forgotten first
+pub const MAX_CURSORS_PER_PUBKEY: usize = 64;
+
+#[derive(Debug)]
+struct Cursor {
+    token: String,
+    served_until: Timestamp,
+    expires_at: Instant,
+}
+
+/// Short-lived resume cursors of authenticated clients
+#[derive(Debug, Clone)]
+pub struct ResumeCursors {
+    ttl: Duration,
+    cursors: Arc<DashMap<PublicKey, Vec<Cursor>>>,
+}
+
+impl Default for ResumeCursors {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESUME_TTL)
+    }
+}
+
+impl ResumeCursors {
+    /// Keep cursors redeemable for `ttl` after they were last advanced
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            cursors: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Create a cursor for `pubkey`, served until `served_until`, and return its token
+    pub fn issue(&self, pubkey: &PublicKey, served_until: Timestamp) -> String {
+        let token = format!("{:016x}", rand::random::<u64>());
+        let now = Instant::now();
+
+        let mut cursors = self.cursors.entry(*pubkey).or_default();
+        cursors.retain(|cursor| cursor.expires_at > now);
+        if cursors.len() >= MAX_CURSORS_PER_PUBKEY {
+            cursors.remove(0);
+        }
+        cursors.push(Cursor {
+            token: token.clone(),
+            served_until,
+            expires_at: now + self.ttl,
+        });
+
+        token
+    }
+
+    /// Record that the client behind `token` was served until `served_until`
+    pub fn advance(&self, pubkey: &PublicKey, token: &str, served_until: Timestamp) {
+        if let Some(mut cursors) = self.cursors.get_mut(pubkey) {
+            if let Some(cursor) = cursors.iter_mut().find(|cursor| cursor.token == token) {
+                cursor.served_until = cursor.served_until.max(served_until);
+                cursor.expires_at = Instant::now() + self.ttl;
+            }
+        }
+    }
+
+    /// Consume the cursor `token` of `pubkey`, returning until when it was served
+    pub fn redeem(&self, pubkey: &PublicKey, token: &str) -> Option<Timestamp> {
+        let mut cursors = self.cursors.get_mut(pubkey)?;
+        let index = cursors.iter().position(|cursor| cursor.token == token)?;
+        let cursor = cursors.remove(index);
+        (cursor.expires_at > Instant::now()).then_some(cursor.served_until)
+    }
+
+    /// Forget expired cursors
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.cursors.retain(|_, cursors| {
+            cursors.retain(|cursor| cursor.expires_at > now);
+            !cursors.is_empty()
+        });
+    }
+
+    /// Resume token carried by a REQ's subscription id, if any
+    pub fn token_of(subscription_id: &SubscriptionId) -> Option<&str> {
+        subscription_id
+            .as_str()
+            .rsplit_once(RESUME_TOKEN_SEPARATOR)
+            .map(|(_, token)| token)
+            .filter(|token| !token.is_empty())
+    }
+
+    /// NOTICE handing `token` to the client for `subscription_id`
+    pub fn notice(subscription_id: &SubscriptionId, token: &str) -> RelayMessage<'static> {
+        RelayMessage::notice(format!("{RESUME_NOTICE_PREFIX} {subscription_id} {token}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_lifecycle() {
+        let cursors = ResumeCursors::default();
+        let pubkey = Keys::generate().public_key();
+        let other = Keys::generate().public_key();
+
+        let token = cursors.issue(&pubkey, Timestamp::from(100));
+        cursors.advance(&pubkey, &token, Timestamp::from(160));
+        // Cursors are only redeemable by their pubkey, and only once
+        assert_eq!(cursors.redeem(&other, &token), None);
+        assert_eq!(cursors.redeem(&pubkey, &token), Some(Timestamp::from(160)));
+        assert_eq!(cursors.redeem(&pubkey, &token), None);
+
+        let expired = ResumeCursors::new(Duration::ZERO);
+        let token = expired.issue(&pubkey, Timestamp::from(100));
+        assert_eq!(expired.redeem(&pubkey, &token), None);
+        expired.issue(&pubkey, Timestamp::from(100));
+        expired.prune();
+        assert!(expired.cursors.is_empty());
+
+        let resumed = SubscriptionId::new(format!("feed{RESUME_TOKEN_SEPARATOR}{token}"));
+        assert_eq!(ResumeCursors::token_of(&resumed), Some(token.as_str()));
+        assert_eq!(ResumeCursors::token_of(&SubscriptionId::new("feed")), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! Configuration that can be reloaded while the relay runs
+//!
+//! [`RelayConfig`] is read once when the relay is built. The settings an
+//! operator typically tunes under load (filter limits, subscription caps, rate
+//! limits, allowlists and retention) live in a [`RuntimeConfig`] instead,
+//! published through a [`ReloadableConfig`] handle. Calling
+//! [`ReloadableConfig::reload`], directly or through
+//! [`ReloadableConfig::watch_file`], swaps the configuration atomically: open
+//! connections pick up the new values with their next message instead of
+//! having to reconnect.
+
+use crate::config::RelayConfig;
+use crate::database::RelayDatabase;
+use crate::error::{Error, Result};
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::rate_limit::{Quota, RateLimitConfig, RateLimitKey, RateLimitedAction, RateLimiter};
+use crate::subscription_coordinator::ClosedReason;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// One quota of the reloadable rate limits
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitRule {
+    pub key: RateLimitKey,
+    pub action: RateLimitedAction,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+/// Settings that can change without restarting the relay
+///
+/// Deserialized from JSON by [`Self::from_file`]; missing fields take their
+/// default value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// Maximum limit value allowed in subscription filters
+    pub max_limit: usize,
+    /// Maximum number of active subscriptions per connection, 0 for no limit
+    pub max_subscriptions: usize,
+    /// Only accept events from these pubkeys, everyone when absent
+    pub allowed_pubkeys: Option<HashSet<PublicKey>>,
+    /// Only accept events of these kinds, all kinds when absent
+    pub allowed_kinds: Option<HashSet<u16>>,
+    /// Delete events created more than this many seconds ago, keep them forever when absent
+    pub retention_secs: Option<u64>,
+    /// Relay-wide rate limits
+    pub rate_limits: Vec<RateLimitRule>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            max_limit: 5000,
+            max_subscriptions: 50,
+            allowed_pubkeys: None,
+            allowed_kinds: None,
+            retention_secs: None,
+            rate_limits: Vec::new(),
+        }
+    }
+}
+
+impl From<&RelayConfig> for RuntimeConfig {
+    fn from(config: &RelayConfig) -> Self {
+        Self {
+            max_limit: config.max_limit,
+            max_subscriptions: config.max_subscriptions,
+            ..Default::default()
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Read a JSON configuration file
+    pub async fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| Error::internal(format!("Failed to read {}: {e}", path.display())))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| Error::internal(format!("Failed to parse {}: {e}", path.display())))
+    }
+
+    /// The rate limits as a [`RateLimitConfig`]
+    pub fn rate_limit_config(&self) -> RateLimitConfig {
+        self.rate_limits
+            .iter()
+            .fold(RateLimitConfig::new(), |config, rule| {
+                config.limit(rule.key, rule.action, rule.quota)
+            })
+    }
+
+    /// Subscription cap per connection, `None` when unlimited
+    pub fn subscription_limit(&self) -> Option<usize> {
+        (self.max_subscriptions > 0).then_some(self.max_subscriptions)
+    }
+}
+
+/// Shared handle to the current [`RuntimeConfig`]
+///
+/// Clones see the same configuration. The handle also
+/// owns the [`RateLimiter`] enforcing [`RuntimeConfig::rate_limits`], and
+/// checks the allowlists as an [`EventPolicy`].
+#[derive(Debug, Clone)]
+pub struct ReloadableConfig {
+    current: Arc<ArcSwap<RuntimeConfig>>,
+    rate_limiter: RateLimiter,
+}
+
+impl ReloadableConfig {
+    /// Start with `config`
+    pub fn new(config: RuntimeConfig) -> Self {
+        Self {
+            rate_limiter: RateLimiter::new(config.rate_limit_config()),
+            current: Arc::new(ArcSwap::from_pointee(config)),
+        }
+    }
+
+    /// The current configuration
+    pub fn load(&self) -> Arc<RuntimeConfig> {
+        self.current.load_full()
+    }
+
+    /// Replace the configuration, effective immediately for all connections
+    pub fn reload(&self, config: RuntimeConfig) {
+        self.rate_limiter.set_config(config.rate_limit_config());
+        self.current.store(Arc::new(config));
+        info!("Runtime configuration reloaded");
+    }
+
+    /// Limiter enforcing the configured rate limits
+    pub fn rate_limiter(&self) -> RateLimiter {
+        self.rate_limiter.clone()
+    }
+
+    /// Reload from `path` now and whenever its modification time changes
+    ///
+    /// The file is checked every `interval` until cancelled. A file that fails
+    /// to parse is logged and the previous configuration stays in effect.
+    pub fn watch_file(
+        &self,
+        path: impl Into<PathBuf>,
+        interval: Duration,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+    ) {
+        let config = self.clone();
+        let path = path.into();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut last_modified: Option<SystemTime> = None;
+
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        let modified = match tokio::fs::metadata(&path).await.and_then(|m| m.modified()) {
+                            Ok(modified) => modified,
+                            Err(e) => {
+                                warn!("Failed to stat {}: {}", path.display(), e);
+                                continue;
+                            }
+                        };
+                        if last_modified == Some(modified) {
+                            continue;
+                        }
+                        last_modified = Some(modified);
+
+                        match RuntimeConfig::from_file(&path).await {
+                            Ok(runtime_config) => config.reload(runtime_config),
+                            Err(e) => warn!("Keeping previous runtime configuration: {}", e),
+                        }
+                    }
+                }
+            }
+
+            debug!("Runtime configuration watcher stopped");
+        });
+    }
+
+    /// Delete events older than the configured retention every `interval` until cancelled
+    ///
+    /// Every scope is pruned. Nothing is deleted while `retention_secs` is unset.
+    pub fn spawn_retention(
+        &self,
+        database: Arc<RelayDatabase>,
+        interval: Duration,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+    ) {
+        let config = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        let Some(retention_secs) = config.load().retention_secs else {
+                            continue;
+                        };
+                        if let Err(e) = prune(&database, retention_secs).await {
+                            warn!("Failed to apply retention: {}", e);
+                        }
+                    }
+                }
+            }
+
+            debug!("Retention task stopped");
+        });
+    }
+}
+
+async fn prune(database: &RelayDatabase, retention_secs: u64) -> Result<()> {
+    let cutoff = Timestamp::from(Timestamp::now().as_u64().saturating_sub(retention_secs));
+    let filter = Filter::new().until(cutoff);
+
+    let mut scopes = database.list_scopes().await?;
+    if !scopes.contains(&Scope::Default) {
+        scopes.push(Scope::Default);
+    }
+    for scope in scopes {
+        database.delete(filter.clone(), &scope).await?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl EventPolicy for ReloadableConfig {
+    async fn check(&self, event: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        let config = self.current.load();
+
+        if let Some(allowed_pubkeys) = &config.allowed_pubkeys {
+            if !allowed_pubkeys.contains(&event.pubkey) {
+                return PolicyDecision::Reject(ClosedReason::Restricted(
+                    "pubkey is not allowed on this relay".to_string(),
+                ));
+            }
+        }
+        if let Some(allowed_kinds) = &config.allowed_kinds {
+            if !allowed_kinds.contains(&event.kind.as_u16()) {
+                return PolicyDecision::Reject(ClosedReason::Blocked(format!(
+                    "kind {} is not accepted",
+                    event.kind
+                )));
+            }
+        }
+
+        PolicyDecision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reload_applies_to_clones() {
+        let config = ReloadableConfig::new(RuntimeConfig::default());
+        let shared = config.clone();
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("hello")
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        assert_eq!(
+            shared.check(&event, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+        let limiter = shared.rate_limiter();
+        limiter
+            .check(&Scope::Default, RateLimitedAction::Event, "1.2.3.4:1", None)
+            .unwrap();
+
+        let reloaded: RuntimeConfig = serde_json::from_str(
+            r#"{
+                "max_limit": 100,
+                "allowed_kinds": [0],
+                "rate_limits": [
+                    {"key": "connection", "action": "event", "per_second": 0.1, "burst": 1}
+                ]
+            }"#,
+        )
+        .unwrap();
+        config.reload(reloaded);
+
+        assert_eq!(shared.load().max_limit, 100);
+        assert_eq!(shared.load().subscription_limit(), Some(50));
+        assert!(matches!(
+            shared.check(&event, &Scope::Default, None).await,
+            PolicyDecision::Reject(ClosedReason::Blocked(_))
+        ));
+        limiter
+            .check(&Scope::Default, RateLimitedAction::Event, "1.2.3.4:1", None)
+            .unwrap();
+        assert!(limiter
+            .check(&Scope::Default, RateLimitedAction::Event, "1.2.3.4:1", None)
+            .is_err());
+    }
+}
@@ -0,0 +1,413 @@
+//! Delayed publication of relay-authored events
+//!
+//! A [`Scheduler`] holds unsigned events relay code wants published later:
+//! expiring group invites, reminders, timed announcements. When an event is
+//! due it is signed with the relay identity, with `created_at` set to the
+//! publication time, then stored and distributed to live subscriptions.
+//!
+//! Register the scheduler with
+//! [`RelayBuilder::with_scheduler`](crate::RelayBuilder::with_scheduler) and
+//! keep a clone to schedule events once the relay is built. Each pending event
+//! is persisted as a relay-signed NIP-78 application data event whose content
+//! is NIP-44 encrypted to the relay's configured keys, even when an external
+//! signer signs for the relay, so schedules survive restarts without
+//! revealing upcoming events. Events that came due while the relay was down
+//! are published when it starts. Publication is at least once: a crash
+//! between publishing and removing the record publishes again.
+
+use crate::crypto_helper::CryptoHelper;
+use crate::database::RelayDatabase;
+use crate::error::{Error, Result};
+use crate::subscription_registry::{EventDistributor, SubscriptionRegistry};
+use crate::utils::Attachment;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// Kind of the events pending publications are persisted in (NIP-78 application data)
+pub const SCHEDULED_EVENT_KIND: Kind = Kind::ApplicationSpecificData;
+
+/// Prefix of the `d` tag of the events pending publications are persisted in
+pub const SCHEDULED_EVENT_IDENTIFIER_PREFIX: &str = "relay_builder/scheduled/";
+
+/// Delay before retrying a publication that failed
+const RETRY_DELAY: u64 = 60;
+
+/// An event waiting for its publication time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledEvent {
+    /// Id to cancel the publication with
+    pub id: String,
+    /// When the event is published
+    pub publish_at: Timestamp,
+    /// Scope the event is published in
+    pub scope: Scope,
+    /// The event, signed when it is published
+    pub event: UnsignedEvent,
+}
+
+/// Persisted form of a [`ScheduledEvent`]
+#[derive(Serialize, Deserialize)]
+struct StoredSchedule {
+    publish_at: Timestamp,
+    scope: Option<String>,
+    event: UnsignedEvent,
+}
+
+/// Relay components the scheduler works with, attached when the relay is built
+pub(crate) struct SchedulerContext {
+    pub(crate) database: Arc<RelayDatabase>,
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+    pub(crate) crypto_helper: CryptoHelper,
+}
+
+#[derive(Default)]
+struct Inner {
+    pending: Mutex<HashMap<String, ScheduledEvent>>,
+    context: Attachment<SchedulerContext>,
+    /// Wakes the publishing task when the earliest publication time may have changed
+    changed: Notify,
+}
+
+/// Queue of relay-authored events published at a future time
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("pending", &self.inner.pending.lock().len())
+            .field("attached", &self.inner.context.is_attached())
+            .finish()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish `event` in `scope` at `publish_at`, returning the id to cancel it with
+    ///
+    /// Fails until the relay the scheduler is registered with is built.
+    pub async fn schedule(
+        &self,
+        event: UnsignedEvent,
+        scope: Scope,
+        publish_at: Timestamp,
+    ) -> Result<String> {
+        let context = self.context()?;
+        let scheduled = ScheduledEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            publish_at,
+            scope,
+            event,
+        };
+        context.persist(&scheduled).await?;
+
+        let id = scheduled.id.clone();
+        self.inner.pending.lock().insert(id.clone(), scheduled);
+        self.inner.changed.notify_one();
+        Ok(id)
+    }
+
+    /// Drop the pending publication `id`, returning whether there was one
+    pub async fn cancel(&self, id: &str) -> Result<bool> {
+        let context = self.context()?;
+        if self.inner.pending.lock().remove(id).is_none() {
+            return Ok(false);
+        }
+        context.forget(id).await?;
+        self.inner.changed.notify_one();
+        Ok(true)
+    }
+
+    /// Pending publications, earliest first
+    pub fn pending(&self) -> Vec<ScheduledEvent> {
+        let mut pending: Vec<ScheduledEvent> =
+            self.inner.pending.lock().values().cloned().collect();
+        pending.sort_by_key(|scheduled| scheduled.publish_at);
+        pending
+    }
+
+    fn context(&self) -> Result<&SchedulerContext> {
+        self.inner
+            .context
+            .get()
+            .ok_or_else(|| Error::internal("Scheduler is not attached to a running relay"))
+    }
+
+    /// Load the persisted publications and publish them as they come due
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+        context: SchedulerContext,
+    ) {
+        if !self.inner.context.attach(context, "Scheduler") {
+            return;
+        }
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        let scheduler = self.clone();
+        task_tracker.spawn(async move {
+            let Ok(context) = scheduler.context() else {
+                return;
+            };
+            match context.load().await {
+                Ok(loaded) => {
+                    info!("Loaded {} scheduled events", loaded.len());
+                    let mut pending = scheduler.inner.pending.lock();
+                    for scheduled in loaded {
+                        pending.entry(scheduled.id.clone()).or_insert(scheduled);
+                    }
+                }
+                Err(e) => warn!("Failed to load scheduled events: {}", e),
+            }
+
+            loop {
+                let wait = match scheduler.next_publish_at() {
+                    Some(publish_at) => {
+                        Duration::from_secs(publish_at.as_u64().saturating_sub(now_secs()))
+                    }
+                    // Nothing to publish until something is scheduled
+                    None => Duration::from_secs(u32::MAX as u64),
+                };
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = scheduler.inner.changed.notified() => {}
+                    _ = tokio::time::sleep(wait) => scheduler.publish_due(context).await,
+                }
+            }
+            debug!("Scheduler stopped");
+        });
+    }
+
+    fn next_publish_at(&self) -> Option<Timestamp> {
+        self.inner
+            .pending
+            .lock()
+            .values()
+            .map(|scheduled| scheduled.publish_at)
+            .min()
+    }
+
+    /// Publish the events that are due, retrying failed ones later
+    async fn publish_due(&self, context: &SchedulerContext) {
+        let now = now_secs();
+        let due: Vec<ScheduledEvent> = self
+            .inner
+            .pending
+            .lock()
+            .values()
+            .filter(|scheduled| scheduled.publish_at.as_u64() <= now)
+            .cloned()
+            .collect();
+
+        for scheduled in due {
+            match context.publish(&scheduled).await {
+                Ok(event_id) => {
+                    debug!("Published scheduled event {} as {}", scheduled.id, event_id);
+                    self.inner.pending.lock().remove(&scheduled.id);
+                    if let Err(e) = context.forget(&scheduled.id).await {
+                        warn!("Failed to remove schedule {}: {}", scheduled.id, e);
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to publish scheduled event {}, retrying in {}s: {}",
+                        scheduled.id, RETRY_DELAY, e
+                    );
+                    if let Some(pending) = self.inner.pending.lock().get_mut(&scheduled.id) {
+                        pending.publish_at = Timestamp::from(now + RETRY_DELAY);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl SchedulerContext {
+    fn filter(&self) -> Filter {
+        Filter::new()
+            .author(self.crypto_helper.public_key())
+            .kind(SCHEDULED_EVENT_KIND)
+    }
+
+    /// Persist `scheduled` as an encrypted relay-signed event
+    async fn persist(&self, scheduled: &ScheduledEvent) -> Result<()> {
+        let stored = StoredSchedule {
+            publish_at: scheduled.publish_at,
+            scope: match &scheduled.scope {
+                Scope::Named { name, .. } => Some(name.to_string()),
+                Scope::Default => None,
+            },
+            event: scheduled.event.clone(),
+        };
+        let json = serde_json::to_string(&stored)
+            .map_err(|e| Error::internal(format!("Failed to serialize schedule: {e}")))?;
+        let relay_pubkey = self.crypto_helper.public_key();
+        let content = self.crypto_helper.encrypt_at_rest(&json)?;
+
+        let unsigned = EventBuilder::new(SCHEDULED_EVENT_KIND, content)
+            .tag(Tag::identifier(format!(
+                "{SCHEDULED_EVENT_IDENTIFIER_PREFIX}{}",
+                scheduled.id
+            )))
+            .build(relay_pubkey);
+        let event = self.crypto_helper.sign_event(unsigned).await?;
+        self.database.save_event(&event, &Scope::Default).await
+    }
+
+    /// Remove the persisted form of the publication `id`
+    async fn forget(&self, id: &str) -> Result<()> {
+        let filter = self
+            .filter()
+            .identifier(format!("{SCHEDULED_EVENT_IDENTIFIER_PREFIX}{id}"));
+        self.database.delete(filter, &Scope::Default).await
+    }
+
+    /// Read back the persisted publications
+    async fn load(&self) -> Result<Vec<ScheduledEvent>> {
+        let stored = self
+            .database
+            .query(vec![self.filter()], &Scope::Default)
+            .await?;
+
+        let mut loaded = Vec::new();
+        for event in stored {
+            let Some(id) = event
+                .tags
+                .identifier()
+                .and_then(|d| d.strip_prefix(SCHEDULED_EVENT_IDENTIFIER_PREFIX))
+            else {
+                continue;
+            };
+            let decoded = self
+                .crypto_helper
+                .decrypt_at_rest(&event.content)
+                .and_then(|json| {
+                    serde_json::from_str::<StoredSchedule>(&json)
+                        .map_err(|e| Error::database(format!("Invalid schedule: {e}")))
+                });
+            let stored = match decoded {
+                Ok(stored) => stored,
+                Err(e) => {
+                    warn!("Skipping unreadable schedule {}: {}", id, e);
+                    continue;
+                }
+            };
+            let scope = match stored.scope {
+                Some(name) => match Scope::named(&name) {
+                    Ok(scope) => scope,
+                    Err(e) => {
+                        warn!("Skipping schedule {} for scope '{}': {}", id, name, e);
+                        continue;
+                    }
+                },
+                None => Scope::Default,
+            };
+            loaded.push(ScheduledEvent {
+                id: id.to_string(),
+                publish_at: stored.publish_at,
+                scope,
+                event: stored.event,
+            });
+        }
+        Ok(loaded)
+    }
+
+    /// Sign, store and distribute `scheduled`'s event
+    async fn publish(&self, scheduled: &ScheduledEvent) -> Result<EventId> {
+        let mut unsigned = scheduled.event.clone();
+        unsigned.created_at = Timestamp::now();
+        unsigned.id = None;
+        unsigned.ensure_id();
+
+        let event = self.crypto_helper.sign_event(unsigned).await?;
+        self.database.save_event(&event, &scheduled.scope).await?;
+        let event_id = event.id;
+        self.registry
+            .distribute_event(Arc::new(event), &scheduled.scope)
+            .await;
+        Ok(event_id)
+    }
+}
+
+fn now_secs() -> u64 {
+    Timestamp::now().as_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test;
+
+    #[tokio::test]
+    async fn test_scheduled_events_are_published_and_survive_restarts() {
+        let (_tmp_dir, database, keys) = setup_test().await;
+        let context = || SchedulerContext {
+            database: database.clone(),
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+            crypto_helper: CryptoHelper::new(Arc::new(keys.clone())),
+        };
+        let announcement =
+            |content: &str| EventBuilder::text_note(content).build(keys.public_key());
+        let published = |content: &'static str| {
+            let database = database.clone();
+            async move {
+                database
+                    .query(vec![Filter::new().kind(Kind::TextNote)], &Scope::Default)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .any(|event| event.content == content)
+            }
+        };
+
+        let tracker = TaskTracker::new();
+        let token = CancellationToken::new();
+        let scheduler = Scheduler::new();
+        scheduler.spawn(&tracker, Some(token.clone()), context());
+        let soon = Timestamp::from(now_secs() + 1);
+        let later = Timestamp::from(now_secs() + 3600);
+        scheduler
+            .schedule(announcement("soon"), Scope::Default, soon)
+            .await
+            .unwrap();
+        let cancelled = scheduler
+            .schedule(announcement("cancelled"), Scope::Default, soon)
+            .await
+            .unwrap();
+        scheduler
+            .schedule(announcement("later"), Scope::Default, later)
+            .await
+            .unwrap();
+        assert!(scheduler.cancel(&cancelled).await.unwrap());
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(published("soon").await);
+        assert!(!published("cancelled").await);
+        assert!(!published("later").await);
+        token.cancel();
+
+        // A new scheduler picks up what is still pending
+        let restarted = Scheduler::new();
+        restarted.spawn(&tracker, None, context());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let pending = restarted.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].publish_at, later);
+        assert_eq!(pending[0].event.content, "later");
+    }
+}
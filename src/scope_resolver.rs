@@ -0,0 +1,143 @@
+//! Resolving the scope of a connection from its `Host` header
+//!
+//! The database and subscription registry keep tenants apart by [`Scope`]; a
+//! [`ScopeResolver`] decides which scope an incoming connection belongs to.
+//! [`ScopeConfig`] resolves by counting domain labels, while
+//! [`SubdomainResolver`] strips a configured base domain and can restrict
+//! which tenant names are served.
+
+use crate::config::ScopeConfig;
+use nostr_lmdb::Scope;
+use std::collections::HashSet;
+
+/// Maps the host a client connected to onto a scope
+pub trait ScopeResolver: Send + Sync + std::fmt::Debug {
+    /// Scope of a connection to `host`, `None` refuses the connection
+    fn resolve(&self, host: Option<&str>) -> Option<Scope>;
+}
+
+impl ScopeResolver for ScopeConfig {
+    fn resolve(&self, host: Option<&str>) -> Option<Scope> {
+        Some(self.resolve_scope(host))
+    }
+}
+
+/// Serves `<tenant>.<base domain>` from `Scope::named(tenant)`
+///
+/// The base domain itself and hosts outside of it are served from the default
+/// scope. Tenants on the deny list, outside the allow list when one is set,
+/// or whose name is not a valid scope are refused.
+#[derive(Debug, Clone)]
+pub struct SubdomainResolver {
+    base_domain: String,
+    allowed: Option<HashSet<String>>,
+    denied: HashSet<String>,
+}
+
+impl SubdomainResolver {
+    /// Resolve tenants below `base_domain`, e.g. `relay.example.com`
+    pub fn new(base_domain: impl AsRef<str>) -> Self {
+        Self {
+            base_domain: normalize(base_domain.as_ref()),
+            allowed: None,
+            denied: HashSet::new(),
+        }
+    }
+
+    /// Only serve these tenants
+    #[must_use]
+    pub fn with_allowed<I, S>(mut self, tenants: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.allowed = Some(tenants.into_iter().map(|t| normalize(t.as_ref())).collect());
+        self
+    }
+
+    /// Never serve these tenants
+    #[must_use]
+    pub fn with_denied<I, S>(mut self, tenants: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.denied
+            .extend(tenants.into_iter().map(|t| normalize(t.as_ref())));
+        self
+    }
+
+    /// Tenant name of `host`, `None` for the base domain and unrelated hosts
+    pub fn tenant(&self, host: &str) -> Option<String> {
+        let host = normalize(strip_port(host));
+        let tenant = host.strip_suffix(self.base_domain.as_str())?;
+        let tenant = tenant.strip_suffix('.')?;
+        (!tenant.is_empty()).then(|| tenant.to_string())
+    }
+
+    /// Whether `tenant` may be served
+    pub fn is_allowed(&self, tenant: &str) -> bool {
+        !self.denied.contains(tenant)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(tenant))
+    }
+}
+
+impl ScopeResolver for SubdomainResolver {
+    fn resolve(&self, host: Option<&str>) -> Option<Scope> {
+        let Some(tenant) = host.and_then(|host| self.tenant(host)) else {
+            return Some(Scope::Default);
+        };
+        if !self.is_allowed(&tenant) {
+            return None;
+        }
+        Scope::named(&tenant).ok()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') && port.parse::<u16>().is_ok() => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subdomain_resolver() {
+        let resolver = SubdomainResolver::new("Relay.Example.com")
+            .with_allowed(["alice", "bob", "spam"])
+            .with_denied(["spam"]);
+
+        assert_eq!(
+            resolver.resolve(Some("alice.relay.example.com:443")),
+            Some(Scope::named("alice").unwrap())
+        );
+        assert_eq!(
+            resolver.resolve(Some("relay.example.com")),
+            Some(Scope::Default)
+        );
+        assert_eq!(
+            resolver.resolve(Some("alice.other.com")),
+            Some(Scope::Default)
+        );
+        assert_eq!(resolver.resolve(None), Some(Scope::Default));
+        // Denied and unlisted tenants are refused
+        assert_eq!(resolver.resolve(Some("spam.relay.example.com")), None);
+        assert_eq!(resolver.resolve(Some("carol.relay.example.com")), None);
+        // A label merely ending with the base domain is not a tenant
+        assert_eq!(
+            resolver.resolve(Some("evilrelay.example.com")),
+            Some(Scope::Default)
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Pluggable signing of relay-generated events
+//!
+//! By default [`CryptoHelper`](crate::CryptoHelper) signs with in-process
+//! [`Keys`]. Operators who won't keep the relay secret key on the box can plug
+//! a [`Signer`] instead, e.g. an [`ExternalSigner`] wrapping a NIP-46 bunker or
+//! an HSM/KMS client. The helper hands the signer batches of events and the
+//! signer applies its own timeout and concurrency limits.
+
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default time a remote signer has to sign one event
+pub const DEFAULT_SIGNER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Signs relay-generated events
+#[async_trait]
+pub trait Signer: Send + Sync + std::fmt::Debug {
+    /// The identity events are signed with; unsigned events must be built for it
+    fn public_key(&self) -> PublicKey;
+
+    /// Sign a batch of events, returning one result per event in the same order
+    async fn sign_events(&self, events: Vec<UnsignedEvent>) -> Vec<Result<Event>>;
+}
+
+#[async_trait]
+impl Signer for Keys {
+    fn public_key(&self) -> PublicKey {
+        Keys::public_key(self)
+    }
+
+    async fn sign_events(&self, events: Vec<UnsignedEvent>) -> Vec<Result<Event>> {
+        events
+            .into_iter()
+            .map(|event| {
+                event
+                    .sign_with_keys(self)
+                    .map_err(|e| Error::internal(format!("Failed to sign event: {e}")))
+            })
+            .collect()
+    }
+}
+
+/// [`Signer`] backed by any [`NostrSigner`], such as a NIP-46 remote signer
+///
+/// Requests are sent with at most `max_in_flight` outstanding at once, and
+/// each one fails after `timeout` instead of stalling relay-generated events.
+#[derive(Debug, Clone)]
+pub struct ExternalSigner {
+    signer: Arc<dyn NostrSigner>,
+    public_key: PublicKey,
+    timeout: Duration,
+    max_in_flight: usize,
+}
+
+impl ExternalSigner {
+    /// Connect to `signer` and fetch its public key
+    pub async fn connect(signer: Arc<dyn NostrSigner>) -> Result<Self> {
+        let public_key = tokio::time::timeout(DEFAULT_SIGNER_TIMEOUT, signer.get_public_key())
+            .await
+            .map_err(|_| Error::internal("External signer timed out"))?
+            .map_err(|e| Error::internal(format!("External signer failed: {e}")))?;
+
+        Ok(Self {
+            signer,
+            public_key,
+            timeout: DEFAULT_SIGNER_TIMEOUT,
+            max_in_flight: 8,
+        })
+    }
+
+    /// Give up on a signing request after `timeout`
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send at most `max_in_flight` signing requests at once
+    #[must_use]
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    async fn sign_one(&self, event: UnsignedEvent) -> Result<Event> {
+        let event = tokio::time::timeout(self.timeout, self.signer.sign_event(event))
+            .await
+            .map_err(|_| Error::internal("External signer timed out"))?
+            .map_err(|e| Error::internal(format!("External signer failed: {e}")))?;
+
+        // Don't trust the remote side to have used the expected identity
+        if event.pubkey != self.public_key {
+            return Err(Error::internal("External signer used an unexpected key"));
+        }
+        Ok(event)
+    }
+}
+
+#[async_trait]
+impl Signer for ExternalSigner {
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    async fn sign_events(&self, events: Vec<UnsignedEvent>) -> Vec<Result<Event>> {
+        futures_util::stream::iter(events)
+            .map(|event| self.sign_one(event))
+            .buffered(self.max_in_flight)
+            .collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_external_signer_batch() {
+        let keys = Keys::generate();
+        let signer = ExternalSigner::connect(Arc::new(keys.clone()))
+            .await
+            .unwrap()
+            .with_max_in_flight(2);
+        assert_eq!(Signer::public_key(&signer), keys.public_key());
+
+        let other = Keys::generate();
+        let events = vec![
+            EventBuilder::text_note("one").build(keys.public_key()),
+            EventBuilder::text_note("two").build(keys.public_key()),
+            EventBuilder::text_note("wrong identity").build(other.public_key()),
+        ];
+        let results = signer.sign_events(events).await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().content, "one");
+        assert_eq!(results[1].as_ref().unwrap().content, "two");
+        assert!(results[2].is_err());
+    }
+}
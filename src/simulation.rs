@@ -0,0 +1,417 @@
+//! Deterministic fan-out simulation, enabled with the `testing` feature
+//!
+//! [`Simulation`] registers fake connections on a [`SubscriptionRegistry`],
+//! with channels in place of sockets, gives them synthetic subscriptions and
+//! replays events on a virtual clock: events are scheduled at virtual times,
+//! created at those times, and distributed in time order without waiting.
+//! Connections, filters and events are drawn from a seeded generator, so a
+//! seed always produces the same workload.
+//!
+//! After each event the simulation drains every connection and compares what
+//! the registry delivered to what the subscriptions' filters match. The
+//! [`SimulationReport`] counts matches, missed and unexpected deliveries and
+//! summarizes how long distribution took, so registry changes such as
+//! sharding or fan-out limits can be checked for correctness and speed.
+//!
+//! ```ignore
+//! let mut simulation = Simulation::new(7);
+//! simulation.add_random_connections(5_000, 3)?;
+//! simulation.schedule_random_events(1_000, Duration::from_secs(600));
+//!
+//! let report = simulation.run().await;
+//! assert!(report.is_correct());
+//! println!("p99 distribution: {:?}", report.latency.p99);
+//! ```
+
+use crate::error::Result;
+use crate::subscription_registry::{ConnectionHandle, EventDistributor, SubscriptionRegistry};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use websocket_builder::MessageSender;
+
+/// Kinds of the synthetic events and filters
+const KINDS: [u16; 4] = [1, 6, 7, 30023];
+
+/// Fake connection and the subscriptions it registered
+struct SimulatedConnection {
+    receiver: flume::Receiver<(RelayMessage<'static>, usize)>,
+    subscriptions: Vec<(SubscriptionId, Vec<Filter>)>,
+    _handle: ConnectionHandle,
+}
+
+/// Spread of the time the distribution of one event took
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// Fastest distribution
+    pub min: Duration,
+    /// Median distribution time
+    pub p50: Duration,
+    /// 90th percentile
+    pub p90: Duration,
+    /// 99th percentile
+    pub p99: Duration,
+    /// Slowest distribution
+    pub max: Duration,
+}
+
+impl LatencySummary {
+    fn from_samples(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+        Self {
+            min: samples[0],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// Outcome of a [`Simulation::run`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulationReport {
+    /// Events distributed
+    pub events: usize,
+    /// Event deliveries the subscriptions' filters call for
+    pub expected_deliveries: usize,
+    /// Event deliveries the connections received
+    pub deliveries: usize,
+    /// Expected deliveries the connections didn't receive
+    pub missed: usize,
+    /// Deliveries to subscriptions that don't match the event
+    pub unexpected: usize,
+    /// Most subscriptions a single event matched
+    pub max_matches: usize,
+    /// Time each event's distribution took
+    pub latency: LatencySummary,
+}
+
+impl SimulationReport {
+    /// Whether every connection received exactly the events it subscribed to
+    pub fn is_correct(&self) -> bool {
+        self.missed == 0 && self.unexpected == 0
+    }
+}
+
+/// Fan-out workload replayed against a subscription registry on a virtual clock
+pub struct Simulation {
+    registry: Arc<SubscriptionRegistry>,
+    scope: Scope,
+    rng: StdRng,
+    authors: Vec<Keys>,
+    topics: Vec<String>,
+    start: Timestamp,
+    now: Timestamp,
+    connections: Vec<SimulatedConnection>,
+    /// Events by the virtual second they are due, in scheduling order
+    schedule: BTreeMap<u64, Vec<Event>>,
+}
+
+impl std::fmt::Debug for Simulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Simulation")
+            .field("scope", &self.scope)
+            .field("now", &self.now)
+            .field("connections", &self.connections.len())
+            .field(
+                "scheduled",
+                &self.schedule.values().map(Vec::len).sum::<usize>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Simulation {
+    /// Simulation on a default registry, with 100 authors and 20 topics drawn from `seed`
+    pub fn new(seed: u64) -> Self {
+        let mut simulation = Self {
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+            scope: Scope::Default,
+            rng: StdRng::seed_from_u64(seed),
+            authors: Vec::new(),
+            topics: (0..20).map(|topic| format!("topic{topic}")).collect(),
+            start: Timestamp::from(1_700_000_000),
+            now: Timestamp::from(1_700_000_000),
+            connections: Vec::new(),
+            schedule: BTreeMap::new(),
+        };
+        simulation.authors = simulation.generate_authors(100);
+        simulation
+    }
+
+    /// Simulate `registry`, e.g. one with distribution shards or a fan-out limit
+    ///
+    /// Set it before adding connections.
+    #[must_use]
+    pub fn with_registry(mut self, registry: SubscriptionRegistry) -> Self {
+        self.registry = Arc::new(registry);
+        self
+    }
+
+    /// Register the connections and distribute the events in `scope`
+    #[must_use]
+    pub fn with_scope(mut self, scope: Scope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Draw events and filters from `count` authors
+    #[must_use]
+    pub fn with_authors(mut self, count: usize) -> Self {
+        self.authors = self.generate_authors(count.max(1));
+        self
+    }
+
+    /// Registry under simulation
+    pub fn registry(&self) -> &Arc<SubscriptionRegistry> {
+        &self.registry
+    }
+
+    /// Virtual time, that of the last distributed event
+    pub fn now(&self) -> Timestamp {
+        self.now
+    }
+
+    /// Register a connection with one subscription per filter list
+    pub fn add_connection(&mut self, subscriptions: Vec<Vec<Filter>>) -> Result<()> {
+        let id = format!("sim-{}", self.connections.len());
+        let (tx, receiver) = flume::bounded(subscriptions.len() + 16);
+        let handle = self.registry.register_connection(
+            id.clone(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(self.scope.clone()),
+        );
+
+        let subscriptions: Vec<_> = subscriptions
+            .into_iter()
+            .enumerate()
+            .map(|(index, filters)| (SubscriptionId::new(format!("sub-{index}")), filters))
+            .collect();
+        for (subscription_id, filters) in &subscriptions {
+            self.registry
+                .add_subscription(&id, subscription_id.clone(), filters.clone())?;
+        }
+
+        self.connections.push(SimulatedConnection {
+            receiver,
+            subscriptions,
+            _handle: handle,
+        });
+        Ok(())
+    }
+
+    /// Register `count` connections with `subscriptions` synthetic subscriptions each
+    pub fn add_random_connections(&mut self, count: usize, subscriptions: usize) -> Result<()> {
+        for _ in 0..count {
+            let filters = (0..subscriptions)
+                .map(|_| vec![self.random_filter()])
+                .collect();
+            self.add_connection(filters)?;
+        }
+        Ok(())
+    }
+
+    /// Distribute `event` `after` the start of the simulation
+    ///
+    /// The event is distributed as is; its `created_at` is up to the caller.
+    pub fn schedule_event(&mut self, after: Duration, event: Event) {
+        self.schedule
+            .entry(after.as_secs())
+            .or_default()
+            .push(event);
+    }
+
+    /// Schedule `count` synthetic events at random times within `over`
+    pub fn schedule_random_events(&mut self, count: usize, over: Duration) {
+        for _ in 0..count {
+            let after = self.rng.gen_range(0..=over.as_secs());
+            let event = self.random_event(self.start + after);
+            self.schedule_event(Duration::from_secs(after), event);
+        }
+    }
+
+    /// Distribute the scheduled events in virtual time order and check the deliveries
+    pub async fn run(&mut self) -> SimulationReport {
+        let mut report = SimulationReport::default();
+        let mut latencies = Vec::new();
+
+        for (after, events) in std::mem::take(&mut self.schedule) {
+            self.now = self.start + after;
+            for event in events {
+                let started = Instant::now();
+                self.registry
+                    .distribute_event(Arc::new(event.clone()), &self.scope)
+                    .await;
+                latencies.push(started.elapsed());
+
+                let mut matches = 0;
+                for connection in &self.connections {
+                    let (expected, received) = connection.deliveries(&event);
+                    report.expected_deliveries += expected.len();
+                    report.deliveries += received.len();
+                    report.missed += expected.iter().filter(|id| !received.contains(id)).count();
+                    report.unexpected +=
+                        received.iter().filter(|id| !expected.contains(id)).count();
+                    matches += received.len();
+                }
+                report.max_matches = report.max_matches.max(matches);
+                report.events += 1;
+            }
+        }
+
+        report.latency = LatencySummary::from_samples(latencies);
+        report
+    }
+
+    fn generate_authors(&mut self, count: usize) -> Vec<Keys> {
+        let mut authors = Vec::with_capacity(count);
+        while authors.len() < count {
+            // Practically every 32 bytes are a valid secret key
+            if let Ok(secret_key) = SecretKey::from_slice(&self.rng.gen::<[u8; 32]>()) {
+                authors.push(Keys::new(secret_key));
+            }
+        }
+        authors
+    }
+
+    fn random_author(&mut self) -> &Keys {
+        self.authors
+            .choose(&mut self.rng)
+            .expect("simulation has authors")
+    }
+
+    fn random_kind(&mut self) -> Kind {
+        Kind::from(*KINDS.choose(&mut self.rng).expect("kinds aren't empty"))
+    }
+
+    fn random_topic(&mut self) -> String {
+        self.topics
+            .choose(&mut self.rng)
+            .expect("simulation has topics")
+            .clone()
+    }
+
+    /// Filter on authors, kinds, a topic or a time range, as clients commonly send
+    fn random_filter(&mut self) -> Filter {
+        match self.rng.gen_range(0..5) {
+            0 => {
+                let count = self.rng.gen_range(1..=3);
+                let authors: Vec<_> = (0..count)
+                    .map(|_| self.random_author().public_key())
+                    .collect();
+                Filter::new().authors(authors)
+            }
+            1 => Filter::new().kind(self.random_kind()),
+            2 => {
+                let author = self.random_author().public_key();
+                Filter::new().author(author).kind(self.random_kind())
+            }
+            3 => Filter::new().hashtag(self.random_topic()),
+            _ => {
+                let since = self.start + self.rng.gen_range(0..3600u64);
+                Filter::new().kind(self.random_kind()).since(since)
+            }
+        }
+    }
+
+    fn random_event(&mut self, created_at: Timestamp) -> Event {
+        let kind = self.random_kind();
+        let topics: Vec<_> = (0..self.rng.gen_range(0..=2))
+            .map(|_| Tag::hashtag(self.random_topic()))
+            .collect();
+        let mut tags = topics;
+        if kind == Kind::from(30023) {
+            tags.push(Tag::identifier(self.rng.gen::<u32>().to_string()));
+        }
+        let author = self.random_author().clone();
+        EventBuilder::new(kind, "")
+            .tags(tags)
+            .custom_created_at(created_at)
+            .sign_with_keys(&author)
+            .expect("signing with generated keys")
+    }
+}
+
+impl SimulatedConnection {
+    /// Subscriptions matching `event`, and those the connection received it on
+    fn deliveries(&self, event: &Event) -> (Vec<SubscriptionId>, Vec<SubscriptionId>) {
+        let expected = self
+            .subscriptions
+            .iter()
+            .filter(|(_, filters)| {
+                filters.iter().any(|filter| {
+                    filter.match_event(event, nostr_sdk::filter::MatchEventOptions::default())
+                })
+            })
+            .map(|(subscription_id, _)| subscription_id.clone())
+            .collect();
+
+        let received = self
+            .receiver
+            .try_iter()
+            .filter_map(|(message, _)| match message {
+                RelayMessage::Event {
+                    subscription_id,
+                    event: delivered,
+                } if delivered.id == event.id => Some(subscription_id.into_owned()),
+                _ => None,
+            })
+            .collect();
+        (expected, received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registry_delivers_exactly_the_matching_events() {
+        let mut simulation = Simulation::new(7).with_authors(20);
+        simulation.add_random_connections(300, 3).unwrap();
+        simulation.schedule_random_events(200, Duration::from_secs(3600));
+
+        let report = simulation.run().await;
+
+        assert_eq!(report.events, 200);
+        assert!(report.expected_deliveries > 0);
+        assert_eq!(report.deliveries, report.expected_deliveries);
+        assert!(report.is_correct(), "{report:?}");
+        assert!(simulation.now() > Timestamp::from(1_700_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_fanout_limit_shows_as_missed_deliveries() {
+        let registry = SubscriptionRegistry::new(None).with_fanout_limit(1);
+        let mut simulation = Simulation::new(7).with_registry(registry);
+        let notes = Filter::new().kind(Kind::TextNote);
+        for _ in 0..3 {
+            simulation
+                .add_connection(vec![vec![notes.clone()]])
+                .unwrap();
+        }
+        let keys = Keys::generate();
+        let note = EventBuilder::text_note("hello")
+            .sign_with_keys(&keys)
+            .unwrap();
+        simulation.schedule_event(Duration::from_secs(10), note);
+
+        let report = simulation.run().await;
+
+        assert_eq!(report.expected_deliveries, 3);
+        assert_eq!(report.deliveries, 1);
+        assert_eq!(report.missed, 2);
+        assert!(!report.is_correct());
+    }
+}
@@ -0,0 +1,164 @@
+//! Slow query log for historical REQ queries
+//!
+//! When serving the stored events of a REQ takes longer than the configured
+//! threshold, the query is logged with its filters, scope, duration,
+//! pagination attempts and how many events were scanned, filtered out and
+//! sent. The last
+//! slow queries can also be kept in a ring buffer and read back through
+//! [`SlowQueryLog::entries`], e.g. from an admin endpoint, to find
+//! pathological REQ patterns.
+
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Default number of slow queries kept for [`SlowQueryLog::entries`]
+pub const DEFAULT_SLOW_QUERY_CAPACITY: usize = 100;
+
+/// A REQ whose historical query exceeded the threshold
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowQuery {
+    pub connection_id: String,
+    pub subscription_id: SubscriptionId,
+    /// Filters after their limits were capped
+    pub filters: Vec<Filter>,
+    pub scope: Scope,
+    /// Time from the first database query to EOSE
+    pub duration: Duration,
+    /// Database queries run, summed over the filters
+    pub pagination_attempts: usize,
+    /// Events returned by the database
+    pub events_scanned: usize,
+    /// Events sent to the client
+    pub events_sent: usize,
+    /// Scanned events hidden from the client by the visibility filter
+    pub events_filtered: usize,
+    pub recorded_at: Timestamp,
+}
+
+/// Threshold and ring buffer of slow queries
+#[derive(Debug, Clone)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<SlowQuery>>>,
+}
+
+impl SlowQueryLog {
+    /// Log queries taking at least `threshold`
+    pub fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            capacity: DEFAULT_SLOW_QUERY_CAPACITY,
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Keep the last `capacity` slow queries, 0 only logs them
+    #[must_use]
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Queries taking at least this long are slow
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// Whether a query that took `duration` is slow
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        duration >= self.threshold
+    }
+
+    /// Log `query` and keep it in the buffer
+    pub fn record(&self, query: SlowQuery) {
+        warn!(
+            "Slow query for subscription {} on connection {} in scope {:?}: {:?}, {} pagination attempts, {} events scanned, {} filtered out, {} sent, filters: [{}]",
+            query.subscription_id,
+            query.connection_id,
+            query.scope,
+            query.duration,
+            query.pagination_attempts,
+            query.events_scanned,
+            query.events_filtered,
+            query.events_sent,
+            query
+                .filters
+                .iter()
+                .map(|filter| filter.as_json())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(query);
+    }
+
+    /// The kept slow queries, oldest first
+    pub fn entries(&self) -> Vec<SlowQuery> {
+        self.entries.lock().iter().cloned().collect()
+    }
+
+    /// Forget the kept slow queries
+    pub fn clear(&self) {
+        self.entries.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slow_query(subscription_id: &str) -> SlowQuery {
+        SlowQuery {
+            connection_id: "conn".to_string(),
+            subscription_id: SubscriptionId::new(subscription_id),
+            filters: vec![Filter::new().kind(Kind::TextNote)],
+            scope: Scope::Default,
+            duration: Duration::from_millis(600),
+            pagination_attempts: 3,
+            events_scanned: 1500,
+            events_sent: 2,
+            events_filtered: 1498,
+            recorded_at: Timestamp::now(),
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_latest() {
+        let log = SlowQueryLog::new(Duration::from_millis(500)).with_capacity(2);
+        assert!(log.is_slow(Duration::from_millis(500)));
+        assert!(!log.is_slow(Duration::from_millis(499)));
+
+        for subscription_id in ["a", "b", "c"] {
+            log.record(slow_query(subscription_id));
+        }
+        let kept: Vec<_> = log
+            .entries()
+            .into_iter()
+            .map(|query| query.subscription_id)
+            .collect();
+        assert_eq!(
+            kept,
+            vec![SubscriptionId::new("b"), SubscriptionId::new("c")]
+        );
+
+        log.clear();
+        assert!(log.entries().is_empty());
+
+        let log_only = SlowQueryLog::new(Duration::ZERO).with_capacity(0);
+        log_only.record(slow_query("a"));
+        assert!(log_only.entries().is_empty());
+    }
+}
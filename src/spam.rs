@@ -0,0 +1,316 @@
+//! Spam scoring of incoming events
+//!
+//! A [`SpamScorer`] sees every new event in the spam stage of the
+//! [ingest pipeline](crate::ingest), once its signature is verified and it is
+//! known not to be a duplicate. It gets a summary of what the author sent
+//! recently and metadata of the connection the event came from, and answers a
+//! [`SpamVerdict`]:
+//!
+//! - [`SpamVerdict::Reject`]: `OK false` with a `blocked:` reason
+//! - [`SpamVerdict::ShadowAccept`]: `OK true`, but the event is neither stored
+//!   nor distributed, so the sender can't tell it was dropped
+//! - [`SpamVerdict::Tag`]: the event is stored and distributed, and queued in
+//!   the [`ReportQueue`] for moderators, with the label as report type
+//!
+//! [`HeuristicScorer`] is the built-in scorer: it shadow-accepts repeated
+//! content, rejects events mentioning too many pubkeys and tags authors
+//! publishing in bursts. Register a scorer with
+//! [`RelayBuilder::with_spam_scorer`](crate::RelayBuilder::with_spam_scorer).
+//!
+//! Author histories are kept in memory for the last hour, for the most recent
+//! authors only, and count every scored event whatever its verdict.
+
+use crate::ingest::{Admission, ConnectionMetadata};
+use crate::reports::ReportQueue;
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use lru::LruCache;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// Seconds of history kept per author
+const HISTORY_WINDOW: u64 = 3600;
+
+/// Authors whose history is kept, least recently seen dropped first
+const TRACKED_AUTHORS: usize = 100_000;
+
+/// Events kept per author
+const EVENTS_PER_AUTHOR: usize = 256;
+
+/// What to do with a scored event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpamVerdict {
+    /// Let the event through
+    Accept,
+    /// Refuse the event; the reason is sent to the client as `blocked:`
+    Reject(String),
+    /// Answer `OK true` without storing or distributing the event
+    ShadowAccept,
+    /// Store the event and queue it for moderators under this label
+    Tag(String),
+}
+
+/// Recent activity of an event's author, the scored event excluded
+///
+/// Counts cover the events scored in the last hour, at most the latest 256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AuthorHistory {
+    /// Events sent in the last minute
+    pub last_minute: usize,
+    /// Events sent in the last hour
+    pub last_hour: usize,
+    /// Events of the last hour with the same content as the scored one
+    pub same_content: usize,
+}
+
+/// Decides whether an event is spam
+#[async_trait]
+pub trait SpamScorer: Send + Sync + std::fmt::Debug {
+    /// Verdict on `event`, sent by `author_history`'s author over `connection`
+    async fn score(
+        &self,
+        event: &Event,
+        author_history: &AuthorHistory,
+        connection: &ConnectionMetadata,
+    ) -> SpamVerdict;
+}
+
+/// Built-in scorer of duplicate content, mention flooding and bursts
+///
+/// Checked in that order, the first limit exceeded decides:
+///
+/// - content the author already sent `max_duplicates` times in the last hour
+///   is shadow-accepted
+/// - events with more `p` tags than `max_mentions` are rejected
+/// - events beyond `max_per_minute` in a minute are tagged `burst`
+///
+/// Empty content, e.g. of reactions and most replaceable events, is never
+/// counted as duplicate.
+#[derive(Debug, Clone)]
+pub struct HeuristicScorer {
+    max_duplicates: usize,
+    max_mentions: usize,
+    max_per_minute: usize,
+}
+
+impl Default for HeuristicScorer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeuristicScorer {
+    /// 3 duplicates an hour, 50 mentions per event and 30 events a minute
+    pub fn new() -> Self {
+        Self {
+            max_duplicates: 3,
+            max_mentions: 50,
+            max_per_minute: 30,
+        }
+    }
+
+    /// Shadow-accept content sent more than `max_duplicates` times in an hour
+    #[must_use]
+    pub fn with_max_duplicates(mut self, max_duplicates: usize) -> Self {
+        self.max_duplicates = max_duplicates;
+        self
+    }
+
+    /// Reject events mentioning more than `max_mentions` pubkeys
+    #[must_use]
+    pub fn with_max_mentions(mut self, max_mentions: usize) -> Self {
+        self.max_mentions = max_mentions;
+        self
+    }
+
+    /// Tag events of authors sending more than `max_per_minute` events a minute
+    #[must_use]
+    pub fn with_max_per_minute(mut self, max_per_minute: usize) -> Self {
+        self.max_per_minute = max_per_minute;
+        self
+    }
+}
+
+#[async_trait]
+impl SpamScorer for HeuristicScorer {
+    async fn score(
+        &self,
+        event: &Event,
+        author_history: &AuthorHistory,
+        _: &ConnectionMetadata,
+    ) -> SpamVerdict {
+        if !event.content.is_empty() && author_history.same_content >= self.max_duplicates {
+            return SpamVerdict::ShadowAccept;
+        }
+        let mentions = event.tags.public_keys().count();
+        if mentions > self.max_mentions {
+            return SpamVerdict::Reject(format!("too many mentions ({mentions})"));
+        }
+        if author_history.last_minute >= self.max_per_minute {
+            return SpamVerdict::Tag("burst".to_string());
+        }
+        SpamVerdict::Accept
+    }
+}
+
+/// Events an author sent recently: receipt time and content hash
+type AuthorEvents = VecDeque<(Timestamp, u64)>;
+
+/// Spam stage of the ingest pipeline: a scorer with the author histories it reads
+#[derive(Clone)]
+pub struct SpamFilter {
+    scorer: Arc<dyn SpamScorer>,
+    reports: Option<ReportQueue>,
+    history: Arc<Mutex<LruCache<PublicKey, AuthorEvents>>>,
+}
+
+impl std::fmt::Debug for SpamFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpamFilter")
+            .field("scorer", &self.scorer)
+            .field("tracked_authors", &self.history.lock().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SpamFilter {
+    /// Score events with `scorer`, logging tagged events
+    pub fn new(scorer: Arc<dyn SpamScorer>) -> Self {
+        Self {
+            scorer,
+            reports: None,
+            history: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(TRACKED_AUTHORS).expect("capacity is non-zero"),
+            ))),
+        }
+    }
+
+    /// Queue tagged events in `reports`
+    #[must_use]
+    pub fn with_report_queue(mut self, reports: Option<ReportQueue>) -> Self {
+        self.reports = reports;
+        self
+    }
+
+    /// Score `event` about to be saved to `scope`, then add it to its author's history
+    pub(crate) async fn admit(
+        &self,
+        event: &Event,
+        scope: &Scope,
+        connection: &ConnectionMetadata,
+    ) -> Admission {
+        let author_history = self.observe(event);
+        match self.scorer.score(event, &author_history, connection).await {
+            SpamVerdict::Accept => Admission::Accept,
+            SpamVerdict::Reject(reason) => Admission::Reject(ClosedReason::Blocked(reason)),
+            SpamVerdict::ShadowAccept => {
+                debug!("Shadow-accepted event {} as spam", event.id);
+                Admission::ShadowAccept
+            }
+            SpamVerdict::Tag(label) => {
+                match &self.reports {
+                    Some(reports) => reports.flag(event, scope, &label),
+                    None => info!("Event {} tagged as spam: {}", event.id, label),
+                }
+                Admission::Accept
+            }
+        }
+    }
+
+    /// History of the author of `event`, before adding `event` to it
+    fn observe(&self, event: &Event) -> AuthorHistory {
+        let now = Timestamp::now();
+        let content = content_hash(&event.content);
+        let mut history = self.history.lock();
+        let events = history.get_or_insert_mut(event.pubkey, VecDeque::new);
+        while events
+            .front()
+            .is_some_and(|(received_at, _)| *received_at + HISTORY_WINDOW < now)
+        {
+            events.pop_front();
+        }
+
+        let summary = AuthorHistory {
+            last_minute: events
+                .iter()
+                .filter(|(received_at, _)| *received_at + 60 >= now)
+                .count(),
+            last_hour: events.len(),
+            same_content: events.iter().filter(|(_, hash)| *hash == content).count(),
+        };
+
+        if events.len() >= EVENTS_PER_AUTHOR {
+            events.pop_front();
+        }
+        events.push_back((now, content));
+        summary
+    }
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reports::ReportTarget;
+
+    #[tokio::test]
+    async fn test_heuristics_shadow_reject_and_tag() {
+        let keys = Keys::generate();
+        let reports = ReportQueue::new();
+        let filter = SpamFilter::new(Arc::new(HeuristicScorer::new().with_max_per_minute(5)))
+            .with_report_queue(Some(reports.clone()));
+        let connection = ConnectionMetadata::default();
+        let scope = Scope::Default;
+
+        let note = |content: &str| {
+            EventBuilder::text_note(content)
+                .sign_with_keys(&keys)
+                .unwrap()
+        };
+
+        // The fourth copy of the same content is dropped silently
+        for _ in 0..3 {
+            assert_eq!(
+                filter.admit(&note("buy now"), &scope, &connection).await,
+                Admission::Accept
+            );
+        }
+        assert_eq!(
+            filter.admit(&note("buy now"), &scope, &connection).await,
+            Admission::ShadowAccept
+        );
+
+        let flood = EventBuilder::text_note("hey")
+            .tags((0..60).map(|_| Tag::public_key(Keys::generate().public_key())))
+            .sign_with_keys(&keys)
+            .unwrap();
+        assert!(matches!(
+            filter.admit(&flood, &scope, &connection).await,
+            Admission::Reject(ClosedReason::Blocked(_))
+        ));
+
+        // Five events already this minute: stored, but queued for moderators
+        let burst = note("one more");
+        assert_eq!(
+            filter.admit(&burst, &scope, &connection).await,
+            Admission::Accept
+        );
+        let entry = reports
+            .entry(&scope, &ReportTarget::Event(burst.id))
+            .unwrap();
+        assert_eq!(entry.author, Some(keys.public_key()));
+        assert_eq!(entry.report_types.get("burst"), Some(&1));
+    }
+}
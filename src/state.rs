@@ -1,9 +1,8 @@
 //! Connection state management
 
-use crate::database::RelayDatabase;
 use crate::error::Error;
-use crate::subscription_coordinator::StoreCommand;
-use crate::subscription_coordinator::SubscriptionCoordinator;
+use crate::subscription_coordinator::{ClosedReason, StoreCommand};
+use crate::subscription_coordinator::{SubscriptionCoordinator, SubscriptionCoordinatorBuilder};
 use crate::subscription_registry::SubscriptionRegistry;
 use anyhow::Result;
 use negentropy::{Negentropy, NegentropyStorageVector};
@@ -13,7 +12,6 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error};
-use websocket_builder::MessageSender;
 
 const DEFAULT_RELAY_URL: &str = "wss://default.relay";
 
@@ -43,6 +41,14 @@ pub struct NostrConnectionState<T = ()> {
     pub(crate) registry: Option<Arc<SubscriptionRegistry>>,
     /// The subdomain scope for this connection
     pub subdomain: Arc<Scope>,
+    /// Address the client connected from, reported in the connection stats
+    pub remote_address: Option<String>,
+    /// `User-Agent` header of the WebSocket upgrade, if any
+    pub user_agent: Option<String>,
+    /// Latency timeline of the EVENT currently being processed
+    pub(crate) event_timeline: Option<crate::latency::EventTimeline>,
+    /// Malformed messages received, counted when they are answered with a NOTICE
+    pub(crate) parse_errors: u32,
     /// Custom state that can be managed by middleware
     pub custom_state: T,
 }
@@ -63,6 +69,10 @@ where
             connection_token: CancellationToken::new(),
             registry: None,
             subdomain: Arc::new(Scope::Default),
+            remote_address: None,
+            user_agent: None,
+            event_timeline: None,
+            parse_errors: 0,
             custom_state: T::default(),
         }
     }
@@ -85,6 +95,10 @@ where
             connection_token: CancellationToken::new(),
             registry: None,
             subdomain: Arc::new(Scope::Default),
+            remote_address: None,
+            user_agent: None,
+            event_timeline: None,
+            parse_errors: 0,
             custom_state: T::default(),
         })
     }
@@ -106,6 +120,10 @@ where
             connection_token: self.connection_token.clone(),
             registry: self.registry.clone(),
             subdomain: self.subdomain.clone(),
+            remote_address: self.remote_address.clone(),
+            user_agent: self.user_agent.clone(),
+            event_timeline: None,
+            parse_errors: self.parse_errors,
             custom_state: self.custom_state.clone(),
         }
     }
@@ -128,6 +146,10 @@ impl<T> NostrConnectionState<T> {
             connection_token: CancellationToken::new(),
             registry: None,
             subdomain: Arc::new(Scope::Default),
+            remote_address: None,
+            user_agent: None,
+            event_timeline: None,
+            parse_errors: 0,
             custom_state,
         })
     }
@@ -136,6 +158,9 @@ impl<T> NostrConnectionState<T> {
     pub fn set_authenticated(&mut self, pubkey: PublicKey) {
         self.authed_pubkey = Some(pubkey);
         self.challenge = None; // Clear challenge after successful auth
+        if let Some(coordinator) = &self.subscription_coordinator {
+            coordinator.set_auth_pubkey(pubkey);
+        }
     }
 
     /// Check if the user is authenticated
@@ -144,31 +169,25 @@ impl<T> NostrConnectionState<T> {
     }
 
     /// Setup the connection with database and registry
+    ///
+    /// The connection's authenticated pubkey, scope, cancellation token,
+    /// remote address and the global subscription metrics handler are filled
+    /// into `coordinator` before it is built.
     pub fn setup_connection(
         &mut self,
-        database: Arc<RelayDatabase>,
-        registry: Arc<SubscriptionRegistry>,
-        connection_id: String,
-        sender: MessageSender<RelayMessage<'static>>,
-        crypto_helper: crate::crypto_helper::CryptoHelper,
-        max_limit: Option<usize>,
+        coordinator: SubscriptionCoordinatorBuilder,
     ) -> Result<(), Error> {
-        debug!("Setting up connection for {}", connection_id);
+        debug!("Setting up connection");
 
         let metrics_handler = crate::global_metrics::get_subscription_metrics_handler();
 
-        let coordinator = SubscriptionCoordinator::new(
-            database,
-            crypto_helper,
-            registry,
-            connection_id,
-            sender,
-            self.authed_pubkey,
-            self.subdomain.clone(),
-            self.connection_token.clone(),
-            metrics_handler,
-            max_limit.unwrap_or(1000), // Default to 1000 if not specified
-        );
+        let coordinator = coordinator
+            .with_auth_pubkey(self.authed_pubkey)
+            .with_scope(self.subdomain.clone())
+            .with_cancellation_token(self.connection_token.clone())
+            .with_metrics_handler(metrics_handler)
+            .with_remote_address(self.remote_address.clone())
+            .build();
         self.subscription_coordinator = Some(coordinator);
 
         debug!("Connection setup complete");
@@ -209,6 +228,20 @@ impl<T> NostrConnectionState<T> {
         coordinator.remove_subscription(subscription_id)
     }
 
+    /// Terminate a subscription server-side with a machine-readable `CLOSED` reason
+    pub fn close_subscription(
+        &mut self,
+        subscription_id: SubscriptionId,
+        reason: ClosedReason,
+    ) -> Result<(), Error> {
+        let Some(coordinator) = &self.subscription_coordinator else {
+            return Err(Error::internal("No subscription coordinator available"));
+        };
+
+        self.active_subscriptions.remove(&subscription_id);
+        coordinator.close_subscription(subscription_id, reason)
+    }
+
     /// Get the subscription coordinator (only for internal use)
     pub(crate) fn subscription_coordinator(&self) -> Option<&SubscriptionCoordinator> {
         self.subscription_coordinator.as_ref()
@@ -270,7 +303,7 @@ impl<T> NostrConnectionState<T> {
         if !self.can_accept_subscription() {
             let count = self.active_subscriptions.len();
             let max = self.max_subscriptions.unwrap_or(0);
-            return Err(Error::internal(format!(
+            return Err(Error::rate_limited(format!(
                 "Subscription limit exceeded: {count}/{max} active subscriptions"
             )));
         }
@@ -282,7 +315,7 @@ impl<T> NostrConnectionState<T> {
             if self.active_subscriptions.len() > max {
                 self.remove_tracked_subscription(&subscription_id);
                 let count = self.active_subscriptions.len();
-                return Err(Error::internal(format!(
+                return Err(Error::rate_limited(format!(
                     "Subscription limit exceeded after adding: {count}/{max}"
                 )));
             }
@@ -0,0 +1,260 @@
+//! Relay status events published over nostr
+//!
+//! A [`StatusPublisher`] periodically publishes, in every scope with
+//! connections or recent events and in the default scope, an event signed by
+//! the relay identity describing the scope's activity as seen by the
+//! [`SubscriptionRegistry`]: connections, open subscriptions, accepted events
+//! per second and uptime. Monitoring clients subscribe to the relay's own
+//! pubkey and kind to track its health without a separate metrics endpoint.
+//!
+//! The status also carries the time the database takes to read and write.
+//! By default the status is a NIP-78 application data event whose content is
+//! the [`RelayStatus`] as JSON, replaced at every publication.
+//! [`StatusPublisher::with_format`] publishes other kinds, e.g. NIP-66
+//! discovery events, and [`StatusPublisher::with_announcement`] an event
+//! published once at startup.
+
+use crate::crypto_helper::CryptoHelper;
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::subscription_registry::{EventDistributor, SubscriptionRegistry};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+/// Kind of the default status events (NIP-78 application data)
+pub const RELAY_STATUS_KIND: Kind = Kind::ApplicationSpecificData;
+
+/// `d` tag of the default status events
+pub const RELAY_STATUS_IDENTIFIER: &str = "relay_builder/status";
+
+/// Activity of one scope at the time a status is published
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RelayStatus {
+    /// Scope the status describes and is published in
+    #[serde(skip)]
+    pub scope: Scope,
+    /// Connections of the scope
+    pub connections: usize,
+    /// Subscriptions open on those connections
+    pub active_subscriptions: usize,
+    /// Events accepted per second, averaged over the last minute
+    pub events_per_second: f64,
+    /// Seconds since the relay started
+    pub uptime: u64,
+    /// Milliseconds an index lookup in the scope's database took
+    pub rtt_read: Option<u64>,
+    /// Milliseconds signing, saving and distributing the previous status of the scope took
+    pub rtt_write: Option<u64>,
+}
+
+/// Builds the status event of one scope
+pub type StatusFormat = Arc<dyn Fn(&RelayStatus) -> EventBuilder + Send + Sync>;
+
+/// Relay components the publisher works with, available once the relay is built
+pub(crate) struct StatusContext {
+    pub(crate) database: Arc<RelayDatabase>,
+    pub(crate) registry: Arc<SubscriptionRegistry>,
+    pub(crate) crypto_helper: CryptoHelper,
+}
+
+/// Periodic publisher of relay-signed status events
+#[derive(Clone)]
+pub struct StatusPublisher {
+    interval: Duration,
+    format: StatusFormat,
+    announcement: Option<EventBuilder>,
+    /// Time the last status save took in each scope, in milliseconds
+    write_times: Arc<Mutex<HashMap<Scope, u64>>>,
+}
+
+impl std::fmt::Debug for StatusPublisher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatusPublisher")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StatusPublisher {
+    /// Publish the status of every active scope each `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            format: Arc::new(default_format),
+            announcement: None,
+            write_times: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Publish the events `format` builds instead of the NIP-78 default
+    #[must_use]
+    pub fn with_format(
+        mut self,
+        format: impl Fn(&RelayStatus) -> EventBuilder + Send + Sync + 'static,
+    ) -> Self {
+        self.format = Arc::new(format);
+        self
+    }
+
+    /// Publish `announcement` in the default scope when the relay starts
+    #[must_use]
+    pub fn with_announcement(mut self, announcement: EventBuilder) -> Self {
+        self.announcement = Some(announcement);
+        self
+    }
+
+    /// Publish statuses every interval until `cancellation_token` fires
+    pub(crate) fn spawn(
+        &self,
+        task_tracker: &TaskTracker,
+        cancellation_token: Option<CancellationToken>,
+        context: StatusContext,
+    ) {
+        let publisher = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+        let started = Instant::now();
+
+        task_tracker.spawn(async move {
+            if let Some(announcement) = &publisher.announcement {
+                if let Err(e) = publisher
+                    .publish_event(&context, announcement.clone(), &Scope::Default)
+                    .await
+                {
+                    warn!("Failed to publish relay announcement: {}", e);
+                }
+            }
+
+            let mut ticker = tokio::time::interval(publisher.interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => publisher.publish(&context, started.elapsed()).await,
+                }
+            }
+            debug!("Status publisher stopped");
+        });
+    }
+
+    /// Status of the default scope and of every scope with activity
+    fn statuses(&self, registry: &SubscriptionRegistry, uptime: Duration) -> Vec<RelayStatus> {
+        let mut activity = registry.scope_activity();
+        activity.entry(Scope::Default).or_default();
+
+        let write_times = self.write_times.lock();
+        activity
+            .into_iter()
+            .map(|(scope, activity)| RelayStatus {
+                rtt_write: write_times.get(&scope).copied(),
+                scope,
+                connections: activity.connections,
+                active_subscriptions: activity.active_subscriptions,
+                events_per_second: activity.events_per_second,
+                uptime: uptime.as_secs(),
+                rtt_read: None,
+            })
+            .collect()
+    }
+
+    /// Publish the status of each scope
+    async fn publish(&self, context: &StatusContext, uptime: Duration) {
+        for mut status in self.statuses(&context.registry, uptime) {
+            let started = Instant::now();
+            let probe = EventId::from_byte_array([0; 32]);
+            if context
+                .database
+                .has_event(&probe, &status.scope)
+                .await
+                .is_ok()
+            {
+                status.rtt_read = Some(started.elapsed().as_millis() as u64);
+            }
+
+            let started = Instant::now();
+            match self
+                .publish_event(context, (self.format)(&status), &status.scope)
+                .await
+            {
+                Ok(()) => {
+                    let write_time = started.elapsed().as_millis() as u64;
+                    self.write_times.lock().insert(status.scope, write_time);
+                }
+                Err(e) => warn!(
+                    "Failed to publish relay status for scope {:?}: {}",
+                    status.scope, e
+                ),
+            }
+        }
+    }
+
+    /// Sign `builder`'s event with the relay identity, store and distribute it
+    async fn publish_event(
+        &self,
+        context: &StatusContext,
+        builder: EventBuilder,
+        scope: &Scope,
+    ) -> Result<()> {
+        let unsigned = builder.build(context.crypto_helper.public_key());
+        let event = context.crypto_helper.sign_event(unsigned).await?;
+        context.database.save_event(&event, scope).await?;
+        context
+            .registry
+            .distribute_event(Arc::new(event), scope)
+            .await;
+        Ok(())
+    }
+}
+
+/// NIP-78 event carrying the status as JSON
+fn default_format(status: &RelayStatus) -> EventBuilder {
+    let content = serde_json::to_string(status).unwrap_or_default();
+    EventBuilder::new(RELAY_STATUS_KIND, content).tag(Tag::identifier(RELAY_STATUS_IDENTIFIER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test;
+
+    #[tokio::test]
+    async fn test_status_is_published_per_scope() {
+        let (_tmp_dir, database, keys) = setup_test().await;
+        let context = StatusContext {
+            database: database.clone(),
+            registry: Arc::new(SubscriptionRegistry::new(None)),
+            crypto_helper: CryptoHelper::new(Arc::new(keys.clone())),
+        };
+        let tenant = Scope::named("tenant").unwrap();
+        let (tx, _rx) = flume::bounded(10);
+        let _connection = context.registry.register_connection(
+            "conn".to_string(),
+            websocket_builder::MessageSender::new(tx, 0),
+            None,
+            Arc::new(tenant.clone()),
+        );
+        let publisher = StatusPublisher::new(Duration::from_secs(60));
+
+        publisher.publish(&context, Duration::from_secs(65)).await;
+
+        let filter = Filter::new()
+            .author(keys.public_key())
+            .kind(RELAY_STATUS_KIND)
+            .identifier(RELAY_STATUS_IDENTIFIER);
+        for (scope, connections) in [(Scope::Default, 0), (tenant, 1)] {
+            let statuses = database.query(vec![filter.clone()], &scope).await.unwrap();
+            assert_eq!(statuses.len(), 1);
+            let status: serde_json::Value =
+                serde_json::from_str(&statuses.into_iter().next().unwrap().content).unwrap();
+            assert_eq!(status["connections"], connections);
+            assert_eq!(status["uptime"], 65);
+        }
+    }
+}
@@ -3,20 +3,34 @@
 //! This module replaces the actor-based subscription_service with a simpler
 //! coordinator that integrates with the SubscriptionRegistry for live events.
 
-use crate::database::RelayDatabase;
+use crate::database::{BatchWrite, ReadReplicas, RelayDatabase};
 use crate::error::Error;
-use crate::metrics::SubscriptionMetricsHandler;
+use crate::event_policy::EventPolicyChain;
+use crate::ingest::{Admission, ConnectionMetadata, IngestOutcome, IngestPipeline, IngestStage};
+use crate::kind_router::KindRouter;
+use crate::latency::EventTimeline;
+use crate::metrics::{ReqStats, SubscriptionMetricsHandler};
+use crate::pagination::Paginator;
+use crate::post_save::PostSaveHooks;
+use crate::provenance::IngestPath;
+use crate::query_planner::{plan_queries, QueryPlan};
+use crate::receipts::ReceivedEvent;
+use crate::resume::ResumeCursors;
+use crate::runtime_config::ReloadableConfig;
+use crate::slow_query_log::{SlowQuery, SlowQueryLog};
 use crate::subscription_registry::{EventDistributor, SubscriptionRegistry};
+use crate::supervisor::TaskSupervisor;
 use flume;
+use futures_util::future::{BoxFuture, FutureExt};
 use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::oneshot;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, warn, Instrument, Span};
 use websocket_builder::MessageSender;
 
 #[derive(Debug)]
@@ -25,6 +39,154 @@ pub enum ResponseHandler {
     MessageSender(MessageSender<RelayMessage<'static>>),
 }
 
+/// Machine-readable reason for a NIP-01 `CLOSED` message
+///
+/// The reason is sent as `<prefix>: <message>` so clients can tell a policy
+/// decision from a transient failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClosedReason {
+    /// The client must authenticate (NIP-42) before this subscription is allowed
+    AuthRequired(String),
+    /// The client is not allowed to read these events
+    Restricted(String),
+    /// The client is subscribing too fast or holds too many subscriptions
+    RateLimited(String),
+    /// The filters are invalid
+    Invalid(String),
+    /// The client or its filters are blocked
+    Blocked(String),
+    /// The client must pay before it may publish
+    PaymentRequired(String),
+    /// The relay failed to serve the subscription
+    Error(String),
+}
+
+impl ClosedReason {
+    /// The NIP-01 prefix, without the trailing colon
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::AuthRequired(_) => "auth-required",
+            Self::Restricted(_) => "restricted",
+            Self::RateLimited(_) => "rate-limited",
+            Self::Invalid(_) => "invalid",
+            Self::Blocked(_) => "blocked",
+            Self::PaymentRequired(_) => "payment-required",
+            Self::Error(_) => "error",
+        }
+    }
+
+    /// The human-readable part of the reason
+    pub fn message(&self) -> &str {
+        match self {
+            Self::AuthRequired(message)
+            | Self::Restricted(message)
+            | Self::RateLimited(message)
+            | Self::Invalid(message)
+            | Self::Blocked(message)
+            | Self::PaymentRequired(message)
+            | Self::Error(message) => message,
+        }
+    }
+}
+
+impl std::fmt::Display for ClosedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.prefix(), self.message())
+    }
+}
+
+/// Machine-readable reason for a NIP-01 `OK` message
+///
+/// Like [`ClosedReason`] it is sent as `<prefix>: <message>`. Only
+/// [`OkReason::Duplicate`] accompanies an accepted event; the message is
+/// shown to clients as is, so it must never carry internal error details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OkReason {
+    /// The relay already has the event
+    Duplicate(String),
+    /// The author or the event is blocked
+    Blocked(String),
+    /// The client is publishing too fast
+    RateLimited(String),
+    /// The event is malformed or breaks the relay's limits
+    Invalid(String),
+    /// The event lacks the required proof of work
+    Pow(String),
+    /// The client must authenticate (NIP-42) before publishing
+    AuthRequired(String),
+    /// The client is not allowed to publish this event
+    Restricted(String),
+    /// The client must pay before it may publish
+    PaymentRequired(String),
+    /// The relay failed to store the event
+    Error(String),
+}
+
+impl OkReason {
+    /// The NIP-01 prefix, without the trailing colon
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::Duplicate(_) => "duplicate",
+            Self::Blocked(_) => "blocked",
+            Self::RateLimited(_) => "rate-limited",
+            Self::Invalid(_) => "invalid",
+            Self::Pow(_) => "pow",
+            Self::AuthRequired(_) => "auth-required",
+            Self::Restricted(_) => "restricted",
+            Self::PaymentRequired(_) => "payment-required",
+            Self::Error(_) => "error",
+        }
+    }
+
+    /// The human-readable part of the reason
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Duplicate(message)
+            | Self::Blocked(message)
+            | Self::RateLimited(message)
+            | Self::Invalid(message)
+            | Self::Pow(message)
+            | Self::AuthRequired(message)
+            | Self::Restricted(message)
+            | Self::PaymentRequired(message)
+            | Self::Error(message) => message,
+        }
+    }
+
+    /// Whether the `OK` message reports the event as accepted
+    pub fn accepted(&self) -> bool {
+        matches!(self, Self::Duplicate(_))
+    }
+
+    /// The `OK` message for `event_id`
+    pub fn to_message(&self, event_id: EventId) -> RelayMessage<'static> {
+        RelayMessage::ok(event_id, self.accepted(), self.to_string())
+    }
+}
+
+impl std::fmt::Display for OkReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.prefix(), self.message())
+    }
+}
+
+impl From<ClosedReason> for OkReason {
+    fn from(reason: ClosedReason) -> Self {
+        match reason {
+            ClosedReason::AuthRequired(message) => Self::AuthRequired(message),
+            ClosedReason::Restricted(message) => Self::Restricted(message),
+            ClosedReason::RateLimited(message) => Self::RateLimited(message),
+            ClosedReason::Invalid(message) => Self::Invalid(message),
+            ClosedReason::Blocked(message) => Self::Blocked(message),
+            ClosedReason::PaymentRequired(message) => Self::PaymentRequired(message),
+            ClosedReason::Error(message) => Self::Error(message),
+        }
+    }
+}
+
+/// `OK` message for an event the relay already stored
+pub const DUPLICATE_EVENT_MESSAGE: &str = "duplicate: already have this event";
+
 /// Commands that can be executed against the database
 #[derive(Debug)]
 pub enum StoreCommand {
@@ -42,6 +204,12 @@ pub enum StoreCommand {
         Scope,
         Option<oneshot::Sender<Result<(), crate::error::Error>>>,
     ),
+    /// Apply the commands together, all of them or none
+    ///
+    /// The commands must share one scope. Unsigned events are signed right
+    /// away instead of going through the replaceable buffer, and one rejected
+    /// or failed command fails the whole batch.
+    Batch(Vec<StoreCommand>),
 }
 
 impl StoreCommand {
@@ -51,6 +219,9 @@ impl StoreCommand {
             StoreCommand::SaveSignedEvent(_, scope, _) => scope,
             StoreCommand::SaveUnsignedEvent(_, scope, _) => scope,
             StoreCommand::DeleteEvents(_, scope, _) => scope,
+            StoreCommand::Batch(commands) => commands
+                .first()
+                .map_or(&Scope::Default, StoreCommand::subdomain_scope),
         }
     }
 
@@ -64,6 +235,17 @@ impl StoreCommand {
                 event.kind.is_replaceable() || event.kind.is_addressable()
             }
             StoreCommand::DeleteEvents(_, _, _) => false,
+            StoreCommand::Batch(commands) => commands.iter().any(StoreCommand::is_replaceable),
+        }
+    }
+
+    /// Move the commands of `commands` into `flat`, unnesting batches
+    fn flatten_into(commands: Vec<StoreCommand>, flat: &mut Vec<StoreCommand>) {
+        for command in commands {
+            match command {
+                StoreCommand::Batch(nested) => Self::flatten_into(nested, flat),
+                command => flat.push(command),
+            }
         }
     }
 
@@ -83,6 +265,17 @@ impl StoreCommand {
     }
 }
 
+/// Whom to answer about one command of a [`StoreCommand::Batch`]
+enum BatchReply {
+    Unsigned(Option<oneshot::Sender<Result<Option<StoreCommand>, Error>>>),
+    Signed {
+        event_id: EventId,
+        handler: Option<ResponseHandler>,
+        duplicate: bool,
+    },
+    Deleted(Option<oneshot::Sender<Result<(), Error>>>),
+}
+
 /// Implement conversion from (Event, Scope) tuple to StoreCommand
 impl From<(Event, Scope)> for StoreCommand {
     fn from((event, scope): (Event, Scope)) -> Self {
@@ -97,18 +290,151 @@ impl From<(Box<Event>, Scope)> for StoreCommand {
     }
 }
 
+/// Flush behaviour of the per-connection replaceable events buffer
+///
+/// Replaceable and addressable events generated by the relay are coalesced so
+/// only the latest per (pubkey, kind, scope) is signed and saved. The buffer is
+/// flushed `flush_interval` after the first event buffered since the last flush,
+/// as soon as it holds `max_entries` entries, and before any buffered event has
+/// waited longer than `max_latency`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaceableBufferConfig {
+    /// Time updates are coalesced for, from the first buffered since the last flush
+    pub flush_interval: Duration,
+    /// Number of buffered entries that triggers an immediate flush
+    pub max_entries: usize,
+    /// Longest time an event may stay buffered
+    pub max_latency: Duration,
+}
+
+impl Default for ReplaceableBufferConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: Duration::from_secs(1),
+            max_entries: 1000,
+            max_latency: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ReplaceableBufferConfig {
+    /// Coalesce updates for `flush_interval` after the first one buffered
+    #[must_use]
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Flush as soon as this many entries are buffered
+    #[must_use]
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// Flush before any buffered event has waited longer than `max_latency`
+    #[must_use]
+    pub fn with_max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+}
+
+/// How many events each pagination window of a REQ fetches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WindowStrategy {
+    /// Every window fetches as many events as the filter's limit
+    #[default]
+    Fixed,
+    /// Each window fetches `factor` times more events than the previous one,
+    /// up to `max_window` events
+    ///
+    /// Suits visibility filters hiding most events, which would otherwise
+    /// run out of attempts after scanning a few small windows.
+    Exponential { factor: usize, max_window: usize },
+}
+
+/// Pagination of the stored events served for a REQ
+///
+/// Each filter is served by a [`Paginator`](crate::pagination::Paginator)
+/// querying windows of events up to the oldest one seen so far, until the
+/// filter's limit is reached, the database runs out of events or
+/// `max_attempts` windows were queried. Events hidden by the visibility
+/// filter don't count towards the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaginationConfig {
+    /// Windows queried per filter before giving up
+    pub max_attempts: usize,
+    /// Size of the successive windows
+    pub strategy: WindowStrategy,
+}
+
+impl Default for PaginationConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 50,
+            strategy: WindowStrategy::Fixed,
+        }
+    }
+}
+
+impl PaginationConfig {
+    /// Query at most `max_attempts` windows per filter
+    #[must_use]
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Size windows with `strategy`
+    #[must_use]
+    pub fn with_strategy(mut self, strategy: WindowStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Size of the window following one of `window` events
+    pub(crate) fn next_window(&self, window: usize) -> usize {
+        match self.strategy {
+            WindowStrategy::Fixed => window,
+            WindowStrategy::Exponential { factor, max_window } => window
+                .saturating_mul(factor.max(1))
+                .min(max_window)
+                .max(window),
+        }
+    }
+}
+
+/// When a client's `OK` is sent relative to storing and distributing its event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderingMode {
+    /// `OK` as soon as the event is saved, then distribution; historical
+    /// queries may be served by read replicas that don't have it yet
+    #[default]
+    Relaxed,
+    /// Read-your-writes: `OK` only once the event is committed to the primary
+    /// database and distributed, and historical queries always read the
+    /// primary, so a client that got `OK` sees its event in any later REQ
+    ReadYourWrites,
+}
+
 /// Buffer for replaceable events to ensure only the latest per (pubkey, kind, scope) survives
 struct ReplaceableEventsBuffer {
     buffer: std::collections::HashMap<(PublicKey, Kind, Scope), UnsignedEvent>,
+    config: ReplaceableBufferConfig,
+    /// When the oldest entry still in the buffer was inserted
+    oldest_entry: Option<Instant>,
     sender: flume::Sender<(UnsignedEvent, Scope)>,
     receiver: Option<flume::Receiver<(UnsignedEvent, Scope)>>,
 }
 
 impl ReplaceableEventsBuffer {
-    pub fn new() -> Self {
+    pub fn new(config: ReplaceableBufferConfig) -> Self {
         let (sender, receiver) = flume::bounded(10_000);
         Self {
             buffer: std::collections::HashMap::new(),
+            config,
+            oldest_entry: None,
             sender,
             receiver: Some(receiver),
         }
@@ -129,6 +455,7 @@ impl ReplaceableEventsBuffer {
 
         let key = (event.pubkey, event.kind, scope);
         self.buffer.insert(key, event);
+        self.oldest_entry.get_or_insert_with(Instant::now);
     }
 
     #[allow(dead_code)] // Used in flush method
@@ -136,6 +463,20 @@ impl ReplaceableEventsBuffer {
         self.buffer.is_empty()
     }
 
+    /// Whether the buffer reached `max_entries` and must be flushed right away
+    fn is_full(&self) -> bool {
+        self.buffer.len() >= self.config.max_entries
+    }
+
+    /// When the next flush is due, `None` while the buffer is empty
+    ///
+    /// Both bounds run from the first entry buffered since the last flush, so
+    /// an event arriving after an idle period still gets the full interval.
+    fn flush_deadline(&self) -> Option<Instant> {
+        let oldest_entry = self.oldest_entry?;
+        Some(oldest_entry + std::cmp::min(self.config.flush_interval, self.config.max_latency))
+    }
+
     pub async fn flush(
         &mut self,
         database: &Arc<RelayDatabase>,
@@ -146,6 +487,10 @@ impl ReplaceableEventsBuffer {
         }
 
         debug!("Flushing {} replaceable events", self.buffer.len());
+        if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+            metrics.record_buffer_flush(self.buffer.len());
+        }
+        self.oldest_entry = None;
 
         // Collect all events to sign in a batch
         let events_to_sign: Vec<(UnsignedEvent, Scope)> = self
@@ -195,38 +540,92 @@ impl ReplaceableEventsBuffer {
         }
     }
 
+    /// Flush buffered events from a task until `cancellation_token` fires
+    ///
+    /// With a `supervisor`, the task starts over with an empty buffer when it
+    /// panics; the entries buffered at that time are lost.
     pub fn start_with_sender(
         mut self,
         database: Arc<RelayDatabase>,
         crypto_helper: crate::crypto_helper::CryptoHelper,
         cancellation_token: CancellationToken,
         task_name: String,
+        supervisor: Option<&TaskSupervisor>,
     ) {
         let receiver = self.receiver.take().expect("Receiver already taken");
 
-        tokio::spawn(async move {
-            debug!("{} started", task_name);
+        let Some(supervisor) = supervisor else {
+            tokio::spawn(self.run(
+                receiver,
+                database,
+                crypto_helper,
+                cancellation_token,
+                task_name,
+            ));
+            return;
+        };
 
-            loop {
-                tokio::select! {
-                    _ = cancellation_token.cancelled() => {
-                        debug!("{} cancelled, flushing remaining events", task_name);
-                        self.flush(&database, &crypto_helper).await;
-                        break;
-                    }
+        let config = self.config;
+        let sender = self.sender.clone();
+        supervisor.spawn(
+            None,
+            cancellation_token.clone(),
+            "replaceable_events_buffer",
+            move || {
+                let buffer = Self {
+                    buffer: HashMap::new(),
+                    config,
+                    oldest_entry: None,
+                    sender: sender.clone(),
+                    receiver: None,
+                };
+                buffer.run(
+                    receiver.clone(),
+                    database.clone(),
+                    crypto_helper.clone(),
+                    cancellation_token.clone(),
+                    task_name.clone(),
+                )
+            },
+        );
+    }
+
+    async fn run(
+        mut self,
+        receiver: flume::Receiver<(UnsignedEvent, Scope)>,
+        database: Arc<RelayDatabase>,
+        crypto_helper: crate::crypto_helper::CryptoHelper,
+        cancellation_token: CancellationToken,
+        task_name: String,
+    ) {
+        debug!("{} started", task_name);
+
+        loop {
+            let deadline = self.flush_deadline();
+
+            tokio::select! {
+                _ = cancellation_token.cancelled() => {
+                    debug!("{} cancelled, flushing remaining events", task_name);
+                    self.flush(&database, &crypto_helper).await;
+                    break;
+                }
 
-                    event_result = receiver.recv_async() => {
-                        if let Ok((event, scope)) = event_result {
-                            self.insert(event, scope);
+                event_result = receiver.recv_async() => {
+                    if let Ok((event, scope)) = event_result {
+                        self.insert(event, scope);
+                        if self.is_full() {
+                            debug!("{} reached {} entries", task_name, self.config.max_entries);
+                            self.flush(&database, &crypto_helper).await;
                         }
                     }
+                }
 
-                    _ = tokio::time::sleep(Duration::from_secs(1)) => {
-                        self.flush(&database, &crypto_helper).await;
-                    }
+                // Only armed while something is buffered
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.flush(&database, &crypto_helper).await;
                 }
             }
-        });
+        }
     }
 }
 
@@ -234,6 +633,16 @@ impl ReplaceableEventsBuffer {
 #[derive(Clone)]
 pub struct SubscriptionCoordinator {
     database: Arc<RelayDatabase>,
+    /// Optional replicas used for historical queries instead of the primary
+    read_replicas: Option<ReadReplicas>,
+    /// Stages every signed event passes before it is saved
+    ingest_pipeline: IngestPipeline,
+    /// Where REQs with a slow historical query are recorded
+    slow_query_log: Option<SlowQueryLog>,
+    /// Reloadable limits, overriding `max_limit` when set
+    runtime_config: Option<ReloadableConfig>,
+    /// NIP-42 authenticated pubkey, updated when the client authenticates
+    auth_pubkey: Arc<parking_lot::RwLock<Option<PublicKey>>>,
     crypto_helper: crate::crypto_helper::CryptoHelper,
     registry: Arc<SubscriptionRegistry>,
     connection_id: String,
@@ -241,6 +650,20 @@ pub struct SubscriptionCoordinator {
     replaceable_event_queue: flume::Sender<(UnsignedEvent, Scope)>,
     metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>,
     max_limit: usize,
+    pagination: PaginationConfig,
+    ordering: OrderingMode,
+    /// Hooks whose follow-up commands run after each stored event
+    post_save_hooks: Option<PostSaveHooks>,
+    /// Background handlers of the stored events of some kinds
+    kind_router: Option<KindRouter>,
+    /// How the connection's events reach the relay, as the ingest pipeline sees it
+    ingest_path: IngestPath,
+    /// Resume cursors handed to authenticated clients after EOSE
+    resume_cursors: Option<ResumeCursors>,
+    /// Resume token issued for each of this connection's subscriptions
+    resume_tokens: Arc<parking_lot::Mutex<HashMap<SubscriptionId, String>>>,
+    /// Parent of the REQ, save and distribution spans, disabled when not sampled
+    span: Span,
     _connection_handle: Arc<crate::subscription_registry::ConnectionHandle>,
 }
 
@@ -248,16 +671,275 @@ impl std::fmt::Debug for SubscriptionCoordinator {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SubscriptionCoordinator")
             .field("database", &self.database)
+            .field(
+                "read_replicas",
+                &self.read_replicas.as_ref().map(|r| r.len()).unwrap_or(0),
+            )
             .field("connection_id", &self.connection_id)
             .field("has_registry", &true)
             .field("metrics_handler", &self.metrics_handler.is_some())
             .field("max_limit", &self.max_limit)
+            .field("pagination", &self.pagination)
+            .field("ordering", &self.ordering)
+            .field("post_save_hooks", &self.post_save_hooks.is_some())
+            .field("kind_router", &self.kind_router.is_some())
+            .field("resume_cursors", &self.resume_cursors.is_some())
             .finish()
     }
 }
 
+/// Collects the settings of a [`SubscriptionCoordinator`]
+///
+/// Created with [`SubscriptionCoordinator::builder`]; everything but the
+/// database, registry and connection has a default, so callers only name what
+/// they change.
+#[derive(Debug)]
+pub struct SubscriptionCoordinatorBuilder {
+    database: Arc<RelayDatabase>,
+    crypto_helper: crate::crypto_helper::CryptoHelper,
+    registry: Arc<SubscriptionRegistry>,
+    connection_id: String,
+    outgoing_sender: MessageSender<RelayMessage<'static>>,
+    auth_pubkey: Option<PublicKey>,
+    scope: Arc<Scope>,
+    cancellation_token: CancellationToken,
+    metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>,
+    max_limit: usize,
+    pagination: PaginationConfig,
+    ordering: OrderingMode,
+    post_save_hooks: Option<PostSaveHooks>,
+    kind_router: Option<KindRouter>,
+    ingest_path: IngestPath,
+    resume_cursors: Option<ResumeCursors>,
+    replaceable_buffer: ReplaceableBufferConfig,
+    read_replicas: Option<ReadReplicas>,
+    ingest_pipeline: Option<IngestPipeline>,
+    event_policies: Option<EventPolicyChain>,
+    slow_query_log: Option<SlowQueryLog>,
+    runtime_config: Option<ReloadableConfig>,
+    trace_sample_rate: f64,
+    remote_address: Option<String>,
+    supervisor: Option<TaskSupervisor>,
+}
+
+impl SubscriptionCoordinatorBuilder {
+    /// Connection authenticated as `auth_pubkey` from the start
+    #[must_use]
+    pub fn with_auth_pubkey(mut self, auth_pubkey: Option<PublicKey>) -> Self {
+        self.auth_pubkey = auth_pubkey;
+        self
+    }
+
+    /// Serve the connection from `scope` instead of the default scope
+    #[must_use]
+    pub fn with_scope(mut self, scope: Arc<Scope>) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Stop the connection's background tasks when `cancellation_token` is cancelled
+    #[must_use]
+    pub fn with_cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = cancellation_token;
+        self
+    }
+
+    /// Report subscription metrics to `metrics_handler`
+    #[must_use]
+    pub fn with_metrics_handler(
+        mut self,
+        metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>,
+    ) -> Self {
+        self.metrics_handler = metrics_handler;
+        self
+    }
+
+    /// Cap filter limits at `max_limit`, 1000 by default
+    #[must_use]
+    pub fn with_max_limit(mut self, max_limit: usize) -> Self {
+        self.max_limit = max_limit;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_pagination`]
+    #[must_use]
+    pub fn with_pagination(mut self, pagination: PaginationConfig) -> Self {
+        self.pagination = pagination;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_ordering`]
+    #[must_use]
+    pub fn with_ordering(mut self, ordering: OrderingMode) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_post_save_hooks`]
+    #[must_use]
+    pub fn with_post_save_hooks(mut self, post_save_hooks: Option<PostSaveHooks>) -> Self {
+        self.post_save_hooks = post_save_hooks;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_kind_router`]
+    #[must_use]
+    pub fn with_kind_router(mut self, kind_router: Option<KindRouter>) -> Self {
+        self.kind_router = kind_router;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_ingest_path`]
+    #[must_use]
+    pub fn with_ingest_path(mut self, ingest_path: IngestPath) -> Self {
+        self.ingest_path = ingest_path;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_resume_cursors`]
+    #[must_use]
+    pub fn with_resume_cursors(mut self, resume_cursors: Option<ResumeCursors>) -> Self {
+        self.resume_cursors = resume_cursors;
+        self
+    }
+
+    /// Flush behaviour of the replaceable events buffer
+    #[must_use]
+    pub fn with_replaceable_buffer(mut self, replaceable_buffer: ReplaceableBufferConfig) -> Self {
+        self.replaceable_buffer = replaceable_buffer;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_read_replicas`]
+    #[must_use]
+    pub fn with_read_replicas(mut self, read_replicas: Option<ReadReplicas>) -> Self {
+        self.read_replicas = read_replicas;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_ingest_pipeline`]
+    #[must_use]
+    pub fn with_ingest_pipeline(mut self, ingest_pipeline: Option<IngestPipeline>) -> Self {
+        self.ingest_pipeline = ingest_pipeline;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_event_policies`]
+    #[must_use]
+    pub fn with_event_policies(mut self, event_policies: Option<EventPolicyChain>) -> Self {
+        self.event_policies = event_policies;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_slow_query_log`]
+    #[must_use]
+    pub fn with_slow_query_log(mut self, slow_query_log: Option<SlowQueryLog>) -> Self {
+        self.slow_query_log = slow_query_log;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_runtime_config`]
+    #[must_use]
+    pub fn with_runtime_config(mut self, runtime_config: Option<ReloadableConfig>) -> Self {
+        self.runtime_config = runtime_config;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_trace_sample_rate`]
+    #[must_use]
+    pub fn with_trace_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.trace_sample_rate = sample_rate;
+        self
+    }
+
+    /// See [`SubscriptionCoordinator::with_remote_address`]
+    #[must_use]
+    pub fn with_remote_address(mut self, remote_address: Option<String>) -> Self {
+        self.remote_address = remote_address;
+        self
+    }
+
+    /// Restart the connection's replaceable events buffer with `supervisor` when it panics
+    #[must_use]
+    pub fn with_supervisor(mut self, supervisor: Option<TaskSupervisor>) -> Self {
+        self.supervisor = supervisor;
+        self
+    }
+
+    /// Register the connection and start its coordinator
+    pub fn build(self) -> SubscriptionCoordinator {
+        SubscriptionCoordinator::create(
+            self.database,
+            self.crypto_helper,
+            self.registry,
+            self.connection_id,
+            self.outgoing_sender,
+            self.auth_pubkey,
+            self.scope,
+            self.cancellation_token,
+            self.metrics_handler,
+            self.max_limit,
+            self.replaceable_buffer,
+            self.supervisor.as_ref(),
+        )
+        .with_pagination(self.pagination)
+        .with_ordering(self.ordering)
+        .with_post_save_hooks(self.post_save_hooks)
+        .with_kind_router(self.kind_router)
+        .with_ingest_path(self.ingest_path)
+        .with_resume_cursors(self.resume_cursors)
+        .with_read_replicas(self.read_replicas)
+        .with_ingest_pipeline(self.ingest_pipeline)
+        .with_event_policies(self.event_policies)
+        .with_trace_sample_rate(self.trace_sample_rate)
+        .with_remote_address(self.remote_address)
+        .with_slow_query_log(self.slow_query_log)
+        .with_runtime_config(self.runtime_config)
+    }
+}
+
 impl SubscriptionCoordinator {
+    /// Start building a coordinator for `connection_id`
+    pub fn builder(
+        database: Arc<RelayDatabase>,
+        crypto_helper: crate::crypto_helper::CryptoHelper,
+        registry: Arc<SubscriptionRegistry>,
+        connection_id: String,
+        outgoing_sender: MessageSender<RelayMessage<'static>>,
+    ) -> SubscriptionCoordinatorBuilder {
+        SubscriptionCoordinatorBuilder {
+            database,
+            crypto_helper,
+            registry,
+            connection_id,
+            outgoing_sender,
+            auth_pubkey: None,
+            scope: Arc::new(Scope::Default),
+            cancellation_token: CancellationToken::new(),
+            metrics_handler: None,
+            max_limit: 1000,
+            pagination: PaginationConfig::default(),
+            ordering: OrderingMode::default(),
+            post_save_hooks: None,
+            kind_router: None,
+            ingest_path: IngestPath::WebSocket,
+            resume_cursors: None,
+            replaceable_buffer: ReplaceableBufferConfig::default(),
+            read_replicas: None,
+            ingest_pipeline: None,
+            event_policies: None,
+            slow_query_log: None,
+            runtime_config: None,
+            trace_sample_rate: 1.0,
+            remote_address: None,
+            supervisor: None,
+        }
+    }
+
     /// Create a new subscription coordinator
+    ///
+    /// [`Self::builder`] is easier to read when only some settings differ
+    /// from their defaults.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         database: Arc<RelayDatabase>,
@@ -270,7 +952,51 @@ impl SubscriptionCoordinator {
         cancellation_token: CancellationToken,
         metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>,
         max_limit: usize,
+        replaceable_buffer: ReplaceableBufferConfig,
+    ) -> Self {
+        Self::create(
+            database,
+            crypto_helper,
+            registry,
+            connection_id,
+            outgoing_sender,
+            auth_pubkey,
+            subdomain,
+            cancellation_token,
+            metrics_handler,
+            max_limit,
+            replaceable_buffer,
+            None,
+        )
+    }
+
+    /// [`Self::new`], with the replaceable events buffer run by `supervisor`
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        database: Arc<RelayDatabase>,
+        crypto_helper: crate::crypto_helper::CryptoHelper,
+        registry: Arc<SubscriptionRegistry>,
+        connection_id: String,
+        outgoing_sender: MessageSender<RelayMessage<'static>>,
+        auth_pubkey: Option<PublicKey>,
+        subdomain: Arc<Scope>,
+        cancellation_token: CancellationToken,
+        metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>,
+        max_limit: usize,
+        replaceable_buffer: ReplaceableBufferConfig,
+        supervisor: Option<&TaskSupervisor>,
     ) -> Self {
+        let span = tracing::info_span!(
+            parent: None,
+            "connection",
+            connection_id = %connection_id,
+            subdomain = ?subdomain,
+            auth_pubkey = tracing::field::Empty,
+        );
+        if let Some(pubkey) = &auth_pubkey {
+            span.record("auth_pubkey", tracing::field::display(pubkey));
+        }
+
         // Register this connection with the registry
         let connection_handle = registry.register_connection(
             connection_id.clone(),
@@ -278,9 +1004,10 @@ impl SubscriptionCoordinator {
             auth_pubkey,
             subdomain,
         );
+        registry.set_connection_token(&connection_id, cancellation_token.clone());
 
         // Create and start the replaceable events buffer
-        let buffer = ReplaceableEventsBuffer::new();
+        let buffer = ReplaceableEventsBuffer::new(replaceable_buffer);
         let replaceable_event_queue = buffer.get_sender();
 
         buffer.start_with_sender(
@@ -288,10 +1015,16 @@ impl SubscriptionCoordinator {
             crypto_helper.clone(),
             cancellation_token,
             format!("replaceable_events_buffer_{connection_id}"),
+            supervisor,
         );
 
         Self {
             database,
+            read_replicas: None,
+            ingest_pipeline: IngestPipeline::new(),
+            slow_query_log: None,
+            runtime_config: None,
+            auth_pubkey: Arc::new(parking_lot::RwLock::new(auth_pubkey)),
             crypto_helper,
             registry,
             connection_id,
@@ -299,378 +1032,1499 @@ impl SubscriptionCoordinator {
             replaceable_event_queue,
             metrics_handler,
             max_limit,
+            pagination: PaginationConfig::default(),
+            ordering: OrderingMode::default(),
+            post_save_hooks: None,
+            kind_router: None,
+            ingest_path: IngestPath::WebSocket,
+            resume_cursors: None,
+            resume_tokens: Arc::new(parking_lot::Mutex::new(HashMap::new())),
+            span,
             _connection_handle: Arc::new(connection_handle),
         }
     }
 
-    /// Add a subscription
-    pub fn add_subscription(
-        &self,
-        subscription_id: SubscriptionId,
-        filters: Vec<Filter>,
-    ) -> Result<(), Error> {
-        self.registry
-            .add_subscription(&self.connection_id, subscription_id, filters)
+    /// Paginate historical queries according to `pagination`
+    #[must_use]
+    pub fn with_pagination(mut self, pagination: PaginationConfig) -> Self {
+        self.pagination = pagination;
+        self
     }
 
-    /// Remove a subscription
-    pub fn remove_subscription(&self, subscription_id: SubscriptionId) -> Result<(), Error> {
-        // Just call directly now since it's not async
-        if let Err(e) = self
-            .registry
-            .remove_subscription(&self.connection_id, &subscription_id)
-        {
-            warn!("Failed to remove subscription: {:?}", e);
-        }
+    /// Order `OK` responses, storage and distribution according to `ordering`
+    #[must_use]
+    pub fn with_ordering(mut self, ordering: OrderingMode) -> Self {
+        self.ordering = ordering;
+        self
+    }
 
-        Ok(())
+    /// Process the follow-up commands `post_save_hooks` return for each stored event
+    ///
+    /// See the [`post_save`](crate::post_save) module.
+    #[must_use]
+    pub fn with_post_save_hooks(mut self, post_save_hooks: Option<PostSaveHooks>) -> Self {
+        self.post_save_hooks = post_save_hooks.filter(|hooks| !hooks.is_empty());
+        self
     }
 
-    /// Save and broadcast a store command
-    pub async fn save_and_broadcast(&self, command: StoreCommand) -> Result<(), Error> {
-        match command {
-            StoreCommand::SaveUnsignedEvent(event, scope, response_handler) => {
-                // For replaceable events, queue them for buffering
-                if event.kind.is_replaceable() || event.kind.is_addressable() {
-                    self.replaceable_event_queue
-                        .send_async((event, scope))
-                        .await
-                        .map_err(|e| {
-                            Error::internal(format!("Failed to queue replaceable event: {e}"))
-                        })?;
+    /// Hand each stored event to the background routes of `kind_router`
+    #[must_use]
+    pub fn with_kind_router(mut self, kind_router: Option<KindRouter>) -> Self {
+        self.kind_router = kind_router.filter(|router| !router.is_empty());
+        self
+    }
 
-                    if let Some(response_handler) = response_handler {
-                        let _ = response_handler.send(Ok(None));
-                    }
+    /// Tell the ingest pipeline the connection's events arrive over `ingest_path`
+    ///
+    /// WebSocket by default; the HTTP event API ingests through a coordinator
+    /// of its own.
+    #[must_use]
+    pub fn with_ingest_path(mut self, ingest_path: IngestPath) -> Self {
+        self.ingest_path = ingest_path;
+        self
+    }
 
-                    return Ok(());
-                }
+    /// Hand authenticated clients a resume token after each EOSE
+    ///
+    /// See the [`resume`](crate::resume) module.
+    #[must_use]
+    pub fn with_resume_cursors(mut self, resume_cursors: Option<ResumeCursors>) -> Self {
+        self.resume_cursors = resume_cursors;
+        self
+    }
 
-                let (tx, rx) = tokio::sync::oneshot::channel();
-                // Send to crypto helper for batched signing
-                // The crypto helper will sign and send the response through the oneshot
-                self.crypto_helper
-                    .sign_store_command(StoreCommand::SaveUnsignedEvent(event, scope, Some(tx)))
-                    .await
-                    .map_err(|e| Error::internal(format!("Failed to sign event: {e}")))?;
-
-                // Wait for the signed result
-                match rx.await {
-                    Ok(Ok(Some(signed_command))) => {
-                        // Extract the signed event and save it directly
-                        if let StoreCommand::SaveSignedEvent(event, scope, _) = signed_command {
-                            self.database
-                                .save_event(&event, &scope)
-                                .await
-                                .map_err(|e| {
-                                    Error::internal(format!("Failed to save event: {e}"))
-                                })?;
-                        }
-                    }
-                    Ok(Ok(None)) => {
-                        return Err(Error::internal("Event signed but not returned"));
-                    }
-                    Ok(Err(e)) => {
-                        return Err(Error::internal(format!("Failed to sign event: {e}")));
-                    }
-                    Err(_) => {
-                        return Err(Error::internal(
-                            "Signing processor dropped response channel",
-                        ));
-                    }
-                }
+    /// Route historical queries to the given read replicas
+    ///
+    /// Writes keep going to the primary database.
+    #[must_use]
+    pub fn with_read_replicas(mut self, read_replicas: Option<ReadReplicas>) -> Self {
+        self.read_replicas = read_replicas;
+        self
+    }
 
-                if let Some(response_handler) = response_handler {
-                    let _ = response_handler.send(Ok(None));
-                }
+    /// Run signed events through `ingest_pipeline` before saving them
+    ///
+    /// Without a pipeline events are only deduplicated, plus the policies set
+    /// with [`Self::with_event_policies`].
+    #[must_use]
+    pub fn with_ingest_pipeline(mut self, ingest_pipeline: Option<IngestPipeline>) -> Self {
+        if let Some(ingest_pipeline) = ingest_pipeline {
+            self.ingest_pipeline = ingest_pipeline;
+        }
+        self
+    }
 
-                Ok(())
-            }
-            StoreCommand::SaveSignedEvent(event, scope, response_handler) => {
-                // Save the event directly to the database
-                let save_result = self
-                    .database
-                    .save_event(&event, &scope)
-                    .await
-                    .map_err(|e| Error::internal(e.to_string()));
-
-                // Send OK response if we have a MessageSender handler
-                if let Some(ResponseHandler::MessageSender(mut sender)) = response_handler {
-                    let ok = save_result.is_ok();
-                    let msg = if ok {
-                        RelayMessage::ok(event.id, true, "")
-                    } else {
-                        RelayMessage::ok(
-                            event.id,
-                            false,
-                            save_result.as_ref().unwrap_err().to_string(),
-                        )
-                    };
-                    sender.send_bypass(msg);
-                } else if let Some(ResponseHandler::Oneshot(tx)) = response_handler {
-                    let _ = tx.send(
-                        save_result
-                            .as_ref()
-                            .map(|_| ())
-                            .map_err(|_| Error::internal("Failed to save event")),
-                    );
-                }
+    /// Check signed events against `event_policies` before saving them
+    ///
+    /// Sets the policy stage of the ingest pipeline.
+    #[must_use]
+    pub fn with_event_policies(mut self, event_policies: Option<EventPolicyChain>) -> Self {
+        if let Some(event_policies) = event_policies {
+            self.ingest_pipeline = self.ingest_pipeline.with_policies(event_policies);
+        }
+        self
+    }
 
-                // If the save was successful, distribute the event to subscribers
-                if save_result.is_ok() {
-                    self.registry
-                        .distribute_event(Arc::new(*event), &scope)
-                        .await;
-                }
+    /// Record REQs whose historical query was slow in `slow_query_log`
+    #[must_use]
+    pub fn with_slow_query_log(mut self, slow_query_log: Option<SlowQueryLog>) -> Self {
+        self.slow_query_log = slow_query_log;
+        self
+    }
 
-                save_result
-            }
-            StoreCommand::DeleteEvents(filter, scope, response_handler) => {
-                // Delete events directly from the database
-                let delete_result = self
-                    .database
-                    .delete(filter, &scope)
-                    .await
-                    .map_err(|e| Error::internal(e.to_string()));
+    /// Read the filter limit cap from `runtime_config` instead of the fixed `max_limit`
+    #[must_use]
+    pub fn with_runtime_config(mut self, runtime_config: Option<ReloadableConfig>) -> Self {
+        self.runtime_config = runtime_config;
+        self
+    }
 
-                // Send response if we have a handler
-                if let Some(handler) = response_handler {
-                    let _ = handler.send(
-                        delete_result
-                            .as_ref()
-                            .map(|_| ())
-                            .map_err(|_| Error::internal("Failed to delete events")),
-                    );
-                }
+    /// Current cap on filter limits
+    fn max_limit(&self) -> usize {
+        match &self.runtime_config {
+            Some(runtime_config) => runtime_config.load().max_limit,
+            None => self.max_limit,
+        }
+    }
 
-                delete_result
-            }
+    /// Trace this connection with probability `sample_rate`, between 0.0 and 1.0
+    ///
+    /// Unsampled connections get no span for their REQs, saves and
+    /// distributions, which keeps tracing cheap on busy relays.
+    #[must_use]
+    pub fn with_trace_sample_rate(mut self, sample_rate: f64) -> Self {
+        if sample_rate < 1.0 && rand::random::<f64>() >= sample_rate {
+            self.span = Span::none();
         }
+        self
     }
 
-    /// Handle a REQ message from a client
-    pub async fn handle_req(
+    /// Span of this connection, disabled if the connection is not sampled
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Record the pubkey the client authenticated as
+    pub fn set_auth_pubkey(&self, pubkey: PublicKey) {
+        *self.auth_pubkey.write() = Some(pubkey);
+        self.registry.set_auth_pubkey(&self.connection_id, pubkey);
+        self.span
+            .record("auth_pubkey", tracing::field::display(pubkey));
+    }
+
+    /// Database used for historical queries
+    fn read_database(&self) -> &Arc<RelayDatabase> {
+        match &self.read_replicas {
+            Some(replicas) if self.ordering == OrderingMode::Relaxed => replicas.pick(),
+            _ => &self.database,
+        }
+    }
+
+    /// Current scope of this connection as seen by the registry
+    ///
+    /// This differs from the scope the connection was created with after an
+    /// admin migrated it with [`SubscriptionRegistry::migrate_scope`]. Returns
+    /// `None` if the connection was detached from the registry.
+    pub fn current_scope(&self) -> Option<Arc<Scope>> {
+        self.registry
+            .get_connection_info(&self.connection_id)
+            .map(|(_, scope)| scope)
+    }
+
+    /// Record client activity, postponing the connection's idle timeout
+    pub fn touch(&self) {
+        self.registry.touch(&self.connection_id);
+    }
+
+    /// Record the address the client connected from in the connection stats
+    #[must_use]
+    pub fn with_remote_address(self, remote_address: Option<String>) -> Self {
+        if let Some(remote_address) = remote_address {
+            self.registry
+                .set_remote_address(&self.connection_id, remote_address);
+        }
+        self
+    }
+
+    /// Metadata of this connection for the ingest pipeline
+    fn connection_metadata(&self) -> ConnectionMetadata {
+        ConnectionMetadata {
+            path: self.ingest_path,
+            connection_id: Some(self.connection_id.clone()),
+            auth_pubkey: *self.auth_pubkey.read(),
+            remote_address: self
+                .registry
+                .connection_stats(&self.connection_id)
+                .and_then(|stats| stats.remote_address),
+        }
+    }
+
+    /// Count a client event as stored or refused in the connection stats
+    pub fn record_event_result(&self, accepted: bool) {
+        self.registry
+            .record_event_result(&self.connection_id, accepted);
+    }
+
+    /// Send `message` past the outbound middlewares, counting it in the connection stats
+    fn send_direct(
+        &self,
+        sender: &mut MessageSender<RelayMessage<'static>>,
+        message: RelayMessage<'static>,
+    ) {
+        self.registry.record_outbound(
+            &self.connection_id,
+            1,
+            crate::broadcast::relay_message_len(&message),
+        );
+        sender.send_bypass(message);
+    }
+
+    /// Add a subscription
+    pub fn add_subscription(
         &self,
         subscription_id: SubscriptionId,
         filters: Vec<Filter>,
-        authed_pubkey: Option<PublicKey>,
-        subdomain: &Scope,
-        filter_fn: impl Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync + Clone + 'static,
     ) -> Result<(), Error> {
-        // Process historical events first
-        self.process_historical_events(
-            subscription_id.clone(),
-            &filters,
-            authed_pubkey,
-            subdomain,
-            self.outgoing_sender.clone(),
-            filter_fn,
-        )
-        .await?;
+        self.registry
+            .add_subscription(&self.connection_id, subscription_id, filters)
+    }
 
-        // Add the subscription for future events
-        self.add_subscription(subscription_id, filters)?;
+    /// Remove a subscription
+    pub fn remove_subscription(&self, subscription_id: SubscriptionId) -> Result<(), Error> {
+        let token = self.resume_tokens.lock().remove(&subscription_id);
+        if let Some(token) = token {
+            self.advance_resume_cursor(&token);
+        }
+
+        // Just call directly now since it's not async
+        if let Err(e) = self
+            .registry
+            .remove_subscription(&self.connection_id, &subscription_id)
+        {
+            warn!("Failed to remove subscription: {:?}", e);
+        }
 
         Ok(())
     }
 
-    async fn process_historical_events(
+    /// Terminate a subscription server-side and tell the client why
+    ///
+    /// Removes the subscription from the registry, so no further live events
+    /// are sent for it, and emits `CLOSED` with the machine-readable `reason`.
+    /// Also used to reject a REQ before it is registered.
+    pub fn close_subscription(
         &self,
         subscription_id: SubscriptionId,
-        filters: &[Filter],
-        authed_pubkey: Option<PublicKey>,
-        subdomain: &Scope,
-        mut sender: MessageSender<RelayMessage<'static>>,
-        filter_fn: impl Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync + Clone + 'static,
+        reason: ClosedReason,
     ) -> Result<(), Error> {
-        // Cap filter limits based on configured max_limit
-        let smallest_limit = filters
-            .iter()
-            .filter_map(|f| f.limit)
-            .min()
-            .unwrap_or(self.max_limit)
-            .min(self.max_limit);
-
-        let filters: Vec<Filter> = filters
-            .iter()
-            .map(|filter| filter.clone().limit(smallest_limit))
-            .collect();
+        self.remove_subscription(subscription_id.clone())?;
 
-        let mut sent_events = HashSet::new();
-        let mut total_sent = 0;
-        let max_limit = filters.iter().filter_map(|f| f.limit).max().unwrap_or(0);
+        debug!(
+            "Closing subscription {} on connection {}: {}",
+            subscription_id, self.connection_id, reason
+        );
 
-        // Process each filter separately
-        for (filter_idx, filter) in filters.iter().enumerate() {
-            // All filters have been adjusted to have a limit by this point
-            let requested_limit = filter
-                .limit
-                .expect("Filter should have limit after adjustment");
+        let mut sender = self.outgoing_sender.clone();
+        sender
+            .send(RelayMessage::closed(subscription_id, reason.to_string()))
+            .map_err(|e| Error::channel_closed(format!("Failed to send CLOSED: {e:?}")))
+    }
 
-            let mut window_filter = filter.clone();
-            let mut filter_sent = 0;
-            let mut last_timestamp = None;
-            let mut attempts = 0;
-            const MAX_ATTEMPTS: usize = 50;
+    /// Save and broadcast a store command
+    pub async fn save_and_broadcast(&self, command: StoreCommand) -> Result<(), Error> {
+        self.save_and_broadcast_timed(command, None).await
+    }
 
-            loop {
-                attempts += 1;
-                debug!(
-                    "Pagination attempt {} for filter {} of subscription {}",
-                    attempts, filter_idx, subscription_id
-                );
+    /// Save and broadcast a store command, marking the persisted and distributed
+    /// stages of a signed event on `timeline`
+    pub async fn save_and_broadcast_timed(
+        &self,
+        command: StoreCommand,
+        timeline: Option<&mut EventTimeline>,
+    ) -> Result<(), Error> {
+        self.ingest(command, timeline).await.map(|_| ())
+    }
 
-                let events = self
-                    .database
-                    .query(vec![window_filter.clone()], subdomain)
-                    .await
-                    .map_err(|e| Error::notice(format!("Failed to fetch events: {e:?}")))?;
+    /// Save and broadcast a store command, telling whether a signed event was
+    /// stored or already known
+    ///
+    /// Signed events go through the [`IngestPipeline`] before they are
+    /// persisted and distributed; other commands report
+    /// [`IngestOutcome::Stored`] once done.
+    pub async fn ingest(
+        &self,
+        command: StoreCommand,
+        timeline: Option<&mut EventTimeline>,
+    ) -> Result<IngestOutcome, Error> {
+        let span = if self.span.is_disabled() {
+            Span::none()
+        } else {
+            tracing::debug_span!(
+                parent: &self.span,
+                "save_and_broadcast",
+                scope = ?command.subdomain_scope(),
+                replaceable = command.is_replaceable(),
+                result = tracing::field::Empty,
+            )
+        };
 
-                if events.is_empty() {
-                    debug!("No more events found for filter {}", filter_idx);
-                    break;
-                }
+        let result = self
+            .process_store_command(command, timeline, 0)
+            .instrument(span.clone())
+            .await;
+        span.record("result", if result.is_ok() { "ok" } else { "error" });
+        result
+    }
 
-                let mut filter_events = Vec::new();
-                for event in events {
-                    // Skip if we've already sent this event
-                    if sent_events.contains(&event.id) {
-                        continue;
-                    }
+    /// Process `command`, the follow-up of `depth` post-save hooks
+    async fn process_store_command(
+        &self,
+        command: StoreCommand,
+        timeline: Option<&mut EventTimeline>,
+        depth: usize,
+    ) -> Result<IngestOutcome, Error> {
+        match command {
+            StoreCommand::SaveUnsignedEvent(event, scope, response_handler) => {
+                // For replaceable events, queue them for buffering
+                if event.kind.is_replaceable() || event.kind.is_addressable() {
+                    self.replaceable_event_queue
+                        .send_async((event, scope))
+                        .await
+                        .map_err(|e| {
+                            Error::channel_closed(format!("Failed to queue replaceable event: {e}"))
+                        })?;
 
-                    // Track oldest timestamp seen for pagination
-                    let event_created_at = event.created_at;
-                    if last_timestamp.is_none() || Some(event_created_at) < last_timestamp {
-                        last_timestamp = Some(event_created_at);
+                    if let Some(response_handler) = response_handler {
+                        let _ = response_handler.send(Ok(None));
                     }
 
-                    if filter_fn(&event, subdomain, authed_pubkey.as_ref()) {
-                        filter_events.push(event);
-                    }
+                    return Ok(IngestOutcome::Stored);
                 }
 
-                // Send events in correct order
-                // Database always returns events in descending order (newest first)
-                // For all query types, maintain descending order
-                filter_events.sort_by(|a, b| b.created_at.cmp(&a.created_at));
-
-                for event in filter_events {
-                    if filter_sent >= requested_limit {
-                        break;
-                    }
-
-                    sent_events.insert(event.id);
-                    let msg = RelayMessage::Event {
-                        subscription_id: Cow::Owned(subscription_id.clone()),
-                        event: Cow::Owned(event.clone()),
-                    };
+                let event = self.sign_event(event, scope.clone()).await?;
+                self.database.save_event(&event, &scope).await?;
 
-                    sender.send_bypass(msg);
-                    filter_sent += 1;
-                    total_sent += 1;
+                if let Some(response_handler) = response_handler {
+                    let _ = response_handler.send(Ok(None));
                 }
+                self.run_post_save_hooks(&event, &scope, depth).await;
+
+                Ok(IngestOutcome::Stored)
+            }
+            StoreCommand::SaveSignedEvent(event, scope, response_handler) => {
+                self.ingest_signed(*event, scope, response_handler, timeline, depth)
+                    .await
+            }
+            StoreCommand::DeleteEvents(filter, scope, response_handler) => {
+                // Delete events directly from the database
+                let delete_result = self.database.delete(filter, &scope).await;
 
-                if filter_sent >= requested_limit {
-                    debug!(
-                        "Reached requested limit {} for filter {}",
-                        requested_limit, filter_idx
+                // Send response if we have a handler
+                if let Some(handler) = response_handler {
+                    let _ = handler.send(
+                        delete_result
+                            .as_ref()
+                            .map(|_| ())
+                            .map_err(|_| Error::database("Failed to delete events")),
                     );
-                    break;
                 }
 
-                // Prepare next window by paging backward
-                if let Some(ts) = last_timestamp {
-                    window_filter.until = Some(ts - 1);
-                } else {
-                    debug!("No valid timestamp found for next window");
-                    break;
+                delete_result.map(|()| IngestOutcome::Stored)
+            }
+            StoreCommand::Batch(commands) => self.ingest_batch(commands, depth).await,
+        }
+    }
+
+    /// Sign `event` with the relay keys through the crypto helper
+    async fn sign_event(&self, event: UnsignedEvent, scope: Scope) -> Result<Event, Error> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        // Send to crypto helper for batched signing
+        // The crypto helper will sign and send the response through the oneshot
+        self.crypto_helper
+            .sign_store_command(StoreCommand::SaveUnsignedEvent(event, scope, Some(tx)))
+            .await
+            .map_err(|e| Error::internal(format!("Failed to sign event: {e}")))?;
+
+        // Wait for the signed result
+        match rx.await {
+            Ok(Ok(Some(StoreCommand::SaveSignedEvent(event, _, _)))) => Ok(*event),
+            Ok(Ok(_)) => Err(Error::internal("Event signed but not returned")),
+            Ok(Err(e)) => Err(Error::internal(format!("Failed to sign event: {e}"))),
+            Err(_) => Err(Error::channel_closed(
+                "Signing processor dropped response channel",
+            )),
+        }
+    }
+
+    /// Apply the commands of a batch together, then distribute the saved events
+    async fn ingest_batch(
+        &self,
+        commands: Vec<StoreCommand>,
+        depth: usize,
+    ) -> Result<IngestOutcome, Error> {
+        let mut flat = Vec::with_capacity(commands.len());
+        StoreCommand::flatten_into(commands, &mut flat);
+        let Some(scope) = flat
+            .first()
+            .map(|command| command.subdomain_scope().clone())
+        else {
+            return Ok(IngestOutcome::Stored);
+        };
+
+        let source = self.connection_metadata();
+        let mut failure = flat
+            .iter()
+            .any(|command| *command.subdomain_scope() != scope)
+            .then(|| Error::invalid("batch commands must share one scope"));
+        let mut writes = Vec::with_capacity(flat.len());
+        let mut replies = Vec::with_capacity(flat.len());
+        for command in flat {
+            match command {
+                StoreCommand::SaveUnsignedEvent(event, _, handler) => {
+                    replies.push(BatchReply::Unsigned(handler));
+                    if failure.is_none() {
+                        match self.sign_event(event, scope.clone()).await {
+                            Ok(event) => writes.push(BatchWrite::Save(event)),
+                            Err(e) => failure = Some(e),
+                        }
+                    }
+                }
+                StoreCommand::SaveSignedEvent(event, _, mut handler) => {
+                    let event_id = event.id;
+                    let mut duplicate = false;
+                    if failure.is_none() {
+                        match self
+                            .ingest_pipeline
+                            .admit(&event, &scope, &source, &self.database)
+                            .await
+                        {
+                            Admission::Accept => writes.push(BatchWrite::Save(*event)),
+                            Admission::Duplicate => duplicate = true,
+                            // Answered OK true like the written events
+                            Admission::ShadowAccept => {}
+                            Admission::Reject(reason) => {
+                                debug!("Batch event {} rejected: {}", event_id, reason);
+                                failure = Some(Error::restricted(format!(
+                                    "event {event_id} rejected: {}",
+                                    reason.message()
+                                )));
+                                // The rejected event is answered with its own reason
+                                if let Some(ResponseHandler::MessageSender(mut sender)) =
+                                    handler.take()
+                                {
+                                    self.send_direct(
+                                        &mut sender,
+                                        OkReason::from(reason).to_message(event_id),
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    replies.push(BatchReply::Signed {
+                        event_id,
+                        handler,
+                        duplicate,
+                    });
                 }
+                StoreCommand::DeleteEvents(filter, _, handler) => {
+                    replies.push(BatchReply::Deleted(handler));
+                    writes.push(BatchWrite::Delete(filter));
+                }
+                StoreCommand::Batch(_) => unreachable!("nested batches are flattened"),
+            }
+        }
 
-                if attempts >= MAX_ATTEMPTS {
-                    warn!(
-                        "Pagination reached max attempts ({}) for subscription {}",
-                        MAX_ATTEMPTS, subscription_id
-                    );
-                    break;
+        let result = match failure {
+            Some(e) => Err(e),
+            None => self.database.apply_batch(&writes, &scope).await,
+        };
+        let saved: Vec<Arc<Event>> = writes
+            .into_iter()
+            .filter_map(|write| match write {
+                BatchWrite::Save(event) => Some(Arc::new(event)),
+                BatchWrite::Delete(_) => None,
+            })
+            .collect();
+        if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+            for event in &saved {
+                match &result {
+                    Ok(()) => metrics.record_event_saved(event.kind.as_u16()),
+                    Err(_) => metrics.record_event_rejected(event.kind.as_u16(), "batch"),
                 }
             }
         }
+        for _ in &saved {
+            self.record_event_result(result.is_ok());
+        }
 
-        debug!(
-            "Pagination complete for subscription {}: sent {} events (requested max: {})",
-            subscription_id, total_sent, max_limit
-        );
+        // Read-your-writes answers once the events are distributed too
+        if result.is_err() || self.ordering == OrderingMode::Relaxed {
+            self.respond_batch(std::mem::take(&mut replies), &result);
+        }
+        result?;
 
-        // Send EOSE
-        sender
-            .send(RelayMessage::EndOfStoredEvents(Cow::Owned(subscription_id)))
-            .map_err(|e| Error::internal(format!("Failed to send EOSE: {e:?}")))?;
+        self.registry.distribute_events(saved.clone(), &scope).await;
+        self.respond_batch(replies, &Ok(()));
+        for event in &saved {
+            self.run_post_save_hooks(event, &scope, depth).await;
+        }
 
-        Ok(())
+        Ok(IngestOutcome::Stored)
     }
 
-    /// Clean up resources (called on connection drop)
-    pub fn cleanup(&self) {
-        debug!(
-            "Cleaning up subscription coordinator for connection {}",
-            self.connection_id
-        );
-        // The connection handle will be dropped, which will remove from registry
+    /// Answer the senders of the commands of a batch with its outcome
+    fn respond_batch(&self, replies: Vec<BatchReply>, result: &Result<(), Error>) {
+        let failed = |e: &Error| Error::database(format!("Batch not applied: {e}"));
+        for reply in replies {
+            match reply {
+                BatchReply::Unsigned(Some(tx)) => {
+                    let _ = tx.send(result.as_ref().map(|_| None).map_err(failed));
+                }
+                BatchReply::Deleted(Some(tx)) => {
+                    let _ = tx.send(result.as_ref().map(|_| ()).map_err(failed));
+                }
+                BatchReply::Signed {
+                    event_id,
+                    handler: Some(ResponseHandler::MessageSender(mut sender)),
+                    duplicate,
+                } => {
+                    let msg = match result {
+                        Ok(()) if duplicate => {
+                            OkReason::Duplicate("already have this event".to_string())
+                                .to_message(event_id)
+                        }
+                        Ok(()) => RelayMessage::ok(event_id, true, ""),
+                        Err(e) => RelayMessage::ok(event_id, false, e.client_message()),
+                    };
+                    self.send_direct(&mut sender, msg);
+                }
+                BatchReply::Signed {
+                    handler: Some(ResponseHandler::Oneshot(tx)),
+                    ..
+                } => {
+                    let _ = tx.send(result.as_ref().map(|_| ()).map_err(failed));
+                }
+                _ => {}
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::test_utils::setup_test_with_database;
-    use std::time::{Duration, Instant};
-    use tokio::time::sleep;
+    /// Run a signed event through the ingest pipeline, then persist and distribute it
+    async fn ingest_signed(
+        &self,
+        event: Event,
+        scope: Scope,
+        mut response_handler: Option<ResponseHandler>,
+        mut timeline: Option<&mut EventTimeline>,
+        depth: usize,
+    ) -> Result<IngestOutcome, Error> {
+        let metrics = crate::global_metrics::get_relay_metrics_handler();
+        match self
+            .ingest_pipeline
+            .admit(&event, &scope, &self.connection_metadata(), &self.database)
+            .await
+        {
+            Admission::Accept => {}
+            Admission::ShadowAccept => {
+                // Answered like a stored event so the sender can't tell
+                if let Some(metrics) = metrics {
+                    metrics.record_event_rejected(event.kind.as_u16(), "spam");
+                }
+                self.record_event_result(false);
+                self.respond_saved(event.id, &Ok(()), response_handler);
+                return Ok(IngestOutcome::ShadowAccepted);
+            }
+            Admission::Duplicate => {
+                // Replayed events are acknowledged without saving or re-broadcasting them
+                debug!("Event {} already stored", event.id);
+                if let Some(metrics) = metrics {
+                    metrics.record_event_rejected(event.kind.as_u16(), "duplicate");
+                }
+                self.record_event_result(false);
+                match response_handler {
+                    Some(ResponseHandler::MessageSender(mut sender)) => {
+                        self.send_direct(
+                            &mut sender,
+                            OkReason::Duplicate("already have this event".to_string())
+                                .to_message(event.id),
+                        );
+                    }
+                    Some(ResponseHandler::Oneshot(tx)) => {
+                        let _ = tx.send(Ok(()));
+                    }
+                    None => {}
+                }
+                return Ok(IngestOutcome::Duplicate);
+            }
+            Admission::Reject(reason) => {
+                debug!("Event {} rejected: {}", event.id, reason);
+                if let Some(metrics) = metrics {
+                    metrics.record_event_rejected(event.kind.as_u16(), reason.prefix());
+                }
+                self.record_event_result(false);
+                let message = reason.to_string();
+                match response_handler {
+                    Some(ResponseHandler::MessageSender(mut sender)) => {
+                        self.send_direct(&mut sender, OkReason::from(reason).to_message(event.id));
+                    }
+                    Some(ResponseHandler::Oneshot(tx)) => {
+                        let _ = tx.send(Err(Error::restricted(message.clone())));
+                    }
+                    None => {}
+                }
+                return Err(Error::restricted(message));
+            }
+        }
 
-    async fn create_test_event(
-        keys: &Keys,
-        timestamp: Timestamp,
-        group: &str,
-        content: &str,
-    ) -> Event {
-        let tags = vec![
-            Tag::custom(TagKind::from("h"), vec![group.to_string()]),
-            Tag::custom(TagKind::from("test"), vec!["pagination".to_string()]),
-        ];
+        let persist_started = std::time::Instant::now();
+        let save_result = self
+            .database
+            .save_event(&event, &scope)
+            .await
+            .inspect_err(|e| error!("Failed to save event {}: {}", event.id, e));
+        crate::ingest::record_stage(IngestStage::Persist, persist_started);
 
-        EventBuilder::new(Kind::from(9), content)
-            .custom_created_at(timestamp)
-            .tags(tags)
-            .build_with_ctx(&Instant::now(), keys.public_key())
-            .sign_with_keys(keys)
+        if let (Ok(()), Some(timeline)) = (&save_result, timeline.as_deref_mut()) {
+            timeline.mark_persisted();
+        }
+        if let Some(metrics) = metrics {
+            match &save_result {
+                Ok(()) => metrics.record_event_saved(event.kind.as_u16()),
+                Err(_) => metrics.record_event_rejected(event.kind.as_u16(), "error"),
+            }
+        }
+        self.record_event_result(save_result.is_ok());
+
+        // Read-your-writes answers once the event is distributed too
+        let event_id = event.id;
+        if save_result.is_err() || self.ordering == OrderingMode::Relaxed {
+            self.respond_saved(event_id, &save_result, response_handler.take());
+        }
+        save_result?;
+
+        let distribute_started = std::time::Instant::now();
+        let event = Arc::new(event);
+        self.registry
+            .distribute_event(Arc::clone(&event), &scope)
+            .await;
+        crate::ingest::record_stage(IngestStage::Distribute, distribute_started);
+
+        if let Some(timeline) = timeline {
+            timeline.mark_distributed();
+        }
+        self.respond_saved(event_id, &Ok(()), response_handler);
+        if let Some(router) = &self.kind_router {
+            router.dispatch(Arc::clone(&event), &scope);
+        }
+        self.run_post_save_hooks(&event, &scope, depth).await;
+
+        Ok(IngestOutcome::Stored)
+    }
+
+    /// Process the follow-up commands the post-save hooks return for `event`
+    ///
+    /// `depth` counts the follow-ups that led to `event`. Boxed, as the
+    /// follow-ups recurse into [`Self::process_store_command`].
+    fn run_post_save_hooks<'a>(
+        &'a self,
+        event: &'a Event,
+        scope: &'a Scope,
+        depth: usize,
+    ) -> BoxFuture<'a, ()> {
+        async move {
+            let Some(hooks) = &self.post_save_hooks else {
+                return;
+            };
+            let commands = hooks.after_save(event, scope).await;
+            if commands.is_empty() {
+                return;
+            }
+            if depth >= hooks.max_depth() {
+                warn!(
+                    "Dropping {} follow-up commands of event {}: chain deeper than {}",
+                    commands.len(),
+                    event.id,
+                    hooks.max_depth()
+                );
+                return;
+            }
+            for command in commands {
+                if let Err(e) = self.process_store_command(command, None, depth + 1).await {
+                    warn!("Follow-up command of event {} failed: {}", event.id, e);
+                }
+            }
+        }
+        .boxed()
+    }
+
+    /// Answer the client that sent the event `event_id` with the outcome of saving it
+    fn respond_saved(
+        &self,
+        event_id: EventId,
+        save_result: &Result<(), Error>,
+        response_handler: Option<ResponseHandler>,
+    ) {
+        match response_handler {
+            Some(ResponseHandler::MessageSender(mut sender)) => {
+                let msg = match save_result {
+                    Ok(()) => RelayMessage::ok(event_id, true, ""),
+                    Err(_) => {
+                        OkReason::Error("could not save the event".to_string()).to_message(event_id)
+                    }
+                };
+                self.send_direct(&mut sender, msg);
+            }
+            Some(ResponseHandler::Oneshot(tx)) => {
+                let _ = tx.send(
+                    save_result
+                        .as_ref()
+                        .map(|_| ())
+                        .map_err(|_| Error::database("Failed to save event")),
+                );
+            }
+            None => {}
+        }
+    }
+
+    /// Handle a REQ message from a client
+    ///
+    /// `filter_fn` decides which stored events are sent, and then which live
+    /// events the connection receives; the function of the latest REQ applies
+    /// to all the connection's subscriptions.
+    pub async fn handle_req(
+        &self,
+        subscription_id: SubscriptionId,
+        filters: Vec<Filter>,
+        authed_pubkey: Option<PublicKey>,
+        subdomain: &Scope,
+        filter_fn: impl Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync + Clone + 'static,
+    ) -> Result<(), Error> {
+        let span = if self.span.is_disabled() {
+            Span::none()
+        } else {
+            tracing::debug_span!(
+                parent: &self.span,
+                "req",
+                subscription_id = %subscription_id,
+                filters = filters.len(),
+            )
+        };
+
+        // A resuming client is only served what it missed
+        match self.redeem_resume_token(&subscription_id, authed_pubkey.as_ref()) {
+            Some(served_until) if self.database.has_receipt_index() => {
+                self.send_received_since(
+                    subscription_id.clone(),
+                    &filters,
+                    served_until,
+                    authed_pubkey,
+                    subdomain,
+                    self.outgoing_sender.clone(),
+                    filter_fn.clone(),
+                )
+                .instrument(span)
+                .await?;
+            }
+            // Without receipt times the cursor can only narrow `created_at`
+            Some(served_until) => {
+                let narrowed: Vec<Filter> = filters
+                    .iter()
+                    .cloned()
+                    .map(|filter| {
+                        let since = filter.since.map_or(served_until, |s| s.max(served_until));
+                        filter.since(since)
+                    })
+                    .collect();
+                self.process_historical_events(
+                    subscription_id.clone(),
+                    &narrowed,
+                    authed_pubkey,
+                    subdomain,
+                    self.outgoing_sender.clone(),
+                    filter_fn.clone(),
+                )
+                .instrument(span)
+                .await?;
+            }
+            None => {
+                self.process_historical_events(
+                    subscription_id.clone(),
+                    &filters,
+                    authed_pubkey,
+                    subdomain,
+                    self.outgoing_sender.clone(),
+                    filter_fn.clone(),
+                )
+                .instrument(span)
+                .await?;
+            }
+        }
+
+        // Live events go through the same visibility check as stored ones
+        self.registry
+            .set_visibility(&self.connection_id, Some(Arc::new(filter_fn)));
+
+        // Add the subscription for future events
+        self.add_subscription(subscription_id.clone(), filters)?;
+
+        if let (Some(resume_cursors), Some(pubkey)) = (&self.resume_cursors, authed_pubkey) {
+            let token = resume_cursors.issue(&pubkey, Timestamp::now());
+            self.send_direct(
+                &mut self.outgoing_sender.clone(),
+                ResumeCursors::notice(&subscription_id, &token),
+            );
+            self.resume_tokens.lock().insert(subscription_id, token);
+        }
+
+        Ok(())
+    }
+
+    /// Send the stored events matching `filters` then EOSE, like
+    /// [`Self::handle_req`] without subscribing to live events
+    pub(crate) async fn handle_stored_req(
+        &self,
+        subscription_id: SubscriptionId,
+        filters: &[Filter],
+        authed_pubkey: Option<PublicKey>,
+        subdomain: &Scope,
+        filter_fn: impl Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync + Clone + 'static,
+    ) -> Result<(), Error> {
+        self.process_historical_events(
+            subscription_id,
+            filters,
+            authed_pubkey,
+            subdomain,
+            self.outgoing_sender.clone(),
+            filter_fn,
+        )
+        .await
+    }
+
+    /// Until when the client resuming `subscription_id` was served, if its token is valid
+    fn redeem_resume_token(
+        &self,
+        subscription_id: &SubscriptionId,
+        authed_pubkey: Option<&PublicKey>,
+    ) -> Option<Timestamp> {
+        let resume_cursors = self.resume_cursors.as_ref()?;
+        let token = ResumeCursors::token_of(subscription_id)?;
+        resume_cursors.redeem(authed_pubkey?, token)
+    }
+
+    /// Send the stored events matching `filters` the relay received since
+    /// `served_until`, in receipt order, then EOSE
+    ///
+    /// Unlike narrowing `created_at`, this keeps events created before the
+    /// cursor but received after it, e.g. backfilled ones or those of clients
+    /// with a skewed clock.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_received_since(
+        &self,
+        subscription_id: SubscriptionId,
+        filters: &[Filter],
+        served_until: Timestamp,
+        authed_pubkey: Option<PublicKey>,
+        subdomain: &Scope,
+        mut sender: MessageSender<RelayMessage<'static>>,
+        filter_fn: impl Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync + Clone + 'static,
+    ) -> Result<(), Error> {
+        let max_limit = self.max_limit();
+        let mut sent_events = HashSet::new();
+        for filter in filters {
+            let limit = filter.limit.map_or(max_limit, |limit| limit.min(max_limit));
+            let received = self
+                .database
+                .query_received(filter.clone(), served_until, limit, subdomain)
+                .await?;
+            for ReceivedEvent { event, .. } in received {
+                if !filter_fn(&event, subdomain, authed_pubkey.as_ref())
+                    || !sent_events.insert(event.id)
+                {
+                    continue;
+                }
+                let msg = RelayMessage::Event {
+                    subscription_id: Cow::Owned(subscription_id.clone()),
+                    event: Cow::Owned(event),
+                };
+                self.send_direct(&mut sender, msg);
+            }
+        }
+        debug!(
+            "Resumed subscription {} with {} events received since {}",
+            subscription_id,
+            sent_events.len(),
+            served_until
+        );
+
+        sender
+            .send(RelayMessage::EndOfStoredEvents(Cow::Owned(subscription_id)))
+            .map_err(|e| Error::channel_closed(format!("Failed to send EOSE: {e:?}")))
+    }
+
+    /// Record that the subscription behind `token` was served until now
+    fn advance_resume_cursor(&self, token: &str) {
+        let pubkey = *self.auth_pubkey.read();
+        if let (Some(resume_cursors), Some(pubkey)) = (&self.resume_cursors, pubkey) {
+            resume_cursors.advance(&pubkey, token, Timestamp::now());
+        }
+    }
+
+    async fn process_historical_events(
+        &self,
+        subscription_id: SubscriptionId,
+        filters: &[Filter],
+        authed_pubkey: Option<PublicKey>,
+        subdomain: &Scope,
+        mut sender: MessageSender<RelayMessage<'static>>,
+        filter_fn: impl Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync + Clone + 'static,
+    ) -> Result<(), Error> {
+        // Cap filter limits based on configured max_limit
+        let max_limit = self.max_limit();
+        let smallest_limit = filters
+            .iter()
+            .filter_map(|f| f.limit)
+            .min()
+            .unwrap_or(max_limit)
+            .min(max_limit);
+
+        let filters: Vec<Filter> = filters
+            .iter()
+            .map(|filter| filter.clone().limit(smallest_limit))
+            .collect();
+
+        let read_database = self.read_database();
+        let started = Instant::now();
+        let mut sent_events = HashSet::new();
+        let mut total_sent = 0;
+        let mut total_attempts = 0;
+        let mut total_scanned = 0;
+        let mut total_filtered = 0;
+        let max_limit = filters.iter().filter_map(|f| f.limit).max().unwrap_or(0);
+
+        // Filters differing in one set only share a query
+        let mut plans = plan_queries(&filters);
+        if plans.len() < filters.len() {
+            debug!(
+                "Merged {} filters of subscription {} into {} queries",
+                filters.len(),
+                subscription_id,
+                plans.len()
+            );
+        }
+
+        let mut plan_idx = 0;
+        while plan_idx < plans.len() {
+            let plan = &plans[plan_idx];
+            let mut paginator = Paginator::new(plan.filter.clone(), self.pagination);
+            let mut quotas = plan.quotas();
+
+            loop {
+                debug!(
+                    "Pagination attempt {} for query {} of subscription {}",
+                    paginator.attempts() + 1,
+                    plan_idx,
+                    subscription_id
+                );
+
+                let page = paginator
+                    .next_page(read_database, subdomain, |event| {
+                        // Skip if we've already sent this event for another query
+                        if sent_events.contains(&event.id) {
+                            return false;
+                        }
+                        if !filter_fn(event, subdomain, authed_pubkey.as_ref()) {
+                            total_filtered += 1;
+                            return false;
+                        }
+                        plan.take(&mut quotas, event)
+                    })
+                    .await?;
+                let Some(page) = page else {
+                    break;
+                };
+
+                // Pages are in descending order (newest first)
+                for event in page {
+                    sent_events.insert(event.id);
+                    let msg = RelayMessage::Event {
+                        subscription_id: Cow::Owned(subscription_id.clone()),
+                        event: Cow::Owned(event),
+                    };
+
+                    self.send_direct(&mut sender, msg);
+                    total_sent += 1;
+                }
+
+                // Events matching several parts leave the merged limit short
+                if QueryPlan::is_filled(&quotas) {
+                    break;
+                }
+            }
+
+            // Busy parts of a merged query may have used up the windows
+            // sparse ones needed, those are queried again on their own
+            let mut retries = Vec::new();
+            if paginator.ran_out_of_attempts() {
+                retries = plan.unfilled(&quotas);
+                if retries.is_empty() {
+                    warn!(
+                        "Pagination reached max attempts ({}) for subscription {}",
+                        self.pagination.max_attempts, subscription_id
+                    );
+                }
+            }
+            debug!(
+                "Sent {} events for query {} in {} windows",
+                paginator.returned(),
+                plan_idx,
+                paginator.attempts()
+            );
+
+            if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+                metrics.record_pagination_attempts(paginator.attempts());
+            }
+            total_attempts += paginator.attempts();
+            total_scanned += paginator.scanned();
+            plans.extend(retries);
+            plan_idx += 1;
+        }
+
+        debug!(
+            "Pagination complete for subscription {}: sent {} events (requested max: {})",
+            subscription_id, total_sent, max_limit
+        );
+
+        // Send EOSE
+        sender
+            .send(RelayMessage::EndOfStoredEvents(Cow::Owned(
+                subscription_id.clone(),
+            )))
+            .map_err(|e| Error::channel_closed(format!("Failed to send EOSE: {e:?}")))?;
+
+        let stats = ReqStats {
+            time_to_eose: started.elapsed(),
+            events_sent: total_sent,
+            windows_scanned: total_attempts,
+            events_scanned: total_scanned,
+            events_filtered: total_filtered,
+        };
+        if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+            metrics.record_req(&stats);
+        }
+        if let Some(slow_query_log) = &self.slow_query_log {
+            if slow_query_log.is_slow(stats.time_to_eose) {
+                slow_query_log.record(SlowQuery {
+                    connection_id: self.connection_id.clone(),
+                    subscription_id,
+                    filters,
+                    scope: subdomain.clone(),
+                    duration: stats.time_to_eose,
+                    pagination_attempts: stats.windows_scanned,
+                    events_scanned: stats.events_scanned,
+                    events_sent: stats.events_sent,
+                    events_filtered: stats.events_filtered,
+                    recorded_at: Timestamp::now(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clean up resources (called on connection drop)
+    pub fn cleanup(&self) {
+        debug!(
+            "Cleaning up subscription coordinator for connection {}",
+            self.connection_id
+        );
+        let tokens: Vec<String> = self.resume_tokens.lock().drain().map(|(_, t)| t).collect();
+        for token in tokens {
+            self.advance_resume_cursor(&token);
+        }
+        // The connection handle will be dropped, which will remove from registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+    use std::time::{Duration, Instant};
+    use tokio::time::sleep;
+
+    async fn create_test_event(
+        keys: &Keys,
+        timestamp: Timestamp,
+        group: &str,
+        content: &str,
+    ) -> Event {
+        let tags = vec![
+            Tag::custom(TagKind::from("h"), vec![group.to_string()]),
+            Tag::custom(TagKind::from("test"), vec!["pagination".to_string()]),
+        ];
+
+        EventBuilder::new(Kind::from(9), content)
+            .custom_created_at(timestamp)
+            .tags(tags)
+            .build_with_ctx(&Instant::now(), keys.public_key())
+            .sign_with_keys(keys)
             .unwrap()
     }
 
-    fn create_test_crypto_helper() -> crate::crypto_helper::CryptoHelper {
-        let test_keys = Keys::generate();
-        crate::crypto_helper::CryptoHelper::new(Arc::new(test_keys))
-    }
+    fn create_test_crypto_helper() -> crate::crypto_helper::CryptoHelper {
+        let test_keys = Keys::generate();
+        crate::crypto_helper::CryptoHelper::new(Arc::new(test_keys))
+    }
+
+    #[tokio::test]
+    async fn test_replaceable_event_buffering() {
+        let buffer = ReplaceableEventsBuffer::new(ReplaceableBufferConfig::default());
+        let sender = buffer.get_sender();
+
+        // Create a replaceable event
+        let event = UnsignedEvent {
+            id: None,
+            pubkey: PublicKey::from_slice(&[1; 32]).unwrap(),
+            created_at: Timestamp::now(),
+            kind: Kind::Metadata, // Replaceable kind
+            tags: Tags::new(),
+            content: "test".to_string(),
+        };
+
+        // Send should succeed
+        sender.send_async((event, Scope::Default)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_window_sliding_limit_only() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (tx, rx) = flume::bounded(100);
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        let coordinator = SubscriptionCoordinator::new(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry,
+            "test_conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+            cancellation_token.clone(),
+            None,
+            1000, // max_limit
+            ReplaceableBufferConfig::default(),
+        );
+
+        let base_timestamp = Timestamp::from(1700000000);
+
+        // Create 10 events alternating between public and private groups
+        for i in 0..10 {
+            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
+            let group = if i % 2 == 0 { "public" } else { "private" };
+            let event = create_test_event(&keys, timestamp, group, &format!("Event {i}")).await;
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        // Wait a bit for database to process
+        sleep(Duration::from_millis(100)).await;
+
+        // Request limit=5, but only public events should be returned
+        let filter = Filter::new().kinds(vec![Kind::from(9)]).limit(5);
+        let sub_id = SubscriptionId::new("test_sub");
+
+        // Filter function that only allows public group events
+        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
+            event.tags.iter().any(|t| {
+                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
+            })
+        };
+
+        // Process the subscription
+        coordinator
+            .handle_req(
+                sub_id.clone(),
+                vec![filter],
+                None,
+                &Scope::Default,
+                filter_fn,
+            )
+            .await
+            .unwrap();
+
+        // Allow some time for events to be processed
+        sleep(Duration::from_millis(100)).await;
+
+        // Collect events from receiver
+        let mut received_events = Vec::new();
+        let mut eose_received = false;
+
+        while let Ok(msg) = rx.try_recv() {
+            match msg.0 {
+                RelayMessage::Event { event, .. } => {
+                    received_events.push(event.into_owned());
+                }
+                RelayMessage::EndOfStoredEvents(_) => {
+                    eose_received = true;
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        assert!(eose_received, "Should receive EOSE");
+        assert_eq!(
+            received_events.len(),
+            5,
+            "Should receive exactly 5 public events through pagination"
+        );
+
+        // Verify all events are public
+        for event in &received_events {
+            assert!(
+                event.tags.iter().any(|t| t.as_slice().len() > 1
+                    && t.as_slice()[0] == "h"
+                    && t.as_slice()[1] == "public"),
+                "All events should be from public group"
+            );
+        }
+
+        // Clean up
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_window_sliding_until_limit() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (tx, rx) = flume::bounded(100);
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        let coordinator = SubscriptionCoordinator::new(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry,
+            "test_conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+            cancellation_token.clone(),
+            None,
+            1000,
+            ReplaceableBufferConfig::default(),
+        );
+
+        let base_timestamp = Timestamp::from(1700000000);
+
+        // Create 10 events across 100 seconds
+        for i in 0..10 {
+            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
+            let group = if i % 2 == 0 { "public" } else { "private" };
+            let event = create_test_event(&keys, timestamp, group, &format!("Event {i}")).await;
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        sleep(Duration::from_millis(100)).await;
+
+        // Request with until=80 (position 8) and limit 5
+        let filter = Filter::new()
+            .kinds(vec![Kind::from(9)])
+            .until(Timestamp::from(base_timestamp.as_u64() + 80))
+            .limit(5);
+
+        let sub_id = SubscriptionId::new("test_sub");
+        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
+            event.tags.iter().any(|t| {
+                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
+            })
+        };
+
+        coordinator
+            .handle_req(
+                sub_id.clone(),
+                vec![filter],
+                None,
+                &Scope::Default,
+                filter_fn,
+            )
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        let mut received_events = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            if let RelayMessage::Event { event, .. } = msg.0 {
+                received_events.push(event.into_owned());
+            }
+        }
+
+        // Should get public events 8, 6, 4, 2, 0 through pagination
+        assert_eq!(received_events.len(), 5, "Should receive 5 public events");
+
+        // Verify they're in reverse chronological order
+        for i in 1..received_events.len() {
+            assert!(
+                received_events[i - 1].created_at > received_events[i].created_at,
+                "Events should be in reverse chronological order"
+            );
+        }
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_window_sliding_since_limit() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (tx, rx) = flume::bounded(100);
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        let coordinator = SubscriptionCoordinator::new(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry,
+            "test_conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+            cancellation_token.clone(),
+            None,
+            1000,
+            ReplaceableBufferConfig::default(),
+        );
+
+        let base_timestamp = Timestamp::from(1700000000);
+
+        // Create 10 events
+        for i in 0..10 {
+            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
+            let group = if i % 2 == 0 { "public" } else { "private" };
+            let event = create_test_event(&keys, timestamp, group, &format!("Event {i}")).await;
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        sleep(Duration::from_millis(100)).await;
+
+        // Request with since=20 and limit 5
+        let filter = Filter::new()
+            .kinds(vec![Kind::from(9)])
+            .since(Timestamp::from(base_timestamp.as_u64() + 20))
+            .limit(5);
+
+        let sub_id = SubscriptionId::new("test_sub");
+        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
+            event.tags.iter().any(|t| {
+                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
+            })
+        };
+
+        coordinator
+            .handle_req(
+                sub_id.clone(),
+                vec![filter],
+                None,
+                &Scope::Default,
+                filter_fn,
+            )
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        let mut received_events = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            if let RelayMessage::Event { event, .. } = msg.0 {
+                received_events.push(event.into_owned());
+            }
+        }
+
+        // Events are created with indices 0-9
+        // Timestamps: i * 10, so: 0, 10, 20, 30, 40, 50, 60, 70, 80, 90
+        // Public events are at even indices (0, 2, 4, 6, 8) with timestamps: 0, 20, 40, 60, 80
+        // With since=20, we get events with timestamp >= 20
+        // Public events meeting this criteria: 20, 40, 60, 80 (4 events)
+        // With limit=5, pagination should find all 4 public events
+        assert_eq!(
+            received_events.len(),
+            4,
+            "Should receive 4 public events with timestamps >= 20"
+        );
+
+        // Verify they're in descending order (newest first)
+        for i in 1..received_events.len() {
+            assert!(
+                received_events[i - 1].created_at > received_events[i].created_at,
+                "Events should be in descending chronological order"
+            );
+        }
+
+        // Verify all events have timestamp >= 20
+        for event in &received_events {
+            assert!(
+                event.created_at.as_u64() >= base_timestamp.as_u64() + 20,
+                "All events should have timestamp >= since filter"
+            );
+        }
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_pagination_bug_scenario() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (tx, rx) = flume::bounded(100);
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        let coordinator = SubscriptionCoordinator::new(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry,
+            "test_conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+            cancellation_token.clone(),
+            None,
+            1000,
+            ReplaceableBufferConfig::default(),
+        );
+
+        let base_timestamp = Timestamp::from(1700000000);
+
+        // Create 1 old accessible event
+        let event =
+            create_test_event(&keys, base_timestamp, "public", "Old accessible event").await;
+        database.save_event(&event, &Scope::Default).await.unwrap();
+
+        // Create 5 newer non-accessible events
+        for i in 0..5 {
+            let timestamp = Timestamp::from(base_timestamp.as_u64() + 100 + i * 10);
+            let event =
+                create_test_event(&keys, timestamp, "private", &format!("Private {i}")).await;
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        // Wait a bit for database to process
+        sleep(Duration::from_millis(100)).await;
 
-    #[tokio::test]
-    async fn test_replaceable_event_buffering() {
-        let buffer = ReplaceableEventsBuffer::new();
-        let sender = buffer.get_sender();
+        // Request limit=5 (will get the 5 newest events, all private)
+        let filter = Filter::new().kinds(vec![Kind::from(9)]).limit(5);
+        let sub_id = SubscriptionId::new("test_sub");
 
-        // Create a replaceable event
-        let event = UnsignedEvent {
-            id: None,
-            pubkey: PublicKey::from_slice(&[1; 32]).unwrap(),
-            created_at: Timestamp::now(),
-            kind: Kind::Metadata, // Replaceable kind
-            tags: Tags::new(),
-            content: "test".to_string(),
+        // Filter function that only allows public group events
+        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
+            event.tags.iter().any(|t| {
+                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
+            })
         };
 
-        // Send should succeed
-        sender.send_async((event, Scope::Default)).await.unwrap();
+        // Process the subscription - pagination should find the old public event
+        coordinator
+            .handle_req(
+                sub_id.clone(),
+                vec![filter],
+                None,
+                &Scope::Default,
+                filter_fn,
+            )
+            .await
+            .unwrap();
+
+        // Allow some time for events to be processed
+        sleep(Duration::from_millis(100)).await;
+
+        // Collect events from receiver
+        let mut received_events = Vec::new();
+        let mut eose_received = false;
+
+        while let Ok(msg) = rx.try_recv() {
+            match msg.0 {
+                RelayMessage::Event { event, .. } => {
+                    received_events.push(event.into_owned());
+                }
+                RelayMessage::EndOfStoredEvents(_) => {
+                    eose_received = true;
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        assert!(eose_received, "Should receive EOSE");
+        assert_eq!(
+            received_events.len(),
+            1,
+            "Should find the old accessible event through pagination"
+        );
+        assert_eq!(received_events[0].content, "Old accessible event");
+
+        cancellation_token.cancel();
     }
 
     #[tokio::test]
-    async fn test_window_sliding_limit_only() {
+    async fn test_exponential_buffer_since_until_limit() {
         let (_tmp_dir, database, keys) = setup_test_with_database().await;
         let (tx, rx) = flume::bounded(100);
         let registry = Arc::new(SubscriptionRegistry::new(None));
@@ -686,14 +2540,15 @@ mod tests {
             Arc::new(Scope::Default),
             cancellation_token.clone(),
             None,
-            1000, // max_limit
+            1000,
+            ReplaceableBufferConfig::default(),
         );
 
         let base_timestamp = Timestamp::from(1700000000);
 
-        // Create 10 events alternating between public and private groups
-        for i in 0..10 {
-            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
+        // Create 20 events: 10 public, 10 private, interleaved
+        for i in 0..20 {
+            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 5);
             let group = if i % 2 == 0 { "public" } else { "private" };
             let event = create_test_event(&keys, timestamp, group, &format!("Event {i}")).await;
             database.save_event(&event, &Scope::Default).await.unwrap();
@@ -702,18 +2557,25 @@ mod tests {
         // Wait a bit for database to process
         sleep(Duration::from_millis(100)).await;
 
-        // Request limit=5, but only public events should be returned
-        let filter = Filter::new().kinds(vec![Kind::from(9)]).limit(5);
-        let sub_id = SubscriptionId::new("test_sub");
+        // Request events in time window [25, 75] with limit 5
+        // Events are at timestamps: 0, 5, 10, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 65, 70, 75, 80, 85, 90, 95
+        // Window [25, 75] contains: 25, 30, 35, 40, 45, 50, 55, 60, 65, 70, 75
+        // That's indices 5-15 inclusive (11 events total)
+        // Public events (even indices): 6, 8, 10, 12, 14 (5 public events)
+        let filter = Filter::new()
+            .kinds(vec![Kind::from(9)])
+            .since(Timestamp::from(base_timestamp.as_u64() + 25))
+            .until(Timestamp::from(base_timestamp.as_u64() + 75))
+            .limit(5);
 
-        // Filter function that only allows public group events
+        let sub_id = SubscriptionId::new("test_sub");
         let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
             event.tags.iter().any(|t| {
                 t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
             })
         };
 
-        // Process the subscription
+        // This should use the unified pagination approach
         coordinator
             .handle_req(
                 sub_id.clone(),
@@ -725,10 +2587,8 @@ mod tests {
             .await
             .unwrap();
 
-        // Allow some time for events to be processed
         sleep(Duration::from_millis(100)).await;
 
-        // Collect events from receiver
         let mut received_events = Vec::new();
         let mut eose_received = false;
 
@@ -749,10 +2609,10 @@ mod tests {
         assert_eq!(
             received_events.len(),
             5,
-            "Should receive exactly 5 public events through pagination"
+            "Should receive exactly 5 public events in the time window"
         );
 
-        // Verify all events are public
+        // Verify all events are public and within the time window
         for event in &received_events {
             assert!(
                 event.tags.iter().any(|t| t.as_slice().len() > 1
@@ -760,94 +2620,246 @@ mod tests {
                     && t.as_slice()[1] == "public"),
                 "All events should be from public group"
             );
+
+            let ts = event.created_at.as_u64();
+            assert!(
+                ts >= base_timestamp.as_u64() + 25 && ts <= base_timestamp.as_u64() + 75,
+                "Event timestamp should be within the requested window"
+            );
+        }
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_max_limit_enforcement() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (tx, rx) = flume::bounded(100);
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        // Create coordinator with small max_limit
+        let max_limit = 10;
+        let coordinator = SubscriptionCoordinator::new(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry,
+            "test_conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+            cancellation_token.clone(),
+            None,
+            max_limit,
+            ReplaceableBufferConfig::default(),
+        );
+
+        // Create many events
+        for i in 0..30 {
+            let event = EventBuilder::text_note(format!("Event {i}"))
+                .build_with_ctx(&Instant::now(), keys.public_key())
+                .sign_with_keys(&keys)
+                .unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        sleep(Duration::from_millis(100)).await;
+
+        // Request with limit higher than max_limit
+        let filter = Filter::new().kinds(vec![Kind::TextNote]).limit(100);
+        let sub_id = SubscriptionId::new("test_sub");
+        let filter_fn = |_: &Event, _: &Scope, _: Option<&PublicKey>| true;
+
+        coordinator
+            .handle_req(
+                sub_id.clone(),
+                vec![filter],
+                None,
+                &Scope::Default,
+                filter_fn,
+            )
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        let mut event_count = 0;
+        let mut eose_received = false;
+
+        while let Ok(msg) = rx.try_recv() {
+            match msg.0 {
+                RelayMessage::Event { .. } => {
+                    event_count += 1;
+                }
+                RelayMessage::EndOfStoredEvents(_) => {
+                    eose_received = true;
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        assert!(eose_received, "Should receive EOSE");
+        assert_eq!(
+            event_count, max_limit,
+            "Should receive exactly max_limit ({}) events even though {} were requested",
+            max_limit, 100
+        );
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_filters_smallest_limit() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (tx, rx) = flume::bounded(100);
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        let coordinator = SubscriptionCoordinator::new(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry,
+            "test_conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+            cancellation_token.clone(),
+            None,
+            1000,
+            ReplaceableBufferConfig::default(),
+        );
+
+        // Create 20 events
+        for i in 0..20 {
+            let event = EventBuilder::text_note(format!("Event {i}"))
+                .build_with_ctx(&Instant::now(), keys.public_key())
+                .sign_with_keys(&keys)
+                .unwrap();
+            database.save_event(&event, &Scope::Default).await.unwrap();
+        }
+
+        sleep(Duration::from_millis(100)).await;
+
+        // Create multiple filters with different limits
+        let filters = vec![
+            Filter::new().kinds(vec![Kind::TextNote]).limit(50),
+            Filter::new().kinds(vec![Kind::TextNote]).limit(5), // Smallest limit
+            Filter::new().kinds(vec![Kind::TextNote]).limit(20),
+        ];
+
+        let sub_id = SubscriptionId::new("test_sub");
+        let filter_fn = |_: &Event, _: &Scope, _: Option<&PublicKey>| true;
+
+        coordinator
+            .handle_req(sub_id.clone(), filters, None, &Scope::Default, filter_fn)
+            .await
+            .unwrap();
+
+        sleep(Duration::from_millis(100)).await;
+
+        let mut event_count = 0;
+        let mut eose_received = false;
+
+        while let Ok(msg) = rx.try_recv() {
+            match msg.0 {
+                RelayMessage::Event { .. } => {
+                    event_count += 1;
+                }
+                RelayMessage::EndOfStoredEvents(_) => {
+                    eose_received = true;
+                    break;
+                }
+                _ => {}
+            }
         }
 
-        // Clean up
+        assert!(eose_received, "Should receive EOSE");
+        assert_eq!(
+            event_count, 5,
+            "Should receive exactly 5 events (the smallest limit among filters)"
+        );
+
         cancellation_token.cancel();
     }
 
     #[tokio::test]
-    async fn test_window_sliding_until_limit() {
-        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+    async fn test_resumed_req_serves_backdated_events_by_receipt() {
+        use crate::resume::RESUME_TOKEN_SEPARATOR;
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let receipts =
+            crate::receipts::ReceiptIndex::open(tmp_dir.path().join("receipts")).unwrap();
+        let database = Arc::new(
+            RelayDatabase::new(tmp_dir.path().join("events.db"))
+                .unwrap()
+                .with_receipt_index(receipts),
+        );
+        let keys = Keys::generate();
         let (tx, rx) = flume::bounded(100);
-        let registry = Arc::new(SubscriptionRegistry::new(None));
         let cancellation_token = CancellationToken::new();
+        let resume_cursors = ResumeCursors::default();
 
-        let coordinator = SubscriptionCoordinator::new(
+        let coordinator = SubscriptionCoordinator::builder(
             database.clone(),
             create_test_crypto_helper(),
-            registry,
+            Arc::new(SubscriptionRegistry::new(None)),
             "test_conn".to_string(),
             MessageSender::new(tx, 0),
-            None,
-            Arc::new(Scope::Default),
-            cancellation_token.clone(),
-            None,
-            1000,
-        );
-
-        let base_timestamp = Timestamp::from(1700000000);
-
-        // Create 10 events across 100 seconds
-        for i in 0..10 {
-            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
-            let group = if i % 2 == 0 { "public" } else { "private" };
-            let event = create_test_event(&keys, timestamp, group, &format!("Event {i}")).await;
-            database.save_event(&event, &Scope::Default).await.unwrap();
-        }
-
-        sleep(Duration::from_millis(100)).await;
-
-        // Request with until=80 (position 8) and limit 5
-        let filter = Filter::new()
-            .kinds(vec![Kind::from(9)])
-            .until(Timestamp::from(base_timestamp.as_u64() + 80))
-            .limit(5);
-
-        let sub_id = SubscriptionId::new("test_sub");
-        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
-            event.tags.iter().any(|t| {
-                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
-            })
-        };
+        )
+        .with_auth_pubkey(Some(keys.public_key()))
+        .with_cancellation_token(cancellation_token.clone())
+        .with_resume_cursors(Some(resume_cursors.clone()))
+        .build();
+
+        // Served before the reconnect
+        let old = EventBuilder::text_note("old")
+            .custom_created_at(Timestamp::now() - 7200)
+            .sign_with_keys(&keys)
+            .unwrap();
+        database.save_event(&old, &Scope::Default).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let token = resume_cursors.issue(&keys.public_key(), Timestamp::now());
+
+        // Received after the cursor, but created an hour before it
+        let backdated = EventBuilder::text_note("backfilled")
+            .custom_created_at(Timestamp::now() - 3600)
+            .sign_with_keys(&keys)
+            .unwrap();
+        database
+            .save_event(&backdated, &Scope::Default)
+            .await
+            .unwrap();
 
+        let filter_fn = |_: &Event, _: &Scope, _: Option<&PublicKey>| true;
         coordinator
             .handle_req(
-                sub_id.clone(),
-                vec![filter],
-                None,
+                SubscriptionId::new(format!("feed{RESUME_TOKEN_SEPARATOR}{token}")),
+                vec![Filter::new().kinds(vec![Kind::TextNote])],
+                Some(keys.public_key()),
                 &Scope::Default,
                 filter_fn,
             )
             .await
             .unwrap();
 
-        sleep(Duration::from_millis(100)).await;
-
-        let mut received_events = Vec::new();
+        let mut received = Vec::new();
         while let Ok(msg) = rx.try_recv() {
-            if let RelayMessage::Event { event, .. } = msg.0 {
-                received_events.push(event.into_owned());
+            match msg.0 {
+                RelayMessage::Event { event, .. } => received.push(event.id),
+                RelayMessage::EndOfStoredEvents(_) => break,
+                _ => {}
             }
         }
-
-        // Should get public events 8, 6, 4, 2, 0 through pagination
-        assert_eq!(received_events.len(), 5, "Should receive 5 public events");
-
-        // Verify they're in reverse chronological order
-        for i in 1..received_events.len() {
-            assert!(
-                received_events[i - 1].created_at > received_events[i].created_at,
-                "Events should be in reverse chronological order"
-            );
-        }
+        assert_eq!(received, vec![backdated.id]);
 
         cancellation_token.cancel();
     }
 
     #[tokio::test]
-    async fn test_window_sliding_since_limit() {
-        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+    async fn test_historical_queries_use_read_replica() {
+        let (tmp_dir, database, keys) = setup_test_with_database().await;
+        let replica = Arc::new(RelayDatabase::new(tmp_dir.path().join("replica.db")).unwrap());
         let (tx, rx) = flume::bounded(100);
         let registry = Arc::new(SubscriptionRegistry::new(None));
         let cancellation_token = CancellationToken::new();
@@ -863,36 +2875,23 @@ mod tests {
             cancellation_token.clone(),
             None,
             1000,
-        );
-
-        let base_timestamp = Timestamp::from(1700000000);
-
-        // Create 10 events
-        for i in 0..10 {
-            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
-            let group = if i % 2 == 0 { "public" } else { "private" };
-            let event = create_test_event(&keys, timestamp, group, &format!("Event {i}")).await;
-            database.save_event(&event, &Scope::Default).await.unwrap();
-        }
-
-        sleep(Duration::from_millis(100)).await;
+            ReplaceableBufferConfig::default(),
+        )
+        .with_read_replicas(ReadReplicas::new(vec![replica.clone()]));
 
-        // Request with since=20 and limit 5
-        let filter = Filter::new()
-            .kinds(vec![Kind::from(9)])
-            .since(Timestamp::from(base_timestamp.as_u64() + 20))
-            .limit(5);
+        // Only the replica has this event, the primary stays empty
+        let event = EventBuilder::text_note("Replicated")
+            .build_with_ctx(&Instant::now(), keys.public_key())
+            .sign_with_keys(&keys)
+            .unwrap();
+        replica.save_event(&event, &Scope::Default).await.unwrap();
 
-        let sub_id = SubscriptionId::new("test_sub");
-        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
-            event.tags.iter().any(|t| {
-                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
-            })
-        };
+        let filter = Filter::new().kinds(vec![Kind::TextNote]).limit(10);
+        let filter_fn = |_: &Event, _: &Scope, _: Option<&PublicKey>| true;
 
         coordinator
             .handle_req(
-                sub_id.clone(),
+                SubscriptionId::new("replica_sub"),
                 vec![filter],
                 None,
                 &Scope::Default,
@@ -901,57 +2900,35 @@ mod tests {
             .await
             .unwrap();
 
-        sleep(Duration::from_millis(100)).await;
-
-        let mut received_events = Vec::new();
+        let mut received = Vec::new();
         while let Ok(msg) = rx.try_recv() {
-            if let RelayMessage::Event { event, .. } = msg.0 {
-                received_events.push(event.into_owned());
+            match msg.0 {
+                RelayMessage::Event { event, .. } => received.push(event.id),
+                RelayMessage::EndOfStoredEvents(_) => break,
+                _ => {}
             }
         }
 
-        // Events are created with indices 0-9
-        // Timestamps: i * 10, so: 0, 10, 20, 30, 40, 50, 60, 70, 80, 90
-        // Public events are at even indices (0, 2, 4, 6, 8) with timestamps: 0, 20, 40, 60, 80
-        // With since=20, we get events with timestamp >= 20
-        // Public events meeting this criteria: 20, 40, 60, 80 (4 events)
-        // With limit=5, pagination should find all 4 public events
         assert_eq!(
-            received_events.len(),
-            4,
-            "Should receive 4 public events with timestamps >= 20"
+            received,
+            vec![event.id],
+            "REQ should be served by the replica"
         );
 
-        // Verify they're in descending order (newest first)
-        for i in 1..received_events.len() {
-            assert!(
-                received_events[i - 1].created_at > received_events[i].created_at,
-                "Events should be in descending chronological order"
-            );
-        }
-
-        // Verify all events have timestamp >= 20
-        for event in &received_events {
-            assert!(
-                event.created_at.as_u64() >= base_timestamp.as_u64() + 20,
-                "All events should have timestamp >= since filter"
-            );
-        }
-
         cancellation_token.cancel();
     }
 
     #[tokio::test]
-    async fn test_pagination_bug_scenario() {
+    async fn test_close_subscription_sends_closed_and_stops_events() {
         let (_tmp_dir, database, keys) = setup_test_with_database().await;
         let (tx, rx) = flume::bounded(100);
         let registry = Arc::new(SubscriptionRegistry::new(None));
         let cancellation_token = CancellationToken::new();
 
         let coordinator = SubscriptionCoordinator::new(
-            database.clone(),
+            database,
             create_test_crypto_helper(),
-            registry,
+            registry.clone(),
             "test_conn".to_string(),
             MessageSender::new(tx, 0),
             None,
@@ -959,84 +2936,285 @@ mod tests {
             cancellation_token.clone(),
             None,
             1000,
+            ReplaceableBufferConfig::default(),
         );
 
-        let base_timestamp = Timestamp::from(1700000000);
-
-        // Create 1 old accessible event
-        let event =
-            create_test_event(&keys, base_timestamp, "public", "Old accessible event").await;
-        database.save_event(&event, &Scope::Default).await.unwrap();
+        let sub_id = SubscriptionId::new("policy_sub");
+        coordinator
+            .add_subscription(sub_id.clone(), vec![Filter::new()])
+            .unwrap();
+        coordinator
+            .close_subscription(
+                sub_id.clone(),
+                ClosedReason::RateLimited("too many REQs".to_string()),
+            )
+            .unwrap();
 
-        // Create 5 newer non-accessible events
-        for i in 0..5 {
-            let timestamp = Timestamp::from(base_timestamp.as_u64() + 100 + i * 10);
-            let event =
-                create_test_event(&keys, timestamp, "private", &format!("Private {i}")).await;
-            database.save_event(&event, &Scope::Default).await.unwrap();
+        match rx.try_recv().unwrap().0 {
+            RelayMessage::Closed {
+                subscription_id,
+                message,
+            } => {
+                assert_eq!(*subscription_id, sub_id);
+                assert_eq!(message, "rate-limited: too many REQs");
+            }
+            other => panic!("Expected CLOSED, got {other:?}"),
         }
 
-        // Wait a bit for database to process
-        sleep(Duration::from_millis(100)).await;
+        // The subscription no longer receives live events
+        let event = EventBuilder::text_note("after close")
+            .build_with_ctx(&Instant::now(), keys.public_key())
+            .sign_with_keys(&keys)
+            .unwrap();
+        registry
+            .distribute_event(Arc::new(event), &Scope::Default)
+            .await;
+        assert!(rx.try_recv().is_err());
 
-        // Request limit=5 (will get the 5 newest events, all private)
-        let filter = Filter::new().kinds(vec![Kind::from(9)]).limit(5);
-        let sub_id = SubscriptionId::new("test_sub");
+        cancellation_token.cancel();
+    }
 
-        // Filter function that only allows public group events
-        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
-            event.tags.iter().any(|t| {
-                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
-            })
-        };
+    #[tokio::test]
+    async fn test_read_your_writes_acknowledges_after_distribution() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let registry = Arc::new(SubscriptionRegistry::new(None));
 
-        // Process the subscription - pagination should find the old public event
+        for ordering in [OrderingMode::Relaxed, OrderingMode::ReadYourWrites] {
+            let (tx, rx) = flume::bounded(100);
+            let coordinator = SubscriptionCoordinator::builder(
+                database.clone(),
+                create_test_crypto_helper(),
+                registry.clone(),
+                format!("{ordering:?}"),
+                MessageSender::new(tx.clone(), 0),
+            )
+            .with_ordering(ordering)
+            .build();
+            coordinator
+                .add_subscription(SubscriptionId::new("own"), vec![Filter::new()])
+                .unwrap();
+
+            let event = EventBuilder::text_note(format!("{ordering:?}"))
+                .sign_with_keys(&keys)
+                .unwrap();
+            coordinator
+                .save_and_broadcast(StoreCommand::SaveSignedEvent(
+                    Box::new(event),
+                    Scope::Default,
+                    Some(ResponseHandler::MessageSender(MessageSender::new(tx, 0))),
+                ))
+                .await
+                .unwrap();
+
+            let order: Vec<&str> = rx
+                .try_iter()
+                .map(|(message, _)| match message {
+                    RelayMessage::Ok { .. } => "OK",
+                    RelayMessage::Event { .. } => "EVENT",
+                    other => panic!("unexpected message {other:?}"),
+                })
+                .collect();
+            let expected = match ordering {
+                OrderingMode::Relaxed => ["OK", "EVENT"],
+                OrderingMode::ReadYourWrites => ["EVENT", "OK"],
+            };
+            assert_eq!(order, expected);
+        }
+    }
+
+    /// Answers every text note with a reply, which it answers again
+    #[derive(Debug)]
+    struct ReplyHook(Keys);
+
+    #[async_trait::async_trait]
+    impl crate::post_save::PostSaveHook for ReplyHook {
+        async fn after_save(&self, event: &Event, scope: &Scope) -> Vec<StoreCommand> {
+            let reply = EventBuilder::text_note(format!("re: {}", event.content))
+                .sign_with_keys(&self.0)
+                .unwrap();
+            vec![StoreCommand::SaveSignedEvent(
+                Box::new(reply),
+                scope.clone(),
+                None,
+            )]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_save_chains_stop_at_max_depth() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let (tx, _rx) = flume::bounded(100);
+        let coordinator = SubscriptionCoordinator::builder(
+            database.clone(),
+            create_test_crypto_helper(),
+            Arc::new(SubscriptionRegistry::new(None)),
+            "chain".to_string(),
+            MessageSender::new(tx, 0),
+        )
+        .with_post_save_hooks(Some(
+            PostSaveHooks::new()
+                .with_hook(ReplyHook(keys.clone()))
+                .with_max_depth(2),
+        ))
+        .build();
+
+        let note = EventBuilder::text_note("hi").sign_with_keys(&keys).unwrap();
         coordinator
-            .handle_req(
-                sub_id.clone(),
-                vec![filter],
+            .save_and_broadcast(StoreCommand::SaveSignedEvent(
+                Box::new(note),
+                Scope::Default,
                 None,
-                &Scope::Default,
-                filter_fn,
-            )
+            ))
             .await
             .unwrap();
 
-        // Allow some time for events to be processed
-        sleep(Duration::from_millis(100)).await;
+        let mut contents: Vec<String> = database
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.content)
+            .collect();
+        contents.sort();
+        assert_eq!(contents, vec!["hi", "re: hi", "re: re: hi"]);
+    }
 
-        // Collect events from receiver
-        let mut received_events = Vec::new();
-        let mut eose_received = false;
+    #[tokio::test]
+    async fn test_batch_is_applied_all_or_nothing() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let (tx, rx) = flume::bounded(100);
+        let coordinator = SubscriptionCoordinator::builder(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry,
+            "batch".to_string(),
+            MessageSender::new(tx, 0),
+        )
+        .build();
+        coordinator
+            .add_subscription(SubscriptionId::new("all"), vec![Filter::new()])
+            .unwrap();
 
-        while let Ok(msg) = rx.try_recv() {
-            match msg.0 {
-                RelayMessage::Event { event, .. } => {
-                    received_events.push(event.into_owned());
-                }
-                RelayMessage::EndOfStoredEvents(_) => {
-                    eose_received = true;
-                    break;
-                }
-                _ => {}
-            }
-        }
+        let note = |content: &str| {
+            EventBuilder::text_note(content)
+                .sign_with_keys(&keys)
+                .unwrap()
+        };
+        let old = note("old");
+        database.save_event(&old, &Scope::Default).await.unwrap();
+
+        // A tampered event rejects the whole batch, the delete isn't applied
+        let mut tampered = note("tampered");
+        tampered.content = "changed".to_string();
+        let result = coordinator
+            .save_and_broadcast(StoreCommand::Batch(vec![
+                StoreCommand::SaveSignedEvent(Box::new(note("first")), Scope::Default, None),
+                StoreCommand::DeleteEvents(Filter::new().id(old.id), Scope::Default, None),
+                StoreCommand::SaveSignedEvent(Box::new(tampered), Scope::Default, None),
+            ]))
+            .await;
+        assert!(result.is_err());
+        let stored = database
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(rx.try_iter().next().is_none());
 
-        assert!(eose_received, "Should receive EOSE");
+        let second = note("second");
+        coordinator
+            .save_and_broadcast(StoreCommand::Batch(vec![
+                StoreCommand::SaveSignedEvent(Box::new(second.clone()), Scope::Default, None),
+                StoreCommand::Batch(vec![StoreCommand::DeleteEvents(
+                    Filter::new().id(old.id),
+                    Scope::Default,
+                    None,
+                )]),
+            ]))
+            .await
+            .unwrap();
+        let stored: Vec<EventId> = database
+            .query(vec![Filter::new()], &Scope::Default)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.id)
+            .collect();
+        assert_eq!(stored, vec![second.id]);
+        assert_eq!(rx.try_iter().count(), 1);
+
+        let result = coordinator
+            .save_and_broadcast(StoreCommand::Batch(vec![
+                StoreCommand::SaveSignedEvent(Box::new(note("a")), Scope::Default, None),
+                StoreCommand::SaveSignedEvent(
+                    Box::new(note("b")),
+                    Scope::named("other").unwrap(),
+                    None,
+                ),
+            ]))
+            .await;
         assert_eq!(
-            received_events.len(),
-            1,
-            "Should find the old accessible event through pagination"
+            result.unwrap_err().client_message(),
+            "invalid: batch commands must share one scope"
         );
-        assert_eq!(received_events[0].content, "Old accessible event");
+    }
+
+    #[tokio::test]
+    async fn test_replaceable_buffer_flush_triggers() {
+        let keys = Keys::generate();
+        let config = ReplaceableBufferConfig::default()
+            .with_flush_interval(Duration::from_secs(10))
+            .with_max_entries(2)
+            .with_max_latency(Duration::from_millis(100));
+        let mut buffer = ReplaceableEventsBuffer::new(config);
+
+        // Nothing to wait for while empty
+        assert!(buffer.flush_deadline().is_none());
+
+        // Updates of the same replaceable event coalesce into one entry
+        for content in ["first", "second"] {
+            let event = EventBuilder::new(Kind::Metadata, content).build(keys.public_key());
+            buffer.insert(event, Scope::Default);
+        }
+        assert!(!buffer.is_full());
+
+        // The latency bound is tighter than the flush interval
+        let deadline = buffer.flush_deadline().unwrap();
+        assert!(deadline <= tokio::time::Instant::now() + Duration::from_millis(100));
+
+        let event = EventBuilder::new(Kind::ContactList, "").build(keys.public_key());
+        buffer.insert(event, Scope::Default);
+        assert!(buffer.is_full());
+    }
 
-        cancellation_token.cancel();
+    #[tokio::test]
+    async fn test_replaceable_buffer_interval_starts_at_first_entry() {
+        let keys = Keys::generate();
+        let config = ReplaceableBufferConfig::default()
+            .with_flush_interval(Duration::from_millis(500))
+            .with_max_latency(Duration::from_secs(10));
+        let mut buffer = ReplaceableEventsBuffer::new(config);
+
+        // An idle period doesn't make the first event after it due at once
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let before_insert = tokio::time::Instant::now();
+        let event = EventBuilder::new(Kind::Metadata, "").build(keys.public_key());
+        buffer.insert(event, Scope::Default);
+        let deadline = buffer.flush_deadline().unwrap();
+        assert!(deadline >= before_insert + Duration::from_millis(500));
+
+        // Later updates don't push the deadline back
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let event = EventBuilder::new(Kind::ContactList, "").build(keys.public_key());
+        buffer.insert(event, Scope::Default);
+        assert_eq!(buffer.flush_deadline(), Some(deadline));
     }
 
     #[tokio::test]
-    async fn test_exponential_buffer_since_until_limit() {
+    async fn test_event_policy_rejects_before_persistence() {
         let (_tmp_dir, database, keys) = setup_test_with_database().await;
-        let (tx, rx) = flume::bounded(100);
+        let (tx, _rx) = flume::bounded(100);
         let registry = Arc::new(SubscriptionRegistry::new(None));
         let cancellation_token = CancellationToken::new();
 
@@ -1051,106 +3229,59 @@ mod tests {
             cancellation_token.clone(),
             None,
             1000,
-        );
-
-        let base_timestamp = Timestamp::from(1700000000);
+            ReplaceableBufferConfig::default(),
+        )
+        .with_event_policies(Some(
+            EventPolicyChain::new().with_policy(crate::event_policy::MaxContentLength(5)),
+        ));
 
-        // Create 20 events: 10 public, 10 private, interleaved
-        for i in 0..20 {
-            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 5);
-            let group = if i % 2 == 0 { "public" } else { "private" };
-            let event = create_test_event(&keys, timestamp, group, &format!("Event {i}")).await;
-            database.save_event(&event, &Scope::Default).await.unwrap();
+        let rejected = EventBuilder::text_note("far too long")
+            .sign_with_keys(&keys)
+            .unwrap();
+        let (response_tx, response_rx) = oneshot::channel();
+        let result = coordinator
+            .save_and_broadcast(StoreCommand::SaveSignedEvent(
+                Box::new(rejected.clone()),
+                Scope::Default,
+                Some(ResponseHandler::Oneshot(response_tx)),
+            ))
+            .await;
+        assert!(matches!(result, Err(Error::Restricted { .. })));
+        match response_rx.await.unwrap() {
+            Err(Error::Restricted { message, .. }) => assert!(message.starts_with("invalid:")),
+            other => panic!("Expected restricted error, got {other:?}"),
         }
 
-        // Wait a bit for database to process
-        sleep(Duration::from_millis(100)).await;
-
-        // Request events in time window [25, 75] with limit 5
-        // Events are at timestamps: 0, 5, 10, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 65, 70, 75, 80, 85, 90, 95
-        // Window [25, 75] contains: 25, 30, 35, 40, 45, 50, 55, 60, 65, 70, 75
-        // That's indices 5-15 inclusive (11 events total)
-        // Public events (even indices): 6, 8, 10, 12, 14 (5 public events)
-        let filter = Filter::new()
-            .kinds(vec![Kind::from(9)])
-            .since(Timestamp::from(base_timestamp.as_u64() + 25))
-            .until(Timestamp::from(base_timestamp.as_u64() + 75))
-            .limit(5);
-
-        let sub_id = SubscriptionId::new("test_sub");
-        let filter_fn = |event: &Event, _scope: &Scope, _auth: Option<&PublicKey>| -> bool {
-            event.tags.iter().any(|t| {
-                t.as_slice().len() > 1 && t.as_slice()[0] == "h" && t.as_slice()[1] == "public"
-            })
-        };
-
-        // This should use the unified pagination approach
+        let accepted = EventBuilder::text_note("short")
+            .sign_with_keys(&keys)
+            .unwrap();
         coordinator
-            .handle_req(
-                sub_id.clone(),
-                vec![filter],
-                None,
+            .save_and_broadcast((accepted.clone(), Scope::Default).into())
+            .await
+            .unwrap();
+
+        let stored = database
+            .query(
+                vec![Filter::new().author(keys.public_key())],
                 &Scope::Default,
-                filter_fn,
             )
             .await
             .unwrap();
-
-        sleep(Duration::from_millis(100)).await;
-
-        let mut received_events = Vec::new();
-        let mut eose_received = false;
-
-        while let Ok(msg) = rx.try_recv() {
-            match msg.0 {
-                RelayMessage::Event { event, .. } => {
-                    received_events.push(event.into_owned());
-                }
-                RelayMessage::EndOfStoredEvents(_) => {
-                    eose_received = true;
-                    break;
-                }
-                _ => {}
-            }
-        }
-
-        assert!(eose_received, "Should receive EOSE");
-        assert_eq!(
-            received_events.len(),
-            5,
-            "Should receive exactly 5 public events in the time window"
-        );
-
-        // Verify all events are public and within the time window
-        for event in &received_events {
-            assert!(
-                event.tags.iter().any(|t| t.as_slice().len() > 1
-                    && t.as_slice()[0] == "h"
-                    && t.as_slice()[1] == "public"),
-                "All events should be from public group"
-            );
-
-            let ts = event.created_at.as_u64();
-            assert!(
-                ts >= base_timestamp.as_u64() + 25 && ts <= base_timestamp.as_u64() + 75,
-                "Event timestamp should be within the requested window"
-            );
-        }
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored.into_iter().next().unwrap().id, accepted.id);
 
         cancellation_token.cancel();
     }
 
     #[tokio::test]
-    async fn test_max_limit_enforcement() {
+    async fn test_duplicate_event_is_not_rebroadcast() {
         let (_tmp_dir, database, keys) = setup_test_with_database().await;
         let (tx, rx) = flume::bounded(100);
         let registry = Arc::new(SubscriptionRegistry::new(None));
         let cancellation_token = CancellationToken::new();
 
-        // Create coordinator with small max_limit
-        let max_limit = 10;
         let coordinator = SubscriptionCoordinator::new(
-            database.clone(),
+            database,
             create_test_crypto_helper(),
             registry,
             "test_conn".to_string(),
@@ -1159,70 +3290,102 @@ mod tests {
             Arc::new(Scope::Default),
             cancellation_token.clone(),
             None,
-            max_limit,
+            1000,
+            ReplaceableBufferConfig::default(),
         );
+        coordinator
+            .add_subscription(SubscriptionId::new("live"), vec![Filter::new()])
+            .unwrap();
 
-        // Create many events
-        for i in 0..30 {
-            let event = EventBuilder::text_note(format!("Event {i}"))
-                .build_with_ctx(&Instant::now(), keys.public_key())
-                .sign_with_keys(&keys)
+        let event = EventBuilder::text_note("once")
+            .sign_with_keys(&keys)
+            .unwrap();
+        for _ in 0..2 {
+            let (sender_tx, _sender_rx) = flume::bounded(10);
+            coordinator
+                .save_and_broadcast(StoreCommand::SaveSignedEvent(
+                    Box::new(event.clone()),
+                    Scope::Default,
+                    Some(ResponseHandler::MessageSender(MessageSender::new(
+                        sender_tx, 0,
+                    ))),
+                ))
+                .await
                 .unwrap();
-            database.save_event(&event, &Scope::Default).await.unwrap();
         }
 
-        sleep(Duration::from_millis(100)).await;
+        let delivered = rx
+            .try_iter()
+            .filter(|(message, _)| matches!(message, RelayMessage::Event { .. }))
+            .count();
+        assert_eq!(delivered, 1);
 
-        // Request with limit higher than max_limit
-        let filter = Filter::new().kinds(vec![Kind::TextNote]).limit(100);
-        let sub_id = SubscriptionId::new("test_sub");
-        let filter_fn = |_: &Event, _: &Scope, _: Option<&PublicKey>| true;
+        cancellation_token.cancel();
+    }
 
-        coordinator
-            .handle_req(
-                sub_id.clone(),
-                vec![filter],
-                None,
-                &Scope::Default,
-                filter_fn,
-            )
-            .await
-            .unwrap();
+    #[test]
+    fn test_ok_reason_rendering() {
+        let event_id = EventId::all_zeros();
+        let duplicate = OkReason::Duplicate("already have this event".to_string());
+        assert_eq!(duplicate.to_string(), DUPLICATE_EVENT_MESSAGE);
+        assert_eq!(
+            duplicate.to_message(event_id),
+            RelayMessage::ok(event_id, true, DUPLICATE_EVENT_MESSAGE)
+        );
 
-        sleep(Duration::from_millis(100)).await;
+        let blocked = OkReason::from(ClosedReason::Blocked("spam".to_string()));
+        assert_eq!(
+            blocked.to_message(event_id),
+            RelayMessage::ok(event_id, false, "blocked: spam")
+        );
+        assert_eq!(
+            OkReason::Pow("difficulty 20 required".to_string()).to_string(),
+            "pow: difficulty 20 required"
+        );
+    }
 
-        let mut event_count = 0;
-        let mut eose_received = false;
+    #[tokio::test]
+    async fn test_trace_sampling() {
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let (_tmp_dir, database, _keys) = setup_test_with_database().await;
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
 
-        while let Ok(msg) = rx.try_recv() {
-            match msg.0 {
-                RelayMessage::Event { .. } => {
-                    event_count += 1;
-                }
-                RelayMessage::EndOfStoredEvents(_) => {
-                    eose_received = true;
-                    break;
-                }
-                _ => {}
-            }
-        }
+        let coordinator = |connection_id: &str, sample_rate: f64| {
+            let (tx, _rx) = flume::bounded(100);
+            SubscriptionCoordinator::new(
+                database.clone(),
+                create_test_crypto_helper(),
+                registry.clone(),
+                connection_id.to_string(),
+                MessageSender::new(tx, 0),
+                None,
+                Arc::new(Scope::Default),
+                cancellation_token.clone(),
+                None,
+                1000,
+                ReplaceableBufferConfig::default(),
+            )
+            .with_trace_sample_rate(sample_rate)
+        };
 
-        assert!(eose_received, "Should receive EOSE");
-        assert_eq!(
-            event_count, max_limit,
-            "Should receive exactly max_limit ({}) events even though {} were requested",
-            max_limit, 100
-        );
+        let sampled = coordinator("sampled", 1.0);
+        assert!(!sampled.span().is_disabled());
+        sampled.set_auth_pubkey(Keys::generate().public_key());
+
+        let unsampled = coordinator("unsampled", 0.0);
+        assert!(unsampled.span().is_disabled());
 
         cancellation_token.cancel();
     }
 
     #[tokio::test]
-    async fn test_multiple_filters_smallest_limit() {
+    async fn test_slow_query_is_recorded() {
         let (_tmp_dir, database, keys) = setup_test_with_database().await;
-        let (tx, rx) = flume::bounded(100);
+        let (tx, _rx) = flume::bounded(100);
         let registry = Arc::new(SubscriptionRegistry::new(None));
         let cancellation_token = CancellationToken::new();
+        let slow_query_log = SlowQueryLog::new(Duration::ZERO);
 
         let coordinator = SubscriptionCoordinator::new(
             database.clone(),
@@ -1235,58 +3398,174 @@ mod tests {
             cancellation_token.clone(),
             None,
             1000,
-        );
+            ReplaceableBufferConfig::default(),
+        )
+        .with_slow_query_log(Some(slow_query_log.clone()));
 
-        // Create 20 events
-        for i in 0..20 {
-            let event = EventBuilder::text_note(format!("Event {i}"))
-                .build_with_ctx(&Instant::now(), keys.public_key())
-                .sign_with_keys(&keys)
-                .unwrap();
+        let base_timestamp = Timestamp::from(1700000000);
+        for i in 0..3 {
+            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
+            let event = create_test_event(&keys, timestamp, "private", "hidden").await;
             database.save_event(&event, &Scope::Default).await.unwrap();
         }
 
-        sleep(Duration::from_millis(100)).await;
-
-        // Create multiple filters with different limits
-        let filters = vec![
-            Filter::new().kinds(vec![Kind::TextNote]).limit(50),
-            Filter::new().kinds(vec![Kind::TextNote]).limit(5), // Smallest limit
-            Filter::new().kinds(vec![Kind::TextNote]).limit(20),
-        ];
-
-        let sub_id = SubscriptionId::new("test_sub");
-        let filter_fn = |_: &Event, _: &Scope, _: Option<&PublicKey>| true;
-
+        // Every scanned event is filtered out
         coordinator
-            .handle_req(sub_id.clone(), filters, None, &Scope::Default, filter_fn)
+            .handle_req(
+                SubscriptionId::new("slow"),
+                vec![Filter::new().kinds(vec![Kind::from(9)]).limit(2)],
+                None,
+                &Scope::Default,
+                |_: &Event, _: &Scope, _: Option<&PublicKey>| false,
+            )
             .await
             .unwrap();
 
-        sleep(Duration::from_millis(100)).await;
+        let entries = slow_query_log.entries();
+        assert_eq!(entries.len(), 1);
+        let query = &entries[0];
+        assert_eq!(query.subscription_id, SubscriptionId::new("slow"));
+        assert_eq!(query.connection_id, "test_conn");
+        assert_eq!(query.events_scanned, 3);
+        assert_eq!(query.events_sent, 0);
+        assert_eq!(query.events_filtered, 3);
+        assert_eq!(query.pagination_attempts, 2);
 
-        let mut event_count = 0;
-        let mut eose_received = false;
+        cancellation_token.cancel();
+    }
 
-        while let Ok(msg) = rx.try_recv() {
-            match msg.0 {
-                RelayMessage::Event { .. } => {
-                    event_count += 1;
-                }
-                RelayMessage::EndOfStoredEvents(_) => {
-                    eose_received = true;
-                    break;
-                }
-                _ => {}
-            }
+    #[tokio::test]
+    async fn test_exponential_windows_reach_sparse_events() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        let base_timestamp = Timestamp::from(1700000000);
+        for i in 0..20 {
+            let timestamp = Timestamp::from(base_timestamp.as_u64() + i * 10);
+            let content = if i == 0 { "visible" } else { "hidden" };
+            let event = create_test_event(&keys, timestamp, "sparse", content).await;
+            database.save_event(&event, &Scope::Default).await.unwrap();
         }
 
-        assert!(eose_received, "Should receive EOSE");
+        // Only the oldest of 20 events is visible and 3 windows are allowed
+        let serve = |strategy: WindowStrategy| {
+            let (tx, rx) = flume::bounded(100);
+            let coordinator = SubscriptionCoordinator::builder(
+                database.clone(),
+                create_test_crypto_helper(),
+                registry.clone(),
+                format!("conn_{strategy:?}"),
+                MessageSender::new(tx, 0),
+            )
+            .with_cancellation_token(cancellation_token.clone())
+            .with_pagination(
+                PaginationConfig::default()
+                    .with_max_attempts(3)
+                    .with_strategy(strategy),
+            )
+            .build();
+            async move {
+                coordinator
+                    .handle_req(
+                        SubscriptionId::new("sparse"),
+                        vec![Filter::new().kinds(vec![Kind::from(9)]).limit(1)],
+                        None,
+                        &Scope::Default,
+                        |event: &Event, _: &Scope, _: Option<&PublicKey>| {
+                            event.content == "visible"
+                        },
+                    )
+                    .await
+                    .unwrap();
+                rx.try_iter()
+                    .filter(|(message, _)| matches!(message, RelayMessage::Event { .. }))
+                    .count()
+            }
+        };
+
+        // Windows of 1 event give up after scanning 3 events
+        assert_eq!(serve(WindowStrategy::Fixed).await, 0);
+        // Windows of 1, 4 and 16 events cover all 20
         assert_eq!(
-            event_count, 5,
-            "Should receive exactly 5 events (the smallest limit among filters)"
+            serve(WindowStrategy::Exponential {
+                factor: 4,
+                max_window: 100,
+            })
+            .await,
+            1
         );
 
         cancellation_token.cancel();
     }
+
+    #[tokio::test]
+    async fn test_merged_query_refills_sparse_parts() {
+        let (_tmp_dir, database, keys) = setup_test_with_database().await;
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let cancellation_token = CancellationToken::new();
+
+        // An old profile behind many newer notes
+        let base_timestamp = Timestamp::from(1700000000);
+        let profile = EventBuilder::new(Kind::Metadata, "{}")
+            .custom_created_at(base_timestamp)
+            .sign_with_keys(&keys)
+            .unwrap();
+        database
+            .save_event(&profile, &Scope::Default)
+            .await
+            .unwrap();
+        for i in 1..=30 {
+            let note = EventBuilder::text_note(i.to_string())
+                .custom_created_at(Timestamp::from(base_timestamp.as_u64() + i))
+                .sign_with_keys(&keys)
+                .unwrap();
+            database.save_event(&note, &Scope::Default).await.unwrap();
+        }
+
+        let (tx, rx) = flume::bounded(100);
+        let coordinator = SubscriptionCoordinator::builder(
+            database.clone(),
+            create_test_crypto_helper(),
+            registry.clone(),
+            "conn_profile".to_string(),
+            MessageSender::new(tx, 0),
+        )
+        .with_cancellation_token(cancellation_token.clone())
+        .with_pagination(PaginationConfig::default().with_max_attempts(1))
+        .build();
+
+        // Merged into one query whose only window is filled by notes
+        coordinator
+            .handle_req(
+                SubscriptionId::new("profile"),
+                vec![
+                    Filter::new()
+                        .author(keys.public_key())
+                        .kind(Kind::Metadata)
+                        .limit(1),
+                    Filter::new()
+                        .author(keys.public_key())
+                        .kind(Kind::TextNote)
+                        .limit(20),
+                ],
+                None,
+                &Scope::Default,
+                |_: &Event, _: &Scope, _: Option<&PublicKey>| true,
+            )
+            .await
+            .unwrap();
+
+        let kinds: Vec<Kind> = rx
+            .try_iter()
+            .filter_map(|(message, _)| match message {
+                RelayMessage::Event { event, .. } => Some(event.kind),
+                _ => None,
+            })
+            .collect();
+        assert!(kinds.contains(&Kind::Metadata));
+        assert!(kinds.contains(&Kind::TextNote));
+
+        cancellation_token.cancel();
+    }
 }
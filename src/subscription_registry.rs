@@ -3,15 +3,27 @@
 //! This module replaces the broadcast channel + actor pattern with a more efficient
 //! DashMap-based approach that allows true parallel event distribution.
 
+use crate::broadcast::{MessageSenderExt, SerializedEvent};
+use crate::connection_hook::ConnectionHook;
 use crate::error::Error;
+use crate::event_sink::EventSink;
+use crate::memory::{event_size, filters_size, MemoryAccount, MemoryUsage};
 use crate::metrics::SubscriptionMetricsHandler;
+use crate::supervisor::TaskSupervisor;
 use dashmap::DashMap;
 use nostr_lmdb::Scope;
 use nostr_sdk::prelude::*;
-use parking_lot::RwLock;
-use std::collections::HashMap;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tracing::{debug, trace, warn};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, trace, warn, Instrument, Span};
 use websocket_builder::MessageSender;
 
 /// Trait for distributing events to subscribers
@@ -19,38 +31,567 @@ use websocket_builder::MessageSender;
 pub trait EventDistributor: Send + Sync {
     /// Distribute an event to all matching subscriptions within the given scope
     async fn distribute_event(&self, event: Arc<Event>, scope: &Scope);
+
+    /// Distribute a burst of events, in order, to all matching subscriptions within the given scope
+    async fn distribute_events(&self, events: Vec<Arc<Event>>, scope: &Scope) {
+        for event in events {
+            self.distribute_event(event, scope).await;
+        }
+    }
+}
+
+/// Decides whether a connection may see an event, given the connection's scope
+/// and authenticated pubkey
+pub type VisibilityFn = Arc<dyn Fn(&Event, &Scope, Option<&PublicKey>) -> bool + Send + Sync>;
+
+/// Whether an author is shadow-banned, see [`SubscriptionRegistry::with_shadow_bans`]
+pub type ShadowBanFn = Arc<dyn Fn(&PublicKey) -> bool + Send + Sync>;
+
+/// Assigns a connection its [`SubscriptionPriority`] from its authenticated
+/// pubkey and scope
+pub type PriorityFn = Arc<dyn Fn(Option<&PublicKey>, &Scope) -> SubscriptionPriority + Send + Sync>;
+
+/// Class of a connection's subscriptions when fan-out is overloaded
+///
+/// Without a fan-out limit all classes get every event, but connections of
+/// the [`Low`](SubscriptionPriority::Low) class are still disconnected on
+/// their first full channel, whatever the [`SlowConsumerPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriptionPriority {
+    /// Throttled first and disconnected as soon as they fall behind, e.g. anonymous users
+    Low,
+    /// Throttled after high priority, the default
+    #[default]
+    Normal,
+    /// Never throttled, e.g. paying members
+    High,
 }
 
 /// Registry for managing all active subscriptions across connections
 #[derive(Clone)]
 pub struct SubscriptionRegistry {
-    /// Map of connection_id to their subscription data
-    connections: Arc<DashMap<String, Arc<ConnectionSubscriptions>>>,
+    /// Map of connection_id to their subscription data, split into shards
+    connections: Arc<ConnectionShards>,
+    /// Per-shard distribution workers, only when there is more than one shard
+    workers: Option<Arc<Vec<flume::Sender<DistributionJob>>>>,
+    /// What to do when a connection's outbound channel is full
+    slow_consumer_policy: SlowConsumerPolicy,
     /// Optional metrics handler
     metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>,
+    /// Recently accepted events per scope, see [`SubscriptionRegistry::scope_activity`]
+    event_rates: Arc<DashMap<Scope, EventRate>>,
+    /// Handed every distributed event, e.g. upstream relays
+    sinks: Vec<Arc<dyn EventSink>>,
+    /// Told about WebSocket connections opening and closing
+    connection_hooks: Vec<Arc<dyn ConnectionHook>>,
+    /// Assigns connections their priority, see [`SubscriptionRegistry::with_priorities`]
+    priority_fn: Option<PriorityFn>,
+    /// Subscriptions one event is delivered to before lower priorities are throttled
+    fanout_limit: Option<usize>,
+    /// Estimated memory of filters and queued events, see [`SubscriptionRegistry::memory_usage`]
+    memory: Arc<MemoryAccount>,
+    /// Authors whose events only reach their own connections
+    shadow_bans: Option<ShadowBanFn>,
 }
 
 impl std::fmt::Debug for SubscriptionRegistry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SubscriptionRegistry")
             .field("connections_count", &self.connections.len())
+            .field("shards", &self.connections.shards.len())
             .field("has_metrics_handler", &self.metrics_handler.is_some())
             .finish()
     }
 }
 
+type ConnectionMap = DashMap<String, Arc<ConnectionSubscriptions>>;
+
+/// Connections split by a hash of their id
+///
+/// Each shard is distributed to by its own worker, so a slow lock or a huge
+/// number of connections in one shard doesn't hold up the others.
+struct ConnectionShards {
+    shards: Vec<ConnectionMap>,
+}
+
+impl ConnectionShards {
+    fn new(shard_count: usize) -> Self {
+        Self {
+            shards: (0..shard_count.max(1)).map(|_| DashMap::new()).collect(),
+        }
+    }
+
+    fn shard(&self, connection_id: &str) -> &ConnectionMap {
+        let mut hasher = twox_hash::XxHash64::with_seed(0);
+        connection_id.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    fn get(
+        &self,
+        connection_id: &str,
+    ) -> Option<dashmap::mapref::one::Ref<'_, String, Arc<ConnectionSubscriptions>>> {
+        self.shard(connection_id).get(connection_id)
+    }
+
+    fn insert(&self, connection_id: String, connection: Arc<ConnectionSubscriptions>) {
+        self.shard(&connection_id).insert(connection_id, connection);
+    }
+
+    fn remove(&self, connection_id: &str) -> Option<(String, Arc<ConnectionSubscriptions>)> {
+        self.shard(connection_id).remove(connection_id)
+    }
+
+    #[cfg(test)]
+    fn contains_key(&self, connection_id: &str) -> bool {
+        self.shard(connection_id).contains_key(connection_id)
+    }
+
+    fn iter(
+        &self,
+    ) -> impl Iterator<
+        Item = dashmap::mapref::multiple::RefMulti<'_, String, Arc<ConnectionSubscriptions>>,
+    > {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+}
+
+/// An event handed to a shard's distribution worker
+struct DistributionJob {
+    /// Events of the burst, in distribution order
+    events: Arc<[Arc<Event>]>,
+    scope: Scope,
+    policy: SlowConsumerPolicy,
+    fanout_limit: Option<usize>,
+    /// Span of the distribution, entered by the worker
+    span: Span,
+    /// Receives the number of matched subscriptions once the shard is done
+    done: tokio::sync::oneshot::Sender<usize>,
+}
+
 /// Subscription data for a single connection
 pub struct ConnectionSubscriptions {
     /// Map of subscription_id to filters - RwLock since writes are rare
     subscriptions: RwLock<HashMap<SubscriptionId, Vec<Filter>>>,
     /// Channel to send events to this connection
     sender: MessageSender<RelayMessage<'static>>,
-    /// Authenticated public key if any
-    auth_pubkey: Option<PublicKey>,
-    /// Subdomain/scope for this connection (Arc for cheap clones)
-    subdomain: Arc<Scope>,
+    /// Authenticated public key if any, updated when the client authenticates
+    auth_pubkey: RwLock<Option<PublicKey>>,
+    /// Visibility check of live events, see [`SubscriptionRegistry::set_visibility`]
+    visibility: RwLock<Option<VisibilityFn>>,
+    /// Subdomain/scope for this connection (Arc for cheap clones).
+    /// Only changes when an admin migrates the scope, see [`SubscriptionRegistry::migrate_scope`]
+    subdomain: RwLock<Arc<Scope>>,
+    /// Class of the connection's subscriptions during fan-out overload
+    priority: RwLock<SubscriptionPriority>,
+    /// Milliseconds since [`CLOCK_ORIGIN`] of the last client activity
+    last_activity_ms: AtomicU64,
+    /// Set when a send to this connection failed; the reaper removes it
+    dead: AtomicBool,
+    /// Set once the connection hooks saw the connection open
+    announced: AtomicBool,
+    /// Cancelled to close the connection's socket when the registry detaches it
+    connection_token: RwLock<Option<CancellationToken>>,
+    /// Slow-consumer bookkeeping, only used by the non-default policies
+    backpressure: Mutex<Backpressure>,
+    /// Traffic counters, see [`ConnectionStats`]
+    counters: ConnectionCounters,
+    /// Registry-wide memory totals this connection's share is counted in
+    memory: Arc<MemoryAccount>,
+    /// Estimated bytes of this connection's filters
+    filter_bytes: AtomicUsize,
+    /// Estimated bytes of this connection's queued events
+    pending_bytes: AtomicUsize,
+}
+
+impl Drop for ConnectionSubscriptions {
+    fn drop(&mut self) {
+        self.memory
+            .remove_filters(self.filter_bytes.load(Ordering::Relaxed));
+        self.memory
+            .remove_pending(self.pending_bytes.load(Ordering::Relaxed));
+    }
+}
+
+/// Running totals behind a [`ConnectionStats`] snapshot
+struct ConnectionCounters {
+    connected_at: Timestamp,
+    remote_address: RwLock<Option<String>>,
+    user_agent: RwLock<Option<String>>,
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    events_accepted: AtomicU64,
+    events_rejected: AtomicU64,
+    subscriptions_opened: AtomicU64,
+}
+
+impl ConnectionCounters {
+    fn new() -> Self {
+        Self {
+            connected_at: Timestamp::now(),
+            remote_address: RwLock::new(None),
+            user_agent: RwLock::new(None),
+            messages_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            events_accepted: AtomicU64::new(0),
+            events_rejected: AtomicU64::new(0),
+            subscriptions_opened: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Point-in-time statistics of one connection
+///
+/// Returned by [`SubscriptionRegistry::connection_stats`] and
+/// [`SubscriptionRegistry::connections_snapshot`], e.g. to find the connection
+/// behind an abuse report or to size a deployment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub connection_id: String,
+    /// Address the client connected from, if known
+    pub remote_address: Option<String>,
+    /// `User-Agent` header of the WebSocket upgrade, if any
+    pub user_agent: Option<String>,
+    /// Pubkey the connection was registered with
+    pub auth_pubkey: Option<PublicKey>,
+    pub scope: Arc<Scope>,
+    pub priority: SubscriptionPriority,
+    pub connected_at: Timestamp,
+    /// Client messages handled by the relay
+    pub messages_in: u64,
+    /// Size of those messages as JSON
+    pub bytes_in: u64,
+    /// Messages sent to the client
+    pub messages_out: u64,
+    /// Size of the frames sent to the client
+    pub bytes_out: u64,
+    /// Client events stored
+    pub events_accepted: u64,
+    /// Client events refused, duplicates included
+    pub events_rejected: u64,
+    /// Subscriptions opened since the connection started
+    pub subscriptions_opened: u64,
+    /// Subscriptions currently open
+    pub active_subscriptions: usize,
+}
+
+/// Per-connection state of the [`SlowConsumerPolicy`]
+#[derive(Default)]
+struct Backpressure {
+    /// Consecutive failed sends
+    failures: u32,
+    /// Undelivered events, oldest first
+    pending: VecDeque<(SubscriptionId, Arc<Event>)>,
+    /// Subscriptions paused until the given instant
+    paused: HashMap<SubscriptionId, Instant>,
+}
+
+impl ConnectionSubscriptions {
+    fn stats(&self, connection_id: &str) -> ConnectionStats {
+        let counters = &self.counters;
+        ConnectionStats {
+            connection_id: connection_id.to_string(),
+            remote_address: counters.remote_address.read().clone(),
+            user_agent: counters.user_agent.read().clone(),
+            auth_pubkey: *self.auth_pubkey.read(),
+            scope: Arc::clone(&self.subdomain.read()),
+            priority: *self.priority.read(),
+            connected_at: counters.connected_at,
+            messages_in: counters.messages_in.load(Ordering::Relaxed),
+            bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+            messages_out: counters.messages_out.load(Ordering::Relaxed),
+            bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+            events_accepted: counters.events_accepted.load(Ordering::Relaxed),
+            events_rejected: counters.events_rejected.load(Ordering::Relaxed),
+            subscriptions_opened: counters.subscriptions_opened.load(Ordering::Relaxed),
+            active_subscriptions: self.subscriptions.read().len(),
+        }
+    }
+
+    fn hold_filters(&self, bytes: usize) {
+        self.filter_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.memory.add_filters(bytes);
+    }
+
+    fn release_filters(&self, bytes: usize) {
+        self.filter_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.memory.remove_filters(bytes);
+    }
+
+    fn hold_pending(&self, event: &Event) {
+        let bytes = event_size(event);
+        self.pending_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.memory.add_pending(bytes);
+    }
+
+    fn release_pending(&self, event: &Event) {
+        let bytes = event_size(event);
+        self.pending_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.memory.remove_pending(bytes);
+    }
+
+    fn touch(&self) {
+        self.last_activity_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        Duration::from_millis(
+            now_ms().saturating_sub(self.last_activity_ms.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// Send a message, flagging the connection as dead if its channel is full or closed
+    fn send(&self, message: RelayMessage<'static>) -> bool {
+        let mut sender = self.sender.clone();
+        let sent = sender.send(message).is_ok();
+        if !sent {
+            self.dead.store(true, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Send an event to several subscriptions back to back, applying `policy`
+    /// when the channel is full
+    ///
+    /// Returns `false` once nothing more should be sent to this connection.
+    fn deliver_batch(
+        &self,
+        policy: SlowConsumerPolicy,
+        sub_ids: &[&SubscriptionId],
+        event: &Arc<Event>,
+        serialized: &SerializedEvent,
+    ) -> bool {
+        // MessageSender.send() is synchronous and uses try_send internally
+        let mut sender = self.sender.clone();
+
+        if policy == SlowConsumerPolicy::Disconnect {
+            for &sub_id in sub_ids {
+                if let Err(e) = sender.send_serialized_event(sub_id.clone(), event, serialized) {
+                    warn!("Failed to send to subscription {}: {:?}", sub_id, e);
+                    self.dead.store(true, Ordering::Relaxed);
+                    return false;
+                }
+            }
+            return true;
+        }
+
+        let mut backpressure = self.backpressure.lock();
+        for &sub_id in sub_ids {
+            let sub_id = sub_id.clone();
+            match policy {
+                // Sent without bookkeeping above
+                SlowConsumerPolicy::Disconnect => {}
+                SlowConsumerPolicy::DropOldest { capacity } => {
+                    // Queue behind older undelivered events to keep the order
+                    if backpressure.pending.is_empty()
+                        && sender
+                            .send_serialized_event(sub_id.clone(), event, serialized)
+                            .is_ok()
+                    {
+                        continue;
+                    }
+                    self.hold_pending(event);
+                    backpressure.pending.push_back((sub_id, Arc::clone(event)));
+                    if backpressure.pending.len() > capacity {
+                        if let Some((_, dropped)) = backpressure.pending.pop_front() {
+                            self.release_pending(&dropped);
+                        }
+                        trace!("Dropped oldest pending event for slow consumer");
+                    }
+                }
+                SlowConsumerPolicy::CloseAfter { max_failures } => {
+                    match sender.send_serialized_event(sub_id, event, serialized) {
+                        Ok(()) => backpressure.failures = 0,
+                        Err(e) => {
+                            backpressure.failures += 1;
+                            if backpressure.failures >= max_failures {
+                                warn!(
+                                    "Closing slow consumer after {} failed sends: {:?}",
+                                    backpressure.failures, e
+                                );
+                                self.dead.store(true, Ordering::Relaxed);
+                                return false;
+                            }
+                        }
+                    }
+                }
+                SlowConsumerPolicy::PauseSubscription { resume_after } => {
+                    if let Some(paused_until) = backpressure.paused.get(&sub_id) {
+                        if Instant::now() < *paused_until {
+                            continue;
+                        }
+                        backpressure.paused.remove(&sub_id);
+                        debug!("Resuming paused subscription {}", sub_id);
+                    }
+                    if sender
+                        .send_serialized_event(sub_id.clone(), event, serialized)
+                        .is_err()
+                    {
+                        debug!("Pausing subscription {} of slow consumer", sub_id);
+                        backpressure
+                            .paused
+                            .insert(sub_id, Instant::now() + resume_after);
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Retry events queued by [`SlowConsumerPolicy::DropOldest`], oldest first
+    fn flush_pending(&self) {
+        let mut backpressure = self.backpressure.lock();
+        let mut sender = self.sender.clone();
+
+        while let Some((sub_id, event)) = backpressure.pending.front().cloned() {
+            let serialized = SerializedEvent::new(&event);
+            if sender
+                .send_serialized_event(sub_id, &event, &serialized)
+                .is_err()
+            {
+                break;
+            }
+            backpressure.pending.pop_front();
+            self.release_pending(&event);
+        }
+    }
+
+    /// Whether any subscription of the connection matches `event`
+    fn matches(&self, event: &Event) -> bool {
+        self.subscriptions.read().values().any(|filters| {
+            filters.iter().any(|filter| {
+                filter.match_event(event, nostr_sdk::filter::MatchEventOptions::default())
+            })
+        })
+    }
+
+    /// Forget the backpressure state of a closed subscription
+    fn forget_subscription(&self, sub_id: &SubscriptionId) {
+        let mut backpressure = self.backpressure.lock();
+        backpressure.pending.retain(|(id, event)| {
+            if id == sub_id {
+                self.release_pending(event);
+            }
+            id != sub_id
+        });
+        backpressure.paused.remove(sub_id);
+    }
+}
+
+/// What distribution does when a connection's outbound channel is full
+///
+/// Set with [`SubscriptionRegistry::with_slow_consumer_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    /// Stop sending on the first failed send and let the reaper drop the connection
+    #[default]
+    Disconnect,
+    /// Queue up to `capacity` undelivered events per connection, dropping the
+    /// oldest when the queue overflows, and retry them on the next distribution
+    /// or reaper sweep
+    DropOldest { capacity: usize },
+    /// Tolerate up to `max_failures - 1` consecutive failed sends, then close the
+    /// connection with a [`SLOW_CONSUMER_NOTICE`]
+    CloseAfter { max_failures: u32 },
+    /// Stop sending to the subscription that hit a full channel for `resume_after`
+    PauseSubscription { resume_after: Duration },
+}
+
+/// NOTICE sent, best effort, to connections reaped after failed sends
+pub const SLOW_CONSUMER_NOTICE: &str = "slow consumer: outbound queue full, closing connection";
+
+/// Reference point for connection activity timestamps
+static CLOCK_ORIGIN: Lazy<Instant> = Lazy::new(Instant::now);
+
+fn now_ms() -> u64 {
+    CLOCK_ORIGIN.elapsed().as_millis() as u64
+}
+
+/// Default interval between two sweeps of the connection reaper
+pub const DEFAULT_REAPER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// NOTICE sent to connections removed for being idle
+pub const IDLE_TIMEOUT_NOTICE: &str = "idle timeout: no activity, subscriptions closed";
+
+/// Seconds over which [`ScopeActivity::events_per_second`] is averaged
+pub const EVENT_RATE_WINDOW_SECS: u64 = 60;
+
+/// Accepted events per second, over the last [`EVENT_RATE_WINDOW_SECS`]
+#[derive(Default)]
+struct EventRate {
+    /// (second since [`CLOCK_ORIGIN`], events), oldest first
+    buckets: Mutex<VecDeque<(u64, u64)>>,
+}
+
+impl EventRate {
+    fn record(&self, now_secs: u64) {
+        let mut buckets = self.buckets.lock();
+        match buckets.back_mut() {
+            Some((second, count)) if *second == now_secs => *count += 1,
+            _ => buckets.push_back((now_secs, 1)),
+        }
+        Self::expire(&mut buckets, now_secs);
+    }
+
+    fn per_second(&self, now_secs: u64) -> f64 {
+        let mut buckets = self.buckets.lock();
+        Self::expire(&mut buckets, now_secs);
+        let events: u64 = buckets.iter().map(|(_, count)| count).sum();
+        events as f64 / EVENT_RATE_WINDOW_SECS as f64
+    }
+
+    fn expire(buckets: &mut VecDeque<(u64, u64)>, now_secs: u64) {
+        while buckets
+            .front()
+            .is_some_and(|(second, _)| second + EVENT_RATE_WINDOW_SECS <= now_secs)
+        {
+            buckets.pop_front();
+        }
+    }
+}
+
+/// Live activity of one scope, see [`SubscriptionRegistry::scope_activity`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ScopeActivity {
+    /// Registered connections
+    pub connections: usize,
+    /// Subscriptions open on those connections
+    pub active_subscriptions: usize,
+    /// Events accepted per second over the last [`EVENT_RATE_WINDOW_SECS`]
+    pub events_per_second: f64,
 }
 
+/// Connections removed by one sweep of the reaper
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReapStats {
+    /// Connections whose channel was full or closed
+    pub dead: usize,
+    /// Connections without client activity for longer than the idle timeout
+    pub idle: usize,
+}
+
+/// How live connections are handled when their scope is renamed or merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeMigration {
+    /// Re-point the connections and their subscriptions to the new scope
+    Migrate,
+    /// Close all subscriptions, send a NOTICE and close the connections
+    Disconnect,
+}
+
+/// NOTICE prefix sent to connections detached by [`ScopeMigration::Disconnect`]
+pub const SCOPE_MOVED_NOTICE_PREFIX: &str = "scope-moved:";
+
 /// Handle for a connection that ensures cleanup on drop
 pub struct ConnectionHandle {
     /// Connection ID
@@ -62,25 +603,7 @@ pub struct ConnectionHandle {
 impl Drop for ConnectionHandle {
     fn drop(&mut self) {
         debug!("Connection {} dropped, removing from registry", self.id);
-
-        // Count subscriptions before removing the connection
-        let subscription_count = if let Some(connection) = self.registry.connections.get(&self.id) {
-            connection.subscriptions.read().len()
-        } else {
-            0
-        };
-
-        self.registry.connections.remove(&self.id);
-
-        if let Some(handler) = &self.registry.metrics_handler {
-            if subscription_count > 0 {
-                handler.decrement_active_subscriptions(subscription_count);
-                debug!(
-                    "Decremented {} subscriptions for dropped connection {}",
-                    subscription_count, self.id
-                );
-            }
-        }
+        self.registry.detach(&self.id, None);
     }
 }
 
@@ -88,8 +611,187 @@ impl SubscriptionRegistry {
     /// Create a new subscription registry
     pub fn new(metrics_handler: Option<Arc<dyn SubscriptionMetricsHandler>>) -> Self {
         Self {
-            connections: Arc::new(DashMap::new()),
+            connections: Arc::new(ConnectionShards::new(1)),
+            workers: None,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
             metrics_handler,
+            event_rates: Arc::new(DashMap::new()),
+            sinks: Vec::new(),
+            connection_hooks: Vec::new(),
+            priority_fn: None,
+            fanout_limit: None,
+            memory: Arc::new(MemoryAccount::new(None)),
+            shadow_bans: None,
+        }
+    }
+
+    /// Tell `hook` about WebSocket connections opening and closing
+    #[must_use]
+    pub fn with_connection_hook(mut self, hook: Arc<dyn ConnectionHook>) -> Self {
+        self.connection_hooks.push(hook);
+        self
+    }
+
+    /// Hand every distributed event to `sink`, after local subscriptions
+    ///
+    /// Sinks are awaited during distribution, so they should only queue the
+    /// event, like [`Upstream`](crate::upstream::Upstream) does.
+    #[must_use]
+    pub fn with_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Only deliver events of authors `shadow_bans` accepts to connections
+    /// authenticated as their author, and never hand them to sinks
+    #[must_use]
+    pub fn with_shadow_bans(mut self, shadow_bans: ShadowBanFn) -> Self {
+        self.shadow_bans = Some(shadow_bans);
+        self
+    }
+
+    /// Split connections into `shard_count` shards that are distributed to in parallel
+    ///
+    /// Each shard gets a worker task, and every event is handed to all workers at
+    /// once, so fan-out during event storms uses more than one thread. Events reach
+    /// a given connection in the order they were distributed. With a single shard
+    /// events are distributed inline on the calling task.
+    ///
+    /// Must be called before any connection is registered, from within a Tokio runtime.
+    #[must_use]
+    pub fn with_distribution_shards(self, shard_count: usize) -> Self {
+        self.distribution_shards(shard_count, None)
+    }
+
+    /// [`Self::with_distribution_shards`], with the workers restarted by
+    /// `supervisor` when distributing panics
+    ///
+    /// The job a worker panicked on counts no matches; the restarted worker
+    /// takes the next one.
+    #[must_use]
+    pub fn with_supervised_distribution_shards(
+        self,
+        shard_count: usize,
+        supervisor: &TaskSupervisor,
+    ) -> Self {
+        self.distribution_shards(shard_count, Some(supervisor))
+    }
+
+    fn distribution_shards(
+        mut self,
+        shard_count: usize,
+        supervisor: Option<&TaskSupervisor>,
+    ) -> Self {
+        let connections = Arc::new(ConnectionShards::new(shard_count));
+
+        self.workers = if connections.shards.len() > 1 {
+            let workers = (0..connections.shards.len())
+                .map(|index| {
+                    let (tx, rx) = flume::unbounded::<DistributionJob>();
+                    let connections = Arc::clone(&connections);
+                    let worker = move || {
+                        Self::run_distribution_worker(Arc::clone(&connections), index, rx.clone())
+                    };
+                    match supervisor {
+                        // Returns, and so isn't restarted, once the senders are gone
+                        Some(supervisor) => supervisor.spawn(
+                            None,
+                            CancellationToken::new(),
+                            format!("distribution_worker_{index}"),
+                            worker,
+                        ),
+                        None => {
+                            tokio::spawn(worker());
+                        }
+                    }
+                    tx
+                })
+                .collect();
+            Some(Arc::new(workers))
+        } else {
+            None
+        };
+        self.connections = connections;
+        self
+    }
+
+    async fn run_distribution_worker(
+        connections: Arc<ConnectionShards>,
+        index: usize,
+        rx: flume::Receiver<DistributionJob>,
+    ) {
+        // Exits once every registry clone (and so every sender) is gone
+        while let Ok(job) = rx.recv_async().await {
+            let _entered = job.span.enter();
+            let matches = distribute_to_shard(
+                &connections.shards[index],
+                &job.events,
+                &job.scope,
+                job.policy,
+                job.fanout_limit,
+            );
+            let _ = job.done.send(matches);
+        }
+    }
+
+    /// Choose how distribution treats connections whose outbound channel is full
+    #[must_use]
+    pub fn with_slow_consumer_policy(mut self, policy: SlowConsumerPolicy) -> Self {
+        self.slow_consumer_policy = policy;
+        self
+    }
+
+    /// Assign connections their priority with `priority_fn`
+    ///
+    /// Called when a connection registers and again when its client
+    /// authenticates, e.g. to rank authenticated users above anonymous ones or
+    /// paying members above free users. Connections default to
+    /// [`SubscriptionPriority::Normal`].
+    #[must_use]
+    pub fn with_priorities(mut self, priority_fn: PriorityFn) -> Self {
+        self.priority_fn = Some(priority_fn);
+        self
+    }
+
+    /// Deliver each event to at most `limit` subscriptions per shard before
+    /// throttling lower priorities
+    ///
+    /// High priority subscriptions always get the event; normal and then low
+    /// priority ones get it while fewer than `limit` subscriptions matched.
+    #[must_use]
+    pub fn with_fanout_limit(mut self, limit: usize) -> Self {
+        self.fanout_limit = Some(limit);
+        self
+    }
+
+    /// Refuse subscriptions once filters and queued events take an estimated
+    /// `bytes` of memory
+    ///
+    /// Refused REQs get `CLOSED: rate-limited:`. See [`crate::memory`] for how
+    /// sizes are estimated. Must be called before any connection is registered.
+    #[must_use]
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory = Arc::new(MemoryAccount::new(Some(bytes)));
+        self
+    }
+
+    /// Estimated memory held by subscription filters and queued events
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory.usage()
+    }
+
+    /// Refuse `filters` if storing them would exceed the memory limit
+    ///
+    /// [`Self::add_subscription`] checks again, this lets callers refuse a REQ
+    /// before querying its stored events.
+    pub fn check_memory(&self, filters: &[Filter]) -> Result<(), Error> {
+        self.memory.check(filters_size(filters))
+    }
+
+    /// Override the priority of a connection until its client authenticates
+    pub fn set_priority(&self, connection_id: &str, priority: SubscriptionPriority) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            *connection.priority.write() = priority;
         }
     }
 
@@ -101,11 +803,28 @@ impl SubscriptionRegistry {
         auth_pubkey: Option<PublicKey>,
         subdomain: Arc<Scope>,
     ) -> ConnectionHandle {
+        let priority = self
+            .priority_fn
+            .as_ref()
+            .map_or(SubscriptionPriority::default(), |priority_fn| {
+                priority_fn(auth_pubkey.as_ref(), &subdomain)
+            });
         let connection_data = Arc::new(ConnectionSubscriptions {
             subscriptions: RwLock::new(HashMap::new()),
             sender,
-            auth_pubkey,
-            subdomain,
+            auth_pubkey: RwLock::new(auth_pubkey),
+            visibility: RwLock::new(None),
+            subdomain: RwLock::new(subdomain),
+            priority: RwLock::new(priority),
+            last_activity_ms: AtomicU64::new(now_ms()),
+            dead: AtomicBool::new(false),
+            announced: AtomicBool::new(false),
+            connection_token: RwLock::new(None),
+            backpressure: Mutex::new(Backpressure::default()),
+            counters: ConnectionCounters::new(),
+            memory: Arc::clone(&self.memory),
+            filter_bytes: AtomicUsize::new(0),
+            pending_bytes: AtomicUsize::new(0),
         });
 
         self.connections
@@ -129,11 +848,25 @@ impl SubscriptionRegistry {
             .get(connection_id)
             .ok_or_else(|| Error::internal("Connection not found"))?;
 
+        let bytes = filters_size(&filters);
         let mut subscriptions = connection.subscriptions.write();
+        let replaced = subscriptions
+            .get(&subscription_id)
+            .map_or(0, |filters| filters_size(filters));
+        self.memory.check(bytes.saturating_sub(replaced))?;
+
+        connection.touch();
+        connection
+            .counters
+            .subscriptions_opened
+            .fetch_add(1, Ordering::Relaxed);
         subscriptions.insert(subscription_id.clone(), filters);
+        connection.hold_filters(bytes);
+        connection.release_filters(replaced);
 
         if let Some(handler) = &self.metrics_handler {
             handler.increment_active_subscriptions();
+            handler.record_subscription_memory(&self.memory.usage());
         }
 
         debug!(
@@ -155,7 +888,9 @@ impl SubscriptionRegistry {
             .ok_or_else(|| Error::internal("Connection not found"))?;
 
         let mut subscriptions = connection.subscriptions.write();
-        if subscriptions.remove(subscription_id).is_some() {
+        if let Some(filters) = subscriptions.remove(subscription_id) {
+            connection.release_filters(filters_size(&filters));
+            connection.forget_subscription(subscription_id);
             if let Some(handler) = &self.metrics_handler {
                 handler.decrement_active_subscriptions(1);
             }
@@ -168,6 +903,299 @@ impl SubscriptionRegistry {
         Ok(())
     }
 
+    /// Record the pubkey a connection's client authenticated as, and reassign its priority
+    pub fn set_auth_pubkey(&self, connection_id: &str, pubkey: PublicKey) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            *connection.auth_pubkey.write() = Some(pubkey);
+            if let Some(priority_fn) = &self.priority_fn {
+                let scope = Arc::clone(&connection.subdomain.read());
+                *connection.priority.write() = priority_fn(Some(&pubkey), &scope);
+            }
+        }
+    }
+
+    /// Only send live events to a connection when `visibility` accepts them
+    ///
+    /// Subscription filters only say what a client asked for; `visibility`
+    /// decides what it may see, e.g. hiding private group events from
+    /// non-members. It is called at most once per distributed event and
+    /// connection, with the connection's current authenticated pubkey.
+    pub fn set_visibility(&self, connection_id: &str, visibility: Option<VisibilityFn>) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            *connection.visibility.write() = visibility;
+        }
+    }
+
+    /// Record client activity on a connection, postponing its idle timeout
+    pub fn touch(&self, connection_id: &str) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            connection.touch();
+        }
+    }
+
+    /// Close a connection's socket by cancelling `token` when the registry
+    /// detaches it, e.g. when it is reaped, shed or drained
+    pub fn set_connection_token(&self, connection_id: &str, token: CancellationToken) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            *connection.connection_token.write() = Some(token);
+        }
+    }
+
+    /// Record the address a connection's client connected from
+    pub fn set_remote_address(&self, connection_id: &str, remote_address: String) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            *connection.counters.remote_address.write() = Some(remote_address);
+        }
+    }
+
+    /// Record the `User-Agent` a connection's client sent
+    pub fn set_user_agent(&self, connection_id: &str, user_agent: String) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            *connection.counters.user_agent.write() = Some(user_agent);
+        }
+    }
+
+    /// Run the `on_connect` connection hooks for a registered WebSocket connection
+    ///
+    /// Stops at the first hook refusing the connection and returns its reason.
+    /// Only announced connections are reported to `on_disconnect`.
+    pub(crate) fn announce_connection(&self, connection_id: &str) -> Result<(), String> {
+        let Some(connection) = self.connections.get(connection_id).map(|c| Arc::clone(&c)) else {
+            return Ok(());
+        };
+        connection.announced.store(true, Ordering::Relaxed);
+
+        if self.connection_hooks.is_empty() {
+            return Ok(());
+        }
+        let stats = connection.stats(connection_id);
+        self.connection_hooks
+            .iter()
+            .try_for_each(|hook| hook.on_connect(&stats))
+    }
+
+    /// Count a client message of `bytes` bytes
+    pub fn record_inbound(&self, connection_id: &str, bytes: usize) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            let counters = &connection.counters;
+            counters.messages_in.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Count `messages` messages totalling `bytes` bytes sent to a connection
+    pub fn record_outbound(&self, connection_id: &str, messages: usize, bytes: usize) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            let counters = &connection.counters;
+            counters
+                .messages_out
+                .fetch_add(messages as u64, Ordering::Relaxed);
+            counters
+                .bytes_out
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a client event as stored or refused
+    pub fn record_event_result(&self, connection_id: &str, accepted: bool) {
+        if let Some(connection) = self.connections.get(connection_id) {
+            let counter = if accepted {
+                &connection.counters.events_accepted
+            } else {
+                &connection.counters.events_rejected
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+
+            if accepted {
+                let scope = Scope::clone(&connection.subdomain.read());
+                self.event_rates
+                    .entry(scope)
+                    .or_default()
+                    .record(now_ms() / 1000);
+            }
+        }
+    }
+
+    /// Connections, subscriptions and event rate of every scope that has
+    /// connections or accepted events recently
+    pub fn scope_activity(&self) -> HashMap<Scope, ScopeActivity> {
+        let mut activity: HashMap<Scope, ScopeActivity> = HashMap::new();
+
+        for entry in self.connections.iter() {
+            let connection = entry.value();
+            let scope = Scope::clone(&connection.subdomain.read());
+            let scope_activity = activity.entry(scope).or_default();
+            scope_activity.connections += 1;
+            scope_activity.active_subscriptions += connection.subscriptions.read().len();
+        }
+
+        let now_secs = now_ms() / 1000;
+        for entry in self.event_rates.iter() {
+            let events_per_second = entry.value().per_second(now_secs);
+            if events_per_second > 0.0 {
+                activity
+                    .entry(entry.key().clone())
+                    .or_default()
+                    .events_per_second = events_per_second;
+            }
+        }
+
+        activity
+    }
+
+    /// Statistics of one connection
+    pub fn connection_stats(&self, connection_id: &str) -> Option<ConnectionStats> {
+        self.connections
+            .get(connection_id)
+            .map(|connection| connection.stats(connection_id))
+    }
+
+    /// Subscriptions of a connection with their filters
+    pub fn subscriptions(&self, connection_id: &str) -> Option<Vec<(SubscriptionId, Vec<Filter>)>> {
+        self.connections.get(connection_id).map(|connection| {
+            connection
+                .subscriptions
+                .read()
+                .iter()
+                .map(|(sub_id, filters)| (sub_id.clone(), filters.clone()))
+                .collect()
+        })
+    }
+
+    /// Statistics of every registered connection
+    pub fn connections_snapshot(&self) -> Vec<ConnectionStats> {
+        self.connections
+            .iter()
+            .map(|entry| entry.value().stats(entry.key()))
+            .collect()
+    }
+
+    /// Remove dead connections, and connections idle for longer than `idle_timeout`
+    ///
+    /// A connection is dead once sends to it failed because its channel was full
+    /// or closed, see [`SlowConsumerPolicy`]; it gets a best-effort
+    /// [`SLOW_CONSUMER_NOTICE`]. Events queued for live connections are retried.
+    /// Idle connections get a CLOSED for every subscription and an
+    /// [`IDLE_TIMEOUT_NOTICE`]. Reaped connections are removed from the registry
+    /// and their socket is closed, see [`Self::set_connection_token`].
+    pub fn reap(&self, idle_timeout: Option<Duration>) -> ReapStats {
+        let mut stats = ReapStats::default();
+        let mut reaped = Vec::new();
+
+        for entry in self.connections.iter() {
+            let connection = entry.value();
+
+            if connection.dead.load(Ordering::Relaxed) {
+                let mut sender = connection.sender.clone();
+                let _ = sender.send(RelayMessage::notice(SLOW_CONSUMER_NOTICE));
+                stats.dead += 1;
+                reaped.push((entry.key().clone(), None));
+            } else if idle_timeout.is_some_and(|timeout| connection.idle_for() > timeout) {
+                stats.idle += 1;
+                reaped.push((entry.key().clone(), Some(IDLE_TIMEOUT_NOTICE)));
+            } else {
+                connection.flush_pending();
+            }
+        }
+
+        for (conn_id, reason) in reaped {
+            self.detach(&conn_id, reason);
+        }
+
+        let now_secs = now_ms() / 1000;
+        self.event_rates
+            .retain(|_, rate| rate.per_second(now_secs) > 0.0);
+
+        if stats != ReapStats::default() {
+            debug!(
+                "Reaped {} dead and {} idle connections",
+                stats.dead, stats.idle
+            );
+            if let Some(handler) = &self.metrics_handler {
+                handler.record_reaped_connections(stats.dead, stats.idle);
+            }
+        }
+        if let Some(handler) = &self.metrics_handler {
+            handler.record_subscription_memory(&self.memory.usage());
+        }
+
+        stats
+    }
+
+    /// Spawn a background task that [`reap`](Self::reap)s connections every `interval`
+    ///
+    /// The task stops when `cancellation_token` fires or the registry is dropped.
+    pub fn spawn_reaper(
+        self: &Arc<Self>,
+        task_tracker: &TaskTracker,
+        interval: Duration,
+        idle_timeout: Option<Duration>,
+        cancellation_token: Option<CancellationToken>,
+    ) {
+        let registry = Arc::downgrade(self);
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(Self::run_reaper(
+            registry,
+            interval,
+            idle_timeout,
+            cancellation_token,
+        ));
+    }
+
+    /// [`Self::spawn_reaper`], restarted by `supervisor` when reaping panics
+    pub(crate) fn supervise_reaper(
+        self: &Arc<Self>,
+        supervisor: &TaskSupervisor,
+        task_tracker: &TaskTracker,
+        interval: Duration,
+        idle_timeout: Option<Duration>,
+        cancellation_token: Option<CancellationToken>,
+    ) {
+        let registry = Arc::downgrade(self);
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        supervisor.spawn(
+            Some(task_tracker),
+            cancellation_token.clone(),
+            "connection_reaper",
+            move || {
+                Self::run_reaper(
+                    registry.clone(),
+                    interval,
+                    idle_timeout,
+                    cancellation_token.clone(),
+                )
+            },
+        );
+    }
+
+    async fn run_reaper(
+        registry: std::sync::Weak<Self>,
+        interval: Duration,
+        idle_timeout: Option<Duration>,
+        cancellation_token: CancellationToken,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = ticker.tick() => {
+                    let Some(registry) = registry.upgrade() else {
+                        break;
+                    };
+                    registry.reap(idle_timeout);
+                }
+            }
+        }
+
+        debug!("Connection reaper stopped");
+    }
+
     /// Get connection info for REQ processing
     pub fn get_connection_info(
         &self,
@@ -175,80 +1203,524 @@ impl SubscriptionRegistry {
     ) -> Option<(Option<PublicKey>, Arc<Scope>)> {
         self.connections
             .get(connection_id)
-            .map(|conn| (conn.auth_pubkey, Arc::clone(&conn.subdomain)))
+            .map(|conn| (*conn.auth_pubkey.read(), Arc::clone(&conn.subdomain.read())))
+    }
+
+    /// Move all live connections bound to scope `from` over to scope `to`
+    ///
+    /// Used when an admin renames a scope or merges it into another one. With
+    /// [`ScopeMigration::Migrate`] each connection's scope is swapped while its
+    /// subscriptions are locked, so no event is distributed against a half-migrated
+    /// connection; the connection state picks up the new scope on its next message.
+    /// With [`ScopeMigration::Disconnect`] every subscription gets a CLOSED, the
+    /// client gets a NOTICE starting with [`SCOPE_MOVED_NOTICE_PREFIX`] and the
+    /// connection is removed from the registry and closed, so the client
+    /// reconnects rather than sending messages to a detached connection.
+    ///
+    /// Returns the number of affected connections.
+    pub fn migrate_scope(&self, from: &Scope, to: &Scope, migration: ScopeMigration) -> usize {
+        let affected = match migration {
+            ScopeMigration::Migrate => {
+                let mut affected = 0;
+                for entry in self.connections.iter() {
+                    let conn_data = entry.value();
+
+                    // Lock subscriptions first, like distribution does, to make the swap atomic
+                    let _subscriptions = conn_data.subscriptions.write();
+                    if conn_data.subdomain.read().as_ref() == from {
+                        *conn_data.subdomain.write() = Arc::new(to.clone());
+                        affected += 1;
+                    }
+                }
+                affected
+            }
+            ScopeMigration::Disconnect => self.disconnect_scope(
+                from,
+                &format!("{SCOPE_MOVED_NOTICE_PREFIX} scope was moved, please reconnect"),
+            ),
+        };
+
+        debug!(
+            "Scope migration {:?} from {:?} to {:?} affected {} connections",
+            migration, from, to, affected
+        );
+
+        affected
+    }
+
+    /// Detach every live connection bound to `scope` from the registry
+    ///
+    /// Each subscription gets a CLOSED and the client a NOTICE, both carrying
+    /// `reason`. Detached connections are closed, see
+    /// [`Self::set_connection_token`].
+    ///
+    /// Returns the number of detached connections.
+    pub fn disconnect_scope(&self, scope: &Scope, reason: &str) -> usize {
+        let connection_ids: Vec<String> = self
+            .connections
+            .iter()
+            .filter(|entry| entry.value().subdomain.read().as_ref() == scope)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        connection_ids
+            .iter()
+            .filter(|conn_id| self.detach(conn_id, Some(reason)))
+            .count()
+    }
+
+    /// Detach every live connection, like [`Self::disconnect_scope`] for all scopes
+    ///
+    /// Returns the number of detached connections.
+    pub fn disconnect_all(&self, reason: &str) -> usize {
+        let connection_ids: Vec<String> = self
+            .connections
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        connection_ids
+            .iter()
+            .filter(|conn_id| self.detach(conn_id, Some(reason)))
+            .count()
+    }
+
+    /// Live connections in the registry
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Close up to `max` live connections that haven't authenticated, least
+    /// recently active first
+    ///
+    /// Like [`Self::disconnect_scope`], each subscription gets a CLOSED and the
+    /// client a NOTICE carrying `reason`, then the connection is detached and
+    /// its socket closed. Returns the number of closed connections.
+    pub fn shed_anonymous(&self, max: usize, reason: &str) -> usize {
+        let mut anonymous: Vec<(u64, String)> = self
+            .connections
+            .iter()
+            .filter(|entry| entry.value().auth_pubkey.read().is_none())
+            .map(|entry| {
+                let last_activity = entry.value().last_activity_ms.load(Ordering::Relaxed);
+                (last_activity, entry.key().clone())
+            })
+            .collect();
+        anonymous.sort_unstable();
+        anonymous.truncate(max);
+
+        let shed = anonymous
+            .iter()
+            .filter(|(_, conn_id)| self.detach(conn_id, Some(reason)))
+            .count();
+        if shed > 0 {
+            debug!("Shed {} anonymous connections: {}", shed, reason);
+        }
+        shed
+    }
+
+    /// Events queued for slow connections across the registry, see [`SlowConsumerPolicy`]
+    pub fn queued_events(&self) -> usize {
+        self.connections
+            .iter()
+            .map(|entry| entry.value().backpressure.lock().pending.len())
+            .sum()
+    }
+
+    /// Remove a connection from the registry, returning whether it was there
+    ///
+    /// With a `reason`, each subscription gets a CLOSED and the client a
+    /// NOTICE carrying it. Connections the hooks saw open are reported to
+    /// their `on_disconnect`, whichever path removes them, and the socket is
+    /// closed once the messages are queued.
+    fn detach(&self, connection_id: &str, reason: Option<&str>) -> bool {
+        let Some((_, conn_data)) = self.connections.remove(connection_id) else {
+            return false;
+        };
+
+        {
+            let mut subscriptions = conn_data.subscriptions.write();
+            match reason {
+                Some(reason) => self.close_subscriptions(&conn_data, &mut subscriptions, reason),
+                None => {
+                    if let Some(handler) = &self.metrics_handler {
+                        if !subscriptions.is_empty() {
+                            handler.decrement_active_subscriptions(subscriptions.len());
+                        }
+                    }
+                }
+            }
+        }
+
+        if conn_data.announced.load(Ordering::Relaxed) {
+            let stats = conn_data.stats(connection_id);
+            for hook in &self.connection_hooks {
+                hook.on_disconnect(&stats);
+            }
+        }
+        if let Some(token) = conn_data.connection_token.read().as_ref() {
+            token.cancel();
+        }
+        true
+    }
+
+    /// Send a CLOSED for each of `subscriptions`, then a NOTICE, carrying `reason`
+    fn close_subscriptions(
+        &self,
+        conn_data: &ConnectionSubscriptions,
+        subscriptions: &mut HashMap<SubscriptionId, Vec<Filter>>,
+        reason: &str,
+    ) {
+        let mut sender = conn_data.sender.clone();
+        for (sub_id, filters) in subscriptions.drain() {
+            conn_data.release_filters(filters_size(&filters));
+            let _ = sender.send(RelayMessage::closed(sub_id, reason.to_string()));
+            if let Some(handler) = &self.metrics_handler {
+                handler.decrement_active_subscriptions(1);
+            }
+        }
+        let _ = sender.send(RelayMessage::notice(reason.to_string()));
     }
 }
 
 impl SubscriptionRegistry {
-    /// Inline event distribution without spawn_blocking
-    fn distribute_event_inline(&self, event: Arc<Event>, scope: &Scope) {
+    /// Inline distribution without spawn_blocking, returning the number of matches
+    fn distribute_inline(&self, events: &[Arc<Event>], scope: &Scope) -> usize {
         trace!(
-            "Distributing event {} to subscribers in scope {:?}",
-            event.id,
+            "Distributing {} event(s) to subscribers in scope {:?}",
+            events.len(),
             scope
         );
 
-        let mut total_matches = 0;
-        let mut dead_connections = Vec::new();
+        let total_matches: usize = self
+            .connections
+            .shards
+            .iter()
+            .map(|shard| {
+                distribute_to_shard(
+                    shard,
+                    events,
+                    scope,
+                    self.slow_consumer_policy,
+                    self.fanout_limit,
+                )
+            })
+            .sum();
+
+        if total_matches > 0 {
+            trace!(
+                "{} event(s) matched {} subscriptions",
+                events.len(),
+                total_matches
+            );
+        }
+        total_matches
+    }
 
-        // Synchronous iteration over connections
+    /// Send a shadow-banned author's `event` to the connections authenticated as its author
+    fn distribute_to_author(&self, event: &Arc<Event>, scope: &Scope) -> usize {
+        let mut batch = ShardBatch {
+            events: std::slice::from_ref(event),
+            scope,
+            policy: self.slow_consumer_policy,
+            serialized: vec![None],
+            matches: vec![0],
+        };
         for entry in self.connections.iter() {
-            let conn_id = entry.key();
             let conn_data = entry.value();
-
-            // Skip connections that don't match the event's scope
-            if conn_data.subdomain.as_ref() != scope {
+            if conn_data.dead.load(Ordering::Relaxed)
+                || conn_data.subdomain.read().as_ref() != scope
+                || *conn_data.auth_pubkey.read() != Some(event.pubkey)
+            {
                 continue;
             }
+            batch.deliver(entry.key(), conn_data, None);
+        }
+        batch.matches[0]
+    }
 
-            // Use blocking read - fast since writes are rare
-            let subscriptions = conn_data.subscriptions.read();
-
-            for (sub_id, filters) in subscriptions.iter() {
-                if filters.iter().any(|filter| {
-                    filter.match_event(&event, nostr_sdk::filter::MatchEventOptions::default())
-                }) {
-                    total_matches += 1;
-
-                    let message = RelayMessage::event(
-                        sub_id.clone(),
-                        (*event).clone(), // Clone the event data
-                    );
-
-                    // MessageSender.send() is synchronous and uses try_send internally
-                    let mut sender = conn_data.sender.clone();
-                    if let Err(e) = sender.send(message) {
-                        // Connection is dead, mark for removal
-                        warn!("Failed to send to connection {}: {:?}", conn_id, e);
-                        dead_connections.push(conn_id.clone());
-                        break;
-                    } else {
-                        trace!(
-                            "Sent event to subscription {} on connection {}",
-                            sub_id,
-                            conn_id
-                        );
-                    }
-                }
+    /// Hand the events to every shard worker and wait until all of them are done
+    async fn distribute_sharded(
+        &self,
+        workers: &[flume::Sender<DistributionJob>],
+        events: Arc<[Arc<Event>]>,
+        scope: &Scope,
+    ) -> usize {
+        trace!(
+            "Distributing {} event(s) to {} shards in scope {:?}",
+            events.len(),
+            workers.len(),
+            scope
+        );
+
+        let mut pending = Vec::with_capacity(workers.len());
+        for worker in workers {
+            let (done, rx) = tokio::sync::oneshot::channel();
+            let job = DistributionJob {
+                events: Arc::clone(&events),
+                scope: scope.clone(),
+                policy: self.slow_consumer_policy,
+                fanout_limit: self.fanout_limit,
+                span: Span::current(),
+                done,
+            };
+            if worker.send(job).is_ok() {
+                pending.push(rx);
             }
         }
 
-        // Clean up dead connections
-        for conn_id in dead_connections {
-            self.connections.remove(&conn_id);
+        let mut total_matches = 0;
+        for rx in pending {
+            total_matches += rx.await.unwrap_or(0);
         }
 
         if total_matches > 0 {
-            trace!("Event {} matched {} subscriptions", event.id, total_matches);
+            trace!(
+                "{} event(s) matched {} subscriptions",
+                events.len(),
+                total_matches
+            );
+        }
+        total_matches
+    }
+
+    /// Distribute `events` to local subscriptions, then hand them to the sinks
+    async fn distribute(&self, events: Vec<Arc<Event>>, scope: &Scope) {
+        let (events, shadowed): (Vec<_>, Vec<_>) = match &self.shadow_bans {
+            Some(is_shadow_banned) => events
+                .into_iter()
+                .partition(|event| !is_shadow_banned(&event.pubkey)),
+            None => (events, Vec::new()),
+        };
+        let mut matches: usize = shadowed
+            .iter()
+            .map(|event| self.distribute_to_author(event, scope))
+            .sum();
+        if events.is_empty() {
+            if !shadowed.is_empty() {
+                if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+                    metrics.record_distribution_matches(matches);
+                }
+            }
+            return;
+        }
+
+        // Only traced under a sampled connection or save span
+        let span = if Span::current().is_disabled() {
+            Span::none()
+        } else {
+            tracing::debug_span!(
+                "distribute",
+                event_id = %events[0].id,
+                kind = events[0].kind.as_u16(),
+                events = events.len(),
+            )
+        };
+
+        let events: Arc<[Arc<Event>]> = events.into();
+        matches += async {
+            match &self.workers {
+                Some(workers) => {
+                    self.distribute_sharded(workers, Arc::clone(&events), scope)
+                        .await
+                }
+                // Distribute inline without spawn_blocking
+                None => self.distribute_inline(&events, scope),
+            }
+        }
+        .instrument(span)
+        .await;
+
+        for event in events.iter() {
+            for sink in &self.sinks {
+                sink.on_event_stored(Arc::clone(event), scope).await;
+            }
+        }
+
+        if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+            metrics.record_distribution_matches(matches);
+        }
+    }
+}
+
+/// Send the events to matching subscriptions of one shard, returning the number of matches
+///
+/// Each connection is visited once for the whole burst and gets its frames
+/// back to back. Full channels are handled according to `policy`; connections
+/// given up on are only flagged here and removed by
+/// [`SubscriptionRegistry::reap`]. With a `fanout_limit`, high priority
+/// connections are served first and the others only while an event's limit
+/// isn't reached, normal before low priority.
+fn distribute_to_shard(
+    shard: &ConnectionMap,
+    events: &[Arc<Event>],
+    scope: &Scope,
+    policy: SlowConsumerPolicy,
+    fanout_limit: Option<usize>,
+) -> usize {
+    let mut batch = ShardBatch {
+        events,
+        scope,
+        policy,
+        // Serialized lazily on the first match, then shared by every subscription
+        serialized: vec![None; events.len()],
+        matches: vec![0; events.len()],
+    };
+    // Matching connections waiting for what the fan-out limit leaves
+    let mut normal = Vec::new();
+    let mut low = Vec::new();
+
+    // Synchronous iteration over connections
+    for entry in shard.iter() {
+        let conn_id = entry.key();
+        let conn_data = entry.value();
+
+        // Skip connections that failed before, the reaper removes them
+        if conn_data.dead.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        // Skip connections that don't match the event's scope
+        if conn_data.subdomain.read().as_ref() != scope {
+            continue;
+        }
+
+        let priority = *conn_data.priority.read();
+        if fanout_limit.is_some() && priority < SubscriptionPriority::High {
+            if events.iter().any(|event| conn_data.matches(event)) {
+                let deferred = if priority == SubscriptionPriority::Low {
+                    &mut low
+                } else {
+                    &mut normal
+                };
+                deferred.push((conn_id.clone(), Arc::clone(conn_data)));
+            }
+            continue;
+        }
+
+        batch.deliver(conn_id, conn_data, None);
+    }
+
+    if fanout_limit.is_some() {
+        let mut throttled = 0;
+        for (conn_id, conn_data) in normal.iter().chain(low.iter()) {
+            throttled += batch.deliver(conn_id, conn_data, fanout_limit);
+        }
+        if throttled > 0 {
+            debug!(
+                "Fan-out limit reached, {} event deliveries withheld",
+                throttled
+            );
+        }
+    }
+
+    batch.matches.iter().sum()
+}
+
+/// Events being distributed to one shard and what they matched so far
+struct ShardBatch<'a> {
+    events: &'a [Arc<Event>],
+    scope: &'a Scope,
+    policy: SlowConsumerPolicy,
+    serialized: Vec<Option<SerializedEvent>>,
+    /// Subscriptions matched per event
+    matches: Vec<usize>,
+}
+
+impl ShardBatch<'_> {
+    /// Send the events to the matching subscriptions of one connection, skipping
+    /// events that already matched `fanout_limit` subscriptions
+    ///
+    /// Returns the number of events withheld because of the limit.
+    fn deliver(
+        &mut self,
+        conn_id: &str,
+        conn_data: &ConnectionSubscriptions,
+        fanout_limit: Option<usize>,
+    ) -> usize {
+        // Low priority connections are the first to go when they fall behind
+        let policy = if *conn_data.priority.read() == SubscriptionPriority::Low {
+            SlowConsumerPolicy::Disconnect
+        } else {
+            self.policy
+        };
+
+        // Use blocking read - fast since writes are rare
+        let subscriptions = conn_data.subscriptions.read();
+
+        if matches!(policy, SlowConsumerPolicy::DropOldest { .. }) {
+            conn_data.flush_pending();
+        }
+
+        let events = self.events;
+        let mut throttled = 0;
+        let mut sub_ids = Vec::new();
+        for (index, event) in events.iter().enumerate() {
+            sub_ids.clear();
+            sub_ids.extend(
+                subscriptions
+                    .iter()
+                    .filter(|(_, filters)| {
+                        filters.iter().any(|filter| {
+                            filter
+                                .match_event(event, nostr_sdk::filter::MatchEventOptions::default())
+                        })
+                    })
+                    .map(|(sub_id, _)| sub_id),
+            );
+            if sub_ids.is_empty() {
+                continue;
+            }
+            if fanout_limit.is_some_and(|limit| self.matches[index] >= limit) {
+                throttled += 1;
+                continue;
+            }
+
+            let visible = conn_data
+                .visibility
+                .read()
+                .as_ref()
+                .is_none_or(|visibility| {
+                    visibility(event, self.scope, conn_data.auth_pubkey.read().as_ref())
+                });
+            if !visible {
+                trace!("Event {} hidden from connection {}", event.id, conn_id);
+                continue;
+            }
+            self.matches[index] += sub_ids.len();
+
+            let serialized =
+                self.serialized[index].get_or_insert_with(|| SerializedEvent::new(event));
+
+            if !conn_data.deliver_batch(policy, &sub_ids, event, serialized) {
+                warn!("Connection {} is not keeping up, marked dead", conn_id);
+                if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+                    metrics.increment_send_failures();
+                }
+                break;
+            }
+            trace!(
+                "Handed event {} to {} subscription(s) on connection {}",
+                event.id,
+                sub_ids.len(),
+                conn_id
+            );
         }
+
+        throttled
     }
 }
 
 #[async_trait::async_trait]
 impl EventDistributor for SubscriptionRegistry {
     async fn distribute_event(&self, event: Arc<Event>, scope: &Scope) {
-        // Distribute inline without spawn_blocking
-        self.distribute_event_inline(event, scope);
+        self.distribute(vec![event], scope).await;
+    }
+
+    /// Distribute a burst in a single pass over the connections, so each
+    /// connection gets its frames for the whole burst back to back
+    async fn distribute_events(&self, events: Vec<Arc<Event>>, scope: &Scope) {
+        self.distribute(events, scope).await;
     }
 }
 
@@ -282,6 +1754,91 @@ mod tests {
         assert!(!registry.connections.contains_key("conn1"));
     }
 
+    #[derive(Debug, Default)]
+    struct BanningHook {
+        disconnected: Mutex<Vec<(String, Option<String>)>>,
+    }
+
+    impl ConnectionHook for BanningHook {
+        fn on_connect(&self, connection: &ConnectionStats) -> Result<(), String> {
+            match connection.remote_address.as_deref() {
+                Some("10.0.0.1:1234") => Err("blocked: banned address".to_string()),
+                _ => Ok(()),
+            }
+        }
+
+        fn on_disconnect(&self, connection: &ConnectionStats) {
+            self.disconnected.lock().push((
+                connection.connection_id.clone(),
+                connection.user_agent.clone(),
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_hooks() {
+        let hook = Arc::new(BanningHook::default());
+        let registry = Arc::new(
+            SubscriptionRegistry::new(None)
+                .with_connection_hook(Arc::clone(&hook) as Arc<dyn ConnectionHook>),
+        );
+        let (tx, _rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+
+        {
+            let _handle = registry.register_connection(
+                "conn1".to_string(),
+                MessageSender::new(tx.clone(), 0),
+                None,
+                Arc::new(Scope::Default),
+            );
+            registry.set_remote_address("conn1", "10.0.0.2:1234".to_string());
+            registry.set_user_agent("conn1", "test-client/1.0".to_string());
+            assert_eq!(registry.announce_connection("conn1"), Ok(()));
+
+            let _banned = registry.register_connection(
+                "conn2".to_string(),
+                MessageSender::new(tx.clone(), 0),
+                None,
+                Arc::new(Scope::Default),
+            );
+            registry.set_remote_address("conn2", "10.0.0.1:1234".to_string());
+            assert_eq!(
+                registry.announce_connection("conn2"),
+                Err("blocked: banned address".to_string())
+            );
+
+            // Connections not announced, e.g. SSE streams, are not reported
+            let _virtual = registry.register_connection(
+                "sse-1".to_string(),
+                MessageSender::new(tx.clone(), 0),
+                None,
+                Arc::new(Scope::Default),
+            );
+        }
+
+        // Detached connections are reported once, not again when their handle drops
+        let detached = registry.register_connection(
+            "conn3".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+        assert_eq!(registry.announce_connection("conn3"), Ok(()));
+        assert_eq!(registry.disconnect_all("restricted: closing"), 1);
+        drop(detached);
+
+        let mut disconnected = hook.disconnected.lock().clone();
+        disconnected.sort();
+        assert_eq!(
+            disconnected,
+            vec![
+                ("conn1".to_string(), Some("test-client/1.0".to_string())),
+                ("conn2".to_string(), None),
+                ("conn3".to_string(), None),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_subscription_management() {
         let registry = Arc::new(SubscriptionRegistry::new(None));
@@ -505,4 +2062,616 @@ mod tests {
             panic!("Expected Event message for tenant2");
         }
     }
+
+    #[tokio::test]
+    async fn test_migrate_scope_repoints_subscriptions() {
+        use nostr_sdk::{EventBuilder, Keys};
+        use std::time::Instant;
+
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let old_scope = Scope::named("old").unwrap();
+        let new_scope = Scope::named("new").unwrap();
+
+        let (tx, rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _handle = registry.register_connection(
+            "conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(old_scope.clone()),
+        );
+        registry
+            .add_subscription("conn", SubscriptionId::new("sub"), vec![Filter::new()])
+            .unwrap();
+
+        let migrated = registry.migrate_scope(&old_scope, &new_scope, ScopeMigration::Migrate);
+        assert_eq!(migrated, 1);
+
+        let (_, scope) = registry.get_connection_info("conn").unwrap();
+        assert_eq!(scope.as_ref(), &new_scope);
+
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("after migration")
+            .build_with_ctx(&Instant::now(), keys.public_key())
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        // Events in the old scope no longer reach the connection
+        registry
+            .distribute_event(Arc::new(event.clone()), &old_scope)
+            .await;
+        assert!(rx.try_recv().is_err());
+
+        // Events in the new scope do, on the same subscription
+        registry
+            .distribute_event(Arc::new(event.clone()), &new_scope)
+            .await;
+        match rx.try_recv() {
+            Ok((
+                RelayMessage::Event {
+                    event: received, ..
+                },
+                _,
+            )) => {
+                assert_eq!(received.id, event.id)
+            }
+            other => panic!("Expected Event message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrate_scope_disconnect() {
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let old_scope = Scope::named("old").unwrap();
+
+        let (tx, rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _handle = registry.register_connection(
+            "conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(old_scope.clone()),
+        );
+        let connection_token = CancellationToken::new();
+        registry.set_connection_token("conn", connection_token.clone());
+        registry
+            .add_subscription("conn", SubscriptionId::new("sub"), vec![Filter::new()])
+            .unwrap();
+
+        let affected =
+            registry.migrate_scope(&old_scope, &Scope::Default, ScopeMigration::Disconnect);
+        assert_eq!(affected, 1);
+        assert!(!registry.connections.contains_key("conn"));
+        assert!(connection_token.is_cancelled());
+
+        match rx.try_recv() {
+            Ok((RelayMessage::Closed { message, .. }, _)) => {
+                assert!(message.starts_with(SCOPE_MOVED_NOTICE_PREFIX))
+            }
+            other => panic!("Expected CLOSED message, got {other:?}"),
+        }
+        match rx.try_recv() {
+            Ok((RelayMessage::Notice(message), _)) => {
+                assert!(message.starts_with(SCOPE_MOVED_NOTICE_PREFIX))
+            }
+            other => panic!("Expected NOTICE message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sharded_distribution_reaches_all_shards() {
+        let registry = Arc::new(SubscriptionRegistry::new(None).with_distribution_shards(4));
+        let tenant = Scope::named("tenant").unwrap();
+
+        let mut handles = Vec::new();
+        let mut receivers = Vec::new();
+        for i in 0..16 {
+            let (tx, rx) = flume::bounded::<(RelayMessage<'static>, usize)>(10);
+            let conn_id = format!("conn{i}");
+            // Half of the connections live in another scope
+            let scope = if i % 2 == 0 {
+                Scope::Default
+            } else {
+                tenant.clone()
+            };
+            handles.push(registry.register_connection(
+                conn_id.clone(),
+                MessageSender::new(tx, 0),
+                None,
+                Arc::new(scope),
+            ));
+            registry
+                .add_subscription(&conn_id, SubscriptionId::new("sub"), vec![Filter::new()])
+                .unwrap();
+            receivers.push(rx);
+        }
+
+        let keys = Keys::generate();
+        let event = EventBuilder::text_note("storm")
+            .sign_with_keys(&keys)
+            .unwrap();
+        registry
+            .distribute_event(Arc::new(event.clone()), &Scope::Default)
+            .await;
+
+        // Distribution has completed on every shard once the call returns
+        for (i, rx) in receivers.iter().enumerate() {
+            match rx.try_recv() {
+                Ok((
+                    RelayMessage::Event {
+                        event: received, ..
+                    },
+                    _,
+                )) => {
+                    assert_eq!(i % 2, 0, "conn{i} is outside the event's scope");
+                    assert_eq!(received.id, event.id);
+                }
+                Ok(other) => panic!("unexpected message {other:?}"),
+                Err(_) => assert_eq!(i % 2, 1, "conn{i} missed the event"),
+            }
+        }
+
+        drop(handles);
+        assert_eq!(registry.connections.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reap_dead_and_idle_connections() {
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+
+        // Channel is full after one message
+        let (dead_tx, _dead_rx) = flume::bounded::<(RelayMessage<'static>, usize)>(1);
+        let (idle_tx, idle_rx) = flume::bounded::<(RelayMessage<'static>, usize)>(10);
+        let (active_tx, _active_rx) = flume::bounded::<(RelayMessage<'static>, usize)>(10);
+
+        let mut handles = Vec::new();
+        let mut tokens = HashMap::new();
+        for (conn_id, tx) in [("dead", dead_tx), ("idle", idle_tx), ("active", active_tx)] {
+            handles.push(registry.register_connection(
+                conn_id.to_string(),
+                MessageSender::new(tx, 0),
+                None,
+                Arc::new(Scope::Default),
+            ));
+            let token = CancellationToken::new();
+            registry.set_connection_token(conn_id, token.clone());
+            tokens.insert(conn_id, token);
+        }
+        registry
+            .add_subscription("idle", SubscriptionId::new("sub"), vec![Filter::new()])
+            .unwrap();
+        registry
+            .add_subscription("dead", SubscriptionId::new("sub"), vec![Filter::new()])
+            .unwrap();
+
+        // Fill the dead connection's channel so distribution fails on it
+        let keys = Keys::generate();
+        for content in ["first", "second"] {
+            let event = EventBuilder::text_note(content)
+                .sign_with_keys(&keys)
+                .unwrap();
+            registry
+                .distribute_event(Arc::new(event), &Scope::Default)
+                .await;
+        }
+        let _ = idle_rx.drain().count();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        registry.touch("active");
+
+        let stats = registry.reap(Some(Duration::from_millis(20)));
+        assert_eq!(stats, ReapStats { dead: 1, idle: 1 });
+        assert!(!registry.connections.contains_key("dead"));
+        assert!(!registry.connections.contains_key("idle"));
+        assert!(registry.connections.contains_key("active"));
+
+        // Reaped sockets are closed
+        assert!(tokens["dead"].is_cancelled());
+        assert!(tokens["idle"].is_cancelled());
+        assert!(!tokens["active"].is_cancelled());
+
+        match idle_rx.try_recv() {
+            Ok((RelayMessage::Closed { message, .. }, _)) => {
+                assert_eq!(message, IDLE_TIMEOUT_NOTICE)
+            }
+            other => panic!("Expected CLOSED message, got {other:?}"),
+        }
+
+        // Without an idle timeout only dead connections are reaped
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(registry.reap(None), ReapStats::default());
+        assert!(registry.connections.contains_key("active"));
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_policy_keeps_newest_events() {
+        let registry = Arc::new(
+            SubscriptionRegistry::new(None)
+                .with_slow_consumer_policy(SlowConsumerPolicy::DropOldest { capacity: 1 }),
+        );
+
+        let (tx, rx) = flume::bounded::<(RelayMessage<'static>, usize)>(1);
+        let _handle = registry.register_connection(
+            "slow".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+        registry
+            .add_subscription("slow", SubscriptionId::new("sub"), vec![Filter::new()])
+            .unwrap();
+
+        let keys = Keys::generate();
+        let mut events = Vec::new();
+        for content in ["first", "second", "third"] {
+            let event = EventBuilder::text_note(content)
+                .sign_with_keys(&keys)
+                .unwrap();
+            registry
+                .distribute_event(Arc::new(event.clone()), &Scope::Default)
+                .await;
+            events.push(event);
+        }
+
+        let received_id = |rx: &flume::Receiver<(RelayMessage<'static>, usize)>| match rx.try_recv()
+        {
+            Ok((RelayMessage::Event { event, .. }, _)) => event.id,
+            other => panic!("Expected EVENT message, got {other:?}"),
+        };
+
+        // "second" was dropped to make room for "third"
+        assert_eq!(received_id(&rx), events[0].id);
+        assert_eq!(registry.reap(None), ReapStats::default());
+        assert_eq!(received_id(&rx), events[2].id);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close_after_policy_tolerates_failures() {
+        let registry = Arc::new(
+            SubscriptionRegistry::new(None)
+                .with_slow_consumer_policy(SlowConsumerPolicy::CloseAfter { max_failures: 2 }),
+        );
+
+        let (tx, _rx) = flume::bounded::<(RelayMessage<'static>, usize)>(1);
+        let _handle = registry.register_connection(
+            "slow".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+        registry
+            .add_subscription("slow", SubscriptionId::new("sub"), vec![Filter::new()])
+            .unwrap();
+
+        let keys = Keys::generate();
+        for (i, content) in ["first", "second", "third"].into_iter().enumerate() {
+            let event = EventBuilder::text_note(content)
+                .sign_with_keys(&keys)
+                .unwrap();
+            registry
+                .distribute_event(Arc::new(event), &Scope::Default)
+                .await;
+
+            // The first send fills the channel, the second is the first failure
+            let expected = if i < 2 {
+                ReapStats::default()
+            } else {
+                ReapStats { dead: 1, idle: 0 }
+            };
+            assert_eq!(registry.reap(None), expected);
+        }
+        assert!(!registry.connections.contains_key("slow"));
+    }
+
+    #[test]
+    fn test_connection_stats() {
+        let registry = SubscriptionRegistry::new(None);
+        let (tx, _rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _handle = registry.register_connection(
+            "conn1".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+
+        registry.set_remote_address("conn1", "203.0.113.7".to_string());
+        registry.record_inbound("conn1", 120);
+        registry.record_inbound("conn1", 30);
+        registry.record_outbound("conn1", 2, 64);
+        registry.record_event_result("conn1", true);
+        registry.record_event_result("conn1", false);
+        registry
+            .add_subscription("conn1", SubscriptionId::new("sub1"), vec![Filter::new()])
+            .unwrap();
+        registry
+            .remove_subscription("conn1", &SubscriptionId::new("sub1"))
+            .unwrap();
+
+        let stats = registry.connection_stats("conn1").unwrap();
+        assert_eq!(stats.remote_address.as_deref(), Some("203.0.113.7"));
+        assert_eq!((stats.messages_in, stats.bytes_in), (2, 150));
+        assert_eq!((stats.messages_out, stats.bytes_out), (2, 64));
+        assert_eq!((stats.events_accepted, stats.events_rejected), (1, 1));
+        assert_eq!(stats.subscriptions_opened, 1);
+        assert_eq!(stats.active_subscriptions, 0);
+
+        assert_eq!(registry.connections_snapshot(), vec![stats]);
+        assert!(registry.connection_stats("unknown").is_none());
+    }
+
+    #[test]
+    fn test_scope_activity() {
+        let registry = SubscriptionRegistry::new(None);
+        let alice = Scope::named("alice").unwrap();
+        let (tx, _rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _handles: Vec<_> = ["conn1", "conn2"]
+            .into_iter()
+            .map(|connection_id| {
+                registry.register_connection(
+                    connection_id.to_string(),
+                    MessageSender::new(tx.clone(), 0),
+                    None,
+                    Arc::new(alice.clone()),
+                )
+            })
+            .collect();
+        let _default = registry.register_connection(
+            "conn3".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+
+        registry
+            .add_subscription("conn1", SubscriptionId::new("sub1"), vec![Filter::new()])
+            .unwrap();
+        for _ in 0..3 {
+            registry.record_event_result("conn2", true);
+        }
+        registry.record_event_result("conn3", false);
+
+        let activity = registry.scope_activity();
+        let alice_activity = &activity[&alice];
+        assert_eq!(alice_activity.connections, 2);
+        assert_eq!(alice_activity.active_subscriptions, 1);
+        assert_eq!(
+            alice_activity.events_per_second,
+            3.0 / EVENT_RATE_WINDOW_SECS as f64
+        );
+        assert_eq!(activity[&Scope::Default].connections, 1);
+        assert_eq!(activity[&Scope::Default].events_per_second, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_visibility_hides_live_events() {
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        let (tx, rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _handle = registry.register_connection(
+            "conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+        registry
+            .add_subscription("conn", SubscriptionId::new("a"), vec![Filter::new()])
+            .unwrap();
+        registry
+            .add_subscription("conn", SubscriptionId::new("b"), vec![Filter::new()])
+            .unwrap();
+
+        // Private events are only visible to the member
+        let member = Keys::generate().public_key();
+        registry.set_visibility(
+            "conn",
+            Some(Arc::new(
+                move |event: &Event, _: &Scope, auth_pubkey: Option<&PublicKey>| {
+                    event.content != "private" || auth_pubkey == Some(&member)
+                },
+            )),
+        );
+
+        let keys = Keys::generate();
+        let private = Arc::new(
+            EventBuilder::text_note("private")
+                .sign_with_keys(&keys)
+                .unwrap(),
+        );
+        let public = Arc::new(
+            EventBuilder::text_note("public")
+                .sign_with_keys(&keys)
+                .unwrap(),
+        );
+
+        registry
+            .distribute_event(private.clone(), &Scope::Default)
+            .await;
+        registry.distribute_event(public, &Scope::Default).await;
+        assert_eq!(rx.try_iter().count(), 2);
+
+        registry.set_auth_pubkey("conn", member);
+        registry.distribute_event(private, &Scope::Default).await;
+        assert_eq!(rx.try_iter().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_shadow_banned_events_only_reach_their_author() {
+        let banned = Keys::generate();
+        let banned_pubkey = banned.public_key();
+        let registry = SubscriptionRegistry::new(None)
+            .with_shadow_bans(Arc::new(move |pubkey| *pubkey == banned_pubkey));
+
+        let (author_tx, author_rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _author = registry.register_connection(
+            "author".to_string(),
+            MessageSender::new(author_tx, 0),
+            Some(banned_pubkey),
+            Arc::new(Scope::Default),
+        );
+        let (other_tx, other_rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _other = registry.register_connection(
+            "other".to_string(),
+            MessageSender::new(other_tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+        for conn_id in ["author", "other"] {
+            registry
+                .add_subscription(conn_id, SubscriptionId::new("all"), vec![Filter::new()])
+                .unwrap();
+        }
+
+        let shadowed = Arc::new(
+            EventBuilder::text_note("spam")
+                .sign_with_keys(&banned)
+                .unwrap(),
+        );
+        let public = Arc::new(
+            EventBuilder::text_note("ham")
+                .sign_with_keys(&Keys::generate())
+                .unwrap(),
+        );
+        registry
+            .distribute_events(vec![shadowed, public], &Scope::Default)
+            .await;
+
+        assert_eq!(author_rx.try_iter().count(), 2);
+        assert_eq!(other_rx.try_iter().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fanout_limit_throttles_lower_priorities() {
+        let member = Keys::generate().public_key();
+        let registry = SubscriptionRegistry::new(None)
+            .with_priorities(Arc::new(
+                move |auth_pubkey: Option<&PublicKey>, _: &Scope| match auth_pubkey {
+                    Some(pubkey) if *pubkey == member => SubscriptionPriority::High,
+                    Some(_) => SubscriptionPriority::Normal,
+                    None => SubscriptionPriority::Low,
+                },
+            ))
+            .with_fanout_limit(2);
+
+        let mut receivers = Vec::new();
+        let mut handles = Vec::new();
+        for (id, auth_pubkey) in [
+            ("anonymous", None),
+            ("user", Some(Keys::generate().public_key())),
+            ("member", None),
+        ] {
+            let (tx, rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+            handles.push(registry.register_connection(
+                id.to_string(),
+                MessageSender::new(tx, 0),
+                auth_pubkey,
+                Arc::new(Scope::Default),
+            ));
+            registry
+                .add_subscription(id, SubscriptionId::new("sub"), vec![Filter::new()])
+                .unwrap();
+            receivers.push(rx);
+        }
+        registry.set_auth_pubkey("member", member);
+        assert_eq!(
+            registry.connection_stats("member").unwrap().priority,
+            SubscriptionPriority::High
+        );
+
+        let event = Arc::new(
+            EventBuilder::text_note("busy")
+                .sign_with_keys(&Keys::generate())
+                .unwrap(),
+        );
+        registry.distribute_event(event, &Scope::Default).await;
+
+        let received: Vec<usize> = receivers.iter().map(|rx| rx.try_iter().count()).collect();
+        assert_eq!(received, vec![0, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_burst_is_batched_per_connection() {
+        let registry = SubscriptionRegistry::new(None);
+        let (tx, rx) = flume::bounded::<(RelayMessage<'static>, usize)>(100);
+        let _handle = registry.register_connection(
+            "conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+        for sub_id in ["a", "b"] {
+            registry
+                .add_subscription("conn", SubscriptionId::new(sub_id), vec![Filter::new()])
+                .unwrap();
+        }
+
+        let keys = Keys::generate();
+        let events: Vec<Arc<Event>> = (0..3)
+            .map(|i| {
+                Arc::new(
+                    EventBuilder::text_note(format!("burst {i}"))
+                        .sign_with_keys(&keys)
+                        .unwrap(),
+                )
+            })
+            .collect();
+        registry
+            .distribute_events(events.clone(), &Scope::Default)
+            .await;
+
+        // Every event reaches both subscriptions, in the order of the burst
+        let received: Vec<EventId> = rx
+            .try_iter()
+            .map(|(message, _)| match message {
+                RelayMessage::Event { event, .. } => event.id,
+                other => panic!("unexpected message {other:?}"),
+            })
+            .collect();
+        let expected: Vec<EventId> = events
+            .iter()
+            .flat_map(|event| [event.id, event.id])
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit_refuses_large_filters() {
+        let small = vec![Filter::new().kind(Kind::TextNote)];
+        let limit = crate::memory::filters_size(&small) * 3;
+        let registry = SubscriptionRegistry::new(None).with_memory_limit(limit);
+        let (tx, _rx) = flume::bounded(10);
+        let handle = registry.register_connection(
+            "conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(Scope::Default),
+        );
+
+        registry
+            .add_subscription("conn", SubscriptionId::new("small"), small.clone())
+            .unwrap();
+        let authors: Vec<PublicKey> = (0..100).map(|_| Keys::generate().public_key()).collect();
+        let huge = vec![Filter::new().authors(authors)];
+        assert!(registry.check_memory(&huge).is_err());
+        let error = registry
+            .add_subscription("conn", SubscriptionId::new("huge"), huge)
+            .unwrap_err();
+        assert!(error.client_message().starts_with("rate-limited:"));
+        assert_eq!(
+            registry.memory_usage().filters,
+            crate::memory::filters_size(&small)
+        );
+
+        // Replacing a subscription only counts its new filters
+        registry
+            .add_subscription("conn", SubscriptionId::new("small"), small)
+            .unwrap();
+        registry
+            .remove_subscription("conn", &SubscriptionId::new("small"))
+            .unwrap();
+        assert_eq!(registry.memory_usage().filters, 0);
+
+        registry
+            .add_subscription("conn", SubscriptionId::new("again"), vec![Filter::new()])
+            .unwrap();
+        drop(handle);
+        assert_eq!(registry.memory_usage().total(), 0);
+    }
 }
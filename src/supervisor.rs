@@ -0,0 +1,228 @@
+//! Supervision of background tasks
+//!
+//! A panic in a bare `tokio::spawn` task only ends that task: a connection's
+//! replaceable events buffer, a distribution worker or the connection reaper
+//! would silently stop working while the relay keeps serving.
+//! [`TaskSupervisor::spawn`] runs such a task and restarts it when it panics,
+//! after a backoff doubling from 1 second up to 1 minute, reset once an
+//! instance ran for longer than the maximum. A task that returns, or whose cancellation token fired, is not
+//! restarted.
+//!
+//! Panics are logged, counted by
+//! [`RelayMetricsHandler::record_task_panic`](crate::metrics::RelayMetricsHandler::record_task_panic)
+//! and reported per task name by [`TaskSupervisor::health`], which the admin
+//! API serves at `GET /tasks`. Tasks started once per connection share their
+//! name, so their health is aggregated. The relay supervises its own tasks;
+//! pass a [`TaskSupervisor`] to
+//! [`RelayBuilder::with_task_supervisor`](crate::RelayBuilder::with_task_supervisor)
+//! to keep a handle on it.
+
+use nostr_sdk::Timestamp;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error};
+
+/// Health of the instances of a supervised task sharing one name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TaskHealth {
+    /// Instances currently running
+    pub running: usize,
+    /// Instances that panicked and wait for their backoff to restart
+    pub restarting: usize,
+    /// Panics since the relay started, all instances together
+    pub panics: u64,
+    /// Message of the latest panic
+    pub last_panic: Option<String>,
+    /// When the latest panic happened
+    pub last_panic_at: Option<Timestamp>,
+}
+
+/// Restarts panicking background tasks and tracks their health
+#[derive(Debug, Clone)]
+pub struct TaskSupervisor {
+    min_backoff: Duration,
+    max_backoff: Duration,
+    tasks: Arc<Mutex<BTreeMap<String, TaskHealth>>>,
+}
+
+impl Default for TaskSupervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskSupervisor {
+    /// Restart panicking tasks after 1 second, doubling up to 1 minute
+    pub fn new() -> Self {
+        Self {
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            tasks: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Restart after `min_backoff`, doubling up to `max_backoff`
+    #[must_use]
+    pub fn with_backoff(mut self, min_backoff: Duration, max_backoff: Duration) -> Self {
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff.max(min_backoff);
+        self
+    }
+
+    /// Health of every task started so far, by name
+    pub fn health(&self) -> BTreeMap<String, TaskHealth> {
+        self.tasks.lock().clone()
+    }
+
+    /// Whether no supervised task is waiting to be restarted
+    pub fn is_healthy(&self) -> bool {
+        self.tasks.lock().values().all(|task| task.restarting == 0)
+    }
+
+    /// Run the future `task` makes until it returns or `cancellation_token`
+    /// fires, making a new one each time it panics
+    ///
+    /// The supervising task is spawned on `task_tracker` when given, so
+    /// shutdown waits for it, and with `tokio::spawn` otherwise.
+    pub fn spawn<F, Fut>(
+        &self,
+        task_tracker: Option<&TaskTracker>,
+        cancellation_token: CancellationToken,
+        name: impl Into<String>,
+        task: F,
+    ) where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.into();
+        let supervised = async move {
+            supervisor.run(cancellation_token, name, task).await;
+        };
+        match task_tracker {
+            Some(task_tracker) => task_tracker.spawn(supervised),
+            None => tokio::spawn(supervised),
+        };
+    }
+
+    async fn run<F, Fut>(&self, cancellation_token: CancellationToken, name: String, task: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut backoff = self.min_backoff;
+        loop {
+            self.update(&name, |health| health.running += 1);
+            let started = Instant::now();
+            let result = tokio::spawn(task()).await;
+            self.update(&name, |health| health.running -= 1);
+
+            let Err(e) = result else { break };
+            if !e.is_panic() {
+                break;
+            }
+            let message = panic_message(e.into_panic());
+            error!("Task '{}' panicked: {}", name, message);
+            if let Some(metrics) = crate::global_metrics::get_relay_metrics_handler() {
+                metrics.record_task_panic(&name);
+            }
+            self.update(&name, |health| {
+                health.panics += 1;
+                health.last_panic = Some(message);
+                health.last_panic_at = Some(Timestamp::now());
+            });
+
+            if started.elapsed() > self.max_backoff {
+                backoff = self.min_backoff;
+            }
+            self.update(&name, |health| health.restarting += 1);
+            let cancelled = tokio::select! {
+                _ = cancellation_token.cancelled() => true,
+                _ = tokio::time::sleep(backoff) => false,
+            };
+            self.update(&name, |health| health.restarting -= 1);
+            if cancelled {
+                break;
+            }
+            debug!("Restarting task '{}' after {:?}", name, backoff);
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    fn update(&self, name: &str, update: impl FnOnce(&mut TaskHealth)) {
+        let mut tasks = self.tasks.lock();
+        update(tasks.entry(name.to_string()).or_default());
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "non-string panic payload".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_panicking_task_is_restarted() {
+        let supervisor =
+            TaskSupervisor::new().with_backoff(Duration::from_millis(10), Duration::from_secs(1));
+        let cancellation_token = CancellationToken::new();
+        let task_tracker = TaskTracker::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        // Panics twice, then runs until cancelled
+        supervisor.spawn(Some(&task_tracker), cancellation_token.clone(), "flaky", {
+            let runs = Arc::clone(&runs);
+            let cancellation_token = cancellation_token.clone();
+            move || {
+                let run = runs.fetch_add(1, Ordering::SeqCst);
+                let cancellation_token = cancellation_token.clone();
+                async move {
+                    if run < 2 {
+                        panic!("run {run} failed");
+                    }
+                    cancellation_token.cancelled().await;
+                }
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while supervisor
+                .health()
+                .get("flaky")
+                .map(|health| health.running)
+                != Some(1)
+                || runs.load(Ordering::SeqCst) < 3
+            {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let health = &supervisor.health()["flaky"];
+        assert_eq!(health.panics, 2);
+        assert_eq!(health.last_panic.as_deref(), Some("run 1 failed"));
+        assert!(supervisor.is_healthy());
+
+        cancellation_token.cancel();
+        task_tracker.close();
+        task_tracker.wait().await;
+        assert_eq!(supervisor.health()["flaky"].running, 0);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+}
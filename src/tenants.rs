@@ -0,0 +1,347 @@
+//! Provisioning and retiring tenants of a multi-tenant relay
+//!
+//! A tenant is a named [`Scope`], usually served from its own subdomain.
+//! [`TenantStore`] keeps the provisioned tenants and their status, persisted
+//! like the moderation lists as a relay-signed NIP-78 event in the default
+//! scope. Wrapping the relay's [`ScopeResolver`] with
+//! [`TenantStore::resolver`] refuses connections to tenants that were never
+//! created or are disabled, and the store's [`EventPolicy`] refuses writes
+//! to them from connections that were already open.
+//!
+//! [`TenantStore::delete`] retires a tenant for good: it is disabled first,
+//! its live connections are sent a NOTICE and removed from the registry, its
+//! events are purged and only then is it forgotten.
+
+use crate::database::RelayDatabase;
+use crate::error::{Error, Result};
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::scope_resolver::ScopeResolver;
+use crate::subscription_coordinator::ClosedReason;
+use crate::subscription_registry::SubscriptionRegistry;
+use crate::utils::Attachment;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::info;
+
+/// Kind of the event the tenants are persisted in (NIP-78 application data)
+pub const TENANT_LIST_KIND: Kind = Kind::ApplicationSpecificData;
+
+/// `d` tag of the event the tenants are persisted in
+pub const TENANT_LIST_IDENTIFIER: &str = "relay_builder/tenants";
+
+/// NOTICE sent to connections of a disabled tenant
+pub const TENANT_DISABLED_NOTICE: &str = "restricted: this relay is disabled";
+
+/// NOTICE sent to connections of a deleted tenant
+pub const TENANT_DELETED_NOTICE: &str = "restricted: this relay was deleted";
+
+/// Whether a tenant is served
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TenantStatus {
+    Active,
+    /// Connections are refused and writes rejected, events are kept
+    Disabled,
+}
+
+/// A provisioned tenant
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tenant {
+    pub status: TenantStatus,
+    pub created_at: Timestamp,
+}
+
+#[derive(Debug)]
+struct Inner {
+    tenants: BTreeMap<String, Tenant>,
+    /// `created_at` of the last persisted version, versions must strictly increase
+    saved_at: Timestamp,
+}
+
+/// Shared, persistent list of tenants
+#[derive(Clone)]
+pub struct TenantStore {
+    inner: Arc<RwLock<Inner>>,
+    database: Arc<RelayDatabase>,
+    keys: Keys,
+    /// Registry of the built relay, set when the store is passed to the builder
+    registry: Attachment<Arc<SubscriptionRegistry>>,
+}
+
+impl std::fmt::Debug for TenantStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TenantStore")
+            .field("tenants", &self.inner.read().tenants.len())
+            .field("attached", &self.registry.is_attached())
+            .finish()
+    }
+}
+
+impl TenantStore {
+    /// Load the tenants persisted in `database` by the relay identified by `keys`
+    pub async fn open(database: Arc<RelayDatabase>, keys: Keys) -> Result<Self> {
+        let filter = Filter::new()
+            .author(keys.public_key())
+            .kind(TENANT_LIST_KIND)
+            .identifier(TENANT_LIST_IDENTIFIER)
+            .limit(1);
+        let stored = database.query(vec![filter], &Scope::Default).await?;
+
+        let (tenants, saved_at) = match stored.into_iter().next() {
+            Some(event) => (
+                serde_json::from_str(&event.content)
+                    .map_err(|e| Error::database(format!("Invalid tenant list event: {e}")))?,
+                event.created_at,
+            ),
+            None => (BTreeMap::new(), Timestamp::from(0)),
+        };
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Inner { tenants, saved_at })),
+            database,
+            keys,
+            registry: Attachment::default(),
+        })
+    }
+
+    /// Disconnect tenants' connections through `registry`
+    pub(crate) fn attach(&self, registry: Arc<SubscriptionRegistry>) {
+        self.registry.attach(registry, "Tenant store");
+    }
+
+    /// All provisioned tenants by name
+    pub fn tenants(&self) -> BTreeMap<String, Tenant> {
+        self.inner.read().tenants.clone()
+    }
+
+    /// The tenant called `name`, if provisioned
+    pub fn tenant(&self, name: &str) -> Option<Tenant> {
+        self.inner.read().tenants.get(name).cloned()
+    }
+
+    /// Whether `scope` may be served; the default scope always is
+    pub fn is_active(&self, scope: &Scope) -> bool {
+        match scope {
+            Scope::Default => true,
+            Scope::Named { name, .. } => self
+                .inner
+                .read()
+                .tenants
+                .get(name.as_str())
+                .is_some_and(|tenant| tenant.status == TenantStatus::Active),
+        }
+    }
+
+    /// Provision the tenant `name`
+    ///
+    /// Fails if the name is not a valid scope name or the tenant exists.
+    pub async fn create(&self, name: &str) -> Result<()> {
+        Scope::named(name)
+            .map_err(|e| Error::protocol(format!("Invalid tenant name '{name}': {e}")))?;
+
+        self.update(|tenants| {
+            if tenants.contains_key(name) {
+                return Err(Error::protocol(format!("Tenant '{name}' already exists")));
+            }
+            tenants.insert(
+                name.to_string(),
+                Tenant {
+                    status: TenantStatus::Active,
+                    created_at: Timestamp::now(),
+                },
+            );
+            Ok(())
+        })
+        .await
+    }
+
+    /// Stop serving `name` while keeping its events
+    ///
+    /// Returns the number of connections that were disconnected.
+    pub async fn disable(&self, name: &str) -> Result<usize> {
+        self.set_status(name, TenantStatus::Disabled).await?;
+        Ok(self.disconnect(name, TENANT_DISABLED_NOTICE))
+    }
+
+    /// Serve a tenant disabled with [`Self::disable`] again
+    pub async fn enable(&self, name: &str) -> Result<()> {
+        self.set_status(name, TenantStatus::Active).await
+    }
+
+    /// Retire `name`: disconnect its clients, purge its events and forget it
+    ///
+    /// If purging fails the tenant stays disabled, so the deletion can be
+    /// retried. Returns the number of connections that were disconnected.
+    pub async fn delete(&self, name: &str) -> Result<usize> {
+        let scope = Scope::named(name)
+            .map_err(|e| Error::protocol(format!("Invalid tenant name '{name}': {e}")))?;
+
+        self.set_status(name, TenantStatus::Disabled).await?;
+        let disconnected = self.disconnect(name, TENANT_DELETED_NOTICE);
+        self.database.delete_scope(&scope).await?;
+        self.update(|tenants| {
+            tenants.remove(name);
+            Ok(())
+        })
+        .await?;
+
+        info!(
+            "Deleted tenant {}, {} connections disconnected",
+            name, disconnected
+        );
+        Ok(disconnected)
+    }
+
+    async fn set_status(&self, name: &str, status: TenantStatus) -> Result<()> {
+        self.update(|tenants| match tenants.get_mut(name) {
+            Some(tenant) => {
+                tenant.status = status;
+                Ok(())
+            }
+            None => Err(Error::protocol(format!("Unknown tenant '{name}'"))),
+        })
+        .await
+    }
+
+    fn disconnect(&self, name: &str, reason: &str) -> usize {
+        let (Some(registry), Ok(scope)) = (self.registry.get(), Scope::named(name)) else {
+            return 0;
+        };
+        registry.disconnect_scope(&scope, reason)
+    }
+
+    /// Change the tenants and persist the result
+    ///
+    /// The change applies immediately, even before it is persisted.
+    async fn update(
+        &self,
+        change: impl FnOnce(&mut BTreeMap<String, Tenant>) -> Result<()>,
+    ) -> Result<()> {
+        let event = {
+            let mut inner = self.inner.write();
+            change(&mut inner.tenants)?;
+
+            let created_at = std::cmp::max(
+                Timestamp::now(),
+                Timestamp::from(inner.saved_at.as_u64() + 1),
+            );
+            inner.saved_at = created_at;
+
+            let content = serde_json::to_string(&inner.tenants)
+                .map_err(|e| Error::internal(format!("Failed to serialize tenants: {e}")))?;
+            EventBuilder::new(TENANT_LIST_KIND, content)
+                .tag(Tag::identifier(TENANT_LIST_IDENTIFIER))
+                .custom_created_at(created_at)
+                .sign_with_keys(&self.keys)
+                .map_err(|e| Error::internal(format!("Failed to sign tenants: {e}")))?
+        };
+
+        self.database.save_event(&event, &Scope::Default).await
+    }
+
+    /// Resolver refusing connections to tenants that are not active
+    pub fn resolver(&self, inner: Arc<dyn ScopeResolver>) -> TenantResolver {
+        TenantResolver {
+            store: self.clone(),
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl EventPolicy for TenantStore {
+    async fn check(&self, _: &Event, scope: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        if self.is_active(scope) {
+            PolicyDecision::Accept
+        } else {
+            PolicyDecision::Reject(ClosedReason::Restricted(
+                "this relay is not active".to_string(),
+            ))
+        }
+    }
+}
+
+/// [`ScopeResolver`] serving only active tenants, see [`TenantStore::resolver`]
+#[derive(Debug, Clone)]
+pub struct TenantResolver {
+    store: TenantStore,
+    inner: Arc<dyn ScopeResolver>,
+}
+
+impl ScopeResolver for TenantResolver {
+    fn resolve(&self, host: Option<&str>) -> Option<Scope> {
+        self.inner
+            .resolve(host)
+            .filter(|scope| self.store.is_active(scope))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scope_resolver::SubdomainResolver;
+    use crate::test_utils::setup_test_with_database;
+    use websocket_builder::MessageSender;
+
+    #[tokio::test]
+    async fn test_tenant_lifecycle() {
+        let (_tmp_dir, database, relay_keys) = setup_test_with_database().await;
+        let store = TenantStore::open(database.clone(), relay_keys.clone())
+            .await
+            .unwrap();
+        let registry = Arc::new(SubscriptionRegistry::new(None));
+        store.attach(registry.clone());
+        let resolver = store.resolver(Arc::new(SubdomainResolver::new("example.com")));
+
+        assert_eq!(resolver.resolve(Some("alice.example.com")), None);
+        store.create("alice").await.unwrap();
+        assert!(store.create("alice").await.is_err());
+        let scope = Scope::named("alice").unwrap();
+        assert_eq!(
+            resolver.resolve(Some("alice.example.com")),
+            Some(scope.clone())
+        );
+
+        let event = EventBuilder::text_note("hi")
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        database.save_event(&event, &scope).await.unwrap();
+
+        let (tx, rx) = flume::bounded(10);
+        let _handle = registry.register_connection(
+            "conn".to_string(),
+            MessageSender::new(tx, 0),
+            None,
+            Arc::new(scope.clone()),
+        );
+
+        // Disabling keeps the events but refuses connections and writes
+        assert_eq!(store.disable("alice").await.unwrap(), 1);
+        assert!(matches!(rx.try_recv().unwrap().0, RelayMessage::Notice(_)));
+        assert_eq!(resolver.resolve(Some("alice.example.com")), None);
+        assert!(matches!(
+            store.check(&event, &scope, None).await,
+            PolicyDecision::Reject(ClosedReason::Restricted(_))
+        ));
+        assert_eq!(
+            store.tenant("alice").unwrap().status,
+            TenantStatus::Disabled
+        );
+
+        // The status survives a restart
+        let reopened = TenantStore::open(database.clone(), relay_keys)
+            .await
+            .unwrap();
+        assert_eq!(reopened.tenants(), store.tenants());
+
+        store.delete("alice").await.unwrap();
+        assert!(store.tenant("alice").is_none());
+        let remaining = database.query(vec![Filter::new()], &scope).await.unwrap();
+        assert!(remaining.is_empty());
+    }
+}
@@ -77,18 +77,17 @@ pub async fn create_test_state_with_subscription_service(
     let test_keys = Keys::generate();
     let crypto_helper = crate::crypto_helper::CryptoHelper::new(Arc::new(test_keys));
 
-    let subscription_coordinator = SubscriptionCoordinator::new(
+    let subscription_coordinator = SubscriptionCoordinator::builder(
         database,
         crypto_helper,
         registry,
         "test_connection".to_string(),
         sender,
-        pubkey,
-        Arc::new(nostr_lmdb::Scope::Default),
-        cancellation_token,
-        None,
-        500, // max_limit
-    );
+    )
+    .with_auth_pubkey(pubkey)
+    .with_cancellation_token(cancellation_token)
+    .with_max_limit(500)
+    .build();
 
     let mut state =
         NostrConnectionState::new(RelayUrl::parse("ws://test.relay").expect("Valid URL"))
@@ -0,0 +1,322 @@
+//! In-process relays for integration tests, enabled with the `testing` feature
+//!
+//! [`TestRelay`] serves a relay built with [`RelayBuilder`] on an ephemeral
+//! port of `127.0.0.1`, with its database in a temporary directory removed
+//! when the relay is dropped. Tests talk to it through a connected
+//! [`nostr_sdk::Client`], or through a [`TestConnection`] that sends raw
+//! client messages and asserts the `OK`, `EVENT`, `EOSE` and `CLOSED`
+//! messages the relay answers with.
+//!
+//! [`TestClock`] stands in for the system time of time dependent middleware,
+//! e.g. [`Nip40ExpirationMiddleware::with_clock`], so expiration tests
+//! advance the clock instead of sleeping.
+//!
+//! ```ignore
+//! let relay = TestRelay::start().await?;
+//! let mut connection = relay.connect().await?;
+//! let event = EventBuilder::text_note("hello").sign_with_keys(&Keys::generate())?;
+//!
+//! connection.send(ClientMessage::event(event.clone())).await;
+//! connection.assert_ok(event.id).await;
+//! ```
+//!
+//! [`Nip40ExpirationMiddleware::with_clock`]: crate::Nip40ExpirationMiddleware::with_clock
+
+use crate::config::RelayConfig;
+use crate::error::{Error, Result};
+use crate::relay_builder::RelayBuilder;
+use nostr_sdk::prelude::*;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+/// Time a [`TestConnection`] waits for the next relay message
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time a client waits for its connection to the relay
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Relay served on an ephemeral local port until dropped
+pub struct TestRelay {
+    url: RelayUrl,
+    keys: Keys,
+    cancellation_token: CancellationToken,
+    _tmp_dir: TempDir,
+}
+
+impl std::fmt::Debug for TestRelay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TestRelay")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TestRelay {
+    /// Start a relay accepting every valid event
+    pub async fn start() -> Result<Self> {
+        Self::start_with(RelayBuilder::<()>::new).await
+    }
+
+    /// Start the relay `configure` builds from a config for the local address
+    ///
+    /// The config has the relay URL, a fresh identity and a database in a
+    /// temporary directory; `configure` adds the processor, middlewares and
+    /// settings under test.
+    pub async fn start_with<T>(
+        configure: impl FnOnce(RelayConfig) -> RelayBuilder<T>,
+    ) -> Result<Self>
+    where
+        T: Clone + Default + Send + Sync + std::fmt::Debug + 'static,
+    {
+        let tmp_dir = TempDir::new()
+            .map_err(|e| Error::internal(format!("Failed to create database directory: {e}")))?;
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .map_err(|e| Error::internal(format!("Failed to bind test relay: {e}")))?;
+        let addr = listener
+            .local_addr()
+            .map_err(|e| Error::internal(format!("Failed to read test relay address: {e}")))?;
+
+        let url = RelayUrl::parse(&format!("ws://{addr}"))
+            .map_err(|e| Error::internal(format!("Invalid test relay url: {e}")))?;
+        let keys = Keys::generate();
+        let db_path = tmp_dir.path().join("relay.db");
+        let config = RelayConfig::new(
+            url.to_string(),
+            db_path.to_string_lossy().to_string(),
+            keys.clone(),
+        );
+
+        let cancellation_token = CancellationToken::new();
+        let router = configure(config)
+            .with_cancellation_token(cancellation_token.clone())
+            .into_axum_router()
+            .await?;
+
+        let shutdown = cancellation_token.clone();
+        tokio::spawn(async move {
+            let service = router.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(listener, service)
+                .with_graceful_shutdown(async move { shutdown.cancelled().await })
+                .await
+            {
+                tracing::warn!("Test relay stopped: {}", e);
+            }
+        });
+
+        Ok(Self {
+            url,
+            keys,
+            cancellation_token,
+            _tmp_dir: tmp_dir,
+        })
+    }
+
+    /// WebSocket URL of the relay
+    pub fn url(&self) -> &RelayUrl {
+        &self.url
+    }
+
+    /// Identity the relay signs its own events with
+    pub fn keys(&self) -> &Keys {
+        &self.keys
+    }
+
+    /// Client with a fresh identity, connected to the relay
+    pub async fn client(&self) -> Result<Client> {
+        self.client_with_keys(Keys::generate()).await
+    }
+
+    /// Client signing with `keys`, connected to the relay
+    pub async fn client_with_keys(&self, keys: Keys) -> Result<Client> {
+        let client = Client::new(keys);
+        client
+            .add_relay(self.url.clone())
+            .await
+            .map_err(|e| Error::internal(format!("Failed to add test relay: {e}")))?;
+        client.connect().await;
+        client.wait_for_connection(CONNECT_TIMEOUT).await;
+        Ok(client)
+    }
+
+    /// Connection sending raw client messages, with a fresh identity
+    pub async fn connect(&self) -> Result<TestConnection> {
+        let client = self.client().await?;
+        let notifications = client.notifications();
+        Ok(TestConnection {
+            client,
+            url: self.url.clone(),
+            notifications,
+        })
+    }
+}
+
+impl Drop for TestRelay {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+    }
+}
+
+/// Raw message exchange with a [`TestRelay`]
+///
+/// The assertions panic when the relay answers anything else, or nothing
+/// within 5 seconds, so tests state the expected message sequence directly.
+#[derive(Debug)]
+pub struct TestConnection {
+    client: Client,
+    url: RelayUrl,
+    notifications: broadcast::Receiver<RelayPoolNotification>,
+}
+
+impl TestConnection {
+    /// Underlying client, e.g. to authenticate with NIP-42
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Send `message` to the relay
+    ///
+    /// # Panics
+    ///
+    /// If the message can't be sent.
+    pub async fn send(&self, message: ClientMessage<'static>) {
+        self.client
+            .send_msg_to([self.url.clone()], message)
+            .await
+            .expect("failed to send message to test relay");
+    }
+
+    /// Next message of the relay
+    ///
+    /// # Panics
+    ///
+    /// If the relay sends nothing within 5 seconds.
+    pub async fn next_message(&mut self) -> RelayMessage<'static> {
+        loop {
+            let notification = tokio::time::timeout(MESSAGE_TIMEOUT, self.notifications.recv())
+                .await
+                .expect("no message from test relay within 5 seconds")
+                .expect("test relay notifications closed");
+            if let RelayPoolNotification::Message { message, .. } = notification {
+                return message;
+            }
+        }
+    }
+
+    /// Assert the next message accepts `event_id`, returning its message
+    pub async fn assert_ok(&mut self, event_id: EventId) -> String {
+        self.assert_ok_status(event_id, true).await
+    }
+
+    /// Assert the next message rejects `event_id`, returning the reason
+    pub async fn assert_rejected(&mut self, event_id: EventId) -> String {
+        self.assert_ok_status(event_id, false).await
+    }
+
+    async fn assert_ok_status(&mut self, expected_id: EventId, expected_status: bool) -> String {
+        match self.next_message().await {
+            RelayMessage::Ok {
+                event_id,
+                status,
+                message,
+            } if event_id == expected_id => {
+                assert_eq!(
+                    status, expected_status,
+                    "unexpected OK status for {event_id}: {message}"
+                );
+                message.into_owned()
+            }
+            message => panic!("expected OK for {expected_id}, got {message:?}"),
+        }
+    }
+
+    /// Assert the next messages are stored events of `subscription_id` then
+    /// its `EOSE`, returning the events
+    pub async fn assert_eose(&mut self, subscription_id: &SubscriptionId) -> Vec<Event> {
+        let mut events = Vec::new();
+        loop {
+            match self.next_message().await {
+                RelayMessage::Event {
+                    subscription_id: id,
+                    event,
+                } if id.as_ref() == subscription_id => events.push(event.into_owned()),
+                RelayMessage::EndOfStoredEvents(id) if id.as_ref() == subscription_id => {
+                    return events
+                }
+                message => panic!("expected EVENT or EOSE for {subscription_id}, got {message:?}"),
+            }
+        }
+    }
+
+    /// Assert the next message is a live event of `subscription_id`
+    pub async fn assert_event(&mut self, subscription_id: &SubscriptionId) -> Event {
+        match self.next_message().await {
+            RelayMessage::Event {
+                subscription_id: id,
+                event,
+            } if id.as_ref() == subscription_id => event.into_owned(),
+            message => panic!("expected EVENT for {subscription_id}, got {message:?}"),
+        }
+    }
+
+    /// Assert the next message closes `subscription_id`, returning the reason
+    pub async fn assert_closed(&mut self, subscription_id: &SubscriptionId) -> String {
+        match self.next_message().await {
+            RelayMessage::Closed {
+                subscription_id: id,
+                message,
+            } if id.as_ref() == subscription_id => message.into_owned(),
+            message => panic!("expected CLOSED for {subscription_id}, got {message:?}"),
+        }
+    }
+
+    /// Assert the relay sends nothing for `duration`
+    pub async fn assert_silent(&mut self, duration: Duration) {
+        loop {
+            match tokio::time::timeout(duration, self.notifications.recv()).await {
+                Err(_) => return,
+                Ok(Ok(RelayPoolNotification::Message { message, .. })) => {
+                    panic!("expected no message, got {message:?}")
+                }
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+/// Clock running with the system time, moved forward on demand
+///
+/// Clones share the offset, so a test keeps one and hands the others to the
+/// relay components under test.
+#[derive(Debug, Clone, Default)]
+pub struct TestClock {
+    offset: Arc<AtomicU64>,
+}
+
+impl TestClock {
+    /// Clock at the system time
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock `duration` forward
+    pub fn advance(&self, duration: Duration) {
+        self.offset.fetch_add(duration.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Current time of the clock
+    pub fn now(&self) -> Timestamp {
+        Timestamp::now() + self.offset.load(Ordering::Relaxed)
+    }
+
+    /// Function returning the clock's time, for `with_clock` builders
+    pub fn source(&self) -> impl Fn() -> Timestamp + Send + Sync + 'static {
+        let clock = self.clone();
+        move || clock.now()
+    }
+}
@@ -0,0 +1,243 @@
+//! Tombstones keeping NIP-09 deleted events from coming back
+//!
+//! Deleting an event removes it from the database, but nothing stops a client
+//! or another relay from broadcasting it again. [`TombstoneStore`] indexes
+//! what kind 5 deletion requests deleted, per scope, and refuses those events
+//! at ingest:
+//!
+//! - ids named in `e` tags, for events by the requester only
+//! - addresses named in `a` tags, as a (pubkey, kind, `d` tag) range covering
+//!   versions up to the request's `created_at`
+//!
+//! Deletion requests stay in the database, so the index is rebuilt from them
+//! when the relay starts. Requests to vanish keep their own tombstones, see
+//! [`crate::vanish`].
+
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::kind_router::{InlineKindHandler, KindContext};
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Reason given for events refused because they were deleted
+pub const DELETED_EVENT_MESSAGE: &str = "event was deleted by its author";
+
+/// Tombstones of one scope
+#[derive(Debug, Default)]
+struct Tombstones {
+    /// Deleted ids with every pubkey that asked to delete them, only the
+    /// author's request counts but it isn't known until the event is seen
+    events: HashMap<EventId, HashSet<PublicKey>>,
+    /// Deleted addresses with the time up to which their versions are deleted
+    addresses: HashMap<(PublicKey, Kind, String), Timestamp>,
+}
+
+/// Index of deleted events and addresses consulted during ingest
+#[derive(Clone, Default)]
+pub struct TombstoneStore {
+    scopes: Arc<RwLock<HashMap<Scope, Tombstones>>>,
+}
+
+impl std::fmt::Debug for TombstoneStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scopes = self.scopes.read();
+        f.debug_struct("TombstoneStore")
+            .field(
+                "events",
+                &scopes.values().map(|t| t.events.len()).sum::<usize>(),
+            )
+            .field(
+                "addresses",
+                &scopes.values().map(|t| t.addresses.len()).sum::<usize>(),
+            )
+            .finish()
+    }
+}
+
+impl TombstoneStore {
+    /// An empty index
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index the deletion requests stored in every scope of `database`
+    pub async fn load(&self, database: &RelayDatabase) -> Result<()> {
+        let mut scopes = database.list_scopes().await?;
+        if !scopes.contains(&Scope::Default) {
+            scopes.push(Scope::Default);
+        }
+        let filter = Filter::new().kind(Kind::EventDeletion);
+        for scope in scopes {
+            for deletion in database.query(vec![filter.clone()], &scope).await?.iter() {
+                self.record(deletion, &scope);
+            }
+        }
+        Ok(())
+    }
+
+    /// Index what the deletion request `deletion`, saved to `scope`, deletes
+    ///
+    /// Events that are not deletion requests are ignored, as are `a` tags
+    /// naming another author's address.
+    pub fn record(&self, deletion: &Event, scope: &Scope) {
+        if deletion.kind != Kind::EventDeletion {
+            return;
+        }
+        let mut scopes = self.scopes.write();
+        let tombstones = scopes.entry(scope.clone()).or_default();
+        for tag in deletion.tags.iter() {
+            match tag.as_slice() {
+                [name, value, ..] if name == "e" => {
+                    if let Ok(id) = EventId::from_hex(value) {
+                        tombstones
+                            .events
+                            .entry(id)
+                            .or_default()
+                            .insert(deletion.pubkey);
+                    }
+                }
+                [name, value, ..] if name == "a" => {
+                    let Some((kind, pubkey, identifier)) = parse_address(value) else {
+                        continue;
+                    };
+                    if pubkey != deletion.pubkey {
+                        continue;
+                    }
+                    let until = tombstones
+                        .addresses
+                        .entry((pubkey, kind, identifier))
+                        .or_insert(deletion.created_at);
+                    *until = (*until).max(deletion.created_at);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether `event` was deleted from `scope` by its author
+    pub fn is_deleted(&self, event: &Event, scope: &Scope) -> bool {
+        let scopes = self.scopes.read();
+        let Some(tombstones) = scopes.get(scope) else {
+            return false;
+        };
+        if tombstones
+            .events
+            .get(&event.id)
+            .is_some_and(|pubkeys| pubkeys.contains(&event.pubkey))
+        {
+            return true;
+        }
+        if tombstones.addresses.is_empty() {
+            return false;
+        }
+        let identifier = event.tags.identifier().unwrap_or_default().to_string();
+        tombstones
+            .addresses
+            .get(&(event.pubkey, event.kind, identifier))
+            .is_some_and(|until| event.created_at <= *until)
+    }
+}
+
+/// Kind, pubkey and `d` tag of a `<kind>:<pubkey>:<d tag>` address
+fn parse_address(address: &str) -> Option<(Kind, PublicKey, String)> {
+    let mut parts = address.splitn(3, ':');
+    let kind = parts.next()?.parse::<u16>().ok()?;
+    let pubkey = PublicKey::from_hex(parts.next()?).ok()?;
+    let identifier = parts.next().unwrap_or_default().to_string();
+    Some((Kind::from(kind), pubkey, identifier))
+}
+
+#[async_trait]
+impl InlineKindHandler for TombstoneStore {
+    async fn handle(&self, deletion: Arc<Event>, context: KindContext) -> PolicyDecision {
+        self.record(&deletion, &context.scope);
+        PolicyDecision::Accept
+    }
+}
+
+#[async_trait]
+impl EventPolicy for TombstoneStore {
+    async fn check(&self, event: &Event, scope: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        if self.is_deleted(event, scope) {
+            PolicyDecision::Reject(ClosedReason::Blocked(DELETED_EVENT_MESSAGE.to_string()))
+        } else {
+            PolicyDecision::Accept
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    #[tokio::test]
+    async fn test_deleted_events_are_refused() {
+        let (_tmp_dir, database, _keys) = setup_test_with_database().await;
+        let author = Keys::generate();
+        let other = Keys::generate();
+        let tombstones = TombstoneStore::new();
+
+        let note = EventBuilder::text_note("oops")
+            .sign_with_keys(&author)
+            .unwrap();
+        let article = EventBuilder::new(Kind::LongFormTextNote, "draft")
+            .tag(Tag::identifier("post"))
+            .custom_created_at(Timestamp::from(1_000))
+            .sign_with_keys(&author)
+            .unwrap();
+        let address = format!("30023:{}:post", author.public_key().to_hex());
+
+        // Someone else can't delete the author's events
+        let forged = EventBuilder::new(Kind::EventDeletion, "")
+            .tag(Tag::parse(["e", &note.id.to_hex()]).unwrap())
+            .tag(Tag::parse(["a", &address]).unwrap())
+            .sign_with_keys(&other)
+            .unwrap();
+        tombstones.record(&forged, &Scope::Default);
+        assert!(!tombstones.is_deleted(&note, &Scope::Default));
+        assert!(!tombstones.is_deleted(&article, &Scope::Default));
+
+        let deletion = EventBuilder::new(Kind::EventDeletion, "")
+            .tag(Tag::parse(["e", &note.id.to_hex()]).unwrap())
+            .tag(Tag::parse(["a", &address]).unwrap())
+            .custom_created_at(Timestamp::from(2_000))
+            .sign_with_keys(&author)
+            .unwrap();
+        database
+            .save_event(&deletion, &Scope::Default)
+            .await
+            .unwrap();
+        tombstones.record(&deletion, &Scope::Default);
+        assert_eq!(
+            tombstones.check(&note, &Scope::Default, None).await,
+            PolicyDecision::Reject(ClosedReason::Blocked(DELETED_EVENT_MESSAGE.to_string()))
+        );
+        assert!(tombstones.is_deleted(&article, &Scope::Default));
+        assert!(!tombstones.is_deleted(&note, &Scope::named("other").unwrap()));
+
+        // A forged request after the author's doesn't lift the tombstone
+        tombstones.record(&forged, &Scope::Default);
+        assert!(tombstones.is_deleted(&note, &Scope::Default));
+
+        // Newer versions of a deleted address are accepted
+        let republished = EventBuilder::new(Kind::LongFormTextNote, "final")
+            .tag(Tag::identifier("post"))
+            .custom_created_at(Timestamp::from(3_000))
+            .sign_with_keys(&author)
+            .unwrap();
+        assert!(!tombstones.is_deleted(&republished, &Scope::Default));
+
+        // The index is rebuilt from stored deletion requests
+        let reloaded = TombstoneStore::new();
+        reloaded.load(&database).await.unwrap();
+        assert!(reloaded.is_deleted(&note, &Scope::Default));
+        assert!(reloaded.is_deleted(&article, &Scope::Default));
+    }
+}
@@ -0,0 +1,278 @@
+//! Mirroring accepted events to upstream relays
+//!
+//! An [`Upstream`] forwards the events this relay stores to another relay,
+//! so a relay built with this crate can act as a write-through cache or an
+//! aggregator node. Only events of one scope matching the upstream's filter
+//! are forwarded.
+//!
+//! Every upstream has its own bounded queue, drained by a task publishing
+//! through a nostr-sdk [`Client`]. Failed publishes are retried with
+//! exponential backoff; events arriving while the queue is full are dropped
+//! and counted, so a slow or unreachable upstream never holds back local
+//! clients.
+
+use crate::event_sink::EventSink;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// Default number of events waiting to be forwarded per upstream
+pub const DEFAULT_UPSTREAM_QUEUE_SIZE: usize = 10_000;
+
+/// Default number of retries before an event is given up on
+pub const DEFAULT_UPSTREAM_MAX_RETRIES: u32 = 5;
+
+/// Default cap on the delay between retries
+pub const DEFAULT_UPSTREAM_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Delay before the first retry, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct Counters {
+    forwarded: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Counters of an upstream since it was started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpstreamStats {
+    /// Events the upstream accepted
+    pub forwarded: u64,
+    /// Events given up on after the last retry
+    pub failed: u64,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// A relay the events stored here are mirrored to
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    url: RelayUrl,
+    filter: Filter,
+    scope: Scope,
+    auth_keys: Option<Keys>,
+    queue_size: usize,
+    max_retries: u32,
+    max_backoff: Duration,
+    /// Queue of the forwarding task, set when the upstream is started
+    queue: Arc<OnceCell<flume::Sender<Event>>>,
+    counters: Arc<Counters>,
+}
+
+impl Upstream {
+    /// Forward every event stored in the default scope to `url`
+    pub fn new(url: RelayUrl) -> Self {
+        Self {
+            url,
+            filter: Filter::new(),
+            scope: Scope::Default,
+            auth_keys: None,
+            queue_size: DEFAULT_UPSTREAM_QUEUE_SIZE,
+            max_retries: DEFAULT_UPSTREAM_MAX_RETRIES,
+            max_backoff: DEFAULT_UPSTREAM_MAX_BACKOFF,
+            queue: Arc::new(OnceCell::new()),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Only forward events matching `filter`
+    #[must_use]
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Forward events stored in `scope` instead of the default scope
+    #[must_use]
+    pub fn with_scope(mut self, scope: Scope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Answer the upstream's NIP-42 challenges with `keys`
+    #[must_use]
+    pub fn with_auth_keys(mut self, keys: Keys) -> Self {
+        self.auth_keys = Some(keys);
+        self
+    }
+
+    /// Keep at most `queue_size` events waiting to be forwarded
+    #[must_use]
+    pub fn with_queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size.max(1);
+        self
+    }
+
+    /// Retry a failed publish up to `max_retries` times, waiting at most `max_backoff` in between
+    #[must_use]
+    pub fn with_retries(mut self, max_retries: u32, max_backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.max_backoff = max_backoff.max(INITIAL_BACKOFF);
+        self
+    }
+
+    /// URL of the upstream relay
+    pub fn url(&self) -> &RelayUrl {
+        &self.url
+    }
+
+    /// Counters since the upstream was started
+    pub fn stats(&self) -> UpstreamStats {
+        UpstreamStats {
+            forwarded: self.counters.forwarded.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Connect to the upstream and start forwarding queued events
+    ///
+    /// Events are only queued once the upstream is started. Starting it again
+    /// does nothing.
+    pub fn spawn(&self, task_tracker: &TaskTracker, cancellation_token: Option<CancellationToken>) {
+        let (tx, rx) = flume::bounded(self.queue_size);
+        if self.queue.set(tx).is_err() {
+            warn!("Upstream {} is already running", self.url);
+            return;
+        }
+
+        let upstream = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let client = match &upstream.auth_keys {
+                Some(keys) => Client::builder().signer(keys.clone()).build(),
+                None => Client::default(),
+            };
+            if let Err(e) = client.add_relay(upstream.url.clone()).await {
+                warn!("Failed to add upstream {}: {}", upstream.url, e);
+                return;
+            }
+            client.connect().await;
+            info!("Forwarding events to upstream {}", upstream.url);
+
+            loop {
+                let event = tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    event = rx.recv_async() => match event {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    },
+                };
+                upstream.forward(&client, event, &cancellation_token).await;
+            }
+
+            client.disconnect().await;
+            debug!("Upstream {} stopped", upstream.url);
+        });
+    }
+
+    /// Publish `event`, retrying with exponential backoff
+    async fn forward(&self, client: &Client, event: Event, cancellation_token: &CancellationToken) {
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 0..=self.max_retries {
+            match client.send_event(&event).await {
+                Ok(output) if !output.success.is_empty() => {
+                    self.counters.forwarded.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Ok(output) => debug!(
+                    "Upstream {} refused event {}: {:?}",
+                    self.url, event.id, output.failed
+                ),
+                Err(e) => debug!(
+                    "Failed to forward event {} to {}: {}",
+                    event.id, self.url, e
+                ),
+            }
+
+            if attempt == self.max_retries {
+                break;
+            }
+            tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+
+        self.counters.failed.fetch_add(1, Ordering::Relaxed);
+        warn!("Gave up forwarding event {} to {}", event.id, self.url);
+    }
+}
+
+#[async_trait]
+impl EventSink for Upstream {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        let Some(queue) = self.queue.get() else {
+            return;
+        };
+        if *scope != self.scope
+            || !self
+                .filter
+                .match_event(&event, nostr_sdk::filter::MatchEventOptions::default())
+        {
+            return;
+        }
+        if queue.try_send(Event::clone(&event)).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_only_matching_events_are_queued() {
+        let upstream = Upstream::new(RelayUrl::parse("wss://upstream.example.com").unwrap())
+            .with_filter(Filter::new().kind(Kind::TextNote))
+            .with_queue_size(1);
+        let keys = Keys::generate();
+        let note = Arc::new(EventBuilder::text_note("hi").sign_with_keys(&keys).unwrap());
+        let metadata = Arc::new(
+            EventBuilder::metadata(&Metadata::new())
+                .sign_with_keys(&keys)
+                .unwrap(),
+        );
+
+        // Nothing is queued before the upstream is started
+        upstream
+            .on_event_stored(note.clone(), &Scope::Default)
+            .await;
+        let (tx, rx) = flume::bounded(1);
+        upstream.queue.set(tx).unwrap();
+
+        upstream.on_event_stored(metadata, &Scope::Default).await;
+        upstream
+            .on_event_stored(note.clone(), &Scope::named("tenant").unwrap())
+            .await;
+        assert!(rx.is_empty());
+
+        upstream
+            .on_event_stored(note.clone(), &Scope::Default)
+            .await;
+        upstream
+            .on_event_stored(note.clone(), &Scope::Default)
+            .await;
+        assert_eq!(rx.try_recv().unwrap().id, note.id);
+        assert_eq!(
+            upstream.stats(),
+            UpstreamStats {
+                dropped: 1,
+                ..Default::default()
+            }
+        );
+    }
+}
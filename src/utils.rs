@@ -19,3 +19,43 @@ pub fn get_blocking_runtime() -> &'static Runtime {
             .unwrap()
     })
 }
+
+/// Relay components a handle works with, set once when the relay is built
+///
+/// Clones share the components, so a handle kept by the caller sees the relay
+/// its clone was attached to.
+pub(crate) struct Attachment<T>(std::sync::Arc<OnceLock<T>>);
+
+impl<T> Attachment<T> {
+    /// Attach `value`, keeping the first one when `name`, the handle, is attached twice
+    ///
+    /// Returns whether `value` was attached.
+    pub(crate) fn attach(&self, value: T, name: &str) -> bool {
+        let attached = self.0.set(value).is_ok();
+        if !attached {
+            tracing::warn!("{name} is already attached to a relay");
+        }
+        attached
+    }
+
+    /// The attached components, once the relay is built
+    pub(crate) fn get(&self) -> Option<&T> {
+        self.0.get()
+    }
+
+    pub(crate) fn is_attached(&self) -> bool {
+        self.0.get().is_some()
+    }
+}
+
+impl<T> Default for Attachment<T> {
+    fn default() -> Self {
+        Self(std::sync::Arc::new(OnceLock::new()))
+    }
+}
+
+impl<T> Clone for Attachment<T> {
+    fn clone(&self) -> Self {
+        Self(std::sync::Arc::clone(&self.0))
+    }
+}
@@ -0,0 +1,306 @@
+//! NIP-62 requests to vanish
+//!
+//! A kind 62 event asks the relays named in its `relay` tags, or every relay
+//! when the tag is `ALL_RELAYS`, to delete everything its author published up
+//! to the request's `created_at`, and the gift wraps addressed to them.
+//! [`VanishRequests`] runs as an inline [`KindRouter`](crate::KindRouter)
+//! route: the purge completes before the request is stored and answered with
+//! `OK`, in the scope the request was sent to, or in every scope for
+//! `ALL_RELAYS`. Stored requests then serve as tombstones: older events of a
+//! vanished pubkey are refused at ingest, also after a restart.
+//!
+//! Enabled with
+//! [`RelayBuilder::with_vanish_requests`](crate::RelayBuilder::with_vanish_requests).
+
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::kind_router::{InlineKindHandler, KindContext};
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Kind of NIP-62 requests to vanish
+pub const VANISH_REQUEST_KIND: u16 = 62;
+
+/// `relay` tag value addressing a request to every relay
+pub const ALL_RELAYS: &str = "ALL_RELAYS";
+
+/// Tombstones of pubkeys that requested to vanish, and the purge itself
+#[derive(Debug, Clone)]
+pub struct VanishRequests {
+    /// Host of the relay URL, subdomains of it address scopes of this relay
+    host: Arc<str>,
+    /// Latest vanish time per pubkey and scope, `None` standing for every scope
+    tombstones: Arc<RwLock<HashMap<(PublicKey, Option<Scope>), Timestamp>>>,
+}
+
+impl VanishRequests {
+    /// Honour requests addressed to `relay_url` or to all relays
+    pub fn new(relay_url: &str) -> Self {
+        let host = url::Url::parse(relay_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+            .unwrap_or_default();
+        Self {
+            host: Arc::from(host),
+            tombstones: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Record the tombstones of the requests stored in every scope of `database`
+    pub async fn load(&self, database: &RelayDatabase) -> Result<()> {
+        let mut scopes = database.list_scopes().await?;
+        if !scopes.contains(&Scope::Default) {
+            scopes.push(Scope::Default);
+        }
+        let filter = Filter::new().kind(Kind::from(VANISH_REQUEST_KIND));
+        for scope in scopes {
+            for request in database.query(vec![filter.clone()], &scope).await?.iter() {
+                if let Some(all_relays) = self.addressed(request) {
+                    self.record(request, &scope, all_relays);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// When `pubkey` vanished from `scope`, if it did
+    pub fn vanished_at(&self, pubkey: &PublicKey, scope: &Scope) -> Option<Timestamp> {
+        let tombstones = self.tombstones.read();
+        let everywhere = tombstones.get(&(*pubkey, None));
+        let here = tombstones.get(&(*pubkey, Some(scope.clone())));
+        everywhere.max(here).copied()
+    }
+
+    /// Whether `request` targets this relay, and if so whether it targets all relays
+    fn addressed(&self, request: &Event) -> Option<bool> {
+        let mut addressed = None;
+        for tag in request.tags.iter() {
+            let [name, value, ..] = tag.as_slice() else {
+                continue;
+            };
+            if name != "relay" {
+                continue;
+            }
+            if value == ALL_RELAYS {
+                return Some(true);
+            }
+            let host = url::Url::parse(value)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_ascii_lowercase));
+            if host.is_some_and(|host| {
+                !self.host.is_empty()
+                    && (host == *self.host || host.ends_with(&format!(".{}", self.host)))
+            }) {
+                addressed = Some(false);
+            }
+        }
+        addressed
+    }
+
+    fn record(&self, request: &Event, scope: &Scope, all_relays: bool) {
+        let scope = (!all_relays).then(|| scope.clone());
+        let mut tombstones = self.tombstones.write();
+        let vanished_at = tombstones
+            .entry((request.pubkey, scope))
+            .or_insert(request.created_at);
+        *vanished_at = (*vanished_at).max(request.created_at);
+    }
+
+    /// Delete what `request` asks to forget from `scope`
+    async fn purge(&self, request: &Event, scope: &Scope, database: &RelayDatabase) -> Result<()> {
+        database
+            .delete(
+                Filter::new()
+                    .author(request.pubkey)
+                    .until(request.created_at),
+                scope,
+            )
+            .await?;
+        database
+            .delete(
+                Filter::new().kind(Kind::GiftWrap).pubkey(request.pubkey),
+                scope,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl InlineKindHandler for VanishRequests {
+    async fn handle(&self, request: Arc<Event>, context: KindContext) -> PolicyDecision {
+        let Some(all_relays) = self.addressed(&request) else {
+            return PolicyDecision::Reject(ClosedReason::Invalid(
+                "request to vanish is not addressed to this relay".to_string(),
+            ));
+        };
+
+        let scopes = if all_relays {
+            match context.database.list_scopes().await {
+                Ok(mut scopes) => {
+                    if !scopes.contains(&Scope::Default) {
+                        scopes.push(Scope::Default);
+                    }
+                    scopes
+                }
+                Err(e) => {
+                    warn!("Could not list scopes to vanish {}: {}", request.pubkey, e);
+                    return PolicyDecision::Reject(ClosedReason::Error(
+                        "could not process the request to vanish".to_string(),
+                    ));
+                }
+            }
+        } else {
+            vec![context.scope.clone()]
+        };
+
+        // Refuse re-ingestion before purging, so nothing slips in between
+        self.record(&request, &context.scope, all_relays);
+        for scope in &scopes {
+            if let Err(e) = self.purge(&request, scope, &context.database).await {
+                warn!("Could not purge {} from {:?}: {}", request.pubkey, scope, e);
+                return PolicyDecision::Reject(ClosedReason::Error(
+                    "could not process the request to vanish".to_string(),
+                ));
+            }
+        }
+        info!(
+            "Purged {} from {} scope(s) on request to vanish",
+            request.pubkey,
+            scopes.len()
+        );
+        PolicyDecision::Accept
+    }
+}
+
+#[async_trait]
+impl EventPolicy for VanishRequests {
+    async fn check(&self, event: &Event, scope: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        if event.kind.as_u16() == VANISH_REQUEST_KIND {
+            return PolicyDecision::Accept;
+        }
+        match self.vanished_at(&event.pubkey, scope) {
+            Some(vanished_at) if event.created_at <= vanished_at => PolicyDecision::Reject(
+                ClosedReason::Blocked("author requested to vanish from this relay".to_string()),
+            ),
+            _ => PolicyDecision::Accept,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    fn vanish_request(keys: &Keys, relay: &str, created_at: Timestamp) -> Arc<Event> {
+        Arc::new(
+            EventBuilder::new(Kind::from(VANISH_REQUEST_KIND), "")
+                .tag(Tag::parse(["relay", relay]).unwrap())
+                .custom_created_at(created_at)
+                .sign_with_keys(keys)
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_vanish_purges_and_blocks_reingestion() {
+        let (_tmp_dir, database, _keys) = setup_test_with_database().await;
+        let vanish = VanishRequests::new("wss://relay.example.com");
+        let user = Keys::generate();
+        let other = Keys::generate();
+        let tenant = Scope::named("tenant").unwrap();
+        let now = Timestamp::now();
+
+        let note = EventBuilder::text_note("forget me")
+            .custom_created_at(now - 10)
+            .sign_with_keys(&user)
+            .unwrap();
+        let gift_wrap = EventBuilder::new(Kind::GiftWrap, "sealed")
+            .tag(Tag::public_key(user.public_key()))
+            .sign_with_keys(&other)
+            .unwrap();
+        let unrelated = EventBuilder::text_note("stay")
+            .sign_with_keys(&other)
+            .unwrap();
+        for scope in [&Scope::Default, &tenant] {
+            for event in [&note, &gift_wrap, &unrelated] {
+                database.save_event(event, scope).await.unwrap();
+            }
+        }
+        let context = |scope: &Scope| KindContext {
+            scope: scope.clone(),
+            auth_pubkey: None,
+            database: Arc::clone(&database),
+        };
+
+        // Requests for other relays are refused
+        let elsewhere = vanish_request(&user, "wss://other.example.com", now);
+        assert!(matches!(
+            vanish.handle(elsewhere, context(&tenant)).await,
+            PolicyDecision::Reject(ClosedReason::Invalid(_))
+        ));
+
+        // A request to a tenant's subdomain only purges that scope
+        let request = vanish_request(&user, "wss://tenant.relay.example.com", now);
+        assert_eq!(
+            vanish.handle(request, context(&tenant)).await,
+            PolicyDecision::Accept
+        );
+        let stored = |scope: Scope| {
+            let database = Arc::clone(&database);
+            async move {
+                database
+                    .query(vec![Filter::new()], &scope)
+                    .await
+                    .unwrap()
+                    .len()
+            }
+        };
+        assert_eq!(stored(tenant.clone()).await, 1);
+        assert_eq!(stored(Scope::Default).await, 3);
+        assert_eq!(
+            vanish.check(&note, &tenant, None).await,
+            PolicyDecision::Reject(ClosedReason::Blocked(
+                "author requested to vanish from this relay".to_string()
+            ))
+        );
+        assert_eq!(
+            vanish.check(&note, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+
+        // ALL_RELAYS purges every scope, newer events are accepted again
+        let request = vanish_request(&user, ALL_RELAYS, now);
+        assert_eq!(
+            vanish
+                .handle(request.clone(), context(&Scope::Default))
+                .await,
+            PolicyDecision::Accept
+        );
+        assert_eq!(stored(Scope::Default).await, 1);
+        let later = EventBuilder::text_note("back")
+            .custom_created_at(now + 10)
+            .sign_with_keys(&user)
+            .unwrap();
+        assert_eq!(
+            vanish.check(&later, &Scope::Default, None).await,
+            PolicyDecision::Accept
+        );
+
+        // Stored requests restore the tombstones
+        database
+            .save_event(&request, &Scope::Default)
+            .await
+            .unwrap();
+        let reloaded = VanishRequests::new("wss://relay.example.com");
+        reloaded.load(&database).await.unwrap();
+        assert_eq!(reloaded.vanished_at(&user.public_key(), &tenant), Some(now));
+    }
+}
@@ -0,0 +1,293 @@
+//! Web of Trust admission policy
+//!
+//! [`WebOfTrust`] builds a follow graph from the kind-3 contact lists stored in
+//! the relay database, starting from a set of root pubkeys (the relay operator
+//! and optional seeds), and only accepts events from pubkeys within a number
+//! of hops of a root. The graph is rebuilt periodically so new follows are
+//! picked up; rejected authors are counted and reported to the
+//! [`MetricsHandler`].
+
+use crate::database::RelayDatabase;
+use crate::error::Result;
+use crate::event_policy::{EventPolicy, PolicyDecision};
+use crate::middlewares::MetricsHandler;
+use crate::subscription_coordinator::ClosedReason;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use parking_lot::RwLock;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+/// Default interval between graph rebuilds
+pub const DEFAULT_WOT_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Authors queried per contact list query
+const AUTHORS_PER_QUERY: usize = 500;
+
+#[derive(Debug)]
+struct Graph {
+    database: Arc<RelayDatabase>,
+    scope: Scope,
+    roots: HashSet<PublicKey>,
+    max_hops: u8,
+    trusted: RwLock<Arc<HashSet<PublicKey>>>,
+    rejections: AtomicU64,
+    /// Rejections per author since the last refresh
+    rejected_authors: DashMap<PublicKey, u64>,
+}
+
+/// Follow-graph based admission policy
+#[derive(Debug, Clone)]
+pub struct WebOfTrust {
+    graph: Arc<Graph>,
+    metrics_handler: Option<Arc<dyn MetricsHandler>>,
+}
+
+impl WebOfTrust {
+    /// Trust `root` and the pubkeys it follows, read from `database`
+    pub fn new(database: Arc<RelayDatabase>, root: PublicKey) -> Self {
+        Self {
+            graph: Arc::new(Graph {
+                database,
+                scope: Scope::Default,
+                roots: HashSet::from([root]),
+                max_hops: 1,
+                trusted: RwLock::new(Arc::new(HashSet::from([root]))),
+                rejections: AtomicU64::new(0),
+                rejected_authors: DashMap::new(),
+            }),
+            metrics_handler: None,
+        }
+    }
+
+    fn graph_mut(&mut self) -> &mut Graph {
+        Arc::get_mut(&mut self.graph).expect("WebOfTrust configured after being shared")
+    }
+
+    /// Also start the graph from `seeds`
+    ///
+    /// Must be called before the policy is cloned.
+    #[must_use]
+    pub fn with_seeds(mut self, seeds: impl IntoIterator<Item = PublicKey>) -> Self {
+        let graph = self.graph_mut();
+        graph.roots.extend(seeds);
+        *graph.trusted.get_mut() = Arc::new(graph.roots.clone());
+        self
+    }
+
+    /// Trust pubkeys up to `max_hops` follows away from a root, 0 trusts the roots only
+    ///
+    /// Must be called before the policy is cloned.
+    #[must_use]
+    pub fn with_max_hops(mut self, max_hops: u8) -> Self {
+        self.graph_mut().max_hops = max_hops;
+        self
+    }
+
+    /// Read contact lists from `scope` instead of the default scope
+    ///
+    /// Must be called before the policy is cloned.
+    #[must_use]
+    pub fn with_scope(mut self, scope: Scope) -> Self {
+        self.graph_mut().scope = scope;
+        self
+    }
+
+    /// Report rejected authors to `handler`
+    #[must_use]
+    pub fn with_metrics_handler(mut self, handler: Arc<dyn MetricsHandler>) -> Self {
+        self.metrics_handler = Some(handler);
+        self
+    }
+
+    /// Whether `pubkey` is within reach of a root
+    pub fn is_trusted(&self, pubkey: &PublicKey) -> bool {
+        self.graph.trusted.read().contains(pubkey)
+    }
+
+    /// Number of trusted pubkeys, roots included
+    pub fn trusted_count(&self) -> usize {
+        self.graph.trusted.read().len()
+    }
+
+    /// Total number of rejected events
+    pub fn rejection_count(&self) -> u64 {
+        self.graph.rejections.load(Ordering::Relaxed)
+    }
+
+    /// Rejected authors since the last refresh, with their number of rejections
+    pub fn rejected_authors(&self) -> Vec<(PublicKey, u64)> {
+        self.graph
+            .rejected_authors
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect()
+    }
+
+    /// Rebuild the trusted set from the stored contact lists
+    ///
+    /// Returns the number of trusted pubkeys.
+    pub async fn refresh(&self) -> Result<usize> {
+        let graph = &self.graph;
+        let mut trusted = graph.roots.clone();
+        let mut frontier: Vec<PublicKey> = graph.roots.iter().copied().collect();
+
+        for _ in 0..graph.max_hops {
+            let mut next = Vec::new();
+            for authors in frontier.chunks(AUTHORS_PER_QUERY) {
+                let filter = Filter::new()
+                    .kind(Kind::ContactList)
+                    .authors(authors.iter().copied());
+                let contact_lists = graph.database.query(vec![filter], &graph.scope).await?;
+
+                for contact_list in contact_lists {
+                    for followed in contact_list.tags.public_keys() {
+                        if trusted.insert(*followed) {
+                            next.push(*followed);
+                        }
+                    }
+                }
+            }
+
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
+        }
+
+        let count = trusted.len();
+        *graph.trusted.write() = Arc::new(trusted);
+        graph.rejected_authors.clear();
+        debug!("Web of trust refreshed, {} trusted pubkeys", count);
+        Ok(count)
+    }
+
+    /// Refresh the graph now and then every `interval` until cancelled
+    pub fn spawn_refresh(
+        &self,
+        task_tracker: &TaskTracker,
+        interval: Duration,
+        cancellation_token: Option<CancellationToken>,
+    ) {
+        let wot = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            loop {
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = ticker.tick() => {
+                        match wot.refresh().await {
+                            Ok(count) => info!("Web of trust has {} trusted pubkeys", count),
+                            Err(e) => warn!("Failed to refresh web of trust: {}", e),
+                        }
+                    }
+                }
+            }
+
+            debug!("Web of trust refresh stopped");
+        });
+    }
+}
+
+#[async_trait]
+impl EventPolicy for WebOfTrust {
+    async fn check(&self, event: &Event, _: &Scope, _: Option<&PublicKey>) -> PolicyDecision {
+        if self.is_trusted(&event.pubkey) {
+            return PolicyDecision::Accept;
+        }
+
+        self.graph.rejections.fetch_add(1, Ordering::Relaxed);
+        *self.graph.rejected_authors.entry(event.pubkey).or_insert(0) += 1;
+        if let Some(handler) = &self.metrics_handler {
+            handler.increment_untrusted_author_rejections();
+        }
+
+        PolicyDecision::Reject(ClosedReason::Restricted(
+            "author is outside the relay's web of trust".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_test_with_database;
+
+    async fn follow(database: &RelayDatabase, keys: &Keys, followed: &[&Keys]) {
+        let contact_list = EventBuilder::new(Kind::ContactList, "")
+            .tags(
+                followed
+                    .iter()
+                    .map(|keys| Tag::public_key(keys.public_key())),
+            )
+            .sign_with_keys(keys)
+            .unwrap();
+        database
+            .save_event(&contact_list, &Scope::Default)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trust_follows_hops() {
+        let (_tmp_dir, database, relay_keys) = setup_test_with_database().await;
+        let friend = Keys::generate();
+        let friend_of_friend = Keys::generate();
+        let stranger = Keys::generate();
+
+        follow(&database, &relay_keys, &[&friend]).await;
+        follow(&database, &friend, &[&friend_of_friend]).await;
+
+        let one_hop = WebOfTrust::new(database.clone(), relay_keys.public_key());
+        assert_eq!(one_hop.refresh().await.unwrap(), 2);
+        assert!(one_hop.is_trusted(&friend.public_key()));
+        assert!(!one_hop.is_trusted(&friend_of_friend.public_key()));
+
+        let two_hops = WebOfTrust::new(database.clone(), relay_keys.public_key()).with_max_hops(2);
+        assert_eq!(two_hops.refresh().await.unwrap(), 3);
+        assert!(two_hops.is_trusted(&friend_of_friend.public_key()));
+        assert!(!two_hops.is_trusted(&stranger.public_key()));
+
+        // Seeds are roots too
+        let seeded = WebOfTrust::new(database, relay_keys.public_key())
+            .with_max_hops(0)
+            .with_seeds([stranger.public_key()]);
+        seeded.refresh().await.unwrap();
+        assert!(seeded.is_trusted(&stranger.public_key()));
+        assert!(!seeded.is_trusted(&friend.public_key()));
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_counted() {
+        let (_tmp_dir, database, relay_keys) = setup_test_with_database().await;
+        let wot = WebOfTrust::new(database, relay_keys.public_key());
+        let stranger = Keys::generate();
+
+        let event = EventBuilder::text_note("hello")
+            .sign_with_keys(&stranger)
+            .unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                wot.check(&event, &Scope::Default, None).await,
+                PolicyDecision::Reject(ClosedReason::Restricted(_))
+            ));
+        }
+        assert_eq!(wot.rejection_count(), 2);
+        assert_eq!(wot.rejected_authors(), vec![(stranger.public_key(), 2)]);
+
+        wot.refresh().await.unwrap();
+        assert!(wot.rejected_authors().is_empty());
+        assert_eq!(wot.rejection_count(), 2);
+    }
+}
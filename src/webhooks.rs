@@ -0,0 +1,337 @@
+//! Webhook notifications for stored events
+//!
+//! A [`Webhook`] POSTs the events this relay stores to an HTTP endpoint, so
+//! notification pushers, indexers and other services can react to events
+//! without holding a WebSocket open. Only events of one scope matching the
+//! webhook's filter are sent.
+//!
+//! Events are batched: a request is sent once `batch_size` events are waiting
+//! or `batch_interval` passed since the first of them. The body is a JSON
+//! object `{"scope": <name or null>, "events": [...]}`. With a secret, the
+//! hex HMAC-SHA256 of the body is sent in the [`SIGNATURE_HEADER`] as
+//! `sha256=<hex>`. Failed requests are retried with exponential backoff; each
+//! webhook has its own bounded queue, and events arriving while it is full
+//! are dropped and counted.
+
+use crate::event_sink::EventSink;
+use async_trait::async_trait;
+use nostr::hashes::hmac::{Hmac, HmacEngine};
+use nostr::hashes::{sha256, Hash, HashEngine};
+use nostr_lmdb::Scope;
+use nostr_sdk::prelude::*;
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+/// Header carrying the HMAC-SHA256 of the request body
+pub const SIGNATURE_HEADER: &str = "X-Relay-Signature";
+
+/// Default maximum number of events per request
+pub const DEFAULT_WEBHOOK_BATCH_SIZE: usize = 100;
+
+/// Default time events wait for a batch to fill up
+pub const DEFAULT_WEBHOOK_BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default number of events waiting to be sent per webhook
+pub const DEFAULT_WEBHOOK_QUEUE_SIZE: usize = 10_000;
+
+/// Default number of retries before a batch is given up on
+pub const DEFAULT_WEBHOOK_MAX_RETRIES: u32 = 5;
+
+/// Delay before the first retry, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Cap on the delay between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct Counters {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Counters of a webhook since it was started, in events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebhookStats {
+    /// Events the endpoint acknowledged with a success status
+    pub delivered: u64,
+    /// Events in batches given up on after the last retry
+    pub failed: u64,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// An HTTP endpoint notified of stored events
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    filter: Filter,
+    scope: Scope,
+    secret: Option<Arc<str>>,
+    batch_size: usize,
+    batch_interval: Duration,
+    queue_size: usize,
+    max_retries: u32,
+    /// Queue of the delivery task, set when the webhook is started
+    queue: Arc<OnceCell<flume::Sender<Event>>>,
+    counters: Arc<Counters>,
+}
+
+impl Webhook {
+    /// POST every event stored in the default scope to `url`
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            filter: Filter::new(),
+            scope: Scope::Default,
+            secret: None,
+            batch_size: DEFAULT_WEBHOOK_BATCH_SIZE,
+            batch_interval: DEFAULT_WEBHOOK_BATCH_INTERVAL,
+            queue_size: DEFAULT_WEBHOOK_QUEUE_SIZE,
+            max_retries: DEFAULT_WEBHOOK_MAX_RETRIES,
+            queue: Arc::new(OnceCell::new()),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Only send events matching `filter`
+    #[must_use]
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Send events stored in `scope` instead of the default scope
+    #[must_use]
+    pub fn with_scope(mut self, scope: Scope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Sign request bodies with `secret`, see [`SIGNATURE_HEADER`]
+    #[must_use]
+    pub fn with_secret(mut self, secret: impl AsRef<str>) -> Self {
+        self.secret = Some(Arc::from(secret.as_ref()));
+        self
+    }
+
+    /// Send up to `batch_size` events per request, waiting at most `batch_interval` for more
+    #[must_use]
+    pub fn with_batching(mut self, batch_size: usize, batch_interval: Duration) -> Self {
+        self.batch_size = batch_size.max(1);
+        self.batch_interval = batch_interval;
+        self
+    }
+
+    /// Keep at most `queue_size` events waiting to be sent
+    #[must_use]
+    pub fn with_queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size.max(1);
+        self
+    }
+
+    /// Retry a failed request up to `max_retries` times
+    #[must_use]
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// URL of the endpoint
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Counters since the webhook was started
+    pub fn stats(&self) -> WebhookStats {
+        WebhookStats {
+            delivered: self.counters.delivered.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Start sending queued events
+    ///
+    /// Events are only queued once the webhook is started. Starting it again
+    /// does nothing.
+    pub fn spawn(&self, task_tracker: &TaskTracker, cancellation_token: Option<CancellationToken>) {
+        let (tx, rx) = flume::bounded(self.queue_size);
+        if self.queue.set(tx).is_err() {
+            warn!("Webhook {} is already running", self.url);
+            return;
+        }
+
+        let webhook = self.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
+
+        task_tracker.spawn(async move {
+            let client = reqwest::Client::new();
+
+            loop {
+                // Wait for the first event of a batch, then for the batch to fill up
+                let first = tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    event = rx.recv_async() => match event {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    },
+                };
+                let mut batch = vec![first];
+                let deadline = tokio::time::sleep(webhook.batch_interval);
+                tokio::pin!(deadline);
+                while batch.len() < webhook.batch_size {
+                    tokio::select! {
+                        _ = &mut deadline => break,
+                        event = rx.recv_async() => match event {
+                            Ok(event) => batch.push(event),
+                            Err(_) => break,
+                        },
+                    }
+                }
+
+                webhook.deliver(&client, &batch, &cancellation_token).await;
+            }
+
+            debug!("Webhook {} stopped", webhook.url);
+        });
+    }
+
+    /// JSON body of a request carrying `events`
+    fn body(&self, events: &[Event]) -> String {
+        let scope = match &self.scope {
+            Scope::Named { name, .. } => Some(name.to_string()),
+            Scope::Default => None,
+        };
+        serde_json::json!({ "scope": scope, "events": events }).to_string()
+    }
+
+    /// POST `events`, retrying with exponential backoff
+    async fn deliver(
+        &self,
+        client: &reqwest::Client,
+        events: &[Event],
+        cancellation_token: &CancellationToken,
+    ) {
+        let body = self.body(events);
+        let signature = self
+            .secret
+            .as_deref()
+            .map(|secret| format!("sha256={}", sign(secret, &body)));
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 0..=self.max_retries {
+            let mut request = client
+                .post(&self.url)
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    self.counters
+                        .delivered
+                        .fetch_add(events.len() as u64, Ordering::Relaxed);
+                    return;
+                }
+                Ok(response) => debug!(
+                    "Webhook {} answered {} to {} events",
+                    self.url,
+                    response.status(),
+                    events.len()
+                ),
+                Err(e) => debug!("Failed to call webhook {}: {}", self.url, e),
+            }
+
+            if attempt == self.max_retries {
+                break;
+            }
+            tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+
+        self.counters
+            .failed
+            .fetch_add(events.len() as u64, Ordering::Relaxed);
+        warn!(
+            "Gave up sending {} events to webhook {}",
+            events.len(),
+            self.url
+        );
+    }
+}
+
+/// Hex HMAC-SHA256 of `body` keyed with `secret`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    hex::encode(Hmac::<sha256::Hash>::from_engine(engine).to_byte_array())
+}
+
+#[async_trait]
+impl EventSink for Webhook {
+    async fn on_event_stored(&self, event: Arc<Event>, scope: &Scope) {
+        let Some(queue) = self.queue.get() else {
+            return;
+        };
+        if *scope != self.scope
+            || !self
+                .filter
+                .match_event(&event, nostr_sdk::filter::MatchEventOptions::default())
+        {
+            return;
+        }
+        if queue.try_send(Event::clone(&event)).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_webhook_queue_and_signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let webhook = Webhook::new("https://hooks.example.com/nostr")
+            .with_filter(Filter::new().kind(Kind::TextNote))
+            .with_scope(Scope::named("tenant").unwrap());
+        let (tx, rx) = flume::bounded(10);
+        webhook.queue.set(tx).unwrap();
+
+        let event = Arc::new(
+            EventBuilder::text_note("hi")
+                .sign_with_keys(&Keys::generate())
+                .unwrap(),
+        );
+        webhook
+            .on_event_stored(event.clone(), &Scope::Default)
+            .await;
+        assert!(rx.is_empty());
+        webhook
+            .on_event_stored(event.clone(), &Scope::named("tenant").unwrap())
+            .await;
+        assert_eq!(rx.try_recv().unwrap().id, event.id);
+
+        let body: serde_json::Value =
+            serde_json::from_str(&webhook.body(&[Event::clone(&event)])).unwrap();
+        assert_eq!(body["scope"], "tenant");
+        assert_eq!(body["events"][0]["id"], event.id.to_hex());
+    }
+}
@@ -0,0 +1,90 @@
+//! Integration tests of the in-process test relay
+
+use nostr_sdk::prelude::*;
+use relay_builder::testing::{TestClock, TestRelay};
+use relay_builder::{FilterValidation, Nip40ExpirationMiddleware, RelayBuilder};
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_publish_query_and_closed_sequences() {
+    let relay = TestRelay::start_with(|config| {
+        RelayBuilder::<()>::new(config)
+            .with_filter_validation(FilterValidation::new().deny_empty_filters())
+    })
+    .await
+    .unwrap();
+    let mut connection = relay.connect().await.unwrap();
+    let keys = Keys::generate();
+
+    let event = EventBuilder::text_note("hello")
+        .sign_with_keys(&keys)
+        .unwrap();
+    connection.send(ClientMessage::event(event.clone())).await;
+    connection.assert_ok(event.id).await;
+
+    let notes = SubscriptionId::new("notes");
+    connection
+        .send(ClientMessage::req(
+            notes.clone(),
+            Filter::new().author(keys.public_key()),
+        ))
+        .await;
+    assert_eq!(connection.assert_eose(&notes).await, vec![event]);
+
+    let everything = SubscriptionId::new("everything");
+    connection
+        .send(ClientMessage::req(everything.clone(), Filter::new()))
+        .await;
+    let reason = connection.assert_closed(&everything).await;
+    assert!(reason.starts_with("invalid:"), "{reason}");
+
+    let client = relay.client().await.unwrap();
+    let events = client
+        .fetch_events(
+            Filter::new().author(keys.public_key()),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+}
+
+#[tokio::test]
+async fn test_expired_events_are_not_delivered_after_clock_advances() {
+    let clock = TestClock::new();
+    let relay = TestRelay::start_with(|config| {
+        RelayBuilder::<()>::new(config)
+            .with_middleware(Nip40ExpirationMiddleware::new().with_clock(clock.source()))
+    })
+    .await
+    .unwrap();
+    let mut publisher = relay.connect().await.unwrap();
+    let mut subscriber = relay.connect().await.unwrap();
+    let keys = Keys::generate();
+    let expiring = |content: &str| {
+        EventBuilder::text_note(content)
+            .tag(Tag::expiration(Timestamp::now() + Duration::from_secs(60)))
+            .sign_with_keys(&keys)
+            .unwrap()
+    };
+
+    let live = SubscriptionId::new("live");
+    subscriber
+        .send(ClientMessage::req(
+            live.clone(),
+            Filter::new().author(keys.public_key()),
+        ))
+        .await;
+    subscriber.assert_eose(&live).await;
+
+    let fresh = expiring("fresh");
+    publisher.send(ClientMessage::event(fresh.clone())).await;
+    publisher.assert_ok(fresh.id).await;
+    assert_eq!(subscriber.assert_event(&live).await, fresh);
+
+    clock.advance(Duration::from_secs(120));
+    let stale = expiring("stale");
+    publisher.send(ClientMessage::event(stale.clone())).await;
+    publisher.assert_ok(stale.id).await;
+    subscriber.assert_silent(Duration::from_millis(500)).await;
+}